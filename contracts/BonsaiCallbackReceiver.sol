@@ -29,6 +29,16 @@ abstract contract BonsaiCallbackReceiver {
     // Invalid callback source error
     error UnauthorizedCallbackSource(IBonsaiRelay expected, IBonsaiRelay found);
 
+    /// @notice Emitted right before a callback request to have the relay trace the request by
+    /// `traceId` in its logs, records and deliveries, instead of by the callback request ID.
+    event CallbackTraceId(string traceId);
+
+    /// @notice Trace the next callback request of this transaction by `traceId`, which must be 1 to
+    /// 128 visible ASCII characters. No other event may be emitted before the request.
+    function _traceCallback(string memory traceId) internal {
+        emit CallbackTraceId(traceId);
+    }
+
     /// @notice Verify that the call came from the Bonsai relay contract
     function _verifyMessageSource() internal view {
         IBonsaiRelay foundRelayAddress = IBonsaiRelay(msg.sender);
@@ -0,0 +1,161 @@
+// SPDX-License-Identifier: BUSL-1.1
+pragma solidity ^0.8.14;
+
+/// @notice Holds the fees paid for Bonsai callback requests until the relay
+/// operator claims them after fulfilling the request. Requesters can take back
+/// fees of requests that were not fulfilled within `refundDelay`.
+/// @dev Request IDs are keccak256(abi.encode(account, imageId, input,
+/// callbackContract, functionSelector, gasLimit)) of the CallbackRequest
+/// event emitted by the relay contract. Identical requests share a request
+/// ID, so each of their fees is escrowed under keccak256(abi.encode(requestId,
+/// nonce)), with nonces assigned in deposit order. The request's callback
+/// contract confirms each fulfilment with `confirm`, which fulfils the
+/// deposits of a request in nonce order, and only fulfilled deposits can be
+/// claimed.
+contract FeeEscrow {
+    struct Deposit {
+        address payer;
+        uint256 amount;
+        uint256 depositedAt;
+        bytes32 requestId;
+        uint64 nonce;
+    }
+
+    error NotOperator();
+    error NotCallbackContract();
+    error UnknownDeposit();
+    error NothingToClaim();
+    error NothingToConfirm();
+    error NotFulfilled();
+    error AlreadyFulfilled();
+    error NotPayer();
+    error RefundTooEarly(uint256 availableAt);
+    error TransferFailed();
+
+    event FeeDeposited(bytes32 indexed escrowId, bytes32 indexed requestId, address indexed payer, uint256 amount);
+    event RequestFulfilled(bytes32 indexed requestId, uint64 nonce);
+    event FeeClaimed(bytes32 indexed escrowId, address indexed operator, uint256 amount);
+    event FeeRefunded(bytes32 indexed escrowId, address indexed payer, uint256 amount);
+
+    address public immutable operator;
+    uint256 public immutable refundDelay;
+
+    mapping(bytes32 => Deposit) public deposits;
+    /// @notice Callback contract of each request ID, which confirms its
+    /// fulfilments.
+    mapping(bytes32 => address) public callbackContracts;
+    /// @notice Number of deposits made for each request ID, the nonce of the
+    /// next one.
+    mapping(bytes32 => uint64) public depositCount;
+    /// @notice Number of fulfilments confirmed for each request ID. Deposits
+    /// with a lower nonce are fulfilled.
+    mapping(bytes32 => uint64) public fulfilledCount;
+
+    constructor(address operator_, uint256 refundDelay_) {
+        operator = operator_;
+        refundDelay = refundDelay_;
+    }
+
+    function requestId(
+        address account,
+        bytes32 imageId,
+        bytes calldata input,
+        address callbackContract,
+        bytes4 functionSelector,
+        uint64 gasLimit
+    ) public pure returns (bytes32) {
+        return keccak256(abi.encode(account, imageId, input, callbackContract, functionSelector, gasLimit));
+    }
+
+    function escrowId(bytes32 requestId_, uint64 nonce) public pure returns (bytes32) {
+        return keccak256(abi.encode(requestId_, nonce));
+    }
+
+    /// @notice Pay the fee of a new request, returning the ID it is escrowed
+    /// under.
+    function open(
+        address account,
+        bytes32 imageId,
+        bytes calldata input,
+        address callbackContract,
+        bytes4 functionSelector,
+        uint64 gasLimit
+    ) external payable returns (bytes32 id) {
+        bytes32 request = requestId(account, imageId, input, callbackContract, functionSelector, gasLimit);
+        uint64 nonce = depositCount[request]++;
+        callbackContracts[request] = callbackContract;
+        id = escrowId(request, nonce);
+        deposits[id] = Deposit({
+            payer: msg.sender,
+            amount: msg.value,
+            depositedAt: block.timestamp,
+            requestId: request,
+            nonce: nonce
+        });
+
+        emit FeeDeposited(id, request, msg.sender, msg.value);
+    }
+
+    /// @notice Add to the fee of an open deposit. The refund delay still runs
+    /// from when the deposit was opened.
+    function deposit(bytes32 id) external payable {
+        Deposit storage d = deposits[id];
+        if (d.payer == address(0)) revert UnknownDeposit();
+        if (_fulfilled(d)) revert AlreadyFulfilled();
+        d.amount += msg.value;
+
+        emit FeeDeposited(id, d.requestId, msg.sender, msg.value);
+    }
+
+    function feeOf(bytes32 id) external view returns (uint256) {
+        return deposits[id].amount;
+    }
+
+    /// @notice Called by the request's callback contract when its callback
+    /// is invoked, fulfilling the request's oldest unfulfilled deposit.
+    function confirm(bytes32 requestId_) external {
+        if (msg.sender != callbackContracts[requestId_]) revert NotCallbackContract();
+        uint64 nonce = fulfilledCount[requestId_];
+        if (nonce >= depositCount[requestId_]) revert NothingToConfirm();
+        fulfilledCount[requestId_] = nonce + 1;
+
+        emit RequestFulfilled(requestId_, nonce);
+    }
+
+    /// @notice Called by the operator once the request's callback contract
+    /// confirmed the fulfilment.
+    function claim(bytes32 id) external {
+        if (msg.sender != operator) revert NotOperator();
+        Deposit memory d = deposits[id];
+        if (d.amount == 0) revert NothingToClaim();
+        if (!_fulfilled(d)) revert NotFulfilled();
+        delete deposits[id];
+
+        (bool success,) = operator.call{value: d.amount}("");
+        if (!success) revert TransferFailed();
+
+        emit FeeClaimed(id, operator, d.amount);
+    }
+
+    /// @notice Return the fee of an unfulfilled request to its payer after the
+    /// refund delay.
+    function refund(bytes32 id) external {
+        Deposit memory d = deposits[id];
+        if (d.amount == 0) revert NothingToClaim();
+        if (msg.sender != d.payer) revert NotPayer();
+        if (_fulfilled(d)) revert AlreadyFulfilled();
+        if (block.timestamp < d.depositedAt + refundDelay) {
+            revert RefundTooEarly(d.depositedAt + refundDelay);
+        }
+        delete deposits[id];
+
+        (bool success,) = d.payer.call{value: d.amount}("");
+        if (!success) revert TransferFailed();
+
+        emit FeeRefunded(id, d.payer, d.amount);
+    }
+
+    function _fulfilled(Deposit memory d) internal view returns (bool) {
+        return d.nonce < fulfilledCount[d.requestId];
+    }
+}
@@ -525,28 +525,9 @@ contract UniswapV3Pool is IUniswapV3Pool, BonsaiCallbackReceiver, LinearVRGDA {
 
             _lockPool(recipient, msg.sender, zeroForOne, amountSpecified, sqrtPriceLimitX96, data);
 
-            // Caching for gas saving
-            Slot0 memory slot0_ = slot0;
-
-            (int24 nextTick,) =
-                tickBitmap.nextInitializedTickWithinOneWord(slot0_.tick, int24(tickSpacing), request.zeroForOne);
-
-            uint160 sqrtPriceNextX96 = TickMath.getSqrtRatioAtTick(nextTick);
-
             bonsaiRelay.requestCallback(
                 swapImageId,
-                abi.encode(
-                    keccak256(abi.encode(request)),
-                    slot0_.sqrtPriceX96,
-                    (
-                        request.zeroForOne
-                            ? sqrtPriceNextX96 < request.sqrtPriceLimitX96
-                            : sqrtPriceNextX96 > request.sqrtPriceLimitX96
-                    ) ? request.sqrtPriceLimitX96 : sqrtPriceNextX96,
-                    liquidity,
-                    request.amountSpecified,
-                    fee
-                ),
+                _swapInput(),
                 address(this),
                 this.settleSwap.selector,
                 BONSAI_CALLBACK_GAS_LIMIT
@@ -559,7 +540,41 @@ contract UniswapV3Pool is IUniswapV3Pool, BonsaiCallbackReceiver, LinearVRGDA {
         }
     }
 
+    /// @notice SWAP guest input of the locked request, read from this pool's own state.
+    /// @dev The trailing state proof is empty, as the pool's own state needs no proof. The guest
+    /// then commits a zero pool and block, which settleSwap does not read.
+    function _swapInput() internal view returns (bytes memory) {
+        // Caching for gas saving
+        Slot0 memory slot0_ = slot0;
+
+        (int24 nextTick,) =
+            tickBitmap.nextInitializedTickWithinOneWord(slot0_.tick, int24(tickSpacing), request.zeroForOne);
+
+        uint160 sqrtPriceNextX96 = TickMath.getSqrtRatioAtTick(nextTick);
+
+        return abi.encode(
+            keccak256(abi.encode(request)),
+            slot0_.sqrtPriceX96,
+            (
+                request.zeroForOne
+                    ? sqrtPriceNextX96 < request.sqrtPriceLimitX96
+                    : sqrtPriceNextX96 > request.sqrtPriceLimitX96
+            ) ? request.sqrtPriceLimitX96 : sqrtPriceNextX96,
+            liquidity,
+            request.amountSpecified,
+            fee,
+            uint64(block.timestamp),
+            // No liquidity threshold: the swap settles against this pool's own liquidity.
+            uint128(0),
+            bytes("")
+        );
+    }
+
     /// @notice Callback function logic for processing verified journals from Bonsai.
+    /// @dev The journal ends with the (uint64, uint64) timestamp range of the pool state it was
+    /// computed from, which relays use to refuse stale results, and the (uint128, uint128)
+    /// liquidity and liquidity threshold. They are not decoded here, as RequestHasNotTimedout
+    /// already bounds the age of a settlement and the liquidity is this pool's own.
     function settleSwap(bytes32 request_root, uint160 sqrt_p, uint256 amount_in, uint256 amount_out, uint256 fee_amount)
         external
         onlyBonsaiCallback(swapImageId)
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: BUSL-1.1
+pragma solidity ^0.8.14;
+
+import {IRiscZeroVerifier} from "bonsai/IRiscZeroVerifier.sol";
+
+/// @notice Keeps a rolling commitment to a pool's oracle observation history,
+/// extended by proofs of the HISTORY guest. Each proof only covers the
+/// observations since the previous one, and is accepted only if it extends
+/// the commitment held here, so the history is never proven twice. The
+/// pinned zkVM cannot verify the previous receipt inside the guest, so this
+/// check takes the place of a receipt assumption.
+/// @dev The commitment is keccak256(abi.encode(pool, count, peaks)) of a
+/// Merkle mountain range with a leaf keccak256(abi.encode(uint32 timestamp,
+/// int56 tickCumulative)) per observation and peaks largest first. The
+/// journal is (address pool, bytes32 previous_root, bytes32 root, uint64
+/// count, bytes32[] peaks, uint64 observed_from, uint64 observed_to, bytes32
+/// block_hash, uint8 checks, uint64 block_number).
+contract ZkObservationHistory {
+    error InvalidProof();
+    error WrongPool(address expected, address found);
+    error UnexpectedRoot(bytes32 expected, bytes32 found);
+    error LeafOutOfRange(uint64 index, uint64 count);
+    error UnknownBlock(uint64 number, bytes32 blockHash);
+
+    event HistoryExtended(bytes32 indexed root, uint64 count, uint64 observedTo);
+
+    IRiscZeroVerifier public immutable verifier;
+    bytes32 public immutable imageId;
+    address public immutable pool;
+
+    bytes32 public root;
+    uint64 public count;
+    /// @notice Timestamp of the newest observation in the history.
+    uint64 public latestTimestamp;
+    bytes32[] internal peaks;
+
+    constructor(IRiscZeroVerifier verifier_, bytes32 imageId_, address pool_) {
+        verifier = verifier_;
+        imageId = imageId_;
+        pool = pool_;
+        root = keccak256(abi.encode(pool_, uint64(0), new bytes32[](0)));
+    }
+
+    /// @notice Append the observations proven by a HISTORY journal. Anyone
+    /// may submit, as the proof is verified, must extend the current root and
+    /// must be proven at one of the last 256 blocks of this chain.
+    function extend(bytes calldata journal, bytes calldata seal, bytes32 postStateDigest) external {
+        if (!verifier.verify(seal, imageId, postStateDigest, sha256(journal))) revert InvalidProof();
+        _checkBlock(journal);
+
+        (
+            address pool_,
+            bytes32 previousRoot,
+            bytes32 root_,
+            uint64 count_,
+            bytes32[] memory peaks_,
+            ,
+            uint64 observedTo,
+        ) = abi.decode(journal, (address, bytes32, bytes32, uint64, bytes32[], uint64, uint64, bytes32));
+        if (pool_ != pool) revert WrongPool(pool, pool_);
+        if (previousRoot != root) revert UnexpectedRoot(root, previousRoot);
+
+        root = root_;
+        count = count_;
+        peaks = peaks_;
+        latestTimestamp = observedTo;
+        emit HistoryExtended(root_, count_, observedTo);
+    }
+
+    /// @dev Checks the journal's block is canonical, as the guest only proves
+    /// the observations against the header it was given.
+    function _checkBlock(bytes calldata journal) internal view {
+        (,,,,,,, bytes32 blockHash,, uint64 blockNumber) = abi.decode(
+            journal, (address, bytes32, bytes32, uint64, bytes32[], uint64, uint64, bytes32, uint8, uint64)
+        );
+        if (blockhash(blockNumber) != blockHash) revert UnknownBlock(blockNumber, blockHash);
+    }
+
+    function getPeaks() external view returns (bytes32[] memory) {
+        return peaks;
+    }
+
+    /// @notice Whether the observation at `timestamp` with `tickCumulative`
+    /// is leaf `index` of the history, given the sibling hashes from the
+    /// leaf up to its peak.
+    function verifyObservation(uint64 index, uint32 timestamp, int56 tickCumulative, bytes32[] calldata proof)
+        external
+        view
+        returns (bool)
+    {
+        if (index >= count) revert LeafOutOfRange(index, count);
+        // Find the peak whose mountain holds the leaf: mountains are the set
+        // bits of `count`, largest first.
+        uint64 offset = 0;
+        uint256 peak = 0;
+        uint256 height = 64;
+        while (true) {
+            height--;
+            uint64 size = uint64(1) << height;
+            if (count & size == 0) continue;
+            if (index < offset + size) break;
+            offset += size;
+            peak++;
+        }
+        if (proof.length != height) return false;
+
+        bytes32 node = keccak256(abi.encode(timestamp, tickCumulative));
+        uint64 position = index - offset;
+        for (uint256 i = 0; i < height; i++) {
+            node = (position >> i) & 1 == 0
+                ? keccak256(abi.encodePacked(node, proof[i]))
+                : keccak256(abi.encodePacked(proof[i], node));
+        }
+        return node == peaks[peak];
+    }
+}
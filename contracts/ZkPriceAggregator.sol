@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: BUSL-1.1
+pragma solidity ^0.8.14;
+
+import "prb-math/PRBMath.sol";
+
+import {IRiscZeroVerifier} from "bonsai/IRiscZeroVerifier.sol";
+
+import "./interfaces/AggregatorV3Interface.sol";
+
+/// @notice Serves pool prices proven by the SWAP guest through Chainlink's
+/// aggregator interface, so existing price feed consumers can read them
+/// without changes. The relay transmits each proven journal as a new round.
+/// @dev The journal is (bytes32 request_root, uint160 sqrt_p, uint256
+/// amount_in, uint256 amount_out, uint256 fee_amount, uint64 observed_from,
+/// uint64 observed_to, uint128 liquidity, uint128 min_liquidity, address pool,
+/// bytes32 block_hash, uint64 block_number, bool unfinalized). Answers are the price of one whole
+/// token0 in token1, or the inverse, with `decimals` decimals.
+/// Keepers read `checkUpdate` to only prove and transmit a round once the
+/// heartbeat has expired or the pool price deviates from the latest answer.
+/// Prices proven at less than `minLiquidity` are rejected, so a feed cannot be
+/// moved through a dust pool.
+/// Feeds updated together are deployed with a `ZkPriceBatcher` as `batcher`,
+/// which verifies one BATCH proof for all of them and records each price
+/// through `transmitBatched`.
+/// Updates served by the relay for the pull model are sent through `submit`
+/// by submitters the transmitter allows.
+/// The SWAP guest proves the price and liquidity against the pool's storage at
+/// the committed block, so journals are only accepted for `pool` at a block
+/// whose hash this chain still serves, i.e. within the last 256 blocks. Journals
+/// flagged `unfinalized` were read from a rollup sequencer that can still
+/// reorder the block; they are accepted, as a reorder of the block on this
+/// chain also reverts the round. Prices
+/// recorded through `transmitBatched` are taken from BATCH journals, whose
+/// pool states are not proven against a block.
+contract ZkPriceAggregator is AggregatorV3Interface {
+    struct Round {
+        int256 answer;
+        uint64 startedAt;
+        uint64 updatedAt;
+    }
+
+    error NotTransmitter();
+    error NotSubmitter();
+    error NotBatcher();
+    error InvalidProof();
+    error UnexpectedRound(uint80 expected, uint80 found);
+    error StaleObservation(uint64 latest, uint64 found);
+    error FutureObservation(uint64 found);
+    error InsufficientLiquidity(uint128 required, uint128 found);
+    error WrongPool(address expected, address found);
+    error UnknownBlock(uint64 number, bytes32 blockHash);
+    error NoDataPresent();
+
+    /// @notice Why a new round is due, as returned by `checkUpdate`.
+    enum UpdateReason {
+        None,
+        FirstRound,
+        Heartbeat,
+        Deviation
+    }
+
+    event AnswerUpdated(int256 indexed current, uint256 indexed roundId, uint256 updatedAt);
+    event SubmitterSet(address indexed submitter, bool allowed);
+
+    IRiscZeroVerifier public immutable verifier;
+    bytes32 public immutable imageId;
+    /// @notice Pool whose proven prices this feed serves.
+    address public immutable pool;
+    address public immutable transmitter;
+    /// @notice Contract allowed to record prices it verified as part of a
+    /// batch, or zero.
+    address public immutable batcher;
+    uint8 public immutable decimals;
+    uint8 public immutable decimals0;
+    uint8 public immutable decimals1;
+    /// @notice Whether answers are token0 per token1 rather than the pool's
+    /// own token1 per token0.
+    bool public immutable inverted;
+    /// @notice Longest time between rounds, in seconds.
+    uint64 public immutable heartbeat;
+    /// @notice Price deviation from the latest answer warranting a new round,
+    /// in basis points.
+    uint16 public immutable deviationThresholdBps;
+    /// @notice Least in-range pool liquidity a proven price is accepted at.
+    uint128 public immutable minLiquidity;
+    uint256 public constant version = 1;
+
+    string public description;
+    uint80 public latestRound;
+
+    mapping(uint80 => Round) internal rounds;
+    /// @notice Accounts allowed to `submit` updates.
+    mapping(address => bool) public submitters;
+
+    constructor(
+        IRiscZeroVerifier verifier_,
+        bytes32 imageId_,
+        address pool_,
+        address transmitter_,
+        address batcher_,
+        uint8 decimals_,
+        uint8 decimals0_,
+        uint8 decimals1_,
+        bool inverted_,
+        uint64 heartbeat_,
+        uint16 deviationThresholdBps_,
+        uint128 minLiquidity_,
+        string memory description_
+    ) {
+        verifier = verifier_;
+        imageId = imageId_;
+        pool = pool_;
+        transmitter = transmitter_;
+        batcher = batcher_;
+        decimals = decimals_;
+        decimals0 = decimals0_;
+        decimals1 = decimals1_;
+        inverted = inverted_;
+        heartbeat = heartbeat_;
+        deviationThresholdBps = deviationThresholdBps_;
+        minLiquidity = minLiquidity_;
+        description = description_;
+    }
+
+    /// @notice Record a proven journal as round `roundId`, which must follow
+    /// the latest round. Observations may not go back in time.
+    function transmit(uint80 roundId, bytes calldata journal, bytes calldata seal, bytes32 postStateDigest)
+        external
+    {
+        if (msg.sender != transmitter) revert NotTransmitter();
+        if (roundId != latestRound + 1) revert UnexpectedRound(latestRound + 1, roundId);
+        _record(roundId, journal, seal, postStateDigest, false);
+    }
+
+    /// @notice Allow or disallow `submitter` to `submit` updates.
+    function setSubmitter(address submitter, bool allowed) external {
+        if (msg.sender != transmitter) revert NotTransmitter();
+        submitters[submitter] = allowed;
+        emit SubmitterSet(submitter, allowed);
+    }
+
+    /// @notice Record a proven journal fetched from the relay API as the next
+    /// round. Only observations newer than the latest round are accepted.
+    function submit(bytes calldata journal, bytes calldata seal, bytes32 postStateDigest) external {
+        if (!submitters[msg.sender]) revert NotSubmitter();
+        _record(latestRound + 1, journal, seal, postStateDigest, true);
+    }
+
+    /// @notice Record a price the batcher proved along with other feeds as
+    /// the next round. Returns false, recording nothing, for observations not
+    /// newer than the latest round, from the future or proven at less than
+    /// `minLiquidity`, so one feed cannot hold back the rest of its batch.
+    function transmitBatched(uint160 sqrtPriceX96, uint64 observedFrom, uint64 observedTo, uint128 liquidity)
+        external
+        returns (bool)
+    {
+        if (msg.sender != batcher) revert NotBatcher();
+        uint64 latestUpdate = rounds[latestRound].updatedAt;
+        if (liquidity < minLiquidity || observedTo <= latestUpdate || observedTo > block.timestamp) return false;
+        _update(latestRound + 1, sqrtPriceX96, observedFrom, observedTo);
+        return true;
+    }
+
+    function _record(
+        uint80 roundId,
+        bytes calldata journal,
+        bytes calldata seal,
+        bytes32 postStateDigest,
+        bool strictlyNewer
+    ) internal {
+        if (!verifier.verify(seal, imageId, postStateDigest, sha256(journal))) revert InvalidProof();
+        _checkSource(journal);
+
+        (, uint160 sqrtPriceX96,,,, uint64 observedFrom, uint64 observedTo, uint128 liquidity,) =
+            abi.decode(journal, (bytes32, uint160, uint256, uint256, uint256, uint64, uint64, uint128, uint128));
+        if (liquidity < minLiquidity) revert InsufficientLiquidity(minLiquidity, liquidity);
+        // A round from the future would hold back every later one.
+        if (observedTo > block.timestamp) revert FutureObservation(observedTo);
+        uint64 latestUpdate = rounds[latestRound].updatedAt;
+        if (observedTo < latestUpdate || (strictlyNewer && observedTo == latestUpdate)) {
+            revert StaleObservation(latestUpdate, observedTo);
+        }
+        _update(roundId, sqrtPriceX96, observedFrom, observedTo);
+    }
+
+    /// @dev The pool and block the journal's state was proven at follow its first nine values. Unproven
+    /// journals commit a zero pool and block, which never match.
+    function _checkSource(bytes calldata journal) internal view {
+        (address provenPool, bytes32 blockHash, uint64 blockNumber) =
+            abi.decode(journal[288:], (address, bytes32, uint64));
+        if (provenPool != pool) revert WrongPool(pool, provenPool);
+        if (blockHash == bytes32(0) || blockhash(blockNumber) != blockHash) {
+            revert UnknownBlock(blockNumber, blockHash);
+        }
+    }
+
+    function _update(uint80 roundId, uint160 sqrtPriceX96, uint64 observedFrom, uint64 observedTo) internal {
+        int256 answer = int256(price(sqrtPriceX96));
+        rounds[roundId] = Round({answer: answer, startedAt: observedFrom, updatedAt: observedTo});
+        latestRound = roundId;
+
+        emit AnswerUpdated(answer, roundId, observedTo);
+    }
+
+    /// @notice Whether the pool being at `sqrtPriceX96` now warrants a new
+    /// round, and why.
+    function checkUpdate(uint160 sqrtPriceX96) external view returns (UpdateReason) {
+        Round memory latest = rounds[latestRound];
+        if (latest.updatedAt == 0) return UpdateReason.FirstRound;
+        if (block.timestamp >= uint256(latest.updatedAt) + heartbeat) return UpdateReason.Heartbeat;
+
+        uint256 previous = uint256(latest.answer);
+        uint256 current = price(sqrtPriceX96);
+        uint256 deviation = current > previous ? current - previous : previous - current;
+        if (deviation * 10_000 >= previous * deviationThresholdBps) return UpdateReason.Deviation;
+        return UpdateReason.None;
+    }
+
+    /// @notice Price at `sqrtPriceX96` in whole tokens, with `decimals`
+    /// decimals.
+    function price(uint160 sqrtPriceX96) public view returns (uint256) {
+        uint256 sqrtPrice = sqrtPriceX96;
+        uint8 baseDecimals = decimals0;
+        uint8 quoteDecimals = decimals1;
+        if (inverted) {
+            // sqrt(token0 / token1) as a Q64.96.
+            sqrtPrice = PRBMath.mulDiv(1 << 96, 1 << 96, sqrtPrice);
+            (baseDecimals, quoteDecimals) = (quoteDecimals, baseDecimals);
+        }
+        // sqrtPrice^2 / 2^192 * 10^baseDecimals / 10^quoteDecimals
+        uint256 priceX96 = PRBMath.mulDiv(sqrtPrice, sqrtPrice, 1 << 96);
+        return PRBMath.mulDiv(
+            priceX96, 10 ** (uint256(decimals) + baseDecimals), (10 ** uint256(quoteDecimals)) << 96
+        );
+    }
+
+    function getRoundData(uint80 _roundId)
+        public
+        view
+        returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)
+    {
+        Round memory round = rounds[_roundId];
+        if (round.updatedAt == 0) revert NoDataPresent();
+        return (_roundId, round.answer, round.startedAt, round.updatedAt, _roundId);
+    }
+
+    function latestRoundData()
+        external
+        view
+        returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)
+    {
+        return getRoundData(latestRound);
+    }
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: BUSL-1.1
+pragma solidity ^0.8.14;
+
+import {IRiscZeroVerifier} from "bonsai/IRiscZeroVerifier.sol";
+
+import "./ZkPriceAggregator.sol";
+
+/// @notice Updates many `ZkPriceAggregator` feeds from a single proof of the
+/// BATCH guest, so a set of pairs costs one verification and one transaction
+/// instead of one of each per pair.
+/// @dev The journal is (bytes32 request_root, uint64 observed_from, uint64
+/// observed_to, (address pool, uint8 status, uint160 sqrt_p, uint128
+/// liquidity, uint128 min_liquidity)[] prices, bool unfinalized), version 4
+/// of the BATCH journal, whose trailing flag is not read. Each price proven with `STATUS_OK` is recorded as the next round
+/// of the feed registered for its pool, which must be deployed with this
+/// contract as its `batcher`; pools the guest failed to price are skipped.
+contract ZkPriceBatcher {
+    struct PoolPrice {
+        address pool;
+        uint8 status;
+        uint160 sqrtPriceX96;
+        uint128 liquidity;
+        uint128 minLiquidity;
+    }
+
+    /// @notice Status of a price the guest proved. Others mean the guest
+    /// could not price the pool, as its liquidity was below the minimum (1) or
+    /// its swap step failed (2).
+    uint8 public constant STATUS_OK = 0;
+
+    error NotOwner();
+    error NotTransmitter();
+    error InvalidProof();
+    error UnknownPool(address pool);
+
+    event FeedSet(address indexed pool, address indexed feed);
+    /// @notice A feed refused the batch's price of its pool, as stale or
+    /// proven at too little liquidity.
+    event FeedSkipped(address indexed pool, address indexed feed);
+    /// @notice The guest could not price the pool, so the batch carries no
+    /// price for its feed.
+    event PriceFailed(address indexed pool, uint8 status);
+
+    IRiscZeroVerifier public immutable verifier;
+    bytes32 public immutable imageId;
+    address public immutable transmitter;
+    address public immutable owner;
+
+    /// @notice Feed updated with the price of each pool.
+    mapping(address => ZkPriceAggregator) public feeds;
+
+    constructor(IRiscZeroVerifier verifier_, bytes32 imageId_, address transmitter_, address owner_) {
+        verifier = verifier_;
+        imageId = imageId_;
+        transmitter = transmitter_;
+        owner = owner_;
+    }
+
+    /// @notice Register the feed updated with the price of `pool`, or
+    /// unregister it with the zero address.
+    function setFeed(address pool, ZkPriceAggregator feed) external {
+        if (msg.sender != owner) revert NotOwner();
+        feeds[pool] = feed;
+        emit FeedSet(pool, address(feed));
+    }
+
+    /// @notice Verify a BATCH journal once and record each of its proven
+    /// prices in the pool's feed. Returns the number of feeds updated.
+    function transmit(bytes calldata journal, bytes calldata seal, bytes32 postStateDigest)
+        external
+        returns (uint256 updated)
+    {
+        if (msg.sender != transmitter) revert NotTransmitter();
+        if (!verifier.verify(seal, imageId, postStateDigest, sha256(journal))) revert InvalidProof();
+
+        (, uint64 observedFrom, uint64 observedTo, PoolPrice[] memory prices) =
+            abi.decode(journal, (bytes32, uint64, uint64, PoolPrice[]));
+        for (uint256 i = 0; i < prices.length; i++) {
+            PoolPrice memory price = prices[i];
+            ZkPriceAggregator feed = feeds[price.pool];
+            if (address(feed) == address(0)) revert UnknownPool(price.pool);
+            if (price.status != STATUS_OK) {
+                emit PriceFailed(price.pool, price.status);
+                continue;
+            }
+            if (feed.transmitBatched(price.sqrtPriceX96, observedFrom, observedTo, price.liquidity)) {
+                updated++;
+            } else {
+                emit FeedSkipped(price.pool, address(feed));
+            }
+        }
+    }
+}
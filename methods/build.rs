@@ -12,6 +12,83 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+
 fn main() {
     risc0_build::embed_methods();
+    write_provenance();
+}
+
+/// Trimmed stdout of a command, if it ran successfully.
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Packages locked in a Cargo.lock, as `name`, `version`, `source` and
+/// `checksum` objects.
+fn locked_packages(lock: &str) -> Vec<Value> {
+    let mut packages = Vec::new();
+    for package in lock.split("[[package]]").skip(1) {
+        let mut entry = serde_json::Map::new();
+        for line in package.lines() {
+            let Some((key, value)) = line.split_once(" = \"") else {
+                continue;
+            };
+            if ["name", "version", "source", "checksum"].contains(&key) {
+                entry.insert(key.into(), value.trim_end_matches('"').into());
+            }
+        }
+        packages.push(Value::Object(entry));
+    }
+    packages
+}
+
+/// Record what the embedded guests were built from, the source commit, the
+/// toolchains and the guest crate's locked dependencies, as the relay's
+/// `BuildProvenance` in `$OUT_DIR/provenance.json`.
+fn write_provenance() {
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is not set");
+    let lock_path = Path::new(&dir).join("guest/Cargo.lock");
+    let lock = std::fs::read_to_string(&lock_path).unwrap_or_default();
+    let git = |args: &[&str]| {
+        let mut command = vec!["-C", &dir];
+        command.extend_from_slice(args);
+        output("git", &command)
+    };
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    // Honor reproducible build timestamps.
+    let built_at = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+    let provenance = json!({
+        "source_commit": git(&["rev-parse", "HEAD"]),
+        "source_dirty": git(&["status", "--porcelain", "--", "."]).map(|status| !status.is_empty()),
+        "host_rustc": output(&rustc, &["--version"]),
+        "guest_rustc": output("rustc", &["+risc0", "--version"]),
+        "built_at": built_at,
+        "dependencies": locked_packages(&lock),
+    });
+    let out = Path::new(&env::var("OUT_DIR").expect("OUT_DIR is not set")).join("provenance.json");
+    std::fs::write(&out, serde_json::to_vec_pretty(&provenance).unwrap())
+        .expect("failed to write provenance.json");
+    println!("cargo:rerun-if-changed={}", lock_path.display());
+    println!("cargo:rerun-if-changed={dir}/../.git/HEAD");
+    println!("cargo:rerun-if-changed={dir}/../.git/index");
 }
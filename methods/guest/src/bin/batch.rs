@@ -0,0 +1,118 @@
+#![no_main]
+
+use std::collections::BTreeSet;
+
+use bonsai_starter_methods_guest::{commit_journal, decode_canonical, read_input, InputSchema};
+use ethabi::{ethereum_types::U256, ParamType, Token};
+use ethers_core::types::I256;
+use uniswap_v3_math::swap_math::compute_swap_step;
+
+risc0_zkvm::guest::entry!(main);
+
+/// Must match the relay's `input_schema` and `input_schema_version`.
+const INPUT_SCHEMA: InputSchema = InputSchema {
+    name: "BATCH",
+    version: 2,
+};
+
+/// Version of the journal this guest commits. Must match the relay's
+/// `journal_version`.
+const JOURNAL_VERSION: u16 = 4;
+
+/// Status committed with each pool's price. A pool that cannot be priced
+/// fails on its own instead of failing the whole batch. Must match
+/// `ZkPriceBatcher` and the relay's `PriceStatus`.
+const STATUS_OK: u8 = 0;
+const STATUS_BELOW_MIN_LIQUIDITY: u8 = 1;
+const STATUS_SWAP_FAILED: u8 = 2;
+
+/// Read a uint input value, checking its width.
+fn into_uint(token: Token, bits: usize) -> U256 {
+    let value = token.into_uint().unwrap();
+    assert!(value.bits() <= bits, "input integer exceeds uint{bits}");
+    value
+}
+
+fn main() {
+    // The input is (bytes32 request_root, uint64 observed_at, Feed[] feeds),
+    // where each feed is a pool and the SWAP guest's inputs for it: (address
+    // pool, uint160 sqrt_p, uint160 sqrt_p_target, uint128 liquidity, int256
+    // amount, uint24 fee, uint128 min_liquidity), followed by (bool
+    // unfinalized), whether the relay read the block from a rollup sequencer
+    // that can still reorder it. All pools were read at the same block.
+    let input = read_input(&INPUT_SCHEMA);
+    let feed = ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Uint(160),
+        ParamType::Uint(160),
+        ParamType::Uint(128),
+        ParamType::Int(256),
+        ParamType::Uint(24),
+        ParamType::Uint(128),
+    ]);
+    let decoded = decode_canonical(
+        &[
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(feed)),
+            ParamType::Bool,
+        ],
+        &input.public,
+    );
+
+    let mut decoded = decoded.into_iter();
+    let request_root = decoded.next().unwrap().into_fixed_bytes().unwrap();
+    let observed_at = into_uint(decoded.next().unwrap(), 64);
+    let feeds = decoded.next().unwrap().into_array().unwrap();
+    let unfinalized = decoded.next().unwrap().into_bool().unwrap();
+    assert!(!feeds.is_empty(), "batch has no feeds");
+
+    let mut pools = BTreeSet::new();
+    let mut prices = Vec::new();
+    for feed in feeds {
+        let mut fields = feed.into_tuple().unwrap().into_iter();
+        let pool = fields.next().unwrap().into_address().unwrap();
+        assert!(pools.insert(pool), "pool {pool:?} is batched twice");
+        let price = into_uint(fields.next().unwrap(), 160);
+        let price_target = into_uint(fields.next().unwrap(), 160);
+        let liquidity = into_uint(fields.next().unwrap(), 128).as_u128();
+        let amount = I256::from_raw(fields.next().unwrap().into_int().unwrap());
+        let fee = into_uint(fields.next().unwrap(), 24).as_u32();
+        let min_liquidity = into_uint(fields.next().unwrap(), 128).as_u128();
+
+        // Refuse to prove prices of dust pools, which anyone can move
+        // cheaply, as the SWAP guest does. Failed pools commit a zero price.
+        let (status, sqrt_p) = if liquidity < min_liquidity {
+            (STATUS_BELOW_MIN_LIQUIDITY, U256::zero())
+        } else {
+            match compute_swap_step(price, price_target, liquidity, amount, fee) {
+                Ok((sqrt_p, ..)) => (STATUS_OK, sqrt_p),
+                Err(_) => (STATUS_SWAP_FAILED, U256::zero()),
+            }
+        };
+        prices.push(Token::Tuple(vec![
+            Token::Address(pool),
+            Token::Uint(status.into()),
+            Token::Uint(sqrt_p),
+            Token::Uint(liquidity.into()),
+            Token::Uint(min_liquidity.into()),
+        ]));
+    }
+
+    // Commit the journal read by `ZkPriceBatcher`, which records each price
+    // proven with `STATUS_OK` in the feed of its pool.
+    commit_journal(
+        JOURNAL_VERSION,
+        &[
+            Token::FixedBytes(request_root),
+            // Timestamp range of the state the prices were computed from, so
+            // relays can refuse to post stale results.
+            Token::Uint(observed_at),
+            Token::Uint(observed_at),
+            Token::Array(prices),
+            // Whether the block could still be reordered, so consumers that did
+            // not opt into unfinalized prices can reject them.
+            Token::Bool(unfinalized),
+        ],
+    );
+}
@@ -0,0 +1,128 @@
+#![no_main]
+
+use std::collections::BTreeSet;
+
+use bonsai_starter_methods_guest::{
+    commit_journal, decode_canonical, mpt,
+    pool::{into_proof, pool_address, sqrt_price_x96, SLOT0_SLOT},
+    read_input, InputSchema,
+};
+use ethabi::{ethereum_types::U256, ParamType, Token};
+use uniswap_v3_math::full_math::mul_div;
+
+risc0_zkvm::guest::entry!(main);
+
+/// Must match the relay's `input_schema` and `input_schema_version`.
+const INPUT_SCHEMA: InputSchema = InputSchema {
+    name: "CYCLE",
+    version: 1,
+};
+
+/// Version of the journal this guest commits. Must match the relay's
+/// `journal_version`.
+const JOURNAL_VERSION: u16 = 2;
+
+fn main() {
+    // The input is (address factory, bytes32 init_code_hash, bytes header,
+    // address start, Leg[] legs), where each leg is a pool (address token0,
+    // address token1, uint24 fee, bytes[] account_proof, bytes[]
+    // slot0_proof) and the legs trade `start` around the cycle back into
+    // itself.
+    let input = read_input(&INPUT_SCHEMA);
+    let proof = ParamType::Array(Box::new(ParamType::Bytes));
+    let leg = ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Address,
+        ParamType::Uint(24),
+        proof.clone(),
+        proof,
+    ]);
+    let decoded = decode_canonical(
+        &[
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Bytes,
+            ParamType::Address,
+            ParamType::Array(Box::new(leg)),
+        ],
+        &input.public,
+    );
+
+    let mut decoded = decoded.into_iter();
+    let factory = decoded.next().unwrap().into_address().unwrap();
+    let init_code_hash = decoded.next().unwrap().into_fixed_bytes().unwrap();
+    let header = mpt::decode_header(&decoded.next().unwrap().into_bytes().unwrap());
+    let start = decoded.next().unwrap().into_address().unwrap();
+    let legs = decoded.next().unwrap().into_array().unwrap();
+    assert!(legs.len() >= 2, "cycle has fewer than two legs");
+
+    // Square root of the factor in Q64.96, multiplied by each pool's
+    // sqrtPriceX96 when trading token0 for token1 and divided by it the
+    // other way round. Rounds down at every leg.
+    let q96 = U256::one() << 96;
+    let mut sqrt_factor_x96 = q96;
+    let mut token = start;
+    let mut pools = Vec::new();
+    let mut fees = Vec::new();
+    let mut seen = BTreeSet::new();
+    for leg in legs {
+        let mut fields = leg.into_tuple().unwrap().into_iter();
+        let token0 = fields.next().unwrap().into_address().unwrap();
+        let token1 = fields.next().unwrap().into_address().unwrap();
+        let fee = fields.next().unwrap().into_uint().unwrap();
+        assert!(fee.bits() <= 24, "input integer exceeds uint24");
+        assert!(token0 < token1, "pool tokens are not sorted");
+        let address = pool_address(factory, &init_code_hash, token0, token1, fee.as_u32());
+        assert!(seen.insert(address), "pool {address:?} is traded twice");
+
+        let storage_root = mpt::verify_account(
+            header.state_root,
+            address.as_fixed_bytes(),
+            &into_proof(fields.next().unwrap()),
+        );
+        let slot0 = mpt::verify_storage(
+            storage_root,
+            SLOT0_SLOT.into(),
+            &into_proof(fields.next().unwrap()),
+        );
+        let sqrt_price_x96 = sqrt_price_x96(slot0);
+
+        sqrt_factor_x96 = if token == token0 {
+            token = token1;
+            mul_div(sqrt_factor_x96, sqrt_price_x96, q96)
+        } else if token == token1 {
+            token = token0;
+            mul_div(sqrt_factor_x96, q96, sqrt_price_x96)
+        } else {
+            panic!("pool {address:?} does not trade {token:?}")
+        }
+        .expect("arbitrage factor overflows");
+        pools.push(Token::Address(address));
+        fees.push(Token::Uint(fee));
+    }
+    assert!(token == start, "legs do not trade back into {start:?}");
+    let factor_x96 =
+        mul_div(sqrt_factor_x96, sqrt_factor_x96, q96).expect("arbitrage factor overflows");
+
+    commit_journal(
+        JOURNAL_VERSION,
+        &[
+            Token::Address(start),
+            // Block the prices were proven at, which consumers must check is
+            // canonical, and its timestamp as the observation interval.
+            Token::FixedBytes(header.hash.to_vec()),
+            Token::Uint(header.timestamp.into()),
+            Token::Uint(header.timestamp.into()),
+            // Consumers must check the pools were deployed by the factory they
+            // expect.
+            Token::Address(factory),
+            Token::FixedBytes(init_code_hash),
+            Token::Array(pools),
+            Token::Array(fees),
+            // Units of `start` one unit buys around the cycle at the pools' mid
+            // prices, in Q96. Without dislocation it lies within the legs' fees
+            // of 1.
+            Token::Uint(factor_x96),
+        ],
+    );
+}
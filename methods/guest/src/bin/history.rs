@@ -0,0 +1,207 @@
+#![no_main]
+
+use bonsai_starter_methods_guest::{
+    commit_journal, decode_canonical, mpt,
+    pool::{
+        check_successor, decode_observation, decode_oracle, into_proof, OBSERVATIONS_SLOT,
+        OBSERVATION_CHECKS, SLOT0_SLOT,
+    },
+    read_input, InputSchema,
+};
+use ethabi::{
+    ethereum_types::{Address, U256},
+    ParamType, Token,
+};
+use ethers_core::{types::I256, utils::keccak256};
+
+risc0_zkvm::guest::entry!(main);
+
+/// Must match the relay's `input_schema` and `input_schema_version`.
+const INPUT_SCHEMA: InputSchema = InputSchema {
+    name: "HISTORY",
+    version: 1,
+};
+
+/// Version of the journal this guest commits. Must match the relay's
+/// `journal_version`.
+const JOURNAL_VERSION: u16 = 4;
+
+/// Commitment to a pool's observation history: a Merkle mountain range of
+/// `count` leaves with the given peaks, largest first. Must match
+/// `ZkObservationHistory`.
+fn commitment(pool: Address, count: u64, peaks: &[[u8; 32]]) -> [u8; 32] {
+    keccak256(ethabi::encode(&[
+        Token::Address(pool),
+        Token::Uint(count.into()),
+        Token::Array(
+            peaks
+                .iter()
+                .map(|peak| Token::FixedBytes(peak.to_vec()))
+                .collect(),
+        ),
+    ]))
+}
+
+/// Leaf committing to one observation, as
+/// `keccak256(abi.encode(uint32 timestamp, int56 tickCumulative))`.
+fn leaf(timestamp: u64, tick_cumulative: i64) -> [u8; 32] {
+    keccak256(ethabi::encode(&[
+        Token::Uint(timestamp.into()),
+        Token::Int(I256::from(tick_cumulative).into_raw()),
+    ]))
+}
+
+/// Append a leaf to the mountain range, merging the peaks of equal height
+/// it completes.
+fn append(peaks: &mut Vec<[u8; 32]>, count: &mut u64, leaf: [u8; 32]) {
+    let mut node = leaf;
+    let mut height = *count;
+    while height & 1 == 1 {
+        let left = peaks.pop().unwrap();
+        node = keccak256([left, node].concat());
+        height >>= 1;
+    }
+    peaks.push(node);
+    *count += 1;
+}
+
+fn main() {
+    // The input is (address pool, uint64 count, bytes32[] peaks, uint64
+    // last_timestamp, bytes header, bytes[] account_proof, bytes[]
+    // slot0_proof, uint16 first_index, bytes[][] observation_proofs): the
+    // history committed by the previous run, the timestamp of its last
+    // observation, and proofs of the pool's observations at one block from
+    // `first_index` through the newest, in ring order.
+    let input = read_input(&INPUT_SCHEMA);
+    let proof = ParamType::Array(Box::new(ParamType::Bytes));
+    let decoded = decode_canonical(
+        &[
+            ParamType::Address,
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+            ParamType::Uint(64),
+            ParamType::Bytes,
+            proof.clone(),
+            proof.clone(),
+            ParamType::Uint(16),
+            ParamType::Array(Box::new(proof)),
+        ],
+        &input.public,
+    );
+
+    let mut decoded = decoded.into_iter();
+    let pool = decoded.next().unwrap().into_address().unwrap();
+    let count = decoded.next().unwrap().into_uint().unwrap();
+    assert!(count.bits() <= 64, "input integer exceeds uint64");
+    let mut count = count.as_u64();
+    let mut peaks: Vec<[u8; 32]> = decoded
+        .next()
+        .unwrap()
+        .into_array()
+        .unwrap()
+        .into_iter()
+        .map(|peak| peak.into_fixed_bytes().unwrap().try_into().unwrap())
+        .collect();
+    assert!(
+        peaks.len() == count.count_ones() as usize,
+        "peaks do not match the history length"
+    );
+    let last_timestamp = decoded.next().unwrap().into_uint().unwrap();
+    assert!(last_timestamp.bits() <= 64, "input integer exceeds uint64");
+    let last_timestamp = last_timestamp.as_u64();
+    assert!(
+        count > 0 || last_timestamp == 0,
+        "empty history has a last observation"
+    );
+    // The previous run committed this root. Consumers chain runs by only
+    // accepting one whose previous root is the root they hold.
+    let previous_root = commitment(pool, count, &peaks);
+
+    let header = mpt::decode_header(&decoded.next().unwrap().into_bytes().unwrap());
+    let storage_root = mpt::verify_account(
+        header.state_root,
+        pool.as_fixed_bytes(),
+        &into_proof(decoded.next().unwrap()),
+    );
+    let slot0 = mpt::verify_storage(
+        storage_root,
+        SLOT0_SLOT.into(),
+        &into_proof(decoded.next().unwrap()),
+    );
+    let oracle = decode_oracle(slot0);
+    let (newest, cardinality) = (oracle.index, oracle.cardinality);
+
+    let first_index = decoded.next().unwrap().into_uint().unwrap();
+    assert!(first_index.bits() <= 16, "input integer exceeds uint16");
+    oracle.check_index(first_index.as_u64());
+    let first_index = first_index.as_u32();
+    let proofs = decoded.next().unwrap().into_array().unwrap();
+    // Observations from `first_index` through the newest, so none can be
+    // left out.
+    let len = (newest as u32 + cardinality as u32 - first_index) % cardinality as u32 + 1;
+    assert!(
+        proofs.len() == len as usize,
+        "observations do not run through the newest"
+    );
+
+    let mut observed = Vec::new();
+    let mut previous = None;
+    for (i, proof) in proofs.into_iter().enumerate() {
+        let index = (first_index + i as u32) % cardinality as u32;
+        let word = mpt::verify_storage(
+            storage_root,
+            U256::from(OBSERVATIONS_SLOT + index as u64),
+            &into_proof(proof),
+        );
+        let observation = decode_observation(word);
+        observation.check_read_at(header.timestamp);
+        if let Some(previous) = &previous {
+            check_successor(previous, &observation);
+        }
+        previous = Some(observation);
+        if i == 0 && count > 0 {
+            // The first observation links to the previous run: it is the
+            // last one committed, so everything after it is new.
+            assert!(
+                observation.timestamp == last_timestamp,
+                "observation {last_timestamp} was overwritten, leaving a gap in the history"
+            );
+            continue;
+        }
+        append(
+            &mut peaks,
+            &mut count,
+            leaf(observation.timestamp, observation.tick_cumulative),
+        );
+        observed.push(observation.timestamp);
+    }
+    let observed_from = observed.first().copied().unwrap_or(last_timestamp);
+    let observed_to = observed.last().copied().unwrap_or(last_timestamp);
+
+    commit_journal(
+        JOURNAL_VERSION,
+        &[
+            Token::Address(pool),
+            Token::FixedBytes(previous_root.to_vec()),
+            Token::FixedBytes(commitment(pool, count, &peaks).to_vec()),
+            Token::Uint(count.into()),
+            Token::Array(
+                peaks
+                    .iter()
+                    .map(|peak| Token::FixedBytes(peak.to_vec()))
+                    .collect(),
+            ),
+            // Timestamps of the observations appended by this run.
+            Token::Uint(observed_from.into()),
+            Token::Uint(observed_to.into()),
+            // Block the observations were proven at, which consumers must check
+            // is canonical.
+            Token::FixedBytes(header.hash.to_vec()),
+            // Sanity checks the observations passed.
+            Token::Uint(OBSERVATION_CHECKS.into()),
+            // Number of the block above, so contracts can check its hash with
+            // `blockhash`.
+            Token::Uint(header.number.into()),
+        ],
+    );
+}
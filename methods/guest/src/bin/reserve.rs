@@ -0,0 +1,208 @@
+#![no_main]
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use bonsai_starter_methods_guest::{
+    commit_journal, decode_canonical, mpt,
+    pool::{into_proof, pool_address, sqrt_price_x96, SLOT0_SLOT},
+    read_input, InputSchema,
+};
+use ethabi::{
+    ethereum_types::{Address, U256},
+    ParamType, Token,
+};
+use ethers_core::{types::I256, utils::keccak256};
+use uniswap_v3_math::{
+    sqrt_price_math::{_get_amount_0_delta, _get_amount_1_delta},
+    tick_math::get_sqrt_ratio_at_tick,
+};
+
+risc0_zkvm::guest::entry!(main);
+
+/// Must match the relay's `input_schema` and `input_schema_version`.
+const INPUT_SCHEMA: InputSchema = InputSchema {
+    name: "RESERVE",
+    version: 1,
+};
+
+/// Version of the journal this guest commits. Must match the relay's
+/// `journal_version`.
+const JOURNAL_VERSION: u16 = 2;
+
+/// Storage slot of the `positions` mapping in a Uniswap v3 pool.
+const POSITIONS_SLOT: u64 = 7;
+
+/// Storage slot of the `Position.Info` of `owner` over [tick_lower,
+/// tick_upper), keyed as in `Position.get`.
+fn position_slot(owner: Address, tick_lower: i32, tick_upper: i32) -> U256 {
+    let key = keccak256(
+        [
+            owner.as_bytes(),
+            &tick_lower.to_be_bytes()[1..],
+            &tick_upper.to_be_bytes()[1..],
+        ]
+        .concat(),
+    );
+    let mut slot = [0u8; 32];
+    U256::from(POSITIONS_SLOT).to_big_endian(&mut slot);
+    U256::from_big_endian(&keccak256([key, slot].concat()))
+}
+
+/// Read an int24 input value, checking its width.
+fn into_tick(token: Token) -> i32 {
+    let tick = I256::from_raw(token.into_int().unwrap());
+    assert!(tick.bits() <= 24, "input integer exceeds int24");
+    tick.as_i32()
+}
+
+/// Token amounts `liquidity` is worth over [tick_lower, tick_upper) at
+/// `sqrt_price_x96`, rounded down as when the position is burned.
+fn position_amounts(
+    sqrt_price_x96: U256,
+    tick_lower: i32,
+    tick_upper: i32,
+    liquidity: u128,
+) -> (U256, U256) {
+    let lower = get_sqrt_ratio_at_tick(tick_lower).unwrap();
+    let upper = get_sqrt_ratio_at_tick(tick_upper).unwrap();
+    if sqrt_price_x96 <= lower {
+        (
+            _get_amount_0_delta(lower, upper, liquidity, false).unwrap(),
+            U256::zero(),
+        )
+    } else if sqrt_price_x96 < upper {
+        (
+            _get_amount_0_delta(sqrt_price_x96, upper, liquidity, false).unwrap(),
+            _get_amount_1_delta(lower, sqrt_price_x96, liquidity, false).unwrap(),
+        )
+    } else {
+        (
+            U256::zero(),
+            _get_amount_1_delta(lower, upper, liquidity, false).unwrap(),
+        )
+    }
+}
+
+fn main() {
+    // The input is (address vault, address factory, bytes32 init_code_hash,
+    // bytes header, Pool[] pools), where each pool is (address token0,
+    // address token1, uint24 fee, bytes[] account_proof, bytes[] slot0_proof,
+    // Position[] positions) and each of the vault's positions in it is
+    // (int24 tick_lower, int24 tick_upper, bytes[] liquidity_proof,
+    // bytes[] tokens_owed_proof).
+    let input = read_input(&INPUT_SCHEMA);
+    let proof = ParamType::Array(Box::new(ParamType::Bytes));
+    let position = ParamType::Tuple(vec![
+        ParamType::Int(24),
+        ParamType::Int(24),
+        proof.clone(),
+        proof.clone(),
+    ]);
+    let pool = ParamType::Tuple(vec![
+        ParamType::Address,
+        ParamType::Address,
+        ParamType::Uint(24),
+        proof.clone(),
+        proof,
+        ParamType::Array(Box::new(position)),
+    ]);
+    let decoded = decode_canonical(
+        &[
+            ParamType::Address,
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Bytes,
+            ParamType::Array(Box::new(pool)),
+        ],
+        &input.public,
+    );
+
+    let mut decoded = decoded.into_iter();
+    let vault = decoded.next().unwrap().into_address().unwrap();
+    let factory = decoded.next().unwrap().into_address().unwrap();
+    let init_code_hash = decoded.next().unwrap().into_fixed_bytes().unwrap();
+    let header = mpt::decode_header(&decoded.next().unwrap().into_bytes().unwrap());
+    let pools = decoded.next().unwrap().into_array().unwrap();
+
+    // Reserves by token. Positions are only counted once, so that repeating
+    // them cannot inflate the reserves.
+    let mut reserves = BTreeMap::<Address, U256>::new();
+    let mut counted = BTreeSet::new();
+    for pool in pools {
+        let mut fields = pool.into_tuple().unwrap().into_iter();
+        let token0 = fields.next().unwrap().into_address().unwrap();
+        let token1 = fields.next().unwrap().into_address().unwrap();
+        let fee = fields.next().unwrap().into_uint().unwrap();
+        assert!(fee.bits() <= 24, "input integer exceeds uint24");
+        assert!(token0 < token1, "pool tokens are not sorted");
+        let address = pool_address(factory, &init_code_hash, token0, token1, fee.as_u32());
+
+        let storage_root = mpt::verify_account(
+            header.state_root,
+            address.as_fixed_bytes(),
+            &into_proof(fields.next().unwrap()),
+        );
+        let slot0 = mpt::verify_storage(
+            storage_root,
+            SLOT0_SLOT.into(),
+            &into_proof(fields.next().unwrap()),
+        );
+        let sqrt_price_x96 = sqrt_price_x96(slot0);
+
+        for position in fields.next().unwrap().into_array().unwrap() {
+            let mut position = position.into_tuple().unwrap().into_iter();
+            let tick_lower = into_tick(position.next().unwrap());
+            let tick_upper = into_tick(position.next().unwrap());
+            assert!(tick_lower < tick_upper, "position ticks are not ordered");
+            assert!(
+                counted.insert((address, tick_lower, tick_upper)),
+                "position is counted twice"
+            );
+
+            // Position.Info is (uint128 liquidity, uint256
+            // feeGrowthInside0LastX128, uint256 feeGrowthInside1LastX128,
+            // uint128 tokensOwed0, uint128 tokensOwed1).
+            let slot = position_slot(vault, tick_lower, tick_upper);
+            let liquidity =
+                mpt::verify_storage(storage_root, slot, &into_proof(position.next().unwrap()))
+                    .low_u128();
+            let owed = mpt::verify_storage(
+                storage_root,
+                slot + 3,
+                &into_proof(position.next().unwrap()),
+            );
+            let (amount0, amount1) =
+                position_amounts(sqrt_price_x96, tick_lower, tick_upper, liquidity);
+            for (token, amount) in [
+                (token0, amount0 + owed.low_u128()),
+                (token1, amount1 + (owed >> 128).low_u128()),
+            ] {
+                let total = reserves.entry(token).or_default();
+                *total = total.checked_add(amount).expect("reserves overflow");
+            }
+        }
+    }
+
+    let (tokens, amounts): (Vec<_>, Vec<_>) = reserves
+        .into_iter()
+        .map(|(token, amount)| (Token::Address(token), Token::Uint(amount)))
+        .unzip();
+    commit_journal(
+        JOURNAL_VERSION,
+        &[
+            Token::Address(vault),
+            // Block the reserves were proven at, which consumers must check is
+            // canonical, and its timestamp as the observation interval.
+            Token::FixedBytes(header.hash.to_vec()),
+            Token::Uint(header.timestamp.into()),
+            Token::Uint(header.timestamp.into()),
+            // Consumers must check the pools were deployed by the factory they
+            // expect.
+            Token::Address(factory),
+            Token::FixedBytes(init_code_hash),
+            Token::Array(tokens),
+            Token::Array(amounts),
+            Token::Uint(counted.len().into()),
+        ],
+    );
+}
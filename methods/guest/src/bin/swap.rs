@@ -1,20 +1,98 @@
 #![no_main]
 
-use std::io::Read;
-
-use ethabi::{ethereum_types::U256, FixedBytes, ParamType, Token};
-use risc0_zkvm::guest::env;
+use bonsai_starter_methods_guest::{
+    commit_journal, decode_canonical, mpt,
+    pool::{into_proof, sqrt_price_x96, LIQUIDITY_SLOT, SLOT0_SLOT},
+    read_input, InputSchema,
+};
+use ethabi::{
+    ethereum_types::{Address, U256},
+    FixedBytes, ParamType, Token,
+};
 use uniswap_v3_math::swap_math::compute_swap_step;
 
 risc0_zkvm::guest::entry!(main);
 
+/// Must match the relay's `input_schema` and `input_schema_version`.
+const INPUT_SCHEMA: InputSchema = InputSchema {
+    name: "SWAP",
+    version: 3,
+};
+
+/// Version of the journal this guest commits. Must match the relay's
+/// `journal_version`.
+const JOURNAL_VERSION: u16 = 4;
+
+/// Block and pool a proven pool state was read from.
+struct ProvenAt {
+    pool: Address,
+    block_hash: [u8; 32],
+    block_number: u64,
+    /// Whether the relay read the block from a rollup sequencer, which can
+    /// still reorder it.
+    unfinalized: bool,
+}
+
+/// Check the pool state of the input against a proof of the pool's storage
+/// at a block: (address pool, bytes header, bytes[] account_proof, bytes[]
+/// slot0_proof, bytes[] liquidity_proof, bool unfinalized).
+fn check_state(state: &[u8], price: U256, liquidity: u128, observed_at: U256) -> ProvenAt {
+    let proof = ParamType::Array(Box::new(ParamType::Bytes));
+    let decoded = decode_canonical(
+        &[
+            ParamType::Address,
+            ParamType::Bytes,
+            proof.clone(),
+            proof.clone(),
+            proof,
+            ParamType::Bool,
+        ],
+        state,
+    );
+    let mut decoded = decoded.into_iter();
+    let pool = decoded.next().unwrap().into_address().unwrap();
+    let header = mpt::decode_header(&decoded.next().unwrap().into_bytes().unwrap());
+    let storage_root = mpt::verify_account(
+        header.state_root,
+        pool.as_fixed_bytes(),
+        &into_proof(decoded.next().unwrap()),
+    );
+    let slot0 = mpt::verify_storage(
+        storage_root,
+        SLOT0_SLOT.into(),
+        &into_proof(decoded.next().unwrap()),
+    );
+    assert!(
+        sqrt_price_x96(slot0) == price,
+        "price is not the pool's price at the proven block"
+    );
+    let proven_liquidity = mpt::verify_storage(
+        storage_root,
+        LIQUIDITY_SLOT.into(),
+        &into_proof(decoded.next().unwrap()),
+    );
+    assert!(
+        proven_liquidity == U256::from(liquidity),
+        "liquidity {liquidity} is not the pool's liquidity {proven_liquidity} at the proven block"
+    );
+    assert!(
+        observed_at == U256::from(header.timestamp),
+        "observed_at is not the timestamp of the proven block"
+    );
+    ProvenAt {
+        pool,
+        block_hash: header.hash,
+        block_number: header.number,
+        unfinalized: decoded.next().unwrap().into_bool().unwrap(),
+    }
+}
+
 fn main() {
-    // Read data sent from the application contract.
-    let mut input_bytes = Vec::<u8>::new();
-    env::stdin().read_to_end(&mut input_bytes).unwrap();
-    // Type array passed to `ethabi::decode_whole` should match the types encoded in
-    // the application contract.
-    let input = ethabi::decode_whole(
+    // Read data sent from the application contract, or framed by the relay.
+    let input_bytes = read_input(&INPUT_SCHEMA).public;
+    // Type array passed to `decode_canonical` should match the types encoded
+    // in the application contract.
+    let input = decode_canonical(
         &[
             ParamType::FixedBytes(32), // request root
             ParamType::Uint(160),      // price
@@ -22,10 +100,28 @@ fn main() {
             ParamType::Uint(128),      // liquidity
             ParamType::Uint(256),      // amount
             ParamType::Uint(24),       // fee
+            ParamType::Uint(64),       // observed_at
+            ParamType::Uint(128),      // min_liquidity
+            ParamType::Bytes,          // state proof
         ],
         &input_bytes,
-    )
-    .unwrap();
+    );
+
+    // ABI decoding does not check integer widths on its own, so reject
+    // integers wider than their types, as the relay's input builder does.
+    for (token, bits) in [
+        (&input[1], 160),
+        (&input[2], 160),
+        (&input[3], 128),
+        (&input[5], 24),
+        (&input[6], 64),
+        (&input[7], 128),
+    ] {
+        assert!(
+            token.clone().into_uint().unwrap().bits() <= bits,
+            "input integer exceeds uint{bits}"
+        );
+    }
 
     let request_root: FixedBytes = input[0].clone().into_fixed_bytes().unwrap();
     let price: U256 = input[1].clone().into_uint().unwrap();
@@ -33,17 +129,52 @@ fn main() {
     let liquidity: u128 = input[3].clone().into_uint().unwrap().as_u128();
     let amount = ethers_core::types::I256::from_raw(input[4].clone().into_uint().unwrap());
     let fee: u32 = input[5].clone().into_uint().unwrap().as_u32();
+    // Block timestamp at which the pool state above was read.
+    let observed_at: U256 = input[6].clone().into_uint().unwrap();
+    // Least in-range liquidity for the pool's price to be worth proving.
+    let min_liquidity: u128 = input[7].clone().into_uint().unwrap().as_u128();
+
+    // Requests of the pool contract itself carry no state proof, as it reads
+    // its own state. Any other pool state must be proven, so consumers can
+    // trust the price and liquidity are the pool's.
+    let state = input[8].clone().into_bytes().unwrap();
+    let proven = (!state.is_empty()).then(|| check_state(&state, price, liquidity, observed_at));
+
+    // Refuse to prove prices of dust pools, which anyone can move cheaply.
+    assert!(
+        liquidity >= min_liquidity,
+        "pool liquidity {liquidity} is below the threshold {min_liquidity}"
+    );
 
     let (sqrt_p, amount_in, amount_out, fee_amount) =
         compute_swap_step(price, price_target, liquidity, amount, fee).unwrap();
 
     // Commit the journal that will be received by the application contract.
     // Encoded types should match the args expected by the application callback.
-    env::commit_slice(&ethabi::encode(&[
-        Token::FixedBytes(request_root),
-        Token::Uint(sqrt_p),
-        Token::Uint(amount_in),
-        Token::Uint(amount_out),
-        Token::Uint(fee_amount),
-    ]));
+    commit_journal(
+        JOURNAL_VERSION,
+        &[
+            Token::FixedBytes(request_root),
+            Token::Uint(sqrt_p),
+            Token::Uint(amount_in),
+            Token::Uint(amount_out),
+            Token::Uint(fee_amount),
+            // Timestamp range of the state the result was computed from, so relays
+            // can refuse to post stale results.
+            Token::Uint(observed_at),
+            Token::Uint(observed_at),
+            // Liquidity the result was computed with and the threshold it was
+            // checked against, so consumers can reject prices from dust pools.
+            Token::Uint(liquidity.into()),
+            Token::Uint(min_liquidity.into()),
+            // Pool and block the state was proven at, which consumers must check
+            // is canonical, or zero for unproven state.
+            Token::Address(proven.as_ref().map_or_else(Address::zero, |at| at.pool)),
+            Token::FixedBytes(proven.as_ref().map_or([0; 32], |at| at.block_hash).to_vec()),
+            Token::Uint(proven.as_ref().map_or(0, |at| at.block_number).into()),
+            // Whether that block could still be reordered, so consumers that did
+            // not opt into unfinalized prices can reject them.
+            Token::Bool(matches!(&proven, Some(at) if at.unfinalized)),
+        ],
+    );
 }
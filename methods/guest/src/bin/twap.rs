@@ -0,0 +1,128 @@
+#![no_main]
+
+use bonsai_starter_methods_guest::{
+    commit_journal, decode_canonical, mpt,
+    pool::{
+        check_successor, decode_observation, decode_oracle, into_proof, Observation,
+        OBSERVATIONS_SLOT, OBSERVATION_CHECKS, SLOT0_SLOT,
+    },
+    read_input, InputSchema,
+};
+use ethabi::{ethereum_types::Address, ParamType, Token};
+use uniswap_v3_math::tick_math::get_sqrt_ratio_at_tick;
+
+risc0_zkvm::guest::entry!(main);
+
+/// Must match the relay's `input_schema` and `input_schema_version`.
+const INPUT_SCHEMA: InputSchema = InputSchema {
+    name: "TWAP",
+    version: 2,
+};
+
+/// Version of the journal this guest commits. Must match the relay's
+/// `journal_version`.
+const JOURNAL_VERSION: u16 = 3;
+
+fn main() {
+    // The input is (address pool, Anchor[] anchors), where each anchor is a
+    // block whose state proves some of the pool's observations:
+    // (bytes header, bytes[] account_proof, bytes[] slot0_proof, (uint16
+    // index, bytes[] proof)[]). Windows longer than the ring buffer at a
+    // single block take several anchors, e.g. one block for each end of the
+    // window.
+    let input = read_input(&INPUT_SCHEMA);
+    let proof = ParamType::Array(Box::new(ParamType::Bytes));
+    let anchor = ParamType::Tuple(vec![
+        ParamType::Bytes, // RLP encoded block header
+        proof.clone(),    // account proof of the pool
+        proof.clone(),    // storage proof of slot0
+        ParamType::Array(Box::new(ParamType::Tuple(vec![
+            ParamType::Uint(16), // observation index
+            proof,               // storage proof of the observation
+        ]))),
+    ]);
+    let decoded = decode_canonical(
+        &[ParamType::Address, ParamType::Array(Box::new(anchor))],
+        &input.public,
+    );
+
+    let pool: Address = decoded[0].clone().into_address().unwrap();
+    let anchors = decoded[1].clone().into_array().unwrap();
+    assert!(!anchors.is_empty(), "input has no anchor blocks");
+
+    // Observations in the order proven, which must be ascending across all
+    // anchors, so each is proven once and none predates the one before it.
+    let mut first: Option<Observation> = None;
+    let mut last: Option<Observation> = None;
+    let mut block_hashes = Vec::new();
+    let mut last_block = None;
+    for anchor in anchors {
+        let mut fields = anchor.into_tuple().unwrap().into_iter();
+        let header = mpt::decode_header(&fields.next().unwrap().into_bytes().unwrap());
+        assert!(
+            last_block < Some(header.number),
+            "anchor blocks are not in ascending order"
+        );
+        last_block = Some(header.number);
+
+        let storage_root = mpt::verify_account(
+            header.state_root,
+            pool.as_fixed_bytes(),
+            &into_proof(fields.next().unwrap()),
+        );
+        let oracle = decode_oracle(mpt::verify_storage(
+            storage_root,
+            SLOT0_SLOT.into(),
+            &into_proof(fields.next().unwrap()),
+        ));
+        for proven in fields.next().unwrap().into_array().unwrap() {
+            let mut proven = proven.into_tuple().unwrap().into_iter();
+            let index = proven.next().unwrap().into_uint().unwrap();
+            assert!(index.bits() <= 16, "input integer exceeds uint16");
+            oracle.check_index(index.as_u64());
+            let word = mpt::verify_storage(
+                storage_root,
+                index + OBSERVATIONS_SLOT,
+                &into_proof(proven.next().unwrap()),
+            );
+            let observation = decode_observation(word);
+            observation.check_read_at(header.timestamp);
+            if let Some(last) = &last {
+                check_successor(last, &observation);
+            }
+            first.get_or_insert(observation);
+            last = Some(observation);
+        }
+        block_hashes.push(Token::FixedBytes(header.hash.to_vec()));
+    }
+
+    // The window covered is exactly the span between the earliest and the
+    // latest proven observation, whatever the requested window was.
+    let first = first.expect("input proves no observations");
+    let last = last.unwrap();
+    let (observed_from, observed_to) = (first.timestamp, last.timestamp);
+    assert!(
+        observed_to > observed_from,
+        "proven observations cover no time"
+    );
+    let elapsed = (observed_to - observed_from) as i64;
+    // Round towards negative infinity, as OracleLibrary.consult does.
+    let mean_tick = (last.tick_cumulative - first.tick_cumulative).div_euclid(elapsed) as i32;
+    let sqrt_price_x96 = get_sqrt_ratio_at_tick(mean_tick).unwrap();
+
+    commit_journal(
+        JOURNAL_VERSION,
+        &[
+            Token::Address(pool),
+            Token::Uint(sqrt_price_x96),
+            Token::Int(ethers_core::types::I256::from(mean_tick).into_raw()),
+            Token::Uint(observed_from.into()),
+            Token::Uint(observed_to.into()),
+            // Blocks the observations were proven at, which consumers must check
+            // are canonical.
+            Token::Array(block_hashes),
+            // Sanity checks the observations passed.
+            Token::Uint(OBSERVATION_CHECKS.into()),
+        ],
+    );
+}
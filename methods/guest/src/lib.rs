@@ -0,0 +1,169 @@
+//! Helpers shared by the guest binaries.
+
+use std::io::Read;
+
+use ethabi::{ParamType, Token};
+use risc0_zkvm::{
+    guest::env,
+    sha::{Impl, Sha256},
+};
+
+pub mod mpt;
+pub mod pool;
+pub mod sample;
+
+/// Prefix of the standard input frame, laid out as `INPUT_FRAME_MAGIC || u8
+/// frame version || 8 byte schema name || u16 LE schema version || u8 section
+/// count || (u32 LE length || section)*`, with the public input first and the
+/// private input, if any, second. Must match `INPUT_FRAME_MAGIC` in the
+/// relay's `input` module.
+pub const INPUT_FRAME_MAGIC: &[u8; 4] = b"RZIF";
+
+/// Version of the frame layout this library reads.
+pub const INPUT_FRAME_VERSION: u8 = 1;
+
+/// Prefix of an input split into a public and a private section, laid out as
+/// `PRIVATE_INPUT_MAGIC || u32 LE public length || public || private`.
+/// Must match `PRIVATE_INPUT_MAGIC` in the relay's `input` module.
+pub const PRIVATE_INPUT_MAGIC: &[u8; 4] = b"RZPI";
+
+/// Length of the salt starting every private section, laid out as `salt ||
+/// private input`. Must match `PRIVATE_SALT_LEN` in the relay's `input`
+/// module.
+pub const PRIVATE_SALT_LEN: usize = 32;
+
+/// Prefix of the trailer word ending every journal, laid out as
+/// `JOURNAL_VERSION_MAGIC || 26 zero bytes || u16 BE version`, after the ABI
+/// encoded values. Must match `JOURNAL_VERSION_MAGIC` in the relay's `schema`
+/// module.
+pub const JOURNAL_VERSION_MAGIC: &[u8; 4] = b"RZJV";
+
+/// Input schema a guest reads. Bump the version with the relay's
+/// `input_schema_version` whenever the input layout changes.
+pub struct InputSchema {
+    pub name: &'static str,
+    pub version: u16,
+}
+
+/// Input sent by the relay, split into its public and private sections.
+pub struct Input {
+    pub public: Vec<u8>,
+    /// Salt of the private input, empty without one.
+    pub salt: Vec<u8>,
+    pub private: Vec<u8>,
+}
+
+impl Input {
+    fn new(public: &[u8], private: &[u8]) -> Self {
+        let (salt, private) = match private.is_empty() {
+            true => (private, private),
+            false => {
+                assert!(
+                    private.len() >= PRIVATE_SALT_LEN,
+                    "private input section is shorter than its salt"
+                );
+                private.split_at(PRIVATE_SALT_LEN)
+            }
+        };
+        Input {
+            public: public.to_vec(),
+            salt: salt.to_vec(),
+            private: private.to_vec(),
+        }
+    }
+
+    /// SHA-256 digest of the salted private section. Guests taking private
+    /// input commit this as the last value of their journal instead of the
+    /// private input itself. The salt keeps the input from being recovered
+    /// by hashing candidates.
+    pub fn private_digest(&self) -> [u8; 32] {
+        Impl::hash_bytes(&[self.salt.as_slice(), &self.private].concat())
+            .as_bytes()
+            .try_into()
+            .unwrap()
+    }
+}
+
+/// Read all of stdin, rejecting input framed for another schema than
+/// `schema`. Unframed input, such as requests built by a contract, is public
+/// in its entirety unless split by the private input framing.
+pub fn read_input(schema: &InputSchema) -> Input {
+    let mut bytes = Vec::<u8>::new();
+    env::stdin().read_to_end(&mut bytes).unwrap();
+    if let Some(rest) = bytes.strip_prefix(INPUT_FRAME_MAGIC.as_slice()) {
+        return read_frame(schema, rest);
+    }
+    let Some(rest) = bytes.strip_prefix(PRIVATE_INPUT_MAGIC.as_slice()) else {
+        return Input::new(&bytes, &[]);
+    };
+    assert!(rest.len() >= 4, "framed input is truncated");
+    let (public_len, rest) = rest.split_at(4);
+    let public_len = u32::from_le_bytes(public_len.try_into().unwrap()) as usize;
+    assert!(rest.len() >= public_len, "framed input is truncated");
+    let (public, private) = rest.split_at(public_len);
+    Input::new(public, private)
+}
+
+/// ABI decode `bytes` against `schema`, rejecting any encoding but the
+/// canonical one the relay's input builder produces, so that equivalent
+/// requests always share the same input bytes. `decode_whole` only counts
+/// the head words of dynamic values, so it rejects every valid input holding
+/// any. Trailing bytes fail the re-encoding check instead.
+pub fn decode_canonical(schema: &[ParamType], bytes: &[u8]) -> Vec<Token> {
+    let tokens = ethabi::decode(schema, bytes).unwrap();
+    assert!(
+        ethabi::encode(&tokens) == bytes,
+        "input is not canonically encoded"
+    );
+    tokens
+}
+
+fn read_frame(schema: &InputSchema, frame: &[u8]) -> Input {
+    assert!(frame.len() >= 12, "input frame is truncated");
+    let (header, mut rest) = frame.split_at(12);
+    assert!(
+        header[0] == INPUT_FRAME_VERSION,
+        "input frame version {} is not supported, expected {INPUT_FRAME_VERSION}",
+        header[0]
+    );
+    let name = std::str::from_utf8(&header[1..9])
+        .unwrap_or_default()
+        .trim_end_matches('\0');
+    let version = u16::from_le_bytes([header[9], header[10]]);
+    assert!(
+        name == schema.name && version == schema.version,
+        "input was built for schema {name} v{version}, but this guest reads {} v{}",
+        schema.name,
+        schema.version
+    );
+    let count = header[11];
+    assert!(
+        (1..=2).contains(&count),
+        "input frame has {count} sections, expected 1 or 2"
+    );
+    let mut sections = Vec::new();
+    for _ in 0..count {
+        assert!(rest.len() >= 4, "input frame is truncated");
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+        assert!(tail.len() >= len, "input frame is truncated");
+        let (section, tail) = tail.split_at(len);
+        sections.push(section.to_vec());
+        rest = tail;
+    }
+    assert!(rest.is_empty(), "input frame has trailing bytes");
+    let mut sections = sections.into_iter();
+    let public = sections.next().unwrap();
+    Input::new(&public, &sections.next().unwrap_or_default())
+}
+
+/// Commit `values` ABI encoded as the journal, followed by the trailer
+/// tagging it with the guest's journal `version`. Bump the version with the
+/// relay's `journal_version` whenever the values change.
+pub fn commit_journal(version: u16, values: &[Token]) {
+    let mut journal = ethabi::encode(values);
+    journal.extend_from_slice(JOURNAL_VERSION_MAGIC);
+    journal.resize(journal.len() + 26, 0);
+    journal.extend_from_slice(&version.to_be_bytes());
+    env::commit_slice(&journal);
+}
@@ -0,0 +1,132 @@
+//! Verification of Ethereum state proofs, as returned by `eth_getProof`.
+//! Mirrors `verify_proof` in the relay's `proofs` module.
+
+use ethers_core::{
+    types::U256,
+    utils::{keccak256, rlp::Rlp},
+};
+
+/// Fields of a block header the guests rely on.
+pub struct Header {
+    pub hash: [u8; 32],
+    pub number: u64,
+    pub timestamp: u64,
+    pub state_root: [u8; 32],
+}
+
+/// Decode an RLP encoded block header, hashing it to its block hash.
+pub fn decode_header(rlp: &[u8]) -> Header {
+    let header = Rlp::new(rlp);
+    Header {
+        hash: keccak256(rlp),
+        state_root: header.at(3).unwrap().data().unwrap().try_into().unwrap(),
+        number: header.val_at(8).unwrap(),
+        timestamp: header.val_at(11).unwrap(),
+    }
+}
+
+fn nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Decode a hex-prefix encoded path into its nibbles and leaf flag.
+fn decode_path(encoded: &[u8]) -> (Vec<u8>, bool) {
+    let first = encoded[0];
+    let is_leaf = match first >> 4 {
+        0 | 1 => false,
+        2 | 3 => true,
+        flag => panic!("invalid trie node path flag {flag}"),
+    };
+    let mut path = nibbles(&encoded[1..]);
+    if first & 0x10 != 0 {
+        path.insert(0, first & 0x0f);
+    }
+    (path, is_leaf)
+}
+
+/// Walk a Merkle Patricia proof from `root` along `key`, returning the value
+/// stored there or `None` if the proof shows the key is absent. Panics if the
+/// proof does not hash up to `root`.
+pub fn verify_proof(root: [u8; 32], key: &[u8], proof: &[Vec<u8>]) -> Option<Vec<u8>> {
+    let path = nibbles(key);
+    let mut pos = 0;
+    let mut nodes = proof.iter();
+    let mut expected = root;
+    let mut node: Vec<u8> = Vec::new();
+    let mut inline = false;
+    loop {
+        if !inline {
+            let next = nodes.next().expect("proof ends before reaching the key");
+            assert!(
+                keccak256(next) == expected,
+                "proof node does not match its parent's hash"
+            );
+            node = next.clone();
+        }
+        let rlp = Rlp::new(&node);
+        let child = match rlp.item_count().unwrap() {
+            17 => {
+                if pos == path.len() {
+                    let value = rlp.at(16).unwrap().data().unwrap();
+                    return (!value.is_empty()).then(|| value.to_vec());
+                }
+                pos += 1;
+                rlp.at(path[pos - 1] as usize).unwrap()
+            }
+            2 => {
+                let (node_path, is_leaf) = decode_path(rlp.at(0).unwrap().data().unwrap());
+                let rest = &path[pos..];
+                if is_leaf {
+                    if rest != node_path.as_slice() {
+                        return None;
+                    }
+                    return Some(rlp.at(1).unwrap().data().unwrap().to_vec());
+                }
+                if !rest.starts_with(&node_path) {
+                    return None;
+                }
+                pos += node_path.len();
+                rlp.at(1).unwrap()
+            }
+            count => panic!("invalid trie node with {count} items"),
+        };
+        // Children are referenced by hash, or embedded when shorter than 32
+        // bytes.
+        if child.is_list() {
+            node = child.as_raw().to_vec();
+            inline = true;
+            continue;
+        }
+        let hash = child.data().unwrap();
+        if hash.is_empty() {
+            return None;
+        }
+        expected = hash.try_into().expect("invalid trie node reference");
+        inline = false;
+    }
+}
+
+/// Storage root of `account`, proven against the block's `state_root`.
+pub fn verify_account(state_root: [u8; 32], account: &[u8; 20], proof: &[Vec<u8>]) -> [u8; 32] {
+    let account = verify_proof(state_root, &keccak256(account), proof).expect("account is absent");
+    // [nonce, balance, storage_root, code_hash]
+    Rlp::new(&account)
+        .at(2)
+        .unwrap()
+        .data()
+        .unwrap()
+        .try_into()
+        .unwrap()
+}
+
+/// Value of storage `slot`, proven against the account's `storage_root`.
+pub fn verify_storage(storage_root: [u8; 32], slot: U256, proof: &[Vec<u8>]) -> U256 {
+    let mut key = [0u8; 32];
+    slot.to_big_endian(&mut key);
+    match verify_proof(storage_root, &keccak256(key), proof) {
+        Some(value) => Rlp::new(&value).as_val().unwrap(),
+        None => U256::zero(),
+    }
+}
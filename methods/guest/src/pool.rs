@@ -0,0 +1,160 @@
+//! Uniswap v3 pool identity and storage layout.
+
+use ethabi::{
+    ethereum_types::{Address, U256},
+    Token,
+};
+use ethers_core::utils::keccak256;
+
+/// Storage slot of `slot0` in a Uniswap v3 pool.
+pub const SLOT0_SLOT: u64 = 0;
+/// Storage slot of the in-range `liquidity` in a Uniswap v3 pool.
+pub const LIQUIDITY_SLOT: u64 = 4;
+/// Storage slot of `observations[0]` in a Uniswap v3 pool.
+pub const OBSERVATIONS_SLOT: u64 = 8;
+
+/// Largest tick of a Uniswap v3 pool, so a tick cumulative grows by at most
+/// this much per second.
+pub const MAX_TICK: i64 = 887272;
+
+// Sanity checks of the observations a guest reads, committed to its journal
+// as a bitmask of the checks its image enforced. A guest panics instead of
+// committing when one fails, so consumers can require the checks they rely
+// on by requiring their bits.
+
+/// Every observation read is initialized.
+pub const CHECK_INITIALIZED: u8 = 1 << 0;
+/// Observation timestamps strictly increase, and none is later than the
+/// block it was read at.
+pub const CHECK_ASCENDING: u8 = 1 << 1;
+/// Every observation index lies within the ring buffer's cardinality.
+pub const CHECK_CARDINALITY: u8 = 1 << 2;
+/// Tick cumulatives of successive observations differ by at most
+/// [MAX_TICK] per second between them.
+pub const CHECK_TICK_BOUNDS: u8 = 1 << 3;
+/// The checks enforced by [decode_observation], [decode_oracle],
+/// [Oracle::check_index], [Observation::check_read_at] and
+/// [check_successor].
+pub const OBSERVATION_CHECKS: u8 =
+    CHECK_INITIALIZED | CHECK_ASCENDING | CHECK_CARDINALITY | CHECK_TICK_BOUNDS;
+
+/// An observation read from a pool's oracle ring buffer.
+#[derive(Debug, Clone, Copy)]
+pub struct Observation {
+    pub timestamp: u64,
+    pub tick_cumulative: i64,
+}
+
+/// Unpack an `Oracle.Observation` storage word: (uint32 blockTimestamp,
+/// int56 tickCumulative, uint160 secondsPerLiquidityCumulativeX128,
+/// bool initialized) from the least significant bits up.
+pub fn decode_observation(word: U256) -> Observation {
+    assert!(word.bit(248), "observation is not initialized");
+    let timestamp = (word & U256::from(u32::MAX)).as_u64();
+    // Sign extend the 56 bit tick cumulative.
+    let tick_cumulative = (((word >> 32).low_u64() << 8) as i64) >> 8;
+    Observation {
+        timestamp,
+        tick_cumulative,
+    }
+}
+
+impl Observation {
+    /// Check the observation was written no later than the block whose state
+    /// it was read from.
+    pub fn check_read_at(&self, block_timestamp: u64) {
+        assert!(
+            self.timestamp <= block_timestamp,
+            "observation at {} is later than its block at {block_timestamp}",
+            self.timestamp
+        );
+    }
+}
+
+/// Check `next` was written after `previous`: at a strictly later timestamp,
+/// with a tick cumulative no further from the previous one than the pool's
+/// tick range allows in between.
+pub fn check_successor(previous: &Observation, next: &Observation) {
+    assert!(
+        next.timestamp > previous.timestamp,
+        "observations are not in ascending order: {} follows {}",
+        next.timestamp,
+        previous.timestamp
+    );
+    let elapsed = (next.timestamp - previous.timestamp) as i128;
+    let delta = next.tick_cumulative as i128 - previous.tick_cumulative as i128;
+    assert!(
+        delta.abs() <= MAX_TICK as i128 * elapsed,
+        "tick cumulative moves by {delta} in {elapsed}s between observations at {} and {}",
+        previous.timestamp,
+        next.timestamp
+    );
+}
+
+/// State of a pool's oracle ring buffer, read from `slot0`.
+pub struct Oracle {
+    /// Index of the newest observation.
+    pub index: u16,
+    /// Number of observations in the ring buffer.
+    pub cardinality: u16,
+}
+
+impl Oracle {
+    /// Check `index` lies within the ring buffer.
+    pub fn check_index(&self, index: u64) {
+        assert!(
+            index < self.cardinality as u64,
+            "observation {index} is outside the ring of {} observations",
+            self.cardinality
+        );
+    }
+}
+
+/// Unpack the oracle state of a `slot0` value, which packs (uint160
+/// sqrtPriceX96, int24 tick, uint16 observationIndex, uint16
+/// observationCardinality, ...) from the least significant bits up, checking
+/// the pool has observations.
+pub fn decode_oracle(slot0: U256) -> Oracle {
+    let index = ((slot0 >> 184).low_u64() & 0xffff) as u16;
+    let cardinality = ((slot0 >> 200).low_u64() & 0xffff) as u16;
+    assert!(cardinality > 0, "pool has no observations");
+    let oracle = Oracle { index, cardinality };
+    oracle.check_index(index.into());
+    oracle
+}
+
+/// Address of the pool `factory` deploys for the token pair and fee, which
+/// proves the pool's tokens without trusting the pool's code.
+pub fn pool_address(
+    factory: Address,
+    init_code_hash: &[u8],
+    token0: Address,
+    token1: Address,
+    fee: u32,
+) -> Address {
+    let salt = keccak256(ethabi::encode(&[
+        Token::Address(token0),
+        Token::Address(token1),
+        Token::Uint(fee.into()),
+    ]));
+    let hash = keccak256([&[0xff], factory.as_bytes(), &salt, init_code_hash].concat());
+    Address::from_slice(&hash[12..])
+}
+
+/// `sqrtPriceX96` packed in the low 160 bits of a `slot0` value, checking
+/// the pool is initialized.
+pub fn sqrt_price_x96(slot0: U256) -> U256 {
+    let sqrt_price_x96 = slot0 & ((U256::one() << 160) - 1);
+    assert!(!sqrt_price_x96.is_zero(), "pool is not initialized");
+    sqrt_price_x96
+}
+
+/// Read a Merkle proof input value.
+pub fn into_proof(token: Token) -> Vec<Vec<u8>> {
+    token
+        .into_array()
+        .unwrap()
+        .into_iter()
+        .map(|node| node.into_bytes().unwrap())
+        .collect()
+}
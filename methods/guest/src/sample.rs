@@ -0,0 +1,110 @@
+//! Sampling of blocks from randomness the guest derives itself, so the host
+//! cannot pick which blocks a sampling guest looks at. Mirrors
+//! `sample_blocks` in the relay's `sample` module.
+
+use ethers_core::{types::U256, utils::keccak256};
+
+use crate::mpt::Header;
+
+/// Randomness drawn from the hash of a seed block. Draw `i` is
+/// `keccak256(seed || u64 BE i)` as a big endian integer, so contracts can
+/// reproduce it with `keccak256(abi.encodePacked(seed, uint64(i)))`.
+pub struct Sampler {
+    seed: [u8; 32],
+    draws: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self { seed, draws: 0 }
+    }
+
+    /// Next draw, reduced below `bound`. The bias of reducing a 256 bit draw
+    /// is negligible for any 64 bit bound.
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "cannot sample below 0");
+        let mut preimage = [0u8; 40];
+        preimage[..32].copy_from_slice(&self.seed);
+        preimage[32..].copy_from_slice(&self.draws.to_be_bytes());
+        self.draws += 1;
+        (U256::from_big_endian(&keccak256(preimage)) % bound).as_u64()
+    }
+}
+
+/// Sample `count` distinct block numbers from `from` up to, but excluding,
+/// the `seed` block, in ascending order. Draws landing on a block already
+/// sampled are skipped. The seed block comes after every sampled block, so
+/// their state was settled before the randomness choosing them existed.
+///
+/// The seed only binds the sample if the guest commits `seed.hash` to its
+/// journal and consumers check it is the hash of canonical block
+/// `seed.number`, such as with `blockhash`. A header the host made up
+/// decodes just as well, and would let it grind for the sample it wants.
+pub fn sample_blocks(seed: &Header, from: u64, count: usize) -> Vec<u64> {
+    assert!(
+        from < seed.number,
+        "seed block {} does not follow the sampled range starting at {from}",
+        seed.number
+    );
+    let span = seed.number - from;
+    assert!(
+        count as u64 <= span,
+        "cannot sample {count} distinct blocks from a range of {span}"
+    );
+    let mut sampler = Sampler::new(seed.hash);
+    let mut blocks = Vec::with_capacity(count);
+    while blocks.len() < count {
+        let block = from + sampler.next_below(span);
+        if !blocks.contains(&block) {
+            blocks.push(block);
+        }
+    }
+    blocks.sort_unstable();
+    blocks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Pinned on both sides: the relay's `sample` module tests the same seed
+    // against the same blocks.
+    fn seed(number: u64) -> Header {
+        Header {
+            hash: [0x11; 32],
+            number,
+            timestamp: 0,
+            state_root: [0; 32],
+        }
+    }
+
+    #[test]
+    fn draws_are_pinned() {
+        let mut sampler = Sampler::new([0x11; 32]);
+        let draws: Vec<u64> = (0..3).map(|_| sampler.next_below(u64::MAX)).collect();
+        assert_eq!(
+            draws,
+            [
+                10_502_111_810_833_164_795,
+                11_830_571_387_132_732_415,
+                206_731_749_061_364_343
+            ]
+        );
+    }
+
+    #[test]
+    fn sample_blocks_is_pinned() {
+        assert_eq!(
+            sample_blocks(&seed(1_000), 900, 5),
+            [915, 930, 933, 962, 973]
+        );
+        // Every block of the range, despite repeated draws.
+        assert_eq!(sample_blocks(&seed(1_000), 996, 4), [996, 997, 998, 999]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot sample 11 distinct blocks")]
+    fn sample_blocks_rejects_oversized_samples() {
+        sample_blocks(&seed(1_000), 990, 11);
+    }
+}
@@ -14,3 +14,7 @@
 
 //! Generated crate containing the image ID and ELF binary of the build guest.
 include!(concat!(env!("OUT_DIR"), "/methods.rs"));
+
+/// Provenance of the embedded guests' build as JSON: the source commit, the
+/// toolchains and the guest crate's locked dependencies.
+pub const PROVENANCE: &str = include_str!(concat!(env!("OUT_DIR"), "/provenance.json"));
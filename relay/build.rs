@@ -0,0 +1,35 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+fn main() {
+    // Link flags the Node.js addon needs on some platforms.
+    #[cfg(feature = "node")]
+    napi_build::setup();
+
+    // Header of the C ABI in src/ffi.rs.
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is not set");
+        let config = cbindgen::Config::from_file(format!("{dir}/cbindgen.toml"))
+            .expect("invalid cbindgen.toml");
+        cbindgen::Builder::new()
+            .with_config(config)
+            .with_src(format!("{dir}/src/ffi.rs"))
+            .generate()
+            .expect("failed to generate the C header")
+            .write_to_file(format!("{dir}/include/relay.h"));
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+    }
+}
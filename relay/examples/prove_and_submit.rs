@@ -0,0 +1,54 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use bonsai_ethereum_relay_cli::oneshot::{prove_and_submit, Chain, Window};
+use clap::Parser;
+use ethers::types::Address;
+
+/// Example code proving a pool's TWAP on Bonsai and submitting it to a
+/// consumer contract in a single call.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about)]
+struct Args {
+    /// Address of the pool.
+    pool: Address,
+
+    /// Address of the consumer contract taking `submit(bytes,bytes,bytes32)`.
+    consumer: Address,
+
+    /// Length of the window ending now, in seconds.
+    #[arg(long, default_value_t = 1800)]
+    window_secs: u64,
+
+    /// Ethereum Node endpoint.
+    #[arg(long, env, default_value = "ws://localhost:8545")]
+    eth_node: String,
+
+    /// Private key of the account paying for the submission, as a hex
+    /// string.
+    #[arg(long, env)]
+    private_key: String,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let chain = Chain::new(args.eth_node, args.private_key, args.consumer);
+    let window = Window::last(Duration::from_secs(args.window_secs));
+    let tx_hash = prove_and_submit(args.pool, window, &chain).await?;
+    println!("{tx_hash:?}");
+    Ok(())
+}
@@ -0,0 +1,21 @@
+// Types of the relay's Node.js addon, see src/node.rs. Integers are decimal
+// strings, and addresses and bytes 0x-prefixed hex strings.
+
+export type AbiValue = string | boolean | AbiValue[]
+
+/** Build the canonical input of a guest from its values and an optional private section. */
+export function buildInput(guest: string, values: AbiValue[], privateInput?: Buffer | null): Buffer
+/** Rewrite an input into the canonical form the relay proves. */
+export function canonicalizeInput(guest: string, input: Buffer): Buffer
+/** Decode the public section of a guest's input into its values. */
+export function decodeInput(guest: string, input: Buffer): AbiValue[]
+/** Decode a guest's journal into its values, at the journal's version. */
+export function decodeJournal(guest: string, journal: Buffer): AbiValue[]
+/** Version of a journal, 1 for journals without a version trailer. */
+export function journalVersion(journal: Buffer): number
+/** The journal's values ABI encoded as a single tuple. */
+export function publicValues(guest: string, journal: Buffer): Buffer
+/** Solidity types of a guest's input values. */
+export function inputTypes(guest: string): string[] | null
+/** Solidity types of the values of a guest's journal. */
+export function journalTypes(guest: string): string[] | null
@@ -0,0 +1,164 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use ethers::types::Address;
+use serde::Deserialize;
+
+/// Access policy for requester addresses, as found in the requester policy
+/// file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RequesterPolicyConfig {
+    /// Only these requesters are served, if given.
+    pub allow: Option<Vec<Address>>,
+    /// These requesters are never served.
+    pub deny: Vec<Address>,
+    /// Requests accepted from each requester per quota window.
+    pub quota: Option<u32>,
+    /// Per-requester overrides of `quota`.
+    pub quotas: HashMap<Address, u32>,
+    /// Length of the quota window, in seconds. Defaults to an hour.
+    pub quota_window_secs: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Rejection {
+    #[error("requester {0:?} is denied")]
+    Denied(Address),
+    #[error("requester {0:?} is not on the allowlist")]
+    NotAllowed(Address),
+    #[error("requester {0:?} exceeded its quota of {1} requests")]
+    QuotaExceeded(Address, u32),
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+/// The parts of a [RequesterPolicy] that a reload replaces.
+struct Rules {
+    config: RequesterPolicyConfig,
+    allow: Option<HashSet<Address>>,
+    deny: HashSet<Address>,
+    window: Duration,
+}
+
+impl Rules {
+    fn new(config: RequesterPolicyConfig) -> Self {
+        Self {
+            allow: config
+                .allow
+                .as_ref()
+                .map(|allow| allow.iter().copied().collect()),
+            deny: config.deny.iter().copied().collect(),
+            window: Duration::from_secs(config.quota_window_secs.unwrap_or(60 * 60)),
+            config,
+        }
+    }
+}
+
+/// Decides which requesters the relay serves, so a public request contract
+/// cannot be used to drain the operator's proving budget.
+pub struct RequesterPolicy {
+    rules: RwLock<Rules>,
+    windows: Mutex<HashMap<Address, Window>>,
+}
+
+impl RequesterPolicy {
+    pub fn new(config: RequesterPolicyConfig) -> Self {
+        Self {
+            rules: RwLock::new(Rules::new(config)),
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Load a JSON [RequesterPolicyConfig].
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(Self::new(Self::load_config(path)?))
+    }
+
+    pub fn load_config(path: &Path) -> Result<RequesterPolicyConfig> {
+        let file = std::fs::File::open(path).context(format!(
+            "Failed to open requester policy {}",
+            path.display()
+        ))?;
+        serde_json::from_reader(file).context(format!(
+            "Failed to parse requester policy {}",
+            path.display()
+        ))
+    }
+
+    /// Replace the allow and deny lists and quotas. Requests already counted
+    /// in the current quota windows stay counted.
+    pub fn reload(&self, config: RequesterPolicyConfig) {
+        let mut rules = match self.rules.write() {
+            Ok(rules) => rules,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *rules = Rules::new(config);
+    }
+
+    /// Check a request from `requester`, counting it against its quota if
+    /// accepted.
+    pub fn admit(&self, requester: Address) -> Result<(), Rejection> {
+        let rules = match self.rules.read() {
+            Ok(rules) => rules,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if rules.deny.contains(&requester) {
+            return Err(Rejection::Denied(requester));
+        }
+        if let Some(allow) = &rules.allow {
+            if !allow.contains(&requester) {
+                return Err(Rejection::NotAllowed(requester));
+            }
+        }
+        let Some(quota) = rules
+            .config
+            .quotas
+            .get(&requester)
+            .or(rules.config.quota.as_ref())
+        else {
+            return Ok(());
+        };
+
+        let mut windows = match self.windows.lock() {
+            Ok(windows) => windows,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let now = Instant::now();
+        let window = windows.entry(requester).or_insert(Window {
+            start: now,
+            count: 0,
+        });
+        if now.duration_since(window.start) >= rules.window {
+            window.start = now;
+            window.count = 0;
+        }
+        if window.count >= *quota {
+            return Err(Rejection::QuotaExceeded(requester, *quota));
+        }
+        window.count += 1;
+        Ok(())
+    }
+}
@@ -0,0 +1,286 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use ethers::types::{Address, TxHash, U256, U64};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    bindings::{ZkPriceAggregator, ZkPriceBatcher},
+    deadletter::{DeadLetter, DeadLetters},
+    elog,
+    eth::EthClient,
+    scheduler::{FeedRound, ProofResult, RunLog},
+    schema::{batch_prices, PriceStatus},
+    snark_seal, Output,
+};
+
+/// Journal, seal and post state digest of a result, as the price feed
+/// contracts verify them.
+fn proven(output: &Output) -> Result<(Vec<u8>, Vec<u8>, [u8; 32])> {
+    match output {
+        Output::Bonsai {
+            journal,
+            receipt_metadata,
+            snark_proof,
+            ..
+        } => Ok((
+            journal.clone(),
+            snark_seal(snark_proof)?,
+            receipt_metadata.post.digest().into(),
+        )),
+        Output::Execution { .. } | Output::Stark { .. } => {
+            bail!("only results with a SNARK can be transmitted to an aggregator")
+        }
+    }
+}
+
+/// Transmits the proven prices of a scheduled job as rounds of a
+/// `ZkPriceAggregator`, which serves them to consumers through Chainlink's
+/// `AggregatorV3Interface`.
+pub struct AggregatorFeed {
+    contract: ZkPriceAggregator<EthClient>,
+    run_log: Option<Arc<RunLog>>,
+}
+
+impl AggregatorFeed {
+    pub fn new(address: Address, client: Arc<EthClient>) -> Self {
+        Self {
+            contract: ZkPriceAggregator::new(address, client),
+            run_log: None,
+        }
+    }
+
+    /// Keep each job's latest round in `run_log`, so results transmitted
+    /// before a restart are not transmitted again.
+    pub fn with_run_log(mut self, run_log: Arc<RunLog>) -> Self {
+        self.run_log = Some(run_log);
+        self
+    }
+
+    /// The round to transmit next. The contract's latest round is
+    /// authoritative; the job state only tells which round the relay last
+    /// sent, which differs from it if that transaction was lost.
+    async fn next_round(&self, job: &str, last: Option<&FeedRound>) -> Result<u64> {
+        let latest = self
+            .contract
+            .latest_round()
+            .call()
+            .await
+            .context("Failed to read the aggregator's latest round")?;
+        let latest = u64::try_from(latest).context("Aggregator round ID exceeds u64")?;
+        if let Some(last) = last.filter(|last| last.round_id != latest) {
+            elog!(
+                "Job {job} last transmitted round {} but the aggregator is at round {latest}",
+                last.round_id
+            );
+        }
+        Ok(latest + 1)
+    }
+
+    /// Transmit the result of a run as the aggregator's next round, unless
+    /// a later run was already transmitted.
+    pub async fn transmit(
+        &self,
+        job: &str,
+        run: u64,
+        output: &Output,
+    ) -> Result<Option<FeedRound>> {
+        let (journal, seal, post_state_digest) = proven(output)?;
+        let last = match &self.run_log {
+            Some(run_log) => run_log.last_round(job)?,
+            None => None,
+        };
+        if let Some(last) = last.as_ref().filter(|last| last.run >= run) {
+            elog!(
+                "Job {job} run {run} is not newer than run {} of round {}; skipping",
+                last.run,
+                last.round_id
+            );
+            return Ok(None);
+        }
+        let round_id = self.next_round(job, last.as_ref()).await?;
+        let call = self.contract.transmit(
+            round_id.into(),
+            journal.into(),
+            seal.into(),
+            post_state_digest,
+        );
+        call.call()
+            .await
+            .context(format!("Aggregator would reject round {round_id}"))?;
+        let receipt = call
+            .send()
+            .await
+            .context(format!("Failed to transmit round {round_id}"))?
+            .await
+            .context("Failed to await round transmission")?
+            .context("Round transmission was dropped")?;
+        if receipt.status != Some(U64::one()) {
+            bail!(
+                "round {round_id} transmission {:?} reverted",
+                receipt.transaction_hash
+            );
+        }
+        let round = FeedRound {
+            round_id,
+            run,
+            tx_hash: receipt.transaction_hash,
+        };
+        if let Some(run_log) = &self.run_log {
+            run_log.record_round(job, &round)?;
+        }
+        Ok(Some(round))
+    }
+
+    /// Transmit every successful run of a job until the job stops.
+    pub async fn watch(&self, job: &str, results: impl Stream<Item = ProofResult> + Unpin) {
+        let mut results = results;
+        while let Some(result) = results.next().await {
+            let Ok(output) = result.output else {
+                continue;
+            };
+            match self.transmit(job, result.run, &output).await {
+                Ok(Some(round)) => elog!(
+                    "Job {job} run {} transmitted as round {} in {:?}",
+                    result.run,
+                    round.round_id,
+                    round.tx_hash
+                ),
+                Ok(None) => (),
+                Err(err) => elog!("Job {job} run {} was not transmitted: {err:?}", result.run),
+            }
+        }
+    }
+}
+
+/// Transmits the proven prices of a scheduled batch to a `ZkPriceBatcher`,
+/// which verifies the proof once and records each price as the next round
+/// of the pool's aggregator. Pools the guest could not price are skipped by
+/// the batcher and routed to the dead-letter queue, if any, so they do not
+/// hold back the rest of the batch.
+pub struct BatchTransmitter {
+    contract: ZkPriceBatcher<EthClient>,
+    dead_letters: Option<Arc<DeadLetters>>,
+}
+
+impl BatchTransmitter {
+    pub fn new(address: Address, client: Arc<EthClient>) -> Self {
+        Self {
+            contract: ZkPriceBatcher::new(address, client),
+            dead_letters: None,
+        }
+    }
+
+    /// Keep the pools each run failed to price in `dead_letters`.
+    pub fn with_dead_letters(mut self, dead_letters: Arc<DeadLetters>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    /// Transmit the result of a run, returning the transaction and the
+    /// number of aggregators it updated. Aggregators skip prices not newer
+    /// than their latest round, so retransmitting a run updates none.
+    pub async fn transmit(&self, output: &Output) -> Result<(TxHash, U256)> {
+        let (journal, seal, post_state_digest) = proven(output)?;
+        let call = self
+            .contract
+            .transmit(journal.into(), seal.into(), post_state_digest);
+        let updated = call
+            .call()
+            .await
+            .context("Batcher would reject the batch")?;
+        let receipt = call
+            .send()
+            .await
+            .context("Failed to transmit batch")?
+            .await
+            .context("Failed to await batch transmission")?
+            .context("Batch transmission was dropped")?;
+        if receipt.status != Some(U64::one()) {
+            bail!("batch transmission {:?} reverted", receipt.transaction_hash);
+        }
+        Ok((receipt.transaction_hash, updated))
+    }
+
+    /// Dead-letter the pools a run failed to price, returning the number of
+    /// pools it did price.
+    fn route_failures(&self, job: &str, run: u64, output: &Output) -> Result<usize> {
+        let (journal, ..) = proven(output)?;
+        let prices = batch_prices(&journal)?;
+        let mut priced = 0;
+        for price in prices {
+            if price.status == PriceStatus::Ok {
+                priced += 1;
+                continue;
+            }
+            elog!(
+                "Batch {job} run {run} failed to price pool {:?}: {:?}",
+                price.pool,
+                price.status
+            );
+            if let Some(dead_letters) = &self.dead_letters {
+                let letter = DeadLetter::new(
+                    job,
+                    run,
+                    price.pool,
+                    price.status,
+                    format!(
+                        "liquidity {} with minimum {}",
+                        price.liquidity, price.min_liquidity
+                    ),
+                );
+                dead_letters.push(&letter)?;
+            }
+        }
+        Ok(priced)
+    }
+
+    /// Transmit every successful run of a batch job until the job stops.
+    /// Runs in which no pool could be priced are not transmitted.
+    pub async fn watch(&self, job: &str, results: impl Stream<Item = ProofResult> + Unpin) {
+        let mut results = results;
+        while let Some(result) = results.next().await {
+            let Ok(output) = result.output else {
+                continue;
+            };
+            match self.route_failures(job, result.run, &output) {
+                Ok(0) => {
+                    elog!(
+                        "Batch {job} run {} priced no pools; not transmitting",
+                        result.run
+                    );
+                    continue;
+                }
+                Ok(_) => (),
+                Err(err) => elog!(
+                    "Batch {job} run {} failures were not dead-lettered: {err:?}",
+                    result.run
+                ),
+            }
+            match self.transmit(&output).await {
+                Ok((tx_hash, updated)) => elog!(
+                    "Batch {job} run {} updated {updated} aggregators in {tx_hash:?}",
+                    result.run
+                ),
+                Err(err) => elog!(
+                    "Batch {job} run {} was not transmitted: {err:?}",
+                    result.run
+                ),
+            }
+        }
+    }
+}
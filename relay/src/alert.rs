@@ -0,0 +1,238 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{path::Path, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep_until, Instant};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    approval::exceeds_deviation, elog, redact::register_secret, scheduler::ProofResult,
+    schema::decode_journal, Output,
+};
+
+const PAGERDUTY_EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// Where alerts are delivered.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum AlertSink {
+    /// POST each [Alert] as JSON.
+    Webhook { url: String },
+    /// Trigger a PagerDuty incident through the Events API v2.
+    Pagerduty { routing_key: String },
+}
+
+/// Alert conditions for a recurring job.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub job: String,
+    /// Index in the guest's journal schema of the value to watch.
+    pub index: Option<usize>,
+    /// Alert when the value moves by more than this many basis points
+    /// between consecutive successful runs.
+    pub max_deviation_bps: Option<u32>,
+    /// Alert when no run succeeds for this many seconds.
+    pub max_silence_secs: Option<u64>,
+}
+
+/// Alerting settings, as found in the alerts file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertConfig {
+    pub sinks: Vec<AlertSink>,
+    pub rules: Vec<AlertRule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    Deviation,
+    Silence,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub job: String,
+    pub kind: AlertKind,
+    pub message: String,
+}
+
+/// Raises alerts on the results of scheduled jobs.
+pub struct Alerts {
+    config: AlertConfig,
+    client: reqwest::Client,
+}
+
+impl Alerts {
+    pub fn new(config: AlertConfig) -> Result<Self> {
+        for sink in &config.sinks {
+            match sink {
+                AlertSink::Webhook { url } => register_secret(url),
+                AlertSink::Pagerduty { routing_key } => register_secret(routing_key),
+            }
+        }
+        for rule in &config.rules {
+            if rule.max_deviation_bps.is_some() && rule.index.is_none() {
+                bail!("deviation rule of job {} needs a journal index", rule.job);
+            }
+        }
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .context("Failed to build alert client")?;
+        Ok(Self { config, client })
+    }
+
+    /// Load a JSON [AlertConfig].
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open alerts file {}", path.display()))?;
+        let config = serde_json::from_reader(file)
+            .context(format!("Failed to parse alerts file {}", path.display()))?;
+        Self::new(config)
+    }
+
+    /// Check the results of a job run by `guest_name` against its rules until
+    /// the job stops. A silence alert fires once per silent period.
+    pub async fn watch(
+        &self,
+        job: &str,
+        guest_name: &str,
+        results: impl Stream<Item = ProofResult> + Unpin,
+    ) {
+        let rules: Vec<&AlertRule> = self.config.rules.iter().filter(|r| r.job == job).collect();
+        if rules.is_empty() {
+            return;
+        }
+        let silence = rules
+            .iter()
+            .filter_map(|rule| rule.max_silence_secs)
+            .min()
+            .map(Duration::from_secs);
+        let mut results = results;
+        let mut last_values: Vec<Option<U256>> = vec![None; rules.len()];
+        let mut deadline = silence.map(|silence| Instant::now() + silence);
+        loop {
+            let result = match deadline {
+                Some(at) => tokio::select! {
+                    result = results.next() => result,
+                    _ = sleep_until(at) => {
+                        let silence = silence.unwrap_or_default();
+                        self.raise(Alert {
+                            job: job.to_string(),
+                            kind: AlertKind::Silence,
+                            message: format!("no successful run for {}s", silence.as_secs()),
+                        })
+                        .await;
+                        deadline = Some(Instant::now() + silence);
+                        continue;
+                    }
+                },
+                None => results.next().await,
+            };
+            let Some(result) = result else {
+                return;
+            };
+            let Ok(output) = result.output else {
+                continue;
+            };
+            deadline = silence.map(|silence| Instant::now() + silence);
+            let journal = match output.as_ref() {
+                Output::Execution { journal }
+                | Output::Bonsai { journal, .. }
+                | Output::Stark { journal, .. } => journal,
+            };
+            for (rule, last_value) in rules.iter().zip(&mut last_values) {
+                let (Some(index), Some(max_bps)) = (rule.index, rule.max_deviation_bps) else {
+                    continue;
+                };
+                let value = match journal_value(guest_name, journal, index) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        elog!(
+                            "Failed to read value {index} of job {job} run {}: {err:?}",
+                            result.run
+                        );
+                        continue;
+                    }
+                };
+                if let Some(last) = last_value.replace(value) {
+                    if exceeds_deviation(last, value, max_bps) {
+                        self.raise(Alert {
+                            job: job.to_string(),
+                            kind: AlertKind::Deviation,
+                            message: format!(
+                                "value {index} moved from {last} to {value} in run {}, more than {max_bps} bps",
+                                result.run
+                            ),
+                        })
+                        .await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Deliver an alert to every sink. Delivery failures are logged, not
+    /// retried.
+    pub async fn raise(&self, alert: Alert) {
+        elog!("ALERT: job {}: {}", alert.job, alert.message);
+        for sink in &self.config.sinks {
+            if let Err(err) = self.deliver(sink, &alert).await {
+                elog!("Failed to deliver alert for job {}: {err:?}", alert.job);
+            }
+        }
+    }
+
+    async fn deliver(&self, sink: &AlertSink, alert: &Alert) -> Result<()> {
+        let (url, body) = match sink {
+            AlertSink::Webhook { url } => (url.as_str(), serde_json::to_vec(alert)?),
+            AlertSink::Pagerduty { routing_key } => (
+                PAGERDUTY_EVENTS_URL,
+                serde_json::to_vec(&serde_json::json!({
+                    "routing_key": routing_key,
+                    "event_action": "trigger",
+                    "dedup_key": format!("{}-{:?}", alert.job, alert.kind),
+                    "payload": {
+                        "summary": format!("{}: {}", alert.job, alert.message),
+                        "source": "bonsai-ethereum-relay",
+                        "severity": "error",
+                    },
+                }))?,
+            ),
+        };
+        self.client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .context("Failed to send alert")?
+            .error_for_status()
+            .context("Alert was rejected")?;
+        Ok(())
+    }
+}
+
+fn journal_value(guest_name: &str, journal: &[u8], index: usize) -> Result<U256> {
+    decode_journal(guest_name, journal)?
+        .ok_or_else(|| anyhow!("guest {guest_name} has no journal schema"))?
+        .get(index)
+        .cloned()
+        .and_then(|token| token.into_uint())
+        .ok_or_else(|| anyhow!("journal value {index} is not an integer"))
+}
@@ -0,0 +1,325 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Tokenizable},
+    types::{Address, Signature, U256},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{bindings::Callback, elog, schema::decode_journal};
+
+/// Journal value of a guest that triggers approval, by its index in the
+/// guest's journal schema.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApprovalRule {
+    pub index: usize,
+    /// Values above this need approval.
+    pub max_value: Option<U256>,
+    /// Changes from the last submitted value larger than this many basis
+    /// points need approval.
+    pub max_deviation_bps: Option<u32>,
+}
+
+/// Approval workflow settings, as found in the approval policy file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApprovalConfig {
+    /// Addresses of the operator keys allowed to approve callbacks.
+    pub operators: Vec<Address>,
+    /// Number of distinct operators that must approve a callback.
+    pub threshold: usize,
+    /// Rules per guest name. Callbacks of other guests need no approval.
+    #[serde(default)]
+    pub rules: HashMap<String, Vec<ApprovalRule>>,
+    /// How long a callback waits for approval before it is dropped, in
+    /// seconds. Defaults to an hour.
+    pub timeout_secs: Option<u64>,
+}
+
+/// Callback waiting for operator approval.
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingApproval {
+    /// Hex encoded ID of the request, which approvals are posted for.
+    pub request_id: String,
+    /// Hex encoded digest that operators sign, see [callback_digest].
+    pub digest: String,
+    pub guest: String,
+    pub reason: String,
+    pub approvers: Vec<Address>,
+    pub threshold: usize,
+}
+
+struct Pending {
+    digest: [u8; 32],
+    guest: String,
+    reason: String,
+    approvers: HashSet<Address>,
+    approved: watch::Sender<bool>,
+    /// Identical requests waiting on this approval, which share the request
+    /// ID and callback.
+    waiters: usize,
+}
+
+/// Whether `value` moved from `last` by more than `max_bps` basis points.
+/// Any change from zero is an unbounded deviation.
+pub fn exceeds_deviation(last: U256, value: U256, max_bps: u32) -> bool {
+    let deviation = if value > last {
+        value - last
+    } else {
+        last - value
+    };
+    if last.is_zero() {
+        !deviation.is_zero()
+    } else {
+        deviation.saturating_mul(10_000.into()) / last > max_bps.into()
+    }
+}
+
+/// Digest identifying a callback: keccak256 of its ABI encoding. Operators
+/// approve a callback by signing this digest as an EIP-191 personal message.
+pub fn callback_digest(callback: &Callback) -> [u8; 32] {
+    keccak256(abi::encode(&[callback.clone().into_token()]))
+}
+
+/// Holds back high-value callbacks until N of M operator keys sign off on
+/// them, for protocols that treat oracle updates as high-impact actions.
+pub struct Approvals {
+    config: ApprovalConfig,
+    operators: HashSet<Address>,
+    timeout: Duration,
+    /// Last submitted value per guest and journal index, for deviation rules.
+    last_values: Mutex<HashMap<(String, usize), U256>>,
+    /// Callbacks waiting for approval, by request ID.
+    pending: Mutex<HashMap<[u8; 32], Pending>>,
+}
+
+impl Approvals {
+    pub fn new(config: ApprovalConfig) -> Result<Self> {
+        let operators: HashSet<Address> = config.operators.iter().copied().collect();
+        if config.threshold == 0 || config.threshold > operators.len() {
+            bail!(
+                "approval threshold must be between 1 and the {} operators",
+                operators.len()
+            );
+        }
+        Ok(Self {
+            operators,
+            timeout: Duration::from_secs(config.timeout_secs.unwrap_or(60 * 60)),
+            last_values: Mutex::new(HashMap::new()),
+            pending: Mutex::new(HashMap::new()),
+            config,
+        })
+    }
+
+    /// Load a JSON [ApprovalConfig].
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open approval policy {}", path.display()))?;
+        let config = serde_json::from_reader(file).context(format!(
+            "Failed to parse approval policy {}",
+            path.display()
+        ))?;
+        Self::new(config)
+    }
+
+    /// Why a guest's journal needs approval, if it does.
+    pub fn requirement(&self, guest_name: &str, journal: &[u8]) -> Result<Option<String>> {
+        let Some(rules) = self.config.rules.get(&guest_name.to_uppercase()) else {
+            return Ok(None);
+        };
+        let values = decode_journal(guest_name, journal)?
+            .ok_or_else(|| anyhow!("guest {guest_name} has no journal schema to check"))?;
+        let last_values = self
+            .last_values
+            .lock()
+            .map_err(|_| anyhow!("approval values lock poisoned"))?;
+        for rule in rules {
+            let value = values
+                .get(rule.index)
+                .cloned()
+                .and_then(|token| token.into_uint())
+                .ok_or_else(|| anyhow!("journal value {} is not an integer", rule.index))?;
+            if let Some(max_value) = rule.max_value {
+                if value > max_value {
+                    return Ok(Some(format!(
+                        "value {} is {value}, above {max_value}",
+                        rule.index
+                    )));
+                }
+            }
+            let last = last_values.get(&(guest_name.to_uppercase(), rule.index));
+            if let (Some(max_bps), Some(last)) = (rule.max_deviation_bps, last) {
+                if exceeds_deviation(*last, value, max_bps) {
+                    return Ok(Some(format!(
+                        "value {} moved from {last} to {value}, more than {max_bps} bps",
+                        rule.index
+                    )));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Remember the values of a submitted journal as the reference for
+    /// deviation rules.
+    pub fn record_submitted(&self, guest_name: &str, journal: &[u8]) -> Result<()> {
+        let Some(rules) = self.config.rules.get(&guest_name.to_uppercase()) else {
+            return Ok(());
+        };
+        let Some(values) = decode_journal(guest_name, journal)? else {
+            return Ok(());
+        };
+        let mut last_values = self
+            .last_values
+            .lock()
+            .map_err(|_| anyhow!("approval values lock poisoned"))?;
+        for rule in rules {
+            if let Some(value) = values.get(rule.index).cloned().and_then(|t| t.into_uint()) {
+                last_values.insert((guest_name.to_uppercase(), rule.index), value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait until enough operators approve the callback answering request
+    /// `request_id`, or fail once the approval timeout passes.
+    pub async fn wait(
+        &self,
+        request_id: [u8; 32],
+        guest_name: &str,
+        reason: String,
+        callback: &Callback,
+    ) -> Result<()> {
+        let digest = callback_digest(callback);
+        let mut approved = {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|_| anyhow!("pending approvals lock poisoned"))?;
+            let entry = pending.entry(request_id).or_insert_with(|| Pending {
+                digest,
+                guest: guest_name.to_string(),
+                reason: reason.clone(),
+                approvers: HashSet::new(),
+                approved: watch::channel(false).0,
+                waiters: 0,
+            });
+            if entry.digest != digest {
+                bail!(
+                    "request 0x{} already awaits approval of callback 0x{}",
+                    hex::encode(request_id),
+                    hex::encode(entry.digest)
+                );
+            }
+            entry.waiters += 1;
+            entry.approved.subscribe()
+        };
+        elog!(
+            "ALERT: callback 0x{} of request 0x{} for guest {guest_name} awaits {} operator \
+             approvals: {reason}",
+            hex::encode(digest),
+            hex::encode(request_id),
+            self.config.threshold
+        );
+        let approval = async {
+            while !*approved.borrow_and_update() {
+                approved.changed().await?;
+            }
+            Ok::<_, watch::error::RecvError>(())
+        };
+        let result = tokio::time::timeout(self.timeout, approval).await;
+        {
+            let mut pending = self
+                .pending
+                .lock()
+                .map_err(|_| anyhow!("pending approvals lock poisoned"))?;
+            if let Some(entry) = pending.get_mut(&request_id) {
+                entry.waiters -= 1;
+                if entry.waiters == 0 {
+                    pending.remove(&request_id);
+                }
+            }
+        }
+        match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => bail!(
+                "approval of request 0x{} was dropped",
+                hex::encode(request_id)
+            ),
+            Err(_) => bail!(
+                "callback 0x{} of request 0x{} was not approved within {}s",
+                hex::encode(digest),
+                hex::encode(request_id),
+                self.timeout.as_secs()
+            ),
+        }
+    }
+
+    /// Record an operator's signature over the digest of the callback
+    /// pending for request `request_id`. Returns the number of distinct
+    /// operators that approved it so far.
+    pub fn approve(&self, request_id: [u8; 32], signature: &Signature) -> Result<usize> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| anyhow!("pending approvals lock poisoned"))?;
+        let entry = pending.get_mut(&request_id).ok_or_else(|| {
+            anyhow!(
+                "no callback of request 0x{} awaits approval",
+                hex::encode(request_id)
+            )
+        })?;
+        let signer = signature
+            .recover(entry.digest.as_slice())
+            .context("Failed to recover approval signer")?;
+        if !self.operators.contains(&signer) {
+            bail!("{signer:?} is not an approval operator");
+        }
+        entry.approvers.insert(signer);
+        let approvals = entry.approvers.len();
+        if approvals >= self.config.threshold {
+            entry.approved.send_replace(true);
+        }
+        Ok(approvals)
+    }
+
+    /// Callbacks currently waiting for approval.
+    pub fn pending(&self) -> Result<Vec<PendingApproval>> {
+        let pending = self
+            .pending
+            .lock()
+            .map_err(|_| anyhow!("pending approvals lock poisoned"))?;
+        Ok(pending
+            .iter()
+            .map(|(request_id, pending)| PendingApproval {
+                request_id: hex::encode(request_id),
+                digest: hex::encode(pending.digest),
+                guest: pending.guest.clone(),
+                reason: pending.reason.clone(),
+                approvers: pending.approvers.iter().copied().collect(),
+                threshold: self.config.threshold,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use ethers::{abi::Token, types::I256};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{
+    backend::BonsaiBackend,
+    checksum::sha256_hex,
+    elog,
+    input::{decode_public, private_input_digest, split_input},
+    pool::ImagePool,
+    redact::recent_logs,
+    registry::Guest,
+    run_guest,
+    schema::input_schema,
+    trace, Output,
+};
+
+/// Summary of a session, written as `session.json`.
+#[derive(Debug, Serialize)]
+struct SessionSummary<'a> {
+    session_id: &'a str,
+    guest: &'a str,
+    image_id: String,
+    /// When the run started, in milliseconds since the Unix epoch.
+    started_at_ms: u128,
+    elapsed_ms: u128,
+    proven: bool,
+    error: Option<String>,
+}
+
+/// Writes a directory of artifacts for every guest run, named by session ID,
+/// so a run can be reproduced and its receipt checked with `relay verify`:
+///
+/// - `input.bin`: the public section of the input, which `relay query` takes
+///   hex encoded. Private input is never written, only its digest.
+/// - `input.json`: the input decoded against the guest's input schema.
+/// - `journal.bin` and, for proofs, `receipt.bin` (bincode) and, for Bonsai
+///   proofs, `snark.json`.
+/// - `session.json`: guest, timings and error, and `log.txt`: the relay's log
+///   lines printed during the run under its trace ID.
+pub struct Artifacts {
+    dir: PathBuf,
+}
+
+impl Artifacts {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create artifacts directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// Run the guest as [run_guest] does, writing the artifacts of the run.
+    pub async fn run_guest(
+        &self,
+        guest: &Arc<Guest>,
+        input: Vec<u8>,
+        pool: &Arc<ImagePool>,
+        bonsai: &BonsaiBackend,
+        dev_mode: bool,
+    ) -> Result<Output> {
+        self.capture(
+            guest,
+            &input,
+            run_guest(guest, input.clone(), pool, bonsai, dev_mode),
+        )
+        .await
+    }
+
+    /// Await a run of the guest on `input`, writing its artifacts. Failing
+    /// to write them is logged, not returned.
+    pub async fn capture(
+        &self,
+        guest: &Guest,
+        input: &[u8],
+        run: impl Future<Output = Result<Output>>,
+    ) -> Result<Output> {
+        // Runs outside of a request get a trace ID of their own, so that
+        // `log.txt` holds only their lines.
+        let trace_id = trace::current().unwrap_or_else(trace::generate);
+        let started_at = SystemTime::now();
+        let start = Instant::now();
+        let output = trace::scope(trace_id.clone(), run).await;
+        let elapsed = start.elapsed();
+        if let Err(err) = self.write(guest, input, &output, started_at, elapsed, &trace_id) {
+            elog!(
+                "Failed to write artifacts for guest {}: {err:?}",
+                guest.name
+            );
+        }
+        output
+    }
+
+    /// Write the artifacts of a run traced under `trace_id`, returning their
+    /// directory.
+    pub fn write(
+        &self,
+        guest: &Guest,
+        input: &[u8],
+        output: &Result<Output>,
+        started_at: SystemTime,
+        elapsed: Duration,
+        trace_id: &str,
+    ) -> Result<PathBuf> {
+        let started_at_ms = started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        // Executions and failed runs have no Bonsai session.
+        let session_id = match output {
+            Ok(Output::Bonsai { session_id, .. }) => session_id.clone(),
+            _ => format!("local-{started_at_ms}-{}", &sha256_hex(input)[..16]),
+        };
+        let dir = self.dir.join(&session_id);
+        std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, data).context(format!("Failed to write {}", path.display()))
+        };
+
+        let (public, private) = split_input(input)?;
+        write("input.bin", public)?;
+        write(
+            "input.json",
+            &serde_json::to_vec_pretty(&decoded_input(&guest.name, public, private))?,
+        )?;
+        match output {
+            Ok(Output::Execution { journal }) => write("journal.bin", journal)?,
+            Ok(Output::Bonsai {
+                journal,
+                snark_proof,
+                receipt,
+                ..
+            }) => {
+                write("journal.bin", journal)?;
+                write("receipt.bin", &bincode::serialize(receipt)?)?;
+                write("snark.json", &serde_json::to_vec_pretty(snark_proof)?)?;
+            }
+            Ok(Output::Stark { journal, receipt }) => {
+                write("journal.bin", journal)?;
+                write("receipt.bin", &bincode::serialize(receipt)?)?;
+            }
+            Err(_) => {}
+        }
+        let summary = SessionSummary {
+            session_id: &session_id,
+            guest: &guest.name,
+            image_id: hex::encode(guest.image_id),
+            started_at_ms,
+            elapsed_ms: elapsed.as_millis(),
+            proven: matches!(output, Ok(Output::Bonsai { .. } | Output::Stark { .. })),
+            error: output.as_ref().err().map(|err| format!("{err:?}")),
+        };
+        write("session.json", &serde_json::to_vec_pretty(&summary)?)?;
+        let mut log = recent_logs(started_at, trace_id).join("\n");
+        log.push('\n');
+        write("log.txt", log.as_bytes())?;
+        Ok(dir)
+    }
+}
+
+/// The public input decoded against the guest's input schema, or its hex
+/// encoding for guests without one.
+fn decoded_input(guest_name: &str, public: &[u8], private: Option<&[u8]>) -> Value {
+    let values = input_schema(guest_name)
+        .and_then(|schema| decode_public(guest_name, &schema, public).ok())
+        .map(|tokens| Value::Array(tokens.iter().map(token_json).collect()));
+    json!({
+        "guest": guest_name,
+        "values": values,
+        "public": format!("0x{}", hex::encode(public)),
+        "private_digest": private.map(|private| format!("0x{}", hex::encode(private_input_digest(private)))),
+    })
+}
+
+/// JSON form of an ABI value. Integers are decimal strings, so they survive
+/// JSON parsers that read numbers as doubles.
+pub(crate) fn token_json(token: &Token) -> Value {
+    match token {
+        Token::Address(address) => json!(format!("{address:?}")),
+        Token::FixedBytes(bytes) | Token::Bytes(bytes) => {
+            json!(format!("0x{}", hex::encode(bytes)))
+        }
+        Token::Int(raw) => json!(I256::from_raw(*raw).to_string()),
+        Token::Uint(value) => json!(value.to_string()),
+        Token::Bool(value) => json!(value),
+        Token::String(value) => json!(value),
+        Token::FixedArray(items) | Token::Array(items) | Token::Tuple(items) => {
+            Value::Array(items.iter().map(token_json).collect())
+        }
+    }
+}
@@ -0,0 +1,334 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+
+use crate::{
+    bonsai_api::{self, ApiRevision},
+    lease,
+    pool::ImagePool,
+    prove_alpha,
+    proving::{execute_segments, ProvingMode},
+    registry::Guest,
+    retry, trace, Output,
+};
+
+/// Kinds of prover backends the API server can dispatch to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum ProverKind {
+    /// Bonsai, see [BonsaiBackend].
+    Bonsai,
+    /// This machine, see [LocalBackend].
+    Local,
+    /// Workers given by --prover-cluster, see `ClusterBackend`.
+    Cluster,
+}
+
+/// Proves guest runs outside of dev mode.
+pub trait ProverBackend: Send + Sync {
+    /// Whether proofs carry the SNARK the relay contract verifies, see
+    /// [Output::Bonsai].
+    fn snark(&self) -> bool;
+
+    fn prove(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>>;
+
+    /// Prove a run with a SNARK, failing without proving if the backend
+    /// cannot produce one.
+    fn prove_snark(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>> {
+        if !self.snark() {
+            return Box::pin(async { bail!("prover backend produces no SNARK") });
+        }
+        self.prove(guest, input)
+    }
+}
+
+/// Executes and proves guests on Bonsai, with a SNARK of every proof,
+/// through the API revision it was created with, as settled on by
+/// [bonsai_api::negotiate]. The default proves through the alpha API. Runs
+/// held under a lease resume the session checkpointed by its previous
+/// holder, see [crate::lease].
+#[derive(Clone, Default)]
+pub struct BonsaiBackend {
+    revision: ApiRevision,
+    /// Pool executing guests for Bonsai to prove their segments, in
+    /// [ProvingMode::Hybrid].
+    hybrid: Option<Arc<ImagePool>>,
+}
+
+impl BonsaiBackend {
+    pub fn new(revision: ApiRevision) -> Self {
+        Self {
+            revision,
+            hybrid: None,
+        }
+    }
+
+    /// Execute guests in `pool` and have Bonsai prove only their segments.
+    /// Only the versioned API proves segments, see
+    /// [ProvingMode::negotiate].
+    pub fn with_hybrid(mut self, pool: Arc<ImagePool>) -> Self {
+        self.hybrid = Some(pool);
+        self
+    }
+
+    /// API revision proofs go through.
+    pub fn revision(&self) -> ApiRevision {
+        self.revision
+    }
+
+    pub fn mode(&self) -> ProvingMode {
+        match self.hybrid {
+            Some(_) => ProvingMode::Hybrid,
+            None => ProvingMode::Remote,
+        }
+    }
+}
+
+impl ProverBackend for BonsaiBackend {
+    fn snark(&self) -> bool {
+        true
+    }
+
+    fn prove(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>> {
+        Box::pin(async move {
+            let elf = guest.elf()?;
+            let revision = self.revision;
+            let hybrid = self.hybrid.clone();
+            let hook = lease::session_hook();
+            let attempts = retry::attempt_log();
+            let output = trace::spawn_blocking({
+                let hybrid = hybrid.clone();
+                move || match revision {
+                    ApiRevision::Alpha => {
+                        prove_alpha(&elf, input, hook.as_ref(), attempts.as_ref())
+                    }
+                    ApiRevision::V1 => {
+                        let segments = hybrid
+                            .map(|pool| execute_segments(&pool, &guest, &input))
+                            .transpose()?;
+                        bonsai_api::prove_v1(
+                            &elf,
+                            input,
+                            segments,
+                            hook.as_ref(),
+                            attempts.as_ref(),
+                        )
+                    }
+                }
+            })
+            .await
+            .context(format!("Failed to run {revision:?} sub-task"))?;
+            if let Some(pool) = hybrid {
+                pool.refill_in_background();
+            }
+            output
+        })
+    }
+}
+
+/// Executes and proves guests on this machine, on its GPU when the zkVM is
+/// built with one. Proofs have no SNARK, see [Output::Stark].
+pub struct LocalBackend {
+    pool: Arc<ImagePool>,
+}
+
+impl LocalBackend {
+    pub fn new(pool: Arc<ImagePool>) -> Self {
+        Self { pool }
+    }
+}
+
+impl ProverBackend for LocalBackend {
+    fn snark(&self) -> bool {
+        false
+    }
+
+    fn prove(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>> {
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let output = trace::spawn_blocking({
+                let pool = pool.clone();
+                move || {
+                    let session = pool.session(&guest, &input)?;
+                    let receipt = session.prove().context("Failed to prove session")?;
+                    Ok(Output::Stark {
+                        journal: receipt.journal.clone(),
+                        receipt,
+                    })
+                }
+            })
+            .await
+            .context("Failed to run proving sub-task")?;
+            pool.refill_in_background();
+            output
+        })
+    }
+}
+
+/// Weight of the latest run in a backend's latency estimate.
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+#[derive(Default)]
+struct DispatchStats {
+    in_flight: Vec<usize>,
+    /// Smoothed latency per backend and guest, in seconds.
+    latency: HashMap<(usize, String), f64>,
+}
+
+/// Releases a backend's in-flight slot, also when the proof is cancelled.
+struct InFlight<'a> {
+    stats: &'a Mutex<DispatchStats>,
+    backend: usize,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut stats = match self.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        stats.in_flight[self.backend] -= 1;
+    }
+}
+
+/// Routes each proof to the backend expected to finish it first, from the
+/// proofs it already has in flight and its past latency for the guest.
+/// A backend that never proved a guest is tried first while it has nothing
+/// in flight, so every backend gets measured, and is otherwise expected to
+/// take the mean latency of the backends that did. Proofs needing a SNARK
+/// only go to backends producing one.
+pub struct Dispatcher {
+    backends: Vec<(String, Arc<dyn ProverBackend>)>,
+    stats: Mutex<DispatchStats>,
+}
+
+impl Dispatcher {
+    pub fn new(backends: Vec<(String, Arc<dyn ProverBackend>)>) -> Result<Self> {
+        if backends.is_empty() {
+            bail!("a dispatcher needs at least one backend");
+        }
+        Ok(Self {
+            stats: Mutex::new(DispatchStats {
+                in_flight: vec![0; backends.len()],
+                latency: HashMap::new(),
+            }),
+            backends,
+        })
+    }
+
+    /// Pick a backend for the guest, among those producing a SNARK if `snark`
+    /// is set, and count the proof as in flight on it.
+    fn assign(&self, guest_name: &str, snark: bool) -> Result<InFlight<'_>> {
+        let mut stats = self
+            .stats
+            .lock()
+            .map_err(|_| anyhow!("dispatcher stats lock poisoned"))?;
+        let candidates: Vec<usize> = (0..self.backends.len())
+            .filter(|backend| !snark || self.backends[*backend].1.snark())
+            .collect();
+        let latency = |backend: usize| {
+            stats
+                .latency
+                .get(&(backend, guest_name.to_string()))
+                .copied()
+        };
+        let measured: Vec<f64> = candidates
+            .iter()
+            .filter_map(|backend| latency(*backend))
+            .collect();
+        // Without any measurement, backends are balanced by their proofs in
+        // flight.
+        let mean = if measured.is_empty() {
+            1.0
+        } else {
+            measured.iter().sum::<f64>() / measured.len() as f64
+        };
+        let expected = |backend: usize| {
+            let in_flight = stats.in_flight[backend];
+            match latency(backend) {
+                Some(latency) => (in_flight + 1) as f64 * latency,
+                None if in_flight == 0 => 0.0,
+                None => (in_flight + 1) as f64 * mean,
+            }
+        };
+        let backend = candidates
+            .iter()
+            .copied()
+            .min_by(|a, b| expected(*a).total_cmp(&expected(*b)))
+            .ok_or_else(|| anyhow!("no prover backend produces a SNARK"))?;
+        stats.in_flight[backend] += 1;
+        Ok(InFlight {
+            stats: &self.stats,
+            backend,
+        })
+    }
+
+    fn record(&self, backend: usize, guest_name: &str, elapsed: Duration, ok: bool) {
+        let mut stats = match self.stats.lock() {
+            Ok(stats) => stats,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        // A failed proof counts double, steering work away from a backend
+        // that keeps failing.
+        let sample = elapsed.as_secs_f64() * if ok { 1.0 } else { 2.0 };
+        stats
+            .latency
+            .entry((backend, guest_name.to_string()))
+            .and_modify(|latency| {
+                *latency = LATENCY_SMOOTHING * sample + (1.0 - LATENCY_SMOOTHING) * *latency
+            })
+            .or_insert(sample);
+    }
+}
+
+impl Dispatcher {
+    async fn dispatch(&self, guest: Arc<Guest>, input: Vec<u8>, snark: bool) -> Result<Output> {
+        let in_flight = self.assign(&guest.name, snark)?;
+        let (name, backend) = &self.backends[in_flight.backend];
+        let start = Instant::now();
+        let output = backend
+            .prove(guest.clone(), input)
+            .await
+            .context(format!("Prover backend {name} failed"));
+        self.record(
+            in_flight.backend,
+            &guest.name,
+            start.elapsed(),
+            output.is_ok(),
+        );
+        output
+    }
+}
+
+impl ProverBackend for Dispatcher {
+    fn snark(&self) -> bool {
+        self.backends.iter().any(|(_, backend)| backend.snark())
+    }
+
+    fn prove(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>> {
+        Box::pin(self.dispatch(guest, input, false))
+    }
+
+    fn prove_snark(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>> {
+        Box::pin(self.dispatch(guest, input, true))
+    }
+}
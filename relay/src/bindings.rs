@@ -0,0 +1,54 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bindings for the contracts the relay talks to, generated at build time from
+//! the ABIs shipped in this crate's `abi` directory.
+
+use ethers::contract::abigen;
+
+// Emits callback requests and verifies and forwards their results.
+abigen!(BonsaiRelay, "abi/IBonsaiRelay.json");
+
+// Verifies seals produced by Bonsai.
+abigen!(RiscZeroVerifier, "abi/IRiscZeroVerifier.json");
+
+// Base of the contracts receiving callbacks, e.g. the pool's `settleSwap`.
+abigen!(BonsaiCallbackReceiver, "abi/IBonsaiCallbackReceiver.json");
+
+// See contracts/FeeEscrow.sol.
+abigen!(FeeEscrow, "abi/FeeEscrow.json");
+
+// Guardian whose pause flag halts the relay's submissions.
+abigen!(Pausable, "abi/IPausable.json");
+
+// OP stack L1 data fee oracle.
+abigen!(GasPriceOracle, "abi/GasPriceOracle.json");
+
+// Arbitrum node interface precompile.
+abigen!(NodeInterface, "abi/NodeInterface.json");
+
+// Pools enumerated by discovery.
+abigen!(UniswapV3Factory, "abi/UniswapV3Factory.json");
+
+// Pool state read into snapshots.
+abigen!(UniswapV3Pool, "abi/UniswapV3Pool.json");
+
+// Chainlink-compatible feed of proven prices.
+abigen!(ZkPriceAggregator, "abi/ZkPriceAggregator.json");
+
+// Transmits the prices of many pools proven in one batch.
+abigen!(ZkPriceBatcher, "abi/ZkPriceBatcher.json");
+
+// Rolling commitment to a pool's observations extended by HISTORY runs.
+abigen!(ZkObservationHistory, "abi/ZkObservationHistory.json");
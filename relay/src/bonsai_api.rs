@@ -0,0 +1,519 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use bonsai_sdk::alpha::{responses::SnarkProof, SdkErr};
+use clap::ValueEnum;
+use reqwest::{blocking, StatusCode};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    checksum::{image_digest, sha256_hex},
+    clock,
+    download::{download_to_file, log_progress},
+    elog,
+    error::RelayError,
+    lease::SessionHook,
+    poll_backoff,
+    retry::{retry_transient, session_retries, AttemptLog, SessionAttempt},
+    version::HOST_ZKVM_VERSION,
+    Output, POLL_INTERVAL_SEC,
+};
+
+/// Revisions of the Bonsai REST API the relay can prove through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ApiRevision {
+    /// The alpha API of the bonsai-sdk this relay is built against.
+    #[default]
+    Alpha,
+    /// The API of bonsai-sdk 0.5 and later, served alongside the alpha API
+    /// by deployments proving for risc0 0.19 and later. Its routes are those
+    /// of the SDK's `alpha::Client` (`bonsai/sdk/src/alpha.rs` in the risc0
+    /// repository at v0.19.0): `GET /version` lists the zkVM versions
+    /// served, `GET /images/upload/{id}` answers 204 No Content for known
+    /// images, sessions are created with assumptions and an execute-only
+    /// flag, and finished SNARKs may be served by URL instead of inline.
+    V1,
+}
+
+/// Feature a deployment of the versioned API lists in `GET /version` when
+/// it proves segments executed by the client: each is uploaded to a URL
+/// from `GET /segments/upload`, as inputs are, and the session is created
+/// with their IDs, see [crate::proving::ProvingMode::Hybrid].
+pub const SEGMENT_UPLOAD_FEATURE: &str = "segment_upload";
+
+/// What a Bonsai deployment reported when probed.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub revision: ApiRevision,
+    /// zkVM versions the deployment proves for, if it reports them.
+    pub zkvm_versions: Vec<String>,
+    /// Whether the deployment lists [SEGMENT_UPLOAD_FEATURE].
+    pub segment_upload: bool,
+}
+
+#[derive(Deserialize)]
+struct VersionRes {
+    risc0_zkvm: Vec<String>,
+    #[serde(default)]
+    features: Vec<String>,
+}
+
+/// Ask the Bonsai deployment at `url` which API revision it speaks. Only
+/// the versioned API serves `GET /version`.
+pub async fn probe(url: &str, api_key: &str) -> Result<Capabilities> {
+    let res = reqwest::Client::new()
+        .get(format!("{}/version", url.trim_end_matches('/')))
+        .header("x-api-key", api_key)
+        .header("x-risc0-version", HOST_ZKVM_VERSION)
+        .send()
+        .await
+        .context("Failed to probe the Bonsai API version")?;
+    match res.status() {
+        StatusCode::NOT_FOUND => Ok(Capabilities {
+            revision: ApiRevision::Alpha,
+            zkvm_versions: Vec::new(),
+            segment_upload: false,
+        }),
+        status if status.is_success() => {
+            let version: VersionRes = res
+                .json()
+                .await
+                .context("Failed to parse the Bonsai API version")?;
+            Ok(Capabilities {
+                revision: ApiRevision::V1,
+                zkvm_versions: version.risc0_zkvm,
+                segment_upload: version.features.iter().any(|f| f == SEGMENT_UPLOAD_FEATURE),
+            })
+        }
+        status => bail!("Bonsai API version probe failed with status {status}"),
+    }
+}
+
+/// Settle on the API revision to prove through: `requested` if given,
+/// otherwise whatever the deployment at `url` reports, so the relay keeps
+/// working while Bonsai rolls out a new revision. The revision is kept by
+/// the [crate::backend::BonsaiBackend] proving through it.
+pub async fn negotiate(
+    url: &str,
+    api_key: &str,
+    requested: Option<ApiRevision>,
+) -> Result<ApiRevision> {
+    let revision = match requested {
+        Some(revision) => revision,
+        None => {
+            let capabilities = probe(url, api_key).await?;
+            let versions = &capabilities.zkvm_versions;
+            if !versions.is_empty() && !versions.iter().any(|v| v == HOST_ZKVM_VERSION) {
+                elog!(
+                    "Bonsai serves zkVM versions {}, not this relay's {HOST_ZKVM_VERSION}",
+                    versions.join(", ")
+                );
+            }
+            capabilities.revision
+        }
+    };
+    elog!("Proving through the Bonsai {revision:?} API");
+    Ok(revision)
+}
+
+#[derive(Deserialize)]
+struct UploadRes {
+    url: String,
+    #[serde(default)]
+    uuid: String,
+}
+
+#[derive(Deserialize)]
+struct CreateRes {
+    uuid: String,
+}
+
+#[derive(Serialize)]
+struct SessionCreate<'a> {
+    img: &'a str,
+    input: &'a str,
+    assumptions: Vec<String>,
+    execute_only: bool,
+    /// Segments executed by the relay, proven instead of executing `input`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    segments: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SnarkCreate<'a> {
+    session_id: &'a str,
+}
+
+#[derive(Deserialize)]
+struct SessionStatusRes {
+    status: String,
+    receipt_url: Option<String>,
+    error_msg: Option<String>,
+}
+
+/// A finished SNARK, inline as in the alpha API or as a download URL.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SnarkOutput {
+    Inline(SnarkProof),
+    Url(String),
+}
+
+#[derive(Deserialize)]
+struct SnarkStatusRes {
+    status: String,
+    output: Option<SnarkOutput>,
+    error_msg: Option<String>,
+}
+
+/// Client of the versioned API, which the alpha SDK cannot talk to.
+struct V1Client {
+    url: String,
+    http: blocking::Client,
+}
+
+impl V1Client {
+    fn from_env() -> Result<Self, SdkErr> {
+        let url = std::env::var("BONSAI_API_URL").map_err(|_| SdkErr::MissingApiUrl)?;
+        let api_key = std::env::var("BONSAI_API_KEY").map_err(|_| SdkErr::MissingApiKey)?;
+        let mut headers = reqwest::header::HeaderMap::new();
+        let mut key = reqwest::header::HeaderValue::from_str(&api_key)
+            .map_err(|_| SdkErr::InternalServerErr("invalid API key".to_string()))?;
+        key.set_sensitive(true);
+        headers.insert("x-api-key", key);
+        headers.insert(
+            "x-risc0-version",
+            reqwest::header::HeaderValue::from_static(HOST_ZKVM_VERSION),
+        );
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            http: blocking::Client::builder()
+                .default_headers(headers)
+                .build()?,
+        })
+    }
+
+    fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, SdkErr> {
+        Ok(self
+            .http
+            .get(format!("{}/{path}", self.url))
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    fn post<T: DeserializeOwned>(&self, path: &str, body: &impl Serialize) -> Result<T, SdkErr> {
+        Ok(self
+            .http
+            .post(format!("{}/{path}", self.url))
+            .json(body)
+            .send()?
+            .error_for_status()?
+            .json()?)
+    }
+
+    fn put(&self, url: &str, body: Vec<u8>) -> Result<(), SdkErr> {
+        self.http.put(url).body(body).send()?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Upload an image unless Bonsai has it already, which it signals with
+    /// 204 No Content instead of an upload URL.
+    fn upload_img(&self, image_id: &str, elf: &[u8]) -> Result<(), SdkErr> {
+        let res = self
+            .http
+            .get(format!("{}/images/upload/{image_id}", self.url))
+            .send()?
+            .error_for_status()?;
+        if res.status() == StatusCode::NO_CONTENT {
+            return Ok(());
+        }
+        let upload: UploadRes = res.json()?;
+        self.put(&upload.url, elf.to_vec())
+    }
+
+    fn upload_input(&self, input: Vec<u8>) -> Result<String, SdkErr> {
+        let upload: UploadRes = self.get("inputs/upload")?;
+        self.put(&upload.url, input)?;
+        Ok(upload.uuid)
+    }
+
+    fn upload_segment(&self, segment: Vec<u8>) -> Result<String, SdkErr> {
+        let upload: UploadRes = self.get("segments/upload")?;
+        self.put(&upload.url, segment)?;
+        Ok(upload.uuid)
+    }
+}
+
+/// Prove `input` through the versioned API, as [crate::prove_alpha] does
+/// through the alpha API, resuming or recording its session through `hook`
+/// and recording sessions replaced after transient failures in `attempts`.
+/// Given the `segments` of its execution by the relay, Bonsai proves those
+/// instead of executing the guest itself.
+pub fn prove_v1(
+    elf: &[u8],
+    input: Vec<u8>,
+    segments: Option<Vec<Vec<u8>>>,
+    hook: Option<&SessionHook>,
+    attempts: Option<&AttemptLog>,
+) -> Result<Output> {
+    let client = V1Client::from_env().context("Failed to create client from env var")?;
+    let image_id = image_digest(elf).context("Failed to generate elf memory image")?;
+    let img_id = hex::encode(image_id);
+    let mut backoff = poll_backoff();
+
+    let mut resume = hook.and_then(SessionHook::resume);
+    let mut uploaded = None;
+    let mut retries = session_retries();
+    let (session, receipt) = loop {
+        let session = match resume.take() {
+            Some(checkpoint) => {
+                elog!(
+                    "Resuming Bonsai session {} started by {}",
+                    checkpoint.session_id,
+                    checkpoint.holder
+                );
+                CreateRes {
+                    uuid: checkpoint.session_id.clone(),
+                }
+            }
+            None => {
+                let (input_id, segment_ids) = match &uploaded {
+                    Some(uploaded) => Clone::clone(uploaded),
+                    None => {
+                        retry_transient("Image upload", &mut backoff, || {
+                            client.upload_img(&img_id, elf)
+                        })?;
+                        let input_digest = sha256_hex(&input);
+                        let input_id = retry_transient("Input upload", &mut backoff, || {
+                            client.upload_input(input.clone())
+                        })
+                        .context(format!(
+                            "Failed to upload input data (sha256 {input_digest})"
+                        ))?;
+                        let mut segment_ids = Vec::new();
+                        for (index, segment) in segments.iter().flatten().enumerate() {
+                            let segment_id =
+                                retry_transient("Segment upload", &mut backoff, || {
+                                    client.upload_segment(segment.clone())
+                                })
+                                .context(format!("Failed to upload segment {index}"))?;
+                            segment_ids.push(segment_id);
+                        }
+                        uploaded.insert((input_id, segment_ids)).clone()
+                    }
+                };
+                let session: CreateRes = client
+                    .post(
+                        "sessions/create",
+                        &SessionCreate {
+                            img: &img_id,
+                            input: &input_id,
+                            assumptions: Vec::new(),
+                            execute_only: false,
+                            segments: segment_ids,
+                        },
+                    )
+                    .context("Failed to create remote proving session")?;
+                if let Some(hook) = hook {
+                    hook.started(&session.uuid);
+                }
+                session
+            }
+        };
+
+        let res = loop {
+            let res: SessionStatusRes = retry_transient("Session status", &mut backoff, || {
+                client.get(&format!("sessions/status/{}", session.uuid))
+            })?;
+            match res.status.as_str() {
+                "RUNNING" => clock::sleep(Duration::from_secs(POLL_INTERVAL_SEC)),
+                _ => break res,
+            }
+        };
+        if res.status == "SUCCEEDED" {
+            let receipt_url = res
+                .receipt_url
+                .context("Missing 'receipt_url' on status response")?;
+            let receipt_file = download_to_file(&receipt_url, log_progress)
+                .context("Failed to download receipt")?;
+            break (session, receipt_file.load(image_id)?);
+        }
+        let attempt = SessionAttempt {
+            session_id: session.uuid,
+            status: res.status,
+            error: res.error_msg,
+        };
+        if retries == 0 || !attempt.is_transient() {
+            return Err(attempt.into_error("STARK proving session").into());
+        }
+        retries -= 1;
+        elog!("{attempt}, retrying with a new session ({retries} retries left)");
+        if let Some(attempts) = attempts {
+            attempts.record(attempt);
+        }
+    };
+    let metadata = receipt.get_metadata()?;
+
+    let snark: CreateRes = client
+        .post(
+            "snark/create",
+            &SnarkCreate {
+                session_id: &session.uuid,
+            },
+        )
+        .context("Failed to create SNARK session")?;
+    let snark_proof = loop {
+        let res: SnarkStatusRes = retry_transient("SNARK status", &mut backoff, || {
+            client.get(&format!("snark/status/{}", snark.uuid))
+        })?;
+        match res.status.as_str() {
+            "RUNNING" => clock::sleep(Duration::from_secs(POLL_INTERVAL_SEC)),
+            "SUCCEEDED" => match res.output {
+                Some(SnarkOutput::Inline(proof)) => break proof,
+                Some(SnarkOutput::Url(url)) => {
+                    break blocking::get(&url)
+                        .and_then(|res| res.error_for_status())
+                        .and_then(|res| res.json())
+                        .context("Failed to download SNARK")?
+                }
+                None => bail!("output expected to be non-empty on success"),
+            },
+            // The guest ran to completion, so a failed SNARK is the prover's.
+            status => {
+                return Err(RelayError::ProverFault {
+                    what: "SNARK proving session".to_string(),
+                    message: match res.error_msg {
+                        Some(msg) => format!("exited with bad status: {status}: {msg}"),
+                        None => format!("exited with bad status: {status}"),
+                    },
+                }
+                .into())
+            }
+        }
+    };
+
+    Ok(Output::Bonsai {
+        journal: receipt.journal.clone(),
+        receipt_metadata: metadata,
+        snark_proof,
+        session_id: session.uuid,
+        receipt,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+
+    use super::*;
+    use crate::{backend::BonsaiBackend, proving::ProvingMode};
+
+    /// Bonsai deployment answering one request with `response`, returning
+    /// the request it received.
+    async fn bonsai_server(response: &'static str) -> (String, JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await.unwrap();
+            stream.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&request[..len]).into_owned()
+        });
+        (url, server)
+    }
+
+    /// Response of the versioned API's `GET /version` with `body`.
+    fn version_response(body: &str) -> &'static str {
+        Box::leak(
+            format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .into_boxed_str(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_probe_reads_versioned_api() {
+        let response = version_response(r#"{"risc0_zkvm":["0.19.0","0.19.1"]}"#);
+        let (url, server) = bonsai_server(response).await;
+        let capabilities = probe(&url, "key").await.unwrap();
+        assert_eq!(capabilities.revision, ApiRevision::V1);
+        assert_eq!(capabilities.zkvm_versions, ["0.19.0", "0.19.1"]);
+        assert!(!capabilities.segment_upload);
+
+        let request = server.await.unwrap().to_lowercase();
+        assert!(request.starts_with("get /version "));
+        assert!(request.contains("x-api-key: key"));
+    }
+
+    #[tokio::test]
+    async fn test_probe_falls_back_to_alpha() {
+        let (url, _server) =
+            bonsai_server("HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n").await;
+        let capabilities = probe(&url, "key").await.unwrap();
+        assert_eq!(capabilities.revision, ApiRevision::Alpha);
+        assert!(capabilities.zkvm_versions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_probe_fails_on_server_errors() {
+        let (url, _server) =
+            bonsai_server("HTTP/1.1 503 Service Unavailable\r\ncontent-length: 0\r\n\r\n").await;
+        assert!(probe(&url, "key").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_keeps_requested_revision() {
+        // Nothing listens here; a requested revision is not probed.
+        let revision = negotiate("http://127.0.0.1:9", "key", Some(ApiRevision::V1))
+            .await
+            .unwrap();
+        assert_eq!(revision, ApiRevision::V1);
+        assert_eq!(BonsaiBackend::new(revision).revision(), ApiRevision::V1);
+        assert_eq!(BonsaiBackend::default().revision(), ApiRevision::Alpha);
+    }
+
+    #[tokio::test]
+    async fn test_hybrid_proving_needs_segment_upload() {
+        let response = version_response(r#"{"risc0_zkvm":[],"features":["segment_upload"]}"#);
+        let (url, _server) = bonsai_server(response).await;
+        let mode = ProvingMode::Hybrid
+            .negotiate(ApiRevision::V1, &url, "key")
+            .await;
+        assert_eq!(mode, ProvingMode::Hybrid);
+
+        let (url, _server) = bonsai_server(version_response(r#"{"risc0_zkvm":[]}"#)).await;
+        let mode = ProvingMode::Hybrid
+            .negotiate(ApiRevision::V1, &url, "key")
+            .await;
+        assert_eq!(mode, ProvingMode::Remote);
+
+        // Nothing listens here; the alpha API is not probed.
+        let mode = ProvingMode::Hybrid
+            .negotiate(ApiRevision::Alpha, "http://127.0.0.1:9", "key")
+            .await;
+        assert_eq!(mode, ProvingMode::Remote);
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+
+use crate::{
+    backend::BonsaiBackend,
+    checksum::sha256_hex,
+    clock, elog,
+    pool::ImagePool,
+    registry::{Guest, GuestRegistry},
+    run_guest, Output,
+};
+
+/// `STABLE=CANDIDATE` pair of guest names given on the command line.
+#[derive(Debug, Clone)]
+pub struct CanarySpec {
+    pub stable: String,
+    pub candidate: String,
+}
+
+impl FromStr for CanarySpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (stable, candidate) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected STABLE=CANDIDATE, got {s:?}"))?;
+        Ok(Self {
+            stable: stable.to_string(),
+            candidate: candidate.to_string(),
+        })
+    }
+}
+
+/// A request on which the candidate disagreed with the stable image.
+#[derive(Debug, Clone, Serialize)]
+pub struct Divergence {
+    /// Unix time of the run, in seconds.
+    pub at: u64,
+    pub input_digest: String,
+    pub stable: Result<String, String>,
+    pub candidate: Result<String, String>,
+}
+
+/// Summary of a canary period, as served at /v1/admin/canary.
+#[derive(Debug, Clone, Serialize)]
+pub struct CanaryReport {
+    pub runs: u64,
+    pub divergences: Vec<Divergence>,
+    pub active: bool,
+}
+
+impl CanaryReport {
+    /// The candidate may be promoted once the period is over and it never
+    /// disagreed with the stable image.
+    pub fn ready_to_promote(&self) -> bool {
+        !self.active && self.runs > 0 && self.divergences.is_empty()
+    }
+}
+
+#[derive(Default)]
+struct CanaryState {
+    runs: u64,
+    divergences: Vec<Divergence>,
+}
+
+/// Runs every request on both the stable and the candidate image of a guest
+/// for a limited period from the relay's startup, serving the stable result
+/// and recording any difference in journals.
+pub struct Canary {
+    pub stable: Arc<Guest>,
+    pub candidate: Arc<Guest>,
+    pub until: SystemTime,
+    state: Mutex<CanaryState>,
+}
+
+impl Canary {
+    pub fn new(stable: Arc<Guest>, candidate: Arc<Guest>, period: Duration) -> Self {
+        Self {
+            stable,
+            candidate,
+            until: clock::now() + period,
+            state: Mutex::new(CanaryState::default()),
+        }
+    }
+
+    /// Canary of the guests named by `spec`.
+    pub fn from_spec(
+        spec: &CanarySpec,
+        registry: &GuestRegistry,
+        period: Duration,
+    ) -> Result<Self> {
+        Ok(Self::new(
+            registry.resolve(&spec.stable)?,
+            registry.resolve(&spec.candidate)?,
+            period,
+        ))
+    }
+
+    pub fn is_active(&self) -> bool {
+        clock::now() < self.until
+    }
+
+    /// Whether requests for `guest` are canaried.
+    pub fn covers(&self, guest: &Guest) -> bool {
+        guest.image_id == self.stable.image_id
+    }
+
+    /// Wait for the period to end, then log whether the candidate may be
+    /// promoted.
+    pub async fn conclude(self: Arc<Self>) {
+        let remaining = self.until.duration_since(clock::now()).unwrap_or_default();
+        tokio::time::sleep(remaining).await;
+        match self.report() {
+            Ok(report) if report.runs == 0 => elog!(
+                "Canary {} ended without a request for {}",
+                self.candidate.name,
+                self.stable.name
+            ),
+            Ok(report) if report.ready_to_promote() => elog!(
+                "Canary {} agreed with {} on all {} requests and may be promoted",
+                self.candidate.name,
+                self.stable.name,
+                report.runs
+            ),
+            Ok(report) => elog!(
+                "ALERT: canary {} ended with {} divergences from {} in {} requests",
+                self.candidate.name,
+                report.divergences.len(),
+                self.stable.name,
+                report.runs
+            ),
+            Err(err) => elog!("Failed to report canary {}: {err}", self.candidate.name),
+        }
+    }
+
+    /// Run the input on the stable image and, while the canary is active, on
+    /// the candidate as well. Always returns the stable image's output.
+    pub async fn run(
+        &self,
+        input: Vec<u8>,
+        pool: &Arc<ImagePool>,
+        bonsai: &BonsaiBackend,
+        dev_mode: bool,
+    ) -> Result<Output> {
+        if !self.is_active() {
+            return run_guest(&self.stable, input, pool, bonsai, dev_mode).await;
+        }
+
+        let input_digest = sha256_hex(&input);
+        let (stable, candidate) = tokio::join!(
+            run_guest(&self.stable, input.clone(), pool, bonsai, dev_mode),
+            run_guest(&self.candidate, input, pool, bonsai, dev_mode),
+        );
+        let stable_journal = journal_digest(&stable);
+        let candidate_journal = journal_digest(&candidate);
+
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("canary state lock poisoned"))?;
+        state.runs += 1;
+        if stable_journal != candidate_journal {
+            elog!(
+                "ALERT: canary {} diverged from {} on input {input_digest}: {:?} vs {:?}",
+                self.candidate.name,
+                self.stable.name,
+                stable_journal,
+                candidate_journal
+            );
+            state.divergences.push(Divergence {
+                at: clock::unix_now(),
+                input_digest,
+                stable: stable_journal,
+                candidate: candidate_journal,
+            });
+        }
+        stable
+    }
+
+    pub fn report(&self) -> Result<CanaryReport> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|_| anyhow!("canary state lock poisoned"))?;
+        Ok(CanaryReport {
+            runs: state.runs,
+            divergences: state.divergences.clone(),
+            active: self.is_active(),
+        })
+    }
+}
+
+fn journal_digest(output: &Result<Output>) -> Result<String, String> {
+    match output {
+        Ok(Output::Execution { journal })
+        | Ok(Output::Bonsai { journal, .. })
+        | Ok(Output::Stark { journal, .. }) => Ok(sha256_hex(journal)),
+        Err(err) => Err(err.to_string()),
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Report of what a relay can do as configured, so orchestration and clients
+//! can adapt to it instead of finding out by trial and error.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{BonsaiBackend, ProverKind},
+    finality::FinalityPolicy,
+    registry::GuestRegistry,
+    schema::{input_schema_version, journal_schema_at, journal_version},
+    version::{HOST_CIRCUIT, HOST_ZKVM_VERSION},
+};
+
+/// What the relay can do, as served at `/v1/capabilities`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub relay_version: String,
+    pub zkvm_version: String,
+    pub circuit: String,
+    /// Guests are only executed, and results carry no proof.
+    pub dev_mode: bool,
+    /// Backends proofs are dispatched to: `bonsai`, `local` or `cluster`.
+    pub provers: Vec<String>,
+    /// Bonsai API revision proofs go through, when Bonsai proves them.
+    pub bonsai_api_revision: Option<String>,
+    /// How Bonsai proves, `remote` or `hybrid`, when it proves. Hybrid
+    /// proving is only reported once Bonsai accepted it when probed.
+    pub bonsai_proving_mode: Option<String>,
+    /// GPU the zkVM prover was built for, `cuda` or `metal`, if any. Local
+    /// and cluster proving run on it.
+    pub gpu: Option<String>,
+    /// Whether proofs come with a SNARK, as verified on chain. Only Bonsai
+    /// converts proofs to SNARKs; local and cluster proofs are STARKs.
+    pub snark: bool,
+    /// Chains the relay reads pool state from.
+    pub chains: Vec<ChainCapabilities>,
+    pub guests: Vec<GuestCapabilities>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainCapabilities {
+    pub chain_id: u64,
+    /// Blocks inputs of the chain are built from.
+    pub finality: FinalityPolicy,
+    /// Whether the newest blocks are read from the chain's sequencer feed.
+    #[serde(default)]
+    pub sequencer_feed: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestCapabilities {
+    pub name: String,
+    pub image_id: String,
+    pub zkvm_version: Option<String>,
+    pub circuit: Option<String>,
+    /// Version of the framed input the guest reads, if it has a schema.
+    pub input_schema_version: Option<u16>,
+    /// Journal versions the relay decodes for the guest, newest first.
+    pub journal_versions: Vec<u16>,
+    /// Whether the guest's build provenance is recorded.
+    pub provenance: bool,
+}
+
+impl Capabilities {
+    /// Capabilities of a relay serving the guests of `registry`, proving
+    /// with `provers` outside of dev mode, Bonsai through `bonsai_api`, and
+    /// reading `chains`.
+    pub fn new(
+        registry: &GuestRegistry,
+        dev_mode: bool,
+        provers: &[ProverKind],
+        bonsai_backend: &BonsaiBackend,
+        chains: Vec<ChainCapabilities>,
+    ) -> Self {
+        let provers: &[ProverKind] = if dev_mode { &[] } else { provers };
+        let bonsai = provers.contains(&ProverKind::Bonsai);
+        let gpu = if cfg!(feature = "cuda") {
+            Some("cuda".to_string())
+        } else if cfg!(feature = "metal") {
+            Some("metal".to_string())
+        } else {
+            None
+        };
+        Self {
+            relay_version: env!("CARGO_PKG_VERSION").to_string(),
+            zkvm_version: HOST_ZKVM_VERSION.to_string(),
+            circuit: HOST_CIRCUIT.to_string(),
+            dev_mode,
+            provers: provers
+                .iter()
+                .map(|kind| format!("{kind:?}").to_lowercase())
+                .collect(),
+            bonsai_api_revision: bonsai
+                .then(|| format!("{:?}", bonsai_backend.revision()).to_lowercase()),
+            bonsai_proving_mode: bonsai
+                .then(|| format!("{:?}", bonsai_backend.mode()).to_lowercase()),
+            gpu,
+            snark: bonsai,
+            chains,
+            guests: registry
+                .iter()
+                .map(|guest| GuestCapabilities {
+                    name: guest.name.clone(),
+                    image_id: hex::encode(guest.image_id),
+                    zkvm_version: guest.zkvm_version.clone(),
+                    circuit: guest.circuit.clone(),
+                    input_schema_version: input_schema_version(&guest.name),
+                    journal_versions: journal_version(&guest.name)
+                        .map(|current| {
+                            (1..=current)
+                                .rev()
+                                .filter(|version| {
+                                    journal_schema_at(&guest.name, *version).is_some()
+                                })
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    provenance: guest.build_provenance.is_some(),
+                })
+                .collect(),
+        }
+    }
+}
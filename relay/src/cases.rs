@@ -0,0 +1,183 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    pool::ImagePool,
+    prepare_salted_input,
+    registry::Guest,
+    tracedump::{write_dump, TraceTail, DEFAULT_TRACE_TAIL},
+    Output,
+};
+
+/// Input and expected result of a guest, as found in a cases file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GuestCase {
+    pub name: String,
+    /// Hex encoded, ABI encoded guest input.
+    pub input: String,
+    /// Hex encoded private input.
+    pub private_input: Option<String>,
+    /// Hex encoded salt of the private input, which cases expecting a
+    /// journal committing its digest need. Random if not given.
+    pub private_salt: Option<String>,
+    /// Hex encoded journal the guest must commit.
+    pub expected_journal: Option<String>,
+    /// The input must be rejected, by the relay or by the guest.
+    #[serde(default)]
+    pub expect_failure: bool,
+    /// Text the rejection must contain, such as the message of the guest
+    /// assertion expected to fail, so an input rejected for another reason
+    /// does not pass. Implies `expect_failure`.
+    pub expected_error: Option<String>,
+}
+
+/// Load a JSON array of [GuestCase]s.
+pub fn load_cases(path: &Path) -> Result<Vec<GuestCase>> {
+    let file =
+        std::fs::File::open(path).context(format!("Failed to open cases {}", path.display()))?;
+    serde_json::from_reader(file).context(format!("Failed to parse cases {}", path.display()))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// The guest committed a different journal.
+    JournalMismatch {
+        expected: String,
+        actual: String,
+    },
+    /// The input was rejected although it should not have been.
+    Failed(String),
+    /// The input was accepted although it should have been rejected.
+    UnexpectedSuccess,
+    /// The input was rejected, but not with the expected error.
+    ErrorMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        *self == Outcome::Passed
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::JournalMismatch { expected, actual } => {
+                write!(f, "expected journal {expected}, got {actual}")
+            }
+            Outcome::Failed(err) => write!(f, "failed: {err}"),
+            Outcome::UnexpectedSuccess => write!(f, "succeeded but was expected to fail"),
+            Outcome::ErrorMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "expected an error containing {expected:?}, got: {actual}"
+                )
+            }
+        }
+    }
+}
+
+/// Execute the guest on one case with the local executor, preparing the
+/// input the way the relay does for live requests.
+pub fn run_case(guest: &Guest, case: &GuestCase, pool: &ImagePool) -> Outcome {
+    let result = prepare_salted_input(
+        guest,
+        &case.input,
+        case.private_input.as_deref(),
+        case.private_salt.as_deref(),
+    )
+    .and_then(|input| pool.execute(guest, &input));
+    let expect_failure = case.expect_failure || case.expected_error.is_some();
+    let journal = match (result, expect_failure) {
+        (Ok(_), true) => return Outcome::UnexpectedSuccess,
+        (Err(err), true) => {
+            let actual = format!("{err:#}");
+            return match &case.expected_error {
+                Some(expected) if !actual.contains(expected.as_str()) => Outcome::ErrorMismatch {
+                    expected: expected.clone(),
+                    actual,
+                },
+                _ => Outcome::Passed,
+            };
+        }
+        (Err(err), false) => return Outcome::Failed(format!("{err:#}")),
+        (
+            Ok(
+                Output::Execution { journal }
+                | Output::Bonsai { journal, .. }
+                | Output::Stark { journal, .. },
+            ),
+            false,
+        ) => journal,
+    };
+    match &case.expected_journal {
+        Some(expected) => {
+            let expected = expected.trim_start_matches("0x").to_lowercase();
+            let actual = hex::encode(journal);
+            if expected == actual {
+                Outcome::Passed
+            } else {
+                Outcome::JournalMismatch { expected, actual }
+            }
+        }
+        None => Outcome::Passed,
+    }
+}
+
+/// Execute the guest on the case at `index` again while tracing it, and dump
+/// the execution to a directory of `root` named after the case, which is
+/// returned. Fails if the relay rejects the input before executing it.
+pub fn dump_case(
+    guest: &Guest,
+    index: usize,
+    case: &GuestCase,
+    pool: &ImagePool,
+    root: &Path,
+) -> Result<PathBuf> {
+    let name: String = case
+        .name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    let dir = root.join(format!("{index:03}-{name}"));
+    let input = prepare_salted_input(
+        guest,
+        &case.input,
+        case.private_input.as_deref(),
+        case.private_salt.as_deref(),
+    )?;
+    let mut trace = TraceTail::new(DEFAULT_TRACE_TAIL);
+    let (result, logs) = pool.execute_traced(guest, &input, &mut trace);
+    write_dump(&dir, guest, pool.limits(), &input, &result, &logs, &trace)?;
+    Ok(dir)
+}
@@ -0,0 +1,953 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::{Provider, Ws},
+    types::{Address, Block, BlockId, H256, I256, U256},
+    utils::keccak256,
+};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::{sync::Mutex, task::JoinHandle};
+
+use crate::{
+    aggregator::{AggregatorFeed, BatchTransmitter},
+    bindings::UniswapV3Pool,
+    capabilities::ChainCapabilities,
+    continuity::ContinuityGuard,
+    deadletter::DeadLetters,
+    discovery::DiscoveryConfig,
+    elog,
+    eth::{connect, EthClient},
+    finality::FinalityPolicy,
+    history::{HistoryFetcher, HistoryState},
+    input::{BatchFeed, BatchInput, PoolStateProof, SwapInput},
+    keeper::Keeper,
+    proofs::{encode_header, verify_account, ProofSource, RpcProofSource},
+    pull::PriceUpdates,
+    registry::GuestRegistry,
+    scheduler::{InputFn, Job, JobInput, Scheduler},
+    sequencer::SequencerFeed,
+};
+
+/// `TickMath.MIN_SQRT_RATIO + 1`, the furthest a zero for one swap can move
+/// the price.
+pub(crate) const MIN_SQRT_RATIO_PLUS_ONE: u64 = 4295128740;
+/// `TickMath.MAX_SQRT_RATIO - 1`.
+pub(crate) const MAX_SQRT_RATIO_MINUS_ONE: &str =
+    "1461446703485210103287273052203988822378723970341";
+
+/// Storage slots of `slot0` and `liquidity` in a Uniswap v3 pool. Must match
+/// the guest's pool module.
+const SLOT0_SLOT: u64 = 0;
+const LIQUIDITY_SLOT: u64 = 4;
+
+/// Most runs a job may fetch inputs for ahead of the one proving. Each holds
+/// a fetched input, with its storage proofs, in memory.
+pub const MAX_PREFETCH_DEPTH: usize = 8;
+
+fn default_guest() -> String {
+    "SWAP".to_string()
+}
+
+fn default_batch_guest() -> String {
+    "BATCH".to_string()
+}
+
+/// A pool whose price the relay proves on a schedule.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolConfig {
+    /// Name of the pool's scheduled job.
+    pub name: String,
+    pub chain_id: u64,
+    pub pool: Address,
+    /// Fee tier in hundredths of a bip, checked against the pool's own so a
+    /// wrong address is caught before anything is proven.
+    pub fee: u32,
+    /// Time between runs, in seconds: the longest consumers wait for a
+    /// fresh price.
+    pub window_secs: u64,
+    /// Aggregator fed with the pool's price. Runs are skipped unless its
+    /// heartbeat or deviation condition fires, and transmitted to it as
+    /// rounds if the relay has a transmitter key.
+    #[serde(default)]
+    pub oracle: Option<Address>,
+    /// Guest proving the price.
+    #[serde(default = "default_guest")]
+    pub guest: String,
+    /// Decimal amount swapped in each run, in raw units of the input token.
+    /// Negative amounts are exact output swaps.
+    pub probe_amount: String,
+    /// Whether the probe swaps token0 for token1.
+    #[serde(default)]
+    pub zero_for_one: bool,
+    /// Least in-range liquidity to prove the pool's price at. Runs fail
+    /// before proving below it, and the guest commits it with the liquidity
+    /// it saw.
+    #[serde(default)]
+    pub min_liquidity: u128,
+    /// `ZkObservationHistory` contract the pool's HISTORY results extend.
+    /// Each run must extend the root it holds.
+    #[serde(default)]
+    pub history: Option<Address>,
+    /// Runs whose inputs are fetched ahead while an earlier run is proving,
+    /// up to [MAX_PREFETCH_DEPTH]. See [Job::prefetch_depth].
+    #[serde(default)]
+    pub prefetch_depth: usize,
+    /// Compress each run's receipt into a succinct receipt once downloaded,
+    /// so the receipts indexed and published for the pool are succinct.
+    /// See [Job::succinct].
+    #[serde(default)]
+    pub succinct: bool,
+}
+
+impl PoolConfig {
+    fn validate(&self) -> Result<()> {
+        if self.name.is_empty() || self.name.starts_with('.') || self.name.contains(['/', '\\']) {
+            bail!("invalid pool name {:?}", self.name);
+        }
+        if self.window_secs == 0 {
+            bail!("pool {} has a zero window", self.name);
+        }
+        if self.prefetch_depth > MAX_PREFETCH_DEPTH {
+            bail!(
+                "pool {} prefetches more than {MAX_PREFETCH_DEPTH} runs",
+                self.name
+            );
+        }
+        self.probe_amount()?;
+        Ok(())
+    }
+
+    fn probe_amount(&self) -> Result<I256> {
+        I256::from_dec_str(&self.probe_amount)
+            .map_err(|err| anyhow!("invalid probe amount of pool {}: {err}", self.name))
+    }
+}
+
+/// Pools of the catalog whose prices are proven together, in a single BATCH
+/// proof per run that a `ZkPriceBatcher` verifies once for all of their
+/// aggregators. Batched pools are proven by their batch instead of a job of
+/// their own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchConfig {
+    /// Name of the batch's scheduled job.
+    pub name: String,
+    pub chain_id: u64,
+    /// Names of the batched pools, which must be in the catalog on the
+    /// batch's chain.
+    pub pools: Vec<String>,
+    /// Time between runs, in seconds.
+    pub window_secs: u64,
+    /// Guest proving the prices.
+    #[serde(default = "default_batch_guest")]
+    pub guest: String,
+    /// `ZkPriceBatcher` the proven prices are transmitted to, if the relay
+    /// has a transmitter key.
+    #[serde(default)]
+    pub batcher: Option<Address>,
+    /// Runs whose inputs are fetched ahead while an earlier run is proving,
+    /// up to [MAX_PREFETCH_DEPTH].
+    #[serde(default)]
+    pub prefetch_depth: usize,
+    /// Compress each run's receipt into a succinct receipt once downloaded.
+    #[serde(default)]
+    pub succinct: bool,
+}
+
+/// Pool catalog file: the node to read each chain from, the pools, and the
+/// factories to discover more pools from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CatalogConfig {
+    /// WebSocket URL of a node for each chain ID.
+    pub chains: HashMap<u64, String>,
+    pub pools: Vec<PoolConfig>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discovery: Vec<DiscoveryConfig>,
+    /// Blocks inputs of each chain are built from. Chains not listed use
+    /// the latest block.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub finality: HashMap<u64, FinalityPolicy>,
+    /// WebSocket URL of the sequencer feed of each rollup chain ID whose
+    /// finality is `sequencer`. Chains without one take the newest block
+    /// from their node.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub sequencer_feeds: HashMap<u64, String>,
+    /// Pools proven together. Batches are read at startup, so changes to
+    /// their pools through the operator API take effect after a restart.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub batches: Vec<BatchConfig>,
+}
+
+impl CatalogConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open pool catalog {}", path.display()))?;
+        let config: Self = serde_json::from_reader(file)
+            .context(format!("Failed to parse pool catalog {}", path.display()))?;
+        for discovery in &config.discovery {
+            discovery.validate()?;
+        }
+        Ok(config)
+    }
+
+    /// Chains the catalog reads, by chain ID.
+    pub fn chains(&self) -> Vec<ChainCapabilities> {
+        chain_capabilities(&self.chains, &self.finality, &self.sequencer_feeds)
+    }
+}
+
+fn chain_capabilities(
+    chains: &HashMap<u64, String>,
+    finality: &HashMap<u64, FinalityPolicy>,
+    sequencer_feeds: &HashMap<u64, String>,
+) -> Vec<ChainCapabilities> {
+    let mut chains: Vec<_> = chains
+        .keys()
+        .map(|chain_id| ChainCapabilities {
+            chain_id: *chain_id,
+            finality: finality.get(chain_id).copied().unwrap_or_default(),
+            sequencer_feed: sequencer_feeds.contains_key(chain_id),
+        })
+        .collect();
+    chains.sort_by_key(|chain| chain.chain_id);
+    chains
+}
+
+/// Key the catalog transmits proven prices with, to the aggregator of each
+/// pool with an `oracle` and the batcher of each batch with a `batcher`.
+/// Without it prices are only served for the pull model.
+pub struct Transmission {
+    pub private_key: String,
+    /// Queue of the pools batches failed to price.
+    pub dead_letters: Option<Arc<DeadLetters>>,
+}
+
+/// The pools the relay proves, each driving a scheduled job that fetches the
+/// pool's state and proves it. Pools can be changed at runtime through the
+/// operator API, which persists the catalog file.
+pub struct PoolCatalog {
+    path: PathBuf,
+    registry: Arc<GuestRegistry>,
+    dev_mode: bool,
+    updates: Option<Arc<PriceUpdates>>,
+    chains: HashMap<u64, String>,
+    clients: HashMap<u64, Arc<Provider<Ws>>>,
+    discovery: Vec<DiscoveryConfig>,
+    finality: HashMap<u64, FinalityPolicy>,
+    sequencer_feeds: HashMap<u64, String>,
+    feeds: HashMap<u64, Arc<SequencerFeed>>,
+    batches: Vec<BatchConfig>,
+    /// Signing client of each chain prices are transmitted on.
+    signers: HashMap<u64, Arc<EthClient>>,
+    dead_letters: Option<Arc<DeadLetters>>,
+    state: Mutex<CatalogState>,
+}
+
+struct CatalogState {
+    scheduler: Scheduler,
+    pools: BTreeMap<String, PoolConfig>,
+    /// Pools found by discovery, waiting for the operator to accept them.
+    proposals: BTreeMap<String, PoolConfig>,
+    /// Tasks publishing each pool's results to the pull model.
+    publishers: HashMap<String, JoinHandle<()>>,
+    /// Tasks transmitting each pool's or batch's results on chain.
+    transmitters: HashMap<String, JoinHandle<()>>,
+}
+
+impl PoolCatalog {
+    /// Load the catalog and start a job for each of its pools. Results are
+    /// published to `updates`, if given, for the pull model, and transmitted
+    /// on chain with `transmission`, if given.
+    pub async fn open(
+        path: &Path,
+        registry: Arc<GuestRegistry>,
+        scheduler: Scheduler,
+        dev_mode: bool,
+        updates: Option<Arc<PriceUpdates>>,
+        transmission: Option<Transmission>,
+    ) -> Result<Self> {
+        let config = CatalogConfig::load(path)?;
+        let mut clients = HashMap::new();
+        for (chain_id, url) in &config.chains {
+            let provider = Provider::<Ws>::connect(url)
+                .await
+                .context(format!("Failed to connect to chain {chain_id}"))?;
+            clients.insert(*chain_id, Arc::new(provider));
+        }
+        let mut feeds = HashMap::new();
+        for (chain_id, url) in &config.sequencer_feeds {
+            let finality = config.finality.get(chain_id).copied().unwrap_or_default();
+            if finality != FinalityPolicy::Sequencer {
+                bail!("chain {chain_id} has a sequencer feed, but {finality} finality");
+            }
+            let feed = SequencerFeed::connect(*chain_id, url).await?;
+            feeds.insert(*chain_id, Arc::new(feed));
+        }
+        let mut signers = HashMap::new();
+        if let Some(transmission) = &transmission {
+            for (chain_id, url) in &config.chains {
+                let signer = connect(url, *chain_id, &transmission.private_key)
+                    .await
+                    .context(format!(
+                        "Failed to connect the transmitter to chain {chain_id}"
+                    ))?;
+                signers.insert(*chain_id, signer);
+            }
+        }
+        let catalog = Self {
+            path: path.to_path_buf(),
+            registry,
+            dev_mode,
+            updates,
+            chains: config.chains,
+            clients,
+            discovery: config.discovery,
+            finality: config.finality,
+            sequencer_feeds: config.sequencer_feeds,
+            feeds,
+            batches: config.batches,
+            signers,
+            dead_letters: transmission.and_then(|transmission| transmission.dead_letters),
+            state: Mutex::new(CatalogState {
+                scheduler,
+                pools: BTreeMap::new(),
+                proposals: BTreeMap::new(),
+                publishers: HashMap::new(),
+                transmitters: HashMap::new(),
+            }),
+        };
+        {
+            let mut state = catalog.state.lock().await;
+            for pool in config.pools {
+                catalog.start(&mut state, pool)?;
+            }
+            for batch in &catalog.batches {
+                catalog.start_batch(&mut state, batch)?;
+            }
+        }
+        Ok(catalog)
+    }
+
+    /// The catalog file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub async fn pools(&self) -> Vec<PoolConfig> {
+        self.state.lock().await.pools.values().cloned().collect()
+    }
+
+    /// Chains the catalog reads, by chain ID.
+    pub fn chains(&self) -> Vec<ChainCapabilities> {
+        chain_capabilities(&self.chains, &self.finality, &self.sequencer_feeds)
+    }
+
+    /// Node of a chain the catalog is connected to.
+    pub fn client(&self, chain_id: u64) -> Option<Arc<Provider<Ws>>> {
+        self.clients.get(&chain_id).cloned()
+    }
+
+    /// Blocks inputs of a chain are built from.
+    pub fn finality(&self, chain_id: u64) -> FinalityPolicy {
+        self.finality.get(&chain_id).copied().unwrap_or_default()
+    }
+
+    pub fn discovery(&self) -> &[DiscoveryConfig] {
+        &self.discovery
+    }
+
+    /// Propose a pool for the operator to accept, returning the name it was
+    /// proposed under, or None if a pool at the same address is already in
+    /// the catalog or proposed. A pool whose name is taken by a pool at
+    /// another address, such as a second pool of the same tokens and fee on
+    /// another factory, is proposed with its address appended to the name.
+    pub async fn propose(&self, mut pool: PoolConfig) -> Result<Option<String>> {
+        pool.validate()?;
+        let mut state = self.state.lock().await;
+        let mut known = state.pools.values().chain(state.proposals.values());
+        if known
+            .clone()
+            .any(|known| known.chain_id == pool.chain_id && known.pool == pool.pool)
+        {
+            return Ok(None);
+        }
+        if known.any(|known| known.name == pool.name) {
+            pool.name = format!("{}-{}", pool.name, hex::encode(pool.pool.as_bytes()));
+            if state.pools.contains_key(&pool.name) || state.proposals.contains_key(&pool.name) {
+                bail!("pool name {} is already taken", pool.name);
+            }
+        }
+        elog!(
+            "Pool {} at {:?} proposed for the catalog",
+            pool.name,
+            pool.pool
+        );
+        let name = pool.name.clone();
+        state.proposals.insert(name.clone(), pool);
+        Ok(Some(name))
+    }
+
+    pub async fn proposals(&self) -> Vec<PoolConfig> {
+        self.state
+            .lock()
+            .await
+            .proposals
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Move a proposed pool into the catalog. Returns false if no pool of
+    /// that name was proposed.
+    pub async fn accept(&self, name: &str) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        let Some(pool) = state.proposals.remove(name) else {
+            return Ok(false);
+        };
+        self.start(&mut state, pool)?;
+        elog!("Proposed pool {name} accepted into the catalog");
+        self.persist(&state)?;
+        Ok(true)
+    }
+
+    /// Drop a proposed pool. Discovery proposes it again only after a
+    /// restart.
+    pub async fn reject(&self, name: &str) -> bool {
+        self.state.lock().await.proposals.remove(name).is_some()
+    }
+
+    /// Add a pool, or replace the pool of the same name, and persist the
+    /// catalog.
+    pub async fn upsert(&self, pool: PoolConfig) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let name = pool.name.clone();
+        self.start(&mut state, pool)?;
+        elog!("Pool {name} added to the catalog");
+        self.persist(&state)
+    }
+
+    /// Stop every pool's job, waiting for runs in progress to release their
+    /// leases, e.g. when the relay is shutting down. The catalog is kept.
+    pub async fn shutdown(&self) {
+        let mut state = self.state.lock().await;
+        for mut job in state.scheduler.drain() {
+            job.stop().await;
+        }
+        let state = &mut *state;
+        for (_, task) in state.publishers.drain().chain(state.transmitters.drain()) {
+            task.abort();
+        }
+    }
+
+    /// Stop and remove a pool, persisting the catalog. Returns whether the
+    /// pool was in it.
+    pub async fn remove(&self, name: &str) -> Result<bool> {
+        let mut state = self.state.lock().await;
+        if !Self::stop(&mut state, name) {
+            return Ok(false);
+        }
+        elog!("Pool {name} removed from the catalog");
+        self.persist(&state)?;
+        Ok(true)
+    }
+
+    /// Re-read the pools from the catalog file, such as after an operator
+    /// edited their schedules. Pools that are new or changed are (re)started
+    /// and pools no longer listed are stopped; the jobs of unchanged pools
+    /// keep running. Chains, discovery, finality and batches are read at
+    /// startup only.
+    pub async fn reload(&self) -> Result<()> {
+        let config = CatalogConfig::load(&self.path)?;
+        for pool in &config.pools {
+            pool.validate()?;
+        }
+        let mut state = self.state.lock().await;
+        let removed: Vec<String> = state
+            .pools
+            .keys()
+            .filter(|name| !config.pools.iter().any(|pool| &pool.name == *name))
+            .cloned()
+            .collect();
+        for name in removed {
+            Self::stop(&mut state, &name);
+            elog!("Pool {name} removed from the catalog");
+        }
+        for pool in config.pools {
+            if state.pools.get(&pool.name) != Some(&pool) {
+                let name = pool.name.clone();
+                self.start(&mut state, pool)?;
+                elog!("Pool {name} reloaded");
+            }
+        }
+        Ok(())
+    }
+
+    /// Stop a pool's job and tasks, returning whether it was in the catalog.
+    fn stop(state: &mut CatalogState, name: &str) -> bool {
+        if state.pools.remove(name).is_none() {
+            return false;
+        }
+        state.scheduler.remove(name);
+        let tasks = [
+            state.publishers.remove(name),
+            state.transmitters.remove(name),
+        ];
+        for task in tasks.into_iter().flatten() {
+            task.abort();
+        }
+        true
+    }
+
+    fn start(&self, state: &mut CatalogState, pool: PoolConfig) -> Result<()> {
+        pool.validate()?;
+        if let Some(batch) = self
+            .batches
+            .iter()
+            .find(|batch| batch.pools.contains(&pool.name))
+        {
+            elog!("Pool {} is proven by batch {}", pool.name, batch.name);
+            state.pools.insert(pool.name.clone(), pool);
+            return Ok(());
+        }
+        let guest = self
+            .registry
+            .resolve(&pool.guest)
+            .context(format!("Failed to resolve guest of pool {}", pool.name))?;
+        let client = self
+            .clients
+            .get(&pool.chain_id)
+            .ok_or_else(|| {
+                anyhow!(
+                    "pool {} is on chain {}, which has no node configured",
+                    pool.name,
+                    pool.chain_id
+                )
+            })?
+            .clone();
+        let condition = pool
+            .oracle
+            .map(|oracle| Arc::new(Keeper::new(pool.pool, oracle, client.clone())).condition());
+        let finality = self.finality(pool.chain_id);
+        let feed = self.feeds.get(&pool.chain_id).cloned();
+        let reader = feed
+            .as_ref()
+            .map_or_else(|| client.clone(), |feed| feed.client());
+        // HISTORY runs extend the commitment of the previous run, so their
+        // inputs come from its journal and their results must continue it.
+        let (input, verify) = if guest.name.eq_ignore_ascii_case("HISTORY") {
+            // The HISTORY journal cannot flag an unfinalized block, and a
+            // reordered one would fork the commitment later runs extend.
+            if finality.unfinalized() {
+                bail!(
+                    "pool {} proves HISTORY, which cannot use the {finality} policy of chain {}",
+                    pool.name,
+                    pool.chain_id
+                );
+            }
+            let mut guard = ContinuityGuard::new(&pool.name, &guest.name, client.clone());
+            if let Some(run_log) = state.scheduler.run_log() {
+                guard = guard.with_run_log(run_log)?;
+            }
+            if let Some(history) = pool.history {
+                guard = guard.with_contract(history);
+            }
+            let guard = Arc::new(guard);
+            (
+                history_input_fn(reader, guard.clone(), pool.pool, finality),
+                Some(guard.verifier()),
+            )
+        } else {
+            (input_fn(reader, pool.clone(), finality, feed), None)
+        };
+        let run_log = state.scheduler.run_log();
+        let job = Job {
+            name: pool.name.clone(),
+            guest: guest.clone(),
+            interval: Duration::from_secs(pool.window_secs),
+            dev_mode: self.dev_mode,
+            input,
+            prefetch_depth: pool.prefetch_depth,
+            succinct: pool.succinct,
+            condition,
+            verify,
+        };
+        let handle = state.scheduler.add(job);
+        if let Some(updates) = &self.updates {
+            let (updates, name, results) = (updates.clone(), pool.name.clone(), handle.results());
+            let oracle = pool.oracle;
+            let publisher = tokio::spawn(async move {
+                updates
+                    .watch(&name, &guest, oracle, Box::pin(results))
+                    .await
+            });
+            if let Some(previous) = state.publishers.insert(pool.name.clone(), publisher) {
+                previous.abort();
+            }
+        }
+        let signer = self.signers.get(&pool.chain_id).cloned();
+        let transmitter = pool.oracle.zip(signer).map(|(oracle, signer)| {
+            let mut feed = AggregatorFeed::new(oracle, signer);
+            if let Some(run_log) = run_log {
+                feed = feed.with_run_log(run_log);
+            }
+            let (name, results) = (pool.name.clone(), handle.results());
+            tokio::spawn(async move { feed.watch(&name, Box::pin(results)).await })
+        });
+        let previous = match transmitter {
+            Some(transmitter) => state.transmitters.insert(pool.name.clone(), transmitter),
+            None => state.transmitters.remove(&pool.name),
+        };
+        if let Some(previous) = previous {
+            previous.abort();
+        }
+        state.pools.insert(pool.name.clone(), pool);
+        Ok(())
+    }
+
+    /// Start the job proving a batch of the catalog's pools.
+    fn start_batch(&self, state: &mut CatalogState, batch: &BatchConfig) -> Result<()> {
+        if batch.window_secs == 0 {
+            bail!("batch {} has a zero window", batch.name);
+        }
+        if batch.pools.is_empty() {
+            bail!("batch {} has no pools", batch.name);
+        }
+        if batch.prefetch_depth > MAX_PREFETCH_DEPTH {
+            bail!(
+                "batch {} prefetches more than {MAX_PREFETCH_DEPTH} runs",
+                batch.name
+            );
+        }
+        if state.pools.contains_key(&batch.name) {
+            bail!("batch {} has the name of a pool", batch.name);
+        }
+        let pools = batch
+            .pools
+            .iter()
+            .map(|name| {
+                let pool = state
+                    .pools
+                    .get(name)
+                    .ok_or_else(|| anyhow!("batch {} has unknown pool {name}", batch.name))?;
+                if pool.chain_id != batch.chain_id {
+                    bail!(
+                        "pool {name} is on chain {}, not on batch {}'s chain {}",
+                        pool.chain_id,
+                        batch.name,
+                        batch.chain_id
+                    );
+                }
+                Ok(pool.clone())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let guest = self
+            .registry
+            .resolve(&batch.guest)
+            .context(format!("Failed to resolve guest of batch {}", batch.name))?;
+        let client = self
+            .clients
+            .get(&batch.chain_id)
+            .ok_or_else(|| {
+                anyhow!(
+                    "batch {} is on chain {}, which has no node configured",
+                    batch.name,
+                    batch.chain_id
+                )
+            })?
+            .clone();
+        let finality = self.finality(batch.chain_id);
+        let feed = self.feeds.get(&batch.chain_id).cloned();
+        let client = feed.as_ref().map_or(client, |feed| feed.client());
+        let name = batch.name.clone();
+        let input: InputFn = Arc::new(move || {
+            let (client, feed) = (client.clone(), feed.clone());
+            fetch_batch_input(client, name.clone(), pools.clone(), finality, feed).boxed()
+        });
+        let handle = state.scheduler.add(Job {
+            name: batch.name.clone(),
+            guest,
+            interval: Duration::from_secs(batch.window_secs),
+            dev_mode: self.dev_mode,
+            input,
+            prefetch_depth: batch.prefetch_depth,
+            succinct: batch.succinct,
+            condition: None,
+            verify: None,
+        });
+        let signer = self.signers.get(&batch.chain_id).cloned();
+        if let Some((batcher, signer)) = batch.batcher.zip(signer) {
+            let mut transmitter = BatchTransmitter::new(batcher, signer);
+            if let Some(dead_letters) = &self.dead_letters {
+                transmitter = transmitter.with_dead_letters(dead_letters.clone());
+            }
+            let (name, results) = (batch.name.clone(), handle.results());
+            let task =
+                tokio::spawn(async move { transmitter.watch(&name, Box::pin(results)).await });
+            if let Some(previous) = state.transmitters.insert(batch.name.clone(), task) {
+                previous.abort();
+            }
+        }
+        Ok(())
+    }
+
+    /// Atomically rewrite the catalog file.
+    fn persist(&self, state: &CatalogState) -> Result<()> {
+        let config = CatalogConfig {
+            chains: self.chains.clone(),
+            pools: state.pools.values().cloned().collect(),
+            discovery: self.discovery.clone(),
+            finality: self.finality.clone(),
+            sequencer_feeds: self.sequencer_feeds.clone(),
+            batches: self.batches.clone(),
+        };
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let mut file = NamedTempFile::new_in(dir).context("Failed to create temp file")?;
+        serde_json::to_writer_pretty(&mut file, &config)
+            .context(format!("Failed to write {}", self.path.display()))?;
+        file.flush()
+            .context(format!("Failed to write {}", self.path.display()))?;
+        file.persist(&self.path)
+            .context(format!("Failed to persist {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Build the SWAP guest input from the pool's state at the newest block
+/// meeting the chain's finality policy.
+fn input_fn(
+    client: Arc<Provider<Ws>>,
+    pool: PoolConfig,
+    finality: FinalityPolicy,
+    feed: Option<Arc<SequencerFeed>>,
+) -> InputFn {
+    Arc::new(move || fetch_input(client.clone(), pool.clone(), finality, feed.clone()).boxed())
+}
+
+/// The newest block meeting `finality`, the head of the chain's sequencer
+/// feed if it has one.
+async fn anchor_block(
+    client: &Provider<Ws>,
+    finality: FinalityPolicy,
+    feed: Option<&SequencerFeed>,
+) -> Result<Block<H256>> {
+    match feed {
+        Some(feed) => feed.head().await,
+        None => finality.block(client).await,
+    }
+}
+
+fn history_input_fn(
+    client: Arc<Provider<Ws>>,
+    guard: Arc<ContinuityGuard<Provider<Ws>>>,
+    pool: Address,
+    finality: FinalityPolicy,
+) -> InputFn {
+    Arc::new(move || {
+        let (client, guard) = (client.clone(), guard.clone());
+        async move {
+            let state = match guard.latest_journal()? {
+                Some(journal) => HistoryState::from_journal(&journal)?,
+                None => HistoryState::genesis(pool),
+            };
+            let input = HistoryFetcher::new(client.clone(), Arc::new(RpcProofSource(client)))
+                .with_finality(finality)
+                .fetch(&state)
+                .await?;
+            Ok(JobInput {
+                input: input.encode()?,
+                block: None,
+                finality: Some(finality),
+            })
+        }
+        .boxed()
+    })
+}
+
+/// State of a pool at a block, checked against its configuration.
+struct PoolState {
+    sqrt_price_x96: U256,
+    sqrt_price_target_x96: U256,
+    liquidity: u128,
+    fee: u32,
+}
+
+async fn read_pool(
+    client: Arc<Provider<Ws>>,
+    config: &PoolConfig,
+    at: BlockId,
+) -> Result<PoolState> {
+    let pool = UniswapV3Pool::new(config.pool, client);
+    let context = |what: &str| format!("Failed to read {what} of pool {}", config.name);
+    let (sqrt_price_x96, ..) = pool
+        .slot_0()
+        .block(at)
+        .call()
+        .await
+        .context(context("slot0"))?;
+    let liquidity = pool
+        .liquidity()
+        .block(at)
+        .call()
+        .await
+        .context(context("liquidity"))?;
+    if liquidity < config.min_liquidity {
+        bail!(
+            "pool {} has liquidity {liquidity}, below the threshold {}",
+            config.name,
+            config.min_liquidity
+        );
+    }
+    let fee = pool.fee().block(at).call().await.context(context("fee"))?;
+    if fee != config.fee {
+        bail!(
+            "pool {} has fee tier {fee}, not the configured {}",
+            config.name,
+            config.fee
+        );
+    }
+    let sqrt_price_target_x96 = if config.zero_for_one {
+        U256::from(MIN_SQRT_RATIO_PLUS_ONE)
+    } else {
+        U256::from_dec_str(MAX_SQRT_RATIO_MINUS_ONE)?
+    };
+    Ok(PoolState {
+        sqrt_price_x96,
+        sqrt_price_target_x96,
+        liquidity,
+        fee,
+    })
+}
+
+async fn fetch_input(
+    client: Arc<Provider<Ws>>,
+    config: PoolConfig,
+    finality: FinalityPolicy,
+    feed: Option<Arc<SequencerFeed>>,
+) -> Result<JobInput> {
+    let block = anchor_block(client.as_ref(), finality, feed.as_deref()).await?;
+    let number = block
+        .number
+        .ok_or_else(|| anyhow!("Latest block has no number"))?
+        .as_u64();
+    let hash = block
+        .hash
+        .ok_or_else(|| anyhow!("Block {number} has no hash"))?;
+    let state = read_pool(client.clone(), &config, BlockId::from(hash)).await?;
+    let input = SwapInput {
+        request_root: keccak256(abi::encode(&[
+            Token::Address(config.pool),
+            Token::Uint(number.into()),
+        ])),
+        sqrt_price_x96: state.sqrt_price_x96,
+        sqrt_price_target_x96: state.sqrt_price_target_x96,
+        liquidity: state.liquidity,
+        amount_specified: config.probe_amount()?,
+        fee_pips: state.fee,
+        observed_at: block.timestamp.as_u64(),
+        min_liquidity: config.min_liquidity,
+        state: Some(prove_pool_state(client, config.pool, &block, finality).await?),
+    };
+    Ok(JobInput {
+        input: input.encode()?,
+        block: Some(number),
+        finality: Some(finality),
+    })
+}
+
+/// Prove a pool's `slot0` and `liquidity` slots at a block, so the SWAP
+/// guest commits the state it read instead of trusting the relay's reads,
+/// and whether the block was chosen by an unfinalized `finality`.
+async fn prove_pool_state(
+    client: Arc<Provider<Ws>>,
+    pool: Address,
+    block: &Block<H256>,
+    finality: FinalityPolicy,
+) -> Result<PoolStateProof> {
+    let hash = block
+        .hash
+        .ok_or_else(|| anyhow!("Block of pool {pool:?} has no hash"))?;
+    let slots = vec![
+        H256::from_low_u64_be(SLOT0_SLOT),
+        H256::from_low_u64_be(LIQUIDITY_SLOT),
+    ];
+    let response = RpcProofSource(client).get_proof(hash, pool, slots).await?;
+    verify_account(block.state_root, &response)?;
+    let mut storage = response.storage_proof.into_iter().map(|slot| slot.proof);
+    let (Some(slot0_proof), Some(liquidity_proof)) = (storage.next(), storage.next()) else {
+        bail!("proof of pool {pool:?} is missing slot0 or liquidity");
+    };
+    Ok(PoolStateProof {
+        pool,
+        header: encode_header(block)?,
+        account_proof: response.account_proof,
+        slot0_proof,
+        liquidity_proof,
+        unfinalized: finality.unfinalized(),
+    })
+}
+
+/// Build the BATCH guest input from the state of every pool of a batch at
+/// the newest block meeting the chain's finality policy.
+async fn fetch_batch_input(
+    client: Arc<Provider<Ws>>,
+    name: String,
+    pools: Vec<PoolConfig>,
+    finality: FinalityPolicy,
+    feed: Option<Arc<SequencerFeed>>,
+) -> Result<JobInput> {
+    let block = anchor_block(client.as_ref(), finality, feed.as_deref()).await?;
+    let number = block
+        .number
+        .ok_or_else(|| anyhow!("Latest block has no number"))?
+        .as_u64();
+    let mut feeds = Vec::new();
+    for config in &pools {
+        let state = read_pool(client.clone(), config, BlockId::from(number)).await?;
+        feeds.push(BatchFeed {
+            pool: config.pool,
+            sqrt_price_x96: state.sqrt_price_x96,
+            sqrt_price_target_x96: state.sqrt_price_target_x96,
+            liquidity: state.liquidity,
+            amount_specified: config.probe_amount()?,
+            fee_pips: state.fee,
+            min_liquidity: config.min_liquidity,
+        });
+    }
+    let input = BatchInput {
+        request_root: keccak256(abi::encode(&[
+            Token::String(name),
+            Token::Uint(number.into()),
+        ])),
+        observed_at: block.timestamp.as_u64(),
+        feeds,
+        unfinalized: finality.unfinalized(),
+    };
+    Ok(JobInput {
+        input: input.encode()?,
+        block: Some(number),
+        finality: Some(finality),
+    })
+}
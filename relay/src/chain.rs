@@ -0,0 +1,100 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use ethers::{
+    providers::Middleware,
+    types::{Address, Bytes, H160},
+};
+
+use crate::{
+    bindings::{GasPriceOracle, NodeInterface},
+    eth::EthClient,
+};
+
+/// OP stack predeploy quoting the L1 data fee of a transaction,
+/// 0x420000000000000000000000000000000000000F.
+pub const OP_GAS_PRICE_ORACLE: Address = H160([
+    0x42, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x0f,
+]);
+
+/// Arbitrum precompile estimating the L1 component of a transaction's gas,
+/// 0x00000000000000000000000000000000000000C8.
+pub const ARB_NODE_INTERFACE: Address = H160([
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xc8,
+]);
+
+/// How a chain charges for transactions, beyond mainnet-style execution gas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ChainKind {
+    /// Execution gas only.
+    Ethereum,
+    /// OP stack rollups such as Optimism and Base, which charge an L1 data
+    /// fee in wei on top of L2 gas.
+    OpStack,
+    /// Arbitrum chains, which charge L1 data as additional L2 gas.
+    Arbitrum,
+}
+
+impl ChainKind {
+    pub fn from_chain_id(chain_id: u64) -> Self {
+        match chain_id {
+            // Optimism, Base and their Sepolia testnets.
+            10 | 8453 | 11155420 | 84532 => ChainKind::OpStack,
+            // Arbitrum One, Nova and Sepolia.
+            42161 | 42170 | 421614 => ChainKind::Arbitrum,
+            _ => ChainKind::Ethereum,
+        }
+    }
+
+    /// Gas, in L2 gas units, charged for posting `calldata` to `to` on L1.
+    pub async fn l1_gas(
+        self,
+        client: &Arc<EthClient>,
+        to: Address,
+        calldata: Bytes,
+    ) -> Result<u64> {
+        match self {
+            ChainKind::Ethereum => Ok(0),
+            ChainKind::OpStack => {
+                let oracle = GasPriceOracle::new(OP_GAS_PRICE_ORACLE, client.clone());
+                let l1_fee = oracle
+                    .get_l1_fee(calldata)
+                    .call()
+                    .await
+                    .context("Failed to query the L1 data fee")?;
+                let gas_price = client
+                    .get_gas_price()
+                    .await
+                    .context("Failed to query the gas price")?;
+                if gas_price.is_zero() {
+                    return Ok(0);
+                }
+                Ok((l1_fee / gas_price).low_u64())
+            }
+            ChainKind::Arbitrum => {
+                let node = NodeInterface::new(ARB_NODE_INTERFACE, client.clone());
+                let (l1_gas, _, _) = node
+                    .gas_estimate_l1_component(to, false, calldata)
+                    .call()
+                    .await
+                    .context("Failed to estimate the L1 gas component")?;
+                Ok(l1_gas)
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::Read;
+
+use anyhow::Result;
+use risc0_zkvm::{sha::Digest, MemoryImage, Program, Receipt, MEM_SIZE, PAGE_SIZE};
+use sha2::{Digest as _, Sha256};
+
+use crate::error::RelayError;
+
+/// Hex encoded SHA-256 of a buffer, used to identify transferred blobs in
+/// logs and errors.
+pub fn sha256_hex(buf: &[u8]) -> String {
+    hex::encode(Sha256::digest(buf))
+}
+
+/// Compute the image ID of a guest ELF.
+pub fn image_digest(elf: &[u8]) -> Result<Digest> {
+    let program = Program::load_elf(elf, MEM_SIZE as u32)?;
+    let image = MemoryImage::new(&program, PAGE_SIZE as u32)?;
+    Ok(image.compute_id())
+}
+
+/// Check that an ELF still hashes to the image ID it is registered under
+/// before shipping it to Bonsai.
+pub fn verify_image_id(elf: &[u8], expected: Digest) -> Result<()> {
+    let actual = image_digest(elf)?;
+    if actual != expected {
+        return Err(RelayError::ChecksumMismatch {
+            what: "guest ELF image ID".to_string(),
+            expected: hex::encode(expected),
+            actual: hex::encode(actual),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Deserialize a downloaded receipt from a stream, whose length and digest
+/// were recorded while it was downloaded, and check it against the image it
+/// was requested for, so that a truncated or corrupted transfer is reported
+/// as such rather than as an opaque bincode error.
+pub fn read_receipt(
+    reader: impl Read,
+    len: u64,
+    digest: &str,
+    image_id: Digest,
+) -> Result<Receipt, RelayError> {
+    let receipt: Receipt =
+        bincode::deserialize_from(reader).map_err(|source| RelayError::CorruptReceipt {
+            len,
+            digest: digest.to_string(),
+            source,
+        })?;
+    receipt
+        .verify(image_id)
+        .map_err(|err| RelayError::ChecksumMismatch {
+            what: "receipt".to_string(),
+            expected: format!("valid receipt for image {}", hex::encode(image_id)),
+            actual: format!("{err} (sha256 {digest})"),
+        })?;
+    Ok(receipt)
+}
@@ -0,0 +1,148 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Source of the current time for the relay's schedules, staleness checks
+//! and audit timestamps. Everything reads the time through [now] and waits
+//! between blocking polls through [sleep], so tests can control both by
+//! installing their own [Clock], such as the one in `testing`.
+//!
+//! Timestamps committed by guests are block timestamps, set by the chain's
+//! proposers rather than this host. Checks comparing against them prefer the
+//! timestamp of the latest block, and otherwise allow for the configured
+//! [MaxSkew] between the local clock and the chain's.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ethers::{providers::Middleware, types::BlockNumber};
+
+/// Source of the current time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Block the calling thread for `duration` of this clock's time.
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// The host's wall clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+/// Read the time from `clock` from now on instead of the system clock.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    let mut current = match CLOCK.write() {
+        Ok(current) => current,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    *current = Some(clock);
+}
+
+fn installed() -> Option<Arc<dyn Clock>> {
+    match CLOCK.read() {
+        Ok(current) => current.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
+    }
+}
+
+/// Current time of the installed clock, the system clock by default.
+pub fn now() -> SystemTime {
+    match installed() {
+        Some(clock) => clock.now(),
+        None => SystemClock.now(),
+    }
+}
+
+/// Block the calling thread for `duration` of the installed clock's time.
+pub fn sleep(duration: Duration) {
+    match installed() {
+        Some(clock) => clock.sleep(duration),
+        None => SystemClock.sleep(duration),
+    }
+}
+
+/// Seconds since the Unix epoch at `time`, zero before it.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Current Unix time of the installed clock, in seconds.
+pub fn unix_now() -> u64 {
+    unix_secs(now())
+}
+
+/// Largest difference tolerated between the local clock and the clocks of
+/// other hosts, such as other replicas or the chain's proposers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MaxSkew(pub Duration);
+
+impl MaxSkew {
+    /// Whether `deadline` has passed for every clock within the skew of the
+    /// local one, so no host can still consider it ahead.
+    pub fn has_passed(&self, deadline: SystemTime, now: SystemTime) -> bool {
+        now > deadline + self.0
+    }
+}
+
+/// Time to compare block timestamps against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChainTime {
+    /// Timestamp of the latest block, on the same clock as the block
+    /// timestamps compared against it.
+    Block { number: u64, time: SystemTime },
+    /// The local clock, used when the latest block could not be read.
+    Local(SystemTime),
+}
+
+impl ChainTime {
+    /// Read the timestamp of the latest block from `client`, falling back to
+    /// the local clock.
+    pub async fn latest<M: Middleware>(client: &M) -> Self {
+        match client.get_block(BlockNumber::Latest).await {
+            Ok(Some(block)) => Self::Block {
+                number: block.number.unwrap_or_default().as_u64(),
+                time: UNIX_EPOCH + Duration::from_secs(block.timestamp.as_u64()),
+            },
+            _ => Self::Local(now()),
+        }
+    }
+
+    pub fn time(&self) -> SystemTime {
+        match self {
+            Self::Block { time, .. } | Self::Local(time) => *time,
+        }
+    }
+
+    /// Slack to allow comparing against this time: none against a block
+    /// timestamp, and the skew against the local clock.
+    pub fn tolerance(&self, skew: MaxSkew) -> Duration {
+        match self {
+            Self::Block { .. } => Duration::ZERO,
+            Self::Local(_) => skew.0,
+        }
+    }
+}
@@ -0,0 +1,383 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Self-hosted proving: the relay executes guests and farms their segments
+//! out to worker machines, for operators who cannot send inputs to Bonsai.
+//!
+//! Relay and workers speak a minimal protocol over TCP. On connecting, the
+//! worker sends a random 32 byte challenge, which the relay answers with its
+//! HMAC-SHA256 under the secret shared by the cluster; workers drop peers
+//! that answer wrong or late. After that, each message is a big-endian u32
+//! length followed by a bincode encoded [WorkerRequest] or [WorkerResponse].
+//! Only the relay authenticates: workers are trusted no further than the
+//! receipts they return verify. Nothing is encrypted, so workers should
+//! still only be reachable from the relay.
+//!
+//! The protocol is hand rolled rather than gRPC because its payloads are
+//! the zkVM's own [Segment] and [SegmentReceipt], which only have serde
+//! encodings: a protobuf schema would wrap them as opaque bytes anyway, and
+//! bring a gRPC stack into the relay for a single request type.
+
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::{try_join_all, BoxFuture};
+use hmac::{Hmac, Mac};
+use risc0_zkvm::{
+    InnerReceipt, Receipt, Segment, SegmentReceipt, SegmentReceipts, VerifierContext,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+};
+
+use crate::{backend::ProverBackend, elog, pool::ImagePool, registry::Guest, Output};
+
+/// Largest message accepted, enough for the segments of the largest po2.
+const MAX_MESSAGE_LEN: usize = 1 << 30;
+
+/// Size of the challenge workers authenticate the relay with.
+const CHALLENGE_LEN: usize = 32;
+
+/// Time a peer has to answer a worker's challenge.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Shortest shared secret accepted, in bytes.
+const MIN_SECRET_LEN: usize = 16;
+
+/// Workers a segment is tried on before the proof fails.
+const SEGMENT_ATTEMPTS: usize = 3;
+
+#[derive(Serialize, Deserialize)]
+pub enum WorkerRequest {
+    ProveSegment(Segment),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum WorkerResponse {
+    Receipt(SegmentReceipt),
+    Error(String),
+}
+
+async fn write_message<T: Serialize>(
+    stream: &mut (impl AsyncWrite + Unpin),
+    message: &T,
+) -> Result<()> {
+    let buf = bincode::serialize(message).context("Failed to encode message")?;
+    if buf.len() > MAX_MESSAGE_LEN {
+        bail!("message of {} bytes is too large", buf.len());
+    }
+    stream.write_u32(buf.len() as u32).await?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Read a message of up to [MAX_MESSAGE_LEN] bytes. The buffer grows with
+/// the bytes actually received, so a peer cannot make the reader allocate a
+/// large message by only announcing its length.
+async fn read_message<T: DeserializeOwned>(stream: &mut (impl AsyncRead + Unpin)) -> Result<T> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_MESSAGE_LEN {
+        bail!("message of {len} bytes is too large");
+    }
+    let mut buf = Vec::new();
+    stream.take(len as u64).read_to_end(&mut buf).await?;
+    if buf.len() != len {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    bincode::deserialize(&buf).context("Failed to decode message")
+}
+
+/// Secret shared by a relay and its prover workers.
+#[derive(Clone)]
+pub struct ClusterSecret(Arc<[u8]>);
+
+impl ClusterSecret {
+    pub fn new(secret: &str) -> Result<Self> {
+        if secret.len() < MIN_SECRET_LEN {
+            bail!("the cluster secret must be at least {MIN_SECRET_LEN} bytes long");
+        }
+        Ok(Self(secret.as_bytes().into()))
+    }
+
+    fn mac(&self, challenge: &[u8]) -> Result<Hmac<Sha256>> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.0)
+            .map_err(|_| anyhow!("invalid cluster secret"))?;
+        mac.update(challenge);
+        Ok(mac)
+    }
+
+    /// Answer a worker's challenge.
+    async fn answer(&self, stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> Result<()> {
+        let mut challenge = [0; CHALLENGE_LEN];
+        stream
+            .read_exact(&mut challenge)
+            .await
+            .context("Failed to read the worker's challenge")?;
+        let answer = self.mac(&challenge)?.finalize().into_bytes();
+        stream.write_all(&answer).await?;
+        Ok(())
+    }
+
+    /// Challenge a peer to prove it holds the secret.
+    async fn challenge(&self, stream: &mut (impl AsyncRead + AsyncWrite + Unpin)) -> Result<()> {
+        let challenge: [u8; CHALLENGE_LEN] = rand::random();
+        stream.write_all(&challenge).await?;
+        let mut answer = [0; 32];
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_exact(&mut answer))
+            .await
+            .map_err(|_| anyhow!("peer did not answer the challenge in time"))??;
+        self.mac(&challenge)?
+            .verify_slice(&answer)
+            .map_err(|_| anyhow!("peer failed to authenticate"))
+    }
+}
+
+/// Proves guests by executing them locally and proving each segment on one
+/// of a pool of workers. Proofs have no SNARK, see [Output::Stark].
+pub struct ClusterBackend {
+    pool: Arc<ImagePool>,
+    secret: ClusterSecret,
+    idle: Mutex<Vec<SocketAddr>>,
+    available: Semaphore,
+}
+
+impl ClusterBackend {
+    pub fn new(
+        pool: Arc<ImagePool>,
+        workers: Vec<SocketAddr>,
+        secret: ClusterSecret,
+    ) -> Result<Self> {
+        if workers.is_empty() {
+            bail!("a prover cluster needs at least one worker");
+        }
+        Ok(Self {
+            pool,
+            secret,
+            available: Semaphore::new(workers.len()),
+            idle: Mutex::new(workers),
+        })
+    }
+
+    /// Prove a segment on the next idle worker.
+    async fn prove_segment(&self, segment: &WorkerRequest) -> Result<SegmentReceipt> {
+        let _permit = self
+            .available
+            .acquire()
+            .await
+            .context("Worker pool closed")?;
+        let worker = self
+            .idle
+            .lock()
+            .map_err(|_| anyhow!("worker pool lock poisoned"))?
+            .pop()
+            .ok_or_else(|| anyhow!("no idle worker despite a permit"))?;
+        let result = request(worker, &self.secret, segment).await;
+        self.idle
+            .lock()
+            .map_err(|_| anyhow!("worker pool lock poisoned"))?
+            .push(worker);
+        result.context(format!("Worker {worker} failed"))
+    }
+
+    async fn prove_with_retries(&self, segment: Segment) -> Result<SegmentReceipt> {
+        let index = segment.index;
+        let request = WorkerRequest::ProveSegment(segment);
+        let mut attempt = 1;
+        loop {
+            match self.prove_segment(&request).await {
+                Ok(receipt) => return Ok(receipt),
+                Err(err) if attempt < SEGMENT_ATTEMPTS => {
+                    elog!("Retrying segment {index} after attempt {attempt}: {err:?}");
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.context(format!("Failed to prove segment {index}"))),
+            }
+        }
+    }
+}
+
+async fn request(
+    worker: SocketAddr,
+    secret: &ClusterSecret,
+    request: &WorkerRequest,
+) -> Result<SegmentReceipt> {
+    let mut stream = TcpStream::connect(worker)
+        .await
+        .context(format!("Failed to connect to {worker}"))?;
+    secret.answer(&mut stream).await?;
+    write_message(&mut stream, request).await?;
+    match read_message(&mut stream).await? {
+        WorkerResponse::Receipt(receipt) => Ok(receipt),
+        WorkerResponse::Error(err) => bail!("{err}"),
+    }
+}
+
+impl ProverBackend for ClusterBackend {
+    fn snark(&self) -> bool {
+        false
+    }
+
+    fn prove(&self, guest: Arc<Guest>, input: Vec<u8>) -> BoxFuture<'_, Result<Output>> {
+        Box::pin(async move {
+            let (segments, journal) = {
+                let pool = self.pool.clone();
+                let guest = guest.clone();
+                tokio::task::spawn_blocking(move || -> Result<_> {
+                    let session = pool.session(&guest, &input)?;
+                    let segments = session
+                        .segments
+                        .iter()
+                        .map(|segment| segment.resolve())
+                        .collect::<Result<Vec<_>>>()
+                        .context("Failed to resolve segment")?;
+                    Ok((segments, session.journal))
+                })
+                .await
+                .context("Failed to run execution sub-task")??
+            };
+            self.pool.refill_in_background();
+            let receipts =
+                try_join_all(segments.into_iter().map(|s| self.prove_with_retries(s))).await?;
+            let receipt = Receipt::new(InnerReceipt::Flat(SegmentReceipts(receipts)), journal);
+            // Workers are trusted no further than their receipts verify.
+            receipt
+                .verify(guest.image_id)
+                .map_err(|err| anyhow!("Cluster receipt failed to verify: {err}"))?;
+            Ok(Output::Stark {
+                journal: receipt.journal.clone(),
+                receipt,
+            })
+        })
+    }
+}
+
+/// Serve segment proving requests from the relay until the process is
+/// stopped. Each connection carries one request at a time, once the peer
+/// authenticated with `secret`.
+pub async fn serve_worker(addr: SocketAddr, secret: ClusterSecret) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .context(format!("Failed to bind {addr}"))?;
+    elog!("Prover worker listening on {addr}");
+    loop {
+        let (mut stream, peer) = listener.accept().await.context("Failed to accept")?;
+        let secret = secret.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(&mut stream, &secret).await {
+                elog!("Prover worker connection from {peer} failed: {err:?}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: &mut TcpStream, secret: &ClusterSecret) -> Result<()> {
+    secret.challenge(stream).await?;
+    loop {
+        let request = match read_message::<WorkerRequest>(stream).await {
+            Ok(request) => request,
+            // The relay closes connections between requests.
+            Err(err) if is_eof(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let WorkerRequest::ProveSegment(segment) = request;
+        let index = segment.index;
+        let response =
+            tokio::task::spawn_blocking(move || segment.prove(&VerifierContext::default()))
+                .await
+                .context("Failed to run proving sub-task")?;
+        let response = match response {
+            Ok(receipt) => WorkerResponse::Receipt(receipt),
+            Err(err) => {
+                elog!("Failed to prove segment {index}: {err:?}");
+                WorkerResponse::Error(format!("{err:#}"))
+            }
+        };
+        write_message(stream, &response).await?;
+    }
+}
+
+fn is_eof(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<std::io::Error>(),
+        Some(err) if err.kind() == ErrorKind::UnexpectedEof
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_admits_holders_of_the_secret() {
+        let secret = ClusterSecret::new("correct horse battery").unwrap();
+        let (mut relay, mut worker) = duplex(1024);
+        let (answered, challenged) =
+            tokio::join!(secret.answer(&mut relay), secret.challenge(&mut worker));
+        answered.unwrap();
+        challenged.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejects_other_secrets() {
+        let secret = ClusterSecret::new("correct horse battery").unwrap();
+        let other = ClusterSecret::new("incorrect horse battery").unwrap();
+        let (mut relay, mut worker) = duplex(1024);
+        let (answered, challenged) =
+            tokio::join!(other.answer(&mut relay), secret.challenge(&mut worker));
+        answered.unwrap();
+        assert!(challenged.is_err());
+    }
+
+    #[test]
+    fn test_short_secrets_are_rejected() {
+        assert!(ClusterSecret::new("hunter2").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_message_bounds_announced_lengths() {
+        let (mut writer, mut reader) = duplex(1024);
+        writer.write_u32(MAX_MESSAGE_LEN as u32 + 1).await.unwrap();
+        let err = read_message::<Vec<u8>>(&mut reader).await.unwrap_err();
+        assert!(err.to_string().contains("too large"));
+
+        // A length announced but not sent fails once the peer hangs up,
+        // without allocating it.
+        writer.write_u32(MAX_MESSAGE_LEN as u32).await.unwrap();
+        writer.write_all(&[0; 16]).await.unwrap();
+        drop(writer);
+        let err = read_message::<Vec<u8>>(&mut reader).await.unwrap_err();
+        assert!(is_eof(&err));
+    }
+
+    #[tokio::test]
+    async fn test_messages_round_trip() {
+        let (mut writer, mut reader) = duplex(1024);
+        write_message(&mut writer, &vec![1u8, 2, 3]).await.unwrap();
+        let message: Vec<u8> = read_message(&mut reader).await.unwrap();
+        assert_eq!(message, vec![1, 2, 3]);
+    }
+}
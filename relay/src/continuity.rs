@@ -0,0 +1,214 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Host-side checks that each run of an incremental guest extends the state
+//! the job's previous run committed, so the relay never submits a proof that
+//! would fork the history held on chain.
+//!
+//! The guard keeps the job's latest [Commitment], persisted in the run log
+//! when the scheduler has one. Given the `ZkObservationHistory` contract the
+//! results are submitted to, it also checks them against the root the
+//! contract holds, which runs submitted by anyone else may have moved. Jobs
+//! it guards must not prefetch inputs, as each input is built from the
+//! previous run's journal.
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    providers::Middleware,
+    types::{Address, H256},
+};
+use futures::FutureExt;
+
+use crate::{
+    bindings::ZkObservationHistory,
+    elog,
+    scheduler::{Commitment, RunLog, VerifyFn},
+    schema::journal_continuity,
+};
+
+/// Refuses results of a job that do not continue its committed state.
+pub struct ContinuityGuard<M> {
+    job: String,
+    guest_name: String,
+    client: Arc<M>,
+    run_log: Option<Arc<RunLog>>,
+    contract: Option<Address>,
+    latest: Mutex<Option<Commitment>>,
+}
+
+impl<M: Middleware + 'static> ContinuityGuard<M> {
+    /// Guard the results of `job`, running the guest `guest_name`, checking
+    /// anchor blocks through `client`.
+    pub fn new(job: &str, guest_name: &str, client: Arc<M>) -> Self {
+        Self {
+            job: job.to_string(),
+            guest_name: guest_name.to_string(),
+            client,
+            run_log: None,
+            contract: None,
+            latest: Mutex::new(None),
+        }
+    }
+
+    /// Persist commitments in `run_log`, resuming from the one it holds.
+    pub fn with_run_log(mut self, run_log: Arc<RunLog>) -> Result<Self> {
+        let latest = run_log.last_commitment(&self.job).context(format!(
+            "Failed to read the last commitment of job {}",
+            self.job
+        ))?;
+        self.latest = Mutex::new(latest);
+        self.run_log = Some(run_log);
+        Ok(self)
+    }
+
+    /// Check results against the root held by the `ZkObservationHistory`
+    /// contract at `contract`.
+    pub fn with_contract(mut self, contract: Address) -> Self {
+        self.contract = Some(contract);
+        self
+    }
+
+    /// Journal of the latest accepted run, from which the next input is
+    /// built. `None` before the first run.
+    pub fn latest_journal(&self) -> Result<Option<Vec<u8>>> {
+        let latest = self
+            .latest
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        latest
+            .map(|commitment| {
+                hex::decode(commitment.journal.trim_start_matches("0x"))
+                    .context("Failed to decode the last committed journal")
+            })
+            .transpose()
+    }
+
+    /// Check that `journal` extends the latest commitment from a block
+    /// after its anchor, that the anchor is still canonical and that it
+    /// extends the contract's root. Accepted journals become the latest
+    /// commitment.
+    pub async fn verify(&self, journal: &[u8]) -> Result<()> {
+        let continuity = journal_continuity(&self.guest_name, journal)?
+            .ok_or_else(|| anyhow!("{} journals carry no commitment", self.guest_name))?;
+        if let Some(contract) = self.contract {
+            let root = self.contract_root(contract).await?;
+            if continuity.previous_root != root {
+                bail!(
+                    "job {} extends commitment {:?}, but contract {contract:?} holds {root:?}; \
+                     the contract would reject it",
+                    self.job,
+                    continuity.previous_root
+                );
+            }
+        }
+        let block_number = self.block_number(continuity.block_hash).await?;
+        let previous = self
+            .latest
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let run = match previous {
+            Some(previous) => {
+                if continuity.previous_root != previous.root {
+                    bail!(
+                        "job {} extends commitment {:?}, but its last run committed {:?}; \
+                         submitting it would fork the history",
+                        self.job,
+                        continuity.previous_root,
+                        previous.root
+                    );
+                }
+                let canonical = self.canonical_hash(previous.block_number).await?;
+                if canonical != Some(previous.block_hash) {
+                    bail!(
+                        "block {:?} of job {}'s last commitment is no longer canonical",
+                        previous.block_hash,
+                        self.job
+                    );
+                }
+                if block_number <= previous.block_number {
+                    bail!(
+                        "job {} proved block {block_number}, not after block {} of its last \
+                         commitment",
+                        self.job,
+                        previous.block_number
+                    );
+                }
+                previous.run + 1
+            }
+            None => 0,
+        };
+        let commitment = Commitment {
+            run,
+            root: continuity.root,
+            block_hash: continuity.block_hash,
+            block_number,
+            journal: format!("0x{}", hex::encode(journal)),
+        };
+        if let Some(run_log) = &self.run_log {
+            run_log
+                .record_commitment(&self.job, &commitment)
+                .context(format!(
+                    "Failed to record the commitment of job {}",
+                    self.job
+                ))?;
+        }
+        elog!(
+            "Job {} committed {:?} at block {block_number}",
+            self.job,
+            commitment.root
+        );
+        *self.latest.lock().unwrap_or_else(PoisonError::into_inner) = Some(commitment);
+        Ok(())
+    }
+
+    /// The guard as a scheduled job's result check.
+    pub fn verifier(self: Arc<Self>) -> VerifyFn {
+        Arc::new(move |journal| {
+            let guard = self.clone();
+            async move { guard.verify(&journal).await }.boxed()
+        })
+    }
+
+    async fn contract_root(&self, contract: Address) -> Result<H256> {
+        let root = ZkObservationHistory::new(contract, self.client.clone())
+            .root()
+            .call()
+            .await
+            .context(format!("Failed to read the root of contract {contract:?}"))?;
+        Ok(H256(root))
+    }
+
+    async fn block_number(&self, hash: H256) -> Result<u64> {
+        self.client
+            .get_block(hash)
+            .await
+            .context(format!("Failed to read block {hash:?}"))?
+            .and_then(|block| block.number)
+            .map(|number| number.as_u64())
+            .ok_or_else(|| anyhow!("block {hash:?} is unknown to the node"))
+    }
+
+    async fn canonical_hash(&self, number: u64) -> Result<Option<H256>> {
+        Ok(self
+            .client
+            .get_block(number)
+            .await
+            .context(format!("Failed to read block {number}"))?
+            .and_then(|block| block.hash))
+    }
+}
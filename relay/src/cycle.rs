@@ -0,0 +1,225 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inputs of the CYCLE guest, which proves the arbitrage factor of a cycle
+//! of pools, such as A/B, B/C and C/A.
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::Middleware,
+    types::{Address, BlockId, Bytes, H256},
+};
+
+use crate::{
+    bindings::UniswapV3Pool,
+    finality::FinalityPolicy,
+    input::canonicalize,
+    proofs::{encode_header, verify_account, ProofCache, ProofSource},
+    reserve::{pool_address, UNISWAP_V3_INIT_CODE_HASH},
+};
+
+/// Storage slot of `slot0` in a Uniswap v3 pool. Must match the CYCLE
+/// guest.
+const SLOT0_SLOT: u64 = 0;
+
+/// A pool the cycle trades through, with its price proven against the
+/// pool's storage root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleLeg {
+    pub token0: Address,
+    pub token1: Address,
+    pub fee: u32,
+    pub account_proof: Vec<Bytes>,
+    pub slot0_proof: Vec<Bytes>,
+}
+
+/// Input of the CYCLE guest: the prices of the pools trading `start` around
+/// the cycle back into itself, proven at one block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CycleInput {
+    pub factory: Address,
+    pub init_code_hash: H256,
+    /// RLP encoded header of the block.
+    pub header: Bytes,
+    pub start: Address,
+    pub legs: Vec<CycleLeg>,
+}
+
+impl CycleInput {
+    /// ABI encode the input in the layout expected by the guest.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let proof = |nodes: &[Bytes]| {
+            Token::Array(
+                nodes
+                    .iter()
+                    .map(|node| Token::Bytes(node.to_vec()))
+                    .collect(),
+            )
+        };
+        let legs = self
+            .legs
+            .iter()
+            .map(|leg| {
+                Token::Tuple(vec![
+                    Token::Address(leg.token0),
+                    Token::Address(leg.token1),
+                    Token::Uint(leg.fee.into()),
+                    proof(&leg.account_proof),
+                    proof(&leg.slot0_proof),
+                ])
+            })
+            .collect();
+        canonicalize(
+            "CYCLE",
+            &abi::encode(&[
+                Token::Address(self.factory),
+                Token::FixedBytes(self.init_code_hash.as_bytes().to_vec()),
+                Token::Bytes(self.header.to_vec()),
+                Token::Address(self.start),
+                Token::Array(legs),
+            ]),
+        )
+    }
+}
+
+/// Builds CYCLE guest inputs from state proofs of the pools' prices.
+pub struct CycleFetcher<M> {
+    client: Arc<M>,
+    source: Arc<dyn ProofSource>,
+    cache: Option<Arc<ProofCache>>,
+    finality: FinalityPolicy,
+    factory: Address,
+    init_code_hash: H256,
+}
+
+impl<M: Middleware + 'static> CycleFetcher<M> {
+    /// Read pools through `client` and fetch proofs from `source`, for pools
+    /// deployed by the canonical Uniswap v3 `factory`.
+    pub fn new(client: Arc<M>, source: Arc<dyn ProofSource>, factory: Address) -> Result<Self> {
+        Ok(Self {
+            client,
+            source,
+            cache: None,
+            finality: FinalityPolicy::default(),
+            factory,
+            init_code_hash: UNISWAP_V3_INIT_CODE_HASH.parse()?,
+        })
+    }
+
+    /// Derive pool addresses with the pool init code hash of a fork of the
+    /// factory.
+    pub fn with_init_code_hash(mut self, init_code_hash: H256) -> Self {
+        self.init_code_hash = init_code_hash;
+        self
+    }
+
+    /// Serve proofs from `cache` where possible.
+    pub fn with_cache(mut self, cache: Arc<ProofCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Only prove prices at blocks meeting `finality`.
+    pub fn with_finality(mut self, finality: FinalityPolicy) -> Self {
+        self.finality = finality;
+        self
+    }
+
+    /// Prove the prices of `pools`, traded in order from `start` back into
+    /// it, at the latest block meeting the finality policy.
+    pub async fn fetch(&self, start: Address, pools: &[Address]) -> Result<CycleInput> {
+        if pools.len() < 2 {
+            bail!("a cycle needs at least two pools");
+        }
+        if pools.iter().collect::<BTreeSet<_>>().len() != pools.len() {
+            bail!("a cycle trades each pool once");
+        }
+        let block = self.finality.block(self.client.as_ref()).await?;
+        let hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        let at = BlockId::Hash(hash);
+
+        let mut token = start;
+        let mut legs = Vec::new();
+        for &address in pools {
+            let pool = UniswapV3Pool::new(address, self.client.clone());
+            let context = |what: &str| format!("Failed to read {what} of pool {address:?}");
+            let token0 = pool
+                .token_0()
+                .block(at)
+                .call()
+                .await
+                .context(context("token0"))?;
+            let token1 = pool
+                .token_1()
+                .block(at)
+                .call()
+                .await
+                .context(context("token1"))?;
+            let fee = pool.fee().block(at).call().await.context(context("fee"))?;
+            if pool_address(self.factory, self.init_code_hash, token0, token1, fee) != address {
+                bail!(
+                    "pool {address:?} was not deployed by factory {:?}",
+                    self.factory
+                );
+            }
+            token = if token == token0 {
+                token1
+            } else if token == token1 {
+                token0
+            } else {
+                bail!("pool {address:?} does not trade {token:?}");
+            };
+
+            let slots = vec![H256::from_low_u64_be(SLOT0_SLOT)];
+            let response = match &self.cache {
+                Some(cache) => {
+                    cache
+                        .get_proof(self.source.as_ref(), &block, address, &slots)
+                        .await?
+                }
+                None => {
+                    let response = self.source.get_proof(hash, address, slots).await?;
+                    verify_account(block.state_root, &response)?;
+                    response
+                }
+            };
+            let slot0_proof = response
+                .storage_proof
+                .into_iter()
+                .next()
+                .ok_or_else(|| anyhow!("proof of pool {address:?} is missing slot0"))?
+                .proof;
+            legs.push(CycleLeg {
+                token0,
+                token1,
+                fee,
+                account_proof: response.account_proof,
+                slot0_proof,
+            });
+        }
+        if token != start {
+            bail!("pools trade {start:?} into {token:?}, not back into itself");
+        }
+        Ok(CycleInput {
+            factory: self.factory,
+            init_code_hash: self.init_code_hash,
+            header: encode_header(&block)?,
+            start,
+            legs,
+        })
+    }
+}
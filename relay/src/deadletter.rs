@@ -0,0 +1,114 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dead-letter queue of the items of batch results that failed on their own
+//! while the rest of the batch was submitted, kept for operators to inspect
+//! and retry by hand instead of holding back every other item.
+
+use std::{
+    fs::OpenOptions,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{bail, Context, Result};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+
+use crate::{clock, schema::PriceStatus};
+
+/// A failed item of a batch run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub job: String,
+    pub run: u64,
+    pub pool: Address,
+    pub status: PriceStatus,
+    /// Why the item was not submitted.
+    pub reason: String,
+    /// When the item was dead-lettered, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl DeadLetter {
+    pub fn new(job: &str, run: u64, pool: Address, status: PriceStatus, reason: String) -> Self {
+        Self {
+            job: job.to_string(),
+            run,
+            pool,
+            status,
+            reason,
+            timestamp: clock::unix_now(),
+        }
+    }
+}
+
+/// Directory keeping the dead letters of each job as `<job>.jsonl`, one
+/// JSON object per line in the order they were added.
+pub struct DeadLetters {
+    dir: PathBuf,
+    append: Mutex<()>,
+}
+
+impl DeadLetters {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create dead-letter directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            append: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, job: &str) -> Result<PathBuf> {
+        if job.is_empty() || job.starts_with('.') || job.contains(['/', '\\']) {
+            bail!("invalid job name {job:?}");
+        }
+        Ok(self.dir.join(format!("{job}.jsonl")))
+    }
+
+    /// Append a dead letter to its job's queue.
+    pub fn push(&self, letter: &DeadLetter) -> Result<()> {
+        let path = self.path(&letter.job)?;
+        let mut line = serde_json::to_vec(letter).context("Failed to serialize dead letter")?;
+        line.push(b'\n');
+        let _append = self.append.lock().unwrap_or_else(PoisonError::into_inner);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&line))
+            .context(format!("Failed to append to {}", path.display()))
+    }
+
+    /// Dead letters of a job, oldest first.
+    pub fn list(&self, job: &str) -> Result<Vec<DeadLetter>> {
+        let path = self.path(job)?;
+        let file = match std::fs::File::open(&path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).context(format!("Failed to open {}", path.display())),
+        };
+        BufReader::new(file)
+            .lines()
+            .map(|line| {
+                let line = line.context(format!("Failed to read {}", path.display()))?;
+                serde_json::from_str(&line).context(format!("Failed to parse {}", path.display()))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{
+    future::{BoxFuture, Shared},
+    FutureExt,
+};
+
+use crate::Output;
+
+/// Result shared between every requester attached to the same session.
+pub type SharedResult = Result<Arc<Output>, Arc<anyhow::Error>>;
+
+struct Entry {
+    created: Instant,
+    result: Shared<BoxFuture<'static, SharedResult>>,
+}
+
+/// Collapses identical requests arriving within a time window onto a single
+/// proving session.
+///
+/// Requests are keyed by [crate::input::request_key], i.e. by guest and
+/// canonical input. The first request for a key starts the work; later ones
+/// within the window await the same result. Failed sessions are not reused.
+pub struct Deduplicator {
+    window: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl Deduplicator {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Await the result for `key`, starting `work` only if no live session for
+    /// the key exists. Returns the result and whether it was shared with an
+    /// earlier request.
+    pub async fn run<F>(&self, key: String, work: F) -> (SharedResult, bool)
+    where
+        F: Future<Output = anyhow::Result<Output>> + Send + 'static,
+    {
+        let (result, shared) = {
+            let mut entries = match self.entries.lock() {
+                Ok(entries) => entries,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let now = Instant::now();
+            entries.retain(|_, entry| {
+                now.duration_since(entry.created) < self.window
+                    && !matches!(entry.result.peek(), Some(Err(_)))
+            });
+            match entries.get(&key) {
+                Some(entry) => (entry.result.clone(), true),
+                None => {
+                    let result = work
+                        .map(|res| res.map(Arc::new).map_err(Arc::new))
+                        .boxed()
+                        .shared();
+                    entries.insert(
+                        key,
+                        Entry {
+                            created: now,
+                            result: result.clone(),
+                        },
+                    );
+                    (result, false)
+                }
+            }
+        };
+        (result.await, shared)
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    providers::{Middleware, Provider, Ws},
+    types::Address,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    bindings::{PoolCreatedFilter, UniswapV3Factory, UniswapV3Pool},
+    catalog::{PoolCatalog, PoolConfig},
+    elog,
+    tokens::TokenResolver,
+};
+
+/// Blocks of factory events read per request.
+const LOG_CHUNK: u64 = 10_000;
+
+/// Where to discover pools and what the proposed entries look like.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveryConfig {
+    pub chain_id: u64,
+    pub factory: Address,
+    /// Block the factory was deployed at, where the scan starts.
+    pub from_block: u64,
+    /// Only pools between two of these tokens are proposed.
+    pub tokens: Vec<Address>,
+    /// Only pools with at least this much in-range liquidity are proposed.
+    pub min_liquidity: u128,
+    pub interval_secs: u64,
+    /// Window and probe amount of the proposed [PoolConfig]s.
+    pub window_secs: u64,
+    pub probe_amount: String,
+}
+
+impl DiscoveryConfig {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.interval_secs == 0 || self.window_secs == 0 {
+            bail!(
+                "discovery on factory {:?} must have a non-zero interval and window",
+                self.factory
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Enumerates the pools of a factory between allowlisted tokens and proposes
+/// those with enough liquidity for the catalog. Pools are created without
+/// liquidity, so every matching pool is checked again on each scan until it
+/// qualifies.
+pub struct Discovery {
+    config: DiscoveryConfig,
+    factory: UniswapV3Factory<Provider<Ws>>,
+    client: Arc<Provider<Ws>>,
+    tokens: TokenResolver<Provider<Ws>>,
+    catalog: Arc<PoolCatalog>,
+    scan: Mutex<ScanState>,
+}
+
+/// What became of a candidate pool on a scan.
+enum Checked {
+    /// Liquidity is still below the minimum.
+    Pending,
+    Proposed(String),
+    /// A pool at the same address is already in the catalog or proposed.
+    Known,
+}
+
+struct ScanState {
+    next_block: u64,
+    /// Matching pools not proposed yet.
+    candidates: Vec<PoolCreatedFilter>,
+}
+
+impl Discovery {
+    /// One discovery task for each factory in the catalog.
+    pub async fn from_catalog(catalog: &Arc<PoolCatalog>) -> Result<Vec<Self>> {
+        let mut discoveries = Vec::new();
+        for config in catalog.discovery() {
+            let client = catalog.client(config.chain_id).ok_or_else(|| {
+                anyhow!(
+                    "discovery on chain {} has no node configured",
+                    config.chain_id
+                )
+            })?;
+            discoveries.push(Self {
+                config: config.clone(),
+                factory: UniswapV3Factory::new(config.factory, client.clone()),
+                tokens: TokenResolver::new(client.clone()).await?,
+                client,
+                catalog: catalog.clone(),
+                scan: Mutex::new(ScanState {
+                    next_block: config.from_block,
+                    candidates: Vec::new(),
+                }),
+            });
+        }
+        Ok(discoveries)
+    }
+
+    /// Read new pools from the factory and propose the candidates that now
+    /// qualify. Returns the names of the proposed pools.
+    pub async fn scan(&self) -> Result<Vec<String>> {
+        let mut scan = self.scan.lock().await;
+        let latest = self
+            .client
+            .get_block_number()
+            .await
+            .context("Failed to read the latest block number")?
+            .as_u64();
+        let allowed: HashSet<Address> = self.config.tokens.iter().copied().collect();
+        while scan.next_block <= latest {
+            let to = latest.min(scan.next_block + LOG_CHUNK - 1);
+            let created = self
+                .factory
+                .event::<PoolCreatedFilter>()
+                .from_block(scan.next_block)
+                .to_block(to)
+                .query()
+                .await
+                .context(format!(
+                    "Failed to read pools created in blocks {} to {to}",
+                    scan.next_block
+                ))?;
+            scan.candidates.extend(
+                created.into_iter().filter(|pool| {
+                    allowed.contains(&pool.token_0) && allowed.contains(&pool.token_1)
+                }),
+            );
+            scan.next_block = to + 1;
+        }
+
+        let mut proposed = Vec::new();
+        let mut remaining = Vec::new();
+        for candidate in std::mem::take(&mut scan.candidates) {
+            match self.check(&candidate).await {
+                Ok(Checked::Proposed(name)) => proposed.push(name),
+                Ok(Checked::Known) => (),
+                Ok(Checked::Pending) => remaining.push(candidate),
+                Err(err) => {
+                    elog!(
+                        "Failed to check discovered pool {:?}: {err:?}",
+                        candidate.pool
+                    );
+                    remaining.push(candidate);
+                }
+            }
+        }
+        scan.candidates = remaining;
+        Ok(proposed)
+    }
+
+    /// Propose a pool if its liquidity qualifies.
+    async fn check(&self, created: &PoolCreatedFilter) -> Result<Checked> {
+        let liquidity = UniswapV3Pool::new(created.pool, self.client.clone())
+            .liquidity()
+            .call()
+            .await
+            .context("Failed to read pool liquidity")?;
+        if liquidity < self.config.min_liquidity {
+            return Ok(Checked::Pending);
+        }
+        let symbol0 = self.tokens.resolve(created.token_0).await?.symbol;
+        let symbol1 = self.tokens.resolve(created.token_1).await?.symbol;
+        let name: String = format!("{symbol0}-{symbol1}-{}", created.fee)
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+            .collect();
+        let pool = PoolConfig {
+            name,
+            chain_id: self.config.chain_id,
+            pool: created.pool,
+            fee: created.fee,
+            window_secs: self.config.window_secs,
+            oracle: None,
+            guest: "SWAP".to_string(),
+            probe_amount: self.config.probe_amount.clone(),
+            zero_for_one: false,
+            min_liquidity: self.config.min_liquidity,
+            history: None,
+            prefetch_depth: 0,
+            succinct: false,
+        };
+        Ok(match self.catalog.propose(pool).await? {
+            Some(name) => Checked::Proposed(name),
+            None => Checked::Known,
+        })
+    }
+
+    /// Scan on the configured interval until the task is stopped.
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(Duration::from_secs(self.config.interval_secs));
+        loop {
+            ticker.tick().await;
+            match self.scan().await {
+                Ok(proposed) if !proposed.is_empty() => elog!(
+                    "Discovery on factory {:?} found {}",
+                    self.config.factory,
+                    proposed.join(", ")
+                ),
+                Ok(_) => (),
+                Err(err) => elog!(
+                    "Discovery on factory {:?} failed: {err:?}",
+                    self.config.factory
+                ),
+            }
+        }
+    }
+}
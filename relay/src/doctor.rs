@@ -0,0 +1,258 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    providers::{Middleware, Provider, Ws},
+    signers::{LocalWallet, Signer},
+    types::{Address, U256},
+};
+
+use crate::{
+    bonsai_api::{self, ApiRevision},
+    checksum::verify_image_id,
+    registry::GuestRegistry,
+    secrets::{self, Source},
+    store::Store,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Not checked, or not a problem for every deployment.
+    Warn,
+    Fail,
+}
+
+/// Outcome of one self-check.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn from_result(name: impl Into<String>, result: Result<String>) -> Self {
+        let (status, detail) = match result {
+            Ok(detail) => (Status::Ok, detail),
+            Err(err) => (Status::Fail, format!("{err:#}")),
+        };
+        Self {
+            name: name.into(),
+            status,
+            detail,
+        }
+    }
+
+    fn warn(name: impl Into<String>, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            status: Status::Warn,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        write!(f, "[{status:>4}] {}: {}", self.name, self.detail)
+    }
+}
+
+/// What `relay doctor` checks the environment against.
+pub struct DoctorConfig {
+    pub bonsai_api_url: String,
+    pub bonsai_api_key: String,
+    pub dev_mode: bool,
+    pub eth_node: String,
+    pub eth_chain_id: u64,
+    /// Hex private key of the signer, whose balance is checked.
+    pub private_key: Option<String>,
+    /// Signer balance below which the check fails, in wei.
+    pub min_balance: U256,
+    /// Contracts that must have code deployed, with their names.
+    pub contracts: Vec<(&'static str, Address)>,
+}
+
+/// Validate the relay's configuration and environment, and the relay API
+/// server's `store` if given, returning every check rather than stopping at
+/// the first failure.
+pub async fn diagnose(
+    registry: &GuestRegistry,
+    config: &DoctorConfig,
+    store: Option<&Store>,
+) -> Vec<Check> {
+    let mut checks: Vec<Check> = registry
+        .iter()
+        .map(|guest| {
+            let result = guest
+                .elf()
+                .and_then(|elf| verify_image_id(&elf, guest.image_id))
+                .map(|()| format!("image ID {} matches its ELF", hex::encode(guest.image_id)));
+            Check::from_result(format!("guest {}", guest.name), result)
+        })
+        .collect();
+
+    checks.push(check_secrets());
+    checks.push(if config.dev_mode {
+        Check::warn("bonsai", "not used in dev mode")
+    } else {
+        check_bonsai(config).await
+    });
+    checks.push(match store {
+        Some(store) => check_store(store),
+        None => Check::warn("store", "no store given"),
+    });
+
+    let provider = match Provider::<Ws>::connect(&config.eth_node).await {
+        Ok(provider) => provider,
+        Err(err) => {
+            checks.push(Check::from_result(
+                "rpc",
+                Err(anyhow!(err).context(format!("Failed to connect to {}", config.eth_node))),
+            ));
+            return checks;
+        }
+    };
+    checks.push(Check::from_result(
+        "rpc",
+        check_chain(&provider, config).await,
+    ));
+    checks.push(match &config.private_key {
+        Some(private_key) => {
+            Check::from_result("signer", check_signer(&provider, private_key, config).await)
+        }
+        None => Check::warn("signer", "no private key given"),
+    });
+    for (name, address) in &config.contracts {
+        let result = provider
+            .get_code(*address, None)
+            .await
+            .context(format!("Failed to get code of {address:?}"))
+            .and_then(|code| {
+                if code.is_empty() {
+                    bail!("no contract deployed at {address:?}");
+                }
+                Ok(format!("{} bytes of code at {address:?}", code.len()))
+            });
+        checks.push(Check::from_result(*name, result));
+    }
+    checks
+}
+
+/// Report which secrets are set and where from, never their values.
+fn check_secrets() -> Check {
+    let sources = secrets::sources();
+    if sources.is_empty() {
+        return Check::warn("secrets", "none set");
+    }
+    let detail = sources
+        .iter()
+        .map(|(name, source)| match source {
+            Source::File => format!("{name} from secrets file"),
+            Source::Environment => format!("{name} from environment"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    Check::from_result("secrets", Ok(detail))
+}
+
+/// Probe Bonsai's read-only `GET /version`, which the versioned API
+/// authenticates. The alpha API has no route that checks the key without
+/// creating anything, so there the key is left unchecked.
+async fn check_bonsai(config: &DoctorConfig) -> Check {
+    let url = &config.bonsai_api_url;
+    match bonsai_api::probe(url, &config.bonsai_api_key).await {
+        Ok(capabilities) => match capabilities.revision {
+            ApiRevision::V1 => Check::from_result(
+                "bonsai",
+                Ok(format!(
+                    "authenticated with {url}, serving zkVM {}",
+                    capabilities.zkvm_versions.join(", ")
+                )),
+            ),
+            ApiRevision::Alpha => Check::warn(
+                "bonsai",
+                format!("{url} serves the alpha API only, API key not checked"),
+            ),
+        },
+        Err(err) => Check::from_result("bonsai", Err(err)),
+    }
+}
+
+/// Report store migrations that have yet to run.
+fn check_store(store: &Store) -> Check {
+    match store.pending_migrations() {
+        Ok(tenants) if tenants.is_empty() => {
+            Check::from_result("store", Ok("no pending migrations".to_string()))
+        }
+        Ok(tenants) => Check::warn(
+            "store",
+            format!(
+                "jobs of tenants {} are indexed when first listed",
+                tenants.join(", ")
+            ),
+        ),
+        Err(err) => Check::from_result("store", Err(err)),
+    }
+}
+
+async fn check_chain(provider: &Provider<Ws>, config: &DoctorConfig) -> Result<String> {
+    let chain_id = provider
+        .get_chainid()
+        .await
+        .context("Failed to get chain ID")?;
+    if chain_id != config.eth_chain_id.into() {
+        bail!(
+            "node is on chain {chain_id}, expected {}",
+            config.eth_chain_id
+        );
+    }
+    let block = provider
+        .get_block_number()
+        .await
+        .context("Failed to get block number")?;
+    Ok(format!("chain {chain_id} at block {block}"))
+}
+
+async fn check_signer(
+    provider: &Provider<Ws>,
+    private_key: &str,
+    config: &DoctorConfig,
+) -> Result<String> {
+    let wallet: LocalWallet = private_key
+        .trim_start_matches("0x")
+        .parse()
+        .context("Failed to parse private key; only local keys are supported here")?;
+    let balance = provider
+        .get_balance(wallet.address(), None)
+        .await
+        .context("Failed to get signer balance")?;
+    if balance < config.min_balance {
+        bail!(
+            "{:?} holds {balance} wei, below the {} wei minimum",
+            wallet.address(),
+            config.min_balance
+        );
+    }
+    Ok(format!("{:?} holds {balance} wei", wallet.address()))
+}
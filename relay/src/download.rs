@@ -0,0 +1,154 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use risc0_zkvm::{sha::Digest, Receipt};
+use sha2::{Digest as _, Sha256};
+use tempfile::NamedTempFile;
+
+use crate::{checksum::read_receipt, elog, error::RelayError};
+
+/// Emit a progress line every time this many more bytes have been received.
+const PROGRESS_STEP: u64 = 16 * 1024 * 1024;
+
+/// A receipt streamed to a temporary file. The receipt is only deserialized
+/// when [ReceiptFile::load] is called, and the file is removed on drop.
+pub struct ReceiptFile {
+    file: NamedTempFile,
+    len: u64,
+    digest: String,
+}
+
+impl ReceiptFile {
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    /// Size of the downloaded receipt in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Hex encoded SHA-256 of the downloaded bytes.
+    pub fn digest(&self) -> &str {
+        &self.digest
+    }
+
+    /// Deserialize the receipt and verify it against the given image ID.
+    pub fn load(&self, image_id: Digest) -> Result<Receipt, RelayError> {
+        let file = File::open(self.path()).map_err(|err| RelayError::CorruptReceipt {
+            len: self.len,
+            digest: self.digest.clone(),
+            source: Box::new(bincode::ErrorKind::Io(err)),
+        })?;
+        read_receipt(BufReader::new(file), self.len, &self.digest, image_id)
+    }
+}
+
+/// Writer adapter hashing and counting everything written through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Stream the contents of `url` into a temporary file, calling `progress`
+/// with the bytes received so far and the expected total, if known.
+pub fn download_to_file(
+    url: &str,
+    mut progress: impl FnMut(u64, Option<u64>),
+) -> Result<ReceiptFile> {
+    #[cfg(feature = "fault-injection")]
+    if let Some(delay) = crate::faults::receipt_delay() {
+        crate::clock::sleep(delay);
+    }
+    let mut res = reqwest::blocking::get(url)
+        .and_then(|res| res.error_for_status())
+        .context("Failed to request receipt download")?;
+    let total = res.content_length();
+
+    let file = NamedTempFile::new().context("Failed to create receipt file")?;
+    let mut writer = HashingWriter {
+        inner: BufWriter::new(file.reopen().context("Failed to open receipt file")?),
+        hasher: Sha256::new(),
+        written: 0,
+    };
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut reported = 0;
+    loop {
+        let n = res
+            .read(&mut buf)
+            .context("Failed to read receipt stream")?;
+        if n == 0 {
+            break;
+        }
+        writer
+            .write_all(&buf[..n])
+            .context("Failed to write receipt file")?;
+        if writer.written - reported >= PROGRESS_STEP {
+            reported = writer.written;
+            progress(reported, total);
+        }
+    }
+    let HashingWriter {
+        inner,
+        hasher,
+        written: len,
+    } = writer;
+    inner
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .context("Failed to write receipt file")?;
+    progress(len, total);
+
+    if let Some(total) = total {
+        if total != len {
+            anyhow::bail!("Receipt download truncated: expected {total} bytes, got {len}");
+        }
+    }
+
+    let digest = hex::encode(hasher.finalize());
+    Ok(ReceiptFile { file, len, digest })
+}
+
+/// Default progress reporter, printing to stderr.
+pub fn log_progress(received: u64, total: Option<u64>) {
+    match total {
+        Some(total) => elog!("Downloaded {received}/{total} bytes of receipt"),
+        None => elog!("Downloaded {received} bytes of receipt"),
+    }
+}
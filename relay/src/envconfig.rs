@@ -0,0 +1,214 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Configuration of a command from the environment alone, for containers
+//! that cannot mount configuration files. Every setting is read from the
+//! environment variable documented in the command's `--help`, and settings
+//! normally read from a file may instead be given inline.
+
+use std::{env, error::Error as _, ffi::OsString, fmt, io::Write};
+
+use clap::{Arg, ArgAction, Command};
+
+use crate::secrets::SECRET_VARS;
+
+/// Flag selecting configuration from the environment alone. It is read
+/// before the command line is parsed, so that every problem with the
+/// environment is reported at once rather than one by one.
+pub const ENV_CONFIG_FLAG: &str = "--env-config";
+
+/// Settings read from a JSON file, which may instead be given as the JSON
+/// itself in `<VAR>_JSON`. Inline files are written to a temporary file, so
+/// changes the relay makes to them, such as pools accepted through the API,
+/// do not survive a restart.
+pub const INLINE_FILE_VARS: &[&str] = &[
+    "POOLS",
+    "QUEUES",
+    "REQUESTER_POLICY",
+    "SLO",
+    "TENANTS",
+    "TOKENS",
+];
+
+/// Whether the command line asks for configuration from the environment.
+pub fn requested(args: impl IntoIterator<Item = String>) -> bool {
+    args.into_iter().any(|arg| arg == ENV_CONFIG_FLAG)
+}
+
+/// Every problem found with the environment of a command.
+#[derive(Debug, Default)]
+pub struct ConfigReport {
+    /// Required settings that are not set, with their help.
+    pub missing: Vec<(String, String)>,
+    /// Settings that are set but invalid, with why.
+    pub invalid: Vec<(String, String)>,
+}
+
+impl ConfigReport {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.invalid.is_empty()
+    }
+}
+
+impl fmt::Display for ConfigReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Invalid environment configuration: {} missing and {} invalid settings",
+            self.missing.len(),
+            self.invalid.len()
+        )?;
+        for (name, help) in &self.missing {
+            writeln!(f, "  missing {name}: {help}")?;
+        }
+        for (name, reason) in &self.invalid {
+            writeln!(f, "  invalid {name}: {reason}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigReport {}
+
+/// First line of a clap error, without its `error: ` prefix.
+fn reason(err: &clap::Error) -> String {
+    let rendered = err.to_string();
+    let line = rendered.lines().next().unwrap_or_default();
+    line.trim_start_matches("error: ").to_string()
+}
+
+/// Check the value of the variable backing `arg` against its parser.
+fn check_value(arg: &Arg, name: &str, value: &OsString) -> Option<String> {
+    let values: Vec<OsString> = match (arg.get_value_delimiter(), value.to_str()) {
+        (Some(delimiter), Some(value)) => value.split(delimiter).map(OsString::from).collect(),
+        _ => vec![value.clone()],
+    };
+    // Values are parsed by a command holding only the argument's parser, as
+    // the command itself stops at its first error.
+    let check = Command::new("env-config").no_binary_name(true).arg(
+        Arg::new("value")
+            .value_parser(arg.get_value_parser().clone())
+            .action(ArgAction::Set)
+            .allow_hyphen_values(true),
+    );
+    for value in values {
+        if let Err(err) = check.clone().try_get_matches_from([value]) {
+            // Never echo the value of a secret.
+            if SECRET_VARS.contains(&name) {
+                return Some("value is not valid".to_string());
+            }
+            return Some(
+                err.source()
+                    .map_or_else(|| reason(&err), ToString::to_string),
+            );
+        }
+    }
+    None
+}
+
+/// Write the inline JSON of a file setting to a temporary file and point its
+/// variable at it.
+fn inline_file(name: &str, json: &str) -> Result<(), String> {
+    serde_json::from_str::<serde_json::Value>(json)
+        .map_err(|err| format!("invalid JSON: {err}"))?;
+    let mut file = tempfile::Builder::new()
+        .prefix(&format!("relay-{}-", name.to_lowercase()))
+        .suffix(".json")
+        .tempfile()
+        .map_err(|err| format!("failed to create a file for it: {err}"))?;
+    file.write_all(json.as_bytes())
+        .map_err(|err| format!("failed to write it: {err}"))?;
+    let (_, path) = file
+        .keep()
+        .map_err(|err| format!("failed to keep its file: {err}"))?;
+    env::set_var(name, path);
+    Ok(())
+}
+
+/// Prepare and check the environment of `subcommand` of `command`, given
+/// the process's command line. Inline file settings are written out, and
+/// every missing or invalid setting is reported at once. Nothing but the
+/// subcommand and [ENV_CONFIG_FLAG] may be given on the command line.
+pub fn prepare(
+    command: &Command,
+    subcommand: &str,
+    args: impl IntoIterator<Item = String>,
+) -> Result<(), ConfigReport> {
+    let mut report = ConfigReport::default();
+    for arg in args.into_iter().skip(1) {
+        if arg != subcommand && arg != ENV_CONFIG_FLAG {
+            report.invalid.push((
+                arg,
+                format!("command line arguments are not read with {ENV_CONFIG_FLAG}"),
+            ));
+        }
+    }
+    let Some(sub) = command.find_subcommand(subcommand) else {
+        report
+            .invalid
+            .push((subcommand.to_string(), "unknown command".to_string()));
+        return Err(report);
+    };
+
+    for arg in command.get_arguments().chain(sub.get_arguments()) {
+        let Some(name) = arg.get_env().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        if INLINE_FILE_VARS.contains(&name) {
+            let inline = format!("{name}_JSON");
+            if let Some(json) = env::var_os(&inline) {
+                if env::var_os(name).is_some() {
+                    report
+                        .invalid
+                        .push((inline, format!("conflicts with {name}, set only one")));
+                    continue;
+                }
+                let Some(json) = json.to_str() else {
+                    report.invalid.push((inline, "not valid UTF-8".to_string()));
+                    continue;
+                };
+                if let Err(err) = inline_file(name, json) {
+                    report.invalid.push((inline, err));
+                }
+                continue;
+            }
+        }
+        match env::var_os(name) {
+            Some(value) => {
+                if let Some(reason) = check_value(arg, name, &value) {
+                    report.invalid.push((name.to_string(), reason));
+                } else if INLINE_FILE_VARS.contains(&name)
+                    && !std::path::Path::new(&value).is_file()
+                {
+                    report.invalid.push((
+                        name.to_string(),
+                        format!(
+                            "{} is not a file; set {name}_JSON to inline it",
+                            value.to_string_lossy()
+                        ),
+                    ));
+                }
+            }
+            None if arg.is_required_set() && arg.get_default_values().is_empty() => {
+                let help = arg.get_help().map(ToString::to_string).unwrap_or_default();
+                report.missing.push((name.to_string(), help));
+            }
+            None => {}
+        }
+    }
+    match report.is_empty() {
+        true => Ok(()),
+        false => Err(report),
+    }
+}
@@ -0,0 +1,278 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use bonsai_sdk::alpha::SdkErr;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Substrings of a failed session's error message, in lower case, blaming
+/// the guest or its input.
+const GUEST_FAULT_MARKERS: &[&str] = &[
+    "panic",
+    "guest",
+    "exit code",
+    "illegal instruction",
+    "out of memory",
+    "cycle limit",
+    "session limit",
+    "assert",
+];
+
+/// Coarse classification of a failed Bonsai API call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The API key was rejected or is missing.
+    Auth,
+    /// The session, image or receipt does not exist.
+    NotFound,
+    /// Bonsai answered with a 5xx or rate limited the request.
+    Server,
+    /// The request never got a response (connect failure, timeout, reset).
+    Network,
+    /// Anything else, e.g. a malformed request or a local I/O failure.
+    Other,
+}
+
+impl ErrorKind {
+    /// Returns true if repeating the same request may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ErrorKind::Server | ErrorKind::Network)
+    }
+}
+
+impl From<&SdkErr> for ErrorKind {
+    fn from(err: &SdkErr) -> Self {
+        match err {
+            SdkErr::HttpErr(err) => match err.status() {
+                Some(status) => ErrorKind::from(status),
+                None => ErrorKind::Network,
+            },
+            // The alpha SDK flattens every non-success response into this,
+            // keeping the body but not the status, so it cannot tell a
+            // rejected key from an overloaded server. It is taken as the
+            // latter and retried; the versioned API keeps statuses.
+            SdkErr::InternalServerErr(_) => ErrorKind::Server,
+            SdkErr::MissingApiKey | SdkErr::MissingApiUrl => ErrorKind::Auth,
+            SdkErr::ReceiptNotFound => ErrorKind::NotFound,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<StatusCode> for ErrorKind {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Auth,
+            StatusCode::NOT_FOUND => ErrorKind::NotFound,
+            StatusCode::TOO_MANY_REQUESTS => ErrorKind::Server,
+            status if status.is_server_error() => ErrorKind::Server,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+/// Which side a failed proof is the fault of. Guest faults, such as a
+/// rejected input or a failed assertion, fail the same way on every retry;
+/// prover faults may not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+    Guest,
+    Prover,
+}
+
+impl Fault {
+    /// Classify a Bonsai session that ended with a bad `status` by its error
+    /// message. Sessions that timed out, or failed without saying why, are
+    /// taken as prover faults.
+    pub fn of_session(status: &str, error_msg: Option<&str>) -> Self {
+        match (status, error_msg) {
+            ("TIMED_OUT", _) | (_, None) => Fault::Prover,
+            (_, Some(msg)) => {
+                let msg = msg.to_lowercase();
+                match GUEST_FAULT_MARKERS
+                    .iter()
+                    .any(|marker| msg.contains(marker))
+                {
+                    true => Fault::Guest,
+                    false => Fault::Prover,
+                }
+            }
+        }
+    }
+
+    /// Fault of the first classified cause of `err`, if any.
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        err.chain()
+            .find_map(|cause| cause.downcast_ref::<RelayError>())
+            .map(RelayError::fault)
+    }
+}
+
+/// Errors surfaced by the relay while talking to the proving service.
+#[derive(Debug, Error)]
+pub enum RelayError {
+    #[error("{context} failed ({kind:?}): {source}")]
+    Bonsai {
+        context: String,
+        kind: ErrorKind,
+        #[source]
+        source: SdkErr,
+    },
+    #[error("{context} still failing after {attempts} attempts: {source}")]
+    RetriesExhausted {
+        context: String,
+        attempts: u32,
+        #[source]
+        source: SdkErr,
+    },
+    /// The guest failed on its input, in Bonsai or the local executor.
+    #[error("{what} failed in the guest: {message}")]
+    GuestFault { what: String, message: String },
+    /// Bonsai failed to prove a session for reasons of its own.
+    #[error("{what} failed in the prover: {message}")]
+    ProverFault { what: String, message: String },
+    #[error("{what} checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        what: String,
+        expected: String,
+        actual: String,
+    },
+    #[error("downloaded receipt ({len} bytes, sha256 {digest}) is corrupt: {source}")]
+    CorruptReceipt {
+        len: u64,
+        digest: String,
+        #[source]
+        source: bincode::Error,
+    },
+}
+
+impl RelayError {
+    /// Wrap an SDK error, classifying it along the way.
+    pub fn bonsai(context: impl Into<String>, source: SdkErr) -> Self {
+        RelayError::Bonsai {
+            context: context.into(),
+            kind: ErrorKind::from(&source),
+            source,
+        }
+    }
+
+    /// A Bonsai session of `what` that ended with a bad `status`, as a guest
+    /// or prover fault according to [Fault::of_session].
+    pub fn session_failed(what: &str, status: &str, error_msg: Option<String>) -> Self {
+        let what = what.to_string();
+        let message = match &error_msg {
+            Some(msg) => format!("exited with bad status: {status}: {msg}"),
+            None => format!("exited with bad status: {status}"),
+        };
+        match Fault::of_session(status, error_msg.as_deref()) {
+            Fault::Guest => RelayError::GuestFault { what, message },
+            Fault::Prover => RelayError::ProverFault { what, message },
+        }
+    }
+
+    /// Which side the failure is the fault of. Failures talking to Bonsai
+    /// or fetching receipts are the prover's.
+    pub fn fault(&self) -> Fault {
+        match self {
+            RelayError::GuestFault { .. } => Fault::Guest,
+            _ => Fault::Prover,
+        }
+    }
+
+    /// The classification of the underlying failure.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RelayError::Bonsai { kind, .. } => *kind,
+            RelayError::RetriesExhausted { source, .. } => ErrorKind::from(source),
+            // A corrupted download is worth fetching again.
+            RelayError::CorruptReceipt { .. } => ErrorKind::Network,
+            RelayError::ChecksumMismatch { .. } => ErrorKind::Other,
+            RelayError::GuestFault { .. } => ErrorKind::Other,
+            RelayError::ProverFault { .. } => ErrorKind::Server,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    /// Error of a request answered with `status`.
+    fn http_err(status: u16) -> SdkErr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]).unwrap();
+            let response = format!("HTTP/1.1 {status} Whatever\r\ncontent-length: 0\r\n\r\n");
+            stream.write_all(response.as_bytes()).unwrap();
+        });
+        let err = reqwest::blocking::get(url)
+            .unwrap()
+            .error_for_status()
+            .unwrap_err();
+        server.join().unwrap();
+        SdkErr::HttpErr(err)
+    }
+
+    #[test]
+    fn test_http_errors_are_classified_by_status() {
+        for (status, kind) in [
+            (401, ErrorKind::Auth),
+            (403, ErrorKind::Auth),
+            (404, ErrorKind::NotFound),
+            (429, ErrorKind::Server),
+            (500, ErrorKind::Server),
+            (503, ErrorKind::Server),
+            (400, ErrorKind::Other),
+        ] {
+            assert_eq!(ErrorKind::from(&http_err(status)), kind, "status {status}");
+        }
+    }
+
+    #[test]
+    fn test_unanswered_requests_are_network_errors() {
+        // Nothing listens on the port once the listener is dropped.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = reqwest::blocking::get(format!("http://{addr}")).unwrap_err();
+        let kind = ErrorKind::from(&SdkErr::HttpErr(err));
+        assert_eq!(kind, ErrorKind::Network);
+        assert!(kind.is_transient());
+    }
+
+    #[test]
+    fn test_sdk_errors_are_classified_by_variant() {
+        // Message text plays no part, even where it names a status.
+        let flattened = SdkErr::InternalServerErr("401 Unauthorized".to_string());
+        assert_eq!(ErrorKind::from(&flattened), ErrorKind::Server);
+        assert_eq!(ErrorKind::from(&SdkErr::MissingApiKey), ErrorKind::Auth);
+        assert_eq!(
+            ErrorKind::from(&SdkErr::ReceiptNotFound),
+            ErrorKind::NotFound
+        );
+        assert_eq!(ErrorKind::from(&SdkErr::ImageIdExists), ErrorKind::Other);
+    }
+}
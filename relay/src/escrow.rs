@@ -0,0 +1,170 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    types::{Address, U256},
+    utils::keccak256,
+};
+
+use crate::{
+    bindings::{CallbackRequestFilter, FeeEscrow},
+    eth::EthClient,
+};
+
+/// ID of a callback request, matching `FeeEscrow.requestId`. Identical
+/// requests share it.
+pub fn request_id(request: &CallbackRequestFilter) -> [u8; 32] {
+    keccak256(abi::encode(&[
+        Token::Address(request.account),
+        Token::FixedBytes(request.image_id.to_vec()),
+        Token::Bytes(request.input.to_vec()),
+        Token::Address(request.callback_contract),
+        Token::FixedBytes(request.function_selector.to_vec()),
+        Token::Uint(request.gas_limit.into()),
+    ]))
+}
+
+/// ID under which the fee of the `nonce`th deposit for a request is
+/// escrowed, matching `FeeEscrow.escrowId`.
+pub fn escrow_id(request_id: [u8; 32], nonce: u64) -> [u8; 32] {
+    keccak256(abi::encode(&[
+        Token::FixedBytes(request_id.to_vec()),
+        Token::Uint(nonce.into()),
+    ]))
+}
+
+/// Fee escrow the relay is paid through. Requests whose escrowed fee is below
+/// the configured price are not served.
+///
+/// The escrow fulfils the deposits of identical requests in nonce order as
+/// their callback contract confirms them, so the relay serves the `n`th
+/// unfulfilled one against the deposit `n` nonces past the fulfilled ones.
+/// Nonces of requests being served are reserved until they are claimed or
+/// given up, so concurrent identical requests are not served on one fee.
+pub struct Escrow {
+    contract: FeeEscrow<EthClient>,
+    price: U256,
+    reserved: Mutex<HashMap<[u8; 32], HashSet<u64>>>,
+}
+
+impl Escrow {
+    pub fn new(address: Address, client: Arc<EthClient>, price: U256) -> Self {
+        Self {
+            contract: FeeEscrow::new(address, client),
+            price,
+            reserved: Default::default(),
+        }
+    }
+
+    /// Reserve the next unfulfilled deposit of a request, checking its fee
+    /// covers the price.
+    pub async fn reserve(&self, request_id: [u8; 32]) -> Result<Reservation<'_>> {
+        let fulfilled = self
+            .contract
+            .fulfilled_count(request_id)
+            .call()
+            .await
+            .context(format!(
+                "Failed to read fulfilments of request {}",
+                hex::encode(request_id)
+            ))?;
+        let nonce = {
+            let mut reserved = self
+                .reserved
+                .lock()
+                .map_err(|_| anyhow!("escrow reservations lock poisoned"))?;
+            let reserved = reserved.entry(request_id).or_default();
+            let nonce = (fulfilled..)
+                .find(|nonce| !reserved.contains(nonce))
+                .ok_or_else(|| anyhow!("request nonces are exhausted"))?;
+            reserved.insert(nonce);
+            nonce
+        };
+        let reservation = Reservation {
+            escrow: self,
+            request_id,
+            nonce,
+        };
+        let id = reservation.id();
+        let fee = self
+            .contract
+            .fee_of(id)
+            .call()
+            .await
+            .context(format!("Failed to read fee of deposit {}", hex::encode(id)))?;
+        if fee < self.price {
+            bail!(
+                "escrowed fee of deposit {nonce} of the request is {fee}, below the price {}",
+                self.price
+            );
+        }
+        Ok(reservation)
+    }
+
+    fn release(&self, request_id: [u8; 32], nonce: u64) {
+        let mut reserved = match self.reserved.lock() {
+            Ok(reserved) => reserved,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if let Some(nonces) = reserved.get_mut(&request_id) {
+            nonces.remove(&nonce);
+            if nonces.is_empty() {
+                reserved.remove(&request_id);
+            }
+        }
+    }
+}
+
+/// A deposit reserved for a request being served, released when dropped.
+pub struct Reservation<'a> {
+    escrow: &'a Escrow,
+    request_id: [u8; 32],
+    nonce: u64,
+}
+
+impl Reservation<'_> {
+    /// ID the reserved fee is escrowed under.
+    pub fn id(&self) -> [u8; 32] {
+        escrow_id(self.request_id, self.nonce)
+    }
+
+    /// Claim the reserved fee once the callback contract confirmed the
+    /// request's fulfilment.
+    pub async fn claim(self) -> Result<()> {
+        let id = self.id();
+        let call = self.escrow.contract.claim(id);
+        call.send()
+            .await
+            .context(format!(
+                "Failed to claim fee of deposit {}",
+                hex::encode(id)
+            ))?
+            .await
+            .context("Failed to await fee claim")?;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.escrow.release(self.request_id, self.nonce);
+    }
+}
@@ -0,0 +1,56 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use ethers::{
+    middleware::{MiddlewareBuilder, NonceManagerMiddleware, SignerMiddleware},
+    providers::{Middleware, Provider, Ws},
+    signers::{LocalWallet, Signer},
+    types::{BlockNumber, U256},
+};
+
+/// Signing Ethereum client used by the listener and submitter. Nonces are
+/// tracked locally so concurrent submissions do not collide.
+pub type EthClient = NonceManagerMiddleware<SignerMiddleware<Provider<Ws>, LocalWallet>>;
+
+/// Connect to a websocket Ethereum node, signing with a hex private key.
+pub async fn connect(eth_node: &str, chain_id: u64, private_key: &str) -> Result<Arc<EthClient>> {
+    let wallet: LocalWallet = private_key
+        .trim_start_matches("0x")
+        .parse()
+        .context("Failed to parse private key; only local keys are supported here")?;
+    let wallet = wallet.with_chain_id(chain_id);
+    let address = wallet.address();
+    let provider = Provider::<Ws>::connect(eth_node)
+        .await
+        .context(format!("Failed to connect to {eth_node}"))?;
+    Ok(Arc::new(
+        provider.with_signer(wallet).nonce_manager(address),
+    ))
+}
+
+/// Current base fee and EIP-4844 blob base fee, in wei per gas. `None` if the
+/// chain does not support blobs or the node does not report their fee.
+pub async fn blob_fees(client: &EthClient) -> Option<(u128, u128)> {
+    let block = client.get_block(BlockNumber::Latest).await.ok()??;
+    let base_fee = block.base_fee_per_gas?;
+    let blob_base_fee: U256 = client
+        .provider()
+        .request("eth_blobBaseFee", ())
+        .await
+        .ok()?;
+    Some((base_fee.as_u128(), blob_base_fee.as_u128()))
+}
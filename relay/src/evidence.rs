@@ -0,0 +1,469 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Evidence of how the listener answered each callback request, kept so a
+//! disputed answer can be exported as a single archive for counterparties or
+//! an arbitration process. The evidence of a request is kept in a directory
+//! named by its request ID:
+//!
+//! - `request.json`: the request event and the raw log it was emitted in, the
+//!   checks the request and its result went through, in order, and the
+//!   transaction submitting the result.
+//! - The artifacts of the run answering the request, as written by [Artifacts],
+//!   in a directory named by session ID.
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    contract::LogMeta,
+    types::{Address, Log, H256},
+};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{
+    artifacts::Artifacts, bindings::CallbackRequestFilter, checksum::sha256_hex, clock,
+    escrow::request_id, receipt::ReceiptEnvelope, registry::GuestRegistry,
+};
+
+const RECORD_FILE: &str = "request.json";
+
+/// Outcome of a check a request or its result went through.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Check {
+    pub check: String,
+    pub passed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the check completed, in seconds since the Unix epoch.
+    pub at: u64,
+}
+
+/// Checks in the order they were made.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Transcript(pub Vec<Check>);
+
+impl Transcript {
+    /// Record the outcome of `check`, passing it on.
+    pub fn check<T>(&mut self, check: &str, result: Result<T>) -> Result<T> {
+        self.0.push(Check {
+            check: check.to_string(),
+            passed: result.is_ok(),
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+            at: clock::unix_now(),
+        });
+        result
+    }
+}
+
+/// A callback request as emitted by the relay contract.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEvent {
+    pub account: Address,
+    pub image_id: String,
+    pub input: String,
+    pub callback_contract: Address,
+    pub function_selector: String,
+    pub gas_limit: u64,
+    /// Log the event was emitted in, if known.
+    pub log: Option<LogMeta>,
+    /// The log itself, with the topics and data the event was decoded from,
+    /// so it can be checked against the chain independently of the relay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_log: Option<Log>,
+}
+
+/// Everything kept about a callback request besides its run's artifacts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvidenceRecord {
+    pub request_id: String,
+    pub event: RequestEvent,
+    /// Guest the request resolved to, if it resolved.
+    pub guest: Option<String>,
+    pub transcript: Transcript,
+    /// Transaction submitting the result, if it was submitted.
+    pub submission: Option<H256>,
+    /// Why the request was not answered, if it was not.
+    pub error: Option<String>,
+    /// When the record was last written, in seconds since the Unix epoch.
+    pub recorded_at: u64,
+}
+
+impl EvidenceRecord {
+    /// Record of `request`, decoded from `log` if known.
+    pub fn new(request: &CallbackRequestFilter, log: Option<Log>) -> Self {
+        Self {
+            request_id: hex::encode(request_id(request)),
+            event: RequestEvent {
+                account: request.account,
+                image_id: hex::encode(request.image_id),
+                input: hex::encode(&request.input),
+                callback_contract: request.callback_contract,
+                function_selector: hex::encode(request.function_selector),
+                gas_limit: request.gas_limit,
+                log: log.as_ref().and_then(log_meta),
+                raw_log: log,
+            },
+            guest: None,
+            transcript: Transcript::default(),
+            submission: None,
+            error: None,
+            recorded_at: clock::unix_now(),
+        }
+    }
+}
+
+/// Where a mined log was emitted. Pending logs have no such metadata.
+pub fn log_meta(log: &Log) -> Option<LogMeta> {
+    Some(LogMeta {
+        address: log.address,
+        block_number: log.block_number?,
+        block_hash: log.block_hash?,
+        transaction_hash: log.transaction_hash?,
+        transaction_index: log.transaction_index?,
+        log_index: log.log_index?,
+    })
+}
+
+/// A file of an exported archive, as listed in its manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// `MANIFEST.json` of an exported archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub request_id: String,
+    pub submission: Option<H256>,
+    pub exported_at: u64,
+    /// Every other file of the archive.
+    pub files: Vec<ManifestEntry>,
+}
+
+/// `verification.json` of an exported archive: the checks recorded while
+/// the request was answered, and those made again on export.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Verification {
+    pub recorded: Transcript,
+    pub exported: Transcript,
+}
+
+/// Directory of the evidence of every callback request the listener
+/// handles.
+pub struct Evidence {
+    dir: PathBuf,
+}
+
+impl Evidence {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create evidence directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn request_dir(&self, request_id: &str) -> Result<PathBuf> {
+        let request_id = request_id.trim_start_matches("0x");
+        if request_id.len() != 64 || !request_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid request ID {request_id:?}");
+        }
+        Ok(self.dir.join(request_id.to_ascii_lowercase()))
+    }
+
+    /// Artifacts of the run answering a request.
+    pub fn artifacts(&self, request_id: &str) -> Result<Artifacts> {
+        Artifacts::open(&self.request_dir(request_id)?)
+    }
+
+    /// Atomically write the record of a request, replacing any previous one.
+    pub fn record(&self, record: &EvidenceRecord) -> Result<()> {
+        let dir = self.request_dir(&record.request_id)?;
+        std::fs::create_dir_all(&dir).context(format!("Failed to create {}", dir.display()))?;
+        let path = dir.join(RECORD_FILE);
+        let mut file = NamedTempFile::new_in(&dir).context("Failed to create temp file")?;
+        serde_json::to_writer_pretty(&mut file, record)
+            .context(format!("Failed to write {}", path.display()))?;
+        file.persist(&path)
+            .context(format!("Failed to persist {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn load(&self, request_id: &str) -> Result<EvidenceRecord> {
+        let path = self.request_dir(request_id)?.join(RECORD_FILE);
+        let file = std::fs::File::open(&path).context(format!(
+            "No evidence of request {request_id} at {}",
+            path.display()
+        ))?;
+        serde_json::from_reader(file).context(format!("Failed to parse {}", path.display()))
+    }
+
+    /// Write the evidence of a request to `out` as a tar archive of a single
+    /// `evidence-<request-id>` directory. Next to the kept files, it holds
+    /// `verification.json`, with every receipt verified again against the
+    /// requested image, and `MANIFEST.json`, with the digest of every file.
+    /// Returns the manifest.
+    pub fn export(
+        &self,
+        request_id: &str,
+        registry: &GuestRegistry,
+        out: &Path,
+    ) -> Result<Manifest> {
+        let record = self.load(request_id)?;
+        let dir = self.request_dir(request_id)?;
+        let mut files = Vec::new();
+        collect_files(&dir, "", &mut files)?;
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut exported = Transcript::default();
+        let guest = exported.check("guest", registry.resolve(&record.event.image_id));
+        for (path, data) in &files {
+            let Some(session) = path.strip_suffix("/receipt.bin") else {
+                continue;
+            };
+            let receipt = exported.check(&format!("{path} decodes"), ReceiptEnvelope::decode(data));
+            let (Ok(receipt), Ok(guest)) = (receipt, &guest) else {
+                continue;
+            };
+            let _ = exported.check(
+                &format!("{path} verifies against image {}", record.event.image_id),
+                receipt.verify(guest.image_id),
+            );
+            let journal = files
+                .iter()
+                .find(|(path, _)| *path == format!("{session}/journal.bin"))
+                .map(|(_, journal)| journal.as_slice());
+            let _ = exported.check(
+                &format!("{session}/journal.bin matches the receipt"),
+                match journal {
+                    Some(journal) if journal == receipt.journal() => Ok(()),
+                    Some(_) => Err(anyhow!("journal differs from the receipt's")),
+                    None => Err(anyhow!("no journal was kept")),
+                },
+            );
+        }
+        let verification = Verification {
+            recorded: record.transcript.clone(),
+            exported,
+        };
+        files.push((
+            "verification.json".to_string(),
+            serde_json::to_vec_pretty(&verification)?,
+        ));
+
+        let manifest = Manifest {
+            request_id: record.request_id.clone(),
+            submission: record.submission,
+            exported_at: clock::unix_now(),
+            files: files
+                .iter()
+                .map(|(path, data)| ManifestEntry {
+                    path: path.clone(),
+                    size: data.len() as u64,
+                    sha256: sha256_hex(data),
+                })
+                .collect(),
+        };
+        files.push((
+            "MANIFEST.json".to_string(),
+            serde_json::to_vec_pretty(&manifest)?,
+        ));
+
+        let parent = match out.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(parent).context("Failed to create temp file")?;
+        let root = format!("evidence-{}", record.request_id);
+        for (path, data) in &files {
+            append_tar(
+                &mut file,
+                &format!("{root}/{path}"),
+                data,
+                manifest.exported_at,
+            )
+            .context(format!("Failed to write {}", out.display()))?;
+        }
+        // A tar archive ends with two zero blocks.
+        file.write_all(&[0; 1024])
+            .context(format!("Failed to write {}", out.display()))?;
+        file.persist(out)
+            .context(format!("Failed to persist {}", out.display()))?;
+        Ok(manifest)
+    }
+}
+
+/// Read every file below `dir`, named by its path relative to the
+/// directory `prefix` names. Temp files of writes in progress are skipped.
+fn collect_files(dir: &Path, prefix: &str, files: &mut Vec<(String, Vec<u8>)>) -> Result<()> {
+    let entries = std::fs::read_dir(dir).context(format!("Failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry.context(format!("Failed to read {}", dir.display()))?;
+        let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let relative = match prefix {
+            "" => name,
+            prefix => format!("{prefix}/{name}"),
+        };
+        if path.is_dir() {
+            collect_files(&path, &relative, files)?;
+        } else {
+            let data =
+                std::fs::read(&path).context(format!("Failed to read {}", path.display()))?;
+            files.push((relative, data));
+        }
+    }
+    Ok(())
+}
+
+/// Append a regular file to a ustar archive. Its directory goes in the
+/// header's 155 byte prefix and its name in the 100 byte name field.
+fn append_tar(out: &mut impl Write, path: &str, data: &[u8], mtime: u64) -> Result<()> {
+    let (prefix, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if prefix.len() > 155 || name.len() > 100 {
+        bail!("path {path} is too long for a tar header");
+    }
+    let mut header = [0u8; 512];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, b"0000644\0");
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", data.len()).as_bytes());
+    field(136, format!("{mtime:011o}\0").as_bytes());
+    // The checksum is taken with its own field set to spaces.
+    field(148, b"        ");
+    field(156, b"0");
+    field(257, b"ustar\0");
+    field(263, b"00");
+    field(345, prefix.as_bytes());
+    let checksum: u32 = header.iter().map(|byte| u32::from(*byte)).sum();
+    header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+    out.write_all(&header)?;
+    out.write_all(data)?;
+    out.write_all(&vec![0; (512 - data.len() % 512) % 512])?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::process::Command;
+
+    use super::*;
+
+    fn record() -> EvidenceRecord {
+        let request = CallbackRequestFilter {
+            image_id: [0x22; 32],
+            input: vec![1, 2, 3].into(),
+            gas_limit: 100_000,
+            ..Default::default()
+        };
+        let log = Log {
+            address: Address::repeat_byte(0x33),
+            topics: vec![H256::repeat_byte(0x44)],
+            data: vec![5, 6].into(),
+            block_number: Some(7.into()),
+            block_hash: Some(H256::repeat_byte(0x55)),
+            transaction_hash: Some(H256::repeat_byte(0x66)),
+            transaction_index: Some(0.into()),
+            log_index: Some(1.into()),
+            ..Default::default()
+        };
+        EvidenceRecord::new(&request, Some(log))
+    }
+
+    #[test]
+    fn test_record_keeps_the_raw_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let evidence = Evidence::open(dir.path()).unwrap();
+        let record = record();
+        evidence.record(&record).unwrap();
+
+        let loaded = evidence.load(&record.request_id).unwrap();
+        let raw_log = loaded.event.raw_log.unwrap();
+        assert_eq!(raw_log.topics, [H256::repeat_byte(0x44)]);
+        assert_eq!(raw_log.data.to_vec(), [5, 6]);
+        assert_eq!(loaded.event.log.unwrap().log_index, 1.into());
+    }
+
+    #[test]
+    fn test_export_round_trips_through_tar() {
+        let dir = tempfile::tempdir().unwrap();
+        let evidence = Evidence::open(dir.path()).unwrap();
+        let record = record();
+        evidence.record(&record).unwrap();
+        // Files filling a block exactly and spilling over one, under a
+        // directory long enough to need the header's prefix field.
+        let session = "s".repeat(60);
+        let session_dir = dir.path().join(&record.request_id).join(&session);
+        std::fs::create_dir_all(&session_dir).unwrap();
+        std::fs::write(session_dir.join("journal.bin"), [7; 512]).unwrap();
+        std::fs::write(session_dir.join("stdout.log"), [b'x'; 700]).unwrap();
+
+        let out = dir.path().join("evidence.tar");
+        let manifest = evidence
+            .export(&record.request_id, &GuestRegistry::default(), &out)
+            .unwrap();
+
+        let extracted = tempfile::tempdir().unwrap();
+        let status = Command::new("tar")
+            .arg("-xf")
+            .arg(&out)
+            .arg("-C")
+            .arg(extracted.path())
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let root = extracted
+            .path()
+            .join(format!("evidence-{}", record.request_id));
+        assert!(manifest
+            .files
+            .iter()
+            .any(|entry| entry.path == format!("{session}/stdout.log") && entry.size == 700));
+        for entry in &manifest.files {
+            let data = std::fs::read(root.join(&entry.path)).unwrap();
+            assert_eq!(data.len() as u64, entry.size, "{}", entry.path);
+            assert_eq!(sha256_hex(&data), entry.sha256, "{}", entry.path);
+        }
+        let exported: Manifest =
+            serde_json::from_slice(&std::fs::read(root.join("MANIFEST.json")).unwrap()).unwrap();
+        assert_eq!(exported.files.len(), manifest.files.len());
+    }
+
+    #[test]
+    fn test_append_tar_rejects_long_names() {
+        let mut out = Vec::new();
+        assert!(append_tar(&mut out, &"n".repeat(101), b"", 0).is_err());
+        assert!(append_tar(&mut out, &format!("{}/name", "d".repeat(156)), b"", 0).is_err());
+        assert!(out.is_empty());
+    }
+}
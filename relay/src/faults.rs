@@ -0,0 +1,338 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fault injection for exercising the relay's retry, resume and dead-letter
+//! paths deterministically: failed Bonsai calls, delayed receipt downloads,
+//! RPC timeouts and reorgs. Only built with the `fault-injection` feature.
+//!
+//! Bonsai and receipt faults apply to the whole process while a [FaultGuard]
+//! returned by [install] is held; guards are exclusive, so tests installing
+//! faults run one at a time. RPC faults and reorgs apply to calls through a
+//! [FaultyMiddleware] wrapping the node client.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bonsai_sdk::alpha::SdkErr;
+use ethers::{
+    providers::{Middleware, MiddlewareError},
+    types::{
+        transaction::eip2718::TypedTransaction, Block, BlockId, Bytes, EIP1186ProofResponse,
+        NameOrAddress, TxHash, H256, U64,
+    },
+    utils::keccak256,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::elog;
+
+/// Environment variable holding a JSON [FaultPlan] to install at startup.
+pub const FAULTS_ENV: &str = "RELAY_FAULTS";
+
+/// Faults to inject. Random faults are drawn from a generator seeded with
+/// `seed`, so a plan fails the same calls on every run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FaultPlan {
+    pub seed: u64,
+    /// Probability of a Bonsai API call failing with a 503.
+    pub bonsai_error_rate: f64,
+    /// Delay added to every receipt download, in milliseconds.
+    pub receipt_delay_ms: u64,
+    /// Probability of an RPC call through a [FaultyMiddleware] timing out.
+    pub rpc_timeout_rate: f64,
+}
+
+/// Number of faults injected so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FaultCounts {
+    pub bonsai_errors: u64,
+    pub receipt_delays: u64,
+    pub rpc_timeouts: u64,
+}
+
+/// Injects the faults of a [FaultPlan] and the reorgs a test asks for.
+#[derive(Debug)]
+pub struct Faults {
+    plan: FaultPlan,
+    rng: Mutex<StdRng>,
+    /// First block replaced by each reorg so far, in order.
+    reorgs: Mutex<Vec<u64>>,
+    bonsai_errors: AtomicU64,
+    receipt_delays: AtomicU64,
+    rpc_timeouts: AtomicU64,
+}
+
+impl Faults {
+    pub fn new(plan: FaultPlan) -> Self {
+        Self {
+            rng: Mutex::new(StdRng::seed_from_u64(plan.seed)),
+            plan,
+            reorgs: Mutex::new(Vec::new()),
+            bonsai_errors: AtomicU64::new(0),
+            receipt_delays: AtomicU64::new(0),
+            rpc_timeouts: AtomicU64::new(0),
+        }
+    }
+
+    /// Replace every block from `from_block` on with a block of a different
+    /// hash, as seen through a [FaultyMiddleware]. Reorgs stack.
+    pub fn reorg(&self, from_block: u64) {
+        elog!("Injecting a reorg from block {from_block}");
+        self.reorgs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(from_block);
+    }
+
+    pub fn counts(&self) -> FaultCounts {
+        FaultCounts {
+            bonsai_errors: self.bonsai_errors.load(Ordering::SeqCst),
+            receipt_delays: self.receipt_delays.load(Ordering::SeqCst),
+            rpc_timeouts: self.rpc_timeouts.load(Ordering::SeqCst),
+        }
+    }
+
+    fn draw(&self, rate: f64) -> bool {
+        rate > 0.0
+            && self
+                .rng
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .gen_bool(rate.min(1.0))
+    }
+
+    fn bonsai_error(&self, context: &str) -> Option<SdkErr> {
+        if !self.draw(self.plan.bonsai_error_rate) {
+            return None;
+        }
+        self.bonsai_errors.fetch_add(1, Ordering::SeqCst);
+        Some(SdkErr::InternalServerErr(format!(
+            "503 Service Unavailable: fault injected into {context}"
+        )))
+    }
+
+    fn receipt_delay(&self) -> Option<Duration> {
+        if self.plan.receipt_delay_ms == 0 {
+            return None;
+        }
+        self.receipt_delays.fetch_add(1, Ordering::SeqCst);
+        Some(Duration::from_millis(self.plan.receipt_delay_ms))
+    }
+
+    fn rpc_timeout(&self) -> bool {
+        let timeout = self.draw(self.plan.rpc_timeout_rate);
+        if timeout {
+            self.rpc_timeouts.fetch_add(1, Ordering::SeqCst);
+        }
+        timeout
+    }
+
+    /// Hash of block `number` after the reorgs so far, given its hash on
+    /// the node.
+    fn reorged_hash(&self, number: u64, hash: H256) -> H256 {
+        let reorgs = self.reorgs.lock().unwrap_or_else(PoisonError::into_inner);
+        let forks = reorgs.iter().filter(|from| **from <= number).count() as u64;
+        if forks == 0 {
+            return hash;
+        }
+        keccak256([hash.as_bytes(), &forks.to_be_bytes()].concat()).into()
+    }
+
+    fn reorg_block(&self, mut block: Block<TxHash>) -> Block<TxHash> {
+        if let Some(number) = block.number.map(|number| number.as_u64()) {
+            block.hash = block.hash.map(|hash| self.reorged_hash(number, hash));
+            if number > 0 {
+                block.parent_hash = self.reorged_hash(number - 1, block.parent_hash);
+            }
+        }
+        block
+    }
+}
+
+static ACTIVE: Mutex<Option<Arc<Faults>>> = Mutex::new(None);
+static EXCLUSIVE: Mutex<()> = Mutex::new(());
+
+/// Keeps faults installed until dropped.
+pub struct FaultGuard {
+    _exclusive: MutexGuard<'static, ()>,
+}
+
+impl Drop for FaultGuard {
+    fn drop(&mut self) {
+        *ACTIVE.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Inject `faults` into Bonsai calls and receipt downloads until the guard
+/// is dropped. Blocks while another guard is held.
+pub fn install(faults: Arc<Faults>) -> FaultGuard {
+    let exclusive = EXCLUSIVE.lock().unwrap_or_else(PoisonError::into_inner);
+    *ACTIVE.lock().unwrap_or_else(PoisonError::into_inner) = Some(faults);
+    FaultGuard {
+        _exclusive: exclusive,
+    }
+}
+
+/// Install the plan in [FAULTS_ENV], if set, for a chaos run of the relay.
+pub fn install_from_env() -> Result<Option<FaultGuard>> {
+    let Ok(plan) = env::var(FAULTS_ENV) else {
+        return Ok(None);
+    };
+    let plan: FaultPlan =
+        serde_json::from_str(&plan).context(format!("Failed to parse {FAULTS_ENV}"))?;
+    elog!("ALERT: injecting faults: {plan:?}");
+    Ok(Some(install(Arc::new(Faults::new(plan)))))
+}
+
+fn active() -> Option<Arc<Faults>> {
+    ACTIVE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone()
+}
+
+/// An injected failure of the Bonsai call described by `context`, if any.
+pub(crate) fn bonsai_error(context: &str) -> Option<SdkErr> {
+    active()?.bonsai_error(context)
+}
+
+/// Delay to add to a receipt download, if any.
+pub(crate) fn receipt_delay() -> Option<Duration> {
+    active()?.receipt_delay()
+}
+
+/// Node client injecting RPC timeouts and reorgs into the calls the relay
+/// reads chain state with.
+#[derive(Debug)]
+pub struct FaultyMiddleware<M> {
+    inner: M,
+    faults: Arc<Faults>,
+}
+
+impl<M: Middleware> FaultyMiddleware<M> {
+    pub fn new(inner: M, faults: Arc<Faults>) -> Self {
+        Self { inner, faults }
+    }
+
+    fn inject(&self, method: &'static str) -> Result<(), FaultyMiddlewareError<M>> {
+        if self.faults.rpc_timeout() {
+            return Err(FaultyMiddlewareError::Timeout(method));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum FaultyMiddlewareError<M: Middleware> {
+    #[error("{0}")]
+    Middleware(M::Error),
+    #[error("{0} timed out (injected fault)")]
+    Timeout(&'static str),
+}
+
+impl<M: Middleware> MiddlewareError for FaultyMiddlewareError<M> {
+    type Inner = M::Error;
+
+    fn from_err(src: M::Error) -> Self {
+        FaultyMiddlewareError::Middleware(src)
+    }
+
+    fn as_inner(&self) -> Option<&Self::Inner> {
+        match self {
+            FaultyMiddlewareError::Middleware(err) => Some(err),
+            FaultyMiddlewareError::Timeout(_) => None,
+        }
+    }
+}
+
+#[async_trait]
+impl<M: Middleware> Middleware for FaultyMiddleware<M> {
+    type Error = FaultyMiddlewareError<M>;
+    type Provider = M::Provider;
+    type Inner = M;
+
+    fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    async fn get_block_number(&self) -> Result<U64, Self::Error> {
+        self.inject("eth_blockNumber")?;
+        self.inner
+            .get_block_number()
+            .await
+            .map_err(MiddlewareError::from_err)
+    }
+
+    async fn get_block<T: Into<BlockId> + Send + Sync>(
+        &self,
+        block_hash_or_number: T,
+    ) -> Result<Option<Block<TxHash>>, Self::Error> {
+        self.inject("eth_getBlock")?;
+        let block = self
+            .inner
+            .get_block(block_hash_or_number)
+            .await
+            .map_err(MiddlewareError::from_err)?;
+        Ok(block.map(|block| self.faults.reorg_block(block)))
+    }
+
+    async fn call(
+        &self,
+        tx: &TypedTransaction,
+        block: Option<BlockId>,
+    ) -> Result<Bytes, Self::Error> {
+        self.inject("eth_call")?;
+        self.inner
+            .call(tx, block)
+            .await
+            .map_err(MiddlewareError::from_err)
+    }
+
+    async fn get_storage_at<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        location: H256,
+        block: Option<BlockId>,
+    ) -> Result<H256, Self::Error> {
+        self.inject("eth_getStorageAt")?;
+        self.inner
+            .get_storage_at(from, location, block)
+            .await
+            .map_err(MiddlewareError::from_err)
+    }
+
+    async fn get_proof<T: Into<NameOrAddress> + Send + Sync>(
+        &self,
+        from: T,
+        locations: Vec<H256>,
+        block: Option<BlockId>,
+    ) -> Result<EIP1186ProofResponse, Self::Error> {
+        self.inject("eth_getProof")?;
+        self.inner
+            .get_proof(from, locations, block)
+            .await
+            .map_err(MiddlewareError::from_err)
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! C ABI for proving guests from hosts not written in Rust, built with the
+//! `ffi` feature, which also generates `include/relay.h` in this crate's
+//! directory. Link against the library built with
+//! `cargo rustc --lib --release --features ffi --crate-type cdylib`.
+//!
+//! Calls return 0 on success. On failure they return a negative status and
+//! [relay_last_error] describes the error. Proving is configured by the
+//! `BONSAI_API_URL` and `BONSAI_API_KEY` environment variables.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+};
+
+use anyhow::{bail, Context, Result};
+use methods::GUEST_LIST;
+
+use crate::{
+    backend::{BonsaiBackend, ProverBackend},
+    input::canonicalize,
+    registry::GuestRegistry,
+    snark_seal, Output,
+};
+
+/// A null pointer or otherwise invalid argument was passed.
+pub const RELAY_INVALID_ARGUMENT: i32 = -1;
+/// Proving failed, see [relay_last_error].
+pub const RELAY_PROVE_FAILED: i32 = -2;
+/// The relay panicked, see [relay_last_error].
+pub const RELAY_PANICKED: i32 = -3;
+
+/// Result of [relay_prove]. Buffers are owned by the relay and released
+/// with [relay_output_free].
+#[repr(C)]
+pub struct RelayOutput {
+    pub journal: *mut u8,
+    pub journal_len: usize,
+    /// ABI encoded SNARK seal, as the relay and verifier contracts take it.
+    pub seal: *mut u8,
+    pub seal_len: usize,
+    pub post_state_digest: [u8; 32],
+    pub image_id: [u8; 32],
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+fn into_raw(bytes: Vec<u8>) -> (*mut u8, usize) {
+    let len = bytes.len();
+    (Box::into_raw(bytes.into_boxed_slice()).cast(), len)
+}
+
+/// Free a buffer created by [into_raw].
+///
+/// # Safety
+///
+/// `data` and `len` must come from the same [into_raw] call, and the buffer
+/// must not have been freed yet.
+unsafe fn free_raw(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+fn prove(guest_name: &str, input: &[u8]) -> Result<RelayOutput> {
+    let guest = GuestRegistry::from_guest_list(GUEST_LIST).resolve(guest_name)?;
+    let input = canonicalize(&guest.name, input)?;
+    let runtime = tokio::runtime::Runtime::new().context("Failed to start a runtime")?;
+    let output = runtime.block_on(BonsaiBackend::default().prove(guest.clone(), input))?;
+    let Output::Bonsai {
+        journal,
+        receipt_metadata,
+        snark_proof,
+        ..
+    } = output
+    else {
+        bail!("Bonsai returned a result without a SNARK");
+    };
+    let seal = snark_seal(&snark_proof)?;
+    let (journal, journal_len) = into_raw(journal);
+    let (seal, seal_len) = into_raw(seal);
+    Ok(RelayOutput {
+        journal,
+        journal_len,
+        seal,
+        seal_len,
+        post_state_digest: receipt_metadata.post.digest().into(),
+        image_id: guest.image_id.into(),
+    })
+}
+
+/// Prove `len` bytes of input at `input_ptr` with the guest named
+/// `guest_name`, or given by hex image ID, and store the result in `out`.
+/// Inputs are canonicalized as the relay does for its own requests. Blocks
+/// until the proof is done.
+///
+/// # Safety
+///
+/// `guest_name` must be a NUL terminated string, `input_ptr` must point to
+/// `len` readable bytes, and `out` must point to a writable [RelayOutput].
+#[no_mangle]
+pub unsafe extern "C" fn relay_prove(
+    guest_name: *const c_char,
+    input_ptr: *const u8,
+    len: usize,
+    out: *mut RelayOutput,
+) -> i32 {
+    if guest_name.is_null() || out.is_null() || (input_ptr.is_null() && len > 0) {
+        set_last_error("relay_prove was passed a null pointer".to_string());
+        return RELAY_INVALID_ARGUMENT;
+    }
+    let Ok(guest_name) = CStr::from_ptr(guest_name).to_str() else {
+        set_last_error("guest name is not valid UTF-8".to_string());
+        return RELAY_INVALID_ARGUMENT;
+    };
+    let input = match len {
+        0 => &[][..],
+        _ => std::slice::from_raw_parts(input_ptr, len),
+    };
+    match catch_unwind(AssertUnwindSafe(|| prove(guest_name, input))) {
+        Ok(Ok(output)) => {
+            out.write(output);
+            0
+        }
+        Ok(Err(err)) => {
+            set_last_error(format!("{err:#}"));
+            RELAY_PROVE_FAILED
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            set_last_error(format!("relay panicked: {message}"));
+            RELAY_PANICKED
+        }
+    }
+}
+
+/// Release the buffers of an output filled by [relay_prove], leaving it
+/// empty.
+///
+/// # Safety
+///
+/// `out` must be null or point to an output filled by [relay_prove] whose
+/// buffers were not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn relay_output_free(out: *mut RelayOutput) {
+    let Some(out) = out.as_mut() else {
+        return;
+    };
+    free_raw(out.journal, out.journal_len);
+    free_raw(out.seal, out.seal_len);
+    out.journal = ptr::null_mut();
+    out.journal_len = 0;
+    out.seal = ptr::null_mut();
+    out.seal_len = 0;
+}
+
+/// Message describing the last error of a call on this thread, empty if
+/// there was none. Valid until the next call on this thread.
+#[no_mangle]
+pub extern "C" fn relay_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
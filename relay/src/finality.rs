@@ -0,0 +1,108 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt;
+
+use anyhow::{anyhow, Context, Result};
+use ethers::{
+    providers::Middleware,
+    types::{Block, BlockNumber, H256},
+};
+use serde::{Deserialize, Serialize};
+
+/// Which blocks of a chain inputs may be built from. Inputs anchored to
+/// blocks that are later reorged out prove state that never became
+/// canonical, so chains are configured with how settled a block must be.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FinalityPolicy {
+    /// The chain head.
+    #[default]
+    Latest,
+    /// The newest block a rollup's sequencer produced, read from the chain's
+    /// sequencer feed if it has one and from the node's head otherwise. The
+    /// sequencer can still reorder the block until its batch is posted, so
+    /// results built from it are flagged as unfinalized. SWAP and BATCH
+    /// journals commit the flag, and HISTORY pools refuse the policy.
+    Sequencer,
+    /// The block this many blocks below the chain head, e.g. 2 on Arbitrum.
+    Confirmations(u64),
+    /// The node's `safe` block, e.g. on OP stack chains once the block's
+    /// batch is posted to L1.
+    Safe,
+    /// The node's `finalized` block, e.g. on mainnet once its epoch is
+    /// finalized.
+    Finalized,
+}
+
+impl FinalityPolicy {
+    /// The newest block meeting the policy.
+    pub async fn block<M: Middleware + 'static>(&self, client: &M) -> Result<Block<H256>> {
+        let number = match self {
+            FinalityPolicy::Latest | FinalityPolicy::Sequencer => BlockNumber::Latest,
+            FinalityPolicy::Safe => BlockNumber::Safe,
+            FinalityPolicy::Finalized => BlockNumber::Finalized,
+            FinalityPolicy::Confirmations(blocks) => {
+                let head = client
+                    .get_block_number()
+                    .await
+                    .context("Failed to read the chain head")?
+                    .as_u64();
+                BlockNumber::Number(head.saturating_sub(*blocks).into())
+            }
+        };
+        client
+            .get_block(number)
+            .await
+            .context(format!("Failed to read the {self} block"))?
+            .ok_or_else(|| anyhow!("Node returned no {self} block"))
+    }
+
+    /// Whether results built from blocks meeting the policy are flagged as
+    /// unfinalized, which consumers opt into by trading finality for latency.
+    pub fn unfinalized(&self) -> bool {
+        matches!(self, FinalityPolicy::Sequencer)
+    }
+}
+
+impl fmt::Display for FinalityPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FinalityPolicy::Latest => write!(f, "latest"),
+            FinalityPolicy::Sequencer => write!(f, "sequencer"),
+            FinalityPolicy::Confirmations(blocks) => write!(f, "{blocks} confirmations"),
+            FinalityPolicy::Safe => write!(f, "safe"),
+            FinalityPolicy::Finalized => write!(f, "finalized"),
+        }
+    }
+}
+
+impl std::str::FromStr for FinalityPolicy {
+    type Err = anyhow::Error;
+
+    /// Parse `latest`, `sequencer`, `safe`, `finalized` or a number of
+    /// confirmations.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "latest" => Ok(FinalityPolicy::Latest),
+            "sequencer" => Ok(FinalityPolicy::Sequencer),
+            "safe" => Ok(FinalityPolicy::Safe),
+            "finalized" => Ok(FinalityPolicy::Finalized),
+            _ => s
+                .parse()
+                .map(FinalityPolicy::Confirmations)
+                .map_err(|_| anyhow!("invalid finality policy {s:?}")),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ciborium::value::Value;
+use ethers::{
+    abi::{self, ParamType, Token},
+    types::{Address, I256, U256},
+};
+
+use crate::{
+    input::{canonicalize, decode_public, frame_schema, split_input},
+    schema::input_schema,
+};
+
+/// Tags identifying the encoding of an on-chain request input, given as its
+/// first byte.
+pub const TAG_RAW: u8 = 0x00;
+pub const TAG_ABI_STRUCT: u8 = 0x01;
+pub const TAG_CBOR: u8 = 0x02;
+
+/// Turns a tagged request payload into the guest input.
+pub trait RequestDecoder: Send + Sync {
+    fn tag(&self) -> u8;
+
+    /// Decode `payload`, the input without its tag byte, given the guest's
+    /// input schema if it has one.
+    fn decode(&self, schema: Option<&[ParamType]>, payload: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Payload passed to the guest unchanged.
+pub struct RawDecoder;
+
+impl RequestDecoder for RawDecoder {
+    fn tag(&self) -> u8 {
+        TAG_RAW
+    }
+
+    fn decode(&self, _schema: Option<&[ParamType]>, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(payload.to_vec())
+    }
+}
+
+/// `abi.encode(request)` of a Solidity struct holding the guest's inputs.
+pub struct AbiStructDecoder;
+
+impl RequestDecoder for AbiStructDecoder {
+    fn tag(&self) -> u8 {
+        TAG_ABI_STRUCT
+    }
+
+    fn decode(&self, schema: Option<&[ParamType]>, payload: &[u8]) -> Result<Vec<u8>> {
+        let schema = schema.ok_or_else(|| anyhow!("ABI struct requests need an input schema"))?;
+        let tokens = decode_public("ABI struct", &[ParamType::Tuple(schema.to_vec())], payload)?;
+        match tokens.into_iter().next() {
+            Some(Token::Tuple(tokens)) => Ok(abi::encode(&tokens)),
+            _ => bail!("ABI struct request is not a tuple"),
+        }
+    }
+}
+
+/// CBOR array of the guest's inputs, with integers beyond 64 bits given as
+/// bignums.
+pub struct CborDecoder;
+
+impl RequestDecoder for CborDecoder {
+    fn tag(&self) -> u8 {
+        TAG_CBOR
+    }
+
+    fn decode(&self, schema: Option<&[ParamType]>, payload: &[u8]) -> Result<Vec<u8>> {
+        let schema = schema.ok_or_else(|| anyhow!("CBOR requests need an input schema"))?;
+        let value: Value =
+            ciborium::de::from_reader(payload).context("Failed to decode CBOR request")?;
+        let tokens = cbor_to_tokens(schema, value)?;
+        Ok(abi::encode(&tokens))
+    }
+}
+
+fn cbor_to_tokens(schema: &[ParamType], value: Value) -> Result<Vec<Token>> {
+    let Value::Array(values) = value else {
+        bail!("expected a CBOR array");
+    };
+    if values.len() != schema.len() {
+        bail!("expected {} values, got {}", schema.len(), values.len());
+    }
+    schema
+        .iter()
+        .zip(values)
+        .map(|(param, value)| cbor_to_token(param, value))
+        .collect()
+}
+
+fn cbor_to_token(param: &ParamType, value: Value) -> Result<Token> {
+    Ok(match (param, value) {
+        (ParamType::Uint(_), Value::Integer(int)) => {
+            let int = u128::try_from(int).map_err(|_| anyhow!("expected an unsigned integer"))?;
+            Token::Uint(U256::from(int))
+        }
+        (ParamType::Uint(_), Value::Tag(2, bytes)) => Token::Uint(bignum(*bytes)?),
+        (ParamType::Int(_), Value::Integer(int)) => {
+            Token::Int(I256::from(i128::from(int)).into_raw())
+        }
+        (ParamType::Int(_), Value::Tag(3, bytes)) => {
+            // CBOR negative bignums encode -1 - n.
+            let n = I256::from_raw(bignum(*bytes)?);
+            if n.is_negative() {
+                bail!("negative bignum out of range");
+            }
+            Token::Int((I256::minus_one() - n).into_raw())
+        }
+        (ParamType::Address, Value::Bytes(bytes)) if bytes.len() == 20 => {
+            Token::Address(Address::from_slice(&bytes))
+        }
+        (ParamType::FixedBytes(len), Value::Bytes(bytes)) if bytes.len() == *len => {
+            Token::FixedBytes(bytes)
+        }
+        (ParamType::Bytes, Value::Bytes(bytes)) => Token::Bytes(bytes),
+        (ParamType::String, Value::Text(text)) => Token::String(text),
+        (ParamType::Bool, Value::Bool(b)) => Token::Bool(b),
+        (ParamType::Array(inner), Value::Array(values)) => Token::Array(
+            values
+                .into_iter()
+                .map(|value| cbor_to_token(inner, value))
+                .collect::<Result<_>>()?,
+        ),
+        (ParamType::FixedArray(inner, len), Value::Array(values)) if values.len() == *len => {
+            Token::FixedArray(
+                values
+                    .into_iter()
+                    .map(|value| cbor_to_token(inner, value))
+                    .collect::<Result<_>>()?,
+            )
+        }
+        (ParamType::Tuple(schema), value) => Token::Tuple(cbor_to_tokens(schema, value)?),
+        (param, value) => bail!("cannot convert CBOR {value:?} to {param}"),
+    })
+}
+
+fn bignum(value: Value) -> Result<U256> {
+    match value {
+        Value::Bytes(bytes) if bytes.len() <= 32 => Ok(U256::from_big_endian(&bytes)),
+        _ => bail!("expected a bignum of at most 32 bytes"),
+    }
+}
+
+/// Request decoders by tag. Inputs that are already the canonical ABI encoding
+/// of the guest's input schema, as sent by contracts predating tags, are used
+/// as is; any other input starts with the tag of its encoding. Inputs of
+/// guests without a schema are raw and untagged, as every other format
+/// needs a schema.
+pub struct RequestFormats {
+    decoders: HashMap<u8, Box<dyn RequestDecoder>>,
+}
+
+impl Default for RequestFormats {
+    fn default() -> Self {
+        let mut formats = Self {
+            decoders: HashMap::new(),
+        };
+        formats.register(RawDecoder);
+        formats.register(AbiStructDecoder);
+        formats.register(CborDecoder);
+        formats
+    }
+}
+
+impl RequestFormats {
+    /// Add a decoder, replacing any registered for the same tag.
+    pub fn register(&mut self, decoder: impl RequestDecoder + 'static) {
+        self.decoders.insert(decoder.tag(), Box::new(decoder));
+    }
+
+    /// Turn a request input into the canonical guest input.
+    pub fn decode(&self, guest_name: &str, input: &[u8]) -> Result<Vec<u8>> {
+        let Some(schema) = input_schema(guest_name) else {
+            return Ok(input.to_vec());
+        };
+        if let Ok(Some(_)) = frame_schema(input) {
+            return canonicalize(guest_name, input);
+        }
+        // Canonical inputs pass as is, whether framed or not.
+        if let Ok(canonical) = canonicalize(guest_name, input) {
+            if canonical == input
+                || matches!(split_input(&canonical), Ok((public, None)) if public == input)
+            {
+                return Ok(canonical);
+            }
+        }
+        let (&tag, payload) = input
+            .split_first()
+            .ok_or_else(|| anyhow!("empty request input"))?;
+        let decoder = self
+            .decoders
+            .get(&tag)
+            .ok_or_else(|| anyhow!("unknown request format tag {tag:#04x}"))?;
+        let input = decoder.decode(Some(&schema), payload)?;
+        canonicalize(guest_name, &input)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_abi_struct_rejects_overwide_integers() {
+        let schema = [ParamType::Uint(24), ParamType::Bytes];
+        let request = abi::encode(&[Token::Tuple(vec![
+            Token::Uint(U256::from(1) << 24),
+            Token::Bytes(vec![1]),
+        ])]);
+        let err = AbiStructDecoder
+            .decode(Some(&schema), &request)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "value 0 does not fit uint24");
+    }
+
+    #[test]
+    fn test_abi_struct_rejects_trailing_bytes() {
+        let schema = [ParamType::Uint(24), ParamType::Bytes];
+        let mut request = abi::encode(&[Token::Tuple(vec![
+            Token::Uint(U256::one()),
+            Token::Bytes(vec![1]),
+        ])]);
+        assert_eq!(
+            AbiStructDecoder.decode(Some(&schema), &request).unwrap(),
+            abi::encode(&[Token::Uint(U256::one()), Token::Bytes(vec![1])])
+        );
+        request.extend([0; 32]);
+        assert!(AbiStructDecoder.decode(Some(&schema), &request).is_err());
+    }
+}
@@ -0,0 +1,221 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, path::Path};
+
+use anyhow::{Context, Result};
+use ethers::abi::AbiEncode;
+use serde::Deserialize;
+
+use crate::{
+    bindings::{Callback, CallbackRequestFilter, InvokeCallbackCall},
+    submitter::Submitter,
+    Output,
+};
+
+/// Intrinsic gas of any transaction.
+pub const TX_BASE_GAS: u64 = 21_000;
+/// Calldata cost per zero and non-zero byte (EIP-2028).
+pub const CALLDATA_ZERO_BYTE_GAS: u64 = 4;
+pub const CALLDATA_NONZERO_BYTE_GAS: u64 = 16;
+/// Approximate cost of verifying a Groth16 seal in the relay contract,
+/// dominated by the pairing check.
+pub const SEAL_VERIFICATION_GAS: u64 = 250_000;
+/// Length of an ABI encoded Groth16 seal.
+pub const SNARK_SEAL_BYTES: usize = 256;
+/// Size of an EIP-4844 blob, each byte of which costs one blob gas.
+pub const BLOB_BYTES: u64 = 131_072;
+/// Commitment left in calldata for each blob: its versioned hash.
+pub const BLOB_COMMITMENT_BYTES: u64 = 32;
+
+/// Longest a callback is held for calldata to get cheaper, by default.
+pub const DEFAULT_MAX_HOLD_SECS: u64 = 300;
+
+/// How callbacks are submitted as their costs change, read from a JSON file
+/// that can be reloaded while the relay runs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct GasStrategy {
+    /// Refuse to submit callbacks estimated to cost more gas than this.
+    pub max_gas: Option<u64>,
+    /// Price posting each result as EIP-4844 blobs against posting it as
+    /// calldata. Results are only ever posted as calldata.
+    pub blob_pricing: bool,
+    /// With `blob_pricing`, hold callbacks whose calldata costs more than
+    /// this percentage over posting them as blobs, until it no longer does
+    /// or `max_hold_secs` pass. Either way they are then posted as calldata,
+    /// the only posting the relay contract reads. Unset, nothing is held.
+    pub max_calldata_premium_pct: Option<u64>,
+    pub max_hold_secs: u64,
+}
+
+impl Default for GasStrategy {
+    fn default() -> Self {
+        Self {
+            max_gas: None,
+            blob_pricing: false,
+            max_calldata_premium_pct: None,
+            max_hold_secs: DEFAULT_MAX_HOLD_SECS,
+        }
+    }
+}
+
+impl GasStrategy {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open gas strategy {}", path.display()))?;
+        serde_json::from_reader(file)
+            .context(format!("Failed to parse gas strategy {}", path.display()))
+    }
+}
+
+/// Estimated gas cost of posting a result on-chain.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GasEstimate {
+    pub calldata_bytes: u64,
+    pub calldata_gas: u64,
+    pub verifier_gas: u64,
+    /// Gas forwarded to the callback, as requested on-chain.
+    pub callback_gas: u64,
+    /// L1 data cost charged by rollups, in L2 gas.
+    pub l1_gas: u64,
+}
+
+impl GasEstimate {
+    pub fn total(&self) -> u64 {
+        TX_BASE_GAS + self.calldata_gas + self.verifier_gas + self.callback_gas + self.l1_gas
+    }
+}
+
+impl fmt::Display for GasEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} gas ({} calldata bytes: {} gas, verifier: {} gas, callback: {} gas, L1 data: \
+             {} gas)",
+            self.total(),
+            self.calldata_bytes,
+            self.calldata_gas,
+            self.verifier_gas,
+            self.callback_gas,
+            self.l1_gas
+        )
+    }
+}
+
+pub fn calldata_gas(data: &[u8]) -> u64 {
+    data.iter()
+        .map(|&byte| match byte {
+            0 => CALLDATA_ZERO_BYTE_GAS,
+            _ => CALLDATA_NONZERO_BYTE_GAS,
+        })
+        .sum()
+}
+
+/// Estimate the cost of an `invokeCallback` transaction for the callback.
+pub fn estimate_callback(callback: &Callback) -> GasEstimate {
+    let calldata = InvokeCallbackCall {
+        callback: callback.clone(),
+    }
+    .encode();
+    GasEstimate {
+        calldata_bytes: calldata.len() as u64,
+        calldata_gas: calldata_gas(&calldata),
+        verifier_gas: match callback.auth.seal.is_empty() {
+            true => 0,
+            false => SEAL_VERIFICATION_GAS,
+        },
+        callback_gas: callback.gas_limit,
+        l1_gas: 0,
+    }
+}
+
+/// Estimate the cost of posting a guest output, excluding the callback itself.
+/// Execution-only outputs are costed as if they carried a SNARK seal, so dry
+/// runs report what proving would cost.
+pub fn estimate_output(image_id: [u8; 32], output: &Output) -> Result<GasEstimate> {
+    let request = CallbackRequestFilter {
+        image_id,
+        ..Default::default()
+    };
+    let mut callback = Submitter::callback(&request, output, None)?;
+    if callback.auth.seal.is_empty() {
+        callback.auth.seal = vec![u8::MAX; SNARK_SEAL_BYTES].into();
+    }
+    Ok(estimate_callback(&callback))
+}
+
+/// Where a result's data is posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Posting {
+    Calldata,
+    /// As EIP-4844 blobs, with only their commitments in calldata.
+    Blob,
+}
+
+/// Cost in wei of posting the estimated calldata either way.
+#[derive(Debug, Clone, Copy)]
+pub struct PostingCost {
+    pub calldata_wei: u128,
+    pub blob_wei: u128,
+}
+
+impl PostingCost {
+    /// Compare posting `estimate`'s calldata as is against posting it as
+    /// blobs, at the given base fee and blob base fee per gas.
+    pub fn new(estimate: &GasEstimate, base_fee: u128, blob_base_fee: u128) -> Self {
+        let blobs = match estimate.calldata_bytes % BLOB_BYTES {
+            0 => estimate.calldata_bytes / BLOB_BYTES,
+            _ => estimate.calldata_bytes / BLOB_BYTES + 1,
+        }
+        .max(1);
+        let commitment_gas = blobs * BLOB_COMMITMENT_BYTES * CALLDATA_NONZERO_BYTE_GAS;
+        Self {
+            calldata_wei: u128::from(estimate.calldata_gas) * base_fee,
+            blob_wei: u128::from(blobs * BLOB_BYTES) * blob_base_fee
+                + u128::from(commitment_gas) * base_fee,
+        }
+    }
+
+    /// The cheaper posting, allowing calldata to cost up to `premium_pct`
+    /// percent more than blobs; calldata when blob fees are unknown.
+    pub fn choose(cost: Option<Self>, premium_pct: u64) -> Posting {
+        match cost {
+            Some(cost)
+                if cost.blob_wei.saturating_mul(100 + u128::from(premium_pct))
+                    < cost.calldata_wei.saturating_mul(100) =>
+            {
+                Posting::Blob
+            }
+            _ => Posting::Calldata,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_choose_allows_calldata_its_premium() {
+        let cost = PostingCost {
+            calldata_wei: 150,
+            blob_wei: 100,
+        };
+        assert_eq!(PostingCost::choose(None, 0), Posting::Calldata);
+        assert_eq!(PostingCost::choose(Some(cost), 0), Posting::Blob);
+        assert_eq!(PostingCost::choose(Some(cost), 49), Posting::Blob);
+        assert_eq!(PostingCost::choose(Some(cost), 50), Posting::Calldata);
+    }
+}
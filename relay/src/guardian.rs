@@ -0,0 +1,134 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Extends a protocol's emergency pause to the relay: while its guardian
+//! contract reports `paused()`, results are still proven but not submitted.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use ethers::{providers::Middleware, types::Address};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{bindings::Pausable, clock, elog};
+
+/// Pause state of a guardian, as reported by the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuardianStatus {
+    pub guardian: Address,
+    pub paused: bool,
+    /// When the flag was last read, in seconds since the Unix epoch.
+    pub checked_at: u64,
+}
+
+/// Follows the pause flag of a guardian contract, such as an OpenZeppelin
+/// `Pausable`. The last flag read is kept while the contract cannot be
+/// read, so a paused relay stays paused through node outages.
+pub struct Guardian<M> {
+    contract: Pausable<M>,
+    poll_interval: Duration,
+    paused: AtomicBool,
+    checked_at: AtomicU64,
+    resumed: Notify,
+}
+
+impl<M: Middleware + 'static> Guardian<M> {
+    /// Follow the guardian at `address`, reading its flag once up front.
+    pub async fn new(address: Address, client: Arc<M>) -> Result<Self> {
+        let guardian = Self {
+            contract: Pausable::new(address, client),
+            poll_interval: Duration::from_secs(12),
+            paused: AtomicBool::new(false),
+            checked_at: AtomicU64::new(0),
+            resumed: Notify::new(),
+        };
+        guardian.poll().await?;
+        Ok(guardian)
+    }
+
+    /// Read the flag every `interval` instead of every 12 seconds.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn status(&self) -> GuardianStatus {
+        GuardianStatus {
+            guardian: self.contract.address(),
+            paused: self.is_paused(),
+            checked_at: self.checked_at.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Read the flag from the contract, returning whether it is set.
+    pub async fn poll(&self) -> Result<bool> {
+        let paused = self.contract.paused().call().await.context(format!(
+            "Failed to read the pause flag of guardian {:?}",
+            self.contract.address()
+        ))?;
+        let now = clock::unix_now();
+        self.checked_at.store(now, Ordering::SeqCst);
+        let was_paused = self.paused.swap(paused, Ordering::SeqCst);
+        match (was_paused, paused) {
+            (false, true) => elog!(
+                "ALERT: guardian {:?} paused the protocol; holding submissions",
+                self.contract.address()
+            ),
+            (true, false) => {
+                elog!(
+                    "Guardian {:?} unpaused the protocol; resuming submissions",
+                    self.contract.address()
+                );
+                self.resumed.notify_waiters();
+            }
+            _ => {}
+        }
+        Ok(paused)
+    }
+
+    /// Poll the flag until the task is dropped.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        loop {
+            tokio::time::sleep(self.poll_interval).await;
+            if let Err(err) = self.poll().await {
+                elog!("{err:?}");
+            }
+        }
+    }
+
+    /// Wait until the guardian is not paused. Returns whether it had to
+    /// wait, in which case results held meanwhile may have gone stale.
+    pub async fn wait_unpaused(&self) -> bool {
+        let mut waited = false;
+        loop {
+            let resumed = self.resumed.notified();
+            if !self.is_paused() {
+                return waited;
+            }
+            waited = true;
+            resumed.await;
+        }
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inputs of the HISTORY guest, which extends a rolling commitment to a
+//! pool's oracle observations with only those made since the previous run.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::Middleware,
+    types::{Address, BlockId, Bytes, H256},
+    utils::keccak256,
+};
+
+use crate::{
+    bindings::UniswapV3Pool,
+    finality::FinalityPolicy,
+    input::canonicalize,
+    proofs::{encode_header, verify_account, ProofCache, ProofSource},
+    schema::decode_journal,
+    twap::{observation_initialized, observation_slot, observation_timestamp},
+};
+
+/// Storage slot of `slot0` in a Uniswap v3 pool. Must match the HISTORY
+/// guest.
+const SLOT0_SLOT: u64 = 0;
+
+/// History committed by a HISTORY run: a Merkle mountain range with a leaf
+/// per observation, and the timestamp of the newest one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryState {
+    pub pool: Address,
+    pub count: u64,
+    /// Peaks of the mountain range, largest first.
+    pub peaks: Vec<H256>,
+    pub last_timestamp: u64,
+}
+
+impl HistoryState {
+    /// The empty history `ZkObservationHistory` starts from.
+    pub fn genesis(pool: Address) -> Self {
+        Self {
+            pool,
+            count: 0,
+            peaks: Vec::new(),
+            last_timestamp: 0,
+        }
+    }
+
+    /// The history committed by a HISTORY journal, which the next run
+    /// extends.
+    pub fn from_journal(journal: &[u8]) -> Result<Self> {
+        let tokens = decode_journal("HISTORY", journal)?
+            .ok_or_else(|| anyhow!("HISTORY guest has no journal schema"))?;
+        let mut tokens = tokens.into_iter();
+        let mut next = || {
+            tokens
+                .next()
+                .ok_or_else(|| anyhow!("HISTORY journal is truncated"))
+        };
+        let pool = next()?.into_address();
+        let (_previous_root, _root) = (next()?, next()?);
+        let count = next()?.into_uint();
+        let peaks = next()?.into_array();
+        let _observed_from = next()?;
+        let last_timestamp = next()?.into_uint();
+        let (Some(pool), Some(count), Some(peaks), Some(last_timestamp)) =
+            (pool, count, peaks, last_timestamp)
+        else {
+            bail!("HISTORY journal does not match its schema");
+        };
+        Ok(Self {
+            pool,
+            count: count.as_u64(),
+            peaks: peaks
+                .into_iter()
+                .filter_map(Token::into_fixed_bytes)
+                .map(|peak| H256::from_slice(&peak))
+                .collect(),
+            last_timestamp: last_timestamp.as_u64(),
+        })
+    }
+
+    /// Commitment to the history, as the guest and `ZkObservationHistory`
+    /// compute it.
+    pub fn root(&self) -> H256 {
+        keccak256(abi::encode(&[
+            Token::Address(self.pool),
+            Token::Uint(self.count.into()),
+            Token::Array(
+                self.peaks
+                    .iter()
+                    .map(|peak| Token::FixedBytes(peak.as_bytes().to_vec()))
+                    .collect(),
+            ),
+        ]))
+        .into()
+    }
+}
+
+/// Input of the HISTORY guest: the history to extend and proofs of the
+/// pool's observations at one block, from the newest already committed
+/// through the newest of the block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryInput {
+    pub state: HistoryState,
+    /// RLP encoded header of the block.
+    pub header: Bytes,
+    pub account_proof: Vec<Bytes>,
+    pub slot0_proof: Vec<Bytes>,
+    /// Ring index of the first proven observation.
+    pub first_index: u16,
+    /// Proofs of the observations from `first_index` through the newest,
+    /// in ring order.
+    pub observations: Vec<Vec<Bytes>>,
+}
+
+impl HistoryInput {
+    /// ABI encode the input in the layout expected by the guest.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let proof = |nodes: &[Bytes]| {
+            Token::Array(
+                nodes
+                    .iter()
+                    .map(|node| Token::Bytes(node.to_vec()))
+                    .collect(),
+            )
+        };
+        canonicalize(
+            "HISTORY",
+            &abi::encode(&[
+                Token::Address(self.state.pool),
+                Token::Uint(self.state.count.into()),
+                Token::Array(
+                    self.state
+                        .peaks
+                        .iter()
+                        .map(|peak| Token::FixedBytes(peak.as_bytes().to_vec()))
+                        .collect(),
+                ),
+                Token::Uint(self.state.last_timestamp.into()),
+                Token::Bytes(self.header.to_vec()),
+                proof(&self.account_proof),
+                proof(&self.slot0_proof),
+                Token::Uint(self.first_index.into()),
+                Token::Array(self.observations.iter().map(|nodes| proof(nodes)).collect()),
+            ]),
+        )
+    }
+}
+
+/// The pool overwrote the newest committed observation before the history
+/// was extended, so the observations made in between are lost. Runs must be
+/// frequent enough for the pool's observation cardinality.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "pool {pool:?} no longer holds observation {last_timestamp}, its oldest is at {oldest}; \
+     the history cannot be extended without a gap"
+)]
+pub struct HistoryGap {
+    pub pool: Address,
+    pub last_timestamp: u64,
+    pub oldest: u64,
+}
+
+/// Builds HISTORY guest inputs from state proofs of a pool's observations.
+pub struct HistoryFetcher<M> {
+    client: Arc<M>,
+    source: Arc<dyn ProofSource>,
+    cache: Option<Arc<ProofCache>>,
+    finality: FinalityPolicy,
+}
+
+impl<M: Middleware + 'static> HistoryFetcher<M> {
+    /// Read pools through `client` and fetch proofs from `source`.
+    pub fn new(client: Arc<M>, source: Arc<dyn ProofSource>) -> Self {
+        Self {
+            client,
+            source,
+            cache: None,
+            finality: FinalityPolicy::default(),
+        }
+    }
+
+    /// Serve proofs from `cache` where possible.
+    pub fn with_cache(mut self, cache: Arc<ProofCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Only prove observations at blocks meeting `finality`.
+    pub fn with_finality(mut self, finality: FinalityPolicy) -> Self {
+        self.finality = finality;
+        self
+    }
+
+    /// Build the input extending `state` with the observations the pool
+    /// made since, at the latest block meeting the finality policy. An
+    /// empty history starts from the oldest observation the pool holds.
+    pub async fn fetch(&self, state: &HistoryState) -> Result<HistoryInput> {
+        let pool = state.pool;
+        let block = self.finality.block(self.client.as_ref()).await?;
+        let hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        let at = BlockId::Hash(hash);
+        let (_, _, newest, cardinality, _) = UniswapV3Pool::new(pool, self.client.clone())
+            .slot_0()
+            .block(at)
+            .call()
+            .await
+            .context(format!("Failed to read slot0 of pool {pool:?} at {hash:?}"))?;
+        if cardinality == 0 {
+            bail!("pool {pool:?} has no observations");
+        }
+        let read = |index: u16| async move {
+            self.client
+                .get_storage_at(pool, observation_slot(index), Some(at))
+                .await
+                .context(format!(
+                    "Failed to read observation {index} of pool {pool:?} at {hash:?}"
+                ))
+        };
+
+        // The oldest observation is the one after the newest, unless the
+        // ring has not filled up yet.
+        let next = ((newest as u32 + 1) % cardinality as u32) as u16;
+        let (oldest, len) = if observation_initialized(read(next).await?) {
+            (next, cardinality)
+        } else {
+            (0, newest + 1)
+        };
+        let index = |pos: u16| ((oldest as u32 + pos as u32) % cardinality as u32) as u16;
+        let first_index = if state.count == 0 {
+            oldest
+        } else {
+            let timestamp = |pos: u16| async move {
+                read(index(pos))
+                    .await
+                    .map(|word| u64::from(observation_timestamp(word)))
+            };
+            let oldest_timestamp = timestamp(0).await?;
+            if oldest_timestamp > state.last_timestamp {
+                return Err(HistoryGap {
+                    pool,
+                    last_timestamp: state.last_timestamp,
+                    oldest: oldest_timestamp,
+                }
+                .into());
+            }
+            // Invariant: position `low` is at or before the last committed
+            // observation, `high` after.
+            let (mut low, mut high) = (0, len);
+            while high - low > 1 {
+                let mid = low + (high - low) / 2;
+                if timestamp(mid).await? <= state.last_timestamp {
+                    low = mid;
+                } else {
+                    high = mid;
+                }
+            }
+            if timestamp(low).await? != state.last_timestamp {
+                bail!(
+                    "pool {pool:?} has no observation at {}, the newest committed to the history",
+                    state.last_timestamp
+                );
+            }
+            index(low)
+        };
+
+        let count =
+            (newest as u32 + cardinality as u32 - first_index as u32) % cardinality as u32 + 1;
+        let mut slots = vec![H256::from_low_u64_be(SLOT0_SLOT)];
+        slots.extend(
+            (0..count)
+                .map(|i| observation_slot(((first_index as u32 + i) % cardinality as u32) as u16)),
+        );
+        let response = match &self.cache {
+            Some(cache) => {
+                cache
+                    .get_proof(self.source.as_ref(), &block, pool, &slots)
+                    .await?
+            }
+            None => {
+                let response = self.source.get_proof(hash, pool, slots).await?;
+                verify_account(block.state_root, &response)?;
+                response
+            }
+        };
+        let mut storage = response.storage_proof.into_iter().map(|slot| slot.proof);
+        let slot0_proof = storage
+            .next()
+            .ok_or_else(|| anyhow!("proof of pool {pool:?} is missing slot0"))?;
+        Ok(HistoryInput {
+            state: state.clone(),
+            header: encode_header(&block)?,
+            account_proof: response.account_proof,
+            slot0_proof,
+            first_index,
+            observations: storage.collect(),
+        })
+    }
+}
@@ -0,0 +1,447 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Index of proven results by guest and canonical input, in a directory
+//! shared by the scheduler and the event listener. A callback request for
+//! an input that a scheduled job has just proven, such as the TWAP of a pool
+//! over the same block range, is answered with the job's receipt instead of
+//! being proven again.
+//!
+//! Each result is kept as `<key>.<nonce>.output` (bincode) with its entry in
+//! `<key>.json`, keyed by [request_key]. The output is written under a name
+//! of its own and the entry, naming it, is written last, so a result is only
+//! found once it is complete and an entry is never paired with the output of
+//! a result replacing it. Results can also be looked up by the hash of their
+//! canonical input alone, whatever guest image proved them, for third
+//! parties fetching proofs that already exist instead of requesting new
+//! ones. The keys of each input hash are listed in `by-input/<input_hash>`,
+//! built from the entries the first time an index is opened without it.
+
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bonsai_sdk::alpha::responses::SnarkProof;
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{
+    checksum::sha256_hex,
+    clock,
+    input::{canonicalize, request_key},
+    registry::Guest,
+    Output,
+};
+
+/// Indexed result, without its output.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub key: String,
+    /// SHA-256 of the canonical input, hex encoded.
+    #[serde(default)]
+    pub input_hash: String,
+    pub guest: String,
+    pub image_id: String,
+    /// Scheduled job and run that proved the result, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub run: Option<u64>,
+    /// Block whose state the input was built from, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block: Option<u64>,
+    /// Unix time at which the result was indexed, in seconds.
+    pub recorded_at: u64,
+}
+
+/// Entry as kept in `<key>.json`, with the file its output was written to.
+#[derive(Serialize, Deserialize)]
+struct StoredEntry {
+    #[serde(flatten)]
+    entry: IndexEntry,
+    /// Absent for entries recorded before outputs had names of their own,
+    /// whose output is `<key>.output`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+}
+
+const BY_INPUT_DIR: &str = "by-input";
+
+/// Keys an input is indexed under.
+#[derive(Debug, Clone)]
+pub struct InputKeys {
+    /// [request_key] of the guest and canonical input.
+    pub key: String,
+    /// SHA-256 of the canonical input, hex encoded.
+    pub input_hash: String,
+}
+
+/// Indexed result as served to clients, with its proof hex encoded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexedReceipt {
+    #[serde(flatten)]
+    pub entry: IndexEntry,
+    pub journal: String,
+    pub post_state_digest: Option<String>,
+    pub snark_proof: Option<SnarkProof>,
+    /// Receipt, bincode encoded, if the result was proven.
+    pub receipt: Option<String>,
+}
+
+impl IndexedReceipt {
+    pub fn new(entry: IndexEntry, output: &Output) -> Result<Self> {
+        let (journal, receipt, snark_proof) = match output {
+            Output::Execution { journal } => (journal, None, None),
+            Output::Bonsai {
+                journal,
+                receipt,
+                snark_proof,
+                ..
+            } => (journal, Some(receipt), Some(snark_proof.clone())),
+            Output::Stark { journal, receipt } => (journal, Some(receipt), None),
+        };
+        let post_state_digest = receipt
+            .map(|receipt| receipt.get_metadata())
+            .transpose()
+            .context("Failed to read receipt metadata")?
+            .map(|metadata| hex::encode(metadata.post.digest()));
+        let receipt = receipt
+            .map(bincode::serialize)
+            .transpose()
+            .context("Failed to serialize receipt")?
+            .map(hex::encode);
+        Ok(Self {
+            entry,
+            journal: hex::encode(journal),
+            post_state_digest,
+            snark_proof,
+            receipt,
+        })
+    }
+}
+
+/// Directory of proven results keyed by guest and canonical input.
+pub struct ReceiptIndex {
+    dir: PathBuf,
+    append: Mutex<()>,
+}
+
+impl ReceiptIndex {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create receipt index directory {}",
+            dir.display()
+        ))?;
+        let index = Self {
+            dir: dir.to_path_buf(),
+            append: Mutex::new(()),
+        };
+        if !index.dir.join(BY_INPUT_DIR).is_dir() {
+            index.build_input_index()?;
+        }
+        Ok(index)
+    }
+
+    /// List the key of every entry under its input hash, in a directory
+    /// moved into place once complete.
+    fn build_input_index(&self) -> Result<()> {
+        let building = tempfile::Builder::new()
+            .prefix(".by-input")
+            .tempdir_in(&self.dir)
+            .context("Failed to create temp dir")?;
+        let entries = std::fs::read_dir(&self.dir)
+            .context(format!("Failed to list {}", self.dir.display()))?;
+        for entry in entries {
+            let path = entry
+                .context(format!("Failed to list {}", self.dir.display()))?
+                .path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(key) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            if let Some(stored) = self.stored(key)? {
+                append_key(building.path(), &stored.entry.input_hash, key)?;
+            }
+        }
+        let path = self.dir.join(BY_INPUT_DIR);
+        match std::fs::rename(building.path(), &path) {
+            Ok(()) => Ok(()),
+            // Built at the same time by another process sharing the index.
+            Err(_) if path.is_dir() => Ok(()),
+            Err(err) => Err(err).context(format!("Failed to create {}", path.display())),
+        }
+    }
+
+    /// Keys of `input` to `guest`, taken over its canonical form.
+    pub fn keys(guest: &Guest, input: &[u8]) -> Result<InputKeys> {
+        let canonical = canonicalize(&guest.name, input)?;
+        Ok(InputKeys {
+            key: request_key(guest.image_id, &canonical),
+            input_hash: sha256_hex(&canonical),
+        })
+    }
+
+    fn path(&self, key: &str, extension: &str) -> Result<PathBuf> {
+        if !is_hex(key) {
+            bail!("invalid receipt index key {key:?}");
+        }
+        Ok(self.dir.join(format!("{key}.{extension}")))
+    }
+
+    /// Path of the output of the entry `stored` under `key`.
+    fn output_path(&self, key: &str, stored: &StoredEntry) -> Result<PathBuf> {
+        match &stored.output {
+            Some(name) => {
+                let nonce = name
+                    .strip_prefix(&format!("{key}."))
+                    .and_then(|name| name.strip_suffix(".output"));
+                match nonce {
+                    Some(nonce) if is_hex(nonce) => Ok(self.dir.join(name)),
+                    _ => bail!("invalid output file {name:?} of receipt index key {key}"),
+                }
+            }
+            None => self.path(key, "output"),
+        }
+    }
+
+    /// Atomically replace the file at `path`.
+    fn write(&self, path: &Path, data: &[u8]) -> Result<()> {
+        let mut file = NamedTempFile::new_in(&self.dir).context("Failed to create temp file")?;
+        file.write_all(data)
+            .context(format!("Failed to write {}", path.display()))?;
+        file.persist(path)
+            .context(format!("Failed to persist {}", path.display()))?;
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<Vec<u8>>> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// Index `output` under its entry, replacing any result with the same
+    /// key. A lookup racing the replacement finds either result whole, or
+    /// misses if the replaced output is removed under it.
+    pub fn record(&self, entry: &IndexEntry, output: &Output) -> Result<()> {
+        let path = self.path(&entry.key, "json")?;
+        let previous = self.stored(&entry.key)?;
+        let output = bincode::serialize(output).context("Failed to serialize indexed output")?;
+        let name = format!(
+            "{}.{}.output",
+            entry.key,
+            hex::encode(rand::random::<[u8; 8]>())
+        );
+        self.write(&self.dir.join(&name), &output)?;
+        let stored = StoredEntry {
+            entry: entry.clone(),
+            output: Some(name),
+        };
+        let data = serde_json::to_vec(&stored).context("Failed to serialize index entry")?;
+        self.write(&path, &data)?;
+
+        if let Some(previous) = &previous {
+            if let Ok(path) = self.output_path(&entry.key, previous) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        if !matches!(&previous, Some(previous) if previous.entry.input_hash == entry.input_hash) {
+            let _append = self.append.lock().unwrap_or_else(PoisonError::into_inner);
+            append_key(&self.dir.join(BY_INPUT_DIR), &entry.input_hash, &entry.key)?;
+        }
+        Ok(())
+    }
+
+    fn stored(&self, key: &str) -> Result<Option<StoredEntry>> {
+        let path = self.path(key, "json")?;
+        let Some(data) = self.read(&path)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&data)
+            .context(format!("Failed to parse {}", path.display()))
+            .map(Some)
+    }
+
+    /// Entry of the result indexed under `key`, if any.
+    pub fn entry(&self, key: &str) -> Result<Option<IndexEntry>> {
+        Ok(self.stored(key)?.map(|stored| stored.entry))
+    }
+
+    /// Result indexed under `key`, if any.
+    pub fn get(&self, key: &str) -> Result<Option<(IndexEntry, Output)>> {
+        let Some(stored) = self.stored(key)? else {
+            return Ok(None);
+        };
+        let path = self.output_path(key, &stored)?;
+        let Some(data) = self.read(&path)? else {
+            return Ok(None);
+        };
+        let output =
+            bincode::deserialize(&data).context(format!("Failed to parse {}", path.display()))?;
+        Ok(Some((stored.entry, output)))
+    }
+
+    /// Result indexed under `key` within the last `max_age`, if any.
+    pub fn recent(&self, key: &str, max_age: Duration) -> Result<Option<(IndexEntry, Output)>> {
+        let oldest = clock::unix_now().saturating_sub(max_age.as_secs());
+        match self.entry(key)? {
+            Some(entry) if entry.recorded_at >= oldest => self.get(key),
+            _ => Ok(None),
+        }
+    }
+
+    /// Every result whose canonical input hashes to `input_hash`, most
+    /// recent first.
+    pub fn by_input(&self, input_hash: &str) -> Result<Vec<(IndexEntry, Output)>> {
+        let input_hash = input_hash.trim_start_matches("0x").to_ascii_lowercase();
+        if !is_hex(&input_hash) {
+            return Ok(Vec::new());
+        }
+        let path = self.dir.join(BY_INPUT_DIR).join(&input_hash);
+        let Some(keys) = self.read(&path)? else {
+            return Ok(Vec::new());
+        };
+        let keys = String::from_utf8(keys).context(format!("Failed to read {}", path.display()))?;
+        let mut seen = HashSet::new();
+        let mut found = Vec::new();
+        for key in keys.lines().filter(|key| seen.insert(*key)) {
+            match self.get(key)? {
+                Some(result) if result.0.input_hash == input_hash => found.push(result),
+                _ => {}
+            }
+        }
+        found.sort_by_key(|(entry, _)| Reverse(entry.recorded_at));
+        Ok(found)
+    }
+}
+
+fn is_hex(value: &str) -> bool {
+    !value.is_empty() && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Add `key` to the keys listed under `input_hash` in the input index at
+/// `dir`. Entries recorded without an input hash are not listed.
+fn append_key(dir: &Path, input_hash: &str, key: &str) -> Result<()> {
+    if !is_hex(input_hash) {
+        return Ok(());
+    }
+    let path = dir.join(input_hash);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(format!("{key}\n").as_bytes()))
+        .context(format!("Failed to append to {}", path.display()))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn entry(key: &str, input_hash: &str, recorded_at: u64) -> IndexEntry {
+        IndexEntry {
+            key: key.to_string(),
+            input_hash: input_hash.to_string(),
+            guest: "TWAP".to_string(),
+            image_id: "00".repeat(32),
+            job: None,
+            run: None,
+            block: None,
+            recorded_at,
+        }
+    }
+
+    fn journal(output: &Output) -> &[u8] {
+        match output {
+            Output::Execution { journal } => journal,
+            _ => panic!("not an execution"),
+        }
+    }
+
+    #[test]
+    fn test_replacing_a_result_keeps_one_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = ReceiptIndex::open(dir.path()).unwrap();
+        let first = Output::Execution { journal: vec![1] };
+        let second = Output::Execution { journal: vec![2] };
+        index.record(&entry("aa", "ff", 1), &first).unwrap();
+        index.record(&entry("aa", "ff", 2), &second).unwrap();
+
+        let (found, output) = index.get("aa").unwrap().unwrap();
+        assert_eq!(found.recorded_at, 2);
+        assert_eq!(journal(&output), [2]);
+        let outputs = std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().ends_with(".output")
+            })
+            .count();
+        assert_eq!(outputs, 1);
+        assert_eq!(index.by_input("0xFF").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_by_input_lists_results_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = ReceiptIndex::open(dir.path()).unwrap();
+        let output = Output::Execution { journal: vec![] };
+        index.record(&entry("aa", "ff", 1), &output).unwrap();
+        index.record(&entry("bb", "ff", 2), &output).unwrap();
+        index.record(&entry("cc", "ee", 3), &output).unwrap();
+
+        let keys: Vec<String> = index
+            .by_input("ff")
+            .unwrap()
+            .into_iter()
+            .map(|(entry, _)| entry.key)
+            .collect();
+        assert_eq!(keys, ["bb", "aa"]);
+        assert!(index.by_input("dd").unwrap().is_empty());
+        assert!(index.by_input("../aa").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_open_indexes_legacy_entries_by_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = Output::Execution { journal: vec![3] };
+        std::fs::write(
+            dir.path().join("aa.output"),
+            bincode::serialize(&output).unwrap(),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("aa.json"),
+            serde_json::to_vec(&entry("aa", "ff", 1)).unwrap(),
+        )
+        .unwrap();
+
+        let index = ReceiptIndex::open(dir.path()).unwrap();
+        let found = index.by_input("ff").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(journal(&found[0].1), [3]);
+    }
+}
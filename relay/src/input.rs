@@ -0,0 +1,531 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, ParamType, Token},
+    types::{Address, Bytes, I256, U256},
+};
+use risc0_zkvm::sha::Digest;
+use sha2::{Digest as _, Sha256};
+
+use crate::{
+    schema::{input_schema, input_schema_version},
+    tokens::{sqrt_price_x96, PriceDirection},
+};
+
+/// Prefix of an input split into a public and a private section, laid out as
+/// `PRIVATE_INPUT_MAGIC || u32 LE public length || public || private`. Guests
+/// taking private input commit only the digest of its salted section, see
+/// [private_section], as the last value of their journal. Must match the
+/// guest library.
+pub const PRIVATE_INPUT_MAGIC: &[u8; 4] = b"RZPI";
+
+/// Prefix of the standard input frame, laid out as `INPUT_FRAME_MAGIC ||
+/// u8 frame version || 8 byte schema name || u16 LE schema version || u8
+/// section count || (u32 LE length || section)*`. The first section is the
+/// public input and the optional second one the private input. Guests reject
+/// frames built for another schema instead of misreading them. Must match
+/// the guest library.
+pub const INPUT_FRAME_MAGIC: &[u8; 4] = b"RZIF";
+
+/// Version of the frame layout itself.
+pub const INPUT_FRAME_VERSION: u8 = 1;
+
+/// Input of the SWAP guest, mirroring the arguments of `requestSwap`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapInput {
+    pub request_root: [u8; 32],
+    pub sqrt_price_x96: U256,
+    pub sqrt_price_target_x96: U256,
+    pub liquidity: u128,
+    pub amount_specified: I256,
+    pub fee_pips: u32,
+    /// Block timestamp at which the pool state was read.
+    pub observed_at: u64,
+    /// Least liquidity the guest accepts the pool at. The guest commits it
+    /// with the pool's liquidity, so consumers can reject dust pools.
+    pub min_liquidity: u128,
+    /// Proof of the price and liquidity against the pool's storage. Inputs
+    /// sent by the pool contract itself carry none, as it reads its own
+    /// state.
+    pub state: Option<PoolStateProof>,
+}
+
+/// Proof of a pool's `slot0` and `liquidity` slots at a block, which the
+/// SWAP guest checks the input's price, liquidity and `observed_at` against
+/// before committing the pool and block. Encoded as (address pool, bytes
+/// header, bytes[] account_proof, bytes[] slot0_proof, bytes[]
+/// liquidity_proof, bool unfinalized).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolStateProof {
+    pub pool: Address,
+    /// RLP encoded header of the block.
+    pub header: Bytes,
+    pub account_proof: Vec<Bytes>,
+    pub slot0_proof: Vec<Bytes>,
+    pub liquidity_proof: Vec<Bytes>,
+    /// The block was read from a rollup sequencer that can still reorder
+    /// it, see [crate::finality::FinalityPolicy::Sequencer]. The guest
+    /// commits it, so consumers see it in the proven journal.
+    pub unfinalized: bool,
+}
+
+impl PoolStateProof {
+    fn encode(&self) -> Vec<u8> {
+        let proof = |nodes: &[Bytes]| {
+            Token::Array(
+                nodes
+                    .iter()
+                    .map(|node| Token::Bytes(node.to_vec()))
+                    .collect(),
+            )
+        };
+        abi::encode(&[
+            Token::Address(self.pool),
+            Token::Bytes(self.header.to_vec()),
+            proof(&self.account_proof),
+            proof(&self.slot0_proof),
+            proof(&self.liquidity_proof),
+            Token::Bool(self.unfinalized),
+        ])
+    }
+}
+
+impl SwapInput {
+    /// ABI encode the input in the layout expected by the guest.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let tokens = vec![
+            Token::FixedBytes(self.request_root.to_vec()),
+            Token::Uint(self.sqrt_price_x96),
+            Token::Uint(self.sqrt_price_target_x96),
+            Token::Uint(self.liquidity.into()),
+            Token::Int(self.amount_specified.into_raw()),
+            Token::Uint(self.fee_pips.into()),
+            Token::Uint(self.observed_at.into()),
+            Token::Uint(self.min_liquidity.into()),
+            Token::Bytes(
+                self.state
+                    .as_ref()
+                    .map(PoolStateProof::encode)
+                    .unwrap_or_default(),
+            ),
+        ];
+        canonicalize("SWAP", &abi::encode(&tokens))
+    }
+
+    /// Set the current and target prices from decimal prices in whole tokens
+    /// quoted in `direction`, e.g. `"1834.25"` USDC per WETH. Entering
+    /// `sqrtPriceX96` values by hand is how prices end up inverted.
+    pub fn with_prices(
+        mut self,
+        price: &str,
+        target: &str,
+        direction: PriceDirection,
+        decimals0: u8,
+        decimals1: u8,
+    ) -> Result<Self> {
+        self.sqrt_price_x96 = sqrt_price_x96(price, direction, decimals0, decimals1)
+            .context("Invalid current price")?;
+        self.sqrt_price_target_x96 = sqrt_price_x96(target, direction, decimals0, decimals1)
+            .context("Invalid target price")?;
+        Ok(self)
+    }
+}
+
+/// A pool's SWAP guest inputs within a BATCH guest input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchFeed {
+    pub pool: Address,
+    pub sqrt_price_x96: U256,
+    pub sqrt_price_target_x96: U256,
+    pub liquidity: u128,
+    pub amount_specified: I256,
+    pub fee_pips: u32,
+    pub min_liquidity: u128,
+}
+
+/// Input of the BATCH guest, which proves the prices of many pools read at
+/// the same block in one proof.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchInput {
+    pub request_root: [u8; 32],
+    /// Block timestamp at which the pools' state was read.
+    pub observed_at: u64,
+    pub feeds: Vec<BatchFeed>,
+    /// The block was read from a rollup sequencer that can still reorder
+    /// it, committed by the guest as for [PoolStateProof::unfinalized].
+    pub unfinalized: bool,
+}
+
+impl BatchInput {
+    /// ABI encode the input in the layout expected by the guest.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let feeds = self
+            .feeds
+            .iter()
+            .map(|feed| {
+                Token::Tuple(vec![
+                    Token::Address(feed.pool),
+                    Token::Uint(feed.sqrt_price_x96),
+                    Token::Uint(feed.sqrt_price_target_x96),
+                    Token::Uint(feed.liquidity.into()),
+                    Token::Int(feed.amount_specified.into_raw()),
+                    Token::Uint(feed.fee_pips.into()),
+                    Token::Uint(feed.min_liquidity.into()),
+                ])
+            })
+            .collect();
+        let tokens = vec![
+            Token::FixedBytes(self.request_root.to_vec()),
+            Token::Uint(self.observed_at.into()),
+            Token::Array(feeds),
+            Token::Bool(self.unfinalized),
+        ];
+        canonicalize("BATCH", &abi::encode(&tokens))
+    }
+}
+
+/// Check that every integer fits the width declared by its type, which ABI
+/// decoding does not enforce on its own.
+fn check_tokens(schema: &[ParamType], tokens: &[Token]) -> Result<()> {
+    for (i, (param, token)) in schema.iter().zip(tokens).enumerate() {
+        match (param, token) {
+            (ParamType::Uint(bits), Token::Uint(value)) if value.bits() > *bits => {
+                bail!("value {i} does not fit uint{bits}")
+            }
+            // I256::bits counts the sign bit, as does the N in intN.
+            (ParamType::Int(bits), Token::Int(raw))
+                if I256::from_raw(*raw).bits() as usize > *bits =>
+            {
+                bail!("value {i} does not fit int{bits}")
+            }
+            (ParamType::Array(inner), Token::Array(items))
+            | (ParamType::FixedArray(inner, _), Token::FixedArray(items)) => {
+                let schema = vec![inner.as_ref().clone(); items.len()];
+                check_tokens(&schema, items)?;
+            }
+            (ParamType::Tuple(schema), Token::Tuple(items)) => check_tokens(schema, items)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Join the public and private sections of a guest input.
+pub fn frame_input(public: &[u8], private: &[u8]) -> Result<Vec<u8>> {
+    let public_len = u32::try_from(public.len()).context("public input is too large")?;
+    Ok([
+        PRIVATE_INPUT_MAGIC.as_slice(),
+        &public_len.to_le_bytes(),
+        public,
+        private,
+    ]
+    .concat())
+}
+
+/// Schema a framed input was built for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameSchema {
+    pub name: String,
+    pub version: u16,
+}
+
+/// Frame the sections of an input of `guest_name`, which must have a
+/// versioned input schema.
+pub fn frame_sections(guest_name: &str, public: &[u8], private: Option<&[u8]>) -> Result<Vec<u8>> {
+    let version = input_schema_version(guest_name)
+        .ok_or_else(|| anyhow!("guest {guest_name} has no versioned input schema"))?;
+    let name = guest_name.to_uppercase();
+    if name.len() > 8 {
+        bail!("schema name {name} is longer than 8 bytes");
+    }
+    let mut frame = INPUT_FRAME_MAGIC.to_vec();
+    frame.push(INPUT_FRAME_VERSION);
+    frame.extend(format!("{name:\0<8}").as_bytes());
+    frame.extend(version.to_le_bytes());
+    let sections: Vec<&[u8]> = std::iter::once(public).chain(private).collect();
+    frame.push(sections.len() as u8);
+    for section in sections {
+        let len = u32::try_from(section.len()).context("input section is too large")?;
+        frame.extend(len.to_le_bytes());
+        frame.extend(section);
+    }
+    Ok(frame)
+}
+
+/// Schema a framed input was built for, or `None` for unframed input.
+pub fn frame_schema(input: &[u8]) -> Result<Option<FrameSchema>> {
+    Ok(parse_frame(input)?.map(|(schema, _, _)| schema))
+}
+
+type Frame<'a> = (FrameSchema, &'a [u8], Option<&'a [u8]>);
+
+fn parse_frame(input: &[u8]) -> Result<Option<Frame<'_>>> {
+    let Some(rest) = input.strip_prefix(INPUT_FRAME_MAGIC.as_slice()) else {
+        return Ok(None);
+    };
+    if rest.len() < 12 {
+        bail!("input frame is truncated");
+    }
+    let (header, mut rest) = rest.split_at(12);
+    if header[0] != INPUT_FRAME_VERSION {
+        bail!(
+            "input frame version {} is not supported, expected {INPUT_FRAME_VERSION}",
+            header[0]
+        );
+    }
+    let name = String::from_utf8_lossy(&header[1..9])
+        .trim_end_matches('\0')
+        .to_string();
+    let version = u16::from_le_bytes([header[9], header[10]]);
+    let count = header[11];
+    if !(1..=2).contains(&count) {
+        bail!("input frame has {count} sections, expected 1 or 2");
+    }
+    let mut sections = Vec::new();
+    for _ in 0..count {
+        if rest.len() < 4 {
+            bail!("input frame is truncated");
+        }
+        let (len, tail) = rest.split_at(4);
+        let len = u32::from_le_bytes(len.try_into()?) as usize;
+        if tail.len() < len {
+            bail!("input frame is shorter than its sections");
+        }
+        let (section, tail) = tail.split_at(len);
+        sections.push(section);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        bail!("input frame has {} trailing bytes", rest.len());
+    }
+    Ok(Some((
+        FrameSchema { name, version },
+        sections[0],
+        sections.get(1).copied(),
+    )))
+}
+
+/// Split a guest input into its public and, if framed, private section.
+pub fn split_input(input: &[u8]) -> Result<(&[u8], Option<&[u8]>)> {
+    if let Some((_, public, private)) = parse_frame(input)? {
+        return Ok((public, private));
+    }
+    let Some(rest) = input.strip_prefix(PRIVATE_INPUT_MAGIC.as_slice()) else {
+        return Ok((input, None));
+    };
+    if rest.len() < 4 {
+        bail!("framed input is truncated");
+    }
+    let (public_len, rest) = rest.split_at(4);
+    let public_len = u32::from_le_bytes(public_len.try_into()?) as usize;
+    if rest.len() < public_len {
+        bail!("framed input is shorter than its public section");
+    }
+    let (public, private) = rest.split_at(public_len);
+    Ok((public, Some(private)))
+}
+
+/// Length of the salt starting every private section.
+pub const PRIVATE_SALT_LEN: usize = 32;
+
+/// Private section of a guest input, `salt || private`. Private inputs such
+/// as strategy parameters are often few enough to try them all against an
+/// unsalted digest, so the section starts with a salt, drawn at random unless
+/// given. The salt must be kept as secret as the input until the commitment
+/// is opened. Must match the guest library.
+pub fn private_section(private: &[u8], salt: Option<[u8; PRIVATE_SALT_LEN]>) -> Vec<u8> {
+    let salt = salt.unwrap_or_else(rand::random);
+    [salt.as_slice(), private].concat()
+}
+
+/// Salt of the private section of a guest input, if it has one.
+pub fn private_salt(input: &[u8]) -> Result<Option<[u8; PRIVATE_SALT_LEN]>> {
+    let (_, private) = split_input(input)?;
+    let Some(private) = private else {
+        return Ok(None);
+    };
+    let salt = private
+        .get(..PRIVATE_SALT_LEN)
+        .ok_or_else(|| anyhow!("private input section is shorter than its salt"))?;
+    Ok(Some(salt.try_into()?))
+}
+
+/// Digest a guest commits in place of its private input: the SHA-256 digest
+/// of the salted private section.
+pub fn private_input_digest(section: &[u8]) -> [u8; 32] {
+    Sha256::digest(section).into()
+}
+
+/// Rewrite an ABI encoded guest input into its canonical form: decoded
+/// strictly against the guest's input schema, integers checked against their
+/// declared widths, re-encoded without padding tricks or trailing bytes and
+/// wrapped in the standard input frame. Only the public section is
+/// rewritten. Inputs framed for another schema are rejected, and inputs of
+/// guests without a schema are returned unchanged.
+pub fn canonicalize(guest_name: &str, input: &[u8]) -> Result<Vec<u8>> {
+    let Some(schema) = input_schema(guest_name) else {
+        return Ok(input.to_vec());
+    };
+    if let Some(framed) = frame_schema(input)? {
+        let version = input_schema_version(guest_name);
+        if !framed.name.eq_ignore_ascii_case(guest_name) || Some(framed.version) != version {
+            bail!(
+                "input was built for schema {} v{}, but guest {guest_name} reads v{}",
+                framed.name,
+                framed.version,
+                version.unwrap_or_default()
+            );
+        }
+    }
+    let (public, private) = split_input(input)?;
+    let tokens = decode_public(guest_name, &schema, public)?;
+    frame_sections(guest_name, &abi::encode(&tokens), private)
+}
+
+/// Decode the public section of an input of `guest_name` strictly against
+/// its input `schema`, rejecting trailing bytes and integers wider than
+/// their declared types.
+pub fn decode_public(guest_name: &str, schema: &[ParamType], public: &[u8]) -> Result<Vec<Token>> {
+    // `decode_whole` only counts the head words of dynamic values, so it
+    // rejects every valid input holding any. Check nothing trails the values
+    // by re-encoding them instead.
+    let tokens = abi::decode(schema, public)
+        .context(format!("Input does not match the {guest_name} schema"))?;
+    check_tokens(schema, &tokens)?;
+    let encoded_len = abi::encode(&tokens).len();
+    if encoded_len != public.len() {
+        bail!(
+            "input does not match the {guest_name} schema: it holds {} bytes, but its values \
+             encode to {encoded_len}",
+            public.len(),
+        );
+    }
+    Ok(tokens)
+}
+
+/// Key identifying a request by guest and canonical input, suitable for
+/// caching and idempotency.
+pub fn request_key(image_id: Digest, canonical_input: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(image_id.as_bytes());
+    hasher.update(canonical_input);
+    hex::encode(hasher.finalize())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_private_sections_are_salted() {
+        let private = [7u8; 4];
+        let first = private_section(&private, None);
+        let second = private_section(&private, None);
+        assert_eq!(first.len(), PRIVATE_SALT_LEN + private.len());
+        assert_eq!(&first[PRIVATE_SALT_LEN..], private);
+        // Equal inputs do not commit equal digests.
+        assert_ne!(private_input_digest(&first), private_input_digest(&second));
+
+        let salt = [0x5a; PRIVATE_SALT_LEN];
+        let input = frame_input(b"public", &private_section(&private, Some(salt))).unwrap();
+        assert_eq!(private_salt(&input).unwrap(), Some(salt));
+        assert_eq!(private_salt(b"public").unwrap(), None);
+        let unsalted = frame_input(b"public", &private).unwrap();
+        assert!(private_salt(&unsalted).is_err());
+    }
+
+    fn batch() -> BatchInput {
+        let feed = BatchFeed {
+            pool: Address::repeat_byte(0x11),
+            sqrt_price_x96: U256::from(1) << 96,
+            sqrt_price_target_x96: U256::from(2) << 96,
+            liquidity: 1_000_000,
+            amount_specified: I256::from(-5_000),
+            fee_pips: 3_000,
+            min_liquidity: 1_000,
+        };
+        BatchInput {
+            request_root: [7; 32],
+            observed_at: 1_700_000_000,
+            feeds: vec![feed.clone(), feed],
+            unfinalized: false,
+        }
+    }
+
+    fn public(framed: &[u8]) -> Vec<u8> {
+        split_input(framed).unwrap().0.to_vec()
+    }
+
+    #[test]
+    fn test_canonicalize_accepts_dynamic_values() {
+        let framed = batch().encode().unwrap();
+        let schema = frame_schema(&framed).unwrap().unwrap();
+        assert_eq!(schema.name, "BATCH");
+        // Canonical input is a fixed point.
+        assert_eq!(canonicalize("BATCH", &framed).unwrap(), framed);
+        assert_eq!(canonicalize("BATCH", &public(&framed)).unwrap(), framed);
+    }
+
+    #[test]
+    fn test_canonicalize_rejects_trailing_bytes() {
+        let mut input = public(&batch().encode().unwrap());
+        input.extend([0; 32]);
+        let err = canonicalize("BATCH", &input).unwrap_err();
+        assert!(err.to_string().contains("values encode to"), "{err}");
+    }
+
+    #[test]
+    fn test_canonicalize_rejects_truncated_input() {
+        let input = public(&batch().encode().unwrap());
+        assert!(canonicalize("BATCH", &input[..input.len() - 32]).is_err());
+        assert!(canonicalize("BATCH", &input[..31]).is_err());
+        assert!(canonicalize("BATCH", &[]).is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_rejects_overwide_integers() {
+        let tokens = vec![
+            Token::FixedBytes(vec![0; 32]),
+            Token::Uint(U256::from(1) << 160),
+            Token::Uint(U256::one()),
+            Token::Uint(U256::one()),
+            Token::Int(U256::one()),
+            Token::Uint(U256::one()),
+            Token::Uint(U256::one()),
+            Token::Uint(U256::zero()),
+            Token::Bytes(vec![]),
+        ];
+        let err = canonicalize("SWAP", &abi::encode(&tokens)).unwrap_err();
+        assert_eq!(err.to_string(), "value 1 does not fit uint160");
+    }
+
+    #[test]
+    fn test_canonicalize_rejects_other_schema() {
+        let framed = batch().encode().unwrap();
+        let err = canonicalize("SWAP", &framed).unwrap_err();
+        assert!(err.to_string().contains("built for schema BATCH"), "{err}");
+
+        let mut framed = framed;
+        framed.push(0);
+        assert!(canonicalize("BATCH", &framed).is_err());
+    }
+
+    #[test]
+    fn test_canonicalize_passes_unknown_guests() {
+        assert_eq!(canonicalize("ECHO", b"\x01\x02").unwrap(), b"\x01\x02");
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use ethers::{providers::Middleware, types::Address};
+use futures::FutureExt;
+
+use crate::{
+    bindings::{UniswapV3Pool, ZkPriceAggregator},
+    elog,
+    scheduler::ConditionFn,
+};
+
+/// Why a `ZkPriceAggregator` wants a new round, mirroring its
+/// `UpdateReason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    None,
+    FirstRound,
+    Heartbeat,
+    Deviation,
+}
+
+impl Trigger {
+    pub fn fires(self) -> bool {
+        self != Trigger::None
+    }
+}
+
+impl TryFrom<u8> for Trigger {
+    type Error = anyhow::Error;
+
+    fn try_from(reason: u8) -> Result<Self> {
+        Ok(match reason {
+            0 => Trigger::None,
+            1 => Trigger::FirstRound,
+            2 => Trigger::Heartbeat,
+            3 => Trigger::Deviation,
+            _ => bail!("unknown update reason {reason}"),
+        })
+    }
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Trigger::None => "no update due",
+            Trigger::FirstRound => "first round",
+            Trigger::Heartbeat => "heartbeat expired",
+            Trigger::Deviation => "price deviation",
+        })
+    }
+}
+
+/// Watches a pool against the update conditions encoded in the aggregator
+/// it feeds, so a job only proves and transmits a round when the aggregator
+/// wants one. Reading the pool's current price costs no proving and no gas.
+pub struct Keeper<M> {
+    pool: UniswapV3Pool<M>,
+    aggregator: ZkPriceAggregator<M>,
+}
+
+impl<M: Middleware + 'static> Keeper<M> {
+    pub fn new(pool: Address, aggregator: Address, client: Arc<M>) -> Self {
+        Self {
+            pool: UniswapV3Pool::new(pool, client.clone()),
+            aggregator: ZkPriceAggregator::new(aggregator, client),
+        }
+    }
+
+    pub async fn check(&self) -> Result<Trigger> {
+        let (sqrt_price_x96, ..) = self.pool.slot_0().call().await.context(format!(
+            "Failed to read slot0 of pool {:?}",
+            self.pool.address()
+        ))?;
+        let reason = self
+            .aggregator
+            .check_update(sqrt_price_x96)
+            .call()
+            .await
+            .context("Failed to check the aggregator's update condition")?;
+        Trigger::try_from(reason)
+    }
+
+    /// The keeper as a scheduled job's condition.
+    pub fn condition(self: Arc<Self>) -> ConditionFn {
+        Arc::new(move || {
+            let keeper = self.clone();
+            async move {
+                let trigger = keeper.check().await?;
+                if trigger.fires() {
+                    elog!(
+                        "Aggregator {:?} is due for an update: {trigger}",
+                        keeper.aggregator.address()
+                    );
+                }
+                Ok(trigger.fires())
+            }
+            .boxed()
+        })
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Leases claiming the runs of scheduled jobs among relay replicas sharing a
+//! directory, such as a volume mounted into every pod of a deployment, and
+//! checkpoints of the Bonsai sessions proving them. A lease is renewed for as
+//! long as its run proves, however many hours that takes; once its holder
+//! stops renewing it, whether shut down or killed, another replica claims the
+//! run and resumes polling the checkpointed session instead of orphaning it.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+};
+
+use crate::{
+    clock::{self, MaxSkew},
+    elog,
+    finality::FinalityPolicy,
+};
+
+/// Fraction of a lease's TTL after which it is renewed.
+const RENEW_DIVISOR: u32 = 3;
+
+tokio::task_local! {
+    static SESSION: SessionHook;
+}
+
+/// Contents of a lease file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LeaseFile {
+    holder: String,
+    /// Unix time at which the lease lapses unless renewed, in seconds.
+    expires_at: u64,
+}
+
+/// Bonsai session proving a claimed run, recorded as soon as it is created so
+/// the next holder of the lease can resume it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub run: u64,
+    pub session_id: String,
+    /// Block the run's input was built from, if any.
+    pub block: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finality: Option<FinalityPolicy>,
+    /// Replica that started the session.
+    pub holder: String,
+}
+
+fn read<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).context(format!("Failed to open {}", path.display())),
+    };
+    serde_json::from_reader(file)
+        .context(format!("Failed to parse {}", path.display()))
+        .map(Some)
+}
+
+/// Atomically replace the file at `path`.
+fn write(path: &Path, value: &impl Serialize) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = NamedTempFile::new_in(dir).context("Failed to create temp file")?;
+    serde_json::to_writer(&mut file, value)
+        .context(format!("Failed to write {}", path.display()))?;
+    file.flush()
+        .context(format!("Failed to write {}", path.display()))?;
+    file.persist(path)
+        .context(format!("Failed to persist {}", path.display()))?;
+    Ok(())
+}
+
+/// Name of this replica in lease files: the pod name under Kubernetes, or
+/// the host name, with the process ID.
+fn holder_name() -> String {
+    let host = std::env::var("POD_NAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "relay".to_string());
+    format!("{host}-{}", std::process::id())
+}
+
+/// Directory of leases shared by the relay's replicas, keeping each lease as
+/// `<key>.lease` and its checkpoint as `<key>.checkpoint.json`.
+pub struct Leases {
+    dir: PathBuf,
+    holder: String,
+    ttl: Duration,
+    max_skew: MaxSkew,
+}
+
+impl Leases {
+    /// Open the lease directory, claiming leases for `ttl` at a time.
+    pub fn open(dir: &Path, ttl: Duration) -> Result<Self> {
+        if ttl.as_secs() < RENEW_DIVISOR as u64 {
+            bail!("lease TTL must be at least {RENEW_DIVISOR} seconds");
+        }
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create lease directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            holder: holder_name(),
+            ttl,
+            max_skew: MaxSkew::default(),
+        })
+    }
+
+    /// Wait until a lease has lapsed by `skew` before taking it over, so a
+    /// holder whose clock runs behind this replica's is not displaced while
+    /// it still renews in time.
+    pub fn with_max_clock_skew(mut self, skew: Duration) -> Self {
+        self.max_skew = MaxSkew(skew);
+        self
+    }
+
+    /// Name this replica holds leases under.
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+
+    /// Claim the lease on `key`, unless another replica holds it. A lapsed
+    /// lease is taken over, keeping its checkpoint.
+    pub fn claim(&self, key: &str) -> Result<Option<Lease>> {
+        if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+            bail!("invalid lease key {key:?}");
+        }
+        let lease = Lease {
+            path: self.dir.join(format!("{key}.lease")),
+            checkpoint: self.dir.join(format!("{key}.checkpoint.json")),
+            key: key.to_string(),
+            holder: self.holder.clone(),
+            ttl: self.ttl,
+        };
+        // Creating the file is atomic, so only one replica claims a free
+        // lease. A lapsed lease is first moved aside, which only one replica
+        // taking it over succeeds at.
+        for _ in 0..2 {
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&lease.path)
+            {
+                Ok(file) => {
+                    serde_json::to_writer(file, &lease.file())
+                        .context(format!("Failed to write {}", lease.path.display()))?;
+                    return Ok(Some(lease));
+                }
+                Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+                Err(err) => {
+                    return Err(err).context(format!("Failed to create {}", lease.path.display()))
+                }
+            }
+            // A lease being written reads as unparseable; leave it to its
+            // writer.
+            let Ok(Some(current)) = read::<LeaseFile>(&lease.path) else {
+                return Ok(None);
+            };
+            let expires_at = UNIX_EPOCH + Duration::from_secs(current.expires_at);
+            if !self.max_skew.has_passed(expires_at, clock::now()) {
+                return Ok(None);
+            }
+            let lapsed = self.dir.join(format!(".{key}.{}.lapsed", self.holder));
+            match std::fs::rename(&lease.path, &lapsed) {
+                Ok(()) => {
+                    elog!(
+                        "Taking over lease {key} of {}, which lapsed at {}",
+                        current.holder,
+                        current.expires_at
+                    );
+                    let _ = std::fs::remove_file(&lapsed);
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(err) => {
+                    return Err(err).context(format!("Failed to move {}", lease.path.display()))
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A claimed lease, see [Leases::claim].
+pub struct Lease {
+    key: String,
+    path: PathBuf,
+    checkpoint: PathBuf,
+    holder: String,
+    ttl: Duration,
+}
+
+impl Lease {
+    fn file(&self) -> LeaseFile {
+        LeaseFile {
+            holder: self.holder.clone(),
+            expires_at: clock::unix_now() + self.ttl.as_secs(),
+        }
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Extend the lease by its TTL. Fails if another replica took it over
+    /// after it lapsed.
+    pub fn renew(&self) -> Result<()> {
+        match read::<LeaseFile>(&self.path)? {
+            Some(current) if current.holder == self.holder => write(&self.path, &self.file()),
+            Some(current) => bail!("lease {} was taken over by {}", self.key, current.holder),
+            None => bail!("lease {} was taken over", self.key),
+        }
+    }
+
+    /// Checkpoint left by this or a previous holder of the lease.
+    pub fn checkpoint(&self) -> Result<Option<Checkpoint>> {
+        read(&self.checkpoint)
+    }
+
+    /// Atomically replace the lease's checkpoint.
+    pub fn record_checkpoint(&self, checkpoint: &Checkpoint) -> Result<()> {
+        write(&self.checkpoint, checkpoint)
+    }
+
+    /// Remove the lease's checkpoint once its run is done with.
+    pub fn clear_checkpoint(&self) -> Result<()> {
+        match std::fs::remove_file(&self.checkpoint) {
+            Err(err) if err.kind() != ErrorKind::NotFound => {
+                Err(err).context(format!("Failed to remove {}", self.checkpoint.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Give up the lease, keeping its checkpoint, so another replica can
+    /// claim it without waiting for it to lapse.
+    pub fn release(&self) {
+        if let Ok(Some(current)) = read::<LeaseFile>(&self.path) {
+            if current.holder == self.holder {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+
+    /// Renew the lease in the background until the returned guard is
+    /// dropped, which releases it.
+    pub fn keep_alive(self) -> LeaseGuard {
+        let lease = Arc::new(self);
+        let lost = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn({
+            let lease = lease.clone();
+            let lost = lost.clone();
+            async move {
+                let mut ticker = tokio::time::interval(lease.ttl / RENEW_DIVISOR);
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    let renewal = tokio::task::spawn_blocking({
+                        let lease = lease.clone();
+                        move || lease.renew()
+                    })
+                    .await;
+                    match renewal {
+                        Ok(Ok(())) => {}
+                        // Failing to write the lease leaves it to lapse, so
+                        // keep trying until it is taken over.
+                        Ok(Err(err)) if lease.held_by_other() => {
+                            elog!("Lost lease {}: {err:?}", lease.key);
+                            lost.store(true, Ordering::SeqCst);
+                            return;
+                        }
+                        Ok(Err(err)) => elog!("Failed to renew lease {}: {err:?}", lease.key),
+                        Err(err) => elog!("Lease {} renewal panicked: {err}", lease.key),
+                    }
+                }
+            }
+        });
+        LeaseGuard { lease, lost, task }
+    }
+
+    /// Whether the lease file names another holder or is gone. Unreadable
+    /// files may be mid-write, so they are not taken as lost.
+    fn held_by_other(&self) -> bool {
+        match read::<LeaseFile>(&self.path) {
+            Ok(Some(current)) => current.holder != self.holder,
+            Ok(None) => true,
+            Err(_) => false,
+        }
+    }
+}
+
+/// A lease kept alive in the background, released when dropped, including
+/// when the task holding it is aborted on shutdown.
+pub struct LeaseGuard {
+    lease: Arc<Lease>,
+    lost: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl LeaseGuard {
+    pub fn lease(&self) -> &Lease {
+        &self.lease
+    }
+
+    /// Whether another replica took the lease over because it lapsed, in
+    /// which case this replica's result must not be used.
+    pub fn lost(&self) -> bool {
+        self.lost.load(Ordering::SeqCst)
+    }
+
+    /// Hook recording the Bonsai session of `run` in the lease's checkpoint,
+    /// and resuming the session already recorded for the run, if any.
+    pub fn session_hook(
+        &self,
+        run: u64,
+        block: Option<u64>,
+        finality: Option<FinalityPolicy>,
+    ) -> SessionHook {
+        let resume = match self.lease.checkpoint() {
+            Ok(Some(checkpoint)) if checkpoint.run == run => Some(checkpoint),
+            Ok(Some(checkpoint)) => {
+                elog!(
+                    "Abandoning Bonsai session {} of run {} of {}, started by {}",
+                    checkpoint.session_id,
+                    checkpoint.run,
+                    self.lease.key,
+                    checkpoint.holder
+                );
+                None
+            }
+            Ok(None) => None,
+            Err(err) => {
+                elog!(
+                    "Ignoring checkpoint of {}, starting afresh: {err:?}",
+                    self.lease.key
+                );
+                None
+            }
+        };
+        SessionHook {
+            lease: self.lease.clone(),
+            run,
+            block,
+            finality,
+            resume,
+        }
+    }
+}
+
+impl Drop for LeaseGuard {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.lease.release();
+    }
+}
+
+/// Bonsai session of a run held under a lease, see
+/// [LeaseGuard::session_hook]. Provers pick it up with [session_hook].
+#[derive(Clone)]
+pub struct SessionHook {
+    lease: Arc<Lease>,
+    run: u64,
+    block: Option<u64>,
+    finality: Option<FinalityPolicy>,
+    resume: Option<Checkpoint>,
+}
+
+impl SessionHook {
+    /// Checkpoint of a session a previous holder started for this run.
+    pub fn resume(&self) -> Option<&Checkpoint> {
+        self.resume.as_ref()
+    }
+
+    /// Record a newly created session, so the run can be resumed.
+    pub fn started(&self, session_id: &str) {
+        let checkpoint = Checkpoint {
+            run: self.run,
+            session_id: session_id.to_string(),
+            block: self.block,
+            finality: self.finality,
+            holder: self.lease.holder.clone(),
+        };
+        if let Err(err) = self.lease.record_checkpoint(&checkpoint) {
+            elog!(
+                "Failed to checkpoint Bonsai session {session_id} of {}: {err:?}",
+                self.lease.key
+            );
+        }
+    }
+}
+
+/// Run `future` with `hook` recording the Bonsai sessions it starts.
+pub async fn with_session_hook<F: std::future::Future>(hook: SessionHook, future: F) -> F::Output {
+    SESSION.scope(hook, future).await
+}
+
+/// Hook of the run being proven, if it is held under a lease.
+pub fn session_hook() -> Option<SessionHook> {
+    SESSION.try_with(Clone::clone).ok()
+}
+
+/// Resolves when the process is asked to stop, by SIGTERM as sent to pods
+/// being evicted or by Ctrl-C. Never resolves if neither can be listened for.
+pub async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            elog!("Failed to install SIGTERM handler, stopping only when killed: {err}");
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => elog!("Received SIGTERM, shutting down"),
+        Ok(()) = tokio::signal::ctrl_c() => elog!("Received Ctrl-C, shutting down"),
+    }
+}
@@ -12,16 +12,117 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::{anyhow, bail, Context, Result};
-use bonsai_sdk::alpha::{responses::SnarkProof, Client, SdkErr};
-use risc0_build::GuestListEntry;
-use risc0_zkvm::{
-    Executor, ExecutorEnv, MemoryImage, Program, Receipt, ReceiptMetadata, MEM_SIZE, PAGE_SIZE,
+use bonsai_sdk::alpha::{responses::SnarkProof, Client, SdkErr, SessionId};
+use ethers::{
+    abi::{self, Token, Tokenizable},
+    types::U256,
 };
+use risc0_zkvm::{Executor, ExecutorEnv, Receipt, ReceiptMetadata};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{BonsaiBackend, ProverBackend},
+    checksum::{image_digest, sha256_hex},
+    download::{download_to_file, log_progress},
+    error::RelayError,
+    input::{canonicalize, frame_input, private_section, PRIVATE_SALT_LEN},
+    lease::SessionHook,
+    pool::ImagePool,
+    registry::Guest,
+    retry::{retry_transient, session_retries, AttemptLog, Backoff, SessionAttempt},
+};
+
+pub mod access;
+pub mod aggregator;
+pub mod alert;
+pub mod approval;
+pub mod artifacts;
+pub mod backend;
+pub mod bindings;
+pub mod bonsai_api;
+pub mod canary;
+pub mod capabilities;
+pub mod cases;
+pub mod catalog;
+pub mod chain;
+pub mod checksum;
+pub mod clock;
+pub mod cluster;
+pub mod continuity;
+pub mod cycle;
+pub mod deadletter;
+pub mod dedup;
+pub mod discovery;
+pub mod doctor;
+pub mod download;
+pub mod envconfig;
+pub mod error;
+pub mod escrow;
+pub mod eth;
+pub mod evidence;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod finality;
+pub mod format;
+pub mod gas;
+pub mod guardian;
+pub mod history;
+pub mod index;
+pub mod input;
+pub mod keeper;
+pub mod lease;
+pub mod listener;
+pub mod loadtest;
+pub mod metrics;
+#[cfg(feature = "node")]
+pub mod node;
+pub mod oneshot;
+pub mod pool;
+pub mod postprocess;
+pub mod proofs;
+pub mod provenance;
+pub mod proving;
+pub mod pull;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod queue;
+pub mod receipt;
+pub mod redact;
+pub mod regenerate;
+pub mod registry;
+pub mod reload;
+pub mod reserve;
+pub mod retry;
+pub mod sample;
+pub mod scheduler;
+pub mod schema;
+pub mod secrets;
+pub mod sequencer;
+pub mod server;
+pub mod sessions;
+pub mod shadow;
+pub mod shell;
+pub mod snapshot;
+pub mod solidity;
+pub mod store;
+pub mod submitter;
+pub mod tenant;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tokens;
+pub mod trace;
+pub mod tracedump;
+pub mod twap;
+pub mod version;
+pub mod webhook;
 
 /// Result of executing a guest image, possibly containing a proof.
+#[derive(Clone, Serialize, Deserialize)]
 pub enum Output {
     Execution {
         journal: Vec<u8>,
@@ -30,7 +131,50 @@ pub enum Output {
         journal: Vec<u8>,
         receipt_metadata: ReceiptMetadata,
         snark_proof: SnarkProof,
+        /// ID of the Bonsai proving session.
+        session_id: String,
+        receipt: Receipt,
     },
+    /// Proven without Bonsai, e.g. by a self-hosted prover cluster. There is
+    /// no SNARK, so the receipt cannot be verified by the relay contract.
+    Stark {
+        journal: Vec<u8>,
+        receipt: Receipt,
+    },
+}
+
+/// Parse a slice of strings as a fixed array of uint256 tokens.
+fn parse_to_tokens(slice: &[String]) -> Result<Token> {
+    Ok(Token::FixedArray(
+        slice
+            .iter()
+            .map(|s| -> Result<_> { Ok(U256::from_str_radix(s, 16)?.into_token()) })
+            .collect::<Result<Vec<_>, _>>()?,
+    ))
+}
+
+fn tokenize_snark_proof(proof: &SnarkProof) -> Result<Token> {
+    if proof.b.len() != 2 {
+        bail!("hex-strings encoded proof is not well formed");
+    }
+    for pair in [&proof.a, &proof.c].into_iter().chain(proof.b.iter()) {
+        if pair.len() != 2 {
+            bail!("hex-strings encoded proof is not well formed");
+        }
+    }
+    Ok(Token::FixedArray(vec![
+        parse_to_tokens(&proof.a)?,
+        Token::FixedArray(vec![
+            parse_to_tokens(&proof.b[0])?,
+            parse_to_tokens(&proof.b[1])?,
+        ]),
+        parse_to_tokens(&proof.c)?,
+    ]))
+}
+
+/// ABI encode a SNARK proof as the seal expected by the relay contract.
+pub fn snark_seal(proof: &SnarkProof) -> Result<Vec<u8>> {
+    Ok(abi::encode(&[tokenize_snark_proof(proof)?]))
 }
 
 /// Execute and prove the guest locally, on this machine, as opposed to sending
@@ -43,9 +187,10 @@ pub fn execute_locally(elf: &[u8], input: Vec<u8>) -> Result<Output> {
         .build()
         .context("Failed to build exec env")?;
     let mut exec = Executor::from_elf(env, elf).context("Failed to instantiate executor")?;
-    let session = exec
-        .run()
-        .context(format!("Failed to run executor {:?}", &input))?;
+    let session = exec.run().map_err(|err| RelayError::GuestFault {
+        what: format!("Execution on input {}", sha256_hex(&input)),
+        message: format!("{err:#}"),
+    })?;
 
     Ok(Output::Execution {
         journal: session.journal,
@@ -54,75 +199,125 @@ pub fn execute_locally(elf: &[u8], input: Vec<u8>) -> Result<Output> {
 
 pub const POLL_INTERVAL_SEC: u64 = 4;
 
-fn get_digest(elf: &[u8]) -> Result<String> {
-    let program = Program::load_elf(elf, MEM_SIZE as u32)?;
-    let image = MemoryImage::new(&program, PAGE_SIZE as u32)?;
-    Ok(hex::encode(image.compute_id()))
+/// Upper bound on the delay between status polls after repeated transient
+/// errors.
+pub const MAX_POLL_BACKOFF_SEC: u64 = 60;
+
+/// Number of consecutive transient errors tolerated while polling a session.
+pub const MAX_POLL_RETRIES: u32 = 10;
+
+pub(crate) fn poll_backoff() -> Backoff {
+    Backoff::new(
+        Duration::from_secs(POLL_INTERVAL_SEC),
+        Duration::from_secs(MAX_POLL_BACKOFF_SEC),
+        MAX_POLL_RETRIES,
+    )
 }
 
-pub fn prove_alpha(elf: &[u8], input: Vec<u8>) -> Result<Output> {
+/// Prove `input` on Bonsai through the alpha API. With a `hook`, the
+/// session it records is resumed instead of starting a new one, and a new
+/// session is recorded through it as soon as it is created. Sessions ending
+/// with a bad status for transient reasons are replaced by new ones, up to
+/// [session_retries] times, and recorded in `attempts`.
+pub fn prove_alpha(
+    elf: &[u8],
+    input: Vec<u8>,
+    hook: Option<&SessionHook>,
+    attempts: Option<&AttemptLog>,
+) -> Result<Output> {
     let client = Client::from_env().context("Failed to create client from env var")?;
 
-    let img_id = get_digest(elf).context("Failed to generate elf memory image")?;
+    let image_id = image_digest(elf).context("Failed to generate elf memory image")?;
+    let img_id = hex::encode(image_id);
 
-    match client.upload_img(&img_id, elf.to_vec()) {
-        Ok(()) => (),
-        Err(SdkErr::ImageIdExists) => (),
-        Err(err) => return Err(err.into()),
-    }
+    let mut resume = hook.and_then(SessionHook::resume);
+    let mut uploaded_input = None;
+    let mut retries = session_retries();
+    let mut backoff = poll_backoff();
+    let (session, receipt) = loop {
+        let session = match resume.take() {
+            Some(checkpoint) => {
+                elog!(
+                    "Resuming Bonsai session {} started by {}",
+                    checkpoint.session_id,
+                    checkpoint.holder
+                );
+                SessionId::new(checkpoint.session_id.clone())
+            }
+            None => {
+                let input_id = match &uploaded_input {
+                    Some(input_id) => String::clone(input_id),
+                    None => {
+                        match client.upload_img(&img_id, elf.to_vec()) {
+                            Ok(()) => (),
+                            Err(SdkErr::ImageIdExists) => (),
+                            Err(err) => return Err(err.into()),
+                        }
 
-    let input_id = client
-        .upload_input(input)
-        .context("Failed to upload input data")?;
-
-    let session = client
-        .create_session(img_id, input_id)
-        .context("Failed to create remote proving session")?;
-
-    // Poll and await the result of the STARK rollup proving session.
-    let receipt: Receipt = (|| {
-        loop {
-            let res = match session.status(&client) {
-                Ok(res) => res,
-                Err(err) => {
-                    eprint!("Failed to get session status: {err}");
-                    std::thread::sleep(Duration::from_secs(POLL_INTERVAL_SEC));
-                    continue;
+                        let input_digest = sha256_hex(&input);
+                        let input_id = client.upload_input(input.clone()).context(format!(
+                            "Failed to upload input data (sha256 {input_digest})"
+                        ))?;
+                        uploaded_input.insert(input_id).clone()
+                    }
+                };
+
+                let session = client
+                    .create_session(img_id.clone(), input_id)
+                    .context("Failed to create remote proving session")?;
+                if let Some(hook) = hook {
+                    hook.started(&session.uuid);
                 }
-            };
+                session
+            }
+        };
+
+        // Poll and await the result of the STARK rollup proving session.
+        let res = loop {
+            let res = retry_transient("Session status", &mut backoff, || session.status(&client))?;
             match res.status.as_str() {
                 "RUNNING" => {
-                    std::thread::sleep(Duration::from_secs(POLL_INTERVAL_SEC));
-                }
-                "SUCCEEDED" => {
-                    let receipt_buf = client
-                        .download(
-                            &res.receipt_url
-                                .context("Missing 'receipt_url' on status response")?,
-                        )
-                        .context("Failed to download receipt")?;
-                    let receipt: Receipt = bincode::deserialize(&receipt_buf)
-                        .context("Failed to deserialize SessionReceipt")?;
-                    // eprintln!("Completed STARK proof on bonsai alpha backend!");
-                    return Ok(receipt);
-                }
-                _ => {
-                    bail!(
-                        "STARK proving session exited with bad status: {}",
-                        res.status
-                    );
+                    clock::sleep(Duration::from_secs(POLL_INTERVAL_SEC));
                 }
+                _ => break res,
             }
+        };
+        if res.status == "SUCCEEDED" {
+            let receipt_url = res
+                .receipt_url
+                .context("Missing 'receipt_url' on status response")?;
+            let receipt_file = download_to_file(&receipt_url, log_progress)
+                .context("Failed to download receipt")?;
+            let receipt: Receipt = receipt_file.load(image_id)?;
+            break (session, receipt);
         }
-    })()?;
+        // The alpha API reports no error message, so only the status tells
+        // a transient failure apart.
+        let attempt = SessionAttempt {
+            session_id: session.uuid,
+            status: res.status,
+            error: None,
+        };
+        if retries == 0 || !attempt.is_transient() {
+            return Err(attempt.into_error("STARK proving session").into());
+        }
+        retries -= 1;
+        elog!("{attempt}, retrying with a new session ({retries} retries left)");
+        if let Some(attempts) = attempts {
+            attempts.record(attempt);
+        }
+    };
     let metadata = receipt.get_metadata()?;
+    let session_id = session.uuid.clone();
 
     let snark_session = client.create_snark(session.uuid)?;
     let snark_proof: SnarkProof = (|| loop {
-        let res = snark_session.status(&client)?;
+        let res = retry_transient("SNARK status", &mut backoff, || {
+            snark_session.status(&client)
+        })?;
         match res.status.as_str() {
             "RUNNING" => {
-                std::thread::sleep(Duration::from_secs(POLL_INTERVAL_SEC));
+                clock::sleep(Duration::from_secs(POLL_INTERVAL_SEC));
             }
             "SUCCEEDED" => {
                 // eprintln!("Completed SNARK proof on bonsai alpha backend!");
@@ -130,65 +325,84 @@ pub fn prove_alpha(elf: &[u8], input: Vec<u8>) -> Result<Output> {
                     .output
                     .ok_or(anyhow!("output expected to be non-empty on success"));
             }
-            _ => {
-                bail!(
-                    "SNARK proving session exited with bad status: {}",
-                    res.status
-                );
+            status => {
+                return Err(RelayError::ProverFault {
+                    what: "SNARK proving session".to_string(),
+                    message: format!("exited with bad status: {status}"),
+                }
+                .into());
             }
         }
     })()?;
 
     Ok(Output::Bonsai {
-        journal: receipt.journal,
+        journal: receipt.journal.clone(),
         receipt_metadata: metadata,
         snark_proof,
+        session_id,
+        receipt,
     })
 }
 
-pub fn resolve_guest_entry<'a>(
-    guest_list: &[GuestListEntry<'a>],
-    guest_binary: &String,
-) -> Result<GuestListEntry<'a>> {
-    // Search list for requested binary name
-    let potential_guest_image_id: [u8; 32] =
-        match hex::decode(guest_binary.to_lowercase().trim_start_matches("0x")) {
-            Ok(byte_vector) => byte_vector.try_into().unwrap_or([0u8; 32]),
-            Err(_) => [0u8; 32],
-        };
-    guest_list
-        .iter()
-        .find(|entry| {
-            entry.name == guest_binary.to_uppercase()
-                || bytemuck::cast::<[u32; 8], [u8; 32]>(entry.image_id) == potential_guest_image_id
-        })
-        .ok_or_else(|| {
-            let found_guests: Vec<String> = guest_list
-                .iter()
-                .map(|g| hex::encode(bytemuck::cast::<[u32; 8], [u8; 32]>(g.image_id)))
-                .collect();
-            anyhow!(
-                "Unknown guest binary {}, found: {:?}",
-                guest_binary,
-                found_guests
-            )
+/// Decode hex encoded public and optional private input into the canonical
+/// input for the guest, salting the private input at random.
+pub fn prepare_input(guest: &Guest, input: &str, private_input: Option<&str>) -> Result<Vec<u8>> {
+    prepare_salted_input(guest, input, private_input, None)
+}
+
+/// [prepare_input] salting the private input with the hex encoded
+/// `private_salt` if given, see [input::private_section].
+pub fn prepare_salted_input(
+    guest: &Guest,
+    input: &str,
+    private_input: Option<&str>,
+    private_salt: Option<&str>,
+) -> Result<Vec<u8>> {
+    let input = hex::decode(input.trim_start_matches("0x")).context("Failed to decode input")?;
+    let salt = private_salt
+        .map(|salt| {
+            let salt = hex::decode(salt.trim_start_matches("0x"))
+                .context("Failed to decode private input salt")?;
+            <[u8; PRIVATE_SALT_LEN]>::try_from(salt)
+                .map_err(|_| anyhow!("private input salt must be {PRIVATE_SALT_LEN} bytes"))
         })
-        .cloned()
+        .transpose()?;
+    let input = match private_input {
+        Some(private) => {
+            let private = hex::decode(private.trim_start_matches("0x"))
+                .context("Failed to decode private input")?;
+            frame_input(&input, &private_section(&private, salt))?
+        }
+        None if salt.is_some() => bail!("a private input salt needs a private input"),
+        None => input,
+    };
+    canonicalize(&guest.name, &input)
 }
 
 pub async fn resolve_image_output(
     input: &str,
-    guest_entry: &GuestListEntry<'static>,
+    private_input: Option<&str>,
+    guest: &Arc<Guest>,
+    pool: &Arc<ImagePool>,
+    bonsai: &BonsaiBackend,
     dev_mode: bool,
 ) -> Result<Output> {
-    let input = hex::decode(input.trim_start_matches("0x")).context("Failed to decode input")?;
-    let elf = guest_entry.elf;
+    let input = prepare_input(guest, input, private_input)?;
+    run_guest(guest, input, pool, bonsai, dev_mode).await
+}
 
+/// Execute the guest with the given input on `pool` in dev mode, or prove it
+/// on Bonsai through `bonsai` otherwise.
+pub async fn run_guest(
+    guest: &Arc<Guest>,
+    input: Vec<u8>,
+    pool: &Arc<ImagePool>,
+    bonsai: &BonsaiBackend,
+    dev_mode: bool,
+) -> Result<Output> {
     if dev_mode {
-        execute_locally(elf, input)
+        pool.execute_blocking(guest, input).await
     } else {
-        tokio::task::spawn_blocking(move || prove_alpha(elf, input))
-            .await
-            .context("Failed to run alpha sub-task")?
+        bonsai.prove(guest.clone(), input).await
     }
 }
@@ -0,0 +1,512 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, ParamType, RawLog},
+    contract::{EthLogDecode, LogMeta},
+    providers::{Middleware, StreamExt},
+    types::{Address, Log, H256},
+    utils::keccak256,
+};
+
+use crate::{
+    access::RequesterPolicy,
+    approval::Approvals,
+    artifacts::Artifacts,
+    backend::BonsaiBackend,
+    bindings::{BonsaiRelay, CallbackRequestFilter},
+    canary::Canary,
+    chain::ChainKind,
+    checksum::sha256_hex,
+    clock,
+    dedup::Deduplicator,
+    elog,
+    escrow::{request_id, Escrow},
+    eth::EthClient,
+    evidence::{log_meta, Evidence, EvidenceRecord},
+    format::RequestFormats,
+    gas::GasStrategy,
+    guardian::Guardian,
+    index::ReceiptIndex,
+    input::request_key,
+    pool::ImagePool,
+    postprocess::PostProcessChain,
+    registry::{Guest, GuestRegistry},
+    run_guest,
+    submitter::Submitter,
+    trace, Output,
+};
+
+/// Event requesters emit right before requesting a callback to have the
+/// request traced by their own ID, see `BonsaiCallbackReceiver`.
+const CALLBACK_TRACE_ID_EVENT: &str = "CallbackTraceId(string)";
+
+/// Serves callback requests emitted by the relay contract: runs the requested
+/// guest and submits its result back.
+pub struct Listener {
+    relay: BonsaiRelay<EthClient>,
+    submitter: Submitter,
+    escrow: Option<Escrow>,
+    registry: Arc<GuestRegistry>,
+    pool: Arc<ImagePool>,
+    bonsai: BonsaiBackend,
+    dev_mode: bool,
+    verify_locally: bool,
+    formats: RequestFormats,
+    requesters: Option<Arc<RequesterPolicy>>,
+    approvals: Option<Arc<Approvals>>,
+    artifacts: Option<Arc<Artifacts>>,
+    canary: Option<Arc<Canary>>,
+    guardian: Option<Arc<Guardian<EthClient>>>,
+    index: Option<(Arc<ReceiptIndex>, Duration)>,
+    evidence: Option<Arc<Evidence>>,
+    post_process: HashMap<String, PostProcessChain>,
+    dedup: Deduplicator,
+}
+
+impl Listener {
+    pub fn new(
+        relay_address: Address,
+        client: Arc<EthClient>,
+        registry: Arc<GuestRegistry>,
+        pool: Arc<ImagePool>,
+        dev_mode: bool,
+    ) -> Self {
+        Self {
+            relay: BonsaiRelay::new(relay_address, client.clone()),
+            submitter: Submitter::new(relay_address, client),
+            escrow: None,
+            registry,
+            pool,
+            bonsai: BonsaiBackend::default(),
+            dev_mode,
+            verify_locally: false,
+            formats: RequestFormats::default(),
+            requesters: None,
+            approvals: None,
+            artifacts: None,
+            canary: None,
+            guardian: None,
+            index: None,
+            evidence: None,
+            post_process: HashMap::new(),
+            dedup: Deduplicator::new(Duration::ZERO),
+        }
+    }
+
+    /// Submit callbacks within the gas limit of `strategy`, pricing blob
+    /// posting if it asks to. Changes to it apply to the next submission.
+    pub fn with_gas_strategy(mut self, strategy: Arc<RwLock<GasStrategy>>) -> Self {
+        self.submitter = self.submitter.with_gas_strategy(strategy);
+        self
+    }
+
+    /// Prove on Bonsai through `bonsai` rather than the alpha API.
+    pub fn with_bonsai(mut self, bonsai: BonsaiBackend) -> Self {
+        self.bonsai = bonsai;
+        self
+    }
+
+    /// Account for the chain's own fee components when estimating callback
+    /// costs.
+    pub fn with_chain(mut self, chain: ChainKind) -> Self {
+        self.submitter = self.submitter.with_chain(chain);
+        self
+    }
+
+    /// Refuse to submit results observed more than `max_age` ago.
+    pub fn with_max_staleness(mut self, max_age: Duration) -> Self {
+        self.submitter = self.submitter.with_max_staleness(max_age);
+        self
+    }
+
+    /// Tolerate `skew` between the local clock and the chain's.
+    pub fn with_max_clock_skew(mut self, skew: Duration) -> Self {
+        self.submitter = self.submitter.with_max_clock_skew(skew);
+        self
+    }
+
+    /// Accept request inputs in the given formats instead of the defaults.
+    pub fn with_formats(mut self, formats: RequestFormats) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    /// Only serve requesters admitted by the policy.
+    pub fn with_requester_policy(mut self, policy: Arc<RequesterPolicy>) -> Self {
+        self.requesters = Some(policy);
+        self
+    }
+
+    /// Hold back callbacks matching the approval rules until enough operators
+    /// approve them.
+    pub fn with_approvals(mut self, approvals: Arc<Approvals>) -> Self {
+        self.approvals = Some(approvals);
+        self
+    }
+
+    /// Write the artifacts of every guest run.
+    pub fn with_artifacts(mut self, artifacts: Arc<Artifacts>) -> Self {
+        self.artifacts = Some(artifacts);
+        self
+    }
+
+    /// Run requests for the canary's stable image on its candidate as well.
+    pub fn with_canary(mut self, canary: Arc<Canary>) -> Self {
+        self.canary = Some(canary);
+        self
+    }
+
+    /// Keep the evidence of every request, for export with
+    /// `export-evidence` should its answer be disputed.
+    pub fn with_evidence(mut self, evidence: Arc<Evidence>) -> Self {
+        self.evidence = Some(evidence);
+        self
+    }
+
+    /// Answer requests for an input indexed within the last `max_age`, such
+    /// as one a scheduled job has just proven, with the indexed result
+    /// instead of proving it again.
+    pub fn with_receipt_index(mut self, index: Arc<ReceiptIndex>, max_age: Duration) -> Self {
+        self.index = Some((index, max_age));
+        self
+    }
+
+    /// Hold submissions while `guardian` is paused. Requests are still
+    /// proven meanwhile.
+    pub fn with_guardian(mut self, guardian: Arc<Guardian<EthClient>>) -> Self {
+        self.guardian = Some(guardian);
+        self
+    }
+
+    /// Post-process the journals of the callbacks of each named guest with
+    /// its chain. Only unsealed, dev mode callbacks can be post-processed,
+    /// see [Submitter::callback].
+    pub fn with_post_process(mut self, chains: HashMap<String, PostProcessChain>) -> Self {
+        self.post_process = chains;
+        self
+    }
+
+    /// Prove identical requests (same guest and canonical input) arriving
+    /// within `window` once, answering each with the shared result.
+    pub fn with_dedup_window(mut self, window: Duration) -> Self {
+        self.dedup = Deduplicator::new(window);
+        self
+    }
+
+    /// Only serve requests whose fee is escrowed, claiming it once served.
+    pub fn with_escrow(mut self, escrow: Escrow) -> Self {
+        self.escrow = Some(escrow);
+        self
+    }
+
+    /// Re-execute every Bonsai result locally before submitting it, refusing
+    /// to submit journals that differ. Meant for operators whose stake is
+    /// slashed for wrong results, so a compromised Bonsai channel cannot cost
+    /// them their collateral.
+    pub fn with_local_verification(mut self) -> Self {
+        self.verify_locally = true;
+        self
+    }
+
+    /// Handle callback requests until the subscription ends.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        // Raw logs are kept as evidence, so they are decoded here rather
+        // than by the event stream.
+        let events = self.relay.event::<CallbackRequestFilter>();
+        let client = self.relay.client();
+        let mut stream = client
+            .subscribe_logs(&events.filter)
+            .await
+            .context("Failed to subscribe to callback requests")?;
+        elog!("Listening for callback requests");
+        while let Some(log) = stream.next().await {
+            let request = match CallbackRequestFilter::decode_log(&RawLog::from(log.clone())) {
+                Ok(request) => request,
+                Err(err) => {
+                    elog!("Failed to decode callback request: {err}");
+                    continue;
+                }
+            };
+            let Some(meta) = log_meta(&log) else {
+                elog!("Ignoring callback request of a pending log");
+                continue;
+            };
+            let listener = self.clone();
+            tokio::spawn(async move {
+                let id = listener.trace_id(&request, &meta).await;
+                trace::scope(id, async move {
+                    match listener.handle(request, log).await {
+                        Ok(()) => elog!("Fulfilled callback request"),
+                        Err(err) => elog!("Failed to fulfill callback request: {err:#}"),
+                    }
+                })
+                .await
+            });
+        }
+        bail!("callback request subscription ended")
+    }
+
+    /// Trace ID of a request: the one its requester emitted with
+    /// `CallbackTraceId` right before requesting the callback, or else the
+    /// callback request ID, which integrators can compute from the event.
+    async fn trace_id(&self, request: &CallbackRequestFilter, log: &LogMeta) -> String {
+        let fallback = || hex::encode(request_id(request));
+        let receipt = match self
+            .relay
+            .client()
+            .get_transaction_receipt(log.transaction_hash)
+            .await
+        {
+            Ok(Some(receipt)) => receipt,
+            Ok(None) => return fallback(),
+            Err(err) => {
+                elog!(
+                    "Failed to read the trace ID of the request in {:?}: {err}",
+                    log.transaction_hash
+                );
+                return fallback();
+            }
+        };
+        // The requester's last log before the request, if it names a trace.
+        let traced = receipt
+            .logs
+            .iter()
+            .filter(|entry| {
+                entry.address == request.account
+                    && matches!(entry.log_index, Some(index) if index < log.log_index)
+            })
+            .last()
+            .filter(|entry| entry.topics.first() == Some(&H256(keccak256(CALLBACK_TRACE_ID_EVENT))))
+            .and_then(|entry| {
+                let tokens = abi::decode(&[ParamType::String], &entry.data).ok()?;
+                trace::validate(&tokens.into_iter().next()?.into_string()?).ok()
+            });
+        traced.unwrap_or_else(fallback)
+    }
+
+    async fn handle(&self, request: CallbackRequestFilter, log: Log) -> Result<()> {
+        let mut record = EvidenceRecord::new(&request, Some(log));
+        let result = self.serve(request, &mut record).await;
+        if let Some(evidence) = &self.evidence {
+            record.error = result.as_ref().err().map(|err| format!("{err:#}"));
+            record.recorded_at = clock::unix_now();
+            if let Err(err) = evidence.record(&record) {
+                elog!("Failed to record the evidence of the request: {err:?}");
+            }
+        }
+        result
+    }
+
+    /// Answer a request, recording the checks it goes through in `record`.
+    async fn serve(
+        &self,
+        request: CallbackRequestFilter,
+        record: &mut EvidenceRecord,
+    ) -> Result<()> {
+        let checks = &mut record.transcript;
+        if let Some(requesters) = &self.requesters {
+            let admitted = requesters.admit(request.account).map_err(Into::into);
+            checks.check("requester", admitted)?;
+        }
+        let guest = checks.check(
+            "guest",
+            self.registry.resolve(&hex::encode(request.image_id)),
+        )?;
+        record.guest = Some(guest.name.clone());
+        let checks = &mut record.transcript;
+        let reservation = match &self.escrow {
+            Some(escrow) => Some(checks.check("fee", escrow.reserve(request_id(&request)).await)?),
+            None => None,
+        };
+
+        let input = checks.check("input", self.formats.decode(&guest.name, &request.input))?;
+        let run = async {
+            if let Some(output) = self.coalesced(&guest, &input) {
+                return Ok(output);
+            }
+            let work = {
+                let (guest, input) = (guest.clone(), input.clone());
+                let (pool, bonsai, dev_mode) =
+                    (self.pool.clone(), self.bonsai.clone(), self.dev_mode);
+                let artifacts = self.artifacts.clone();
+                let canary = self.canary.clone().filter(|canary| canary.covers(&guest));
+                async move {
+                    if let Some(canary) = canary {
+                        return canary.run(input, &pool, &bonsai, dev_mode).await;
+                    }
+                    match artifacts {
+                        Some(artifacts) => {
+                            artifacts
+                                .run_guest(&guest, input, &pool, &bonsai, dev_mode)
+                                .await
+                        }
+                        None => run_guest(&guest, input, &pool, &bonsai, dev_mode).await,
+                    }
+                }
+            };
+            let key = request_key(guest.image_id, &input);
+            match self.dedup.run(key, work).await {
+                (Ok(output), shared) => {
+                    if shared {
+                        elog!("Answering from an identical request proven meanwhile");
+                    }
+                    Ok(Arc::try_unwrap(output).unwrap_or_else(|output| (*output).clone()))
+                }
+                (Err(err), _) => Err(anyhow!("{err:#}")),
+            }
+        };
+        let output = match &self.evidence {
+            Some(evidence) => match evidence.artifacts(&record.request_id) {
+                Ok(artifacts) => artifacts.capture(&guest, &input, run).await,
+                Err(err) => {
+                    elog!("Failed to keep the artifacts of the request: {err:?}");
+                    run.await
+                }
+            },
+            None => run.await,
+        };
+        let checks = &mut record.transcript;
+        let output = checks.check("proof", output)?;
+        let journal = match &output {
+            Output::Execution { journal }
+            | Output::Bonsai { journal, .. }
+            | Output::Stark { journal, .. } => journal,
+        };
+        if let (true, Output::Bonsai { .. }) = (self.verify_locally, &output) {
+            checks.check(
+                "local verification",
+                self.verify_journal(&guest, &input, journal).await,
+            )?;
+        }
+        checks.check(
+            "journal version",
+            self.submitter.check_version(&guest.name, journal),
+        )?;
+        checks.check(
+            "freshness",
+            self.submitter.check_fresh(&guest.name, journal).await,
+        )?;
+        let post_process = self
+            .post_process
+            .get(&guest.name)
+            .map(|chain| (guest.name.as_str(), chain));
+        let callback = checks.check(
+            "callback",
+            Submitter::callback(&request, &output, post_process),
+        )?;
+        if let Some(approvals) = &self.approvals {
+            if let Some(reason) = approvals.requirement(&guest.name, journal)? {
+                checks.check(
+                    "approval",
+                    approvals
+                        .wait(request_id(&request), &guest.name, reason, &callback)
+                        .await,
+                )?;
+                // Approval may take up to its timeout, by which the result
+                // can have gone stale.
+                checks.check(
+                    "freshness after approval",
+                    self.submitter.check_fresh(&guest.name, journal).await,
+                )?;
+            }
+        }
+        if let Some(guardian) = &self.guardian {
+            if guardian.wait_unpaused().await {
+                checks.check(
+                    "freshness after pause",
+                    self.submitter.check_fresh(&guest.name, journal).await,
+                )?;
+            }
+        }
+        if checks.check("posting", self.submitter.hold_for_calldata(&callback).await)? {
+            checks.check(
+                "freshness after hold",
+                self.submitter.check_fresh(&guest.name, journal).await,
+            )?;
+        }
+        let submission = checks.check("submission", self.submitter.submit(callback).await)?;
+        record.submission = Some(submission.transaction_hash);
+        if let Some(approvals) = &self.approvals {
+            approvals.record_submitted(&guest.name, journal)?;
+        }
+
+        if let Some(reservation) = reservation {
+            record
+                .transcript
+                .check("fee claim", reservation.claim().await)?;
+        }
+        Ok(())
+    }
+
+    /// Indexed result for `input`, if it is recent and of the kind this
+    /// listener would produce itself. Failed lookups fall back to proving.
+    fn coalesced(&self, guest: &Guest, input: &[u8]) -> Option<Output> {
+        let (index, max_age) = self.index.as_ref()?;
+        let found =
+            ReceiptIndex::keys(guest, input).and_then(|keys| index.recent(&keys.key, *max_age));
+        let (entry, output) = match found {
+            Ok(found) => found?,
+            Err(err) => {
+                elog!("Failed to look up the receipt index, proving instead: {err:?}");
+                return None;
+            }
+        };
+        if !matches!(
+            (self.dev_mode, &output),
+            (true, Output::Execution { .. }) | (false, Output::Bonsai { .. })
+        ) {
+            return None;
+        }
+        match (&entry.job, entry.run) {
+            (Some(job), Some(run)) => {
+                elog!("Answering from the result of scheduled job {job} run {run}")
+            }
+            _ => elog!("Answering from indexed result {}", entry.key),
+        }
+        Some(output)
+    }
+
+    async fn verify_journal(
+        &self,
+        guest: &Arc<Guest>,
+        input: &[u8],
+        bonsai_journal: &[u8],
+    ) -> Result<()> {
+        let local_journal = match self.pool.execute_blocking(guest, input.to_vec()).await? {
+            Output::Execution { journal }
+            | Output::Bonsai { journal, .. }
+            | Output::Stark { journal, .. } => journal,
+        };
+        if local_journal != bonsai_journal {
+            elog!(
+                "ALERT: refusing to submit Bonsai journal for guest {} on input {}: \
+                 expected {}, Bonsai returned {}",
+                guest.name,
+                sha256_hex(input),
+                hex::encode(&local_journal),
+                hex::encode(bonsai_journal)
+            );
+            bail!("Bonsai journal differs from local execution");
+        }
+        Ok(())
+    }
+}
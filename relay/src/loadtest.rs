@@ -0,0 +1,304 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `relay loadtest`: open-loop load against a relay API server, to validate
+//! its concurrency and rate-limit settings before production cutover.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use reqwest::{header::CONTENT_TYPE, Client, RequestBuilder};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time::MissedTickBehavior;
+
+use crate::{
+    elog,
+    server::API_KEY_HEADER,
+    sessions::{SessionStatus, MAX_WAIT},
+};
+
+/// Relay API a load test exercises.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Endpoint {
+    /// `/v1/simulate`: execution only.
+    Simulate,
+    /// `/v1/prove`: held open until proven.
+    Prove,
+    /// `/v1/sessions`, then waiting on the session until it is proven.
+    Session,
+}
+
+/// What to send, how fast and for how long.
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    /// Base URL of the relay API, e.g. `http://127.0.0.1:8090`.
+    pub url: String,
+    pub api_key: Option<String>,
+    pub guest: String,
+    /// Hex encoded inputs, sent in turn. The relay deduplicates identical
+    /// requests, so varied inputs measure proving rather than the cache.
+    pub inputs: Vec<String>,
+    pub endpoint: Endpoint,
+    /// Requests started per second, whether or not earlier ones finished.
+    pub rate: f64,
+    pub duration: Duration,
+    /// Requests in flight beyond which due requests are skipped, so an
+    /// overloaded relay shows up as skips rather than unbounded memory.
+    pub max_in_flight: usize,
+    /// Per-request timeout. Sessions are waited on in requests of up to
+    /// [MAX_WAIT], so it should exceed that for [Endpoint::Session].
+    pub timeout: Duration,
+}
+
+/// Outcome of a load test.
+#[derive(Debug, Clone, Default)]
+pub struct LoadReport {
+    pub elapsed: Duration,
+    pub sent: u64,
+    pub succeeded: u64,
+    /// Failed requests by HTTP status, or `timeout` and `error` for requests
+    /// that got no response.
+    pub failed: BTreeMap<String, u64>,
+    /// Requests that fell due while `max_in_flight` were outstanding.
+    pub skipped: u64,
+    /// Latencies of successful requests, sorted.
+    pub latencies: Vec<Duration>,
+    pub max_in_flight: usize,
+    /// Requests in flight, averaged over the moments requests fell due.
+    pub mean_in_flight: f64,
+}
+
+impl LoadReport {
+    /// Latency at or below which `pct` percent of successful requests
+    /// completed, by nearest rank.
+    pub fn percentile(&self, pct: f64) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let rank = ((pct / 100.0) * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+
+    /// Successful requests per second.
+    pub fn throughput(&self) -> f64 {
+        self.succeeded as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} sent, {} succeeded, {} skipped in {:.1}s ({:.2} succeeded/s)",
+            self.sent,
+            self.succeeded,
+            self.skipped,
+            self.elapsed.as_secs_f64(),
+            self.throughput()
+        )?;
+        for (status, count) in &self.failed {
+            writeln!(f, "failed with {status}: {count}")?;
+        }
+        let ms = |pct| {
+            self.percentile(pct)
+                .map_or_else(|| "-".to_string(), |d| format!("{}ms", d.as_millis()))
+        };
+        writeln!(
+            f,
+            "latency p50 {} p95 {} p99 {} max {}",
+            ms(50.0),
+            ms(95.0),
+            ms(99.0),
+            ms(100.0)
+        )?;
+        write!(
+            f,
+            "in flight: mean {:.1}, max {}",
+            self.mean_in_flight, self.max_in_flight
+        )
+    }
+}
+
+/// Read hex encoded inputs, one per line, skipping blank lines.
+pub fn load_inputs(path: &Path) -> Result<Vec<String>> {
+    let inputs: Vec<String> = std::fs::read_to_string(path)
+        .context(format!("Failed to read inputs file {}", path.display()))?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect();
+    if inputs.is_empty() {
+        bail!("inputs file {} holds no inputs", path.display());
+    }
+    Ok(inputs)
+}
+
+#[derive(Deserialize)]
+struct SessionCreated {
+    session_id: String,
+}
+
+enum Outcome {
+    Succeeded(Duration),
+    Failed(String),
+}
+
+impl LoadTest {
+    pub async fn run(&self) -> Result<LoadReport> {
+        if self.inputs.is_empty() {
+            bail!("a load test needs at least one input");
+        }
+        if !self.rate.is_finite() || self.rate <= 0.0 {
+            bail!("request rate must be positive");
+        }
+        let client = Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .context("Failed to build load test client")?;
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let mut report = LoadReport::default();
+        let mut in_flight_sum = 0usize;
+        let mut requests = Vec::new();
+        let mut ticks = tokio::time::interval(Duration::from_secs_f64(1.0 / self.rate));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Burst);
+        let start = Instant::now();
+        elog!(
+            "Sending {:.1} requests/s to {} for {}s",
+            self.rate,
+            self.url,
+            self.duration.as_secs()
+        );
+        while start.elapsed() < self.duration {
+            ticks.tick().await;
+            let current = in_flight.load(Ordering::SeqCst);
+            in_flight_sum += current;
+            report.max_in_flight = report.max_in_flight.max(current);
+            if current >= self.max_in_flight {
+                report.skipped += 1;
+                continue;
+            }
+            let input = self.inputs[report.sent as usize % self.inputs.len()].clone();
+            report.sent += 1;
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            let (test, client, in_flight) = (self.clone(), client.clone(), in_flight.clone());
+            requests.push(tokio::spawn(async move {
+                let outcome = test.send(&client, input).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                outcome
+            }));
+        }
+        let due = report.sent + report.skipped;
+        report.mean_in_flight = in_flight_sum as f64 / due.max(1) as f64;
+        for request in requests {
+            match request.await.context("Load test request panicked")? {
+                Outcome::Succeeded(latency) => {
+                    report.succeeded += 1;
+                    report.latencies.push(latency);
+                }
+                Outcome::Failed(status) => *report.failed.entry(status).or_default() += 1,
+            }
+        }
+        report.elapsed = start.elapsed();
+        report.latencies.sort();
+        Ok(report)
+    }
+
+    async fn send(&self, client: &Client, input: String) -> Outcome {
+        let start = Instant::now();
+        let body = json!({ "guest": self.guest, "input": input });
+        let result = match self.endpoint {
+            Endpoint::Simulate => self.post(client, "/v1/simulate", &body).await.map(drop),
+            Endpoint::Prove => self.post(client, "/v1/prove", &body).await.map(drop),
+            Endpoint::Session => self.prove_session(client, &body).await,
+        };
+        match result {
+            Ok(()) => Outcome::Succeeded(start.elapsed()),
+            Err(status) => Outcome::Failed(status),
+        }
+    }
+
+    async fn prove_session(&self, client: &Client, body: &serde_json::Value) -> Result<(), String> {
+        let created = self.post(client, "/v1/sessions", body).await?;
+        let created: SessionCreated =
+            serde_json::from_slice(&created).map_err(|_| "bad response".to_string())?;
+        let wait = format!(
+            "{}/v1/sessions/{}/wait?timeout={}",
+            self.url,
+            created.session_id,
+            MAX_WAIT.as_secs()
+        );
+        loop {
+            let status = self.send_request(self.authorize(client.get(&wait))).await?;
+            let status: SessionStatus<serde_json::Value> =
+                serde_json::from_slice(&status).map_err(|_| "bad response".to_string())?;
+            match status {
+                SessionStatus::Running => continue,
+                SessionStatus::Succeeded { .. } => return Ok(()),
+                SessionStatus::Failed { .. } => return Err("session failed".to_string()),
+            }
+        }
+    }
+
+    async fn post(
+        &self,
+        client: &Client,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> Result<Vec<u8>, String> {
+        let request = client
+            .post(format!("{}{path}", self.url))
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        self.send_request(self.authorize(request)).await
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.api_key {
+            Some(key) => request.header(API_KEY_HEADER, key),
+            None => request,
+        }
+    }
+
+    /// Send a request, returning its body or how it failed.
+    async fn send_request(&self, request: RequestBuilder) -> Result<Vec<u8>, String> {
+        let response = request.send().await.map_err(|err| {
+            if err.is_timeout() {
+                "timeout".to_string()
+            } else {
+                "error".to_string()
+            }
+        })?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(status.as_u16().to_string());
+        }
+        response
+            .bytes()
+            .await
+            .map(|body| body.to_vec())
+            .map_err(|_| "error".to_string())
+    }
+}
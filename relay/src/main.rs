@@ -12,21 +12,101 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::io::Write;
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc, RwLock},
+    time::Duration,
+};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use axum::Router;
 use bonsai_ethereum_relay::{EthersClientConfig, Relayer};
-use bonsai_ethereum_relay_cli::{resolve_guest_entry, resolve_image_output, Output};
+use bonsai_ethereum_relay_cli::{
+    access::RequesterPolicy,
+    alert::Alerts,
+    approval::Approvals,
+    artifacts::Artifacts,
+    backend::{BonsaiBackend, Dispatcher, LocalBackend, ProverBackend, ProverKind},
+    bonsai_api::{self, ApiRevision},
+    canary::{Canary, CanarySpec},
+    capabilities::Capabilities,
+    cases::{dump_case, load_cases, run_case},
+    catalog::{CatalogConfig, PoolCatalog, Transmission},
+    chain::ChainKind,
+    checksum::verify_image_id,
+    cluster::{serve_worker, ClusterBackend, ClusterSecret},
+    cycle::CycleFetcher,
+    deadletter::DeadLetters,
+    dedup::Deduplicator,
+    discovery::Discovery,
+    doctor::{diagnose, DoctorConfig, Status},
+    elog, envconfig,
+    escrow::Escrow,
+    eth::connect,
+    evidence::Evidence,
+    finality::FinalityPolicy,
+    gas::{estimate_output, GasStrategy, DEFAULT_MAX_HOLD_SECS},
+    guardian::Guardian,
+    history::{HistoryFetcher, HistoryState},
+    index::ReceiptIndex,
+    lease::{shutdown_signal, Leases},
+    listener::Listener,
+    loadtest::{load_inputs, Endpoint, LoadTest},
+    metrics::Metrics,
+    pool::{ExecLimits, ImagePool},
+    postprocess::load_callback_chains,
+    prepare_salted_input,
+    proofs::{proof_source, ProofCache},
+    proving::ProvingMode,
+    pull::PriceUpdates,
+    queue::Queues,
+    receipt::{self, ReceiptEnvelope},
+    redact::{self, register_secret},
+    regenerate::{compare_journals, submitted_journal, twap_input, TwapJournal},
+    registry::{export_dir, sign_dir, Guest, GuestRegistry},
+    reload::Reloader,
+    reserve::{ReserveFetcher, VaultPosition},
+    retry::{set_session_retries, DEFAULT_SESSION_RETRIES},
+    run_guest,
+    sample::plan_sample,
+    scheduler::{RunLog, Scheduler},
+    schema::public_values,
+    secrets,
+    server::{
+        approval_router, guardian_router, key_only, reload_router, router, serve_router,
+        serve_router_until, updates_router, AppState,
+    },
+    sessions::{Sessions, MAX_SESSIONS_PER_TENANT, SESSION_RETENTION},
+    shadow::ShadowVerifier,
+    shell::Shell,
+    snapshot::{diff, PoolSnapshot},
+    snark_seal,
+    solidity::journal_library,
+    store::{Cipher, Store},
+    tenant::Tenants,
+    tokens::TokenResolver,
+    trace,
+    tracedump::{write_dump, ExecutionOutcome, TraceDump, TraceTail, DEFAULT_TRACE_TAIL},
+    twap::TwapFetcher,
+    version::VersionPolicy,
+    webhook::Webhooks,
+    Output,
+};
 use bonsai_sdk::{
-    alpha::{responses::SnarkProof, SdkErr},
+    alpha::SdkErr,
     alpha_async::{get_client_from_parts, put_image},
 };
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, CommandFactory, Parser, Subcommand};
 use ethers::{
     abi::{Hash, Token, Tokenizable},
-    types::{Address, U256},
+    providers::{Middleware, Provider, Ws},
+    signers::{LocalWallet, Signer},
+    types::{Address, H256, U256},
 };
-use methods::GUEST_LIST;
+use methods::{GUEST_LIST, PROVENANCE};
 use risc0_zkvm::sha::Digest;
 
 /// Index 0 private key generated by default in Anvil.
@@ -42,6 +122,33 @@ enum Command {
 
         /// The input to provide to the guest binary
         input: Option<String>,
+
+        /// Hex encoded private input, passed to the guest alongside the
+        /// public input. The guest commits only its salted digest.
+        #[arg(long, requires = "input")]
+        private_input: Option<String>,
+
+        /// Hex encoded 32 byte salt of the private input's digest, needed
+        /// to open the commitment later. Drawn at random if not given.
+        #[arg(long, requires = "private_input")]
+        private_salt: Option<String>,
+
+        /// Execute the guest locally first and print what it writes to
+        /// stdout and stderr, which Bonsai does not return, and its cycle
+        /// counts per segment.
+        #[arg(long, requires = "input")]
+        guest_logs: bool,
+
+        /// Execute the guest locally first, and if it fails, dump the
+        /// execution to this directory for `replay-trace`. The dump holds
+        /// the private input, if any.
+        #[arg(long, requires = "input")]
+        dump_trace: Option<PathBuf>,
+
+        /// Write the receipt of the proof to this file, as `verify` reads
+        /// it. Dev mode executions have no receipt.
+        #[arg(long, requires = "input")]
+        receipt_out: Option<PathBuf>,
     },
     /// Upload the RISC-V ELF binary to Bonsai.
     Upload {
@@ -49,6 +156,589 @@ enum Command {
         /// If not provided, all defined guests will be uploaded.
         guest_binary: Option<String>,
     },
+    /// Verify a serialized receipt of any supported format against a guest.
+    Verify {
+        /// The name or image ID of the guest binary
+        guest_binary: String,
+
+        /// Path to the bincode serialized receipt
+        receipt: PathBuf,
+    },
+    /// Print, as JSON, what the relay can do as configured: its prover
+    /// backends, GPU and SNARK support, chains, guests and their schema
+    /// versions, as served at /v1/capabilities.
+    Capabilities {
+        /// Prover backends, as given to `serve`.
+        #[arg(long, env, value_enum, value_delimiter = ',')]
+        provers: Vec<ProverKind>,
+
+        /// Prover workers, as given to `serve`.
+        #[arg(long, env, value_delimiter = ',')]
+        prover_cluster: Vec<SocketAddr>,
+
+        /// Pool catalog file, as given to `serve`, listing the chains read.
+        #[arg(long, env)]
+        pools: Option<PathBuf>,
+    },
+    /// Write the guests embedded in this relay to a guest directory, with
+    /// their build provenance in its manifest.
+    ExportGuests {
+        /// Directory to write the guest ELFs to
+        dir: PathBuf,
+    },
+    /// Sign the guest ELFs in a directory with an operator key, recording
+    /// the signatures in its manifest, for relays run with
+    /// `--guest-signers`.
+    SignGuests {
+        /// Directory of guest ELFs
+        dir: PathBuf,
+
+        /// Operator private key to sign with
+        #[arg(long, env = "GUEST_SIGNING_KEY")]
+        private_key: String,
+    },
+    /// Execute a guest over a table of inputs and expected journals with the
+    /// local executor, failing if any case does not match.
+    TestGuest {
+        /// The name or image ID of a registered guest, or the path to a
+        /// guest ELF to validate before registering it
+        guest: String,
+
+        /// JSON array of cases, each with a name, a hex input and optionally
+        /// a private input, an expected journal, `expect_failure` or an
+        /// `expected_error` the rejection must contain
+        #[arg(long)]
+        cases: PathBuf,
+
+        /// Dump the execution of each failed case to a directory of this
+        /// one, for `replay-trace`.
+        #[arg(long)]
+        dump_trace: Option<PathBuf>,
+    },
+    /// Execute a guest again from a dump written with `--dump-trace`,
+    /// printing its logs and the tail of its trace, and fail if it does
+    /// not end the way the dumped execution did.
+    ReplayTrace {
+        /// Directory of the dump
+        dump: PathBuf,
+
+        /// Number of trace events to print, from the end.
+        #[arg(long, default_value_t = 20)]
+        tail: usize,
+    },
+    /// Explore guests interactively: select a guest and pool, fetch inputs
+    /// at chosen blocks, then execute or prove them. Fetched pool state is
+    /// kept for the session.
+    Shell {
+        /// Ethereum node to fetch pool state from. Without one, inputs must
+        /// be set by hand.
+        #[arg(long, env)]
+        eth_node: Option<String>,
+    },
+    /// Save the state of a pool at a block, for debugging proof inputs.
+    Snapshot {
+        /// Address of the pool
+        pool: Address,
+
+        /// Where to write the bincode serialized snapshot
+        out: PathBuf,
+
+        /// Block to read the state at. Defaults to the latest block.
+        #[arg(long)]
+        block: Option<u64>,
+
+        /// Number of tick bitmap words on either side of the current tick
+        /// whose initialized ticks are included.
+        #[arg(long, default_value_t = 2)]
+        tick_words: i16,
+
+        /// Ethereum Node endpoint.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Compare two pool snapshots field by field: slot0, ticks and
+    /// observations.
+    DiffSnapshot { a: PathBuf, b: PathBuf },
+    /// Write the evidence kept of a callback request to a single tar archive
+    /// for counterparties or an arbitration process: the request event, the
+    /// input artifacts and receipt of its run, a transcript of the checks
+    /// its result passed, with the receipt verified again, and the
+    /// submission's transaction hash. Requires `--evidence-dir`.
+    ExportEvidence {
+        /// Hex encoded callback request ID, as logged by the listener
+        request_id: String,
+
+        /// Where to write the archive. Defaults to
+        /// `evidence-<request-id>.tar`.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Generate a Solidity library decoding a guest's journals, accepting
+    /// every journal version the relay reads.
+    JournalSol {
+        /// Name of the guest
+        guest: String,
+
+        /// Where to write the library. Defaults to stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+    /// Build a TWAP guest input proving a pool's observations over a window,
+    /// printed hex encoded for `query TWAP`. Windows over which the pool's
+    /// observation ring buffer wrapped are proven at several blocks.
+    TwapInput {
+        /// Address of the pool
+        pool: Address,
+
+        /// Start of the window, as a Unix timestamp.
+        #[arg(long)]
+        from: u64,
+
+        /// End of the window, as a Unix timestamp.
+        #[arg(long)]
+        to: u64,
+
+        /// Directory caching fetched state proofs.
+        #[arg(long)]
+        proof_cache: Option<PathBuf>,
+
+        /// JSON file mapping chain IDs to the backend serving their state
+        /// proofs, an archive node or a proof API. Without it, proofs are
+        /// fetched from `--eth-node`.
+        #[arg(long)]
+        proof_sources: Option<PathBuf>,
+
+        /// Only prove the window from the pool's observations at its end,
+        /// failing if its observation cardinality is too low to cover it.
+        #[arg(long)]
+        single_block: bool,
+
+        /// Newest block the input may be anchored to: `latest`, `sequencer`,
+        /// `safe`, `finalized` or a number of confirmations.
+        #[arg(long, default_value = "latest")]
+        finality: FinalityPolicy,
+
+        /// Ethereum archive node endpoint, serving `eth_getProof` at
+        /// historical blocks.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Prove a submitted TWAP again, to audit a disputed oracle update: its
+    /// input is rebuilt at the blocks its journal commits, from archived
+    /// proofs or fetched again, proven and the journal compared with the
+    /// submitted one, failing if they differ.
+    Regenerate {
+        /// The name or image ID of the guest that proved the update. Only
+        /// TWAP inputs can be rebuilt.
+        #[arg(long)]
+        guest: String,
+
+        /// Block the update was anchored to, the last its journal commits.
+        #[arg(long)]
+        block: u64,
+
+        /// Hex encoded journal of the update.
+        #[arg(long, required_unless_present = "tx", conflicts_with = "tx")]
+        journal: Option<String>,
+
+        /// Transaction that submitted the update, to read its journal from.
+        #[arg(long)]
+        tx: Option<H256>,
+
+        /// Directory of archived state proofs, as written with
+        /// `--proof-cache`. Proofs it lacks are fetched again.
+        #[arg(long)]
+        proof_cache: Option<PathBuf>,
+
+        /// JSON file mapping chain IDs to the backend serving their state
+        /// proofs, an archive node or a proof API. Without it, proofs are
+        /// fetched from `--eth-node`.
+        #[arg(long)]
+        proof_sources: Option<PathBuf>,
+
+        /// Ethereum archive node endpoint, serving `eth_getProof` at
+        /// historical blocks.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Build the input of the HISTORY guest, extending a pool's rolling
+    /// commitment of observations with those made since the previous run.
+    HistoryInput {
+        /// Address of the pool
+        pool: Address,
+
+        /// Hex encoded journal of the previous run. Without it, the history
+        /// starts from the oldest observation the pool holds.
+        #[arg(long)]
+        previous_journal: Option<String>,
+
+        /// Directory caching fetched state proofs.
+        #[arg(long)]
+        proof_cache: Option<PathBuf>,
+
+        /// JSON file mapping chain IDs to the backend serving their state
+        /// proofs, an archive node or a proof API. Without it, proofs are
+        /// fetched from `--eth-node`.
+        #[arg(long)]
+        proof_sources: Option<PathBuf>,
+
+        /// Newest block the input may be anchored to: `latest`, `sequencer`,
+        /// `safe`, `finalized` or a number of confirmations.
+        #[arg(long, default_value = "latest")]
+        finality: FinalityPolicy,
+
+        /// Ethereum archive node endpoint, serving `eth_getProof` at
+        /// historical blocks.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Build the input of the RESERVE guest, proving the reserves a vault
+    /// holds in Uniswap v3 positions.
+    ReserveInput {
+        /// Address of the vault owning the positions
+        vault: Address,
+
+        /// JSON file listing the vault's positions as `pool`, `tick_lower`
+        /// and `tick_upper` objects.
+        #[arg(long)]
+        positions: PathBuf,
+
+        /// Uniswap v3 factory that deployed the pools.
+        #[arg(long)]
+        factory: Address,
+
+        /// Pool init code hash of the factory, if it is a fork of Uniswap v3.
+        #[arg(long)]
+        init_code_hash: Option<H256>,
+
+        /// Directory caching fetched state proofs.
+        #[arg(long)]
+        proof_cache: Option<PathBuf>,
+
+        /// JSON file mapping chain IDs to the backend serving their state
+        /// proofs, an archive node or a proof API. Without it, proofs are
+        /// fetched from `--eth-node`.
+        #[arg(long)]
+        proof_sources: Option<PathBuf>,
+
+        /// Newest block the input may be anchored to: `latest`, `sequencer`,
+        /// `safe`, `finalized` or a number of confirmations.
+        #[arg(long, default_value = "latest")]
+        finality: FinalityPolicy,
+
+        /// Ethereum archive node endpoint, serving `eth_getProof` at
+        /// historical blocks.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Build the input of the CYCLE guest, proving the arbitrage factor of
+    /// a cycle of pools to detect a dislocated pool price.
+    CycleInput {
+        /// Token traded around the cycle
+        start: Address,
+
+        /// Pools of the cycle, in trading order from `start` back into it.
+        #[arg(long, value_delimiter = ',', required = true)]
+        pools: Vec<Address>,
+
+        /// Uniswap v3 factory that deployed the pools.
+        #[arg(long)]
+        factory: Address,
+
+        /// Pool init code hash of the factory, if it is a fork of Uniswap v3.
+        #[arg(long)]
+        init_code_hash: Option<H256>,
+
+        /// Directory caching fetched state proofs.
+        #[arg(long)]
+        proof_cache: Option<PathBuf>,
+
+        /// JSON file mapping chain IDs to the backend serving their state
+        /// proofs, an archive node or a proof API. Without it, proofs are
+        /// fetched from `--eth-node`.
+        #[arg(long)]
+        proof_sources: Option<PathBuf>,
+
+        /// Newest block the input may be anchored to: `latest`, `sequencer`,
+        /// `safe`, `finalized` or a number of confirmations.
+        #[arg(long, default_value = "latest")]
+        finality: FinalityPolicy,
+
+        /// Ethereum archive node endpoint, serving `eth_getProof` at
+        /// historical blocks.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Print, as JSON, the blocks a sampling guest will read when seeded by
+    /// a block, so their state can be fetched before proving.
+    SampleBlocks {
+        /// First block of the sampled range.
+        #[arg(long)]
+        from: u64,
+
+        /// Block whose hash seeds the sample, ending the sampled range.
+        #[arg(long)]
+        seed_block: u64,
+
+        /// Number of distinct blocks to sample.
+        #[arg(long)]
+        count: usize,
+
+        /// Policy the seed block must meet: `latest`, `sequencer`, `safe`,
+        /// `finalized` or a number of confirmations.
+        #[arg(long, default_value = "latest")]
+        finality: FinalityPolicy,
+
+        /// Ethereum node endpoint
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+    },
+    /// Validate the configuration before starting the relay: Bonsai
+    /// credentials, the Ethereum node and chain ID, the signer's balance, the
+    /// guest registry, the deployed contracts and the store's migrations.
+    Doctor {
+        /// Bonsai Relay contract address on Ethereum
+        #[arg(long, env)]
+        relay_address: Address,
+
+        /// Verifier contract address, if deployed separately from the relay
+        #[arg(long, env)]
+        verifier_address: Option<Address>,
+
+        /// Ethereum Node endpoint.
+        #[arg(long, env, default_value = "ws://localhost:8545")]
+        eth_node: String,
+
+        /// Ethereum chain ID
+        #[arg(long, default_value_t = 31337)]
+        eth_chain_id: u64,
+
+        /// Hex encoded private key of the signer whose balance to check
+        #[arg(short, long, env)]
+        private_key: Option<String>,
+
+        /// Signer balance, in wei, below which the check fails.
+        #[arg(long, env, default_value_t = U256::zero())]
+        min_balance: U256,
+
+        /// Store directory of the relay API server to check.
+        #[arg(long, env)]
+        store_dir: Option<PathBuf>,
+
+        /// Hex encoded 256-bit key the store is encrypted with.
+        #[arg(long, env, conflicts_with = "store_kms_key_ciphertext")]
+        store_key: Option<String>,
+
+        /// Hex encoded store key encrypted under an AWS KMS key.
+        #[arg(long, env)]
+        store_kms_key_ciphertext: Option<String>,
+    },
+    /// Send requests to a relay API server at a fixed rate and report latency
+    /// percentiles and how requests queued. Run the target with
+    /// --risc0-dev-mode to load the relay rather than the prover.
+    Loadtest {
+        /// Name or hex image ID of the guest to request
+        guest: String,
+
+        /// File of hex encoded inputs, one per line, sent in turn
+        #[arg(long)]
+        inputs: PathBuf,
+
+        /// Base URL of the relay API
+        #[arg(long, default_value = "http://127.0.0.1:8090")]
+        url: String,
+
+        /// API key to authenticate with
+        #[arg(long, env = "RELAY_API_KEY")]
+        api_key: Option<String>,
+
+        #[arg(long, value_enum, default_value_t = Endpoint::Simulate)]
+        endpoint: Endpoint,
+
+        /// Requests started per second
+        #[arg(long, default_value_t = 10.0)]
+        rate: f64,
+
+        /// How long to send requests for, in seconds
+        #[arg(long, default_value_t = 60)]
+        duration_secs: u64,
+
+        /// Requests in flight beyond which due requests are skipped
+        #[arg(long, default_value_t = 256)]
+        max_in_flight: usize,
+
+        /// Per-request timeout, in seconds
+        #[arg(long, default_value_t = 600)]
+        timeout_secs: u64,
+    },
+    /// Serve the relay API, proving guest inputs submitted over HTTP.
+    Serve {
+        /// Address to listen on
+        #[arg(long, env, default_value = "127.0.0.1:8090")]
+        listen: SocketAddr,
+
+        /// Identical requests (same guest and canonical input) arriving
+        /// within this many minutes share a single proving session.
+        #[arg(long, env, default_value_t = 10)]
+        dedup_window_mins: u64,
+
+        /// Most sessions started through the API that a tenant may hold at
+        /// once. The oldest finished one makes room for a new one.
+        #[arg(long, env, default_value_t = MAX_SESSIONS_PER_TENANT)]
+        max_sessions_per_tenant: usize,
+
+        /// Seconds after which a session started through the API expires,
+        /// whether or not its client collected the result.
+        #[arg(long, env, default_value_t = SESSION_RETENTION.as_secs())]
+        session_retention_secs: u64,
+
+        /// JSON file describing the tenants served by this relay, with their
+        /// role-scoped API keys, quotas and guest allowlists. Without it all
+        /// requests are served unauthenticated as a single tenant.
+        #[arg(long, env)]
+        tenants: Option<PathBuf>,
+
+        /// Key of the relay's operator, the only one allowed to pause and
+        /// resume proving for every tenant and to change the pool catalog.
+        /// Without it these routes are only served while the relay runs
+        /// without tenant API keys.
+        #[arg(long, env)]
+        operator_key: Option<String>,
+
+        /// Directory in which to keep the inputs and receipts of completed
+        /// requests. Nothing is persisted if unset.
+        #[arg(long, env)]
+        store_dir: Option<PathBuf>,
+
+        /// Hex encoded 256-bit key with which to encrypt the store using
+        /// AES-GCM.
+        #[arg(long, env, conflicts_with = "store_kms_key_ciphertext")]
+        store_key: Option<String>,
+
+        /// Hex encoded store key encrypted under an AWS KMS key, decrypted at
+        /// startup with the AWS credentials from the environment.
+        #[arg(long, env)]
+        store_kms_key_ciphertext: Option<String>,
+
+        /// zstd compress receipts kept in the store. Previously stored
+        /// receipts stay readable either way.
+        #[arg(long, env)]
+        compress_receipts: bool,
+
+        /// Comma separated addresses of `prover-worker`s to prove requests
+        /// on instead of Bonsai. Proofs come without a SNARK, so they cannot
+        /// be verified on chain.
+        #[arg(long, env, value_delimiter = ',')]
+        prover_cluster: Vec<SocketAddr>,
+
+        /// Secret shared with the `prover-worker`s of --prover-cluster, which
+        /// only serve relays holding it.
+        #[arg(long, env)]
+        cluster_secret: Option<String>,
+
+        /// Comma separated prover backends to dispatch proofs to, each going
+        /// to the backend expected to finish it first given its queue and
+        /// past latency for the guest. Defaults to the cluster if
+        /// --prover-cluster is given, or Bonsai otherwise.
+        #[arg(long, env, value_enum, value_delimiter = ',')]
+        provers: Vec<ProverKind>,
+
+        /// Ethereum node to read token metadata from, for post-processors
+        /// that scale by a token's decimals.
+        #[arg(long, env)]
+        eth_node: Option<String>,
+
+        /// JSON array of tokens with their chain ID, address, symbol and
+        /// decimals, used instead of reading the metadata from the chain.
+        #[arg(long, env, requires = "eth_node")]
+        tokens: Option<PathBuf>,
+
+        /// JSON pool catalog of the nodes of each chain, the pools to prove
+        /// on a schedule and the factories to discover more pools from.
+        /// The operator may change it and accept discovered pools through
+        /// the API.
+        /// Each pool's latest proven update is served for consumers to
+        /// submit.
+        #[arg(long, env)]
+        pools: Option<PathBuf>,
+
+        /// How long after its observation a served pool update is signed as
+        /// fresh.
+        #[arg(long, env, default_value_t = 3600, requires = "pools")]
+        update_ttl_secs: u64,
+
+        /// Hex encoded private key signing the freshness envelopes of served
+        /// pool updates.
+        #[arg(long, env, requires = "pools")]
+        update_signing_key: Option<String>,
+
+        /// Hex encoded private key transmitting proven prices on chain: each
+        /// run of a catalog pool with an `oracle` as the next round of that
+        /// aggregator, and each run of a batch with a `batcher` to that
+        /// batcher. Without it prices are only served for consumers to
+        /// submit.
+        #[arg(long, env, requires = "pools")]
+        transmitter_key: Option<String>,
+
+        /// Directory keeping, as `<batch>.jsonl`, the pools each transmitted
+        /// batch run failed to price.
+        #[arg(long, env, requires = "transmitter_key")]
+        dead_letter_dir: Option<PathBuf>,
+
+        /// Directory shared by the relay's replicas, such as a volume mounted
+        /// into every pod, keeping leases on the runs of the catalog's pools,
+        /// checkpoints of their Bonsai sessions and their run log. Each run
+        /// is proven by one replica, and resumed by another if that replica
+        /// stops while proving it.
+        #[arg(long, env, requires = "pools")]
+        lease_dir: Option<PathBuf>,
+
+        /// Seconds a run's lease is held without being renewed. Leases are
+        /// renewed every third of this while their run proves.
+        #[arg(long, env, default_value_t = 60)]
+        lease_ttl_secs: u64,
+
+        /// JSON file of latency objectives by guest name, each with a
+        /// `target_p95_secs` and a `window_hours` (24 by default). The
+        /// latency of requests and scheduled runs of these guests is tracked
+        /// against them and reported at /v1/admin/slo.
+        #[arg(long, env)]
+        slo: Option<PathBuf>,
+
+        /// JSON file of alert sinks and of rules for the jobs of --pools.
+        /// An alert fires when a watched journal value moves by more than
+        /// `max_deviation_bps` between consecutive runs, or when no run of a
+        /// job succeeds for `max_silence_secs`.
+        #[arg(long, env)]
+        alerts: Option<PathBuf>,
+
+        /// JSON file of NATS subjects and Kafka topics, with the scheduled
+        /// jobs and tenants whose results are published to each, and an
+        /// optional `receipt_base_url` the relay's API is reached at.
+        #[arg(long, env)]
+        queues: Option<PathBuf>,
+
+        /// Read every setting from its environment variable alone, and report
+        /// all missing or invalid settings at once before starting. The
+        /// tenants, tokens, pools, SLO, queues and requester policy files may
+        /// instead be given inline as JSON in `TENANTS_JSON`, `TOKENS_JSON`,
+        /// `POOLS_JSON`, `SLO_JSON`, `QUEUES_JSON` and
+        /// `REQUESTER_POLICY_JSON`; pools accepted through the API are then
+        /// lost on restart.
+        #[arg(long)]
+        env_config: bool,
+    },
+    /// Prove segments for a relay serving with --prover-cluster.
+    ProverWorker {
+        /// Address to listen on. Only the relay should be able to reach it.
+        #[arg(long, env, default_value = "127.0.0.1:8092")]
+        listen: SocketAddr,
+
+        /// Secret shared with the relay, which must prove it holds it before
+        /// its requests are served.
+        #[arg(long, env)]
+        cluster_secret: String,
+    },
     /// Upload the RISC-V ELF binary to Bonsai.
     Run {
         /// Bonsai Relay contract address on Ethereum
@@ -73,6 +763,110 @@ enum Command {
             default_value = ANVIL_DEFAULT_KEY
         )]
         private_key: String,
+
+        /// Fee escrow contract. When set, requests are served by this relay's
+        /// own listener, which skips requests whose escrowed fee is below
+        /// --min-fee and claims the fee after invoking the callback.
+        #[arg(long, env)]
+        fee_escrow: Option<Address>,
+
+        /// Minimum escrowed fee, in wei, for a request to be served.
+        #[arg(long, env, default_value_t = U256::zero())]
+        min_fee: U256,
+
+        /// Operator mode for relays with staked collateral: requests are
+        /// served by this relay's own listener, which executes every guest
+        /// locally as well and refuses to submit Bonsai journals that differ.
+        #[arg(long, env, default_value_t = false)]
+        operator_mode: bool,
+
+        /// Refuse to submit callbacks estimated to cost more gas than this.
+        /// Requests are served by this relay's own listener when set.
+        #[arg(long, env, conflicts_with = "gas_strategy")]
+        max_submission_gas: Option<u64>,
+
+        /// Price posting each result as EIP-4844 blobs against posting it as
+        /// calldata, to hold callbacks by --max-calldata-premium-pct. Results
+        /// are only ever posted as calldata, which is where the relay
+        /// contract reads them from. Requests are served by this relay's own
+        /// listener when set.
+        #[arg(long, env, default_value_t = false, conflicts_with = "gas_strategy")]
+        blob_pricing: bool,
+
+        /// With --blob-pricing, hold callbacks whose calldata costs more than
+        /// this percentage over posting them as blobs, pricing them again
+        /// every block, until it no longer does or --max-blob-hold-secs pass.
+        /// They are then posted as calldata either way.
+        #[arg(long, env, requires = "blob_pricing")]
+        max_calldata_premium_pct: Option<u64>,
+
+        /// Longest a callback is held by --max-calldata-premium-pct, in
+        /// seconds.
+        #[arg(long, env, default_value_t = DEFAULT_MAX_HOLD_SECS)]
+        max_blob_hold_secs: u64,
+
+        /// JSON file with the `max_gas`, `blob_pricing`,
+        /// `max_calldata_premium_pct` and `max_hold_secs` settings of the
+        /// flags above, reloaded on SIGHUP or through the admin API.
+        /// Requests are served by this relay's own listener when set.
+        #[arg(long, env)]
+        gas_strategy: Option<PathBuf>,
+
+        /// Fee model of the chain, used to account for rollup L1 data fees in
+        /// callback cost estimates. Inferred from the chain ID if not given.
+        #[arg(long, env, value_enum)]
+        chain_kind: Option<ChainKind>,
+
+        /// Refuse to submit results computed from chain state observed more
+        /// than this many seconds before submission. Requests are served by
+        /// this relay's own listener when set.
+        #[arg(long, env)]
+        max_result_age_secs: Option<u64>,
+
+        /// Seconds for which a result in the receipt index answers requests
+        /// for the same input.
+        #[arg(long, env, default_value_t = 600)]
+        coalesce_window_secs: u64,
+
+        /// Identical requests (same guest and canonical input) arriving
+        /// within this many minutes share a single proving session.
+        #[arg(long, env, default_value_t = 10)]
+        dedup_window_mins: u64,
+
+        /// JSON file with approval rules and operator keys. Callbacks matching
+        /// a rule are held until enough operators sign off through the admin
+        /// API on --admin-listen. Requests are served by this relay's own
+        /// listener when set.
+        #[arg(long, env)]
+        approval_policy: Option<PathBuf>,
+
+        /// Address of the protocol's guardian contract. While its `paused()`
+        /// flag is set, requests are still proven but their results are held
+        /// until it is cleared. Requests are served by this relay's own
+        /// listener when set.
+        #[arg(long, env)]
+        guardian: Option<Address>,
+
+        /// How often to read the guardian's pause flag, in seconds.
+        #[arg(long, env, default_value_t = 12)]
+        guardian_poll_secs: u64,
+
+        /// JSON file mapping guest names to the post-processors applied to
+        /// the journals of their callbacks. Only dev mode relay contracts,
+        /// which do not verify seals, accept post-processed journals.
+        /// Requests are served by this relay's own listener when set.
+        #[arg(long, env)]
+        post_process: Option<PathBuf>,
+
+        /// Address of the admin API for approving callbacks and reloading the
+        /// requester policy and gas strategy.
+        #[arg(long, env, default_value = "127.0.0.1:8091")]
+        admin_listen: SocketAddr,
+
+        /// API key required to reload configuration through the admin API.
+        /// Without it configuration is only reloaded on SIGHUP.
+        #[arg(long, env)]
+        operator_key: Option<String>,
     },
 }
 
@@ -91,6 +885,114 @@ struct GlobalOpts {
     /// zkVM program and no proof is generated.
     #[arg(long, env, global = true, default_value_t = false)]
     risc0_dev_mode: bool,
+
+    /// Directory of additional guest ELFs, registered under their file name.
+    /// These are memory-mapped on first use rather than held in memory.
+    #[arg(long, env, global = true)]
+    guest_dir: Option<PathBuf>,
+
+    /// Addresses of operator keys, one of which must have signed every
+    /// guest ELF in the guest directory with `sign-guests`. Guests embedded
+    /// in the relay need no signature.
+    #[arg(long, env, global = true, value_delimiter = ',')]
+    guest_signers: Vec<Address>,
+
+    /// Whether to warn about or refuse guests built for a different zkVM
+    /// version than this relay.
+    #[arg(long, env, global = true, value_enum, default_value_t = VersionPolicy::Warn)]
+    zkvm_version_policy: VersionPolicy,
+
+    /// Canary a new guest image, given as STABLE=CANDIDATE guest names: for
+    /// the period from startup, requests for STABLE that `serve` or `run`
+    /// handles are also run on CANDIDATE and journals compared. The server
+    /// reports the canary at /v1/admin/canary.
+    #[arg(long, env, global = true)]
+    canary: Option<CanarySpec>,
+
+    /// How long the canary stays active, in seconds.
+    #[arg(long, env, global = true, default_value_t = 24 * 60 * 60)]
+    canary_period_secs: u64,
+
+    /// Fraction of Bonsai-proven requests to also execute locally, comparing
+    /// journals byte for byte. 0 disables shadow checks. The server reports
+    /// its checks at /v1/admin/shadow.
+    #[arg(long, env, global = true, default_value_t = 0.0)]
+    shadow_sample_rate: f64,
+
+    /// JSON file with allow and deny lists of requester addresses and their
+    /// quotas, enforced by the listener and the API server. The server takes
+    /// the requester of a request from its tenant's `requesters`.
+    #[arg(long, env, global = true)]
+    requester_policy: Option<PathBuf>,
+
+    /// Directory in which to write the input, journal, receipt, timings and
+    /// log lines of every guest run, one directory per session. With `run`,
+    /// requests are served by this relay's own listener when set.
+    #[arg(long, env, global = true)]
+    artifacts_dir: Option<PathBuf>,
+
+    /// Directory of proven results indexed by guest and canonical input,
+    /// shared by `serve`, whose scheduled jobs index their results and which
+    /// serves them at /v1/receipts/by-input/:hash, and `run`, whose listener
+    /// answers requests for an indexed input with its result instead of
+    /// proving it again. With `run`, requests are served by this relay's own
+    /// listener when set.
+    #[arg(long, env, global = true)]
+    receipt_index_dir: Option<PathBuf>,
+
+    /// Directory in which the listener of `run` keeps the evidence of every
+    /// callback request it handles: the request event, the run's artifacts,
+    /// the checks its result passed and the submission, for
+    /// `export-evidence`. With `run`, requests are served by this relay's
+    /// own listener when set.
+    #[arg(long, env, global = true)]
+    evidence_dir: Option<PathBuf>,
+
+    /// Whether Bonsai executes guests itself or proves segments executed by
+    /// the relay. Hybrid proving falls back to remote proving when Bonsai
+    /// does not list segment upload among its features.
+    #[arg(long, env, global = true, value_enum, default_value_t = ProvingMode::Remote)]
+    proving_mode: ProvingMode,
+
+    /// Bonsai API revision to prove through. Probed from Bonsai if not
+    /// given, so the relay follows Bonsai's rollouts.
+    #[arg(long, env, global = true, value_enum)]
+    bonsai_api_revision: Option<ApiRevision>,
+
+    /// New Bonsai sessions to create for a proof whose session ended with a
+    /// bad status for transient reasons, rather than a fault of the guest.
+    /// Replaced sessions are recorded in the job history.
+    #[arg(long, env, global = true, default_value_t = DEFAULT_SESSION_RETRIES)]
+    session_retries: u32,
+
+    /// File of secrets in `.env` format, such as BONSAI_API_KEY and
+    /// PRIVATE_KEY, kept apart from the rest of the configuration. Variables
+    /// set in the environment take precedence.
+    #[arg(long, env, global = true)]
+    secrets_file: Option<PathBuf>,
+
+    /// Largest segment executed locally, as a power of two of cycles. Lower
+    /// it to prove on machines with less memory. Defaults to 20 on Linux
+    /// and 19 on macOS and Windows.
+    #[arg(long, env, global = true)]
+    segment_limit_po2: Option<u32>,
+
+    /// Most cycles a local execution may run for. Unbounded if not given.
+    #[arg(long, env, global = true)]
+    session_limit: Option<u64>,
+
+    /// Trace ID prefixed to every log line of this invocation, to correlate
+    /// it with the caller's own records.
+    #[arg(long, env, global = true, value_parser = trace::validate)]
+    trace_id: Option<String>,
+
+    /// Largest difference, in seconds, tolerated between this host's clock
+    /// and those of other replicas and the chain. Leases are taken over only
+    /// once lapsed by this long, and results are checked for staleness
+    /// against the local clock with this much slack when the latest block
+    /// cannot be read.
+    #[arg(long, env, global = true, default_value_t = 5)]
+    max_clock_skew_secs: u64,
 }
 
 #[derive(Parser)]
@@ -103,59 +1005,237 @@ pub struct App {
     command: Command,
 }
 
-/// Parse a slice of strings as a fixed array of uint256 tokens.
-fn parse_to_tokens(slice: &[String]) -> anyhow::Result<Token> {
-    Ok(Token::FixedArray(
-        slice
-            .iter()
-            .map(|s| -> anyhow::Result<_> { Ok(U256::from_str_radix(s, 16)?.into_token()) })
-            .collect::<Result<Vec<_>, _>>()?,
-    ))
-}
-
-fn tokenize_snark_proof(proof: &SnarkProof) -> anyhow::Result<Token> {
-    if proof.b.len() != 2 {
-        anyhow::bail!("hex-strings encoded proof is not well formed");
+fn main() {
+    // Errors are printed through the redaction layer rather than by the
+    // runtime, as their context may include endpoints with embedded keys.
+    // Secrets are loaded into the environment before the runtime starts, as
+    // setting variables is only sound while no other thread may read them.
+    if let Some(path) = secrets::secrets_file_path(std::env::args()) {
+        if let Err(err) = secrets::load(&path) {
+            elog!("Error: {err:?}");
+            std::process::exit(1);
+        }
     }
-    for pair in [&proof.a, &proof.c].into_iter().chain(proof.b.iter()) {
-        if pair.len() != 2 {
-            anyhow::bail!("hex-strings encoded proof is not well formed");
+    secrets::register_env();
+    if envconfig::requested(std::env::args()) {
+        if let Err(report) = envconfig::prepare(&App::command(), "serve", std::env::args()) {
+            elog!("{report}");
+            std::process::exit(1);
+        }
+    }
+    #[cfg(feature = "fault-injection")]
+    let _faults = match bonsai_ethereum_relay_cli::faults::install_from_env() {
+        Ok(guard) => guard,
+        Err(err) => {
+            elog!("Error: {err:?}");
+            std::process::exit(1);
+        }
+    };
+    let args = App::parse();
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            elog!("Error: failed to start the runtime: {err}");
+            std::process::exit(1);
+        }
+    };
+    let result = runtime.block_on(async move {
+        match args.global_opts.trace_id.clone() {
+            Some(trace_id) => trace::scope(trace_id, run(args)).await,
+            None => run(args).await,
         }
+    });
+    if let Err(err) = result {
+        elog!("Error: {err:?}");
+        std::process::exit(1);
     }
-    Ok(Token::FixedArray(vec![
-        parse_to_tokens(&proof.a)?,
-        Token::FixedArray(vec![
-            parse_to_tokens(&proof.b[0])?,
-            parse_to_tokens(&proof.b[1])?,
-        ]),
-        parse_to_tokens(&proof.c)?,
-    ]))
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = App::parse();
+async fn run(args: App) -> anyhow::Result<()> {
+    register_secret(&args.global_opts.bonsai_api_key);
     let dev_mode = args.global_opts.risc0_dev_mode;
+    let mut exec_limits = ExecLimits::default();
+    if let Some(po2) = args.global_opts.segment_limit_po2 {
+        exec_limits.segment_limit_po2 = po2;
+    }
+    exec_limits.session_limit = args.global_opts.session_limit;
+    let max_clock_skew = Duration::from_secs(args.global_opts.max_clock_skew_secs);
+    let mut registry = GuestRegistry::from_build(GUEST_LIST, PROVENANCE);
+    // Signing guests must not require the signatures it is about to write.
+    if !matches!(args.command, Command::SignGuests { .. }) {
+        registry = registry.with_signers(args.global_opts.guest_signers.clone());
+    }
+    if let Some(guest_dir) = &args.global_opts.guest_dir {
+        registry.load_dir(guest_dir)?;
+    }
+    registry.check_versions(args.global_opts.zkvm_version_policy);
+    set_session_retries(args.global_opts.session_retries);
+    let mut bonsai = BonsaiBackend::default();
+    if !dev_mode {
+        // Commands not talking to Bonsai still work while it is unreachable,
+        // proving through the alpha API should they need to.
+        match bonsai_api::negotiate(
+            &args.global_opts.bonsai_api_url,
+            &args.global_opts.bonsai_api_key,
+            args.global_opts.bonsai_api_revision,
+        )
+        .await
+        {
+            Ok(revision) => bonsai = BonsaiBackend::new(revision),
+            Err(err) => {
+                elog!("Failed to negotiate the Bonsai API revision, using the alpha API: {err:?}")
+            }
+        }
+        let mode = args
+            .global_opts
+            .proving_mode
+            .negotiate(
+                bonsai.revision(),
+                &args.global_opts.bonsai_api_url,
+                &args.global_opts.bonsai_api_key,
+            )
+            .await;
+        if mode == ProvingMode::Hybrid {
+            bonsai = bonsai.with_hybrid(Arc::new(ImagePool::default().with_limits(exec_limits)));
+        }
+    }
+    let requester_policy = args
+        .global_opts
+        .requester_policy
+        .as_deref()
+        .map(RequesterPolicy::load)
+        .transpose()?
+        .map(Arc::new);
+    let artifacts = args
+        .global_opts
+        .artifacts_dir
+        .as_deref()
+        .map(Artifacts::open)
+        .transpose()?
+        .map(Arc::new);
+    let receipt_index = args
+        .global_opts
+        .receipt_index_dir
+        .as_deref()
+        .map(ReceiptIndex::open)
+        .transpose()?
+        .map(Arc::new);
+    let evidence = args
+        .global_opts
+        .evidence_dir
+        .as_deref()
+        .map(Evidence::open)
+        .transpose()?
+        .map(Arc::new);
+    // The canary's period runs from startup, across every request served.
+    let canary = match &args.global_opts.canary {
+        Some(spec) => {
+            let period = Duration::from_secs(args.global_opts.canary_period_secs);
+            let canary = Arc::new(Canary::from_spec(spec, &registry, period)?);
+            tokio::spawn(canary.clone().conclude());
+            Some(canary)
+        }
+        None => None,
+    };
+    let mut reloader = Reloader::default();
+    if let (Some(path), Some(policy)) = (&args.global_opts.requester_policy, &requester_policy) {
+        reloader = reloader.with_requester_policy(path.clone(), policy.clone());
+    }
 
     match args.command {
         Command::Query {
             guest_binary,
             input,
+            private_input,
+            private_salt,
+            guest_logs,
+            dump_trace,
+            receipt_out,
         } => {
+            if let Some(private_input) = &private_input {
+                register_secret(private_input.trim_start_matches("0x"));
+            }
+            if let Some(private_salt) = &private_salt {
+                register_secret(private_salt.trim_start_matches("0x"));
+            }
             // Search list for requested binary name
-            let guest_entry = resolve_guest_entry(GUEST_LIST, &guest_binary)
+            let guest = registry
+                .resolve(&guest_binary)
                 .context("failed to resolve guest entry")?;
 
             // Execute or return image id
             let output_tokens = match &input {
-                // Input provided. Return the Ethereum ABI encoded journal and
+                // Input provided. Return the Ethereum ABI encoded journal and,
+                // when proving, the post state digest and seal. The journal
+                // values re-encoded per the guest's Solidity schema are
+                // appended last, so decoders of the leading fields are
+                // unaffected.
                 Some(input) => {
-                    let output = resolve_image_output(input, &guest_entry, dev_mode)
-                        .await
-                        .context("failed to resolve image output")?;
+                    let pool = Arc::new(ImagePool::default().with_limits(exec_limits));
+                    // Prepared once, so that every run commits the same salt.
+                    let input = prepare_salted_input(
+                        &guest,
+                        input,
+                        private_input.as_deref(),
+                        private_salt.as_deref(),
+                    )?;
+                    let logged = if guest_logs || dump_trace.is_some() {
+                        let mut trace = TraceTail::new(DEFAULT_TRACE_TAIL);
+                        let (result, logs) = match &dump_trace {
+                            Some(_) => pool.execute_traced(&guest, &input, &mut trace),
+                            None => pool.execute_with_logs(&guest, &input),
+                        };
+                        if guest_logs {
+                            for line in logs.stdout.lines() {
+                                elog!("guest stdout: {line}");
+                            }
+                            for line in logs.stderr.lines() {
+                                elog!("guest stderr: {line}");
+                            }
+                        }
+                        if let (Some(dir), Err(_)) = (&dump_trace, &result) {
+                            write_dump(dir, &guest, exec_limits, &input, &result, &logs, &trace)?;
+                            elog!("Dumped the failed execution to {}", dir.display());
+                        }
+                        let (output, stats) = result.context("guest execution failed")?;
+                        elog!("{stats}");
+                        Some(output)
+                    } else {
+                        None
+                    };
+                    // In dev mode the logged execution is the result.
+                    let output = match logged.filter(|_| dev_mode) {
+                        Some(output) => output,
+                        None if args.global_opts.shadow_sample_rate > 0.0 => {
+                            ShadowVerifier::new(args.global_opts.shadow_sample_rate)
+                                .run(&guest, input, &pool, &bonsai, dev_mode)
+                                .await
+                                .context("failed to resolve image output")?
+                        }
+                        None => run_guest(&guest, input, &pool, &bonsai, dev_mode)
+                            .await
+                            .context("failed to resolve image output")?,
+                    };
+                    elog!(
+                        "Estimated submission cost: {}",
+                        estimate_output(guest.image_id.into(), &output)?
+                    );
+                    if let Some(path) = &receipt_out {
+                        match &output {
+                            Output::Bonsai { receipt, .. } | Output::Stark { receipt, .. } => {
+                                receipt::write_file(receipt, path)?;
+                                elog!("Wrote the receipt to {}", path.display());
+                            }
+                            Output::Execution { .. } => bail!("executions have no receipt"),
+                        }
+                    }
                     match (dev_mode, output) {
                         (true, Output::Execution { journal }) => {
-                            vec![Token::Bytes(journal)]
+                            let public_values = public_values(&guest.name, &journal)?;
+                            vec![Token::Bytes(journal), Token::Bytes(public_values)]
                         }
                         (
                             false,
@@ -163,15 +1243,16 @@ async fn main() -> anyhow::Result<()> {
                                 journal,
                                 receipt_metadata,
                                 snark_proof,
+                                ..
                             },
                         ) => {
+                            let public_values = public_values(&guest.name, &journal)?;
                             vec![
                                 Token::Bytes(journal),
                                 Hash::from(<[u8; 32]>::from(receipt_metadata.post.digest()))
                                     .into_token(),
-                                Token::Bytes(ethers::abi::encode(&[tokenize_snark_proof(
-                                    &snark_proof,
-                                )?])),
+                                Token::Bytes(snark_seal(&snark_proof)?),
+                                Token::Bytes(public_values),
                             ]
                         }
                         _ => {
@@ -180,9 +1261,7 @@ async fn main() -> anyhow::Result<()> {
                     }
                 }
                 // No input. Return the Ethereum ABI encoded bytes32 image ID.
-                None => vec![
-                    Hash::from(bytemuck::cast::<_, [u8; 32]>(guest_entry.image_id)).into_token(),
-                ],
+                None => vec![Hash::from(<[u8; 32]>::from(guest.image_id)).into_token()],
             };
 
             let output = hex::encode(ethers::abi::encode(&output_tokens));
@@ -191,8 +1270,480 @@ async fn main() -> anyhow::Result<()> {
                 .flush()
                 .context("failed to flush stdout buffer")?;
         }
+        Command::Verify {
+            guest_binary,
+            receipt,
+        } => {
+            let guest = registry
+                .resolve(&guest_binary)
+                .context("failed to resolve guest entry")?;
+            let envelope = ReceiptEnvelope::from_file(&receipt)?;
+            envelope.verify(guest.image_id)?;
+            elog!(
+                "Verified {:?} receipt for guest {}",
+                envelope.format(),
+                guest.name
+            );
+            print!("{}", hex::encode(envelope.journal()));
+            std::io::stdout()
+                .flush()
+                .context("failed to flush stdout buffer")?;
+        }
+        Command::Capabilities {
+            provers,
+            prover_cluster,
+            pools,
+        } => {
+            let chains = match pools {
+                Some(path) => CatalogConfig::load(&path)?.chains(),
+                None => Vec::new(),
+            };
+            let capabilities = Capabilities::new(
+                &registry,
+                dev_mode,
+                &prover_kinds(provers, &prover_cluster),
+                &bonsai,
+                chains,
+            );
+            println!("{}", serde_json::to_string_pretty(&capabilities)?);
+        }
+        Command::ExportGuests { dir } => {
+            for guest in export_dir(&registry, &dir)? {
+                println!(
+                    "Exported guest {} ({})",
+                    guest.name,
+                    hex::encode(guest.image_id)
+                );
+            }
+        }
+        Command::SignGuests { dir, private_key } => {
+            register_secret(private_key.trim_start_matches("0x"));
+            let wallet: LocalWallet = private_key
+                .parse()
+                .context("Failed to parse the signing key")?;
+            for guest in sign_dir(&dir, &wallet).await? {
+                println!(
+                    "Signed guest {} ({}) as {:?}",
+                    guest.name,
+                    hex::encode(guest.image_id),
+                    wallet.address()
+                );
+            }
+        }
+        Command::TestGuest {
+            guest,
+            cases,
+            dump_trace,
+        } => {
+            let path = Path::new(&guest);
+            let guest = match registry.resolve(&guest) {
+                Ok(guest) => guest,
+                Err(_) if path.is_file() => Arc::new(Guest::from_file(path)?),
+                Err(err) => return Err(err),
+            };
+            let cases = load_cases(&cases)?;
+            let pool = ImagePool::default().with_limits(exec_limits);
+            let mut failed = 0;
+            for (index, case) in cases.iter().enumerate() {
+                let outcome = run_case(&guest, case, &pool);
+                println!("{}: {outcome}", case.name);
+                if outcome.passed() {
+                    continue;
+                }
+                failed += 1;
+                if let Some(root) = &dump_trace {
+                    match dump_case(&guest, index, case, &pool, root) {
+                        Ok(dir) => println!("{}: dumped to {}", case.name, dir.display()),
+                        Err(err) => println!("{}: not dumped: {err:#}", case.name),
+                    }
+                }
+            }
+            println!(
+                "{} of {} cases passed for guest {} ({})",
+                cases.len() - failed,
+                cases.len(),
+                guest.name,
+                hex::encode(guest.image_id)
+            );
+            if failed > 0 {
+                anyhow::bail!("{failed} guest test cases failed");
+            }
+        }
+        Command::ReplayTrace { dump, tail } => {
+            let dump = TraceDump::open(&dump)?;
+            let guest = dump.guest()?;
+            let input = dump.input()?;
+            let recorded = dump.trace()?;
+            let pool = ImagePool::default().with_limits(dump.manifest.limits());
+            let mut trace = TraceTail::new(dump.manifest.trace_events);
+            let (result, logs) = pool.execute_traced(&guest, &input, &mut trace);
+            for line in logs.stdout.lines() {
+                elog!("guest stdout: {line}");
+            }
+            for line in logs.stderr.lines() {
+                elog!("guest stderr: {line}");
+            }
+            if let Ok((_, stats)) = &result {
+                elog!("{stats}");
+            }
+            let replayed: Vec<_> = trace.events().cloned().collect();
+            for event in &replayed[replayed.len().saturating_sub(tail)..] {
+                println!("{}", serde_json::to_string(event)?);
+            }
+            let outcome = ExecutionOutcome::of(&result);
+            if outcome != dump.manifest.outcome {
+                anyhow::bail!(
+                    "replay diverged from the dump: it ended with {outcome}, the dump with {}",
+                    dump.manifest.outcome
+                );
+            }
+            if let Some(event) = replayed.iter().zip(&recorded).position(|(a, b)| a != b) {
+                anyhow::bail!("replayed trace diverges from the dump at event {event} of its tail");
+            }
+            elog!(
+                "Replay of guest {} reproduced the dumped {outcome}",
+                guest.name
+            );
+        }
+        Command::Shell { eth_node } => {
+            let mut shell = Shell::new(
+                Arc::new(registry),
+                Arc::new(ImagePool::default().with_limits(exec_limits)),
+                dev_mode,
+            )
+            .with_bonsai(bonsai);
+            if let Some(eth_node) = eth_node {
+                let provider = Provider::<Ws>::connect(&eth_node)
+                    .await
+                    .context(format!("Failed to connect to {eth_node}"))?;
+                shell = shell.with_provider(Arc::new(provider));
+            }
+            shell.run().await?;
+        }
+        Command::Snapshot {
+            pool,
+            out,
+            block,
+            tick_words,
+            eth_node,
+        } => {
+            let provider = Provider::<Ws>::connect(&eth_node)
+                .await
+                .context(format!("Failed to connect to {eth_node}"))?;
+            let block = match block {
+                Some(block) => block,
+                None => provider.get_block_number().await?.as_u64(),
+            };
+            let snapshot = PoolSnapshot::fetch(Arc::new(provider), pool, block, tick_words).await?;
+            snapshot.save(&out)?;
+            elog!(
+                "Saved pool {pool:?} at block {block} with {} ticks and {} observations",
+                snapshot.ticks.len(),
+                snapshot.observations.len()
+            );
+        }
+        Command::DiffSnapshot { a, b } => {
+            let diffs = diff(&PoolSnapshot::load(&a)?, &PoolSnapshot::load(&b)?);
+            for diff in &diffs {
+                println!("{diff}");
+            }
+            if !diffs.is_empty() {
+                anyhow::bail!("snapshots differ in {} fields", diffs.len());
+            }
+            elog!("Snapshots are identical");
+        }
+        Command::ExportEvidence { request_id, out } => {
+            let evidence = evidence
+                .ok_or_else(|| anyhow::anyhow!("exporting evidence requires --evidence-dir"))?;
+            let request_id = request_id.trim_start_matches("0x").to_ascii_lowercase();
+            let out = out.unwrap_or_else(|| PathBuf::from(format!("evidence-{request_id}.tar")));
+            let manifest = evidence.export(&request_id, &registry, &out)?;
+            elog!(
+                "Wrote {} files of evidence of request {request_id} to {}",
+                manifest.files.len() + 1,
+                out.display()
+            );
+        }
+        Command::JournalSol { guest, out } => {
+            let library = journal_library(&guest)?;
+            match out {
+                Some(out) => std::fs::write(&out, library)
+                    .context(format!("Failed to write {}", out.display()))?,
+                None => print!("{library}"),
+            }
+        }
+        Command::TwapInput {
+            pool,
+            from,
+            to,
+            proof_cache,
+            proof_sources,
+            single_block,
+            finality,
+            eth_node,
+        } => {
+            let provider = Arc::new(
+                Provider::<Ws>::connect(&eth_node)
+                    .await
+                    .context(format!("Failed to connect to {eth_node}"))?,
+            );
+            let source = proof_source(provider.clone(), proof_sources.as_deref()).await?;
+            let mut fetcher = TwapFetcher::new(provider, source).with_finality(finality);
+            if let Some(dir) = &proof_cache {
+                fetcher = fetcher.with_cache(Arc::new(ProofCache::open(dir)?));
+            }
+            if single_block {
+                fetcher = fetcher.with_single_block();
+            }
+            let input = fetcher.fetch(pool, from, to).await?;
+            elog!(
+                "Proved observations of pool {pool:?} at {} blocks meeting the {finality} policy",
+                input.anchors.len()
+            );
+            println!("0x{}", hex::encode(input.encode()?));
+        }
+        Command::Regenerate {
+            guest,
+            block,
+            journal,
+            tx,
+            proof_cache,
+            proof_sources,
+            eth_node,
+        } => {
+            let guest = registry
+                .resolve(&guest)
+                .context("failed to resolve guest entry")?;
+            if !guest.name.eq_ignore_ascii_case("TWAP") {
+                bail!("cannot rebuild inputs of {}, only of TWAP", guest.name);
+            }
+            let provider = Arc::new(
+                Provider::<Ws>::connect(&eth_node)
+                    .await
+                    .context(format!("Failed to connect to {eth_node}"))?,
+            );
+            let submitted = match (journal, tx) {
+                (Some(journal), _) => hex::decode(journal.trim_start_matches("0x"))
+                    .context("Failed to decode journal")?,
+                (None, Some(tx)) => {
+                    let tx = provider
+                        .get_transaction(tx)
+                        .await
+                        .context(format!("Failed to get transaction {tx:?}"))?
+                        .ok_or_else(|| anyhow::anyhow!("transaction {tx:?} not found"))?;
+                    submitted_journal(&tx.input)?
+                }
+                (None, None) => bail!("either a journal or a submission is required"),
+            };
+            let claimed = TwapJournal::decode(&submitted)?;
+            let source = proof_source(provider.clone(), proof_sources.as_deref()).await?;
+            let mut fetcher = TwapFetcher::new(provider.clone(), source);
+            if let Some(dir) = &proof_cache {
+                fetcher = fetcher.with_cache(Arc::new(ProofCache::open(dir)?));
+            }
+            let input = twap_input(provider.as_ref(), &fetcher, &claimed, block).await?;
+            elog!(
+                "Rebuilt the input of pool {:?} over [{}, {}] at {} blocks",
+                claimed.pool,
+                claimed.observed_from,
+                claimed.observed_to,
+                input.anchors.len()
+            );
+            let pool = Arc::new(ImagePool::default().with_limits(exec_limits));
+            let output = run_guest(&guest, input.encode()?, &pool, &bonsai, dev_mode).await?;
+            let regenerated = match &output {
+                Output::Execution { journal }
+                | Output::Bonsai { journal, .. }
+                | Output::Stark { journal, .. } => journal,
+            };
+            let diffs = compare_journals(&guest.name, &submitted, regenerated)?;
+            for diff in &diffs {
+                println!("{diff}");
+            }
+            if !diffs.is_empty() {
+                bail!(
+                    "regenerated journal differs from the submitted one in {} fields",
+                    diffs.len()
+                );
+            }
+            elog!("Regenerated journal matches the submitted one");
+        }
+        Command::HistoryInput {
+            pool,
+            previous_journal,
+            proof_cache,
+            proof_sources,
+            finality,
+            eth_node,
+        } => {
+            let state = match &previous_journal {
+                Some(journal) => {
+                    let journal = hex::decode(journal.trim_start_matches("0x"))
+                        .context("Failed to decode previous journal")?;
+                    let state = HistoryState::from_journal(&journal)?;
+                    if state.pool != pool {
+                        bail!("previous journal is of pool {:?}", state.pool);
+                    }
+                    state
+                }
+                None => HistoryState::genesis(pool),
+            };
+            let provider = Arc::new(
+                Provider::<Ws>::connect(&eth_node)
+                    .await
+                    .context(format!("Failed to connect to {eth_node}"))?,
+            );
+            let source = proof_source(provider.clone(), proof_sources.as_deref()).await?;
+            let mut fetcher = HistoryFetcher::new(provider, source).with_finality(finality);
+            if let Some(dir) = &proof_cache {
+                fetcher = fetcher.with_cache(Arc::new(ProofCache::open(dir)?));
+            }
+            let input = fetcher.fetch(&state).await?;
+            elog!(
+                "Proved {} observations of pool {pool:?} extending history {:?} of {} observations",
+                input.observations.len(),
+                state.root(),
+                state.count
+            );
+            println!("0x{}", hex::encode(input.encode()?));
+        }
+        Command::ReserveInput {
+            vault,
+            positions,
+            factory,
+            init_code_hash,
+            proof_cache,
+            proof_sources,
+            finality,
+            eth_node,
+        } => {
+            let positions: Vec<VaultPosition> = serde_json::from_slice(
+                &std::fs::read(&positions)
+                    .context(format!("Failed to read {}", positions.display()))?,
+            )
+            .context(format!("Failed to parse {}", positions.display()))?;
+            let provider = Arc::new(
+                Provider::<Ws>::connect(&eth_node)
+                    .await
+                    .context(format!("Failed to connect to {eth_node}"))?,
+            );
+            let source = proof_source(provider.clone(), proof_sources.as_deref()).await?;
+            let mut fetcher =
+                ReserveFetcher::new(provider, source, factory)?.with_finality(finality);
+            if let Some(init_code_hash) = init_code_hash {
+                fetcher = fetcher.with_init_code_hash(init_code_hash);
+            }
+            if let Some(dir) = &proof_cache {
+                fetcher = fetcher.with_cache(Arc::new(ProofCache::open(dir)?));
+            }
+            let input = fetcher.fetch(vault, &positions).await?;
+            elog!(
+                "Proved {} positions of vault {vault:?} in {} pools at a block meeting the {finality} policy",
+                positions.len(),
+                input.pools.len()
+            );
+            println!("0x{}", hex::encode(input.encode()?));
+        }
+        Command::CycleInput {
+            start,
+            pools,
+            factory,
+            init_code_hash,
+            proof_cache,
+            proof_sources,
+            finality,
+            eth_node,
+        } => {
+            let provider = Arc::new(
+                Provider::<Ws>::connect(&eth_node)
+                    .await
+                    .context(format!("Failed to connect to {eth_node}"))?,
+            );
+            let source = proof_source(provider.clone(), proof_sources.as_deref()).await?;
+            let mut fetcher = CycleFetcher::new(provider, source, factory)?.with_finality(finality);
+            if let Some(init_code_hash) = init_code_hash {
+                fetcher = fetcher.with_init_code_hash(init_code_hash);
+            }
+            if let Some(dir) = &proof_cache {
+                fetcher = fetcher.with_cache(Arc::new(ProofCache::open(dir)?));
+            }
+            let input = fetcher.fetch(start, &pools).await?;
+            elog!(
+                "Proved prices of {} pools at a block meeting the {finality} policy",
+                input.legs.len()
+            );
+            println!("0x{}", hex::encode(input.encode()?));
+        }
+        Command::SampleBlocks {
+            from,
+            seed_block,
+            count,
+            finality,
+            eth_node,
+        } => {
+            let provider = Provider::<Ws>::connect(&eth_node)
+                .await
+                .context(format!("Failed to connect to {eth_node}"))?;
+            let plan = plan_sample(&provider, finality, from, seed_block, count).await?;
+            elog!(
+                "Block {seed_block} seeds a sample of {} blocks from {from}",
+                plan.blocks.len()
+            );
+            println!("{}", serde_json::to_string_pretty(&plan)?);
+        }
+        Command::Doctor {
+            relay_address,
+            verifier_address,
+            eth_node,
+            eth_chain_id,
+            private_key,
+            min_balance,
+            store_dir,
+            store_key,
+            store_kms_key_ciphertext,
+        } => {
+            if let Some(private_key) = &private_key {
+                register_secret(private_key.trim_start_matches("0x"));
+            }
+            if let Some(store_key) = &store_key {
+                register_secret(store_key);
+            }
+            // Opening the store creates its directory, which a check must not.
+            let store = match store_dir {
+                Some(dir) if !dir.is_dir() => bail!("no store at {}", dir.display()),
+                Some(dir) => {
+                    let cipher = store_cipher(store_key, store_kms_key_ciphertext).await?;
+                    Some(Store::open(&dir, cipher)?)
+                }
+                None => None,
+            };
+            let mut contracts = vec![("relay contract", relay_address)];
+            contracts.extend(verifier_address.map(|address| ("verifier contract", address)));
+            let config = DoctorConfig {
+                bonsai_api_url: args.global_opts.bonsai_api_url,
+                bonsai_api_key: args.global_opts.bonsai_api_key,
+                dev_mode,
+                eth_node,
+                eth_chain_id,
+                private_key,
+                min_balance,
+                contracts,
+            };
+            let checks = diagnose(&registry, &config, store.as_ref()).await;
+            for check in &checks {
+                println!("{}", redact::redact(&check.to_string()));
+            }
+            let failed = checks
+                .iter()
+                .filter(|check| check.status == Status::Fail)
+                .count();
+            if failed > 0 {
+                anyhow::bail!("{failed} of {} checks failed", checks.len());
+            }
+        }
         Command::Upload { guest_binary } => {
             let image_ids = upload_images(
+                &registry,
                 guest_binary,
                 &args.global_opts.bonsai_api_url,
                 &args.global_opts.bonsai_api_key,
@@ -212,12 +1763,423 @@ async fn main() -> anyhow::Result<()> {
                 .flush()
                 .context("failed to flush stdout buffer")?;
         }
+        Command::Serve {
+            listen,
+            dedup_window_mins,
+            max_sessions_per_tenant,
+            session_retention_secs,
+            tenants: tenants_path,
+            operator_key,
+            store_dir,
+            store_key,
+            store_kms_key_ciphertext,
+            compress_receipts,
+            prover_cluster,
+            cluster_secret,
+            provers,
+            eth_node,
+            tokens: tokens_path,
+            pools,
+            update_ttl_secs,
+            update_signing_key,
+            transmitter_key,
+            dead_letter_dir,
+            lease_dir,
+            lease_ttl_secs,
+            slo,
+            alerts,
+            queues,
+            env_config: _,
+        } => {
+            if requester_policy.is_some() && tenants_path.is_none() {
+                bail!("--requester-policy needs --tenants, whose requesters API requests are made for");
+            }
+            if let Some(store_key) = &store_key {
+                register_secret(store_key);
+            }
+            if let Some(key) = &operator_key {
+                register_secret(key);
+            }
+            if let Some(key) = &update_signing_key {
+                register_secret(key.trim_start_matches("0x"));
+            }
+            if let Some(key) = &transmitter_key {
+                register_secret(key.trim_start_matches("0x"));
+            }
+            let tenants = Arc::new(RwLock::new(match &tenants_path {
+                Some(path) => Tenants::load(path)?,
+                None => Tenants::open(),
+            }));
+            if let Some(path) = tenants_path {
+                reloader = reloader.with_tenants(path, tenants.clone());
+            }
+            let cipher = store_cipher(store_key, store_kms_key_ciphertext).await?;
+            let mut store = store_dir.map(|dir| Store::open(&dir, cipher)).transpose()?;
+            if compress_receipts {
+                store = store.map(Store::with_receipt_compression);
+            }
+            let pool = Arc::new(ImagePool::default().with_limits(exec_limits));
+            let prover_kinds = prover_kinds(provers.clone(), &prover_cluster);
+            let prover = prover_backend(provers, prover_cluster, cluster_secret, &pool, &bonsai)?;
+            let tokens = match eth_node {
+                Some(eth_node) => {
+                    let provider = Provider::<Ws>::connect(&eth_node)
+                        .await
+                        .context(format!("Failed to connect to {eth_node}"))?;
+                    let mut resolver = TokenResolver::new(Arc::new(provider)).await?;
+                    if let Some(path) = tokens_path {
+                        resolver = resolver.with_known_file(&path)?;
+                    }
+                    Some(Arc::new(resolver))
+                }
+                None => None,
+            };
+            let queues = queues
+                .as_deref()
+                .map(Queues::load)
+                .transpose()?
+                .map(Arc::new);
+            let metrics = match slo {
+                Some(path) => {
+                    let metrics = Metrics::load(&path)?;
+                    for guest in metrics.guests() {
+                        let resolved = registry
+                            .resolve(guest)
+                            .context(format!("Invalid SLO file {}", path.display()))?;
+                        if resolved.name != guest {
+                            bail!(
+                                "SLO file {} must name guest {} by its name",
+                                path.display(),
+                                resolved.name
+                            );
+                        }
+                    }
+                    Some(Arc::new(metrics))
+                }
+                None => None,
+            };
+            let registry = Arc::new(registry);
+            let mut updates = None;
+            let catalog = match pools {
+                Some(path) => {
+                    let mut pool_updates = PriceUpdates::new(Duration::from_secs(update_ttl_secs));
+                    if let Some(key) = update_signing_key {
+                        pool_updates = pool_updates.with_signer(
+                            key.trim_start_matches("0x")
+                                .parse::<LocalWallet>()
+                                .context("Failed to parse update signing key")?,
+                        );
+                    }
+                    let pool_updates = Arc::new(pool_updates);
+                    updates = Some(pool_updates.clone());
+                    let mut scheduler = Scheduler::new(pool.clone()).with_bonsai(bonsai.clone());
+                    if let Some(dir) = &lease_dir {
+                        scheduler = scheduler
+                            .with_run_log(RunLog::open(&dir.join("runs"))?)
+                            .with_leases(
+                                Leases::open(
+                                    &dir.join("leases"),
+                                    Duration::from_secs(lease_ttl_secs),
+                                )?
+                                .with_max_clock_skew(max_clock_skew),
+                            );
+                    }
+                    if let Some(metrics) = &metrics {
+                        scheduler = scheduler.with_metrics(metrics.clone());
+                    }
+                    if let Some(index) = &receipt_index {
+                        scheduler = scheduler.with_receipt_index(index.clone());
+                    }
+                    if let Some(queues) = &queues {
+                        scheduler = scheduler.with_queues(queues.clone());
+                    }
+                    if let Some(path) = &alerts {
+                        scheduler = scheduler.with_alerts(Arc::new(Alerts::load(path)?));
+                    }
+                    let transmission = match transmitter_key {
+                        Some(private_key) => Some(Transmission {
+                            private_key,
+                            dead_letters: dead_letter_dir
+                                .as_deref()
+                                .map(DeadLetters::open)
+                                .transpose()?
+                                .map(Arc::new),
+                        }),
+                        None => None,
+                    };
+                    let catalog = Arc::new(
+                        PoolCatalog::open(
+                            &path,
+                            registry.clone(),
+                            scheduler,
+                            dev_mode,
+                            Some(pool_updates),
+                            transmission,
+                        )
+                        .await?,
+                    );
+                    for discovery in Discovery::from_catalog(&catalog).await? {
+                        tokio::spawn(discovery.run());
+                    }
+                    Some(catalog)
+                }
+                None => None,
+            };
+            if let Some(catalog) = &catalog {
+                reloader = reloader.with_catalog(catalog.clone());
+            }
+            let reloader = (!reloader.is_empty()).then(|| Arc::new(reloader));
+            let chains = catalog
+                .as_ref()
+                .map(|catalog| catalog.chains())
+                .unwrap_or_default();
+            let capabilities =
+                Capabilities::new(&registry, dev_mode, &prover_kinds, &bonsai, chains);
+            let state = AppState {
+                registry,
+                pool,
+                dedup: Deduplicator::new(Duration::from_secs(dedup_window_mins * 60)),
+                tenants,
+                store,
+                requesters: requester_policy,
+                dev_mode,
+                operator_key,
+                paused: AtomicBool::new(false),
+                reloader: reloader.clone(),
+                artifacts,
+                bonsai,
+                prover,
+                tokens,
+                catalog,
+                sessions: Sessions::new(
+                    max_sessions_per_tenant,
+                    Duration::from_secs(session_retention_secs),
+                ),
+                metrics,
+                capabilities,
+                index: receipt_index,
+                queues,
+                webhooks: Webhooks::new()?,
+                shadow: (args.global_opts.shadow_sample_rate > 0.0)
+                    .then(|| Arc::new(ShadowVerifier::new(args.global_opts.shadow_sample_rate))),
+                canary,
+            };
+            let catalog = state.catalog.clone();
+            let mut router = router(Arc::new(state));
+            if let Some(updates) = updates {
+                router = router.merge(updates_router(updates));
+            }
+            let server = serve_router_until(listen, router, shutdown_signal());
+            match reloader {
+                Some(reloader) => tokio::select! {
+                    res = server => res?,
+                    res = reloader.run() => res?,
+                },
+                None => server.await?,
+            }
+            if let Some(catalog) = catalog {
+                catalog.shutdown().await;
+            }
+            // Threads still polling Bonsai sessions would hold the runtime
+            // open until the pod is killed; their sessions are checkpointed
+            // for the next holder of their leases.
+            elog!("Relay stopped");
+            std::process::exit(0);
+        }
+        Command::Loadtest {
+            guest,
+            inputs,
+            url,
+            api_key,
+            endpoint,
+            rate,
+            duration_secs,
+            max_in_flight,
+            timeout_secs,
+        } => {
+            if let Some(api_key) = &api_key {
+                register_secret(api_key);
+            }
+            let test = LoadTest {
+                url: url.trim_end_matches('/').to_string(),
+                api_key,
+                guest,
+                inputs: load_inputs(&inputs)?,
+                endpoint,
+                rate,
+                duration: Duration::from_secs(duration_secs),
+                max_in_flight,
+                timeout: Duration::from_secs(timeout_secs),
+            };
+            println!("{}", test.run().await?);
+        }
+        Command::ProverWorker {
+            listen,
+            cluster_secret,
+        } => serve_worker(listen, ClusterSecret::new(&cluster_secret)?).await?,
         Command::Run {
             relay_address,
             eth_node,
             eth_chain_id,
             private_key,
+            fee_escrow,
+            min_fee,
+            operator_mode,
+            max_submission_gas,
+            blob_pricing,
+            max_calldata_premium_pct,
+            max_blob_hold_secs,
+            gas_strategy,
+            chain_kind,
+            max_result_age_secs,
+            coalesce_window_secs,
+            dedup_window_mins,
+            approval_policy,
+            guardian,
+            guardian_poll_secs,
+            post_process,
+            admin_listen,
+            operator_key,
         } => {
+            register_secret(private_key.trim_start_matches("0x"));
+            if let Some(key) = &operator_key {
+                register_secret(key);
+            }
+            if post_process.is_some() && !dev_mode {
+                bail!(
+                    "--post-process needs --risc0-dev-mode, as the relay contract verifies seals \
+                     against the guest's own journal"
+                );
+            }
+            if fee_escrow.is_some()
+                || operator_mode
+                || max_submission_gas.is_some()
+                || blob_pricing
+                || gas_strategy.is_some()
+                || max_result_age_secs.is_some()
+                || approval_policy.is_some()
+                || guardian.is_some()
+                || requester_policy.is_some()
+                || artifacts.is_some()
+                || receipt_index.is_some()
+                || evidence.is_some()
+                || canary.is_some()
+                || post_process.is_some()
+            {
+                let client = connect(&eth_node, eth_chain_id, &private_key).await?;
+                let post_process = match post_process {
+                    Some(path) => {
+                        let resolver = TokenResolver::new(client.clone()).await?;
+                        load_callback_chains(&path, &registry, Some(&resolver)).await?
+                    }
+                    None => HashMap::new(),
+                };
+                let mut listener = Listener::new(
+                    relay_address,
+                    client.clone(),
+                    Arc::new(registry),
+                    Arc::new(ImagePool::default().with_limits(exec_limits)),
+                    dev_mode,
+                )
+                .with_bonsai(bonsai)
+                .with_dedup_window(Duration::from_secs(dedup_window_mins * 60))
+                .with_chain(chain_kind.unwrap_or_else(|| ChainKind::from_chain_id(eth_chain_id)));
+                if let Some(fee_escrow) = fee_escrow {
+                    listener =
+                        listener.with_escrow(Escrow::new(fee_escrow, client.clone(), min_fee));
+                }
+                if let Some(policy) = requester_policy {
+                    listener = listener.with_requester_policy(policy);
+                }
+                if operator_mode {
+                    listener = listener.with_local_verification();
+                }
+                if let Some(artifacts) = artifacts {
+                    listener = listener.with_artifacts(artifacts);
+                }
+                if let Some(index) = receipt_index {
+                    listener = listener
+                        .with_receipt_index(index, Duration::from_secs(coalesce_window_secs));
+                }
+                if let Some(evidence) = evidence {
+                    listener = listener.with_evidence(evidence);
+                }
+                if let Some(canary) = canary {
+                    listener = listener.with_canary(canary);
+                }
+                if !post_process.is_empty() {
+                    listener = listener.with_post_process(post_process);
+                }
+                let strategy = match &gas_strategy {
+                    Some(path) => GasStrategy::load(path)?,
+                    None => GasStrategy {
+                        max_gas: max_submission_gas,
+                        blob_pricing,
+                        max_calldata_premium_pct,
+                        max_hold_secs: max_blob_hold_secs,
+                    },
+                };
+                let strategy = Arc::new(RwLock::new(strategy));
+                listener = listener.with_gas_strategy(strategy.clone());
+                if let Some(path) = gas_strategy {
+                    reloader = reloader.with_gas_strategy(path, strategy);
+                }
+                if let Some(max_age) = max_result_age_secs {
+                    listener = listener
+                        .with_max_staleness(Duration::from_secs(max_age))
+                        .with_max_clock_skew(max_clock_skew);
+                }
+                let approvals = approval_policy
+                    .as_deref()
+                    .map(Approvals::load)
+                    .transpose()?
+                    .map(Arc::new);
+                if let Some(approvals) = &approvals {
+                    listener = listener.with_approvals(approvals.clone());
+                }
+                let guardian = match guardian {
+                    Some(address) => Some(Arc::new(
+                        Guardian::new(address, client.clone())
+                            .await?
+                            .with_poll_interval(Duration::from_secs(guardian_poll_secs)),
+                    )),
+                    None => None,
+                };
+                if let Some(guardian) = &guardian {
+                    listener = listener.with_guardian(guardian.clone());
+                    tokio::spawn(guardian.clone().run());
+                }
+                let listener = Arc::new(listener).run();
+                if approvals.is_none() && guardian.is_none() && reloader.is_empty() {
+                    return listener.await;
+                }
+                let mut admin = Router::new();
+                if let Some(approvals) = approvals {
+                    admin = admin.merge(approval_router(approvals));
+                }
+                if let Some(guardian) = guardian {
+                    admin = admin.merge(guardian_router(guardian));
+                }
+                let reloader = Arc::new(reloader);
+                if !reloader.is_empty() {
+                    match &operator_key {
+                        Some(key) => {
+                            admin = admin.merge(key_only(reload_router(reloader.clone()), key));
+                        }
+                        None => elog!(
+                            "Reloading through the admin API needs --operator-key; reload with \
+                             SIGHUP instead"
+                        ),
+                    }
+                }
+                return tokio::try_join!(
+                    listener,
+                    serve_router(admin_listen, admin),
+                    reloader.run()
+                )
+                .map(|_| ());
+            }
+
             let relayer = Relayer {
                 rest_api: true,
                 dev_mode: dev_mode,
@@ -235,6 +2197,7 @@ async fn main() -> anyhow::Result<()> {
 
             // Upload all locally defined images.
             upload_images(
+                &registry,
                 None,
                 &args.global_opts.bonsai_api_url,
                 &args.global_opts.bonsai_api_key,
@@ -248,44 +2211,104 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// The cipher of a store, from `--store-key` or `--store-kms-key-ciphertext`.
+async fn store_cipher(
+    key: Option<String>,
+    kms_ciphertext: Option<String>,
+) -> anyhow::Result<Option<Cipher>> {
+    match (key, kms_ciphertext) {
+        (Some(key), _) => Ok(Some(Cipher::from_hex(&key)?)),
+        (None, Some(ciphertext)) => {
+            let ciphertext = hex::decode(ciphertext.trim_start_matches("0x"))
+                .context("Failed to decode store key ciphertext")?;
+            Ok(Some(Cipher::from_kms(&ciphertext).await?))
+        }
+        (None, None) => Ok(None),
+    }
+}
+
+/// Backends `--provers` and `--prover-cluster` dispatch proofs to: the
+/// cluster if only it is given, Bonsai if neither is.
+fn prover_kinds(mut kinds: Vec<ProverKind>, cluster: &[SocketAddr]) -> Vec<ProverKind> {
+    if kinds.is_empty() {
+        kinds.push(if cluster.is_empty() {
+            ProverKind::Bonsai
+        } else {
+            ProverKind::Cluster
+        });
+    }
+    kinds.sort();
+    kinds.dedup();
+    kinds
+}
+
+/// The backend proving API requests, if not the default of Bonsai.
+fn prover_backend(
+    kinds: Vec<ProverKind>,
+    cluster: Vec<SocketAddr>,
+    cluster_secret: Option<String>,
+    pool: &Arc<ImagePool>,
+    bonsai: &BonsaiBackend,
+) -> anyhow::Result<Option<Arc<dyn ProverBackend>>> {
+    if kinds.is_empty() && cluster.is_empty() {
+        return Ok(None);
+    }
+    let mut backends: Vec<(String, Arc<dyn ProverBackend>)> = Vec::new();
+    for kind in prover_kinds(kinds, &cluster) {
+        let backend: Arc<dyn ProverBackend> = match kind {
+            ProverKind::Bonsai => Arc::new(bonsai.clone()),
+            ProverKind::Local => Arc::new(LocalBackend::new(pool.clone())),
+            ProverKind::Cluster => {
+                let Some(secret) = &cluster_secret else {
+                    anyhow::bail!("--cluster-secret is required to prove on a cluster");
+                };
+                let secret = ClusterSecret::new(secret)?;
+                Arc::new(ClusterBackend::new(pool.clone(), cluster.clone(), secret)?)
+            }
+        };
+        backends.push((format!("{kind:?}").to_lowercase(), backend));
+    }
+    if backends.len() == 1 {
+        return Ok(backends.pop().map(|(_, backend)| backend));
+    }
+    Ok(Some(Arc::new(Dispatcher::new(backends)?)))
+}
+
 /// Upload a single specified image, or, if guest_binary is None, upload all
-/// images in the GUEST_LIST. Returns a list of uploaded image IDs.
+/// images in the registry. Returns a list of uploaded image IDs.
 async fn upload_images(
+    registry: &GuestRegistry,
     guest_binary: Option<String>,
     bonsai_api_url: &str,
     bonsai_api_key: &str,
 ) -> anyhow::Result<Vec<Digest>> {
     // Create a list of either the single binary name to upload or all guests.
-    let guest_entries = guest_binary.map_or_else(
-        || Ok::<_, anyhow::Error>(GUEST_LIST.iter().cloned().collect::<Vec<_>>()),
-        |name| Ok(vec![resolve_guest_entry(&GUEST_LIST, &name)?]),
+    let guests = guest_binary.map_or_else(
+        || Ok::<_, anyhow::Error>(registry.iter().cloned().collect::<Vec<_>>()),
+        |name| Ok(vec![registry.resolve(&name)?]),
     )?;
 
     // Upload each guest binary.
     let mut image_ids = Vec::<Digest>::new();
-    for guest_entry in guest_entries.iter() {
-        // Search list for requested binary name
-        let image_id = hex::encode(Vec::from(bytemuck::cast::<[u32; 8], [u8; 32]>(
-            guest_entry.image_id,
-        )));
+    for guest in guests.iter() {
+        let image_id = hex::encode(guest.image_id);
+        let elf = guest.elf()?;
+
+        // Make sure the ELF was not corrupted on its way into the binary.
+        verify_image_id(&elf, guest.image_id)
+            .context(format!("refusing to upload guest {}", guest.name))?;
 
         // upload binary to Bonsai
         let bonsai_client =
             get_client_from_parts(bonsai_api_url.to_string(), bonsai_api_key.to_string()).await?;
         let img_id = image_id.clone();
 
-        match put_image(
-            bonsai_client.clone(),
-            img_id.clone(),
-            guest_entry.elf.to_vec(),
-        )
-        .await
-        {
+        match put_image(bonsai_client.clone(), img_id.clone(), elf.to_vec()).await {
             Ok(()) | Err(SdkErr::ImageIdExists) => Ok::<_, anyhow::Error>(()),
             Err(err) => Err(err.into()),
         }?;
 
-        image_ids.push(guest_entry.image_id.into());
+        image_ids.push(guest.image_id);
     }
 
     Ok(image_ids)
@@ -0,0 +1,252 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end proof latency per guest, tracked against latency objectives
+//! operators commit to for their oracle consumers. Each objective targets the
+//! p95 latency over a rolling window: at most 5% of the window's proofs may
+//! take longer than the target or fail. Burn rates compare the share of such
+//! proofs to that 5% budget, so a burn rate above 1 spends the budget faster
+//! than the window allows. The trace IDs of the latest missed proofs are
+//! reported as exemplars, to find their requests in the logs.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    path::Path,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::trace;
+
+/// Share of proofs an objective allows to miss its target.
+const ERROR_BUDGET: f64 = 0.05;
+
+/// Missed proofs whose trace IDs are reported per objective.
+const MAX_EXEMPLARS: usize = 5;
+
+/// Recent window over which burn rates are reported alongside the
+/// objective's own window, to catch fast burns early.
+const SHORT_WINDOW: Duration = Duration::from_secs(3600);
+
+fn default_window_hours() -> u64 {
+    24
+}
+
+/// Latency objective of a guest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Slo {
+    /// Target p95 end-to-end latency, from request or scheduled run to
+    /// proof, in seconds.
+    pub target_p95_secs: u64,
+    /// Rolling window the objective is measured over, in hours.
+    #[serde(default = "default_window_hours")]
+    pub window_hours: u64,
+}
+
+impl Slo {
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_hours * 3600)
+    }
+}
+
+/// Objectives by guest name, as read from the file given by --slo.
+pub type SloConfig = HashMap<String, Slo>;
+
+/// How a guest is doing against its objective.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SloState {
+    /// No proofs in the window.
+    NoData,
+    Met,
+    /// Met over the window, but burning budget faster than it allows over
+    /// the last hour.
+    AtRisk,
+    Breached,
+}
+
+/// Status of a guest's objective, as served by the admin API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SloStatus {
+    pub guest: String,
+    pub target_p95_secs: u64,
+    pub window_hours: u64,
+    pub state: SloState,
+    /// Proofs completed or failed in the window.
+    pub proofs: usize,
+    /// Proofs in the window that failed.
+    pub failed: usize,
+    /// Proofs in the window that failed or took longer than the target.
+    pub missed: usize,
+    /// p95 latency of the window's successful proofs, in seconds.
+    pub p95_secs: Option<f64>,
+    /// Burn rate over the objective's window.
+    pub burn_rate: f64,
+    /// Burn rate over the last hour.
+    pub short_burn_rate: f64,
+    /// Latest traced proofs in the window that missed the target, newest
+    /// first.
+    pub exemplars: Vec<Exemplar>,
+}
+
+/// A proof that missed its objective, by the trace ID of its request or run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub latency_secs: f64,
+    pub succeeded: bool,
+}
+
+/// A completed or failed proof.
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    succeeded: bool,
+    trace_id: Option<String>,
+}
+
+/// Records proof latencies of the guests with an objective and reports
+/// their status.
+pub struct Metrics {
+    slos: SloConfig,
+    samples: Mutex<HashMap<String, VecDeque<Sample>>>,
+}
+
+impl Metrics {
+    pub fn new(slos: SloConfig) -> Result<Self> {
+        for (guest, slo) in &slos {
+            if slo.target_p95_secs == 0 || slo.window_hours == 0 {
+                bail!("SLO of guest {guest} must have a non-zero target and window");
+            }
+        }
+        Ok(Self {
+            slos,
+            samples: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open SLO file {}", path.display()))?;
+        let slos: SloConfig = serde_json::from_reader(file)
+            .context(format!("Failed to parse SLO file {}", path.display()))?;
+        Self::new(slos)
+    }
+
+    /// Guests with an objective.
+    pub fn guests(&self) -> impl Iterator<Item = &str> {
+        self.slos.keys().map(String::as_str)
+    }
+
+    /// Record a proof of `guest` that finished `latency` after its request
+    /// arrived or its run started, under the current trace ID. Guests
+    /// without an objective are ignored.
+    pub fn record(&self, guest: &str, latency: Duration, succeeded: bool) {
+        let Some(slo) = self.slos.get(guest) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        let samples = samples.entry(guest.to_string()).or_default();
+        samples.push_back(Sample {
+            at: now,
+            latency,
+            succeeded,
+            trace_id: trace::current(),
+        });
+        while matches!(samples.front(), Some(sample) if now.duration_since(sample.at) > slo.window())
+        {
+            samples.pop_front();
+        }
+    }
+
+    /// Status of every objective, by guest name.
+    pub fn status(&self) -> Vec<SloStatus> {
+        let now = Instant::now();
+        let samples = self.samples.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut status: Vec<_> = self
+            .slos
+            .iter()
+            .map(|(guest, slo)| {
+                let window: Vec<&Sample> = samples
+                    .get(guest)
+                    .into_iter()
+                    .flatten()
+                    .filter(|sample| now.duration_since(sample.at) <= slo.window())
+                    .collect();
+                let target = Duration::from_secs(slo.target_p95_secs);
+                let missed = |sample: &&&Sample| !sample.succeeded || sample.latency > target;
+                let burn_rate = |samples: &[&Sample]| match samples.len() {
+                    0 => 0.0,
+                    len => samples.iter().filter(missed).count() as f64 / len as f64 / ERROR_BUDGET,
+                };
+                let recent: Vec<&Sample> = window
+                    .iter()
+                    .copied()
+                    .filter(|sample| now.duration_since(sample.at) <= SHORT_WINDOW)
+                    .collect();
+                let mut latencies: Vec<Duration> = window
+                    .iter()
+                    .filter(|sample| sample.succeeded)
+                    .map(|sample| sample.latency)
+                    .collect();
+                latencies.sort();
+                let p95_secs = latencies
+                    .len()
+                    .checked_sub(1)
+                    .map(|last| latencies[(last as f64 * 0.95).round() as usize].as_secs_f64());
+                let (burn_rate, short_burn_rate) = (burn_rate(&window), burn_rate(&recent));
+                let state = if window.is_empty() {
+                    SloState::NoData
+                } else if burn_rate > 1.0 {
+                    SloState::Breached
+                } else if short_burn_rate > 1.0 {
+                    SloState::AtRisk
+                } else {
+                    SloState::Met
+                };
+                SloStatus {
+                    guest: guest.clone(),
+                    target_p95_secs: slo.target_p95_secs,
+                    window_hours: slo.window_hours,
+                    state,
+                    proofs: window.len(),
+                    failed: window.iter().filter(|sample| !sample.succeeded).count(),
+                    missed: window.iter().filter(missed).count(),
+                    p95_secs,
+                    burn_rate,
+                    short_burn_rate,
+                    exemplars: window
+                        .iter()
+                        .rev()
+                        .filter(missed)
+                        .filter_map(|sample| {
+                            Some(Exemplar {
+                                trace_id: sample.trace_id.clone()?,
+                                latency_secs: sample.latency.as_secs_f64(),
+                                succeeded: sample.succeeded,
+                            })
+                        })
+                        .take(MAX_EXEMPLARS)
+                        .collect(),
+                }
+            })
+            .collect();
+        status.sort_by(|a, b| a.guest.cmp(&b.guest));
+        status
+    }
+}
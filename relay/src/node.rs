@@ -0,0 +1,218 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Node.js bindings, built as a native addon with the `node` feature, see
+//! this crate's package.json. They expose the relay's input builder,
+//! journal decoder and ABI helpers, so TypeScript clients encode inputs and
+//! read results with the same code as the relay itself.
+//!
+//! Values cross the boundary as JSON: integers as decimal strings, and
+//! addresses and bytes as 0x-prefixed hex strings.
+
+use anyhow::{anyhow, bail, Context};
+use ethers::{
+    abi::{self, ParamType, Token},
+    types::{Address, I256, U256},
+};
+use napi::bindgen_prelude::Buffer;
+use napi_derive::napi;
+use serde_json::Value;
+
+use crate::{
+    artifacts::token_json,
+    input::{canonicalize, decode_public, frame_sections, private_section, split_input},
+    schema::{self, input_schema, journal_schema, split_journal_version},
+};
+
+fn js_error(err: anyhow::Error) -> napi::Error {
+    napi::Error::from_reason(format!("{err:#}"))
+}
+
+/// Read a JSON value as an ABI value of type `param`.
+fn json_token(param: &ParamType, value: &Value) -> anyhow::Result<Token> {
+    let text = || -> anyhow::Result<String> {
+        match value {
+            Value::String(text) => Ok(text.clone()),
+            Value::Number(number) => Ok(number.to_string()),
+            _ => bail!("expected a {param} string, got {value}"),
+        }
+    };
+    let items = || -> anyhow::Result<&Vec<Value>> {
+        value
+            .as_array()
+            .ok_or_else(|| anyhow!("expected a {param} array, got {value}"))
+    };
+    Ok(match param {
+        ParamType::Address => Token::Address(
+            text()?
+                .parse::<Address>()
+                .context(format!("invalid address {value}"))?,
+        ),
+        ParamType::Bytes | ParamType::FixedBytes(_) => {
+            let bytes = hex::decode(text()?.trim_start_matches("0x"))
+                .context(format!("invalid hex {value}"))?;
+            match param {
+                ParamType::FixedBytes(size) if bytes.len() != *size => {
+                    bail!("expected {size} bytes, got {}", bytes.len())
+                }
+                ParamType::FixedBytes(_) => Token::FixedBytes(bytes),
+                _ => Token::Bytes(bytes),
+            }
+        }
+        ParamType::Uint(_) => {
+            let text = text()?;
+            let value = match text.strip_prefix("0x") {
+                Some(hex) => U256::from_str_radix(hex, 16).map_err(|err| anyhow!("{err}")),
+                None => U256::from_dec_str(&text).map_err(|err| anyhow!("{err}")),
+            }
+            .context(format!("invalid {param} {text}"))?;
+            Token::Uint(value)
+        }
+        ParamType::Int(_) => {
+            let text = text()?;
+            let value = I256::from_dec_str(&text).context(format!("invalid {param} {text}"))?;
+            Token::Int(value.into_raw())
+        }
+        ParamType::Bool => Token::Bool(
+            value
+                .as_bool()
+                .ok_or_else(|| anyhow!("expected a bool, got {value}"))?,
+        ),
+        ParamType::String => Token::String(text()?),
+        ParamType::Array(inner) => Token::Array(
+            items()?
+                .iter()
+                .map(|item| json_token(inner, item))
+                .collect::<anyhow::Result<_>>()?,
+        ),
+        ParamType::FixedArray(inner, len) => {
+            let items = items()?;
+            if items.len() != *len {
+                bail!("expected {len} items, got {}", items.len());
+            }
+            Token::FixedArray(
+                items
+                    .iter()
+                    .map(|item| json_token(inner, item))
+                    .collect::<anyhow::Result<_>>()?,
+            )
+        }
+        ParamType::Tuple(params) => {
+            let items = items()?;
+            if items.len() != params.len() {
+                bail!(
+                    "expected {} tuple values, got {}",
+                    params.len(),
+                    items.len()
+                );
+            }
+            Token::Tuple(
+                params
+                    .iter()
+                    .zip(items)
+                    .map(|(param, item)| json_token(param, item))
+                    .collect::<anyhow::Result<_>>()?,
+            )
+        }
+    })
+}
+
+/// Build the canonical input of a guest from its values, in the order of
+/// its input schema, and an optional private input, salted at random. The
+/// salt starts the input's private section.
+#[napi]
+pub fn build_input(
+    guest: String,
+    values: Vec<Value>,
+    private_input: Option<Buffer>,
+) -> napi::Result<Buffer> {
+    (|| {
+        let schema =
+            input_schema(&guest).ok_or_else(|| anyhow!("guest {guest} has no input schema"))?;
+        if values.len() != schema.len() {
+            bail!(
+                "guest {guest} takes {} input values, got {}",
+                schema.len(),
+                values.len()
+            );
+        }
+        let tokens = schema
+            .iter()
+            .zip(&values)
+            .map(|(param, value)| json_token(param, value))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let private = private_input.map(|private| private_section(&private, None));
+        let framed = frame_sections(&guest, &abi::encode(&tokens), private.as_deref())?;
+        canonicalize(&guest, &framed)
+    })()
+    .map(Buffer::from)
+    .map_err(js_error)
+}
+
+/// Rewrite an input into the canonical form the relay proves.
+#[napi]
+pub fn canonicalize_input(guest: String, input: Buffer) -> napi::Result<Buffer> {
+    canonicalize(&guest, &input)
+        .map(Buffer::from)
+        .map_err(js_error)
+}
+
+/// Decode the public section of a guest's input into its values.
+#[napi]
+pub fn decode_input(guest: String, input: Buffer) -> napi::Result<Vec<Value>> {
+    (|| {
+        let schema =
+            input_schema(&guest).ok_or_else(|| anyhow!("guest {guest} has no input schema"))?;
+        let (public, _) = split_input(&input)?;
+        let tokens = decode_public(&guest, &schema, public)?;
+        Ok(tokens.iter().map(token_json).collect())
+    })()
+    .map_err(js_error)
+}
+
+/// Decode a guest's journal into its values, at the journal's version.
+#[napi]
+pub fn decode_journal(guest: String, journal: Buffer) -> napi::Result<Vec<Value>> {
+    schema::decode_journal(&guest, &journal)
+        .and_then(|tokens| tokens.ok_or_else(|| anyhow!("guest {guest} has no journal schema")))
+        .map(|tokens| tokens.iter().map(token_json).collect())
+        .map_err(js_error)
+}
+
+/// Version of a journal, 1 for journals without a version trailer.
+#[napi]
+pub fn journal_version(journal: Buffer) -> u32 {
+    split_journal_version(&journal).0.into()
+}
+
+/// The journal's values ABI encoded as a single tuple, as Solidity's
+/// `abi.encode` would.
+#[napi]
+pub fn public_values(guest: String, journal: Buffer) -> napi::Result<Buffer> {
+    schema::public_values(&guest, &journal)
+        .map(Buffer::from)
+        .map_err(js_error)
+}
+
+/// Solidity types of a guest's input values, e.g. `uint160`.
+#[napi]
+pub fn input_types(guest: String) -> Option<Vec<String>> {
+    input_schema(&guest).map(|schema| schema.iter().map(ToString::to_string).collect())
+}
+
+/// Solidity types of the values of a guest's journal.
+#[napi]
+pub fn journal_types(guest: String) -> Option<Vec<String>> {
+    journal_schema(&guest).map(|schema| schema.iter().map(ToString::to_string).collect())
+}
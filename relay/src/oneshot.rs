@@ -0,0 +1,208 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! One call proving a pool's TWAP and submitting it, for integrators who do
+//! not need to configure the fetcher, prover and submitter themselves.
+//!
+//! ```ignore
+//! let chain = Chain::new("wss://node", private_key, consumer);
+//! let tx_hash = prove_and_submit(pool, Window::last(Duration::from_secs(1800)), &chain).await?;
+//! ```
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::{Middleware, Provider, Ws},
+    types::{Address, TransactionRequest, TxHash, U64},
+    utils::id,
+};
+use methods::GUEST_LIST;
+
+use crate::{
+    backend::{BonsaiBackend, ProverBackend},
+    clock, elog,
+    eth::connect,
+    finality::FinalityPolicy,
+    proofs::RpcProofSource,
+    registry::GuestRegistry,
+    schema::check_journal_version,
+    snark_seal,
+    twap::TwapFetcher,
+    Output,
+};
+
+/// Signature of the function proofs are submitted to unless overridden,
+/// taking the journal, seal and post state digest for the consumer to verify
+/// itself. No contract in this repository consumes TWAP journals:
+/// `ZkPriceAggregator.submit` has this signature but decodes SWAP journals,
+/// and rejects TWAP ones. The consumer must be a contract of your own
+/// decoding the TWAP journal.
+pub const SUBMIT_SIGNATURE: &str = "submit(bytes,bytes,bytes32)";
+
+/// Time window a TWAP is proven over, as Unix timestamps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub from: u64,
+    pub to: u64,
+}
+
+impl Window {
+    pub fn new(from: u64, to: u64) -> Self {
+        Self { from, to }
+    }
+
+    /// The window of length `length` ending now.
+    pub fn last(length: Duration) -> Self {
+        let now = clock::unix_now();
+        Self {
+            from: now.saturating_sub(length.as_secs()),
+            to: now,
+        }
+    }
+}
+
+/// Chain a pool is read from and its proven TWAP submitted to.
+#[derive(Debug, Clone)]
+pub struct Chain {
+    pub eth_node: String,
+    /// Hex private key of the account paying for the submission.
+    pub private_key: String,
+    /// Contract decoding TWAP journals the proof is submitted to, as
+    /// [SUBMIT_SIGNATURE] unless overridden with [Chain::with_signature].
+    pub consumer: Address,
+    pub finality: FinalityPolicy,
+    pub signature: String,
+}
+
+impl Chain {
+    pub fn new(
+        eth_node: impl Into<String>,
+        private_key: impl Into<String>,
+        consumer: Address,
+    ) -> Self {
+        Self {
+            eth_node: eth_node.into(),
+            private_key: private_key.into(),
+            consumer,
+            finality: FinalityPolicy::default(),
+            signature: SUBMIT_SIGNATURE.to_string(),
+        }
+    }
+
+    /// Only anchor the TWAP to blocks meeting `finality`.
+    pub fn with_finality(mut self, finality: FinalityPolicy) -> Self {
+        self.finality = finality;
+        self
+    }
+
+    /// Submit to a consumer function other than [SUBMIT_SIGNATURE], which
+    /// must take the same `(bytes journal, bytes seal, bytes32
+    /// postStateDigest)` arguments.
+    pub fn with_signature(mut self, signature: impl Into<String>) -> Self {
+        self.signature = signature.into();
+        self
+    }
+}
+
+/// Calldata calling `signature` with the journal, seal and post state digest
+/// of a proven result.
+pub fn verifier_calldata(signature: &str, output: &Output) -> Result<Vec<u8>> {
+    let Output::Bonsai {
+        journal,
+        receipt_metadata,
+        snark_proof,
+        ..
+    } = output
+    else {
+        bail!("only results with a SNARK can be verified on chain");
+    };
+    let post_state_digest: [u8; 32] = receipt_metadata.post.digest().into();
+    Ok([
+        &id(signature)[..],
+        &abi::encode(&[
+            Token::Bytes(journal.clone()),
+            Token::Bytes(snark_seal(snark_proof)?),
+            Token::FixedBytes(post_state_digest.to_vec()),
+        ]),
+    ]
+    .concat())
+}
+
+/// Prove the TWAP of `pool` over `window` with the TWAP guest on Bonsai and
+/// submit it to the chain's consumer, returning the submission's
+/// transaction hash. Bonsai is configured by the `BONSAI_API_URL` and
+/// `BONSAI_API_KEY` environment variables.
+pub async fn prove_and_submit(pool: Address, window: Window, chain: &Chain) -> Result<TxHash> {
+    let provider = Provider::<Ws>::connect(&chain.eth_node)
+        .await
+        .context(format!("Failed to connect to {}", chain.eth_node))?;
+    let chain_id = provider
+        .get_chainid()
+        .await
+        .context("Failed to read the chain ID")?
+        .as_u64();
+    let client = connect(&chain.eth_node, chain_id, &chain.private_key).await?;
+
+    let provider = Arc::new(provider);
+    let input = TwapFetcher::new(provider.clone(), Arc::new(RpcProofSource(provider)))
+        .with_finality(chain.finality)
+        .fetch(pool, window.from, window.to)
+        .await?
+        .encode()?;
+    let guest = GuestRegistry::from_guest_list(GUEST_LIST).resolve("TWAP")?;
+    elog!(
+        "Proving the TWAP of pool {pool:?} over [{}, {}]",
+        window.from,
+        window.to
+    );
+    let output = BonsaiBackend::default().prove(guest.clone(), input).await?;
+    // Bonsai is trusted no further than its receipt verifies, so a bad proof
+    // never costs a submission.
+    if let Output::Bonsai {
+        journal, receipt, ..
+    } = &output
+    {
+        receipt
+            .verify(guest.image_id)
+            .map_err(|err| anyhow!("Bonsai receipt failed to verify: {err}"))?;
+        if receipt.journal != *journal {
+            bail!("Bonsai journal does not match its receipt");
+        }
+        check_journal_version(&guest.name, journal)?;
+    }
+
+    let tx = TransactionRequest::new()
+        .to(chain.consumer)
+        .data(verifier_calldata(&chain.signature, &output)?);
+    client
+        .call(&tx.clone().into(), None)
+        .await
+        .context(format!(
+            "Consumer {:?} would reject the proof",
+            chain.consumer
+        ))?;
+    let receipt = client
+        .send_transaction(tx, None)
+        .await
+        .context("Failed to submit the proof")?
+        .await
+        .context("Failed to await the submission")?
+        .ok_or_else(|| anyhow!("Submission was dropped"))?;
+    if receipt.status != Some(U64::one()) {
+        bail!("submission {:?} reverted", receipt.transaction_hash);
+    }
+    Ok(receipt.transaction_hash)
+}
@@ -0,0 +1,399 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use risc0_zkvm::{
+    sha::Digest, Executor, ExecutorEnv, MemoryImage, Program, Session, MEM_SIZE, PAGE_SIZE,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::sha256_hex, elog, error::RelayError, registry::Guest, trace, tracedump::TraceTail,
+    Output,
+};
+
+/// Number of ready-to-use images kept per guest.
+pub const DEFAULT_WARM_IMAGES: usize = 2;
+
+/// Limits on local executions, which bound the memory proving them takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecLimits {
+    /// Largest segment, as a power of two of cycles. Proving a segment takes
+    /// memory in proportion to its size.
+    pub segment_limit_po2: u32,
+    /// Most cycles a session may run for, if bounded.
+    pub session_limit: Option<u64>,
+}
+
+impl Default for ExecLimits {
+    /// The zkVM's own segment size on Linux. macOS and Windows developer
+    /// machines get half size segments: on macOS the prover shares unified
+    /// memory with the desktop, and Windows commits memory up front rather
+    /// than overcommitting, so full size segments fail to allocate on common
+    /// 16 GiB machines.
+    fn default() -> Self {
+        let segment_limit_po2 = if cfg!(any(target_os = "macos", target_os = "windows")) {
+            19
+        } else {
+            20
+        };
+        Self {
+            segment_limit_po2,
+            session_limit: None,
+        }
+    }
+}
+
+struct Slot {
+    template: Arc<MemoryImage>,
+    warm: Vec<MemoryImage>,
+}
+
+fn build_image(guest: &Guest) -> Result<MemoryImage> {
+    let elf = guest.elf()?;
+    let program = Program::load_elf(&elf, MEM_SIZE as u32)
+        .context(format!("Failed to load ELF of guest {}", guest.name))?;
+    MemoryImage::new(&program, PAGE_SIZE as u32)
+        .context(format!("Failed to build image of guest {}", guest.name))
+}
+
+/// What a guest printed during an execution, decoded lossily as UTF-8.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GuestLogs {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Cycle counts of an execution.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CycleStats {
+    pub segments: usize,
+    /// Cycles spent executing guest instructions.
+    pub user_cycles: u64,
+    /// Cycles a proof would cover, with each segment padded to a power of
+    /// two.
+    pub total_cycles: u64,
+    /// Cycles not spent on guest instructions: paging memory in and out,
+    /// syscalls and padding. The pinned executor does not report page faults
+    /// themselves, but paging dominates this for guests touching much memory,
+    /// so a data layout touching fewer pages shows up as a drop here.
+    #[serde(default)]
+    pub overhead_cycles: u64,
+    #[serde(default)]
+    pub per_segment: Vec<SegmentStats>,
+}
+
+/// Cycle counts of one segment of an execution.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct SegmentStats {
+    pub index: u32,
+    /// The segment's size, as a power of two of cycles.
+    pub po2: usize,
+    pub user_cycles: u64,
+    pub overhead_cycles: u64,
+}
+
+impl fmt::Display for CycleStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} segments, {} user cycles, {} total cycles, {} overhead cycles",
+            self.segments, self.user_cycles, self.total_cycles, self.overhead_cycles
+        )?;
+        for segment in &self.per_segment {
+            write!(
+                f,
+                "\n  segment {}: 2^{} cycles, {} user, {} overhead ({:.1}%)",
+                segment.index,
+                segment.po2,
+                segment.user_cycles,
+                segment.overhead_cycles,
+                100.0 * segment.overhead_cycles as f64 / (1u64 << segment.po2) as f64
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Pool of pre-built memory images per guest.
+///
+/// Building a [MemoryImage] parses the ELF and hashes every page; an executor
+/// then consumes its image. The pool builds each guest's image once and hands
+/// out copies of it, keeping a few copies ready so that the execution-only
+/// path does not pay for image construction on every request.
+pub struct ImagePool {
+    warm_images: usize,
+    limits: ExecLimits,
+    slots: Mutex<HashMap<Digest, Slot>>,
+}
+
+impl Default for ImagePool {
+    fn default() -> Self {
+        Self::new(DEFAULT_WARM_IMAGES)
+    }
+}
+
+impl ImagePool {
+    pub fn new(warm_images: usize) -> Self {
+        Self {
+            warm_images,
+            limits: ExecLimits::default(),
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run executions within `limits` rather than the platform's defaults.
+    pub fn with_limits(mut self, limits: ExecLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Take an image for the given guest, building its template on first use.
+    pub fn checkout(&self, guest: &Guest) -> Result<MemoryImage> {
+        let mut slots = self
+            .slots
+            .lock()
+            .map_err(|_| anyhow!("image pool lock poisoned"))?;
+        let slot = match slots.entry(guest.image_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(Slot {
+                template: Arc::new(build_image(guest)?),
+                warm: Vec::new(),
+            }),
+        };
+        Ok(match slot.warm.pop() {
+            Some(image) => image,
+            None => slot.template.as_ref().clone(),
+        })
+    }
+
+    /// Top up the ready copies of every guest checked out so far. Meant to be
+    /// called off the request path, e.g. after a response was sent. Copies
+    /// are made without holding the pool, so checkouts are not held up.
+    pub fn refill(&self) -> Result<()> {
+        let missing: Vec<(Digest, Arc<MemoryImage>, usize)> = self
+            .slots
+            .lock()
+            .map_err(|_| anyhow!("image pool lock poisoned"))?
+            .iter()
+            .filter(|(_, slot)| slot.warm.len() < self.warm_images)
+            .map(|(id, slot)| {
+                let missing = self.warm_images - slot.warm.len();
+                (*id, slot.template.clone(), missing)
+            })
+            .collect();
+        for (id, template, missing) in missing {
+            let copies: Vec<MemoryImage> =
+                (0..missing).map(|_| template.as_ref().clone()).collect();
+            let mut slots = self
+                .slots
+                .lock()
+                .map_err(|_| anyhow!("image pool lock poisoned"))?;
+            if let Some(slot) = slots.get_mut(&id) {
+                let room = self.warm_images.saturating_sub(slot.warm.len());
+                slot.warm.extend(copies.into_iter().take(room));
+            }
+        }
+        Ok(())
+    }
+
+    /// [Self::refill] the pool on the blocking thread pool, without waiting
+    /// for it.
+    pub fn refill_in_background(self: &Arc<Self>) {
+        let pool = self.clone();
+        trace::spawn_blocking(move || {
+            if let Err(err) = pool.refill() {
+                elog!("Failed to refill the image pool: {err:?}");
+            }
+        });
+    }
+
+    /// [Self::execute] on the blocking thread pool, so the calling runtime
+    /// keeps serving other tasks, and refill the pool in the background
+    /// after.
+    pub async fn execute_blocking(
+        self: &Arc<Self>,
+        guest: &Arc<Guest>,
+        input: Vec<u8>,
+    ) -> Result<Output> {
+        let output = {
+            let pool = self.clone();
+            let guest = guest.clone();
+            trace::spawn_blocking(move || pool.execute(&guest, &input))
+                .await
+                .context("Failed to run execution sub-task")?
+        };
+        self.refill_in_background();
+        output
+    }
+
+    /// Execute a guest without proving, using a pooled image.
+    pub fn execute(&self, guest: &Guest, input: &[u8]) -> Result<Output> {
+        self.execute_with_stats(guest, input)
+            .map(|(output, _)| output)
+    }
+
+    /// Execute a guest without proving, also counting the cycles a proof of
+    /// the execution would cover.
+    pub fn execute_with_stats(&self, guest: &Guest, input: &[u8]) -> Result<(Output, CycleStats)> {
+        self.run(guest, input, None, None)
+    }
+
+    /// Execute a guest like [Self::execute_with_stats], capturing what it
+    /// prints. The logs are returned whether the execution succeeds or not.
+    pub fn execute_with_logs(
+        &self,
+        guest: &Guest,
+        input: &[u8],
+    ) -> (Result<(Output, CycleStats)>, GuestLogs) {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let result = self.run(guest, input, Some((&mut stdout, &mut stderr)), None);
+        let logs = GuestLogs {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        };
+        (result, logs)
+    }
+
+    /// Execute a guest like [Self::execute_with_logs], also recording the
+    /// tail of its instruction trace into `trace`, to be dumped for
+    /// debugging.
+    pub fn execute_traced(
+        &self,
+        guest: &Guest,
+        input: &[u8],
+        trace: &mut TraceTail,
+    ) -> (Result<(Output, CycleStats)>, GuestLogs) {
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        let result = self.run(guest, input, Some((&mut stdout, &mut stderr)), Some(trace));
+        let logs = GuestLogs {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        };
+        (result, logs)
+    }
+
+    /// Limits executions run within.
+    pub fn limits(&self) -> ExecLimits {
+        self.limits
+    }
+
+    /// Execute a guest, returning the session whose segments a prover can
+    /// prove.
+    pub fn session(&self, guest: &Guest, input: &[u8]) -> Result<Session> {
+        self.execute_session(guest, input, None, None)
+    }
+
+    fn run(
+        &self,
+        guest: &Guest,
+        input: &[u8],
+        capture: Option<(&mut Vec<u8>, &mut Vec<u8>)>,
+        trace: Option<&mut TraceTail>,
+    ) -> Result<(Output, CycleStats)> {
+        let session = self.execute_session(guest, input, capture, trace)?;
+        let mut stats = CycleStats {
+            segments: session.segments.len(),
+            ..Default::default()
+        };
+        for segment in &session.segments {
+            let segment = segment.resolve().context("Failed to resolve segment")?;
+            let (user_cycles, total_cycles) = (segment.insn_cycles as u64, 1u64 << segment.po2);
+            let overhead_cycles = total_cycles.saturating_sub(user_cycles);
+            stats.user_cycles += user_cycles;
+            stats.total_cycles += total_cycles;
+            stats.overhead_cycles += overhead_cycles;
+            stats.per_segment.push(SegmentStats {
+                index: segment.index,
+                po2: segment.po2,
+                user_cycles,
+                overhead_cycles,
+            });
+        }
+        Ok((
+            Output::Execution {
+                journal: session.journal,
+            },
+            stats,
+        ))
+    }
+
+    fn execute_session(
+        &self,
+        guest: &Guest,
+        input: &[u8],
+        capture: Option<(&mut Vec<u8>, &mut Vec<u8>)>,
+        trace: Option<&mut TraceTail>,
+    ) -> Result<Session> {
+        let image = self.checkout(guest)?;
+        let mut builder = ExecutorEnv::builder();
+        builder
+            .add_input(input)
+            .segment_limit_po2(self.limits.segment_limit_po2)
+            .session_limit(self.limits.session_limit);
+        if let Some((stdout, stderr)) = capture {
+            builder.stdout(stdout).stderr(stderr);
+        }
+        if let Some(trace) = trace {
+            builder.trace_callback(|event| {
+                trace.record(event);
+                Ok(())
+            });
+        }
+        let env = builder.build().context("Failed to build exec env")?;
+        let mut exec = Executor::new(env, image).context("Failed to instantiate executor")?;
+        // The executor only fails on the guest: a panic, a bad instruction
+        // or running out of cycles.
+        exec.run().map_err(|err| {
+            RelayError::GuestFault {
+                what: format!("Execution on input {}", sha256_hex(input)),
+                message: format!("{err:#}"),
+            }
+            .into()
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_limits_halve_segments_off_linux() {
+        let limits = ExecLimits::default();
+        if cfg!(any(target_os = "macos", target_os = "windows")) {
+            assert_eq!(limits.segment_limit_po2, 19);
+        } else {
+            assert_eq!(limits.segment_limit_po2, 20);
+        }
+        assert_eq!(limits.session_limit, None);
+    }
+
+    #[test]
+    fn test_pools_run_within_their_limits() {
+        let limits = ExecLimits {
+            segment_limit_po2: 16,
+            session_limit: Some(1 << 24),
+        };
+        assert_eq!(ImagePool::default().limits(), ExecLimits::default());
+        assert_eq!(ImagePool::default().with_limits(limits).limits(), limits);
+    }
+}
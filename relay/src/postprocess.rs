@@ -0,0 +1,421 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{Address, U256, U512},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    registry::GuestRegistry,
+    schema::{decode_journal, journal_schema},
+    tokens::{PriceDirection, TokenResolver, TokenTable},
+};
+
+/// Most decimals a [U256] can be scaled by.
+const MAX_DECIMALS: u32 = 77;
+
+/// Rewrites the decoded values of a journal for a consumer, so small
+/// formatting differences between consumers do not need new guest images.
+pub trait PostProcessor: Send + Sync {
+    fn apply(&self, values: &mut Vec<Token>) -> Result<()>;
+
+    /// Check that the post-processor applies to values of the given types,
+    /// updating them to the types it produces.
+    fn check(&self, types: &mut Vec<ParamType>) -> Result<()>;
+}
+
+/// Rescale the fixed point integer at `index` from `from` to `to` decimals,
+/// rounding down.
+pub struct ScaleDecimals {
+    pub index: usize,
+    pub from: u32,
+    pub to: u32,
+}
+
+impl PostProcessor for ScaleDecimals {
+    fn apply(&self, values: &mut Vec<Token>) -> Result<()> {
+        let value = uint_at(values, self.index)?;
+        let scaled = if self.to >= self.from {
+            value.checked_mul(U256::exp10((self.to - self.from) as usize))
+        } else {
+            Some(value / U256::exp10((self.from - self.to) as usize))
+        };
+        values[self.index] =
+            Token::Uint(scaled.ok_or_else(|| {
+                anyhow!("value {} overflows at {} decimals", self.index, self.to)
+            })?);
+        Ok(())
+    }
+
+    fn check(&self, types: &mut Vec<ParamType>) -> Result<()> {
+        if self.from.max(self.to) > MAX_DECIMALS {
+            bail!(
+                "value {} cannot be scaled beyond {MAX_DECIMALS} decimals",
+                self.index
+            );
+        }
+        check_uint_at(types, self.index)
+    }
+}
+
+/// Convert the Q64.96 fixed point number at `index`, such as a Uniswap
+/// `sqrtPriceX96`, to an 18 decimal UD60x18.
+pub struct Q96ToUd60x18 {
+    pub index: usize,
+}
+
+impl PostProcessor for Q96ToUd60x18 {
+    fn apply(&self, values: &mut Vec<Token>) -> Result<()> {
+        let value = uint_at(values, self.index)?;
+        let converted = value
+            .checked_mul(U256::exp10(18))
+            .ok_or_else(|| anyhow!("value {} overflows as UD60x18", self.index))?;
+        values[self.index] = Token::Uint(converted >> 96);
+        Ok(())
+    }
+
+    fn check(&self, types: &mut Vec<ParamType>) -> Result<()> {
+        check_uint_at(types, self.index)
+    }
+}
+
+/// Convert the `sqrtPriceX96` at `index` to a UD60x18 price in whole
+/// tokens, in `direction` and accounting for the decimals of both tokens.
+pub struct Q96ToPrice {
+    pub index: usize,
+    pub decimals0: u8,
+    pub decimals1: u8,
+    pub direction: PriceDirection,
+}
+
+impl PostProcessor for Q96ToPrice {
+    fn apply(&self, values: &mut Vec<Token>) -> Result<()> {
+        let value = uint_at(values, self.index)?;
+        let overflow = || anyhow!("price {} overflows as UD60x18", self.index);
+        // token1 per token0 = sqrtPriceX96^2 / 2^192 * 10^decimals0 / 10^decimals1
+        let sqrt_price_squared = value.full_mul(value);
+        let x192 = U512::one() << 192;
+        let (numerator, denominator) = match self.direction {
+            PriceDirection::Token1PerToken0 => (
+                sqrt_price_squared.checked_mul(U512::exp10(18 + self.decimals0 as usize)),
+                U512::exp10(self.decimals1 as usize).checked_mul(x192),
+            ),
+            PriceDirection::Token0PerToken1 => (
+                U512::exp10(18 + self.decimals1 as usize).checked_mul(x192),
+                sqrt_price_squared.checked_mul(U512::exp10(self.decimals0 as usize)),
+            ),
+        };
+        let denominator = denominator.ok_or_else(overflow)?;
+        if denominator.is_zero() {
+            bail!("price {} is zero and cannot be inverted", self.index);
+        }
+        let price = numerator.ok_or_else(overflow)? / denominator;
+        let price = U256::try_from(price).map_err(|_| overflow())?;
+        values[self.index] = Token::Uint(price);
+        Ok(())
+    }
+
+    fn check(&self, types: &mut Vec<ParamType>) -> Result<()> {
+        if self.decimals0.max(self.decimals1) as u32 > MAX_DECIMALS {
+            bail!(
+                "price {} is of tokens with over {MAX_DECIMALS} decimals",
+                self.index
+            );
+        }
+        check_uint_at(types, self.index)
+    }
+}
+
+/// Append opaque bytes, such as a feed ID, as a trailing `bytes` value.
+pub struct AppendMetadata {
+    pub data: Vec<u8>,
+}
+
+impl PostProcessor for AppendMetadata {
+    fn apply(&self, values: &mut Vec<Token>) -> Result<()> {
+        values.push(Token::Bytes(self.data.clone()));
+        Ok(())
+    }
+
+    fn check(&self, types: &mut Vec<ParamType>) -> Result<()> {
+        types.push(ParamType::Bytes);
+        Ok(())
+    }
+}
+
+fn uint_at(values: &[Token], index: usize) -> Result<U256> {
+    match values.get(index) {
+        Some(Token::Uint(value)) => Ok(*value),
+        Some(_) => bail!("journal value {index} is not an unsigned integer"),
+        None => bail!("journal has no value {index}"),
+    }
+}
+
+/// Check that the value at `index` is an unsigned integer, which the
+/// post-processor replaces with a uint256.
+fn check_uint_at(types: &mut [ParamType], index: usize) -> Result<()> {
+    match types.get(index) {
+        Some(ParamType::Uint(_)) => {
+            types[index] = ParamType::Uint(256);
+            Ok(())
+        }
+        Some(_) => bail!("journal value {index} is not an unsigned integer"),
+        None => bail!("journal has no value {index}"),
+    }
+}
+
+/// Serialized form of a [PostProcessor], as given by API clients.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum PostProcessorConfig {
+    ScaleDecimals {
+        index: usize,
+        from: u32,
+        to: u32,
+    },
+    /// [ScaleDecimals] from the decimals of `token`.
+    ScaleToken {
+        index: usize,
+        token: Address,
+        to: u32,
+    },
+    Q96ToUd60x18 {
+        index: usize,
+    },
+    /// [Q96ToPrice] for the pool of `token0` and `token1`.
+    Q96ToPrice {
+        index: usize,
+        token0: Address,
+        token1: Address,
+        #[serde(default)]
+        direction: PriceDirection,
+    },
+    /// Hex encoded metadata.
+    AppendMetadata {
+        data: String,
+    },
+}
+
+impl PostProcessorConfig {
+    /// Tokens whose metadata is needed to build the post-processor.
+    pub fn tokens(&self) -> Vec<Address> {
+        match self {
+            PostProcessorConfig::ScaleToken { token, .. } => vec![*token],
+            PostProcessorConfig::Q96ToPrice { token0, token1, .. } => vec![*token0, *token1],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn build(&self, tokens: &TokenTable) -> Result<Box<dyn PostProcessor>> {
+        Ok(match self {
+            PostProcessorConfig::ScaleDecimals { index, from, to } => Box::new(ScaleDecimals {
+                index: *index,
+                from: *from,
+                to: *to,
+            }),
+            PostProcessorConfig::ScaleToken { index, token, to } => Box::new(ScaleDecimals {
+                index: *index,
+                from: tokens.get(*token)?.decimals as u32,
+                to: *to,
+            }),
+            PostProcessorConfig::Q96ToUd60x18 { index } => Box::new(Q96ToUd60x18 { index: *index }),
+            PostProcessorConfig::Q96ToPrice {
+                index,
+                token0,
+                token1,
+                direction,
+            } => Box::new(Q96ToPrice {
+                index: *index,
+                decimals0: tokens.get(*token0)?.decimals,
+                decimals1: tokens.get(*token1)?.decimals,
+                direction: *direction,
+            }),
+            PostProcessorConfig::AppendMetadata { data } => Box::new(AppendMetadata {
+                data: hex::decode(data.trim_start_matches("0x"))
+                    .context("Failed to decode metadata")?,
+            }),
+        })
+    }
+}
+
+/// Post-processors applied in order to a journal once its guest completes.
+/// Proofs commit to the journal the guest produced, so the processed journal
+/// is only returned next to it by the API, and only replaces it in the
+/// callbacks of relay contracts that do not verify seals.
+#[derive(Default)]
+pub struct PostProcessChain(Vec<Box<dyn PostProcessor>>);
+
+impl PostProcessChain {
+    pub fn from_configs(configs: &[PostProcessorConfig], tokens: &TokenTable) -> Result<Self> {
+        Ok(Self(
+            configs
+                .iter()
+                .map(|config| config.build(tokens))
+                .collect::<Result<_>>()?,
+        ))
+    }
+
+    /// Build the chain, first resolving the metadata of the tokens it refers
+    /// to. Without a resolver, only post-processors referring to no token
+    /// can be built.
+    pub async fn resolve<M: Middleware>(
+        configs: &[PostProcessorConfig],
+        resolver: Option<&TokenResolver<M>>,
+    ) -> Result<Self> {
+        let addresses: Vec<_> = configs
+            .iter()
+            .flat_map(PostProcessorConfig::tokens)
+            .collect();
+        let tokens = match resolver {
+            Some(resolver) => resolver.resolve_all(addresses).await?,
+            None if addresses.is_empty() => TokenTable::default(),
+            None => bail!("token post-processors need the relay to be connected to a chain"),
+        };
+        Self::from_configs(configs, &tokens)
+    }
+
+    pub fn push(mut self, processor: impl PostProcessor + 'static) -> Self {
+        self.0.push(Box::new(processor));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check that every post-processor applies to the journals of the
+    /// current version of the guest's schema, so a request is refused before
+    /// anything is proven for it.
+    pub fn validate(&self, guest_name: &str) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let mut types = journal_schema(guest_name)
+            .ok_or_else(|| anyhow!("guest {guest_name} has no journal schema to post-process"))?;
+        for (position, processor) in self.0.iter().enumerate() {
+            processor
+                .check(&mut types)
+                .context(format!("Post-processor {position} does not apply"))?;
+        }
+        Ok(())
+    }
+
+    /// Decode the journal against the guest's schema, run every
+    /// post-processor and ABI encode the result.
+    pub fn apply(&self, guest_name: &str, journal: &[u8]) -> Result<Vec<u8>> {
+        let mut values = decode_journal(guest_name, journal)?
+            .ok_or_else(|| anyhow!("guest {guest_name} has no journal schema to post-process"))?;
+        for processor in &self.0 {
+            processor.apply(&mut values)?;
+        }
+        Ok(abi::encode(&values))
+    }
+}
+
+/// Load the post-process chains of each guest's callbacks from a JSON file
+/// mapping guest names or image IDs to post-processors, checking each chain
+/// against its guest's schema.
+pub async fn load_callback_chains<M: Middleware>(
+    path: &Path,
+    registry: &GuestRegistry,
+    resolver: Option<&TokenResolver<M>>,
+) -> Result<HashMap<String, PostProcessChain>> {
+    let contents = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+    let configs: HashMap<String, Vec<PostProcessorConfig>> =
+        serde_json::from_slice(&contents).context(format!("Failed to parse {}", path.display()))?;
+    let mut chains = HashMap::new();
+    for (name, configs) in configs {
+        let guest = registry.resolve(&name)?;
+        let chain = PostProcessChain::resolve(&configs, resolver).await?;
+        chain
+            .validate(&guest.name)
+            .context(format!("Invalid post-processors for guest {}", guest.name))?;
+        chains.insert(guest.name.clone(), chain);
+    }
+    Ok(chains)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn chain(configs: &[PostProcessorConfig]) -> PostProcessChain {
+        PostProcessChain::from_configs(configs, &TokenTable::default()).unwrap()
+    }
+
+    #[test]
+    fn test_validate_accepts_chains_matching_the_schema() {
+        // SWAP commits its pool's sqrtPriceX96 as value 1.
+        let chain = chain(&[
+            PostProcessorConfig::Q96ToUd60x18 { index: 1 },
+            PostProcessorConfig::ScaleDecimals {
+                index: 1,
+                from: 18,
+                to: 8,
+            },
+            PostProcessorConfig::AppendMetadata {
+                data: "0xfeed".to_string(),
+            },
+        ]);
+        chain.validate("SWAP").unwrap();
+    }
+
+    #[test]
+    fn test_validate_rejects_mismatched_values() {
+        let not_uint = chain(&[PostProcessorConfig::Q96ToUd60x18 { index: 0 }]);
+        assert!(not_uint.validate("SWAP").is_err());
+
+        let missing = chain(&[PostProcessorConfig::Q96ToUd60x18 { index: 99 }]);
+        assert!(missing.validate("SWAP").is_err());
+
+        // Appended metadata is bytes, which cannot be scaled.
+        let appended = chain(&[
+            PostProcessorConfig::AppendMetadata {
+                data: String::new(),
+            },
+            PostProcessorConfig::ScaleDecimals {
+                index: 13,
+                from: 0,
+                to: 8,
+            },
+        ]);
+        assert!(appended.validate("SWAP").is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_unrepresentable_decimals() {
+        let chain = chain(&[PostProcessorConfig::ScaleDecimals {
+            index: 2,
+            from: 0,
+            to: 78,
+        }]);
+        assert!(chain.validate("SWAP").is_err());
+    }
+
+    #[test]
+    fn test_validate_needs_a_schema() {
+        let chain = chain(&[PostProcessorConfig::Q96ToUd60x18 { index: 0 }]);
+        assert!(chain.validate("NO_SUCH_GUEST").is_err());
+        PostProcessChain::default()
+            .validate("NO_SUCH_GUEST")
+            .unwrap();
+    }
+}
@@ -0,0 +1,508 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    providers::{Http, Middleware, Provider, Ws},
+    types::{Address, Block, BlockId, Bytes, EIP1186ProofResponse, StorageProof, H256, U256},
+    utils::{
+        keccak256,
+        rlp::{Rlp, RlpStream},
+    },
+};
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+
+use crate::redact::register_secret;
+
+/// Root of an empty Merkle Patricia trie, keccak256(rlp("")).
+pub const EMPTY_ROOT: H256 = H256([
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+]);
+
+fn nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter()
+        .flat_map(|byte| [byte >> 4, byte & 0x0f])
+        .collect()
+}
+
+/// Decode a hex-prefix encoded path into its nibbles and leaf flag.
+fn decode_path(encoded: &[u8]) -> Result<(Vec<u8>, bool)> {
+    let first = *encoded
+        .first()
+        .ok_or_else(|| anyhow!("empty trie node path"))?;
+    let is_leaf = match first >> 4 {
+        0 | 1 => false,
+        2 | 3 => true,
+        flag => bail!("invalid trie node path flag {flag}"),
+    };
+    let mut path = nibbles(&encoded[1..]);
+    if first & 0x10 != 0 {
+        path.insert(0, first & 0x0f);
+    }
+    Ok((path, is_leaf))
+}
+
+/// Walk a Merkle Patricia proof from `root` along `key`, returning the value
+/// stored there or `None` if the proof shows the key is absent. Fails if the
+/// proof does not hash up to `root`.
+pub fn verify_proof(root: H256, key: &[u8], proof: &[Bytes]) -> Result<Option<Vec<u8>>> {
+    if proof.is_empty() {
+        if root == EMPTY_ROOT {
+            return Ok(None);
+        }
+        bail!("empty proof for a non-empty trie");
+    }
+    let path = nibbles(key);
+    let mut pos = 0;
+    let mut nodes = proof.iter();
+    let mut expected = root;
+    let mut node: Vec<u8> = Vec::new();
+    let mut inline = false;
+    loop {
+        if !inline {
+            let next = nodes
+                .next()
+                .ok_or_else(|| anyhow!("proof ends before reaching the key"))?;
+            if H256(keccak256(next)) != expected {
+                bail!("proof node does not match its parent's hash {expected:?}");
+            }
+            node = next.to_vec();
+        }
+        let rlp = Rlp::new(&node);
+        let child = match rlp.item_count()? {
+            17 => {
+                if pos == path.len() {
+                    let value = rlp.at(16)?.data()?;
+                    return Ok((!value.is_empty()).then(|| value.to_vec()));
+                }
+                pos += 1;
+                rlp.at(path[pos - 1] as usize)?
+            }
+            2 => {
+                let (node_path, is_leaf) = decode_path(rlp.at(0)?.data()?)?;
+                let rest = &path[pos..];
+                if is_leaf {
+                    if rest != node_path.as_slice() {
+                        return Ok(None);
+                    }
+                    return Ok(Some(rlp.at(1)?.data()?.to_vec()));
+                }
+                if !rest.starts_with(&node_path) {
+                    return Ok(None);
+                }
+                pos += node_path.len();
+                rlp.at(1)?
+            }
+            count => bail!("invalid trie node with {count} items"),
+        };
+        // Children are referenced by hash, or embedded when shorter than 32
+        // bytes.
+        if child.is_list() {
+            node = child.as_raw().to_vec();
+            inline = true;
+            continue;
+        }
+        let hash = child.data()?;
+        match hash.len() {
+            0 => return Ok(None),
+            32 => {
+                expected = H256::from_slice(hash);
+                inline = false;
+            }
+            len => bail!("invalid trie node reference of {len} bytes"),
+        }
+    }
+}
+
+/// Check an `eth_getProof` response against the state root of its block:
+/// the account proof, and every storage proof against the proven storage
+/// root.
+pub fn verify_account(state_root: H256, response: &EIP1186ProofResponse) -> Result<()> {
+    let account = verify_proof(
+        state_root,
+        &keccak256(response.address),
+        &response.account_proof,
+    )
+    .context(format!("Invalid account proof for {:?}", response.address))?;
+    match account {
+        Some(account) => {
+            let mut expected = RlpStream::new_list(4);
+            expected
+                .append(&response.nonce)
+                .append(&response.balance)
+                .append(&response.storage_hash)
+                .append(&response.code_hash);
+            if account != expected.out().to_vec() {
+                bail!(
+                    "account proof of {:?} does not match its fields",
+                    response.address
+                );
+            }
+        }
+        None => {
+            if !response.nonce.is_zero() || !response.balance.is_zero() {
+                bail!(
+                    "account {:?} is proven absent but has state",
+                    response.address
+                );
+            }
+        }
+    }
+    for storage in &response.storage_proof {
+        verify_storage(response.storage_hash, storage).context(format!(
+            "Invalid storage proof for {:?} slot {:#x}",
+            response.address, storage.key
+        ))?;
+    }
+    Ok(())
+}
+
+fn verify_storage(storage_root: H256, storage: &StorageProof) -> Result<()> {
+    let mut slot = [0u8; 32];
+    storage.key.to_big_endian(&mut slot);
+    let value = match verify_proof(storage_root, &keccak256(slot), &storage.proof)? {
+        Some(value) => Rlp::new(&value).as_val::<U256>()?,
+        None => U256::zero(),
+    };
+    if value != storage.value {
+        bail!("proven value {value} differs from {}", storage.value);
+    }
+    Ok(())
+}
+
+/// RLP encode the header of `block`, as hashed into its block hash, so guests
+/// can prove state against the header's state root. Fields added by later
+/// forks are appended when the node returns them. Fails if the encoding does
+/// not hash to the block's hash.
+pub fn encode_header(block: &Block<H256>) -> Result<Bytes> {
+    let missing = |field: &str| anyhow!("block is missing its {field}");
+    let mut stream = RlpStream::new();
+    stream.begin_unbounded_list();
+    stream
+        .append(&block.parent_hash)
+        .append(&block.uncles_hash)
+        .append(&block.author.ok_or_else(|| missing("author"))?)
+        .append(&block.state_root)
+        .append(&block.transactions_root)
+        .append(&block.receipts_root)
+        .append(&block.logs_bloom.ok_or_else(|| missing("logs bloom"))?)
+        .append(&block.difficulty)
+        .append(&block.number.ok_or_else(|| missing("number"))?)
+        .append(&block.gas_limit)
+        .append(&block.gas_used)
+        .append(&block.timestamp)
+        .append(&block.extra_data.as_ref())
+        .append(&block.mix_hash.ok_or_else(|| missing("mix hash"))?)
+        .append(&block.nonce.ok_or_else(|| missing("nonce"))?);
+    if let Some(base_fee) = block.base_fee_per_gas {
+        stream.append(&base_fee);
+    }
+    if let Some(withdrawals_root) = block.withdrawals_root {
+        stream.append(&withdrawals_root);
+    }
+    if let (Some(blob_gas_used), Some(excess_blob_gas)) =
+        (block.blob_gas_used, block.excess_blob_gas)
+    {
+        stream.append(&blob_gas_used).append(&excess_blob_gas);
+    }
+    if let Some(parent_beacon_block_root) = block.parent_beacon_block_root {
+        stream.append(&parent_beacon_block_root);
+    }
+    stream.finalize_unbounded_list();
+    let header = stream.out().freeze();
+
+    let hash = block.hash.ok_or_else(|| missing("hash"))?;
+    if H256(keccak256(&header)) != hash {
+        bail!("encoded header of block {hash:?} does not match its hash");
+    }
+    Ok(header.into())
+}
+
+/// Account part of a cached proof, shared by its storage slots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedAccount {
+    balance: U256,
+    code_hash: H256,
+    nonce: ethers::types::U64,
+    storage_hash: H256,
+    account_proof: Vec<Bytes>,
+}
+
+/// Where historical state proofs come from. Sources are not trusted: their
+/// proofs are checked against the state root of a header read from the
+/// Ethereum node, never against a root the source reports.
+pub trait ProofSource: Send + Sync {
+    /// `eth_getProof` of `account` and its storage `slots` at the block.
+    fn get_proof(
+        &self,
+        block_hash: H256,
+        account: Address,
+        slots: Vec<H256>,
+    ) -> BoxFuture<'_, Result<EIP1186ProofResponse>>;
+}
+
+/// Proofs served over Ethereum JSON-RPC, by an archive node or by a proof
+/// service speaking the same methods.
+pub struct RpcProofSource<M>(pub Arc<M>);
+
+impl<M: Middleware + 'static> ProofSource for RpcProofSource<M> {
+    fn get_proof(
+        &self,
+        block_hash: H256,
+        account: Address,
+        slots: Vec<H256>,
+    ) -> BoxFuture<'_, Result<EIP1186ProofResponse>> {
+        async move {
+            self.0
+                .get_proof(account, slots, Some(BlockId::Hash(block_hash)))
+                .await
+                .context(format!(
+                    "Failed to get proof of {account:?} at {block_hash:?}"
+                ))
+        }
+        .boxed()
+    }
+}
+
+/// Backend serving a chain's historical state proofs.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProofSourceConfig {
+    /// A websocket endpoint of a full archive node.
+    ArchiveNode { url: String },
+    /// An HTTP JSON-RPC endpoint serving `eth_getProof` for historical
+    /// blocks without being an archive node itself, such as a portal network
+    /// client or a hosted proof API.
+    ProofApi {
+        url: String,
+        /// Sent as a bearer token, if set.
+        api_key: Option<String>,
+    },
+}
+
+impl ProofSourceConfig {
+    pub async fn connect(&self) -> Result<Arc<dyn ProofSource>> {
+        match self {
+            ProofSourceConfig::ArchiveNode { url } => {
+                let provider = Provider::<Ws>::connect(url)
+                    .await
+                    .context(format!("Failed to connect to {url}"))?;
+                Ok(Arc::new(RpcProofSource(Arc::new(provider))))
+            }
+            ProofSourceConfig::ProofApi { url, api_key } => {
+                let mut headers = reqwest::header::HeaderMap::new();
+                if let Some(api_key) = api_key {
+                    register_secret(api_key);
+                    let mut value =
+                        reqwest::header::HeaderValue::from_str(&format!("Bearer {api_key}"))
+                            .context("Invalid proof API key")?;
+                    value.set_sensitive(true);
+                    headers.insert(reqwest::header::AUTHORIZATION, value);
+                }
+                let client = reqwest::Client::builder()
+                    .default_headers(headers)
+                    .build()
+                    .context("Failed to build proof API client")?;
+                let url =
+                    reqwest::Url::parse(url).context(format!("Invalid proof API URL {url}"))?;
+                let provider = Provider::new(Http::new_with_client(url, client));
+                Ok(Arc::new(RpcProofSource(Arc::new(provider))))
+            }
+        }
+    }
+}
+
+/// Proof backends per chain ID, as found in the proof sources file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProofSources(HashMap<u64, ProofSourceConfig>);
+
+impl ProofSources {
+    /// Load a JSON object mapping chain IDs to [ProofSourceConfig]s.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open proof sources {}", path.display()))?;
+        serde_json::from_reader(file)
+            .context(format!("Failed to parse proof sources {}", path.display()))
+    }
+
+    /// Connect to the proof backend configured for `chain_id`.
+    pub async fn connect(&self, chain_id: u64) -> Result<Arc<dyn ProofSource>> {
+        self.0
+            .get(&chain_id)
+            .ok_or_else(|| anyhow!("no proof source configured for chain {chain_id}"))?
+            .connect()
+            .await
+    }
+}
+
+/// Source of the proofs of the chain `node` is on: the one configured for its
+/// chain ID in the proof sources file at `sources`, or `node` itself.
+pub async fn proof_source<M: Middleware + 'static>(
+    node: Arc<M>,
+    sources: Option<&Path>,
+) -> Result<Arc<dyn ProofSource>> {
+    let Some(sources) = sources else {
+        return Ok(Arc::new(RpcProofSource(node)));
+    };
+    let chain_id = node
+        .get_chainid()
+        .await
+        .context("Failed to read the chain ID")?;
+    ProofSources::load(sources)?
+        .connect(chain_id.as_u64())
+        .await
+}
+
+/// On-disk cache of `eth_getProof` responses, keyed by block hash, account
+/// and storage slot, so backfills and replays do not refetch identical
+/// proofs. Entries are verified against the block's state root whenever they
+/// are read, so a tampered cache cannot feed forged state into a guest.
+pub struct ProofCache {
+    dir: PathBuf,
+}
+
+impl ProofCache {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create proof cache directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn account_dir(&self, block_hash: H256, account: Address) -> PathBuf {
+        self.dir
+            .join(hex::encode(block_hash))
+            .join(hex::encode(account))
+    }
+
+    fn read<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<Option<T>> {
+        match std::fs::read(path) {
+            Ok(buf) => serde_json::from_slice(&buf)
+                .map(Some)
+                .context(format!("Failed to parse {}", path.display())),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).context(format!("Failed to read {}", path.display())),
+        }
+    }
+
+    fn write<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .context(format!("Failed to create {}", parent.display()))?;
+        }
+        let buf = serde_json::to_vec(value).context("Failed to serialize proof")?;
+        std::fs::write(path, buf).context(format!("Failed to write {}", path.display()))
+    }
+
+    /// Cached proof of the account and slots, if every part is cached.
+    fn load(
+        &self,
+        block_hash: H256,
+        account: Address,
+        slots: &[H256],
+    ) -> Result<(Option<CachedAccount>, HashMap<H256, StorageProof>)> {
+        let dir = self.account_dir(block_hash, account);
+        let cached_account = Self::read(&dir.join("account.json"))?;
+        let mut storage = HashMap::new();
+        for slot in slots {
+            if let Some(proof) = Self::read(&dir.join(format!("{}.json", hex::encode(slot))))? {
+                storage.insert(*slot, proof);
+            }
+        }
+        Ok((cached_account, storage))
+    }
+
+    /// Proof of `account` and its storage `slots` at `block`, served from the
+    /// cache where possible and fetched from `source` otherwise. Proofs are
+    /// checked against the state root committed by the block's header, which
+    /// must come from the Ethereum node.
+    pub async fn get_proof(
+        &self,
+        source: &dyn ProofSource,
+        block: &Block<H256>,
+        account: Address,
+        slots: &[H256],
+    ) -> Result<EIP1186ProofResponse> {
+        // The header hashing to the block's hash binds its state root.
+        encode_header(block)?;
+        let block_hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        let state_root = block.state_root;
+        let (cached_account, mut storage) = self.load(block_hash, account, slots)?;
+        let missing: Vec<H256> = slots
+            .iter()
+            .filter(|slot| !storage.contains_key(slot))
+            .copied()
+            .collect();
+
+        let response = match cached_account {
+            Some(cached) if missing.is_empty() => EIP1186ProofResponse {
+                address: account,
+                balance: cached.balance,
+                code_hash: cached.code_hash,
+                nonce: cached.nonce,
+                storage_hash: cached.storage_hash,
+                account_proof: cached.account_proof,
+                storage_proof: slots
+                    .iter()
+                    .filter_map(|slot| storage.remove(slot))
+                    .collect(),
+            },
+            _ => {
+                let fetched = source
+                    .get_proof(block_hash, account, missing.clone())
+                    .await?;
+                verify_account(state_root, &fetched)?;
+                let dir = self.account_dir(block_hash, account);
+                Self::write(
+                    &dir.join("account.json"),
+                    &CachedAccount {
+                        balance: fetched.balance,
+                        code_hash: fetched.code_hash,
+                        nonce: fetched.nonce,
+                        storage_hash: fetched.storage_hash,
+                        account_proof: fetched.account_proof.clone(),
+                    },
+                )?;
+                for proof in &fetched.storage_proof {
+                    let mut slot = H256::zero();
+                    proof.key.to_big_endian(slot.as_bytes_mut());
+                    Self::write(&dir.join(format!("{}.json", hex::encode(slot))), proof)?;
+                    storage.insert(slot, proof.clone());
+                }
+                EIP1186ProofResponse {
+                    storage_proof: slots
+                        .iter()
+                        .filter_map(|slot| storage.remove(slot))
+                        .collect(),
+                    ..fetched
+                }
+            }
+        };
+        verify_account(state_root, &response).context(format!(
+            "Cached proof of {account:?} at {block_hash:?} is invalid"
+        ))?;
+        Ok(response)
+    }
+}
@@ -0,0 +1,60 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Provenance of guest images, recording what source and toolchains built
+//! them so consumers can audit the code behind the proofs they rely on.
+
+use serde::{Deserialize, Serialize};
+
+/// What a guest build was produced from, as recorded by the methods crate's
+/// build script for embedded guests, or in the guest directory's manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildProvenance {
+    /// Git commit of the source tree.
+    pub source_commit: Option<String>,
+    /// Whether the guest sources had uncommitted changes.
+    pub source_dirty: Option<bool>,
+    /// `rustc --version` of the host toolchain.
+    pub host_rustc: Option<String>,
+    /// `rustc --version` of the RISC Zero toolchain compiling the guests.
+    pub guest_rustc: Option<String>,
+    /// When the guests were built, in seconds since the Unix epoch.
+    pub built_at: Option<u64>,
+    /// Packages locked in the guest crate's Cargo.lock.
+    #[serde(default)]
+    pub dependencies: Vec<LockedPackage>,
+}
+
+/// A package locked in a Cargo.lock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    /// Registry or git source, absent for packages of the workspace.
+    pub source: Option<String>,
+    pub checksum: Option<String>,
+}
+
+/// Provenance of one guest image, as served at
+/// `/v1/guests/{id}/provenance`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestProvenance {
+    pub guest: String,
+    pub image_id: String,
+    /// SHA-256 of the guest ELF.
+    pub elf_sha256: String,
+    pub zkvm_version: Option<String>,
+    pub circuit: Option<String>,
+    pub build: BuildProvenance,
+}
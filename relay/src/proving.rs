@@ -0,0 +1,80 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    bonsai_api::{self, ApiRevision},
+    elog,
+    pool::ImagePool,
+    registry::Guest,
+};
+
+/// How guests are proven on Bonsai outside of dev mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ProvingMode {
+    /// Bonsai executes and proves the guest.
+    Remote,
+    /// Execute the guest locally and have Bonsai prove only the resulting
+    /// segments, saving Bonsai's execution time.
+    Hybrid,
+}
+
+impl ProvingMode {
+    /// The mode to prove in through the Bonsai API `revision` at `url`.
+    /// Hybrid proving needs a deployment listing the
+    /// [bonsai_api::SEGMENT_UPLOAD_FEATURE], which only the versioned API
+    /// can, and falls back to [ProvingMode::Remote] when the probe fails or
+    /// does not list it.
+    pub async fn negotiate(self, revision: ApiRevision, url: &str, api_key: &str) -> Self {
+        if self == ProvingMode::Remote {
+            return self;
+        }
+        if revision == ApiRevision::Alpha {
+            elog!(
+                "The Bonsai alpha API does not accept locally executed segments; proving remotely"
+            );
+            return ProvingMode::Remote;
+        }
+        match bonsai_api::probe(url, api_key).await {
+            Ok(capabilities) if capabilities.segment_upload => {
+                elog!("Proving segments executed by the relay on Bonsai");
+                self
+            }
+            Ok(_) => {
+                elog!("Bonsai does not accept locally executed segments; proving remotely");
+                ProvingMode::Remote
+            }
+            Err(err) => {
+                elog!("Failed to probe Bonsai for segment upload, proving remotely: {err:?}");
+                ProvingMode::Remote
+            }
+        }
+    }
+}
+
+/// Execute `guest` on `input` locally, returning the bincode encoded
+/// segments of the session for Bonsai to prove.
+pub fn execute_segments(pool: &ImagePool, guest: &Guest, input: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let session = pool.session(guest, input)?;
+    session
+        .segments
+        .iter()
+        .map(|segment| {
+            let segment = segment.resolve().context("Failed to resolve segment")?;
+            bincode::serialize(&segment).context("Failed to encode segment")
+        })
+        .collect()
+}
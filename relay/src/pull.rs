@@ -0,0 +1,262 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, AbiEncode, Token},
+    signers::{LocalWallet, Signer},
+    types::{Address, Signature},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{
+    bindings::SubmitCall, clock, elog, finality::FinalityPolicy, registry::Guest,
+    scheduler::ProofResult, schema::journal_validity, snark_seal, Output,
+};
+
+/// Operator's statement that an update was fresh until `expires_at`, so
+/// consumers can drop stale updates before paying to submit them. The
+/// signature is an EIP-191 signature over [Envelope::digest].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub job: String,
+    pub image_id: String,
+    /// Hex encoded SHA-256 digest of the journal, as the verifier checks it.
+    pub journal_hash: String,
+    /// Block timestamp of the latest chain state the update was computed
+    /// from, in seconds since the Unix epoch.
+    pub observed_at: u64,
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub signer: Address,
+    pub signature: String,
+}
+
+impl Envelope {
+    /// keccak256(abi.encode(job, imageId, journalHash, observedAt, issuedAt,
+    /// expiresAt)).
+    pub fn digest(&self) -> Result<[u8; 32]> {
+        let bytes32 = |hex: &str| -> Result<Token> {
+            Ok(Token::FixedBytes(
+                hex::decode(hex.trim_start_matches("0x")).context("Invalid envelope hash")?,
+            ))
+        };
+        Ok(keccak256(abi::encode(&[
+            Token::String(self.job.clone()),
+            bytes32(&self.image_id)?,
+            bytes32(&self.journal_hash)?,
+            Token::Uint(self.observed_at.into()),
+            Token::Uint(self.issued_at.into()),
+            Token::Uint(self.expires_at.into()),
+        ])))
+    }
+
+    /// Check the signature and that the envelope has not expired at `now`.
+    pub fn verify(&self, now: SystemTime) -> Result<()> {
+        let signature: Signature = self
+            .signature
+            .parse()
+            .context("Invalid envelope signature")?;
+        signature
+            .verify(self.digest()?.as_slice(), self.signer)
+            .map_err(|err| anyhow!("Envelope signature does not match its signer: {err}"))?;
+        if clock::unix_secs(now) > self.expires_at {
+            bail!("update expired at {}", self.expires_at);
+        }
+        Ok(())
+    }
+}
+
+/// Proven result of a job, served for consumers to submit on chain
+/// themselves instead of the relay pushing it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceUpdate {
+    pub job: String,
+    pub run: u64,
+    pub image_id: String,
+    pub journal: String,
+    pub seal: String,
+    pub post_state_digest: String,
+    /// Built from a block the sequencer could still reorder, see
+    /// [FinalityPolicy::Sequencer].
+    #[serde(default)]
+    pub unfinalized: bool,
+    /// The aggregator fed by the job, which consumers submit the update to.
+    #[serde(default)]
+    pub aggregator: Option<Address>,
+    /// Absent unless the relay has a signing key.
+    pub envelope: Option<Envelope>,
+}
+
+/// `ZkPriceAggregator.submit` call carrying an update.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateCalldata {
+    /// The aggregator to send the call to, if the relay knows it.
+    pub to: Option<Address>,
+    pub data: String,
+    pub envelope: Option<Envelope>,
+}
+
+/// Latest update of each job, for the pull model.
+pub struct PriceUpdates {
+    latest: RwLock<HashMap<String, PriceUpdate>>,
+    signer: Option<LocalWallet>,
+    ttl: Duration,
+}
+
+impl PriceUpdates {
+    /// Updates are fresh for `ttl` after the chain state they were computed
+    /// from was observed.
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            latest: Default::default(),
+            signer: None,
+            ttl,
+        }
+    }
+
+    /// Sign a freshness [Envelope] for every update.
+    pub fn with_signer(mut self, signer: LocalWallet) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Replace the latest update of `job`, which feeds `aggregator`, with
+    /// the result of a run.
+    pub async fn publish(
+        &self,
+        job: &str,
+        guest: &Guest,
+        aggregator: Option<Address>,
+        run: u64,
+        finality: Option<FinalityPolicy>,
+        output: &Output,
+    ) -> Result<()> {
+        let Output::Bonsai {
+            journal,
+            receipt_metadata,
+            snark_proof,
+            ..
+        } = output
+        else {
+            bail!("only results with a SNARK can be submitted on chain");
+        };
+        let now = clock::unix_now();
+        let observed_at = journal_validity(&guest.name, journal)?
+            .map(|validity| validity.observed_to)
+            .unwrap_or(now);
+        let mut update = PriceUpdate {
+            job: job.to_string(),
+            run,
+            image_id: hex::encode(guest.image_id),
+            journal: hex::encode(journal),
+            seal: hex::encode(snark_seal(snark_proof)?),
+            post_state_digest: hex::encode(receipt_metadata.post.digest()),
+            unfinalized: matches!(finality, Some(finality) if finality.unfinalized()),
+            aggregator,
+            envelope: None,
+        };
+        if let Some(signer) = &self.signer {
+            let mut envelope = Envelope {
+                job: job.to_string(),
+                image_id: update.image_id.clone(),
+                journal_hash: hex::encode(Sha256::digest(journal)),
+                observed_at,
+                issued_at: now,
+                expires_at: observed_at + self.ttl.as_secs(),
+                signer: signer.address(),
+                signature: String::new(),
+            };
+            let signature = signer
+                .sign_message(envelope.digest()?)
+                .await
+                .context("Failed to sign update envelope")?;
+            envelope.signature = signature.to_string();
+            update.envelope = Some(envelope);
+        }
+        let mut latest = self
+            .latest
+            .write()
+            .map_err(|_| anyhow!("price updates lock poisoned"))?;
+        match latest.get(job) {
+            Some(previous) if previous.run > run => (),
+            _ => {
+                latest.insert(job.to_string(), update);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn latest(&self, job: &str) -> Result<Option<PriceUpdate>> {
+        Ok(self
+            .latest
+            .read()
+            .map_err(|_| anyhow!("price updates lock poisoned"))?
+            .get(job)
+            .cloned())
+    }
+
+    /// The latest update of `job` as a ready to send `submit` call.
+    pub fn latest_calldata(&self, job: &str) -> Result<Option<UpdateCalldata>> {
+        let Some(update) = self.latest(job)? else {
+            return Ok(None);
+        };
+        let decode = |hex: &str| hex::decode(hex).context("Invalid stored update");
+        let post_state_digest: [u8; 32] = decode(&update.post_state_digest)?
+            .try_into()
+            .map_err(|_| anyhow!("Invalid stored post state digest"))?;
+        let call = SubmitCall {
+            journal: decode(&update.journal)?.into(),
+            seal: decode(&update.seal)?.into(),
+            post_state_digest,
+        };
+        Ok(Some(UpdateCalldata {
+            to: update.aggregator,
+            data: format!("0x{}", hex::encode(call.encode())),
+            envelope: update.envelope,
+        }))
+    }
+
+    /// Publish every successful run of a job feeding `aggregator` until the
+    /// job stops.
+    pub async fn watch(
+        &self,
+        job: &str,
+        guest: &Guest,
+        aggregator: Option<Address>,
+        results: impl Stream<Item = ProofResult> + Unpin,
+    ) {
+        let mut results = results;
+        while let Some(result) = results.next().await {
+            let Ok(output) = result.output else {
+                continue;
+            };
+            if let Err(err) = self
+                .publish(job, guest, aggregator, result.run, result.finality, &output)
+                .await
+            {
+                elog!("Job {job} run {} was not published: {err:?}", result.run);
+            }
+        }
+    }
+}
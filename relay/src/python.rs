@@ -0,0 +1,246 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Python bindings, built as the `relay_py` extension module with the
+//! `python` feature, e.g. with `maturin build --features python` from this
+//! crate's directory. They drive a relay serving its API: submit inputs as
+//! sessions, read their status, and decode guest journals with the relay's
+//! own schemas.
+//!
+//! ```python
+//! from relay_py import RelayClient, decode_journal
+//! client = RelayClient("http://localhost:8080", api_key="...")
+//! session = client.submit("SWAP", input_hex)
+//! status = client.wait(session, timeout_secs=300)
+//! values = decode_journal("SWAP", bytes.fromhex(status["result"]["journal"]))
+//! ```
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use ethers::{abi::Token, types::I256};
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::{PyBytes, PyDict, PyList, PyTuple},
+};
+use reqwest::{blocking::Client, header::CONTENT_TYPE};
+use serde_json::{json, Value};
+
+use crate::{schema, server::API_KEY_HEADER, sessions::MAX_WAIT};
+
+fn runtime_error(err: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{err:#}"))
+}
+
+/// Blocking client of a relay's proving API.
+#[pyclass]
+pub struct RelayClient {
+    url: String,
+    api_key: Option<String>,
+    client: Client,
+}
+
+impl RelayClient {
+    fn send(&self, request: reqwest::blocking::RequestBuilder) -> anyhow::Result<Value> {
+        let request = match &self.api_key {
+            Some(key) => request.header(API_KEY_HEADER, key),
+            None => request,
+        };
+        let response = request.send().context("Failed to reach the relay")?;
+        let status = response.status();
+        let body: Value = response.json().context("Relay returned invalid JSON")?;
+        if !status.is_success() {
+            match body.get("error").and_then(Value::as_str) {
+                Some(error) => bail!("relay returned {status}: {error}"),
+                None => bail!("relay returned {status}"),
+            }
+        }
+        Ok(body)
+    }
+}
+
+#[pymethods]
+impl RelayClient {
+    #[new]
+    #[pyo3(signature = (url, api_key = None, timeout_secs = 300))]
+    fn new(url: String, api_key: Option<String>, timeout_secs: u64) -> PyResult<Self> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(timeout_secs))
+            .build()
+            .map_err(|err| runtime_error(err.into()))?;
+        Ok(Self {
+            url: url.trim_end_matches('/').to_string(),
+            api_key,
+            client,
+        })
+    }
+
+    /// Start proving a hex encoded input with a guest, given by name or hex
+    /// image ID. Returns the session ID to poll. The salt of a private input
+    /// is drawn at random unless given, and returned with the result.
+    #[pyo3(signature = (guest, input, private_input = None, requester = None, private_salt = None))]
+    fn submit(
+        &self,
+        py: Python<'_>,
+        guest: &str,
+        input: &str,
+        private_input: Option<&str>,
+        requester: Option<&str>,
+        private_salt: Option<&str>,
+    ) -> PyResult<String> {
+        let body = json!({
+            "guest": guest,
+            "input": input,
+            "private_input": private_input,
+            "private_salt": private_salt,
+            "requester": requester,
+        });
+        let created = py
+            .allow_threads(|| {
+                self.send(
+                    self.client
+                        .post(format!("{}/v1/sessions", self.url))
+                        .header(CONTENT_TYPE, "application/json")
+                        .body(body.to_string()),
+                )
+            })
+            .map_err(runtime_error)?;
+        created
+            .get("session_id")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| PyRuntimeError::new_err("relay returned no session ID"))
+    }
+
+    /// Status of a session, as a dict with a `status` of `running`,
+    /// `succeeded` with its `result`, or `failed` with its `error`.
+    fn status(&self, py: Python<'_>, session_id: &str) -> PyResult<PyObject> {
+        let status = py
+            .allow_threads(|| {
+                self.send(
+                    self.client
+                        .get(format!("{}/v1/sessions/{session_id}", self.url)),
+                )
+            })
+            .map_err(runtime_error)?;
+        json_to_py(py, &status)
+    }
+
+    /// Block until a session finishes or `timeout_secs` elapse, returning
+    /// its status as `status` does.
+    #[pyo3(signature = (session_id, timeout_secs = 60))]
+    fn wait(&self, py: Python<'_>, session_id: &str, timeout_secs: u64) -> PyResult<PyObject> {
+        let deadline = std::time::Instant::now() + Duration::from_secs(timeout_secs);
+        loop {
+            let remaining = deadline.saturating_duration_since(std::time::Instant::now());
+            let timeout = remaining.min(MAX_WAIT).as_secs();
+            let status = py
+                .allow_threads(|| {
+                    self.send(self.client.get(format!(
+                        "{}/v1/sessions/{session_id}/wait?timeout={timeout}",
+                        self.url
+                    )))
+                })
+                .map_err(runtime_error)?;
+            let running = status.get("status").and_then(Value::as_str) == Some("running");
+            if !running || timeout == 0 {
+                return json_to_py(py, &status);
+            }
+            py.check_signals()?;
+        }
+    }
+}
+
+/// Decode a guest's journal into a list of its values. Addresses and hashes
+/// are returned as bytes and integers as Python ints.
+#[pyfunction]
+fn decode_journal(py: Python<'_>, guest: &str, journal: &[u8]) -> PyResult<PyObject> {
+    let tokens = schema::decode_journal(guest, journal)
+        .map_err(|err| PyValueError::new_err(format!("{err:#}")))?
+        .ok_or_else(|| PyValueError::new_err(format!("guest {guest} has no journal schema")))?;
+    let values = tokens
+        .iter()
+        .map(|token| token_to_py(py, token))
+        .collect::<PyResult<Vec<_>>>()?;
+    Ok(PyList::new(py, values).into_py(py))
+}
+
+fn int_to_py(py: Python<'_>, decimal: String) -> PyResult<PyObject> {
+    Ok(py
+        .import("builtins")?
+        .getattr("int")?
+        .call1((decimal,))?
+        .into_py(py))
+}
+
+fn token_to_py(py: Python<'_>, token: &Token) -> PyResult<PyObject> {
+    Ok(match token {
+        Token::Address(address) => PyBytes::new(py, address.as_bytes()).into_py(py),
+        Token::FixedBytes(bytes) | Token::Bytes(bytes) => PyBytes::new(py, bytes).into_py(py),
+        Token::Uint(value) => int_to_py(py, value.to_string())?,
+        Token::Int(value) => int_to_py(py, I256::from_raw(*value).to_string())?,
+        Token::Bool(value) => (*value).into_py(py),
+        Token::String(value) => value.as_str().into_py(py),
+        Token::Array(tokens) | Token::FixedArray(tokens) => {
+            let values = tokens
+                .iter()
+                .map(|token| token_to_py(py, token))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyList::new(py, values).into_py(py)
+        }
+        Token::Tuple(tokens) => {
+            let values = tokens
+                .iter()
+                .map(|token| token_to_py(py, token))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyTuple::new(py, values).into_py(py)
+        }
+    })
+}
+
+fn json_to_py(py: Python<'_>, value: &Value) -> PyResult<PyObject> {
+    Ok(match value {
+        Value::Null => py.None(),
+        Value::Bool(value) => (*value).into_py(py),
+        Value::Number(number) => match (number.as_i64(), number.as_u64()) {
+            (Some(value), _) => value.into_py(py),
+            (None, Some(value)) => value.into_py(py),
+            (None, None) => number.as_f64().unwrap_or_default().into_py(py),
+        },
+        Value::String(value) => value.as_str().into_py(py),
+        Value::Array(values) => {
+            let values = values
+                .iter()
+                .map(|value| json_to_py(py, value))
+                .collect::<PyResult<Vec<_>>>()?;
+            PyList::new(py, values).into_py(py)
+        }
+        Value::Object(entries) => {
+            let dict = PyDict::new(py);
+            for (key, value) in entries {
+                dict.set_item(key, json_to_py(py, value)?)?;
+            }
+            dict.into_py(py)
+        }
+    })
+}
+
+/// The `relay_py` extension module.
+#[pymodule]
+fn relay_py(_py: Python<'_>, module: &PyModule) -> PyResult<()> {
+    module.add_class::<RelayClient>()?;
+    module.add_function(wrap_pyfunction!(decode_journal, module)?)?;
+    Ok(())
+}
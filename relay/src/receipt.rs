@@ -0,0 +1,206 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{io::Write, path::Path};
+
+use anyhow::{anyhow, bail, Context, Result};
+use bincode::Options;
+use risc0_zkvm::{
+    recursion::{join, lift},
+    sha::Digest,
+    InnerReceipt, Receipt,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+/// Receipt layouts the relay knows how to read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReceiptFormat {
+    /// `Receipt` as produced by the zkVM version this crate is built against.
+    Current,
+    /// `SessionReceipt` from risc0 0.16 and earlier.
+    LegacySession,
+    /// `SessionRollupReceipt` returned by the Bonsai alpha rollup prover.
+    LegacyRollup,
+}
+
+/// Segment receipt as serialized by risc0 0.16.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegacySegmentReceipt {
+    pub seal: Vec<u32>,
+    pub index: u32,
+}
+
+/// Layout of a risc0 0.16 `SessionReceipt`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegacySessionReceipt {
+    pub segments: Vec<LegacySegmentReceipt>,
+    pub journal: Vec<u8>,
+}
+
+/// Layout of a Bonsai alpha `SessionRollupReceipt`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LegacyRollupReceipt {
+    pub seal: Vec<u32>,
+    pub journal: Vec<u8>,
+}
+
+/// A receipt in any supported format, with a uniform interface over the
+/// parts the relay needs.
+#[derive(Debug, Clone)]
+pub enum ReceiptEnvelope {
+    Current(Receipt),
+    LegacySession(LegacySessionReceipt),
+    LegacyRollup(LegacyRollupReceipt),
+}
+
+/// Strict bincode decoding: a format only matches if it consumes the whole
+/// buffer, which keeps detection from mistaking one layout for another.
+fn decode_exact<T: DeserializeOwned>(buf: &[u8]) -> Result<T, bincode::Error> {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(buf)
+}
+
+impl ReceiptEnvelope {
+    /// Detect the format of a serialized receipt and decode it.
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let current_err = match decode_exact::<Receipt>(buf) {
+            Ok(receipt) => return Ok(ReceiptEnvelope::Current(receipt)),
+            Err(err) => err,
+        };
+        if let Ok(receipt) = decode_exact::<LegacySessionReceipt>(buf) {
+            return Ok(ReceiptEnvelope::LegacySession(receipt));
+        }
+        if let Ok(receipt) = decode_exact::<LegacyRollupReceipt>(buf) {
+            return Ok(ReceiptEnvelope::LegacyRollup(receipt));
+        }
+        Err(anyhow!(current_err).context("Receipt does not match any supported format"))
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let buf = std::fs::read(path).context(format!("Failed to read receipt {path:?}"))?;
+        Self::decode(&buf).context(format!("Failed to decode receipt {path:?}"))
+    }
+
+    pub fn format(&self) -> ReceiptFormat {
+        match self {
+            ReceiptEnvelope::Current(_) => ReceiptFormat::Current,
+            ReceiptEnvelope::LegacySession(_) => ReceiptFormat::LegacySession,
+            ReceiptEnvelope::LegacyRollup(_) => ReceiptFormat::LegacyRollup,
+        }
+    }
+
+    pub fn journal(&self) -> &[u8] {
+        match self {
+            ReceiptEnvelope::Current(receipt) => &receipt.journal,
+            ReceiptEnvelope::LegacySession(receipt) => &receipt.journal,
+            ReceiptEnvelope::LegacyRollup(receipt) => &receipt.journal,
+        }
+    }
+
+    /// Seal words, concatenated in segment order for segmented receipts.
+    pub fn seal(&self) -> Vec<u32> {
+        match self {
+            ReceiptEnvelope::Current(receipt) => match &receipt.inner {
+                InnerReceipt::Flat(segments) => segments
+                    .0
+                    .iter()
+                    .flat_map(|segment| segment.seal.iter().copied())
+                    .collect(),
+                InnerReceipt::Succinct(receipt) => receipt.seal.clone(),
+            },
+            ReceiptEnvelope::LegacySession(receipt) => receipt
+                .segments
+                .iter()
+                .flat_map(|segment| segment.seal.iter().copied())
+                .collect(),
+            ReceiptEnvelope::LegacyRollup(receipt) => receipt.seal.clone(),
+        }
+    }
+
+    /// Verify the receipt against an image ID. Legacy formats can still be
+    /// inspected, but their seals are only verifiable by the prover version
+    /// that produced them.
+    pub fn verify(&self, image_id: Digest) -> Result<()> {
+        match self {
+            ReceiptEnvelope::Current(receipt) => receipt
+                .verify(image_id)
+                .map_err(|err| anyhow!("Receipt verification failed: {err}")),
+            _ => bail!(
+                "Receipts in {:?} format cannot be verified by this zkVM version",
+                self.format()
+            ),
+        }
+    }
+
+    /// Returns the receipt in the current format, if it is one.
+    pub fn into_current(self) -> Result<Receipt> {
+        match self {
+            ReceiptEnvelope::Current(receipt) => Ok(receipt),
+            other => bail!("Expected a current receipt, got {:?}", other.format()),
+        }
+    }
+}
+
+/// Write `receipt` to `path` in the format [ReceiptEnvelope::from_file]
+/// reads. The receipt is written to a temporary file in the same directory
+/// and renamed over `path`, so an interrupted write leaves no partial
+/// receipt, and the rename never crosses volumes as one out of the system's
+/// temporary directory could, e.g. to another drive on Windows.
+pub fn write_file(receipt: &Receipt, path: &Path) -> Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let data = bincode::serialize(receipt).context("Failed to serialize receipt")?;
+    let mut file = NamedTempFile::new_in(dir)
+        .context(format!("Failed to create a temp file in {}", dir.display()))?;
+    file.write_all(&data)
+        .context(format!("Failed to write receipt {}", path.display()))?;
+    file.persist(path)
+        .context(format!("Failed to persist receipt {}", path.display()))?;
+    Ok(())
+}
+
+impl From<Receipt> for ReceiptEnvelope {
+    fn from(receipt: Receipt) -> Self {
+        ReceiptEnvelope::Current(receipt)
+    }
+}
+
+/// Compress a composite receipt into a succinct one by lifting each segment
+/// receipt and joining the results in order. This is local recursion
+/// proving: expensive, but the succinct seal does not grow with the number
+/// of segments. Succinct receipts are returned as they are.
+pub fn compress(receipt: &Receipt) -> Result<Receipt> {
+    let segments = match &receipt.inner {
+        InnerReceipt::Flat(segments) => &segments.0,
+        InnerReceipt::Succinct(_) => return Ok(receipt.clone()),
+    };
+    let (first, rest) = segments
+        .split_first()
+        .ok_or_else(|| anyhow!("Receipt has no segments to compress"))?;
+    let mut succinct = lift(first).context("Failed to lift segment 0")?;
+    for segment in rest {
+        let lifted = lift(segment).context(format!("Failed to lift segment {}", segment.index))?;
+        succinct = join(&succinct, &lifted)
+            .context(format!("Failed to join segment {}", segment.index))?;
+    }
+    Ok(Receipt::new(
+        InnerReceipt::Succinct(succinct),
+        receipt.journal.clone(),
+    ))
+}
@@ -0,0 +1,215 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Masking of secrets in log output and error messages.
+
+use std::{collections::VecDeque, sync::Mutex, time::SystemTime};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Replacement for masked secrets.
+pub const MASK: &str = "[REDACTED]";
+
+/// Secrets shorter than this are not registered, so that masking them does
+/// not garble unrelated output.
+const MIN_SECRET_LEN: usize = 6;
+
+/// Query parameters whose values are masked in URLs.
+const SECRET_PARAMS: &[&str] = &[
+    "key",
+    "api_key",
+    "apikey",
+    "api-key",
+    "token",
+    "access_token",
+    "secret",
+    "password",
+];
+
+/// URL path segments at least this long and made of key characters are taken
+/// for API keys, as many RPC providers embed them in the path.
+const MIN_PATH_KEY_LEN: usize = 24;
+
+/// Number of recent log lines kept for [recent_logs].
+const RECENT_LOG_LINES: usize = 1000;
+
+static SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Recent log lines with the time they were printed and their trace ID.
+static RECENT_LOGS: Mutex<VecDeque<RecentLog>> = Mutex::new(VecDeque::new());
+
+type RecentLog = (SystemTime, Option<String>, String);
+
+/// Mask every occurrence of `secret` in redacted output from now on, such as
+/// an API key or a private key read from the configuration.
+pub fn register_secret(secret: &str) {
+    let secret = secret.trim();
+    if secret.len() < MIN_SECRET_LEN {
+        return;
+    }
+    let mut secrets = match SECRETS.lock() {
+        Ok(secrets) => secrets,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+        // Mask longer secrets first, in case one contains another.
+        secrets.sort_by_key(|s| std::cmp::Reverse(s.len()));
+    }
+}
+
+/// Mask registered secrets and URL credentials in `text`: user info, secret
+/// query parameters and key-like path segments.
+pub fn redact(text: &str) -> String {
+    let mut out = mask_urls(text);
+    let secrets = match SECRETS.lock() {
+        Ok(secrets) => secrets,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    for secret in secrets.iter() {
+        if out.contains(secret.as_str()) {
+            out = out.replace(secret.as_str(), MASK);
+        }
+    }
+    out
+}
+
+/// Serialize `value` to JSON with every string in it redacted, for records
+/// persisted by the relay, such as run logs and job records.
+pub fn redact_json(value: &impl Serialize) -> serde_json::Result<Vec<u8>> {
+    fn redact_strings(value: &mut Value) {
+        match value {
+            Value::String(text) => *text = redact(text),
+            Value::Array(values) => values.iter_mut().for_each(redact_strings),
+            Value::Object(fields) => fields.values_mut().for_each(redact_strings),
+            Value::Null | Value::Bool(_) | Value::Number(_) => (),
+        }
+    }
+    let mut value = serde_json::to_value(value)?;
+    redact_strings(&mut value);
+    serde_json::to_vec(&value)
+}
+
+/// Print a log line to stderr with secrets masked, keeping it for
+/// [recent_logs]. Called by [elog].
+pub fn log(text: &str) {
+    let trace_id = crate::trace::current();
+    let line = match &trace_id {
+        Some(trace_id) => redact(&format!("[{trace_id}] {text}")),
+        None => redact(text),
+    };
+    eprintln!("{line}");
+    let mut recent = match RECENT_LOGS.lock() {
+        Ok(recent) => recent,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if recent.len() == RECENT_LOG_LINES {
+        recent.pop_front();
+    }
+    recent.push_back((SystemTime::now(), trace_id, line));
+}
+
+/// Redacted log lines printed since `since` under `trace_id`, as far as they
+/// are still kept. Lines of concurrent requests carry other trace IDs.
+pub fn recent_logs(since: SystemTime, trace_id: &str) -> Vec<String> {
+    let recent = match RECENT_LOGS.lock() {
+        Ok(recent) => recent,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    recent
+        .iter()
+        .filter(|(at, id, _)| *at >= since && id.as_deref() == Some(trace_id))
+        .map(|(_, _, line)| line.clone())
+        .collect()
+}
+
+fn mask_urls(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(sep) = rest.find("://") {
+        let start = rest[..sep]
+            .rfind(|c: char| !(c.is_ascii_alphanumeric() || "+-.".contains(c)))
+            .map_or(0, |i| i + 1);
+        let end = rest[sep..]
+            .find(|c: char| c.is_whitespace() || "\"'<>()[]{},".contains(c))
+            .map_or(rest.len(), |i| sep + i);
+        out.push_str(&rest[..start]);
+        out.push_str(&rest[start..sep + 3]);
+        out.push_str(&mask_url_tail(&rest[sep + 3..end]));
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Mask the part of a URL after its `scheme://`.
+fn mask_url_tail(tail: &str) -> String {
+    let (before_fragment, fragment) = match tail.find('#') {
+        Some(i) => tail.split_at(i),
+        None => (tail, ""),
+    };
+    let (before_query, query) = match before_fragment.find('?') {
+        Some(i) => (&before_fragment[..i], Some(&before_fragment[i + 1..])),
+        None => (before_fragment, None),
+    };
+    let (authority, path) = match before_query.find('/') {
+        Some(i) => before_query.split_at(i),
+        None => (before_query, ""),
+    };
+
+    let mut out = match authority.rfind('@') {
+        Some(i) => format!("{MASK}{}", &authority[i..]),
+        None => authority.to_string(),
+    };
+    let path: Vec<&str> = path
+        .split('/')
+        .map(|segment| {
+            let is_key = segment.len() >= MIN_PATH_KEY_LEN
+                && segment
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if is_key {
+                MASK
+            } else {
+                segment
+            }
+        })
+        .collect();
+    out.push_str(&path.join("/"));
+    if let Some(query) = query {
+        let params: Vec<String> = query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some((name, _)) if SECRET_PARAMS.contains(&name.to_lowercase().as_str()) => {
+                    format!("{name}={MASK}")
+                }
+                _ => param.to_string(),
+            })
+            .collect();
+        out.push('?');
+        out.push_str(&params.join("&"));
+    }
+    out.push_str(fragment);
+    out
+}
+
+/// `eprintln!` with secrets masked by [redact]. All relay log output goes
+/// through this.
+#[macro_export]
+macro_rules! elog {
+    ($($arg:tt)*) => {
+        $crate::redact::log(&format!($($arg)*))
+    };
+}
@@ -0,0 +1,182 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Regeneration of historical proofs, to audit disputed oracle updates: the
+//! input a submitted journal was proven from is rebuilt at the blocks the
+//! journal commits, proven again and compared with the submitted journal.
+//! Storage proofs come from an archive of fetched proofs where it has them
+//! and are fetched again otherwise.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{Address, H256},
+};
+
+use crate::{
+    schema::{decode_journal, split_journal_version},
+    snapshot::Difference,
+    solidity::field_names,
+    twap::{TwapFetcher, TwapInput},
+};
+
+/// Values of a TWAP journal locating the input it was proven from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TwapJournal {
+    pub pool: Address,
+    pub observed_from: u64,
+    pub observed_to: u64,
+    /// Blocks the observations were proven at, in ascending order.
+    pub block_hashes: Vec<H256>,
+}
+
+impl TwapJournal {
+    pub fn decode(journal: &[u8]) -> Result<Self> {
+        let tokens = decode_journal("TWAP", journal)?
+            .ok_or_else(|| anyhow!("TWAP has no journal schema"))?;
+        let invalid = |field: &str| anyhow!("TWAP journal has no valid {field}");
+        let timestamp = |i: usize, field: &str| {
+            tokens
+                .get(i)
+                .cloned()
+                .and_then(Token::into_uint)
+                .map(|value| value.low_u64())
+                .ok_or_else(|| invalid(field))
+        };
+        let pool = tokens
+            .first()
+            .cloned()
+            .and_then(Token::into_address)
+            .ok_or_else(|| invalid("pool"))?;
+        let block_hashes = tokens
+            .get(5)
+            .cloned()
+            .and_then(Token::into_array)
+            .ok_or_else(|| invalid("block hashes"))?
+            .into_iter()
+            .map(|token| {
+                token
+                    .into_fixed_bytes()
+                    .filter(|hash| hash.len() == 32)
+                    .map(|hash| H256::from_slice(&hash))
+                    .ok_or_else(|| invalid("block hash"))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            pool,
+            observed_from: timestamp(3, "observation timestamp")?,
+            observed_to: timestamp(4, "observation timestamp")?,
+            block_hashes,
+        })
+    }
+}
+
+/// The journal of a submission's calldata: the first argument of a
+/// `(bytes journal, bytes seal, bytes32 postStateDigest)` call, as built by
+/// [crate::oneshot::verifier_calldata].
+pub fn submitted_journal(calldata: &[u8]) -> Result<Vec<u8>> {
+    if calldata.len() < 4 {
+        bail!("submission calldata has no function selector");
+    }
+    let params = [
+        ParamType::Bytes,
+        ParamType::Bytes,
+        ParamType::FixedBytes(32),
+    ];
+    abi::decode(&params, &calldata[4..])
+        .context("Submission calldata is not a (bytes, bytes, bytes32) call")?
+        .into_iter()
+        .next()
+        .and_then(Token::into_bytes)
+        .ok_or_else(|| anyhow!("submission calldata has no journal"))
+}
+
+/// Rebuild the input `journal` was proven from, checking that it was
+/// anchored at `block`: the last block it commits.
+pub async fn twap_input<M: Middleware + 'static>(
+    client: &M,
+    fetcher: &TwapFetcher<M>,
+    journal: &TwapJournal,
+    block: u64,
+) -> Result<TwapInput> {
+    let end = journal
+        .block_hashes
+        .last()
+        .ok_or_else(|| anyhow!("TWAP journal commits no blocks"))?;
+    let number = client
+        .get_block(*end)
+        .await
+        .context(format!("Failed to get block {end:?}"))?
+        .and_then(|block| block.number)
+        .ok_or_else(|| anyhow!("block {end:?} the journal commits is not canonical"))?
+        .as_u64();
+    if number != block {
+        bail!("journal was proven at block {number}, not {block}");
+    }
+    fetcher
+        .fetch_at(journal.pool, &journal.block_hashes, journal.observed_from)
+        .await
+}
+
+/// Compare the values of two journals of a guest, naming the fields that
+/// differ. Journals of guests without a schema are compared as a whole.
+pub fn compare_journals(
+    guest_name: &str,
+    submitted: &[u8],
+    regenerated: &[u8],
+) -> Result<Vec<Difference>> {
+    let mut diffs = Vec::new();
+    let (a, b) = (
+        decode_journal(guest_name, submitted)?,
+        decode_journal(guest_name, regenerated)?,
+    );
+    let (Some(a), Some(b)) = (a, b) else {
+        if submitted != regenerated {
+            diffs.push(Difference {
+                field: "journal".to_string(),
+                a: hex::encode(submitted),
+                b: hex::encode(regenerated),
+            });
+        }
+        return Ok(diffs);
+    };
+    let (version_a, _) = split_journal_version(submitted);
+    let (version_b, _) = split_journal_version(regenerated);
+    if version_a != version_b {
+        diffs.push(Difference {
+            field: "version".to_string(),
+            a: version_a.to_string(),
+            b: version_b.to_string(),
+        });
+    }
+    let names = field_names(guest_name).unwrap_or_default();
+    for i in 0..a.len().max(b.len()) {
+        let (a, b) = (a.get(i), b.get(i));
+        if a == b {
+            continue;
+        }
+        let field = match names.get(i) {
+            Some(name) => name.split(':').next().unwrap_or(name).to_string(),
+            None => format!("value {i}"),
+        };
+        let show = |token: Option<&Token>| token.map_or("-".to_string(), |t| t.to_string());
+        diffs.push(Difference {
+            field,
+            a: show(a),
+            b: show(b),
+        });
+    }
+    Ok(diffs)
+}
@@ -0,0 +1,543 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::File,
+    io::{ErrorKind, Read},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    signers::{LocalWallet, Signer},
+    types::{Address, Signature},
+    utils::keccak256,
+};
+use memmap2::Mmap;
+use risc0_build::GuestListEntry;
+use risc0_zkvm::sha::Digest;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::{image_digest, sha256_hex},
+    elog,
+    provenance::{BuildProvenance, GuestProvenance},
+    version::{check_guest, VersionPolicy, HOST_CIRCUIT, HOST_ZKVM_VERSION},
+};
+
+/// Borrowed view of a guest ELF, either embedded in the binary or mapped
+/// from disk.
+#[derive(Clone)]
+pub enum Elf {
+    Embedded(&'static [u8]),
+    Mapped(Arc<Mmap>),
+}
+
+impl Deref for Elf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Elf::Embedded(elf) => elf,
+            Elf::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+enum ElfSource {
+    Embedded(&'static [u8]),
+    File {
+        path: PathBuf,
+        mapping: Mutex<Option<Arc<Mmap>>>,
+        /// SHA-256 of the file when its signature was checked, which it must
+        /// still match when mapped again.
+        checksum: Option<String>,
+    },
+}
+
+/// A guest program known to the relay.
+pub struct Guest {
+    pub name: String,
+    pub image_id: Digest,
+    /// zkVM release the guest was built with, if known.
+    pub zkvm_version: Option<String>,
+    /// Proving circuit the guest targets, if known.
+    pub circuit: Option<String>,
+    /// What the guest was built from, if recorded.
+    pub build_provenance: Option<Arc<BuildProvenance>>,
+    source: ElfSource,
+}
+
+impl Guest {
+    /// Returns the guest ELF, mapping it into memory on first use.
+    pub fn elf(&self) -> Result<Elf> {
+        match &self.source {
+            ElfSource::Embedded(elf) => Ok(Elf::Embedded(elf)),
+            ElfSource::File {
+                path,
+                mapping,
+                checksum,
+            } => {
+                let mut mapping = mapping
+                    .lock()
+                    .map_err(|_| anyhow!("guest mapping lock poisoned"))?;
+                if let Some(mmap) = mapping.as_ref() {
+                    return Ok(Elf::Mapped(mmap.clone()));
+                }
+                let mmap = Arc::new(map_file(path)?);
+                if matches!(checksum, Some(checksum) if *checksum != sha256_hex(&mmap)) {
+                    bail!("guest ELF {path:?} changed since its signature was checked");
+                }
+                *mapping = Some(mmap.clone());
+                Ok(Elf::Mapped(mmap))
+            }
+        }
+    }
+
+    /// Drop the registry's mapping of a file backed ELF. Outstanding [Elf]
+    /// handles stay valid; the mapping is released once they are dropped.
+    pub fn release(&self) {
+        if let ElfSource::File { mapping, .. } = &self.source {
+            if let Ok(mut mapping) = mapping.lock() {
+                *mapping = None;
+            }
+        }
+    }
+
+    /// Path of the ELF on disk, if the guest is not embedded.
+    pub fn path(&self) -> Option<&Path> {
+        match &self.source {
+            ElfSource::Embedded(_) => None,
+            ElfSource::File { path, .. } => Some(path),
+        }
+    }
+}
+
+impl Guest {
+    /// Guest backed by a single ELF file outside the registry, named after
+    /// its file stem, such as a new image under validation.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let name = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| anyhow!("invalid guest file name {path:?}"))?
+            .to_uppercase();
+        let image_id = image_digest(&map_file(path)?)
+            .context(format!("Failed to compute image ID of {path:?}"))?;
+        Ok(Self {
+            name,
+            image_id,
+            zkvm_version: None,
+            circuit: None,
+            build_provenance: None,
+            source: ElfSource::File {
+                path: path.to_path_buf(),
+                mapping: Mutex::new(None),
+                checksum: None,
+            },
+        })
+    }
+
+    /// The guest's provenance, if its build was recorded.
+    pub fn provenance(&self) -> Result<Option<GuestProvenance>> {
+        let Some(build) = &self.build_provenance else {
+            return Ok(None);
+        };
+        Ok(Some(GuestProvenance {
+            guest: self.name.clone(),
+            image_id: hex::encode(self.image_id),
+            elf_sha256: sha256_hex(&self.elf()?),
+            zkvm_version: self.zkvm_version.clone(),
+            circuit: self.circuit.clone(),
+            build: build.as_ref().clone(),
+        }))
+    }
+}
+
+/// Whether the file at `path` starts with the ELF magic number.
+fn is_elf(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path).context(format!("Failed to open {path:?}"))?;
+    match file.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == b"\x7fELF"),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err).context(format!("Failed to read {path:?}")),
+    }
+}
+
+fn map_file(path: &Path) -> Result<Mmap> {
+    let file = File::open(path).context(format!("Failed to open guest ELF {path:?}"))?;
+    // SAFETY: guest ELFs are treated as read-only artifacts; the relay never
+    // writes to them while mapped.
+    unsafe { Mmap::map(&file) }.context(format!("Failed to map guest ELF {path:?}"))
+}
+
+/// Name of the build manifest optionally shipped alongside guest ELFs.
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Build metadata of a guest ELF, as recorded in [MANIFEST_FILE].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// File name of the ELF within the guest directory.
+    pub file: String,
+    /// Name to register the guest under, defaults to the file stem.
+    pub name: Option<String>,
+    pub zkvm_version: Option<String>,
+    pub circuit: Option<String>,
+    /// Hex encoded EIP-191 signature of [guest_digest] by an operator key,
+    /// required when the registry is given guest signers.
+    pub signature: Option<String>,
+    pub provenance: Option<BuildProvenance>,
+}
+
+fn read_manifest(dir: &Path) -> Result<Vec<ManifestEntry>> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let buf = std::fs::read(&path).context(format!("Failed to read {path:?}"))?;
+    serde_json::from_slice(&buf).context(format!("Failed to parse {path:?}"))
+}
+
+fn write_manifest(dir: &Path, manifest: &[ManifestEntry]) -> Result<()> {
+    let path = dir.join(MANIFEST_FILE);
+    std::fs::write(&path, serde_json::to_vec_pretty(manifest)?)
+        .context(format!("Failed to write {path:?}"))
+}
+
+/// The manifest entry of `file`, added if missing.
+fn manifest_entry<'a>(manifest: &'a mut Vec<ManifestEntry>, file: &str) -> &'a mut ManifestEntry {
+    let index = match manifest.iter().position(|m| m.file == file) {
+        Some(index) => index,
+        None => {
+            manifest.push(ManifestEntry {
+                file: file.to_string(),
+                ..Default::default()
+            });
+            manifest.len() - 1
+        }
+    };
+    &mut manifest[index]
+}
+
+/// keccak256(abi.encode(name, imageId)), signed by operators to vouch for
+/// the guest ELF with `image_id` under `name`. Signing the name keeps a
+/// signed ELF from being registered in place of another guest.
+pub fn guest_digest(name: &str, image_id: Digest) -> [u8; 32] {
+    keccak256(abi::encode(&[
+        Token::String(name.to_string()),
+        Token::FixedBytes(image_id.as_bytes().to_vec()),
+    ]))
+}
+
+/// Sign every guest ELF in `dir` with `wallet`, recording the signatures in
+/// the directory's [MANIFEST_FILE]. Returns the signed guests.
+pub async fn sign_dir(dir: &Path, wallet: &LocalWallet) -> Result<Vec<Arc<Guest>>> {
+    let mut registry = GuestRegistry::default();
+    registry.load_dir(dir)?;
+    let mut manifest = read_manifest(dir)?;
+    for guest in registry.iter() {
+        let file = guest
+            .path()
+            .and_then(|path| path.file_name())
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("guest {} has no file name", guest.name))?;
+        let signature = wallet
+            .sign_message(guest_digest(&guest.name, guest.image_id))
+            .await
+            .context(format!("Failed to sign guest {}", guest.name))?;
+        manifest_entry(&mut manifest, file).signature = Some(signature.to_string());
+    }
+    write_manifest(dir, &manifest)?;
+    Ok(registry.guests)
+}
+
+/// Write the ELFs of the guests embedded in `registry` to `dir` as
+/// `<name>.elf`, recording their build metadata and provenance in its
+/// [MANIFEST_FILE], so they can be served from a guest directory. Returns
+/// the exported guests.
+pub fn export_dir(registry: &GuestRegistry, dir: &Path) -> Result<Vec<Arc<Guest>>> {
+    std::fs::create_dir_all(dir).context(format!("Failed to create guest dir {dir:?}"))?;
+    let mut manifest = read_manifest(dir)?;
+    let mut exported = Vec::new();
+    for guest in registry.iter() {
+        if !matches!(guest.source, ElfSource::Embedded(_)) {
+            continue;
+        }
+        let file = format!("{}.elf", guest.name.to_lowercase());
+        let path = dir.join(&file);
+        std::fs::write(&path, &*guest.elf()?).context(format!("Failed to write {path:?}"))?;
+        let entry = manifest_entry(&mut manifest, &file);
+        entry.name = Some(guest.name.clone());
+        entry.zkvm_version = guest.zkvm_version.clone();
+        entry.circuit = guest.circuit.clone();
+        entry.provenance = guest.build_provenance.as_deref().cloned();
+        // The ELF may have changed, invalidating any signature.
+        entry.signature = None;
+        exported.push(guest.clone());
+    }
+    write_manifest(dir, &manifest)?;
+    Ok(exported)
+}
+
+/// Set of guests the relay can execute, looked up by name or image ID.
+#[derive(Default)]
+pub struct GuestRegistry {
+    guests: Vec<Arc<Guest>>,
+    /// Operator keys one of which must have signed each guest loaded from
+    /// disk, if any.
+    signers: Vec<Address>,
+}
+
+impl GuestRegistry {
+    /// Registry of the guests embedded at build time.
+    pub fn from_guest_list(guest_list: &[GuestListEntry<'static>]) -> Self {
+        Self::embedded(guest_list, None)
+    }
+
+    /// Registry of the guests embedded at build time, with the provenance
+    /// JSON the methods crate recorded of their build. Unreadable provenance
+    /// is logged and left out rather than failing the relay.
+    pub fn from_build(guest_list: &[GuestListEntry<'static>], provenance: &str) -> Self {
+        let provenance = match serde_json::from_str(provenance) {
+            Ok(provenance) => Some(Arc::new(provenance)),
+            Err(err) => {
+                elog!("Failed to parse the provenance of embedded guests: {err}");
+                None
+            }
+        };
+        Self::embedded(guest_list, provenance)
+    }
+
+    fn embedded(
+        guest_list: &[GuestListEntry<'static>],
+        provenance: Option<Arc<BuildProvenance>>,
+    ) -> Self {
+        Self {
+            guests: guest_list
+                .iter()
+                .map(|entry| {
+                    Arc::new(Guest {
+                        name: entry.name.to_string(),
+                        image_id: entry.image_id.into(),
+                        // Embedded guests are built by the same workspace as
+                        // the host.
+                        zkvm_version: Some(HOST_ZKVM_VERSION.to_string()),
+                        circuit: Some(HOST_CIRCUIT.to_string()),
+                        build_provenance: provenance.clone(),
+                        source: ElfSource::Embedded(entry.elf),
+                    })
+                })
+                .collect(),
+            signers: Vec::new(),
+        }
+    }
+
+    /// Only load guest ELFs from disk that one of `signers` signed, so a
+    /// compromised guest directory cannot slip in a modified guest. Guests
+    /// embedded at build time are trusted.
+    pub fn with_signers(mut self, signers: Vec<Address>) -> Self {
+        self.signers = signers;
+        self
+    }
+
+    /// Check `signature` of the guest ELF with `image_id` under `name`,
+    /// returning its signer.
+    fn check_signature(&self, name: &str, image_id: Digest, signature: &str) -> Result<Address> {
+        let signature: Signature = signature.parse().context("Invalid guest signature")?;
+        let signer = signature
+            .recover(guest_digest(name, image_id).as_slice())
+            .context("Failed to recover guest signer")?;
+        if !self.signers.contains(&signer) {
+            bail!("guest {name} is signed by {signer:?}, which is not a guest signer");
+        }
+        Ok(signer)
+    }
+
+    /// Register every file in `dir` as a guest named after its file stem.
+    /// ELFs are mapped once to compute their image ID and then released until
+    /// they are first requested. Build metadata is read from an optional
+    /// [MANIFEST_FILE] in the same directory. With signers, fails on the
+    /// first ELF without a valid signature in the manifest.
+    pub fn load_dir(&mut self, dir: &Path) -> Result<()> {
+        let manifest = read_manifest(dir)?;
+        let entries =
+            std::fs::read_dir(dir).context(format!("Failed to read guest dir {dir:?}"))?;
+        for entry in entries {
+            let path = entry?.path();
+            if !path.is_file() || path.file_name() == Some(MANIFEST_FILE.as_ref()) {
+                continue;
+            }
+            let file_name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .ok_or_else(|| anyhow!("invalid guest file name {path:?}"))?;
+            // Skip what file managers leave behind, such as .DS_Store on
+            // macOS and desktop.ini or Thumbs.db on Windows.
+            if file_name.starts_with('.') || !is_elf(&path)? {
+                elog!("Skipping {path:?} in guest dir, as it is not an ELF");
+                continue;
+            }
+            let meta = manifest.iter().find(|m| m.file == file_name);
+            let name = match meta.and_then(|m| m.name.clone()) {
+                Some(name) => name.to_uppercase(),
+                None => path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .ok_or_else(|| anyhow!("invalid guest file name {path:?}"))?
+                    .to_uppercase(),
+            };
+            let mmap = map_file(&path)?;
+            let image_id =
+                image_digest(&mmap).context(format!("Failed to compute image ID of {path:?}"))?;
+            let checksum = if self.signers.is_empty() {
+                None
+            } else {
+                let signature = meta
+                    .and_then(|m| m.signature.as_deref())
+                    .ok_or_else(|| anyhow!("guest ELF {path:?} is not signed"))?;
+                let signer = self
+                    .check_signature(&name, image_id, signature)
+                    .context(format!("Refusing guest ELF {path:?}"))?;
+                elog!("Guest {name} in {path:?} is signed by {signer:?}");
+                Some(sha256_hex(&mmap))
+            };
+            self.insert(Guest {
+                name,
+                image_id,
+                zkvm_version: meta.and_then(|m| m.zkvm_version.clone()),
+                circuit: meta.and_then(|m| m.circuit.clone()),
+                build_provenance: meta.and_then(|m| m.provenance.clone()).map(Arc::new),
+                source: ElfSource::File {
+                    path,
+                    mapping: Mutex::new(None),
+                    checksum,
+                },
+            });
+        }
+        Ok(())
+    }
+
+    /// Add a guest, replacing any previously registered guest of that name.
+    fn insert(&mut self, guest: Guest) {
+        self.guests.retain(|g| g.name != guest.name);
+        self.guests.push(Arc::new(guest));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Guest>> {
+        self.guests.iter()
+    }
+
+    /// Check every registered guest against the host zkVM version. Guests
+    /// the policy refuses are logged and dropped, so one stale guest does not
+    /// keep the relay from serving the others.
+    pub fn check_versions(&mut self, policy: VersionPolicy) {
+        self.guests
+            .retain(|guest| match check_guest(guest, policy) {
+                Ok(()) => true,
+                Err(err) => {
+                    elog!("Skipping guest: {err}");
+                    false
+                }
+            });
+    }
+
+    /// Find a guest by (case insensitive) name or hex encoded image ID.
+    pub fn resolve(&self, guest_binary: &str) -> Result<Arc<Guest>> {
+        let potential_image_id: Option<[u8; 32]> =
+            hex::decode(guest_binary.to_lowercase().trim_start_matches("0x"))
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok());
+        self.guests
+            .iter()
+            .find(|guest| {
+                guest.name == guest_binary.to_uppercase()
+                    || potential_image_id == Some(guest.image_id.into())
+            })
+            .cloned()
+            .ok_or_else(|| {
+                let found_guests: Vec<String> = self
+                    .guests
+                    .iter()
+                    .map(|g| hex::encode(g.image_id))
+                    .collect();
+                anyhow!(
+                    "Unknown guest binary {}, found: {:?}",
+                    guest_binary,
+                    found_guests
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_elf_checks_the_magic_number() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        assert!(is_elf(&write("guest.elf", b"\x7fELF\x01\x01\x01")).unwrap());
+        assert!(!is_elf(&write("desktop.ini", b"[.ShellClassInfo]")).unwrap());
+        assert!(!is_elf(&write("short", b"\x7fE")).unwrap());
+        assert!(!is_elf(&write("empty", b"")).unwrap());
+    }
+
+    fn guest(name: &str, zkvm_version: &str) -> Guest {
+        Guest {
+            name: name.to_string(),
+            image_id: Digest::default(),
+            zkvm_version: Some(zkvm_version.to_string()),
+            circuit: None,
+            build_provenance: None,
+            source: ElfSource::Embedded(b""),
+        }
+    }
+
+    #[test]
+    fn test_check_versions_drops_refused_guests() {
+        let mut registry = GuestRegistry::default();
+        registry.insert(guest("CURRENT", HOST_ZKVM_VERSION));
+        registry.insert(guest("STALE", "0.0.1"));
+
+        registry.check_versions(VersionPolicy::Warn);
+        assert_eq!(registry.iter().count(), 2);
+
+        registry.check_versions(VersionPolicy::Refuse);
+        let names: Vec<_> = registry.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["CURRENT"]);
+    }
+
+    #[test]
+    fn test_load_dir_skips_files_that_are_not_guests() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join(".DS_Store"), b"\0\0\0\x01Bud1").unwrap();
+        std::fs::write(dir.path().join("desktop.ini"), b"[.ShellClassInfo]").unwrap();
+        std::fs::write(dir.path().join("Thumbs.db"), b"\xd0\xcf").unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+
+        let mut registry = GuestRegistry::default();
+        registry.load_dir(dir.path()).unwrap();
+        assert_eq!(registry.iter().count(), 0);
+    }
+}
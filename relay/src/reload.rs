@@ -0,0 +1,165 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{
+    future::{self, BoxFuture},
+    FutureExt,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::{
+    access::RequesterPolicy, catalog::PoolCatalog, elog, gas::GasStrategy, tenant::Tenants,
+};
+
+type ReloadFn = Box<dyn Fn() -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// Configuration files re-read while the relay runs, on SIGHUP or through
+/// the admin API. Sessions in flight keep the configuration they started
+/// with; the rest of the relay's settings are flags and need a restart.
+#[derive(Default)]
+pub struct Reloader {
+    sources: Vec<(String, ReloadFn)>,
+}
+
+impl Reloader {
+    /// Reload the requester allow and deny lists and quotas from `path`.
+    pub fn with_requester_policy(self, path: PathBuf, policy: Arc<RequesterPolicy>) -> Self {
+        let name = format!("requester policy {}", path.display());
+        self.with_source(name, move || {
+            policy.reload(RequesterPolicy::load_config(&path)?);
+            Ok(())
+        })
+    }
+
+    /// Reload the tenants, their API keys, rate limits and guest allowlists
+    /// from `path`.
+    pub fn with_tenants(self, path: PathBuf, tenants: Arc<RwLock<Tenants>>) -> Self {
+        let name = format!("tenants file {}", path.display());
+        self.with_source(name, move || {
+            let configs = Tenants::load_configs(&path)?;
+            let mut tenants = tenants
+                .write()
+                .map_err(|_| anyhow!("tenants lock poisoned"))?;
+            *tenants = tenants.reload(configs)?;
+            Ok(())
+        })
+    }
+
+    /// Reload the gas limit and blob posting comparison of callback
+    /// submissions from `path`.
+    pub fn with_gas_strategy(self, path: PathBuf, strategy: Arc<RwLock<GasStrategy>>) -> Self {
+        let name = format!("gas strategy {}", path.display());
+        self.with_source(name, move || {
+            let loaded = GasStrategy::load(&path)?;
+            *strategy
+                .write()
+                .map_err(|_| anyhow!("gas strategy lock poisoned"))? = loaded;
+            Ok(())
+        })
+    }
+
+    /// Reload the pools of the catalog, and so the schedules of their jobs,
+    /// from its file.
+    pub fn with_catalog(mut self, catalog: Arc<PoolCatalog>) -> Self {
+        let name = format!("pool catalog {}", catalog.path().display());
+        self.sources.push((
+            name,
+            Box::new(move || {
+                let catalog = catalog.clone();
+                async move { catalog.reload().await }.boxed()
+            }),
+        ));
+        self
+    }
+
+    fn with_source(
+        mut self,
+        name: String,
+        reload: impl Fn() -> Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.sources
+            .push((name, Box::new(move || future::ready(reload()).boxed())));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Re-read every file, returning the names of those reloaded. A file
+    /// that fails to load or validate leaves its current configuration in
+    /// place.
+    pub async fn reload(&self) -> Result<Vec<String>> {
+        let mut reloaded = Vec::new();
+        let mut failures = Vec::new();
+        for (name, reload) in &self.sources {
+            match reload().await.context(format!("Failed to reload {name}")) {
+                Ok(()) => reloaded.push(name.clone()),
+                Err(err) => failures.push(format!("{err:#}")),
+            }
+        }
+        if !failures.is_empty() {
+            bail!("{}", failures.join("; "));
+        }
+        Ok(reloaded)
+    }
+
+    /// Reload on every SIGHUP until the process is stopped. Returns at once
+    /// if there is nothing to reload.
+    pub async fn run(self: Arc<Self>) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let mut hangups =
+            signal(SignalKind::hangup()).context("Failed to install SIGHUP handler")?;
+        while hangups.recv().await.is_some() {
+            match self.reload().await {
+                Ok(reloaded) => elog!("Reloaded {}", reloaded.join(", ")),
+                Err(err) => elog!("Configuration reload failed: {err}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload_keeps_the_strategy_of_a_bad_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gas.json");
+        std::fs::write(&path, r#"{"max_gas": 500000}"#).unwrap();
+        let strategy = Arc::new(RwLock::new(GasStrategy::load(&path).unwrap()));
+        let reloader = Reloader::default().with_gas_strategy(path.clone(), strategy.clone());
+
+        std::fs::write(&path, r#"{"max_gas": 300000, "blob_pricing": true}"#).unwrap();
+        assert_eq!(reloader.reload().await.unwrap().len(), 1);
+        let reloaded = strategy.read().unwrap().clone();
+        assert_eq!(reloaded.max_gas, Some(300_000));
+        assert!(reloaded.blob_pricing);
+
+        std::fs::write(&path, "{").unwrap();
+        assert!(reloader.reload().await.is_err());
+        assert_eq!(strategy.read().unwrap().max_gas, Some(300_000));
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Inputs of the RESERVE guest, which proves the reserves a vault holds in
+//! Uniswap v3 positions.
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::Middleware,
+    types::{Address, BlockId, Bytes, H256, I256, U256},
+    utils::{get_create2_address_from_hash, keccak256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bindings::UniswapV3Pool,
+    finality::FinalityPolicy,
+    input::canonicalize,
+    proofs::{encode_header, verify_account, ProofCache, ProofSource},
+};
+
+/// `POOL_INIT_CODE_HASH` of the canonical Uniswap v3 factory.
+pub const UNISWAP_V3_INIT_CODE_HASH: &str =
+    "0xe34f199b19b2b4f47f68442619d555527d244f78a3297ea89325f843f87b8b54";
+
+/// Storage slots of `slot0` and the `positions` mapping in a Uniswap v3 pool.
+/// Must match the RESERVE guest.
+const SLOT0_SLOT: u64 = 0;
+const POSITIONS_SLOT: u64 = 7;
+
+/// Offset of `tokensOwed0` and `tokensOwed1` in a `Position.Info`.
+const TOKENS_OWED_OFFSET: u64 = 3;
+
+/// A position the vault holds directly in a pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VaultPosition {
+    pub pool: Address,
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionProof {
+    pub tick_lower: i32,
+    pub tick_upper: i32,
+    pub liquidity_proof: Vec<Bytes>,
+    pub tokens_owed_proof: Vec<Bytes>,
+}
+
+/// A pool's price and the vault's positions in it, proven against the
+/// pool's storage root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolProof {
+    pub token0: Address,
+    pub token1: Address,
+    pub fee: u32,
+    pub account_proof: Vec<Bytes>,
+    pub slot0_proof: Vec<Bytes>,
+    pub positions: Vec<PositionProof>,
+}
+
+/// Input of the RESERVE guest: the vault's positions proven at one block.
+/// The guest derives each pool's address from `factory`, so the tokens the
+/// reserves are reported in are proven too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReserveInput {
+    pub vault: Address,
+    pub factory: Address,
+    pub init_code_hash: H256,
+    /// RLP encoded header of the block.
+    pub header: Bytes,
+    pub pools: Vec<PoolProof>,
+}
+
+impl ReserveInput {
+    /// ABI encode the input in the layout expected by the guest.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let proof = |nodes: &[Bytes]| {
+            Token::Array(
+                nodes
+                    .iter()
+                    .map(|node| Token::Bytes(node.to_vec()))
+                    .collect(),
+            )
+        };
+        let tick = |tick: i32| Token::Int(I256::from(tick).into_raw());
+        let pools = self
+            .pools
+            .iter()
+            .map(|pool| {
+                let positions = pool
+                    .positions
+                    .iter()
+                    .map(|position| {
+                        Token::Tuple(vec![
+                            tick(position.tick_lower),
+                            tick(position.tick_upper),
+                            proof(&position.liquidity_proof),
+                            proof(&position.tokens_owed_proof),
+                        ])
+                    })
+                    .collect();
+                Token::Tuple(vec![
+                    Token::Address(pool.token0),
+                    Token::Address(pool.token1),
+                    Token::Uint(pool.fee.into()),
+                    proof(&pool.account_proof),
+                    proof(&pool.slot0_proof),
+                    Token::Array(positions),
+                ])
+            })
+            .collect();
+        canonicalize(
+            "RESERVE",
+            &abi::encode(&[
+                Token::Address(self.vault),
+                Token::Address(self.factory),
+                Token::FixedBytes(self.init_code_hash.as_bytes().to_vec()),
+                Token::Bytes(self.header.to_vec()),
+                Token::Array(pools),
+            ]),
+        )
+    }
+}
+
+/// Address of the pool `factory` deploys for the token pair and fee.
+pub fn pool_address(
+    factory: Address,
+    init_code_hash: H256,
+    token0: Address,
+    token1: Address,
+    fee: u32,
+) -> Address {
+    let salt = keccak256(abi::encode(&[
+        Token::Address(token0),
+        Token::Address(token1),
+        Token::Uint(fee.into()),
+    ]));
+    get_create2_address_from_hash(factory, salt, init_code_hash)
+}
+
+/// Storage slot of the `Position.Info` of `owner` over [tick_lower,
+/// tick_upper).
+fn position_slot(owner: Address, tick_lower: i32, tick_upper: i32) -> U256 {
+    let key = keccak256(
+        [
+            owner.as_bytes(),
+            &tick_lower.to_be_bytes()[1..],
+            &tick_upper.to_be_bytes()[1..],
+        ]
+        .concat(),
+    );
+    let slot = H256::from_low_u64_be(POSITIONS_SLOT);
+    U256::from_big_endian(&keccak256([key.as_slice(), slot.as_bytes()].concat()))
+}
+
+fn slot_key(slot: U256) -> H256 {
+    let mut key = H256::zero();
+    slot.to_big_endian(key.as_bytes_mut());
+    key
+}
+
+/// Builds RESERVE guest inputs from state proofs of a vault's positions.
+/// Positions the input leaves out are not counted, so the proven reserves
+/// are a lower bound; consumers must know how many positions to expect.
+/// Fees accrued since a position was last touched are not counted either.
+pub struct ReserveFetcher<M> {
+    client: Arc<M>,
+    source: Arc<dyn ProofSource>,
+    cache: Option<Arc<ProofCache>>,
+    finality: FinalityPolicy,
+    factory: Address,
+    init_code_hash: H256,
+}
+
+impl<M: Middleware + 'static> ReserveFetcher<M> {
+    /// Read pools through `client` and fetch proofs from `source`, for pools
+    /// deployed by the canonical Uniswap v3 `factory`.
+    pub fn new(client: Arc<M>, source: Arc<dyn ProofSource>, factory: Address) -> Result<Self> {
+        Ok(Self {
+            client,
+            source,
+            cache: None,
+            finality: FinalityPolicy::default(),
+            factory,
+            init_code_hash: UNISWAP_V3_INIT_CODE_HASH.parse()?,
+        })
+    }
+
+    /// Derive pool addresses with the pool init code hash of a fork of the
+    /// factory.
+    pub fn with_init_code_hash(mut self, init_code_hash: H256) -> Self {
+        self.init_code_hash = init_code_hash;
+        self
+    }
+
+    /// Serve proofs from `cache` where possible.
+    pub fn with_cache(mut self, cache: Arc<ProofCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Only prove reserves at blocks meeting `finality`.
+    pub fn with_finality(mut self, finality: FinalityPolicy) -> Self {
+        self.finality = finality;
+        self
+    }
+
+    /// Prove the `positions` of `vault` at the latest block meeting the
+    /// finality policy.
+    pub async fn fetch(&self, vault: Address, positions: &[VaultPosition]) -> Result<ReserveInput> {
+        let block = self.finality.block(self.client.as_ref()).await?;
+        let hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        let mut by_pool = BTreeMap::<Address, Vec<&VaultPosition>>::new();
+        for position in positions {
+            if position.tick_lower >= position.tick_upper {
+                bail!(
+                    "position [{}, {}) in pool {:?} is empty",
+                    position.tick_lower,
+                    position.tick_upper,
+                    position.pool
+                );
+            }
+            by_pool.entry(position.pool).or_default().push(position);
+        }
+
+        let mut pools = Vec::new();
+        for (address, positions) in by_pool {
+            let pool = UniswapV3Pool::new(address, self.client.clone());
+            let at = BlockId::Hash(hash);
+            let context = |what: &str| format!("Failed to read {what} of pool {address:?}");
+            let token0 = pool
+                .token_0()
+                .block(at)
+                .call()
+                .await
+                .context(context("token0"))?;
+            let token1 = pool
+                .token_1()
+                .block(at)
+                .call()
+                .await
+                .context(context("token1"))?;
+            let fee = pool.fee().block(at).call().await.context(context("fee"))?;
+            if pool_address(self.factory, self.init_code_hash, token0, token1, fee) != address {
+                bail!(
+                    "pool {address:?} was not deployed by factory {:?}",
+                    self.factory
+                );
+            }
+
+            let mut slots = vec![H256::from_low_u64_be(SLOT0_SLOT)];
+            for position in &positions {
+                let slot = position_slot(vault, position.tick_lower, position.tick_upper);
+                slots.push(slot_key(slot));
+                slots.push(slot_key(slot + TOKENS_OWED_OFFSET));
+            }
+            let response = match &self.cache {
+                Some(cache) => {
+                    cache
+                        .get_proof(self.source.as_ref(), &block, address, &slots)
+                        .await?
+                }
+                None => {
+                    let response = self.source.get_proof(hash, address, slots).await?;
+                    verify_account(block.state_root, &response)?;
+                    response
+                }
+            };
+            let mut storage = response.storage_proof.into_iter().map(|slot| slot.proof);
+            let mut next_proof = || {
+                storage
+                    .next()
+                    .ok_or_else(|| anyhow!("proof of pool {address:?} is missing slots"))
+            };
+            let slot0_proof = next_proof()?;
+            let mut proven = Vec::new();
+            for position in positions {
+                proven.push(PositionProof {
+                    tick_lower: position.tick_lower,
+                    tick_upper: position.tick_upper,
+                    liquidity_proof: next_proof()?,
+                    tokens_owed_proof: next_proof()?,
+                });
+            }
+            pools.push(PoolProof {
+                token0,
+                token1,
+                fee,
+                account_proof: response.account_proof,
+                slot0_proof,
+                positions: proven,
+            });
+        }
+        Ok(ReserveInput {
+            vault,
+            factory: self.factory,
+            init_code_hash: self.init_code_hash,
+            header: encode_header(&block)?,
+            pools,
+        })
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use bonsai_sdk::alpha::SdkErr;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock, elog,
+    error::{ErrorKind, Fault, RelayError},
+};
+
+/// New sessions created by default for a proof whose session failed for
+/// transient reasons, see [set_session_retries].
+pub const DEFAULT_SESSION_RETRIES: u32 = 2;
+
+static SESSION_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_SESSION_RETRIES);
+
+/// Set how many new sessions are created for a proof whose Bonsai session
+/// ended with a bad status for transient reasons.
+pub fn set_session_retries(retries: u32) {
+    SESSION_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+pub fn session_retries() -> u32 {
+    SESSION_RETRIES.load(Ordering::Relaxed)
+}
+
+/// A Bonsai session that ended with a bad status.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionAttempt {
+    pub session_id: String,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl SessionAttempt {
+    pub fn fault(&self) -> Fault {
+        Fault::of_session(&self.status, self.error.as_deref())
+    }
+
+    /// Whether a new session may succeed: the session failed by a fault of
+    /// the prover, and was not aborted on purpose.
+    pub fn is_transient(&self) -> bool {
+        self.status != "ABORTED" && self.fault() == Fault::Prover
+    }
+
+    /// The session's failure as an error.
+    pub fn into_error(self, what: &str) -> RelayError {
+        RelayError::session_failed(what, &self.status, self.error)
+    }
+}
+
+impl fmt::Display for SessionAttempt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Bonsai session {} ended {}",
+            self.session_id, self.status
+        )?;
+        match &self.error {
+            Some(error) => write!(f, ": {error}"),
+            None => Ok(()),
+        }
+    }
+}
+
+tokio::task_local! {
+    static ATTEMPTS: AttemptLog;
+}
+
+/// Sessions replaced by new ones while proving a request, for its job
+/// history. Provers pick up the log of the current task with [attempt_log].
+#[derive(Debug, Clone, Default)]
+pub struct AttemptLog(Arc<Mutex<Vec<SessionAttempt>>>);
+
+impl AttemptLog {
+    pub fn record(&self, attempt: SessionAttempt) {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(attempt);
+    }
+
+    pub fn attempts(&self) -> Vec<SessionAttempt> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Run `future` recording the sessions it replaces in this log.
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        ATTEMPTS.scope(self.clone(), future).await
+    }
+}
+
+/// Log of the request being proven, if any.
+pub fn attempt_log() -> Option<AttemptLog> {
+    ATTEMPTS.try_with(Clone::clone).ok()
+}
+
+/// Exponential backoff used between retries of transient failures.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    max_attempts: u32,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, max_attempts: u32) -> Self {
+        Self {
+            initial,
+            max,
+            max_attempts,
+            attempts: 0,
+        }
+    }
+
+    /// Number of consecutive failures recorded since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Forget previous failures, e.g. after a successful request.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Record a failure and return how long to wait before the next attempt,
+    /// or None once the attempt budget is spent.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempts >= self.max_attempts {
+            return None;
+        }
+        let delay = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(self.max);
+        self.attempts += 1;
+        Some(delay)
+    }
+}
+
+/// Call `f` until it succeeds, sleeping with backoff between transient
+/// failures and returning immediately on permanent ones.
+pub fn retry_transient<T>(
+    context: &str,
+    backoff: &mut Backoff,
+    mut f: impl FnMut() -> Result<T, SdkErr>,
+) -> Result<T, RelayError> {
+    loop {
+        #[cfg(feature = "fault-injection")]
+        let result = match crate::faults::bonsai_error(context) {
+            Some(err) => Err(err),
+            None => f(),
+        };
+        #[cfg(not(feature = "fault-injection"))]
+        let result = f();
+        match result {
+            Ok(value) => {
+                backoff.reset();
+                return Ok(value);
+            }
+            Err(source) => {
+                let kind = ErrorKind::from(&source);
+                if !kind.is_transient() {
+                    return Err(RelayError::bonsai(context, source));
+                }
+                let Some(delay) = backoff.next_delay() else {
+                    return Err(RelayError::RetriesExhausted {
+                        context: context.to_string(),
+                        attempts: backoff.attempts(),
+                        source,
+                    });
+                };
+                elog!("{context} failed ({kind:?}): {source}, retrying in {delay:?}");
+                clock::sleep(delay);
+            }
+        }
+    }
+}
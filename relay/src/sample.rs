@@ -0,0 +1,162 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    providers::Middleware,
+    types::{Bytes, H256, U256},
+    utils::keccak256,
+};
+use serde::Serialize;
+
+use crate::{finality::FinalityPolicy, proofs::encode_header};
+
+/// Randomness drawn from the hash of a seed block, as derived by sampling
+/// guests. Draw `i` is `keccak256(seed || u64 BE i)`. Must match `Sampler`
+/// in the guest library's `sample` module.
+pub struct Sampler {
+    seed: H256,
+    draws: u64,
+}
+
+impl Sampler {
+    pub fn new(seed: H256) -> Self {
+        Self { seed, draws: 0 }
+    }
+
+    /// Next draw, reduced below `bound`.
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        let mut preimage = [0u8; 40];
+        preimage[..32].copy_from_slice(self.seed.as_bytes());
+        preimage[32..].copy_from_slice(&self.draws.to_be_bytes());
+        self.draws += 1;
+        (U256::from_big_endian(&keccak256(preimage)) % bound).as_u64()
+    }
+}
+
+/// The `count` distinct blocks from `from` up to, but excluding, block
+/// `seed_block` with hash `seed` that a sampling guest will read, in
+/// ascending order. Must match `sample_blocks` in the guest library. The
+/// guest commits `seed`, which consumers must check is the hash of the
+/// canonical block `seed_block`, or the sample is the host's choice.
+pub fn sample_blocks(seed: H256, seed_block: u64, from: u64, count: usize) -> Result<Vec<u64>> {
+    if from >= seed_block {
+        bail!("seed block {seed_block} does not follow the sampled range starting at {from}");
+    }
+    let span = seed_block - from;
+    if count as u64 > span {
+        bail!("cannot sample {count} distinct blocks from a range of {span}");
+    }
+    let mut sampler = Sampler::new(seed);
+    let mut blocks = Vec::with_capacity(count);
+    while blocks.len() < count {
+        let block = from + sampler.next_below(span);
+        if !blocks.contains(&block) {
+            blocks.push(block);
+        }
+    }
+    blocks.sort_unstable();
+    Ok(blocks)
+}
+
+/// Blocks a sampling guest will read, precomputed so their state can be
+/// fetched before proving.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SamplePlan {
+    pub seed_block: u64,
+    pub seed_hash: H256,
+    /// RLP encoded header of the seed block, from which the guest derives
+    /// the sample.
+    pub seed_header: Bytes,
+    pub blocks: Vec<u64>,
+}
+
+/// Plan the sample of `count` blocks from `from` seeded by block
+/// `seed_block`. The seed block must meet `finality`, since a reorg
+/// replacing it would change the sample.
+pub async fn plan_sample<M: Middleware + 'static>(
+    client: &M,
+    finality: FinalityPolicy,
+    from: u64,
+    seed_block: u64,
+    count: usize,
+) -> Result<SamplePlan> {
+    let settled = finality
+        .block(client)
+        .await?
+        .number
+        .ok_or_else(|| anyhow!("{finality} block has no number"))?
+        .as_u64();
+    if seed_block > settled {
+        bail!(
+            "seed block {seed_block} does not meet the {finality} policy yet, \
+             which reaches block {settled}"
+        );
+    }
+    let block = client
+        .get_block(seed_block)
+        .await
+        .context(format!("Failed to get block {seed_block}"))?
+        .ok_or_else(|| anyhow!("block {seed_block} not found"))?;
+    let seed_hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+    Ok(SamplePlan {
+        seed_block,
+        seed_hash,
+        seed_header: encode_header(&block)?,
+        blocks: sample_blocks(seed_hash, seed_block, from, count)?,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    // Pinned on both sides: the guest library's `sample` module tests the
+    // same seeds against the same blocks.
+    const SEED: H256 = H256::repeat_byte(0x11);
+
+    #[test]
+    fn test_draws_are_pinned() {
+        let mut sampler = Sampler::new(SEED);
+        let draws: Vec<u64> = (0..3).map(|_| sampler.next_below(u64::MAX)).collect();
+        assert_eq!(
+            draws,
+            [
+                10_502_111_810_833_164_795,
+                11_830_571_387_132_732_415,
+                206_731_749_061_364_343
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sample_blocks_is_pinned() {
+        assert_eq!(
+            sample_blocks(SEED, 1_000, 900, 5).unwrap(),
+            [915, 930, 933, 962, 973]
+        );
+        // Every block of the range, despite repeated draws.
+        assert_eq!(
+            sample_blocks(SEED, 1_000, 996, 4).unwrap(),
+            [996, 997, 998, 999]
+        );
+    }
+
+    #[test]
+    fn test_sample_blocks_rejects_bad_ranges() {
+        assert!(sample_blocks(SEED, 1_000, 1_000, 1).is_err());
+        assert!(sample_blocks(SEED, 1_000, 990, 11).is_err());
+    }
+}
@@ -0,0 +1,797 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::types::H256;
+use futures::future::BoxFuture;
+use risc0_zkvm::{sha::Digest, Receipt};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tempfile::NamedTempFile;
+use tokio::{
+    sync::{broadcast, mpsc},
+    task::JoinHandle,
+    time::{interval_at, Instant, MissedTickBehavior},
+};
+use tokio_stream::{wrappers::BroadcastStream, Stream, StreamExt};
+
+use crate::{
+    alert::Alerts,
+    backend::BonsaiBackend,
+    checksum::sha256_hex,
+    clock, elog,
+    finality::FinalityPolicy,
+    index::{IndexEntry, ReceiptIndex},
+    lease::{with_session_hook, LeaseGuard, Leases, SessionHook},
+    metrics::Metrics,
+    pool::ImagePool,
+    queue::Queues,
+    receipt,
+    redact::redact_json,
+    registry::Guest,
+    run_guest, trace, Output,
+};
+
+/// Number of results buffered for slow subscribers before they start
+/// skipping runs.
+const RESULT_BUFFER: usize = 16;
+
+/// Guest input built for a run.
+pub struct JobInput {
+    pub input: Vec<u8>,
+    /// Block whose state the input was built from, if any.
+    pub block: Option<u64>,
+    /// Policy the block was chosen by.
+    pub finality: Option<FinalityPolicy>,
+}
+
+/// Builds the guest input for a run, e.g. by fetching fresh pool state.
+pub type InputFn = Arc<dyn Fn() -> BoxFuture<'static, Result<JobInput>> + Send + Sync>;
+
+/// Decides whether a run is worth proving, e.g. by asking an oracle contract
+/// whether its price is due for an update. See [crate::keeper::Keeper].
+pub type ConditionFn = Arc<dyn Fn() -> BoxFuture<'static, Result<bool>> + Send + Sync>;
+
+/// Checks a run's journal before its result is recorded and published, e.g.
+/// that it extends the state committed by the previous run. Runs it rejects
+/// fail. See [crate::continuity::ContinuityGuard].
+pub type VerifyFn = Arc<dyn Fn(Vec<u8>) -> BoxFuture<'static, Result<()>> + Send + Sync>;
+
+/// A guest run repeated on a fixed interval.
+#[derive(Clone)]
+pub struct Job {
+    pub name: String,
+    pub guest: Arc<Guest>,
+    pub interval: Duration,
+    pub dev_mode: bool,
+    pub input: InputFn,
+    /// Number of runs whose inputs may be fetched ahead while an earlier run
+    /// is still proving. Zero fetches and proves strictly in turn.
+    pub prefetch_depth: usize,
+    /// Compress downloaded receipts into succinct receipts before the result
+    /// is verified, indexed, published or recorded, trading local proving
+    /// time for much smaller receipts. Runs whose receipt fails to compress
+    /// fail rather than publish the composite receipt.
+    pub succinct: bool,
+    /// Checked when a run falls due. Runs are skipped while it does not
+    /// fire, keeping their numbers. Runs go ahead if it cannot be checked.
+    pub condition: Option<ConditionFn>,
+    /// Checked on every successful run before its result is published.
+    pub verify: Option<VerifyFn>,
+}
+
+/// Outcome of a single run of a scheduled job.
+#[derive(Clone)]
+pub struct ProofResult {
+    pub job: String,
+    /// Sequence number of the run, starting at zero. Runs missed while the
+    /// relay was down keep their numbers, leaving a gap.
+    pub run: u64,
+    pub started_at: SystemTime,
+    /// Policy the input's block was chosen by, if it was built from one.
+    pub finality: Option<FinalityPolicy>,
+    pub output: Result<Arc<Output>, String>,
+}
+
+/// Last successful run of a job, persisted so the scheduler resumes from it
+/// after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LastRun {
+    pub run: u64,
+    pub block: Option<u64>,
+    /// Finality policy `block` met when the input was built.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finality: Option<FinalityPolicy>,
+    /// When the run started, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Hex encoded SHA-256 digest of the run's journal.
+    pub journal_hash: String,
+}
+
+/// Latest round a job transmitted to a price feed, see
+/// [crate::aggregator::AggregatorFeed].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FeedRound {
+    pub round_id: u64,
+    /// Run whose result the round carries.
+    pub run: u64,
+    pub tx_hash: H256,
+}
+
+/// Latest state committed by a job of an incremental guest, which its next
+/// run must extend, see [crate::continuity::ContinuityGuard].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Commitment {
+    pub run: u64,
+    pub root: H256,
+    pub block_hash: H256,
+    pub block_number: u64,
+    /// Hex encoded journal of the run, from which the next input is built.
+    pub journal: String,
+}
+
+/// Directory keeping the [LastRun] of each job as `<job>.json`, the
+/// [FeedRound] of jobs feeding a price feed as `<job>.round.json`, and the
+/// [Commitment] of incremental jobs as `<job>.commitment.json`. Every run
+/// recorded is also appended to the job's audit log, `<job>.runs.jsonl`,
+/// which is never rewritten.
+pub struct RunLog {
+    dir: PathBuf,
+    append: Mutex<()>,
+}
+
+impl RunLog {
+    pub fn open(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create run log directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            append: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, job: &str, suffix: &str) -> Result<PathBuf> {
+        if job.is_empty() || job.starts_with('.') || job.contains(['/', '\\']) {
+            bail!("invalid job name {job:?}");
+        }
+        Ok(self.dir.join(format!("{job}{suffix}")))
+    }
+
+    fn read<T: DeserializeOwned>(&self, path: &Path) -> Result<Option<T>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("Failed to open {}", path.display())),
+        };
+        serde_json::from_reader(file)
+            .context(format!("Failed to parse {}", path.display()))
+            .map(Some)
+    }
+
+    /// Atomically replace the file at `path`, with secrets masked.
+    fn write(&self, path: &Path, value: &impl Serialize) -> Result<()> {
+        let data = redact_json(value).context(format!("Failed to serialize {}", path.display()))?;
+        let mut file = NamedTempFile::new_in(&self.dir).context("Failed to create temp file")?;
+        file.write_all(&data)
+            .context(format!("Failed to write {}", path.display()))?;
+        file.flush()
+            .context(format!("Failed to write {}", path.display()))?;
+        file.persist(path)
+            .context(format!("Failed to persist {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Append a line to the file at `path`, with secrets masked.
+    fn append(&self, path: &Path, value: &impl Serialize) -> Result<()> {
+        let mut line =
+            redact_json(value).context(format!("Failed to serialize {}", path.display()))?;
+        line.push(b'\n');
+        let _append = self.append.lock().unwrap_or_else(PoisonError::into_inner);
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&line))
+            .context(format!("Failed to append to {}", path.display()))
+    }
+
+    pub fn last_run(&self, job: &str) -> Result<Option<LastRun>> {
+        self.read(&self.path(job, ".json")?)
+    }
+
+    /// Append the run to the job's audit log, then atomically replace the
+    /// job's last run with it.
+    pub fn record(&self, job: &str, last_run: &LastRun) -> Result<()> {
+        self.append(&self.path(job, ".runs.jsonl")?, last_run)?;
+        self.write(&self.path(job, ".json")?, last_run)
+    }
+
+    pub fn last_round(&self, job: &str) -> Result<Option<FeedRound>> {
+        self.read(&self.path(job, ".round.json")?)
+    }
+
+    /// Atomically replace the job's latest feed round.
+    pub fn record_round(&self, job: &str, round: &FeedRound) -> Result<()> {
+        self.write(&self.path(job, ".round.json")?, round)
+    }
+
+    pub fn last_commitment(&self, job: &str) -> Result<Option<Commitment>> {
+        self.read(&self.path(job, ".commitment.json")?)
+    }
+
+    /// Atomically replace the job's latest commitment.
+    pub fn record_commitment(&self, job: &str, commitment: &Commitment) -> Result<()> {
+        self.write(&self.path(job, ".commitment.json")?, commitment)
+    }
+}
+
+/// Where a job picks up after its last recorded run.
+struct Resume {
+    first_run: u64,
+    start: Instant,
+    missed: u64,
+}
+
+impl Resume {
+    fn new(job: &Job, last_run: Option<&LastRun>) -> Self {
+        let Some(last_run) = last_run else {
+            return Self {
+                first_run: 0,
+                start: Instant::now(),
+                missed: 0,
+            };
+        };
+        let due = UNIX_EPOCH + Duration::from_secs(last_run.timestamp) + job.interval;
+        match clock::now().duration_since(due) {
+            // The next run is not due yet, so wait for it instead of running
+            // again right away.
+            Err(early) => Self {
+                first_run: last_run.run + 1,
+                start: Instant::now() + early.duration(),
+                missed: 0,
+            },
+            Ok(late) => {
+                let missed = (late.as_nanos() / job.interval.as_nanos().max(1)) as u64;
+                Self {
+                    first_run: last_run.run + 1 + missed,
+                    start: Instant::now(),
+                    missed,
+                }
+            }
+        }
+    }
+}
+
+/// Handle on a running job.
+pub struct JobHandle {
+    results: broadcast::Sender<ProofResult>,
+    task: JoinHandle<()>,
+    missed_runs: u64,
+}
+
+impl JobHandle {
+    /// Number of runs that fell due while the relay was down, according to
+    /// the run log.
+    pub fn missed_runs(&self) -> u64 {
+        self.missed_runs
+    }
+
+    /// Stream of results produced from now on. Subscribers that fall more
+    /// than a few runs behind skip the results they missed.
+    pub fn results(&self) -> impl Stream<Item = ProofResult> {
+        BroadcastStream::new(self.results.subscribe()).filter_map(|res| res.ok())
+    }
+
+    pub fn abort(&self) {
+        self.task.abort();
+    }
+
+    /// Stop the job and wait until its run in progress, if any, is dropped,
+    /// releasing its lease.
+    pub async fn stop(&mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// What every job of a scheduler proves with and records its runs in.
+#[derive(Clone)]
+struct Services {
+    pool: Arc<ImagePool>,
+    bonsai: BonsaiBackend,
+    run_log: Option<Arc<RunLog>>,
+    leases: Option<Arc<Leases>>,
+    metrics: Option<Arc<Metrics>>,
+    index: Option<Arc<ReceiptIndex>>,
+    queues: Option<Arc<Queues>>,
+    alerts: Option<Arc<Alerts>>,
+}
+
+/// Runs jobs on their intervals and fans their results out to subscribers.
+pub struct Scheduler {
+    services: Services,
+    jobs: HashMap<String, JobHandle>,
+}
+
+impl Scheduler {
+    pub fn new(pool: Arc<ImagePool>) -> Self {
+        Self {
+            services: Services {
+                pool,
+                bonsai: BonsaiBackend::default(),
+                run_log: None,
+                leases: None,
+                metrics: None,
+                index: None,
+                queues: None,
+                alerts: None,
+            },
+            jobs: HashMap::new(),
+        }
+    }
+
+    /// Prove on Bonsai through `bonsai` rather than the alpha API.
+    pub fn with_bonsai(mut self, bonsai: BonsaiBackend) -> Self {
+        self.services.bonsai = bonsai;
+        self
+    }
+
+    /// Record each job's last successful run in `run_log`, and resume jobs
+    /// from there when they are added.
+    pub fn with_run_log(mut self, run_log: RunLog) -> Self {
+        self.services.run_log = Some(Arc::new(run_log));
+        self
+    }
+
+    /// Claim each run from `leases` before proving it, so that replicas
+    /// sharing the lease directory, and the run log, prove each run once.
+    /// Runs another replica holds are skipped.
+    pub fn with_leases(mut self, leases: Leases) -> Self {
+        self.services.leases = Some(Arc::new(leases));
+        self
+    }
+
+    /// Record each run's latency, from its start to its proof, against the
+    /// latency objective of its guest.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.services.metrics = Some(metrics);
+        self
+    }
+
+    /// Index each successful run's result by its canonical input, so the
+    /// event listener answers requests for the same input with it.
+    pub fn with_receipt_index(mut self, index: Arc<ReceiptIndex>) -> Self {
+        self.services.index = Some(index);
+        self
+    }
+
+    /// Publish each successful run's result to the queues listing its job.
+    pub fn with_queues(mut self, queues: Arc<Queues>) -> Self {
+        self.services.queues = Some(queues);
+        self
+    }
+
+    /// Watch every job's results against its alert rules, alerting when
+    /// its values deviate or its runs keep failing.
+    pub fn with_alerts(mut self, alerts: Arc<Alerts>) -> Self {
+        self.services.alerts = Some(alerts);
+        self
+    }
+
+    /// Log the scheduler records its runs in, if any.
+    pub fn run_log(&self) -> Option<Arc<RunLog>> {
+        self.services.run_log.clone()
+    }
+
+    /// Start a job, replacing (and stopping) any job with the same name.
+    pub fn add(&mut self, job: Job) -> &JobHandle {
+        let last_run = match self
+            .services
+            .run_log
+            .as_ref()
+            .map(|log| log.last_run(&job.name))
+        {
+            Some(Ok(last_run)) => last_run,
+            Some(Err(err)) => {
+                elog!("Scheduled job {} starts afresh: {err:?}", job.name);
+                None
+            }
+            None => None,
+        };
+        let resume = Resume::new(&job, last_run.as_ref());
+        if let Some(last_run) = last_run.as_ref().filter(|_| resume.missed > 0) {
+            elog!(
+                "Scheduled job {} missed {} runs after run {} at {}",
+                job.name,
+                resume.missed,
+                last_run.run,
+                last_run.timestamp
+            );
+        }
+        let missed_runs = resume.missed;
+        let (results, _) = broadcast::channel(RESULT_BUFFER);
+        let task = tokio::spawn(run_job(
+            job.clone(),
+            self.services.clone(),
+            results.clone(),
+            resume,
+        ));
+        let handle = JobHandle {
+            results,
+            task,
+            missed_runs,
+        };
+        // The watch ends with the job's results, once the job is stopped.
+        if let Some(alerts) = &self.services.alerts {
+            let alerts = alerts.clone();
+            let results = handle.results();
+            let (name, guest_name) = (job.name.clone(), job.guest.name.clone());
+            tokio::spawn(async move { alerts.watch(&name, &guest_name, results).await });
+        }
+        self.jobs.insert(job.name.clone(), handle);
+        &self.jobs[&job.name]
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<JobHandle> {
+        self.jobs.remove(name)
+    }
+
+    /// Remove every job, to be stopped by the caller.
+    pub fn drain(&mut self) -> Vec<JobHandle> {
+        self.jobs.drain().map(|(_, handle)| handle).collect()
+    }
+
+    pub fn job(&self, name: &str) -> Option<&JobHandle> {
+        self.jobs.get(name)
+    }
+
+    /// Stream of results for the named job.
+    pub fn results(&self, name: &str) -> Option<impl Stream<Item = ProofResult>> {
+        self.jobs.get(name).map(JobHandle::results)
+    }
+}
+
+/// Input fetched for a run, waiting to be proven.
+struct Fetched {
+    run: u64,
+    started_at: SystemTime,
+    input: Result<JobInput>,
+}
+
+async fn run_job(
+    job: Job,
+    services: Services,
+    results: broadcast::Sender<ProofResult>,
+    resume: Resume,
+) {
+    if job.prefetch_depth == 0 {
+        let mut ticker = interval_at(resume.start, job.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        for run in resume.first_run.. {
+            ticker.tick().await;
+            if let Some(fetched) = fetch(&job, run).await {
+                prove(&job, &services, &results, fetched).await;
+            }
+        }
+        return;
+    }
+
+    // Fetch inputs on the job's interval in a separate task, at most
+    // `prefetch_depth` runs ahead of the prover. A full queue holds back the
+    // fetcher, delaying its ticks like a slow proof does in the serial case.
+    let (sender, mut receiver) = mpsc::channel(job.prefetch_depth);
+    let fetcher = {
+        let job = job.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval_at(resume.start, job.interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            for run in resume.first_run.. {
+                ticker.tick().await;
+                let Some(fetched) = fetch(&job, run).await else {
+                    continue;
+                };
+                if sender.send(fetched).await.is_err() {
+                    return;
+                }
+            }
+        })
+    };
+    while let Some(fetched) = receiver.recv().await {
+        prove(&job, &services, &results, fetched).await;
+    }
+    fetcher.abort();
+}
+
+/// Fetch the input of a run, unless the job's condition does not fire.
+async fn fetch(job: &Job, run: u64) -> Option<Fetched> {
+    let started_at = clock::now();
+    if let Some(condition) = &job.condition {
+        match condition().await {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(err) => elog!(
+                "Scheduled job {} run {run} condition could not be checked, running anyway: {err:?}",
+                job.name
+            ),
+        }
+    }
+    Some(Fetched {
+        run,
+        started_at,
+        input: (job.input)().await,
+    })
+}
+
+/// Replace the composite receipt of a Bonsai proof with a succinct one,
+/// verified against the guest's image ID. Outputs without a receipt are
+/// returned unchanged.
+async fn compress_output(output: Output, image_id: Digest) -> Result<Output> {
+    let Output::Bonsai {
+        journal,
+        receipt_metadata,
+        snark_proof,
+        session_id,
+        receipt,
+    } = output
+    else {
+        return Ok(output);
+    };
+    let receipt = tokio::task::spawn_blocking(move || -> Result<Receipt> {
+        let receipt = receipt::compress(&receipt)?;
+        receipt
+            .verify(image_id)
+            .map_err(|err| anyhow!("Succinct receipt verification failed: {err}"))?;
+        Ok(receipt)
+    })
+    .await
+    .context("Receipt compression task panicked")?
+    .context(format!(
+        "Failed to compress receipt of session {session_id}"
+    ))?;
+    Ok(Output::Bonsai {
+        journal,
+        receipt_metadata,
+        snark_proof,
+        session_id,
+        receipt,
+    })
+}
+
+fn journal(output: &Output) -> &[u8] {
+    match output {
+        Output::Execution { journal }
+        | Output::Bonsai { journal, .. }
+        | Output::Stark { journal, .. } => journal,
+    }
+}
+
+/// Claim `run` among the replicas sharing `leases`, unless another replica
+/// holds it or the shared run log already records it.
+fn claim(
+    job: &Job,
+    run: u64,
+    leases: &Leases,
+    run_log: Option<&RunLog>,
+) -> Result<Option<LeaseGuard>> {
+    let Some(lease) = leases.claim(&job.name)? else {
+        return Ok(None);
+    };
+    let last_run = match run_log.map(|log| log.last_run(&job.name)).transpose() {
+        Ok(last_run) => last_run.flatten(),
+        Err(err) => {
+            lease.release();
+            return Err(err);
+        }
+    };
+    if matches!(last_run, Some(last_run) if last_run.run >= run) {
+        lease.release();
+        return Ok(None);
+    }
+    Ok(Some(lease.keep_alive()))
+}
+
+/// Prove a fetched run, traced by the job's name and run number, so its
+/// logs, queue messages and latency exemplars can be correlated.
+async fn prove(
+    job: &Job,
+    services: &Services,
+    results: &broadcast::Sender<ProofResult>,
+    fetched: Fetched,
+) {
+    let id = trace::validate(&format!("{}-{}", job.name, fetched.run))
+        .unwrap_or_else(|_| trace::generate());
+    trace::scope(id, prove_run(job, services, results, fetched)).await
+}
+
+async fn prove_run(
+    job: &Job,
+    services: &Services,
+    results: &broadcast::Sender<ProofResult>,
+    fetched: Fetched,
+) {
+    let Fetched {
+        run,
+        started_at,
+        input,
+    } = fetched;
+    let run_log = services.run_log.as_deref();
+    let leases = services.leases.as_deref();
+    let metrics = services.metrics.as_deref();
+    let guard = match leases.map(|leases| claim(job, run, leases, run_log)) {
+        Some(Ok(Some(guard))) => Some(guard),
+        Some(Ok(None)) => {
+            elog!(
+                "Scheduled job {} run {run} is held or done by another replica, skipping",
+                job.name
+            );
+            return;
+        }
+        Some(Err(err)) => {
+            elog!(
+                "Scheduled job {} run {run} skipped, failed to claim its lease: {err:?}",
+                job.name
+            );
+            return;
+        }
+        None => None,
+    };
+    let (output, block, finality) = match input {
+        Ok(JobInput {
+            input,
+            block,
+            finality,
+        }) => {
+            let hook = guard
+                .as_ref()
+                .map(|guard| guard.session_hook(run, block, finality));
+            // A resumed session proves the input its first holder built.
+            let (block, finality) = match hook.as_ref().and_then(SessionHook::resume) {
+                Some(checkpoint) => (checkpoint.block, checkpoint.finality),
+                None => (block, finality),
+            };
+            let keys = match &services.index {
+                Some(_) => match ReceiptIndex::keys(&job.guest, &input) {
+                    Ok(keys) => Some(keys),
+                    Err(err) => {
+                        elog!(
+                            "Scheduled job {} run {run} will not be indexed: {err:?}",
+                            job.name
+                        );
+                        None
+                    }
+                },
+                None => None,
+            };
+            let proving = run_guest(
+                &job.guest,
+                input,
+                &services.pool,
+                &services.bonsai,
+                job.dev_mode,
+            );
+            let output = match hook {
+                Some(hook) => with_session_hook(hook, proving).await,
+                None => proving.await,
+            };
+            if matches!(&guard, Some(guard) if guard.lost()) {
+                elog!(
+                    "Scheduled job {} run {run} was taken over by another replica, dropping its result",
+                    job.name
+                );
+                return;
+            }
+            let output = match output {
+                Ok(output) if job.succinct => compress_output(output, job.guest.image_id).await,
+                output => output,
+            };
+            let output = match (output, &job.verify) {
+                (Ok(output), Some(verify)) => verify(journal(&output).to_vec())
+                    .await
+                    .map(|()| output)
+                    .context("Result was rejected"),
+                (output, _) => output,
+            };
+            // Messages only point at receipts that made it into the index.
+            let mut indexed = None;
+            if let (Ok(output), Some(index), Some(keys)) = (&output, &services.index, keys) {
+                let entry = IndexEntry {
+                    key: keys.key,
+                    input_hash: keys.input_hash,
+                    guest: job.guest.name.clone(),
+                    image_id: hex::encode(job.guest.image_id),
+                    job: Some(job.name.clone()),
+                    run: Some(run),
+                    block,
+                    recorded_at: clock::unix_now(),
+                };
+                match index.record(&entry, output) {
+                    Ok(()) => indexed = Some(entry.input_hash),
+                    Err(err) => elog!(
+                        "Failed to index scheduled job {} run {run}: {err:?}",
+                        job.name
+                    ),
+                }
+            }
+            if let (Ok(output), Some(queues)) = (&output, &services.queues) {
+                queues
+                    .publish_job(
+                        &job.name,
+                        run,
+                        &job.guest,
+                        output,
+                        indexed.as_deref(),
+                        finality,
+                    )
+                    .await;
+            }
+            (output, block, finality)
+        }
+        Err(err) => (Err(err.context("Failed to build job input")), None, None),
+    };
+    if let Some(metrics) = metrics {
+        let latency = clock::now().duration_since(started_at).unwrap_or_default();
+        metrics.record(&job.guest.name, latency, output.is_ok());
+    }
+    match (&output, run_log) {
+        (Err(err), _) => elog!("Scheduled job {} run {run} failed: {err:?}", job.name),
+        (Ok(output), Some(run_log)) => {
+            let last_run = LastRun {
+                run,
+                block,
+                finality,
+                timestamp: clock::unix_secs(started_at),
+                journal_hash: sha256_hex(journal(output)),
+            };
+            if let Err(err) = run_log.record(&job.name, &last_run) {
+                elog!(
+                    "Failed to record scheduled job {} run {run}: {err:?}",
+                    job.name
+                );
+            }
+        }
+        (Ok(_), None) => {}
+    }
+    if let Some(guard) = guard {
+        if let Err(err) = guard.lease().clear_checkpoint() {
+            elog!(
+                "Failed to clear checkpoint of scheduled job {} run {run}: {err:?}",
+                job.name
+            );
+        }
+    }
+    // Sending only fails when nobody is subscribed, which is fine.
+    let _ = results.send(ProofResult {
+        job: job.name.clone(),
+        run,
+        started_at,
+        finality,
+        output: output.map(Arc::new).map_err(|err| format!("{err:?}")),
+    });
+}
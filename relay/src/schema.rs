@@ -0,0 +1,619 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, ParamType, Token},
+    types::{Address, H256, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::tokens::{Price, PriceDirection, TokenMetadata};
+
+/// Solidity types of the values a guest commits to its journal, in order.
+/// Must match the `ethabi::encode` call at the end of the guest.
+pub fn journal_schema(guest_name: &str) -> Option<Vec<ParamType>> {
+    match guest_name.to_uppercase().as_str() {
+        // (bytes32 request_root, uint160 sqrt_p, uint256 amount_in,
+        //  uint256 amount_out, uint256 fee_amount, uint64 observed_from,
+        //  uint64 observed_to, uint128 liquidity, uint128 min_liquidity,
+        //  address pool, bytes32 block_hash, uint64 block_number, bool
+        //  unfinalized), see settleSwap and ZkPriceAggregator. The pool and
+        //  block are zero, and the result finalized, for inputs without a
+        //  state proof.
+        "SWAP" => Some(vec![
+            ParamType::FixedBytes(32),
+            ParamType::Uint(160),
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+            ParamType::Uint(256),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::Uint(128),
+            ParamType::Uint(128),
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Bool,
+        ]),
+        // (bytes32 request_root, uint64 observed_from, uint64 observed_to,
+        //  (address pool, uint8 status, uint160 sqrt_p, uint128 liquidity,
+        //  uint128 min_liquidity)[] prices, bool unfinalized), see
+        //  ZkPriceBatcher and [PriceStatus].
+        "BATCH" => Some(vec![
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Uint(8),
+                ParamType::Uint(160),
+                ParamType::Uint(128),
+                ParamType::Uint(128),
+            ]))),
+            ParamType::Bool,
+        ]),
+        // (address pool, uint160 sqrt_p, int24 mean_tick, uint64
+        //  observed_from, uint64 observed_to, bytes32[] block_hashes, uint8
+        //  checks), the mean tick over exactly the observed interval, the
+        //  blocks it was proven at and the [OBSERVATION_CHECKS] the
+        //  observations passed.
+        "TWAP" => Some(vec![
+            ParamType::Address,
+            ParamType::Uint(160),
+            ParamType::Int(24),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+            ParamType::Uint(8),
+        ]),
+        // (address pool, bytes32 previous_root, bytes32 root, uint64 count,
+        //  bytes32[] peaks, uint64 observed_from, uint64 observed_to, bytes32
+        //  block_hash, uint8 checks, uint64 block_number), see
+        //  ZkObservationHistory and [OBSERVATION_CHECKS].
+        "HISTORY" => Some(vec![
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::FixedBytes(32),
+            ParamType::Uint(8),
+            ParamType::Uint(64),
+        ]),
+        // (address vault, bytes32 block_hash, uint64 observed_from, uint64
+        //  observed_to, address factory, bytes32 init_code_hash, address[]
+        //  tokens, uint256[] reserves, uint256 positions), the vault's
+        //  reserves by token at a single block.
+        "RESERVE" => Some(vec![
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Array(Box::new(ParamType::Address)),
+            ParamType::Array(Box::new(ParamType::Uint(256))),
+            ParamType::Uint(256),
+        ]),
+        // (address start, bytes32 block_hash, uint64 observed_from, uint64
+        //  observed_to, address factory, bytes32 init_code_hash, address[]
+        //  pools, uint24[] fees, uint256 factor_x96), the arbitrage factor of
+        //  a cycle of pools at a single block.
+        "CYCLE" => Some(vec![
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::Address,
+            ParamType::FixedBytes(32),
+            ParamType::Array(Box::new(ParamType::Address)),
+            ParamType::Array(Box::new(ParamType::Uint(24))),
+            ParamType::Uint(256),
+        ]),
+        _ => None,
+    }
+}
+
+/// Sanity checks of a pool's oracle observations the TWAP and HISTORY guests
+/// enforce, committed as a bitmask of: observations initialized (`1`),
+/// timestamps strictly ascending and not after their block (`2`), indices
+/// within the ring's cardinality (`4`) and tick cumulatives within the tick
+/// range (`8`). Must match `OBSERVATION_CHECKS` in the guest library.
+pub const OBSERVATION_CHECKS: u8 = 0b1111;
+
+/// Prefix of the trailer word versioned journals end with, laid out as
+/// `JOURNAL_VERSION_MAGIC || 26 zero bytes || u16 BE version`. The trailer
+/// follows the ABI encoded values, so decoders of the previous version,
+/// Solidity's `abi.decode` and callback argument decoding included, still
+/// read the values and ignore it. Journals without it are version 1, from
+/// guests built before journals were versioned. Must match
+/// `JOURNAL_VERSION_MAGIC` in the guest library.
+pub const JOURNAL_VERSION_MAGIC: &[u8; 4] = b"RZJV";
+
+/// Version of the journals the current image of a guest commits. Bump it
+/// with the guest's `JOURNAL_VERSION` whenever [journal_schema] changes, and
+/// keep the previous version in [journal_schema_at] until every consumer
+/// runs the new image.
+pub fn journal_version(guest_name: &str) -> Option<u16> {
+    match guest_name.to_uppercase().as_str() {
+        "RESERVE" | "CYCLE" => Some(2),
+        "TWAP" => Some(3),
+        "SWAP" | "BATCH" | "HISTORY" => Some(4),
+        _ => None,
+    }
+}
+
+/// Solidity types of the values of version `version` of a guest's journal.
+/// The relay reads the current version and the one before it, so results of
+/// the previous image are still decoded and submitted while both images are
+/// live during an upgrade.
+pub fn journal_schema_at(guest_name: &str, version: u16) -> Option<Vec<ParamType>> {
+    let current = journal_version(guest_name)?;
+    if version > current || version + 1 < current {
+        return None;
+    }
+    match (guest_name.to_uppercase().as_str(), version) {
+        // Version 3 of BATCH added each price's status, before which every
+        // committed price was proven.
+        ("BATCH", 2) => Some(vec![
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Uint(160),
+                ParamType::Uint(128),
+                ParamType::Uint(128),
+            ]))),
+        ]),
+        // Version 3 of SWAP appended the pool and block its state was proven
+        // at.
+        ("SWAP", 2) => journal_schema(guest_name).map(|mut schema| {
+            schema.truncate(schema.len() - 4);
+            schema
+        }),
+        // Version 4 of SWAP and BATCH appended whether the block they were
+        // read at was unfinalized.
+        ("SWAP" | "BATCH", 3) => journal_schema(guest_name).map(|mut schema| {
+            schema.pop();
+            schema
+        }),
+        // Version 3 of TWAP and HISTORY appended the sanity checks the
+        // observations passed.
+        ("TWAP" | "HISTORY", 2) => journal_schema(guest_name).map(|mut schema| {
+            schema.pop();
+            schema
+        }),
+        // Version 4 of HISTORY appended the number of its block, which
+        // contracts check the block hash against.
+        ("HISTORY", 3) => journal_schema(guest_name).map(|mut schema| {
+            schema.pop();
+            schema
+        }),
+        (_, 3 | 4) => journal_schema(guest_name),
+        // Version 2 only added the version trailer.
+        (_, 1 | 2) => journal_schema(guest_name),
+        _ => None,
+    }
+}
+
+/// Split a journal into its version and its ABI encoded values.
+pub fn split_journal_version(journal: &[u8]) -> (u16, &[u8]) {
+    if journal.len() >= 32 && journal.chunks_exact(32).remainder().is_empty() {
+        let (values, trailer) = journal.split_at(journal.len() - 32);
+        if trailer.starts_with(JOURNAL_VERSION_MAGIC) && trailer[4..30].iter().all(|b| *b == 0) {
+            return (u16::from_be_bytes([trailer[30], trailer[31]]), values);
+        }
+    }
+    (1, journal)
+}
+
+/// Read the version of a guest's journal, failing if the relay cannot decode
+/// it. Journals of guests without a registered schema are always accepted.
+pub fn check_journal_version(guest_name: &str, journal: &[u8]) -> Result<u16> {
+    let (version, _) = split_journal_version(journal);
+    let Some(current) = journal_version(guest_name) else {
+        return Ok(version);
+    };
+    if journal_schema_at(guest_name, version).is_none() {
+        bail!(
+            "{guest_name} journal version {version} is not supported, expected version {} or {current}",
+            current.saturating_sub(1).max(1)
+        );
+    }
+    Ok(version)
+}
+
+/// Version of a guest's input schema, framed into every input so guests
+/// reject inputs built for another version. Bump it with the guest's
+/// `INPUT_SCHEMA` whenever [input_schema] changes.
+pub fn input_schema_version(guest_name: &str) -> Option<u16> {
+    match guest_name.to_uppercase().as_str() {
+        "HISTORY" | "RESERVE" | "CYCLE" => Some(1),
+        "BATCH" | "TWAP" => Some(2),
+        "SWAP" => Some(3),
+        _ => None,
+    }
+}
+
+/// Solidity types of a guest's input, in order. Must match the
+/// `ethabi::decode_whole` call at the start of the guest.
+pub fn input_schema(guest_name: &str) -> Option<Vec<ParamType>> {
+    match guest_name.to_uppercase().as_str() {
+        // (bytes32 request_root, uint160 sqrt_p, uint160 sqrt_p_target,
+        //  uint128 liquidity, int256 amount, uint24 fee, uint64 observed_at,
+        //  uint128 min_liquidity, bytes state_proof), see requestSwap and
+        //  input::PoolStateProof for the layout of the state proof.
+        "SWAP" => Some(vec![
+            ParamType::FixedBytes(32),
+            ParamType::Uint(160),
+            ParamType::Uint(160),
+            ParamType::Uint(128),
+            ParamType::Int(256),
+            ParamType::Uint(24),
+            ParamType::Uint(64),
+            ParamType::Uint(128),
+            ParamType::Bytes,
+        ]),
+        // (bytes32 request_root, uint64 observed_at, (address pool, uint160
+        //  sqrt_p, uint160 sqrt_p_target, uint128 liquidity, int256 amount,
+        //  uint24 fee, uint128 min_liquidity)[] feeds, bool unfinalized), see
+        //  input::BatchInput.
+        "BATCH" => Some(vec![
+            ParamType::FixedBytes(32),
+            ParamType::Uint(64),
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Address,
+                ParamType::Uint(160),
+                ParamType::Uint(160),
+                ParamType::Uint(128),
+                ParamType::Int(256),
+                ParamType::Uint(24),
+                ParamType::Uint(128),
+            ]))),
+            ParamType::Bool,
+        ]),
+        // (address pool, (bytes header, bytes[] account_proof, bytes[]
+        //  slot0_proof, (uint16 index, bytes[] proof)[] observations)[]
+        //  anchors), see twap::TwapInput.
+        "TWAP" => Some(vec![
+            ParamType::Address,
+            ParamType::Array(Box::new(ParamType::Tuple(vec![
+                ParamType::Bytes,
+                ParamType::Array(Box::new(ParamType::Bytes)),
+                ParamType::Array(Box::new(ParamType::Bytes)),
+                ParamType::Array(Box::new(ParamType::Tuple(vec![
+                    ParamType::Uint(16),
+                    ParamType::Array(Box::new(ParamType::Bytes)),
+                ]))),
+            ]))),
+        ]),
+        // (address pool, uint64 count, bytes32[] peaks, uint64
+        //  last_timestamp, bytes header, bytes[] account_proof, bytes[]
+        //  slot0_proof, uint16 first_index, bytes[][] observation_proofs), see
+        //  history::HistoryInput.
+        "HISTORY" => {
+            let proof = ParamType::Array(Box::new(ParamType::Bytes));
+            Some(vec![
+                ParamType::Address,
+                ParamType::Uint(64),
+                ParamType::Array(Box::new(ParamType::FixedBytes(32))),
+                ParamType::Uint(64),
+                ParamType::Bytes,
+                proof.clone(),
+                proof.clone(),
+                ParamType::Uint(16),
+                ParamType::Array(Box::new(proof)),
+            ])
+        }
+        // (address vault, address factory, bytes32 init_code_hash, bytes
+        //  header, (address token0, address token1, uint24 fee, bytes[]
+        //  account_proof, bytes[] slot0_proof, (int24 tick_lower, int24
+        //  tick_upper, bytes[] liquidity_proof, bytes[] tokens_owed_proof)[]
+        //  positions)[] pools), see reserve::ReserveInput.
+        "RESERVE" => {
+            let proof = ParamType::Array(Box::new(ParamType::Bytes));
+            Some(vec![
+                ParamType::Address,
+                ParamType::Address,
+                ParamType::FixedBytes(32),
+                ParamType::Bytes,
+                ParamType::Array(Box::new(ParamType::Tuple(vec![
+                    ParamType::Address,
+                    ParamType::Address,
+                    ParamType::Uint(24),
+                    proof.clone(),
+                    proof.clone(),
+                    ParamType::Array(Box::new(ParamType::Tuple(vec![
+                        ParamType::Int(24),
+                        ParamType::Int(24),
+                        proof.clone(),
+                        proof,
+                    ]))),
+                ]))),
+            ])
+        }
+        // (address factory, bytes32 init_code_hash, bytes header, address
+        //  start, (address token0, address token1, uint24 fee, bytes[]
+        //  account_proof, bytes[] slot0_proof)[] legs), see
+        //  cycle::CycleInput.
+        "CYCLE" => {
+            let proof = ParamType::Array(Box::new(ParamType::Bytes));
+            Some(vec![
+                ParamType::Address,
+                ParamType::FixedBytes(32),
+                ParamType::Bytes,
+                ParamType::Address,
+                ParamType::Array(Box::new(ParamType::Tuple(vec![
+                    ParamType::Address,
+                    ParamType::Address,
+                    ParamType::Uint(24),
+                    proof.clone(),
+                    proof,
+                ]))),
+            ])
+        }
+        _ => None,
+    }
+}
+
+/// Index of the (uint64 observed_from, uint64 observed_to) pair in the guest's
+/// journal, for guests whose results are only valid for the chain state they
+/// were computed from.
+fn validity_index(guest_name: &str) -> Option<usize> {
+    match guest_name.to_uppercase().as_str() {
+        "SWAP" => Some(5),
+        "BATCH" => Some(1),
+        "TWAP" => Some(3),
+        "HISTORY" => Some(5),
+        "RESERVE" | "CYCLE" => Some(2),
+        _ => None,
+    }
+}
+
+/// Index of the `sqrtPriceX96` in the guest's journal, for guests committing
+/// a pool price.
+fn price_index(guest_name: &str) -> Option<usize> {
+    match guest_name.to_uppercase().as_str() {
+        "SWAP" | "TWAP" => Some(1),
+        _ => None,
+    }
+}
+
+/// Indices of the (bytes32 previous_root, bytes32 root) pair and of the
+/// anchor block hash in the guest's journal, for guests extending a
+/// commitment made by their previous run.
+fn continuity_index(guest_name: &str) -> Option<(usize, usize)> {
+    match guest_name.to_uppercase().as_str() {
+        "HISTORY" => Some((1, 7)),
+        _ => None,
+    }
+}
+
+/// State a journal of an incremental guest extends and commits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Continuity {
+    /// Commitment the run extended, which the previous run committed.
+    pub previous_root: H256,
+    pub root: H256,
+    /// Block the run's new state was proven at.
+    pub block_hash: H256,
+}
+
+/// Read the commitments and anchor block of a journal. Returns `None` for
+/// guests that do not extend a previous run's commitment.
+pub fn journal_continuity(guest_name: &str, journal: &[u8]) -> Result<Option<Continuity>> {
+    let Some((roots, block)) = continuity_index(guest_name) else {
+        return Ok(None);
+    };
+    let Some(tokens) = decode_journal(guest_name, journal)? else {
+        return Ok(None);
+    };
+    let hash = |i: usize| -> Result<H256> {
+        tokens
+            .get(i)
+            .cloned()
+            .and_then(Token::into_fixed_bytes)
+            .filter(|bytes| bytes.len() == 32)
+            .map(|bytes| H256::from_slice(&bytes))
+            .ok_or_else(|| anyhow!("{guest_name} journal has no hash {i}"))
+    };
+    Ok(Some(Continuity {
+        previous_root: hash(roots)?,
+        root: hash(roots + 1)?,
+        block_hash: hash(block)?,
+    }))
+}
+
+/// Range of block timestamps, in seconds since the Unix epoch, of the chain
+/// state a journal was computed from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    pub observed_from: u64,
+    pub observed_to: u64,
+}
+
+impl Validity {
+    /// Time elapsed since the most recent observation, zero if it lies in
+    /// the future.
+    pub fn age(&self, now: SystemTime) -> Duration {
+        let observed_to = UNIX_EPOCH + Duration::from_secs(self.observed_to);
+        now.duration_since(observed_to).unwrap_or_default()
+    }
+
+    /// Whether the result is older than `max_age` at `now`.
+    pub fn is_stale(&self, now: SystemTime, max_age: Duration) -> bool {
+        self.age(now) > max_age
+    }
+}
+
+/// Read the observation range committed to a journal. Returns `None` for
+/// guests whose results do not depend on when they were computed.
+pub fn journal_validity(guest_name: &str, journal: &[u8]) -> Result<Option<Validity>> {
+    let Some(index) = validity_index(guest_name) else {
+        return Ok(None);
+    };
+    let Some(tokens) = decode_journal(guest_name, journal)? else {
+        return Ok(None);
+    };
+    let timestamp = |i: usize| -> Result<u64> {
+        tokens
+            .get(i)
+            .cloned()
+            .and_then(Token::into_uint)
+            .map(|value| value.low_u64())
+            .ok_or_else(|| anyhow!("{guest_name} journal has no observation timestamp {i}"))
+    };
+    Ok(Some(Validity {
+        observed_from: timestamp(index)?,
+        observed_to: timestamp(index + 1)?,
+    }))
+}
+
+/// Read the pool price committed to a journal in whole tokens, quoted in
+/// `direction`. Returns `None` for guests that commit no price.
+pub fn journal_price(
+    guest_name: &str,
+    journal: &[u8],
+    token0: &TokenMetadata,
+    token1: &TokenMetadata,
+    direction: PriceDirection,
+) -> Result<Option<Price>> {
+    let Some(index) = price_index(guest_name) else {
+        return Ok(None);
+    };
+    let Some(tokens) = decode_journal(guest_name, journal)? else {
+        return Ok(None);
+    };
+    let sqrt_price_x96 = tokens
+        .get(index)
+        .cloned()
+        .and_then(Token::into_uint)
+        .ok_or_else(|| anyhow!("{guest_name} journal has no price {index}"))?;
+    Ok(Some(Price::from_sqrt_price_x96(
+        sqrt_price_x96,
+        token0,
+        token1,
+        direction,
+    )))
+}
+
+/// Outcome of pricing one pool of a BATCH journal. Pools that could not be
+/// priced fail on their own, and their price is zero. Must match the guest's
+/// `STATUS_*` constants and `ZkPriceBatcher`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceStatus {
+    Ok,
+    /// The pool's liquidity was below the input's `min_liquidity`.
+    BelowMinLiquidity,
+    /// The swap step from the pool's price failed.
+    SwapFailed,
+}
+
+impl PriceStatus {
+    fn from_code(code: u8) -> Result<Self> {
+        Ok(match code {
+            0 => Self::Ok,
+            1 => Self::BelowMinLiquidity,
+            2 => Self::SwapFailed,
+            _ => bail!("unknown batch price status {code}"),
+        })
+    }
+}
+
+/// A pool's price in a BATCH journal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchPrice {
+    pub pool: Address,
+    pub status: PriceStatus,
+    pub sqrt_price_x96: U256,
+    pub liquidity: u128,
+    pub min_liquidity: u128,
+}
+
+/// Read the price of each pool committed to a BATCH journal. Prices of
+/// journals before version 3 carry no status and were all proven.
+pub fn batch_prices(journal: &[u8]) -> Result<Vec<BatchPrice>> {
+    let (version, _) = split_journal_version(journal);
+    let tokens =
+        decode_journal("BATCH", journal)?.ok_or_else(|| anyhow!("BATCH journal has no schema"))?;
+    let prices = tokens
+        .get(3)
+        .cloned()
+        .and_then(Token::into_array)
+        .ok_or_else(|| anyhow!("BATCH journal has no prices"))?;
+    prices
+        .into_iter()
+        .map(|price| {
+            let mut fields = price
+                .into_tuple()
+                .ok_or_else(|| anyhow!("BATCH journal price is not a tuple"))?
+                .into_iter();
+            let pool = fields
+                .next()
+                .and_then(Token::into_address)
+                .ok_or_else(|| anyhow!("BATCH journal price has no pool"))?;
+            let mut uint = |name: &str| {
+                fields
+                    .next()
+                    .and_then(Token::into_uint)
+                    .ok_or_else(|| anyhow!("BATCH journal price of {pool:?} has no {name}"))
+            };
+            let status = match version {
+                1 | 2 => PriceStatus::Ok,
+                _ => PriceStatus::from_code(uint("status")?.low_u32() as u8)?,
+            };
+            Ok(BatchPrice {
+                pool,
+                status,
+                sqrt_price_x96: uint("price")?,
+                liquidity: uint("liquidity")?.low_u128(),
+                min_liquidity: uint("minimum liquidity")?.low_u128(),
+            })
+        })
+        .collect()
+}
+
+/// Decode a journal according to the guest's schema at the journal's
+/// version.
+pub fn decode_journal(guest_name: &str, journal: &[u8]) -> Result<Option<Vec<Token>>> {
+    if journal_schema(guest_name).is_none() {
+        return Ok(None);
+    }
+    let version = check_journal_version(guest_name, journal)?;
+    let Some(schema) = journal_schema_at(guest_name, version) else {
+        return Ok(None);
+    };
+    let (_, values) = split_journal_version(journal);
+    let tokens = abi::decode(&schema, values).context(format!(
+        "Journal does not match version {version} of the {guest_name} schema"
+    ))?;
+    Ok(Some(tokens))
+}
+
+/// ABI encode the journal values as a single tuple, the way Solidity's
+/// `abi.encode(values)` would for the guest's schema. Journals of guests
+/// without a registered schema are assumed to be ABI encoded already.
+pub fn public_values(guest_name: &str, journal: &[u8]) -> Result<Vec<u8>> {
+    Ok(match decode_journal(guest_name, journal)? {
+        Some(tokens) => abi::encode(&[Token::Tuple(tokens)]),
+        None => journal.to_vec(),
+    })
+}
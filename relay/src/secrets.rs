@@ -0,0 +1,177 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Loading of secrets from a file kept apart from the rest of the
+//! configuration, in `.env` format.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::redact::register_secret;
+
+/// Environment variables holding secrets. Only these may be set from a
+/// secrets file, and their values are masked wherever they are set from.
+pub const SECRET_VARS: &[&str] = &[
+    "BONSAI_API_KEY",
+    "OPERATOR_KEY",
+    "PRIVATE_KEY",
+    "STORE_KEY",
+    "STORE_KMS_KEY_CIPHERTEXT",
+    "TRANSMITTER_KEY",
+    "UPDATE_SIGNING_KEY",
+];
+
+/// Environment variable and flag naming the secrets file. These are read
+/// before the command line is parsed, so that secrets from the file fill in
+/// the arguments they back.
+pub const SECRETS_FILE_VAR: &str = "SECRETS_FILE";
+const SECRETS_FILE_FLAG: &str = "--secrets-file";
+
+/// Secrets set from the secrets file, by variable name.
+static FROM_FILE: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Where a secret variable was set from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    File,
+    Environment,
+}
+
+/// Path of the secrets file given on the command line or in the environment.
+pub fn secrets_file_path(args: impl IntoIterator<Item = String>) -> Option<PathBuf> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == SECRETS_FILE_FLAG {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg
+            .strip_prefix(SECRETS_FILE_FLAG)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(path.into());
+        }
+    }
+    env::var_os(SECRETS_FILE_VAR).map(PathBuf::from)
+}
+
+/// Parse a secrets file of `NAME=value` lines. Blank lines and lines starting
+/// with `#` are skipped, an `export ` prefix is allowed and values may be
+/// quoted. Names other than [SECRET_VARS] are rejected, so that plain settings
+/// stay in the configuration where they can be reviewed and dumped.
+pub fn parse(contents: &str) -> Result<Vec<(String, String)>> {
+    let mut secrets = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        // Never echo the line itself, as it holds a secret.
+        let Some((name, value)) = line.split_once('=') else {
+            bail!("line {} is not of the form NAME=value", number + 1);
+        };
+        let name = name.trim();
+        if !SECRET_VARS.contains(&name) {
+            bail!(
+                "line {} sets {name}, which is not a secret; expected one of {}",
+                number + 1,
+                SECRET_VARS.join(", ")
+            );
+        }
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+            .unwrap_or(value);
+        secrets.push((name.to_string(), value.to_string()));
+    }
+    Ok(secrets)
+}
+
+/// Load the secrets file at `path` into the environment. Variables already
+/// set in the environment take precedence over the file. Every loaded value
+/// is registered for redaction before anything else can log it. As it sets
+/// environment variables, it must be called before any other thread starts.
+pub fn load(path: &Path) -> Result<()> {
+    check_permissions(path)?;
+    let contents = fs::read_to_string(path)
+        .context(format!("Failed to read secrets file {}", path.display()))?;
+    let secrets = parse(&contents).context(format!("Invalid secrets file {}", path.display()))?;
+    let mut from_file = FROM_FILE.lock().unwrap_or_else(PoisonError::into_inner);
+    for (name, value) in secrets {
+        register_secret(value.trim_start_matches("0x"));
+        if env::var_os(&name).is_some() {
+            continue;
+        }
+        env::set_var(&name, value);
+        from_file.push(name);
+    }
+    Ok(())
+}
+
+/// Register the secrets set in the environment for redaction.
+pub fn register_env() {
+    for name in SECRET_VARS {
+        if let Ok(value) = env::var(name) {
+            register_secret(value.trim_start_matches("0x"));
+        }
+    }
+}
+
+/// Secret variables that are set and where from, for reporting. Never
+/// includes the values.
+pub fn sources() -> Vec<(&'static str, Source)> {
+    let from_file = FROM_FILE.lock().unwrap_or_else(PoisonError::into_inner);
+    SECRET_VARS
+        .iter()
+        .filter(|name| env::var_os(name).is_some())
+        .map(|name| {
+            let source = if from_file.iter().any(|loaded| loaded == name) {
+                Source::File
+            } else {
+                Source::Environment
+            };
+            (*name, source)
+        })
+        .collect()
+}
+
+/// Refuse secrets files other users may read.
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)
+        .context(format!("Failed to read secrets file {}", path.display()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        bail!(
+            "secrets file {} is accessible to other users (mode {:o}); restrict it with chmod 600",
+            path.display(),
+            mode & 0o777
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
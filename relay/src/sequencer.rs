@@ -0,0 +1,123 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Ingestion of a rollup sequencer's feed, so latency sensitive consumers
+//! can have inputs anchored to blocks as soon as the sequencer produces them
+//! rather than once they settle. On Arbitrum the feed is read through a node
+//! following the sequencer's broadcast feed, on OP stack chains through the
+//! sequencer's or a node's endpoint serving unsafe blocks.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result};
+use ethers::{
+    providers::{Middleware, Provider, Ws},
+    types::{Block, H256},
+};
+use tokio::{sync::watch, task::JoinHandle};
+use tokio_stream::StreamExt;
+
+use crate::{elog, finality::FinalityPolicy};
+
+/// Delay before subscribing again to a feed whose subscription ended.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Follows the new heads of a sequencer feed. Input state is read from the
+/// feed's endpoint too, since the chain's node may not have the newest
+/// block yet.
+pub struct SequencerFeed {
+    chain_id: u64,
+    client: Arc<Provider<Ws>>,
+    head: watch::Receiver<Option<Block<H256>>>,
+    task: JoinHandle<()>,
+}
+
+impl SequencerFeed {
+    /// Connect to the WebSocket endpoint of the sequencer feed of
+    /// `chain_id` and follow its new heads.
+    pub async fn connect(chain_id: u64, url: &str) -> Result<Self> {
+        let client = Provider::<Ws>::connect(url).await.context(format!(
+            "Failed to connect to the sequencer feed of chain {chain_id}"
+        ))?;
+        let client = Arc::new(client);
+        let (sender, head) = watch::channel(None);
+        let task = tokio::spawn(follow(chain_id, client.clone(), sender));
+        Ok(Self {
+            chain_id,
+            client,
+            head,
+            task,
+        })
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    /// Endpoint of the feed, serving state at the blocks it produced.
+    pub fn client(&self) -> Arc<Provider<Ws>> {
+        self.client.clone()
+    }
+
+    /// The newest block the sequencer produced. Until the first head
+    /// arrives, it is read from the feed's endpoint.
+    pub async fn head(&self) -> Result<Block<H256>> {
+        let head = self.head.borrow().clone();
+        match head {
+            Some(head) => Ok(head),
+            None => FinalityPolicy::Sequencer.block(self.client.as_ref()).await,
+        }
+    }
+}
+
+impl Drop for SequencerFeed {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Keep the newest head of the feed in `sender`, subscribing again whenever
+/// the subscription ends. Heads older than the one kept, as replayed after
+/// resubscribing, are ignored.
+async fn follow(
+    chain_id: u64,
+    client: Arc<Provider<Ws>>,
+    sender: watch::Sender<Option<Block<H256>>>,
+) {
+    loop {
+        match client.subscribe_blocks().await {
+            Ok(mut heads) => {
+                while let Some(head) = heads.next().await {
+                    sender.send_if_modified(|current| {
+                        let kept = current.as_ref().and_then(|current| current.number);
+                        let newer = match (kept, head.number) {
+                            (Some(kept), Some(number)) => kept < number,
+                            (None, Some(_)) => true,
+                            (_, None) => false,
+                        };
+                        if newer {
+                            *current = Some(head);
+                        }
+                        newer
+                    });
+                }
+                elog!("Sequencer feed of chain {chain_id} ended, subscribing again");
+            }
+            Err(err) => {
+                elog!("Failed to subscribe to the sequencer feed of chain {chain_id}: {err}")
+            }
+        }
+        tokio::time::sleep(RESUBSCRIBE_DELAY).await;
+    }
+}
@@ -0,0 +1,1206 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    body::{self, Full, HttpBody},
+    extract::{Path, Query, State},
+    http::{
+        header::{ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, VARY},
+        HeaderMap, HeaderValue, Request, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Extension, Json, Router,
+};
+use bonsai_sdk::alpha::responses::SnarkProof;
+use ethers::{
+    providers::{Provider, Ws},
+    types::{Address, Signature},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    access::{Rejection, RequesterPolicy},
+    approval::{Approvals, PendingApproval},
+    artifacts::Artifacts,
+    backend::{BonsaiBackend, ProverBackend},
+    canary::{Canary, CanaryReport},
+    capabilities::Capabilities,
+    catalog::{PoolCatalog, PoolConfig},
+    clock,
+    dedup::Deduplicator,
+    elog,
+    error::Fault,
+    eth::EthClient,
+    guardian::{Guardian, GuardianStatus},
+    index::{IndexedReceipt, ReceiptIndex},
+    input::{private_salt, request_key, split_input},
+    metrics::{Metrics, SloStatus},
+    pool::{CycleStats, GuestLogs, ImagePool},
+    postprocess::{PostProcessChain, PostProcessorConfig},
+    prepare_input, prepare_salted_input,
+    provenance::GuestProvenance,
+    pull::{PriceUpdate, PriceUpdates, UpdateCalldata},
+    queue::Queues,
+    redact::redact,
+    registry::GuestRegistry,
+    reload::Reloader,
+    retry::AttemptLog,
+    run_guest,
+    schema::public_values,
+    sessions::{SessionStatus, Sessions},
+    shadow::{ShadowStats, ShadowVerifier},
+    store::{BlobKind, JobFilter, JobPage, JobRecord, JobStatus, Store},
+    tenant::{key_digest, Role, Tenant, TenantUsage, Tenants},
+    tokens::TokenResolver,
+    trace,
+    webhook::Webhooks,
+    Output,
+};
+
+/// Header carrying the caller's API key, as an alternative to a bearer token.
+pub const API_KEY_HEADER: &str = "x-api-key";
+
+/// Marks requests made with the relay's operator key, which may change state
+/// shared by every tenant, e.g. pause proving. No tenant's key may.
+#[derive(Debug, Clone, Copy)]
+pub struct Operator;
+
+/// Shared state of the relay API server.
+pub struct AppState {
+    pub registry: Arc<GuestRegistry>,
+    pub pool: Arc<ImagePool>,
+    pub dedup: Deduplicator,
+    pub tenants: Arc<RwLock<Tenants>>,
+    /// Where inputs and receipts of completed requests are kept, if anywhere.
+    pub store: Option<Store>,
+    /// Which requester addresses are served, if restricted.
+    pub requesters: Option<Arc<RequesterPolicy>>,
+    pub dev_mode: bool,
+    /// Key of the relay's operator. Without one, operator routes are only
+    /// served while the relay runs without tenant API keys.
+    pub operator_key: Option<String>,
+    /// Set by the operator to stop accepting prove requests.
+    pub paused: AtomicBool,
+    /// Configuration files admins may reload, if any.
+    pub reloader: Option<Arc<Reloader>>,
+    /// Where to write the artifacts of every guest run, if anywhere.
+    pub artifacts: Option<Arc<Artifacts>>,
+    /// Proves requests outside of dev mode without a `prover`.
+    pub bonsai: BonsaiBackend,
+    /// Proves requests in place of Bonsai outside of dev mode, if set.
+    pub prover: Option<Arc<dyn ProverBackend>>,
+    /// Resolves the tokens post-processors refer to, if connected to a chain.
+    pub tokens: Option<Arc<TokenResolver<Provider<Ws>>>>,
+    /// Pools proven on a schedule, which admins may change.
+    pub catalog: Option<Arc<PoolCatalog>>,
+    /// Prove requests running in the background.
+    pub sessions: Sessions<ProveResponse>,
+    /// Latency objectives of guests, whose status admins may query.
+    pub metrics: Option<Arc<Metrics>>,
+    /// What the relay can do as configured, reported to clients.
+    pub capabilities: Capabilities,
+    /// Results of scheduled jobs by canonical input, if indexed.
+    pub index: Option<Arc<ReceiptIndex>>,
+    /// Where tenants' results are published, if anywhere.
+    pub queues: Option<Arc<Queues>>,
+    /// Delivers results to the webhooks of the tenants that have one.
+    pub webhooks: Webhooks,
+    /// Re-executes a sample of the requests proven on Bonsai without a
+    /// `prover`, if enabled.
+    pub shadow: Option<Arc<ShadowVerifier>>,
+    /// Canary of a new guest image, run since startup, if any.
+    pub canary: Option<Arc<Canary>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProveRequest {
+    /// Name or hex image ID of the guest.
+    pub guest: String,
+    /// Hex encoded, ABI encoded guest input.
+    pub input: String,
+    /// Hex encoded private input, for guests that commit only its digest.
+    /// It is never persisted.
+    pub private_input: Option<String>,
+    /// Hex encoded 32 byte salt of the private input's digest. Drawn at
+    /// random if not given, and returned either way.
+    pub private_salt: Option<String>,
+    /// Address on whose behalf the request is made, which must be one of the
+    /// tenant's `requesters`. Defaults to the tenant's only requester, and is
+    /// required when the relay has a requester policy.
+    pub requester: Option<Address>,
+    /// Post-processors to apply to the journal for this request, in order.
+    #[serde(default)]
+    pub post_process: Vec<PostProcessorConfig>,
+    /// Require the SNARK the relay contract verifies, proving only on
+    /// backends that produce one.
+    #[serde(default)]
+    pub snark: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProveResponse {
+    pub image_id: String,
+    pub journal: String,
+    pub public_values: String,
+    /// Journal values after the request's post-processors, ABI encoded. The
+    /// proof commits to `journal`, not to these.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processed_journal: Option<String>,
+    pub post_state_digest: Option<String>,
+    pub snark_proof: Option<SnarkProof>,
+    /// Whether the result was shared with an identical earlier request.
+    pub deduplicated: bool,
+    /// Trace ID of the request, as supplied by the client or generated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Salt the private input's digest was committed with, which opens the
+    /// commitment together with the private input. It is never persisted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_salt: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionCreated {
+    pub session_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WaitParams {
+    /// Seconds to wait for the session to finish, at most
+    /// [crate::sessions::MAX_WAIT].
+    #[serde(default = "default_wait_secs")]
+    pub timeout: u64,
+}
+
+fn default_wait_secs() -> u64 {
+    60
+}
+
+/// Most jobs returned in one page.
+pub const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct ListJobsParams {
+    /// Guest name or hex image ID.
+    pub guest: Option<String>,
+    pub requester: Option<Address>,
+    pub status: Option<JobStatus>,
+    /// Earliest completion time, in Unix seconds.
+    pub since: Option<u64>,
+    /// Latest completion time, in Unix seconds.
+    pub until: Option<u64>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    #[serde(default = "default_page_size")]
+    pub limit: usize,
+}
+
+fn default_page_size() -> usize {
+    20
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimulateRequest {
+    /// Name or hex image ID of the guest.
+    pub guest: String,
+    /// Hex encoded, ABI encoded guest input.
+    pub input: String,
+    /// Hex encoded private input. It is never persisted.
+    pub private_input: Option<String>,
+    /// Return what the guest printed to stdout and stderr.
+    #[serde(default)]
+    pub guest_logs: bool,
+}
+
+/// Result of an execution without a proof. Nothing here can be verified on
+/// chain; `unproven` is always set so clients cannot mistake it for a
+/// [ProveResponse].
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SimulateResponse {
+    pub unproven: bool,
+    pub image_id: String,
+    /// Canonical form of the input, as it would be proven.
+    pub input: String,
+    pub journal: String,
+    pub public_values: String,
+    pub cycles: CycleStats,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guest_logs: Option<GuestLogs>,
+}
+
+/// Error returned to API clients as `{"error": ...}`.
+pub struct ApiError(StatusCode, anyhow::Error);
+
+impl ApiError {
+    fn bad_request(err: anyhow::Error) -> Self {
+        Self(StatusCode::BAD_REQUEST, err)
+    }
+
+    fn unauthorized(err: anyhow::Error) -> Self {
+        Self(StatusCode::UNAUTHORIZED, err)
+    }
+
+    fn forbidden(err: anyhow::Error) -> Self {
+        Self(StatusCode::FORBIDDEN, err)
+    }
+
+    fn too_many_requests(err: anyhow::Error) -> Self {
+        Self(StatusCode::TOO_MANY_REQUESTS, err)
+    }
+
+    fn unavailable(err: anyhow::Error) -> Self {
+        Self(StatusCode::SERVICE_UNAVAILABLE, err)
+    }
+
+    fn not_found(err: anyhow::Error) -> Self {
+        Self(StatusCode::NOT_FOUND, err)
+    }
+
+    fn internal(err: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, err)
+    }
+
+    /// A failed proof: unprocessable if the guest rejected the input, which
+    /// fails the same way when retried, internal otherwise.
+    fn proof_failed(err: anyhow::Error, fault: Option<Fault>) -> Self {
+        match fault {
+            Some(Fault::Guest) => Self(StatusCode::UNPROCESSABLE_ENTITY, err),
+            _ => Self::internal(err),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": redact(&format!("{:#}", self.1)) });
+        (self.0, Json(body)).into_response()
+    }
+}
+
+pub fn router(state: Arc<AppState>) -> Router {
+    let prove_routes = Router::new()
+        .route("/v1/prove", post(prove))
+        .route("/v1/simulate", post(simulate))
+        .route("/v1/sessions", post(start_session))
+        .route("/v1/sessions/:id", get(session_status))
+        .route("/v1/sessions/:id/wait", get(wait_session))
+        .route_layer(middleware::from_fn_with_state(Role::Prove, require_role));
+    let read_routes = Router::new()
+        .route("/v1/usage", get(usage))
+        .route("/v1/jobs", get(list_jobs))
+        .route("/v1/capabilities", get(capabilities))
+        .route("/v1/guests/:id/provenance", get(guest_provenance))
+        .route("/v1/receipts/by-input/:hash", get(receipts_by_input))
+        .route_layer(middleware::from_fn_with_state(Role::Read, require_role));
+    let mut operator_routes = Router::new()
+        .route("/v1/admin/pause", post(pause))
+        .route("/v1/admin/resume", post(resume));
+    if let Some(catalog) = &state.catalog {
+        operator_routes = operator_routes.merge(catalog_router(catalog.clone()));
+    }
+    if let Some(reloader) = &state.reloader {
+        operator_routes = operator_routes.merge(reload_router(reloader.clone()));
+    }
+    let operator_routes = operator_routes.route_layer(middleware::from_fn(require_operator));
+    let mut router = Router::new()
+        .merge(prove_routes)
+        .merge(read_routes)
+        .merge(operator_routes);
+    if let Some(metrics) = &state.metrics {
+        router = router.merge(admin_only(slo_router(metrics.clone())));
+    }
+    if let Some(shadow) = &state.shadow {
+        router = router.merge(admin_only(shadow_router(shadow.clone())));
+    }
+    if let Some(canary) = &state.canary {
+        router = router.merge(admin_only(canary_router(canary.clone())));
+    }
+    router
+        .route_layer(middleware::from_fn_with_state(state.clone(), authenticate))
+        .layer(middleware::from_fn(compress_response))
+        .layer(middleware::from_fn(trace_request))
+        .with_state(state)
+}
+
+/// Require the [Role::Admin] role for `routes`, which must not be empty.
+fn admin_only<S: Clone + Send + Sync + 'static>(routes: Router<S>) -> Router<S> {
+    routes.route_layer(middleware::from_fn_with_state(Role::Admin, require_role))
+}
+
+/// Require `key` as the API key of `routes`, for admin APIs served apart from
+/// the relay API and its tenants.
+pub fn key_only(routes: Router, key: &str) -> Router {
+    routes.route_layer(middleware::from_fn_with_state(
+        Arc::<str>::from(key),
+        require_key,
+    ))
+}
+
+/// Admin API of a listener's approval workflow. Approving needs no API key:
+/// requests carry an operator's signature over the callback digest.
+pub fn approval_router(approvals: Arc<Approvals>) -> Router {
+    Router::new()
+        .route("/v1/admin/approvals", get(pending_approvals))
+        .route("/v1/admin/approvals/:request_id", post(approve))
+        .with_state(approvals)
+}
+
+/// Admin route reporting the pause flag of the protocol's guardian.
+pub fn guardian_router<S>(guardian: Arc<Guardian<EthClient>>) -> Router<S> {
+    Router::new()
+        .route("/v1/admin/guardian", get(guardian_status))
+        .with_state(guardian)
+}
+
+/// Operator routes listing and changing the pool catalog, which every
+/// tenant's results are drawn from.
+pub fn catalog_router<S>(catalog: Arc<PoolCatalog>) -> Router<S> {
+    Router::new()
+        .route("/v1/admin/pools", get(list_pools))
+        .route("/v1/admin/pools/:name", put(put_pool).delete(delete_pool))
+        .route("/v1/admin/pool-proposals", get(list_proposals))
+        .route("/v1/admin/pool-proposals/:name", delete(reject_proposal))
+        .route(
+            "/v1/admin/pool-proposals/:name/accept",
+            post(accept_proposal),
+        )
+        .with_state(catalog)
+}
+
+/// Admin route reporting each guest's proof latency against its objective.
+pub fn slo_router<S>(metrics: Arc<Metrics>) -> Router<S> {
+    Router::new()
+        .route("/v1/admin/slo", get(slo_status))
+        .with_state(metrics)
+}
+
+/// Admin route reporting the shadow checks of Bonsai results so far.
+pub fn shadow_router<S>(shadow: Arc<ShadowVerifier>) -> Router<S> {
+    Router::new()
+        .route("/v1/admin/shadow", get(shadow_status))
+        .with_state(shadow)
+}
+
+/// Admin route reporting how the canary image compared with the stable one.
+pub fn canary_router<S>(canary: Arc<Canary>) -> Router<S> {
+    Router::new()
+        .route("/v1/admin/canary", get(canary_status))
+        .with_state(canary)
+}
+
+/// Public routes of the pull model, serving the latest proven update of each
+/// job for consumers to submit themselves.
+pub fn updates_router(updates: Arc<PriceUpdates>) -> Router {
+    Router::new()
+        .route("/v1/updates/:job/latest", get(latest_update))
+        .route(
+            "/v1/updates/:job/latest/calldata",
+            get(latest_update_calldata),
+        )
+        .with_state(updates)
+}
+
+/// Operator route reloading configuration files, as on SIGHUP.
+pub fn reload_router<S>(reloader: Arc<Reloader>) -> Router<S> {
+    Router::new()
+        .route("/v1/admin/reload", post(reload))
+        .with_state(reloader)
+}
+
+/// Serve the relay API until the process is stopped.
+pub async fn serve(addr: SocketAddr, state: Arc<AppState>) -> Result<()> {
+    serve_router(addr, router(state)).await
+}
+
+pub async fn serve_router(addr: SocketAddr, router: Router) -> Result<()> {
+    serve_router_until(addr, router, std::future::pending()).await
+}
+
+/// Serve `router` until `shutdown` resolves, then stop accepting connections
+/// and wait for requests in flight.
+pub async fn serve_router_until(
+    addr: SocketAddr,
+    router: Router,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    elog!("Relay API listening on {addr}");
+    axum::Server::try_bind(&addr)
+        .context(format!("Failed to bind {addr}"))?
+        .serve(router.into_make_service())
+        .with_graceful_shutdown(shutdown)
+        .await
+        .context("Relay API server failed")
+}
+
+/// Attribute the request to a tenant and role by its API key, given either as
+/// a bearer token or in the [API_KEY_HEADER], or to the [Operator] if it is
+/// the operator key.
+async fn authenticate<B>(
+    State(state): State<Arc<AppState>>,
+    mut req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let api_key = api_key(req.headers());
+    let (tenant, operator) = {
+        let tenants = state
+            .tenants
+            .read()
+            .map_err(|_| ApiError::internal(anyhow!("tenants lock poisoned")))?;
+        let operator = match &state.operator_key {
+            Some(operator_key) => api_key.map(key_digest) == Some(key_digest(operator_key)),
+            None => !tenants.requires_key(),
+        };
+        (tenants.authenticate(api_key), operator)
+    };
+    match tenant {
+        Some((tenant, role)) => {
+            req.extensions_mut().insert(tenant);
+            req.extensions_mut().insert(role);
+        }
+        None if operator => (),
+        None => {
+            return Err(ApiError::unauthorized(anyhow!(
+                "missing or unknown API key"
+            )))
+        }
+    }
+    if operator {
+        req.extensions_mut().insert(Operator);
+    }
+    Ok(next.run(req).await)
+}
+
+/// Handle the request under the client's trace ID, from the
+/// [trace::TRACE_ID_HEADER] or `traceparent` header, or a new one. The ID is
+/// returned in the [trace::TRACE_ID_HEADER] of the response.
+async fn trace_request<B>(req: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let trace_id = match header(trace::TRACE_ID_HEADER) {
+        Some(id) => trace::validate(id).map_err(ApiError::bad_request)?,
+        None => header(trace::TRACEPARENT_HEADER)
+            .and_then(trace::from_traceparent)
+            .unwrap_or_else(trace::generate),
+    };
+    let value = HeaderValue::from_str(&trace_id).map_err(|err| ApiError::internal(err.into()))?;
+    let mut response = trace::scope(trace_id, next.run(req)).await;
+    response.headers_mut().insert(trace::TRACE_ID_HEADER, value);
+    Ok(response)
+}
+
+/// zstd compress response bodies for clients sending `Accept-Encoding: zstd`,
+/// which mostly pays off for the SNARK receipts of prove responses.
+async fn compress_response<B>(req: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    let accepts_zstd = req
+        .headers()
+        .get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| coding.split(';').next().unwrap_or_default().trim() == "zstd");
+    let response = next.run(req).await;
+    if !accepts_zstd || response.headers().contains_key(CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    let (mut parts, mut body) = response.into_parts();
+    let mut data = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| ApiError::internal(anyhow!(err)))?;
+        data.extend_from_slice(&chunk);
+    }
+    let data = zstd::encode_all(data.as_slice(), 3)
+        .context("Failed to compress response")
+        .map_err(ApiError::internal)?;
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("zstd"));
+    parts
+        .headers
+        .insert(VARY, HeaderValue::from_static("accept-encoding"));
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, body::boxed(Full::from(data))))
+}
+
+/// Reject requests whose API key lacks the role required by the route.
+async fn require_role<B>(
+    State(required): State<Role>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    let role = req.extensions().get::<Role>().copied();
+    if !matches!(role, Some(role) if role.permits(required)) {
+        return Err(ApiError::forbidden(anyhow!(
+            "API key lacks the {required:?} role"
+        )));
+    }
+    Ok(next.run(req).await)
+}
+
+/// API key of a request, from the API key header or a bearer token.
+fn api_key(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(API_KEY_HEADER)
+        .or_else(|| headers.get(AUTHORIZATION))
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim_start_matches("Bearer ").trim())
+}
+
+/// Reject requests not made with the key of a [key_only] router.
+async fn require_key<B>(
+    State(key): State<Arc<str>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ApiError> {
+    if api_key(req.headers()).map(key_digest) != Some(key_digest(&key)) {
+        return Err(ApiError::unauthorized(anyhow!(
+            "missing or unknown API key"
+        )));
+    }
+    Ok(next.run(req).await)
+}
+
+/// Reject requests not made with the operator key.
+async fn require_operator<B>(req: Request<B>, next: Next<B>) -> Result<Response, ApiError> {
+    if req.extensions().get::<Operator>().is_none() {
+        return Err(ApiError::forbidden(anyhow!(
+            "route requires the operator key"
+        )));
+    }
+    Ok(next.run(req).await)
+}
+
+async fn pause(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.paused.store(true, Ordering::SeqCst);
+    elog!("Proving paused by operator");
+    StatusCode::NO_CONTENT
+}
+
+async fn resume(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.paused.store(false, Ordering::SeqCst);
+    elog!("Proving resumed by operator");
+    StatusCode::NO_CONTENT
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReloadResponse {
+    /// Configuration files that were reloaded.
+    pub reloaded: Vec<String>,
+}
+
+async fn reload(State(reloader): State<Arc<Reloader>>) -> Result<Json<ReloadResponse>, ApiError> {
+    let reloaded = reloader.reload().await.map_err(ApiError::internal)?;
+    elog!("Reloaded {} by admin", reloaded.join(", "));
+    Ok(Json(ReloadResponse { reloaded }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApproveRequest {
+    /// Hex encoded EIP-191 signature of the callback digest by an operator
+    /// key.
+    pub signature: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApproveResponse {
+    pub approvals: usize,
+}
+
+async fn pending_approvals(
+    State(approvals): State<Arc<Approvals>>,
+) -> Result<Json<Vec<PendingApproval>>, ApiError> {
+    Ok(Json(approvals.pending().map_err(ApiError::internal)?))
+}
+
+async fn approve(
+    State(approvals): State<Arc<Approvals>>,
+    Path(request_id): Path<String>,
+    Json(req): Json<ApproveRequest>,
+) -> Result<Json<ApproveResponse>, ApiError> {
+    let request_id: [u8; 32] = hex::decode(request_id.trim_start_matches("0x"))
+        .ok()
+        .and_then(|request_id| request_id.try_into().ok())
+        .ok_or_else(|| ApiError::bad_request(anyhow!("request ID must be 32 hex encoded bytes")))?;
+    let signature = Signature::from_str(&req.signature)
+        .context("Failed to parse signature")
+        .map_err(ApiError::bad_request)?;
+    let approvals = approvals
+        .approve(request_id, &signature)
+        .map_err(ApiError::forbidden)?;
+    Ok(Json(ApproveResponse { approvals }))
+}
+
+async fn guardian_status(State(guardian): State<Arc<Guardian<EthClient>>>) -> Json<GuardianStatus> {
+    Json(guardian.status())
+}
+
+async fn slo_status(State(metrics): State<Arc<Metrics>>) -> Json<Vec<SloStatus>> {
+    Json(metrics.status())
+}
+
+async fn canary_status(State(canary): State<Arc<Canary>>) -> Result<Json<CanaryReport>, ApiError> {
+    Ok(Json(canary.report().map_err(ApiError::internal)?))
+}
+
+async fn shadow_status(
+    State(shadow): State<Arc<ShadowVerifier>>,
+) -> Result<Json<ShadowStats>, ApiError> {
+    Ok(Json(shadow.stats().map_err(ApiError::internal)?))
+}
+
+async fn list_pools(State(catalog): State<Arc<PoolCatalog>>) -> Json<Vec<PoolConfig>> {
+    Json(catalog.pools().await)
+}
+
+async fn put_pool(
+    State(catalog): State<Arc<PoolCatalog>>,
+    Path(name): Path<String>,
+    Json(pool): Json<PoolConfig>,
+) -> Result<StatusCode, ApiError> {
+    if pool.name != name {
+        return Err(ApiError::bad_request(anyhow!(
+            "pool name {} does not match the path",
+            pool.name
+        )));
+    }
+    catalog.upsert(pool).await.map_err(ApiError::bad_request)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_pool(
+    State(catalog): State<Arc<PoolCatalog>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if catalog.remove(&name).await.map_err(ApiError::internal)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::not_found(anyhow!(
+            "pool {name} is not in the catalog"
+        )))
+    }
+}
+
+async fn list_proposals(State(catalog): State<Arc<PoolCatalog>>) -> Json<Vec<PoolConfig>> {
+    Json(catalog.proposals().await)
+}
+
+async fn accept_proposal(
+    State(catalog): State<Arc<PoolCatalog>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if catalog.accept(&name).await.map_err(ApiError::bad_request)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::not_found(anyhow!("pool {name} is not proposed")))
+    }
+}
+
+async fn reject_proposal(
+    State(catalog): State<Arc<PoolCatalog>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    if catalog.reject(&name).await {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::not_found(anyhow!("pool {name} is not proposed")))
+    }
+}
+
+async fn latest_update(
+    State(updates): State<Arc<PriceUpdates>>,
+    Path(job): Path<String>,
+) -> Result<Json<PriceUpdate>, ApiError> {
+    updates
+        .latest(&job)
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(anyhow!("job {job} has no update")))
+}
+
+async fn latest_update_calldata(
+    State(updates): State<Arc<PriceUpdates>>,
+    Path(job): Path<String>,
+) -> Result<Json<UpdateCalldata>, ApiError> {
+    updates
+        .latest_calldata(&job)
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(anyhow!("job {job} has no update")))
+}
+
+async fn usage(Extension(tenant): Extension<Arc<Tenant>>) -> Result<Json<TenantUsage>, ApiError> {
+    Ok(Json(tenant.usage().map_err(ApiError::internal)?))
+}
+
+/// Page through the tenant's completed jobs, most recent first.
+async fn list_jobs(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Query(params): Query<ListJobsParams>,
+) -> Result<Json<JobPage>, ApiError> {
+    let store = state
+        .store
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found(anyhow!("this relay keeps no job history")))?;
+    // Query strings are not flattened into the filter, as serde_urlencoded
+    // cannot parse numbers through `#[serde(flatten)]`.
+    let filter = JobFilter {
+        guest: params.guest,
+        requester: params.requester,
+        status: params.status,
+        since: params.since,
+        until: params.until,
+    };
+    let limit = params.limit.clamp(1, MAX_PAGE_SIZE);
+    let page = store
+        .list_jobs(tenant.id(), &filter, params.cursor.as_deref(), limit)
+        .map_err(ApiError::bad_request)?;
+    Ok(Json(page))
+}
+
+async fn capabilities(State(state): State<Arc<AppState>>) -> Json<Capabilities> {
+    Json(state.capabilities.clone())
+}
+
+/// What source and toolchains built a guest, by name or image ID, so
+/// consumers can audit the code behind its proofs.
+async fn guest_provenance(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Path(id): Path<String>,
+) -> Result<Json<GuestProvenance>, ApiError> {
+    let guest = state.registry.resolve(&id).map_err(ApiError::not_found)?;
+    if !tenant.allows_guest(&guest) {
+        return Err(ApiError::forbidden(anyhow!(
+            "guest {} is not enabled for tenant {}",
+            guest.name,
+            tenant.id()
+        )));
+    }
+    guest
+        .provenance()
+        .map_err(ApiError::internal)?
+        .map(Json)
+        .ok_or_else(|| {
+            ApiError::not_found(anyhow!(
+                "no provenance is recorded for guest {}",
+                guest.name
+            ))
+        })
+}
+
+/// Results already proven for a canonical input, by the hex SHA-256 of the
+/// input, so clients can fetch an existing proof, such as a scheduled TWAP,
+/// instead of requesting a new one. Results of guests not enabled for the
+/// tenant are left out.
+async fn receipts_by_input(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Path(hash): Path<String>,
+) -> Result<Json<Vec<IndexedReceipt>>, ApiError> {
+    let index = state
+        .index
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found(anyhow!("no receipt index is configured")))?;
+    let mut receipts = Vec::new();
+    for (entry, output) in index.by_input(&hash).map_err(ApiError::internal)? {
+        match state.registry.resolve(&entry.image_id) {
+            Ok(guest) if tenant.allows_guest(&guest) => {}
+            _ => continue,
+        }
+        receipts.push(IndexedReceipt::new(entry, &output).map_err(ApiError::internal)?);
+    }
+    if receipts.is_empty() {
+        return Err(ApiError::not_found(anyhow!("no receipt for input {hash}")));
+    }
+    Ok(Json(receipts))
+}
+
+/// Execute a guest without proving it, so integrators can check their input
+/// construction before paying for proofs.
+async fn simulate(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Json(req): Json<SimulateRequest>,
+) -> Result<Json<SimulateResponse>, ApiError> {
+    tenant.admit().map_err(ApiError::too_many_requests)?;
+    let guest = state
+        .registry
+        .resolve(&req.guest)
+        .map_err(ApiError::not_found)?;
+    if !tenant.allows_guest(&guest) {
+        return Err(ApiError::forbidden(anyhow!(
+            "guest {} is not enabled for tenant {}",
+            guest.name,
+            tenant.id()
+        )));
+    }
+    let input = prepare_input(&guest, &req.input, req.private_input.as_deref())
+        .map_err(ApiError::bad_request)?;
+    tenant
+        .record(|usage| usage.simulations += 1)
+        .map_err(ApiError::internal)?;
+
+    let ((output, cycles), guest_logs) = {
+        let guest = guest.clone();
+        let pool = state.pool.clone();
+        let input = input.clone();
+        let capture = req.guest_logs;
+        let (result, logs) = trace::spawn_blocking(move || {
+            if capture {
+                let (result, logs) = pool.execute_with_logs(&guest, &input);
+                (result, Some(logs))
+            } else {
+                (pool.execute_with_stats(&guest, &input), None)
+            }
+        })
+        .await
+        .context("Failed to run simulation sub-task")
+        .map_err(ApiError::internal)?;
+        state.pool.refill_in_background();
+        // A failing guest's prints are what explain the failure.
+        let result = match (result, &logs) {
+            (Err(err), Some(logs)) => Err(err.context(format!(
+                "Guest execution failed\nguest stdout:\n{}\nguest stderr:\n{}",
+                logs.stdout, logs.stderr
+            ))),
+            (result, _) => result,
+        };
+        (result.map_err(ApiError::bad_request)?, logs)
+    };
+    let journal = match &output {
+        Output::Execution { journal }
+        | Output::Bonsai { journal, .. }
+        | Output::Stark { journal, .. } => journal,
+    };
+    let (public_input, _) = split_input(&input).map_err(ApiError::internal)?;
+    Ok(Json(SimulateResponse {
+        unproven: true,
+        image_id: hex::encode(guest.image_id),
+        input: hex::encode(public_input),
+        journal: hex::encode(journal),
+        public_values: hex::encode(
+            public_values(&guest.name, journal).map_err(ApiError::internal)?,
+        ),
+        cycles,
+        guest_logs,
+    }))
+}
+
+async fn prove(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Json(req): Json<ProveRequest>,
+) -> Result<Json<ProveResponse>, ApiError> {
+    prove_request(&state, &tenant, req).await.map(Json)
+}
+
+/// Start proving in the background, returning a session ID to fetch or wait
+/// for the result by.
+async fn start_session(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Json(req): Json<ProveRequest>,
+) -> Result<(StatusCode, Json<SessionCreated>), ApiError> {
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::unavailable(anyhow!("proving is paused")));
+    }
+    let session_id = state
+        .sessions
+        .start(tenant.id(), {
+            let state = state.clone();
+            let tenant = tenant.clone();
+            async move {
+                prove_request(&state, &tenant, req)
+                    .await
+                    .map_err(|err| redact(&format!("{:#}", err.1)))
+            }
+        })
+        .map_err(ApiError::too_many_requests)?;
+    Ok((StatusCode::ACCEPTED, Json(SessionCreated { session_id })))
+}
+
+async fn session_status(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Path(id): Path<String>,
+) -> Result<Json<SessionStatus<ProveResponse>>, ApiError> {
+    state
+        .sessions
+        .status(tenant.id(), &id)
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(anyhow!("no session {id}")))
+}
+
+/// Block until the session finishes or the timeout elapses, for clients that
+/// neither poll nor hold a WebSocket open. A session still running when the
+/// timeout elapses is returned with the `running` status.
+async fn wait_session(
+    State(state): State<Arc<AppState>>,
+    Extension(tenant): Extension<Arc<Tenant>>,
+    Path(id): Path<String>,
+    Query(params): Query<WaitParams>,
+) -> Result<Json<SessionStatus<ProveResponse>>, ApiError> {
+    state
+        .sessions
+        .wait(tenant.id(), &id, Duration::from_secs(params.timeout))
+        .await
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found(anyhow!("no session {id}")))
+}
+
+async fn prove_request(
+    state: &Arc<AppState>,
+    tenant: &Arc<Tenant>,
+    req: ProveRequest,
+) -> Result<ProveResponse, ApiError> {
+    let received = Instant::now();
+    if state.paused.load(Ordering::SeqCst) {
+        return Err(ApiError::unavailable(anyhow!("proving is paused")));
+    }
+    tenant.admit().map_err(ApiError::too_many_requests)?;
+    let requester = tenant
+        .requester(req.requester)
+        .map_err(ApiError::forbidden)?;
+    if let Some(requesters) = &state.requesters {
+        let requester =
+            requester.ok_or_else(|| ApiError::bad_request(anyhow!("requester is required")))?;
+        requesters.admit(requester).map_err(|err| match err {
+            Rejection::QuotaExceeded(..) => ApiError::too_many_requests(err.into()),
+            Rejection::Denied(_) | Rejection::NotAllowed(_) => ApiError::forbidden(err.into()),
+        })?;
+    }
+    tenant
+        .record(|usage| usage.requests += 1)
+        .map_err(ApiError::internal)?;
+    let guest = state
+        .registry
+        .resolve(&req.guest)
+        .map_err(ApiError::not_found)?;
+    if !tenant.allows_guest(&guest) {
+        return Err(ApiError::forbidden(anyhow!(
+            "guest {} is not enabled for tenant {}",
+            guest.name,
+            tenant.id()
+        )));
+    }
+    let input = prepare_salted_input(
+        &guest,
+        &req.input,
+        req.private_input.as_deref(),
+        req.private_salt.as_deref(),
+    )
+    .map_err(ApiError::bad_request)?;
+    let private_salt = private_salt(&input)
+        .map_err(ApiError::internal)?
+        .map(hex::encode);
+    let post_process = PostProcessChain::resolve(&req.post_process, state.tokens.as_deref())
+        .await
+        .map_err(ApiError::bad_request)?;
+    post_process
+        .validate(&guest.name)
+        .map_err(ApiError::bad_request)?;
+
+    let snark = req.snark;
+    if snark {
+        let produces_snark = match &state.prover {
+            Some(prover) => prover.snark(),
+            None => true,
+        };
+        if state.dev_mode || !produces_snark {
+            return Err(ApiError::bad_request(anyhow!(
+                "no prover backend produces a SNARK"
+            )));
+        }
+    }
+
+    // Sessions are only shared within a tenant, and proofs without a SNARK
+    // are not shared with requests needing one.
+    let request_key = request_key(guest.image_id, &input);
+    let mut key = format!("{}/{request_key}", tenant.id());
+    if snark {
+        key.push_str("/snark");
+    }
+    let attempts = AttemptLog::default();
+    let work = {
+        let attempts = attempts.clone();
+        let guest = guest.clone();
+        let pool = state.pool.clone();
+        let input = input.clone();
+        let dev_mode = state.dev_mode;
+        let artifacts = state.artifacts.clone();
+        let bonsai = state.bonsai.clone();
+        let prover = state.prover.clone().filter(|_| !dev_mode);
+        let shadow = state.shadow.clone();
+        let canary = state.canary.clone().filter(|canary| canary.covers(&guest));
+        async move {
+            let run = async {
+                match (prover, canary, shadow) {
+                    (Some(prover), _, _) if snark => {
+                        prover.prove_snark(guest.clone(), input.clone()).await
+                    }
+                    (Some(prover), _, _) => prover.prove(guest.clone(), input.clone()).await,
+                    (None, Some(canary), _) => {
+                        canary.run(input.clone(), &pool, &bonsai, dev_mode).await
+                    }
+                    (None, None, Some(shadow)) => {
+                        shadow
+                            .run(&guest, input.clone(), &pool, &bonsai, dev_mode)
+                            .await
+                    }
+                    (None, None, None) => {
+                        run_guest(&guest, input.clone(), &pool, &bonsai, dev_mode).await
+                    }
+                }
+            };
+            let run = attempts.scope(run);
+            match artifacts {
+                Some(artifacts) => artifacts.capture(&guest, &input, run).await,
+                None => run.await,
+            }
+        }
+    };
+    let (output, deduplicated) = state.dedup.run(key, work).await;
+    if let Some(metrics) = &state.metrics {
+        metrics.record(&guest.name, received.elapsed(), output.is_ok());
+    }
+    let job = |status, error, fault| JobRecord {
+        id: JobRecord::new_id(),
+        key: request_key.clone(),
+        guest: guest.name.clone(),
+        image_id: hex::encode(guest.image_id),
+        requester,
+        status,
+        trace_id: trace::current(),
+        completed_at: clock::unix_now(),
+        error,
+        fault,
+        attempts: attempts.attempts(),
+    };
+    let output = match output {
+        Ok(output) => output,
+        Err(err) => {
+            let fault = Fault::of(&err);
+            let err = anyhow!("{err:#}");
+            if let (Some(store), false) = (&state.store, deduplicated) {
+                let error = redact(&format!("{err:#}"));
+                let job = job(JobStatus::Failed, Some(error), fault);
+                if let Err(err) = store.put_job(tenant.id(), &job) {
+                    elog!("Failed to record failed job {request_key}: {err:?}");
+                }
+            }
+            return Err(ApiError::proof_failed(err, fault));
+        }
+    };
+    if let (Some(queues), false) = (&state.queues, deduplicated) {
+        let queues = queues.clone();
+        let (tenant, guest, output) = (tenant.id().to_string(), guest.clone(), output.clone());
+        tokio::spawn(async move { queues.publish_tenant(&tenant, &guest, &output).await });
+    }
+    if let (Some(url), false) = (&tenant.config.webhook_url, deduplicated) {
+        let webhooks = state.webhooks.clone();
+        let (url, tenant, guest, output) = (
+            url.clone(),
+            tenant.id().to_string(),
+            guest.clone(),
+            output.clone(),
+        );
+        tokio::spawn(async move { webhooks.deliver(&url, &tenant, &guest, &output).await });
+    }
+    tenant
+        .record(|usage| match (deduplicated, output.as_ref()) {
+            (true, _) => usage.deduplicated += 1,
+            (false, Output::Execution { .. }) => usage.executions += 1,
+            (false, Output::Bonsai { .. } | Output::Stark { .. }) => usage.proofs += 1,
+        })
+        .map_err(ApiError::internal)?;
+
+    let (journal, post_state_digest, snark_proof) = match output.as_ref() {
+        Output::Execution { journal } => (journal, None, None),
+        Output::Bonsai {
+            journal,
+            receipt_metadata,
+            snark_proof,
+            ..
+        } => (
+            journal,
+            Some(hex::encode(receipt_metadata.post.digest())),
+            Some(snark_proof.clone()),
+        ),
+        Output::Stark { journal, receipt } => (
+            journal,
+            Some(hex::encode(
+                receipt
+                    .get_metadata()
+                    .map_err(ApiError::internal)?
+                    .post
+                    .digest(),
+            )),
+            None,
+        ),
+    };
+    let processed_journal = if post_process.is_empty() {
+        None
+    } else {
+        let processed = post_process
+            .apply(&guest.name, journal)
+            .map_err(ApiError::bad_request)?;
+        Some(hex::encode(processed))
+    };
+    let response = ProveResponse {
+        image_id: hex::encode(guest.image_id),
+        journal: hex::encode(journal),
+        public_values: hex::encode(
+            public_values(&guest.name, journal).map_err(ApiError::internal)?,
+        ),
+        processed_journal,
+        post_state_digest,
+        snark_proof,
+        deduplicated,
+        trace_id: trace::current(),
+        private_salt: None,
+    };
+
+    if let (Some(store), false) = (&state.store, deduplicated) {
+        let receipt = serde_json::to_vec(&response)
+            .context("Failed to serialize receipt")
+            .map_err(ApiError::internal)?;
+        let (public_input, _) = split_input(&input).map_err(ApiError::internal)?;
+        store
+            .put(BlobKind::Input, tenant.id(), &request_key, public_input)
+            .and_then(|()| store.put(BlobKind::Receipt, tenant.id(), &request_key, &receipt))
+            .and_then(|()| store.put_job(tenant.id(), &job(JobStatus::Succeeded, None, None)))
+            .map_err(ApiError::internal)?;
+    }
+    Ok(ProveResponse {
+        private_salt,
+        ..response
+    })
+}
@@ -0,0 +1,205 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::trace;
+
+/// How long sessions are kept for their clients to collect, by default.
+pub const SESSION_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// Most sessions kept for a tenant at once, by default.
+pub const MAX_SESSIONS_PER_TENANT: usize = 64;
+
+/// Longest a client may wait on a session in one request, so that
+/// connections are not held open past typical proxy timeouts.
+pub const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// State of a session started through the API.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SessionStatus<T> {
+    Running,
+    Succeeded { result: T },
+    Failed { error: String },
+}
+
+impl<T> SessionStatus<T> {
+    pub fn is_running(&self) -> bool {
+        matches!(self, Self::Running)
+    }
+}
+
+struct Session<T> {
+    tenant: String,
+    created: Instant,
+    status: watch::Receiver<SessionStatus<T>>,
+}
+
+/// Requests running in the background, whose results clients fetch or wait
+/// for by session ID. Sessions are only visible to the tenant that started
+/// them, and expire `retention` after they started. Each tenant holds at
+/// most `max_per_tenant` of them: its oldest finished session makes room for
+/// a new one, and new sessions are refused while all of them still run.
+pub struct Sessions<T> {
+    sessions: Mutex<HashMap<String, Session<T>>>,
+    max_per_tenant: usize,
+    retention: Duration,
+}
+
+impl<T> Default for Sessions<T> {
+    fn default() -> Self {
+        Self::new(MAX_SESSIONS_PER_TENANT, SESSION_RETENTION)
+    }
+}
+
+impl<T> Sessions<T> {
+    pub fn new(max_per_tenant: usize, retention: Duration) -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            max_per_tenant,
+            retention,
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Sessions<T> {
+    /// Run `work` in the background for `tenant`, returning its session ID.
+    pub fn start<F>(&self, tenant: &str, work: F) -> Result<String>
+    where
+        F: Future<Output = Result<T, String>> + Send + 'static,
+    {
+        let mut sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        sessions.retain(|_, session| now.duration_since(session.created) < self.retention);
+        let held: Vec<_> = sessions
+            .iter()
+            .filter(|(_, session)| session.tenant == tenant)
+            .collect();
+        if held.len() >= self.max_per_tenant {
+            let oldest_finished = held
+                .into_iter()
+                .filter(|(_, session)| !session.status.borrow().is_running())
+                .min_by_key(|(_, session)| session.created)
+                .map(|(id, _)| id.clone());
+            let Some(oldest_finished) = oldest_finished else {
+                bail!(
+                    "tenant {tenant} already runs {} sessions, the most allowed",
+                    self.max_per_tenant
+                );
+            };
+            sessions.remove(&oldest_finished);
+        }
+
+        let id = hex::encode(rand::random::<[u8; 16]>());
+        let (sender, status) = watch::channel(SessionStatus::Running);
+        trace::spawn(async move {
+            let _ = sender.send(match work.await {
+                Ok(result) => SessionStatus::Succeeded { result },
+                Err(error) => SessionStatus::Failed { error },
+            });
+        });
+        sessions.insert(
+            id.clone(),
+            Session {
+                tenant: tenant.to_string(),
+                created: now,
+                status,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Current status of session `id`, if `tenant` started it.
+    pub fn status(&self, tenant: &str, id: &str) -> Option<SessionStatus<T>> {
+        let status = self.receiver(tenant, id)?;
+        let status = status.borrow().clone();
+        Some(status)
+    }
+
+    /// Wait up to `timeout`, capped at [MAX_WAIT], for session `id` to
+    /// finish, returning its status then.
+    pub async fn wait(
+        &self,
+        tenant: &str,
+        id: &str,
+        timeout: Duration,
+    ) -> Option<SessionStatus<T>> {
+        let mut status = self.receiver(tenant, id)?;
+        let finished = async {
+            while status.borrow().is_running() {
+                if status.changed().await.is_err() {
+                    // The task ended without reporting, i.e. it panicked.
+                    return false;
+                }
+            }
+            true
+        };
+        if let Ok(false) = tokio::time::timeout(timeout.min(MAX_WAIT), finished).await {
+            return Some(SessionStatus::Failed {
+                error: "session was aborted".to_string(),
+            });
+        }
+        let status = status.borrow().clone();
+        Some(status)
+    }
+
+    fn receiver(&self, tenant: &str, id: &str) -> Option<watch::Receiver<SessionStatus<T>>> {
+        let sessions = self.sessions.lock().unwrap_or_else(PoisonError::into_inner);
+        sessions
+            .get(id)
+            .filter(|session| {
+                session.tenant == tenant && session.created.elapsed() < self.retention
+            })
+            .map(|session| session.status.clone())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_start_makes_room_from_finished_sessions_only() {
+        let sessions = Sessions::<u32>::new(2, SESSION_RETENTION);
+        let finished = sessions.start("a", async { Ok(1) }).unwrap();
+        sessions.wait("a", &finished, MAX_WAIT).await.unwrap();
+        let running = sessions.start("a", std::future::pending()).unwrap();
+        sessions.start("b", std::future::pending()).unwrap();
+
+        sessions.start("a", std::future::pending()).unwrap();
+        assert!(sessions.status("a", &finished).is_none());
+        assert!(sessions.status("a", &running).is_some());
+        assert!(sessions.start("a", std::future::pending()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sessions_expire_after_retention() {
+        let sessions = Sessions::<u32>::new(2, Duration::ZERO);
+        let id = sessions.start("a", std::future::pending()).unwrap();
+        assert!(sessions.status("a", &id).is_none());
+        sessions.start("a", std::future::pending()).unwrap();
+        sessions.start("a", std::future::pending()).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::Serialize;
+
+use crate::{
+    backend::BonsaiBackend, checksum::sha256_hex, clock, elog, pool::ImagePool, registry::Guest,
+    run_guest, Output,
+};
+
+/// Most divergences kept, the oldest being dropped first.
+const MAX_DIVERGENCES: usize = 100;
+
+/// A Bonsai result whose journal differs from local execution.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShadowDivergence {
+    /// Unix time of the check, in seconds.
+    pub at: u64,
+    pub guest: String,
+    pub input_digest: String,
+    pub bonsai_journal: String,
+    pub local_journal: Result<String, String>,
+}
+
+/// Shadow checks so far, as served at /v1/admin/shadow.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ShadowStats {
+    /// Number of Bonsai results that were re-executed locally.
+    pub checked: u64,
+    /// Number of those whose journals differed.
+    pub diverged: u64,
+    /// The latest divergences, oldest first.
+    pub divergences: Vec<ShadowDivergence>,
+}
+
+/// Re-executes a sampled fraction of Bonsai-proven requests locally and
+/// compares journals byte for byte.
+pub struct ShadowVerifier {
+    sample_rate: f64,
+    stats: Mutex<ShadowStats>,
+}
+
+impl ShadowVerifier {
+    /// `sample_rate` is the fraction of requests to check, between 0 and 1.
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            sample_rate: sample_rate.clamp(0.0, 1.0),
+            stats: Mutex::new(ShadowStats::default()),
+        }
+    }
+
+    fn should_sample(&self) -> bool {
+        self.sample_rate > 0.0 && rand::thread_rng().gen_bool(self.sample_rate)
+    }
+
+    /// Run the guest and, if the request is sampled and was proven on Bonsai,
+    /// execute it locally too. The Bonsai output is returned either way;
+    /// divergences are recorded and reported, not raised.
+    pub async fn run(
+        &self,
+        guest: &Arc<Guest>,
+        input: Vec<u8>,
+        pool: &Arc<ImagePool>,
+        bonsai: &BonsaiBackend,
+        dev_mode: bool,
+    ) -> Result<Output> {
+        if dev_mode || !self.should_sample() {
+            return run_guest(guest, input, pool, bonsai, dev_mode).await;
+        }
+        let output = run_guest(guest, input.clone(), pool, bonsai, dev_mode).await?;
+        if let Output::Bonsai { journal, .. } = &output {
+            self.compare(guest, input, journal, pool).await?;
+        }
+        Ok(output)
+    }
+
+    async fn compare(
+        &self,
+        guest: &Arc<Guest>,
+        input: Vec<u8>,
+        bonsai_journal: &[u8],
+        pool: &Arc<ImagePool>,
+    ) -> Result<()> {
+        let input_digest = sha256_hex(&input);
+        let local_journal = match pool.execute_blocking(guest, input).await {
+            Ok(Output::Execution { journal })
+            | Ok(Output::Bonsai { journal, .. })
+            | Ok(Output::Stark { journal, .. }) => Ok(journal),
+            Err(err) => Err(err.to_string()),
+        };
+
+        let mut stats = self
+            .stats
+            .lock()
+            .map_err(|_| anyhow!("shadow stats lock poisoned"))?;
+        stats.checked += 1;
+        if local_journal.as_deref() == Ok(bonsai_journal) {
+            return Ok(());
+        }
+
+        let divergence = ShadowDivergence {
+            at: clock::unix_now(),
+            guest: guest.name.clone(),
+            input_digest,
+            bonsai_journal: hex::encode(bonsai_journal),
+            local_journal: local_journal.map(hex::encode),
+        };
+        elog!("ALERT: Bonsai journal differs from local execution: {divergence:?}");
+        stats.diverged += 1;
+        if stats.divergences.len() == MAX_DIVERGENCES {
+            stats.divergences.remove(0);
+        }
+        stats.divergences.push(divergence);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<ShadowStats> {
+        Ok(self
+            .stats
+            .lock()
+            .map_err(|_| anyhow!("shadow stats lock poisoned"))?
+            .clone())
+    }
+}
@@ -0,0 +1,398 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Interactive shell for exploratory proving. Fetched pool state is kept for
+//! the session, so trying a guest against several blocks or amounts does
+//! not read the node again.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::{Middleware, Provider, Ws},
+    types::{Address, I256, U256},
+    utils::keccak256,
+};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::{
+    backend::{BonsaiBackend, LocalBackend, ProverBackend},
+    catalog::{MAX_SQRT_RATIO_MINUS_ONE, MIN_SQRT_RATIO_PLUS_ONE},
+    elog,
+    input::SwapInput,
+    pool::ImagePool,
+    proofs::RpcProofSource,
+    registry::{Guest, GuestRegistry},
+    schema::decode_journal,
+    snapshot::PoolSnapshot,
+    twap::TwapFetcher,
+    Output,
+};
+
+/// Tick bitmap words read on either side of the current tick by `fetch`.
+const TICK_WORDS: i16 = 2;
+
+const HELP: &str = "\
+commands:
+  guests                     list registered guests
+  use guest NAME             select the guest to run
+  set pool ADDRESS           select the pool to fetch
+  set amount AMOUNT          amount the SWAP input swaps, negative for exact output
+  set zero-for-one BOOL      direction of the SWAP input
+  set min-liquidity N        least liquidity the SWAP guest accepts
+  set from TIMESTAMP         start of the TWAP window
+  set to TIMESTAMP           end of the TWAP window
+  set input HEX              use a hand written input
+  fetch [--block N]          build the selected guest's input from the pool
+  snapshots                  list pool state fetched this session
+  save PATH [--block N]      save a fetched snapshot for `diff-snapshot`
+  show                       print the session state and input
+  execute                    execute the input locally
+  prove [--local]            prove the input on Bonsai, or on this machine
+  help                       print this help
+  exit                       leave the shell";
+
+/// A pool's state at a block, with the block's timestamp.
+struct Fetched {
+    snapshot: PoolSnapshot,
+    timestamp: u64,
+}
+
+/// Session state of `relay shell`.
+pub struct Shell {
+    registry: Arc<GuestRegistry>,
+    image_pool: Arc<ImagePool>,
+    provider: Option<Arc<Provider<Ws>>>,
+    bonsai: BonsaiBackend,
+    dev_mode: bool,
+    guest: Option<Arc<Guest>>,
+    pool: Option<Address>,
+    amount: I256,
+    zero_for_one: bool,
+    min_liquidity: u128,
+    window: (Option<u64>, Option<u64>),
+    input: Option<Vec<u8>>,
+    /// Fetched pool state, by pool and block.
+    fetched: BTreeMap<(Address, u64), Fetched>,
+}
+
+impl Shell {
+    pub fn new(registry: Arc<GuestRegistry>, image_pool: Arc<ImagePool>, dev_mode: bool) -> Self {
+        Self {
+            registry,
+            image_pool,
+            provider: None,
+            bonsai: BonsaiBackend::default(),
+            dev_mode,
+            guest: None,
+            pool: None,
+            amount: I256::exp10(18),
+            zero_for_one: true,
+            min_liquidity: 0,
+            window: (None, None),
+            input: None,
+            fetched: BTreeMap::new(),
+        }
+    }
+
+    /// Read pool state from `provider`. Without one, inputs must be set by
+    /// hand.
+    pub fn with_provider(mut self, provider: Arc<Provider<Ws>>) -> Self {
+        self.provider = Some(provider);
+        self
+    }
+
+    /// Prove on Bonsai through `bonsai` rather than the alpha API.
+    pub fn with_bonsai(mut self, bonsai: BonsaiBackend) -> Self {
+        self.bonsai = bonsai;
+        self
+    }
+
+    /// Read commands from stdin until `exit` or end of input. Failing
+    /// commands print their error and leave the session as it was. Output
+    /// goes to stderr through [elog!], masking secrets like the relay's logs.
+    pub async fn run(mut self) -> Result<()> {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        let mut stderr = tokio::io::stderr();
+        elog!("{HELP}");
+        loop {
+            stderr.write_all(b"relay> ").await?;
+            stderr.flush().await?;
+            let Some(line) = lines.next_line().await? else {
+                return Ok(());
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                [] => continue,
+                ["exit" | "quit"] => return Ok(()),
+                words => {
+                    if let Err(err) = self.command(words).await {
+                        elog!("error: {err:#}");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn command(&mut self, words: &[&str]) -> Result<()> {
+        match words {
+            ["help"] => elog!("{HELP}"),
+            ["guests"] => {
+                for guest in self.registry.iter() {
+                    elog!("{} {}", guest.name, hex::encode(guest.image_id));
+                }
+            }
+            ["use", "guest", name] => {
+                let guest = self.registry.resolve(&name.to_uppercase())?;
+                elog!("using guest {}", guest.name);
+                self.guest = Some(guest);
+                self.input = None;
+            }
+            ["set", name, value] => self.set(name, value)?,
+            ["fetch"] => self.fetch(None).await?,
+            ["fetch", "--block", block] => {
+                self.fetch(Some(block.parse().context("Invalid block number")?))
+                    .await?
+            }
+            ["snapshots"] => {
+                for ((pool, block), fetched) in &self.fetched {
+                    elog!(
+                        "{pool:?} at block {block} ({}): tick {}, liquidity {}, {} ticks, {} observations",
+                        fetched.timestamp,
+                        fetched.snapshot.slot0.tick,
+                        fetched.snapshot.liquidity,
+                        fetched.snapshot.ticks.len(),
+                        fetched.snapshot.observations.len()
+                    );
+                }
+            }
+            ["save", path] => self.latest()?.snapshot.save(Path::new(path))?,
+            ["save", path, "--block", block] => {
+                let pool = self.pool()?;
+                let block: u64 = block.parse().context("Invalid block number")?;
+                self.fetched
+                    .get(&(pool, block))
+                    .ok_or_else(|| anyhow!("block {block} of {pool:?} was not fetched"))?
+                    .snapshot
+                    .save(Path::new(path))?
+            }
+            ["show"] => self.show(),
+            ["execute"] => {
+                let (guest, input) = self.selected()?;
+                let (result, logs) = self.image_pool.execute_with_logs(&guest, &input);
+                for line in logs.stdout.lines() {
+                    elog!("guest stdout: {line}");
+                }
+                for line in logs.stderr.lines() {
+                    elog!("guest stderr: {line}");
+                }
+                let (output, stats) = result?;
+                elog!("{stats}");
+                print_journal(&guest, &output)?;
+            }
+            ["prove"] | ["prove", "--local"] => {
+                if self.dev_mode {
+                    bail!("no proofs are generated in dev mode; use `execute`");
+                }
+                let (guest, input) = self.selected()?;
+                let backend: Box<dyn ProverBackend> = if words.len() == 2 {
+                    Box::new(LocalBackend::new(self.image_pool.clone()))
+                } else {
+                    Box::new(self.bonsai.clone())
+                };
+                let output = backend.prove(guest.clone(), input).await?;
+                if let Output::Bonsai { session_id, .. } = &output {
+                    elog!("proven in Bonsai session {session_id}");
+                }
+                print_journal(&guest, &output)?;
+            }
+            _ => bail!("unknown command {:?}; try `help`", words.join(" ")),
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "pool" => self.pool = Some(value.parse().context("Invalid pool address")?),
+            "amount" => self.amount = I256::from_dec_str(value).map_err(|err| anyhow!("{err}"))?,
+            "zero-for-one" => {
+                self.zero_for_one = value.parse().context("Expected true or false")?
+            }
+            "min-liquidity" => self.min_liquidity = value.parse().context("Invalid liquidity")?,
+            "from" => self.window.0 = Some(value.parse().context("Invalid timestamp")?),
+            "to" => self.window.1 = Some(value.parse().context("Invalid timestamp")?),
+            "input" => {
+                self.input =
+                    Some(hex::decode(value.trim_start_matches("0x")).context("Invalid hex")?)
+            }
+            _ => bail!("unknown setting {name:?}; try `help`"),
+        }
+        Ok(())
+    }
+
+    /// Build the selected guest's input from the selected pool.
+    async fn fetch(&mut self, block: Option<u64>) -> Result<()> {
+        let guest = self.guest()?;
+        let pool = self.pool()?;
+        let provider = self
+            .provider
+            .clone()
+            .ok_or_else(|| anyhow!("no node to fetch from; start the shell with --eth-node"))?;
+        match guest.name.as_str() {
+            "SWAP" => {
+                let block = match block {
+                    Some(block) => block,
+                    None => provider.get_block_number().await?.as_u64(),
+                };
+                match self.fetched.entry((pool, block)) {
+                    Entry::Occupied(_) => elog!("using block {block} fetched earlier"),
+                    Entry::Vacant(entry) => {
+                        let snapshot =
+                            PoolSnapshot::fetch(provider.clone(), pool, block, TICK_WORDS).await?;
+                        let timestamp = provider
+                            .get_block(block)
+                            .await
+                            .context(format!("Failed to get block {block}"))?
+                            .ok_or_else(|| anyhow!("block {block} not found"))?
+                            .timestamp
+                            .as_u64();
+                        entry.insert(Fetched {
+                            snapshot,
+                            timestamp,
+                        });
+                    }
+                }
+                let input = self.swap_input(pool, block)?;
+                self.input = Some(input.encode()?);
+                elog!(
+                    "SWAP input at block {block}: price {}, liquidity {}",
+                    input.sqrt_price_x96,
+                    input.liquidity
+                );
+            }
+            "TWAP" => {
+                if block.is_some() {
+                    bail!("TWAP inputs are fetched for a window; set from and to instead");
+                }
+                let (Some(from), Some(to)) = self.window else {
+                    bail!("set from and to before fetching a TWAP input");
+                };
+                let fetcher =
+                    TwapFetcher::new(provider.clone(), Arc::new(RpcProofSource(provider)));
+                let input = fetcher.fetch(pool, from, to).await?;
+                self.input = Some(input.encode()?);
+                elog!(
+                    "TWAP input proving observations at {} blocks",
+                    input.anchors.len()
+                );
+            }
+            name => bail!("cannot fetch inputs of guest {name}; use `set input`"),
+        }
+        Ok(())
+    }
+
+    fn swap_input(&self, pool: Address, block: u64) -> Result<SwapInput> {
+        let fetched = &self.fetched[&(pool, block)];
+        let sqrt_price_target_x96 = if self.zero_for_one {
+            U256::from(MIN_SQRT_RATIO_PLUS_ONE)
+        } else {
+            U256::from_dec_str(MAX_SQRT_RATIO_MINUS_ONE)?
+        };
+        Ok(SwapInput {
+            request_root: keccak256(abi::encode(&[
+                Token::Address(pool),
+                Token::Uint(block.into()),
+            ])),
+            sqrt_price_x96: fetched.snapshot.slot0.sqrt_price_x96,
+            sqrt_price_target_x96,
+            liquidity: fetched.snapshot.liquidity,
+            amount_specified: self.amount,
+            fee_pips: fetched.snapshot.fee,
+            observed_at: fetched.timestamp,
+            min_liquidity: self.min_liquidity,
+            // Shell inputs are for trying out prices by hand, so the guest
+            // commits them as unproven state.
+            state: None,
+        })
+    }
+
+    fn show(&self) {
+        let guest = self.guest.as_ref().map(|guest| guest.name.as_str());
+        elog!("guest: {}", guest.unwrap_or("none"));
+        match self.pool {
+            Some(pool) => elog!("pool: {pool:?}"),
+            None => elog!("pool: none"),
+        }
+        elog!(
+            "amount: {}, zero-for-one: {}",
+            self.amount,
+            self.zero_for_one
+        );
+        elog!("min-liquidity: {}", self.min_liquidity);
+        elog!("window: {:?} to {:?}", self.window.0, self.window.1);
+        match &self.input {
+            Some(input) => elog!("input: 0x{}", hex::encode(input)),
+            None => elog!("input: none"),
+        }
+    }
+
+    fn guest(&self) -> Result<Arc<Guest>> {
+        self.guest
+            .clone()
+            .ok_or_else(|| anyhow!("no guest selected; try `use guest NAME`"))
+    }
+
+    fn pool(&self) -> Result<Address> {
+        self.pool
+            .ok_or_else(|| anyhow!("no pool selected; try `set pool ADDRESS`"))
+    }
+
+    fn selected(&self) -> Result<(Arc<Guest>, Vec<u8>)> {
+        let input = self
+            .input
+            .clone()
+            .ok_or_else(|| anyhow!("no input; try `fetch` or `set input`"))?;
+        Ok((self.guest()?, input))
+    }
+
+    /// The selected pool's most recently fetched block.
+    fn latest(&self) -> Result<&Fetched> {
+        let pool = self.pool()?;
+        self.fetched
+            .range((pool, 0)..=(pool, u64::MAX))
+            .next_back()
+            .map(|(_, fetched)| fetched)
+            .ok_or_else(|| anyhow!("nothing fetched for {pool:?}"))
+    }
+}
+
+fn print_journal(guest: &Guest, output: &Output) -> Result<()> {
+    let journal = match output {
+        Output::Execution { journal }
+        | Output::Bonsai { journal, .. }
+        | Output::Stark { journal, .. } => journal,
+    };
+    elog!("journal: 0x{}", hex::encode(journal));
+    if let Some(tokens) = decode_journal(&guest.name, journal)? {
+        for (i, token) in tokens.iter().enumerate() {
+            elog!("  {i}: {token}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,299 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockId, U256},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::bindings::UniswapV3Pool;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Slot0 {
+    pub sqrt_price_x96: U256,
+    pub tick: i32,
+    pub observation_index: u16,
+    pub observation_cardinality: u16,
+    pub observation_cardinality_next: u16,
+}
+
+/// State of an initialized tick.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TickInfo {
+    pub liquidity_gross: u128,
+    pub liquidity_net: i128,
+    pub fee_growth_outside_0_x128: U256,
+    pub fee_growth_outside_1_x128: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Observation {
+    pub timestamp: u32,
+    pub tick_cumulative: i64,
+    pub initialized: bool,
+}
+
+/// State of a pool at a block, as read from its public getters. Snapshots
+/// are stored bincode serialized.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PoolSnapshot {
+    pub pool: Address,
+    pub block_number: u64,
+    pub slot0: Slot0,
+    pub liquidity: u128,
+    pub fee: u32,
+    pub tick_spacing: u32,
+    pub fee_growth_global_0_x128: U256,
+    pub fee_growth_global_1_x128: U256,
+    /// Initialized ticks within the fetched tick bitmap words.
+    pub ticks: BTreeMap<i32, TickInfo>,
+    /// The pool's observations, up to its cardinality.
+    pub observations: Vec<Observation>,
+}
+
+impl PoolSnapshot {
+    /// Read the state of `pool` at `block`, including the initialized ticks
+    /// of the `tick_words` tick bitmap words on either side of the current
+    /// tick.
+    pub async fn fetch<M: Middleware + 'static>(
+        client: Arc<M>,
+        pool: Address,
+        block: u64,
+        tick_words: i16,
+    ) -> Result<Self> {
+        let contract = UniswapV3Pool::new(pool, client);
+        let at = BlockId::from(block);
+        let context =
+            |what: &str| format!("Failed to read {what} of pool {pool:?} at block {block}");
+
+        let (sqrt_price_x96, tick, observation_index, observation_cardinality, next) = contract
+            .slot_0()
+            .block(at)
+            .call()
+            .await
+            .context(context("slot0"))?;
+        let tick_spacing = contract
+            .tick_spacing()
+            .block(at)
+            .call()
+            .await
+            .context(context("tick spacing"))?;
+        let mut snapshot = Self {
+            pool,
+            block_number: block,
+            slot0: Slot0 {
+                sqrt_price_x96,
+                tick,
+                observation_index,
+                observation_cardinality,
+                observation_cardinality_next: next,
+            },
+            liquidity: contract
+                .liquidity()
+                .block(at)
+                .call()
+                .await
+                .context(context("liquidity"))?,
+            fee: contract
+                .fee()
+                .block(at)
+                .call()
+                .await
+                .context(context("fee"))?,
+            tick_spacing,
+            fee_growth_global_0_x128: contract
+                .fee_growth_global_0x128()
+                .block(at)
+                .call()
+                .await
+                .context(context("fee growth"))?,
+            fee_growth_global_1_x128: contract
+                .fee_growth_global_1x128()
+                .block(at)
+                .call()
+                .await
+                .context(context("fee growth"))?,
+            ticks: BTreeMap::new(),
+            observations: Vec::new(),
+        };
+
+        // Ticks are tracked in the bitmap divided by the tick spacing, 256 to
+        // a word; see TickBitmap.position.
+        let spacing = tick_spacing as i32;
+        let word = (tick.div_euclid(spacing) >> 8) as i16;
+        for word in word.saturating_sub(tick_words)..=word.saturating_add(tick_words) {
+            let bitmap = contract
+                .tick_bitmap(word)
+                .block(at)
+                .call()
+                .await
+                .context(context("tick bitmap"))?;
+            for bit in (0..256).filter(|bit| bitmap.bit(*bit)) {
+                let tick = ((word as i32) * 256 + bit as i32) * spacing;
+                let (_, liquidity_gross, liquidity_net, outside_0, outside_1) = contract
+                    .ticks(tick)
+                    .block(at)
+                    .call()
+                    .await
+                    .context(context("tick"))?;
+                snapshot.ticks.insert(
+                    tick,
+                    TickInfo {
+                        liquidity_gross,
+                        liquidity_net,
+                        fee_growth_outside_0_x128: outside_0,
+                        fee_growth_outside_1_x128: outside_1,
+                    },
+                );
+            }
+        }
+
+        for index in 0..observation_cardinality {
+            let (timestamp, tick_cumulative, initialized) = contract
+                .observations(index.into())
+                .block(at)
+                .call()
+                .await
+                .context(context("observation"))?;
+            snapshot.observations.push(Observation {
+                timestamp,
+                tick_cumulative,
+                initialized,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let buf = std::fs::read(path).context(format!("Failed to read {}", path.display()))?;
+        bincode::deserialize(&buf).context(format!(
+            "Failed to deserialize pool snapshot {}",
+            path.display()
+        ))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let buf = bincode::serialize(self).context("Failed to serialize pool snapshot")?;
+        std::fs::write(path, buf).context(format!("Failed to write {}", path.display()))
+    }
+}
+
+/// A field whose value differs between two snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difference {
+    pub field: String,
+    pub a: String,
+    pub b: String,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {} != {}", self.field, self.a, self.b)
+    }
+}
+
+fn compare<T: fmt::Debug + PartialEq>(
+    diffs: &mut Vec<Difference>,
+    field: impl Into<String>,
+    a: &T,
+    b: &T,
+) {
+    if a != b {
+        diffs.push(Difference {
+            field: field.into(),
+            a: format!("{a:?}"),
+            b: format!("{b:?}"),
+        });
+    }
+}
+
+/// Compare two snapshots field by field: slot0, the scalar pool state, every
+/// tick present in either snapshot and every observation slot.
+pub fn diff(a: &PoolSnapshot, b: &PoolSnapshot) -> Vec<Difference> {
+    let mut diffs = Vec::new();
+    compare(&mut diffs, "pool", &a.pool, &b.pool);
+    compare(&mut diffs, "block_number", &a.block_number, &b.block_number);
+    compare(
+        &mut diffs,
+        "slot0.sqrt_price_x96",
+        &a.slot0.sqrt_price_x96,
+        &b.slot0.sqrt_price_x96,
+    );
+    compare(&mut diffs, "slot0.tick", &a.slot0.tick, &b.slot0.tick);
+    compare(
+        &mut diffs,
+        "slot0.observation_index",
+        &a.slot0.observation_index,
+        &b.slot0.observation_index,
+    );
+    compare(
+        &mut diffs,
+        "slot0.observation_cardinality",
+        &a.slot0.observation_cardinality,
+        &b.slot0.observation_cardinality,
+    );
+    compare(
+        &mut diffs,
+        "slot0.observation_cardinality_next",
+        &a.slot0.observation_cardinality_next,
+        &b.slot0.observation_cardinality_next,
+    );
+    compare(&mut diffs, "liquidity", &a.liquidity, &b.liquidity);
+    compare(&mut diffs, "fee", &a.fee, &b.fee);
+    compare(&mut diffs, "tick_spacing", &a.tick_spacing, &b.tick_spacing);
+    compare(
+        &mut diffs,
+        "fee_growth_global_0_x128",
+        &a.fee_growth_global_0_x128,
+        &b.fee_growth_global_0_x128,
+    );
+    compare(
+        &mut diffs,
+        "fee_growth_global_1_x128",
+        &a.fee_growth_global_1_x128,
+        &b.fee_growth_global_1_x128,
+    );
+
+    let ticks = a
+        .ticks
+        .keys()
+        .chain(b.ticks.keys())
+        .collect::<BTreeSet<_>>();
+    for tick in ticks {
+        compare(
+            &mut diffs,
+            format!("ticks[{tick}]"),
+            &a.ticks.get(tick),
+            &b.ticks.get(tick),
+        );
+    }
+    for index in 0..a.observations.len().max(b.observations.len()) {
+        compare(
+            &mut diffs,
+            format!("observations[{index}]"),
+            &a.observations.get(index),
+            &b.observations.get(index),
+        );
+    }
+    diffs
+}
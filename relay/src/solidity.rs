@@ -0,0 +1,250 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Solidity libraries decoding guest journals, generated from their schemas.
+
+use std::fmt::Write;
+
+use anyhow::{anyhow, bail, Result};
+use ethers::abi::ParamType;
+
+use crate::schema::{journal_schema_at, journal_version, JOURNAL_VERSION_MAGIC};
+
+/// Names of the values of a guest's journal, in the order of its schema.
+/// Values holding tuples name their struct and its members as
+/// `name:Struct(member,..)`.
+pub(crate) fn field_names(guest_name: &str) -> Option<&'static [&'static str]> {
+    Some(match guest_name.to_uppercase().as_str() {
+        "SWAP" => &[
+            "requestRoot",
+            "sqrtPriceX96",
+            "amountIn",
+            "amountOut",
+            "feeAmount",
+            "observedFrom",
+            "observedTo",
+            "liquidity",
+            "minLiquidity",
+            "pool",
+            "blockHash",
+            "blockNumber",
+            "unfinalized",
+        ],
+        "BATCH" => &[
+            "requestRoot",
+            "observedFrom",
+            "observedTo",
+            "prices:PoolPrice(pool,status,sqrtPriceX96,liquidity,minLiquidity)",
+            "unfinalized",
+        ],
+        "TWAP" => &[
+            "pool",
+            "sqrtPriceX96",
+            "meanTick",
+            "observedFrom",
+            "observedTo",
+            "blockHashes",
+            "checks",
+        ],
+        "HISTORY" => &[
+            "pool",
+            "previousRoot",
+            "root",
+            "count",
+            "peaks",
+            "observedFrom",
+            "observedTo",
+            "blockHash",
+            "checks",
+            "blockNumber",
+        ],
+        "RESERVE" => &[
+            "vault",
+            "blockHash",
+            "observedFrom",
+            "observedTo",
+            "factory",
+            "initCodeHash",
+            "tokens",
+            "reserves",
+            "positions",
+        ],
+        "CYCLE" => &[
+            "start",
+            "blockHash",
+            "observedFrom",
+            "observedTo",
+            "factory",
+            "initCodeHash",
+            "pools",
+            "fees",
+            "factorX96",
+        ],
+        _ => return None,
+    })
+}
+
+/// Solidity type of a journal value, naming tuples `tuple`.
+fn solidity_type(param: &ParamType, tuple: &str) -> Result<String> {
+    Ok(match param {
+        ParamType::Address => "address".to_string(),
+        ParamType::Bool => "bool".to_string(),
+        ParamType::Bytes => "bytes".to_string(),
+        ParamType::String => "string".to_string(),
+        ParamType::FixedBytes(size) => format!("bytes{size}"),
+        ParamType::Uint(bits) => format!("uint{bits}"),
+        ParamType::Int(bits) => format!("int{bits}"),
+        ParamType::Array(inner) => format!("{}[]", solidity_type(inner, tuple)?),
+        ParamType::FixedArray(inner, len) => format!("{}[{len}]", solidity_type(inner, tuple)?),
+        ParamType::Tuple(_) if !tuple.is_empty() => tuple.to_string(),
+        ParamType::Tuple(_) => bail!("journal tuple has no struct name"),
+    })
+}
+
+/// Components of the tuple a journal value holds, if any.
+fn tuple_components(param: &ParamType) -> Option<&[ParamType]> {
+    match param {
+        ParamType::Tuple(components) => Some(components),
+        ParamType::Array(inner) | ParamType::FixedArray(inner, _) => tuple_components(inner),
+        _ => None,
+    }
+}
+
+/// Generate a Solidity library decoding the journals of a guest into a
+/// struct of their values. It accepts every journal version the relay reads,
+/// so contracts built against it take results of the previous image as well
+/// while an upgrade rolls out.
+pub fn journal_library(guest_name: &str) -> Result<String> {
+    let guest = guest_name.to_uppercase();
+    let current =
+        journal_version(&guest).ok_or_else(|| anyhow!("{guest} has no journal schema"))?;
+    let names = field_names(&guest).ok_or_else(|| anyhow!("{guest} has no journal field names"))?;
+    let schema = journal_schema_at(&guest, current)
+        .ok_or_else(|| anyhow!("{guest} has no schema for journal version {current}"))?;
+    if schema.len() != names.len() {
+        bail!(
+            "{guest} journal has {} values but {} names",
+            schema.len(),
+            names.len()
+        );
+    }
+    let versions: Vec<u16> = (1..=current)
+        .filter(|version| journal_schema_at(&guest, *version).is_some())
+        .collect();
+    for version in &versions {
+        if journal_schema_at(&guest, *version).as_ref() != Some(&schema) {
+            bail!(
+                "version {version} of the {guest} journal has another layout than version \
+                 {current}, decode it by hand"
+            );
+        }
+    }
+    let min_version = versions.first().copied().unwrap_or(current);
+    let library = format!("{}{}Journal", &guest[..1], guest[1..].to_lowercase());
+
+    let mut structs = String::new();
+    let mut fields = String::new();
+    let mut types = Vec::new();
+    let mut targets = Vec::new();
+    for (param, name) in schema.iter().zip(names) {
+        let (name, tuple) = match name.split_once(':') {
+            Some((name, tuple)) => {
+                let (tuple, members) = tuple
+                    .strip_suffix(')')
+                    .and_then(|tuple| tuple.split_once('('))
+                    .ok_or_else(|| anyhow!("malformed {guest} journal field {name}"))?;
+                let components = tuple_components(param)
+                    .ok_or_else(|| anyhow!("{guest} journal field {name} holds no tuple"))?;
+                let members: Vec<&str> = members.split(',').collect();
+                if members.len() != components.len() {
+                    bail!(
+                        "{guest} journal field {name} names {} members",
+                        members.len()
+                    );
+                }
+                writeln!(structs, "    struct {tuple} {{")?;
+                for (component, member) in components.iter().zip(members) {
+                    writeln!(
+                        structs,
+                        "        {} {member};",
+                        solidity_type(component, "")?
+                    )?;
+                }
+                writeln!(structs, "    }}\n")?;
+                (name, tuple)
+            }
+            None => (*name, ""),
+        };
+        let ty = solidity_type(param, tuple)?;
+        writeln!(fields, "        {ty} {name};")?;
+        targets.push(format!("values.{name}"));
+        types.push(ty);
+    }
+
+    let magic = hex::encode(JOURNAL_VERSION_MAGIC);
+    let mut out = String::new();
+    writeln!(out, "// SPDX-License-Identifier: BUSL-1.1")?;
+    writeln!(
+        out,
+        "// Generated by `relay journal-sol {guest}`. Do not edit."
+    )?;
+    writeln!(out, "pragma solidity ^0.8.14;\n")?;
+    writeln!(
+        out,
+        "/// @notice Decodes journals of the {guest} guest, versions {min_version} to {current}."
+    )?;
+    writeln!(
+        out,
+        "/// @dev Versioned journals end with a trailer word laid out as 0x{magic} || 26 zero\n\
+         /// bytes || uint16 version, after the ABI encoded values. Journals without it are\n\
+         /// version 1."
+    )?;
+    writeln!(out, "library {library} {{")?;
+    writeln!(
+        out,
+        "    error UnsupportedJournalVersion(uint16 version);\n"
+    )?;
+    writeln!(
+        out,
+        "    uint16 internal constant MIN_VERSION = {min_version};"
+    )?;
+    writeln!(out, "    uint16 internal constant VERSION = {current};\n")?;
+    out.push_str(&structs);
+    writeln!(out, "    struct Values {{\n{fields}    }}\n")?;
+    writeln!(
+        out,
+        "    /// @notice Version of a journal, 1 for journals without a version trailer.\n    \
+         function version(bytes calldata journal) internal pure returns (uint16) {{\n        \
+         if (journal.length < 32 || journal.length % 32 != 0) return 1;\n        \
+         bytes32 trailer = bytes32(journal[journal.length - 32:]);\n        \
+         if (trailer >> 16 != bytes32(bytes4(0x{magic})) >> 16) return 1;\n        \
+         return uint16(uint256(trailer));\n    \
+         }}\n"
+    )?;
+    writeln!(
+        out,
+        "    /// @notice Decode the values of a journal of any supported version.\n    \
+         function decode(bytes calldata journal) internal pure returns (Values memory values) {{\n        \
+         uint16 journalVersion = version(journal);\n        \
+         if (journalVersion < MIN_VERSION || journalVersion > VERSION) {{\n            \
+         revert UnsupportedJournalVersion(journalVersion);\n        \
+         }}\n        \
+         ({}) =\n            abi.decode(journal, ({}));\n    \
+         }}",
+        targets.join(", "),
+        types.join(", ")
+    )?;
+    writeln!(out, "}}")?;
+    Ok(out)
+}
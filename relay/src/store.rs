@@ -0,0 +1,499 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, OsRng, Payload},
+    AeadCore, Aes256Gcm, Key, Nonce,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::types::Address;
+use rusoto_core::Region;
+use rusoto_kms::{DecryptRequest, Kms, KmsClient};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use crate::{error::Fault, redact::redact_json, retry::SessionAttempt};
+
+/// Prefix marking an encrypted blob, followed by the nonce and ciphertext.
+const ENCRYPTED_MAGIC: &[u8; 4] = b"RZE1";
+const NONCE_LEN: usize = 12;
+
+/// Prefix marking a zstd compressed blob, followed by the zstd frame.
+/// Compression is applied before encryption.
+const COMPRESSED_MAGIC: &[u8; 4] = b"RZZ1";
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// AES-256-GCM encryption of stored blobs.
+pub struct Cipher(Aes256Gcm);
+
+impl Cipher {
+    /// Use a hex encoded 256-bit key.
+    pub fn from_hex(key: &str) -> Result<Self> {
+        let key =
+            hex::decode(key.trim_start_matches("0x")).context("Failed to decode store key")?;
+        Self::from_bytes(&key)
+    }
+
+    /// Use a data key encrypted under an AWS KMS key, decrypting it with the
+    /// credentials and region from the environment.
+    pub async fn from_kms(ciphertext: &[u8]) -> Result<Self> {
+        let client = KmsClient::new(Region::default());
+        let response = client
+            .decrypt(DecryptRequest {
+                ciphertext_blob: ciphertext.to_vec().into(),
+                ..Default::default()
+            })
+            .await
+            .context("Failed to decrypt store key with KMS")?;
+        let key = response
+            .plaintext
+            .ok_or_else(|| anyhow!("KMS returned no plaintext store key"))?;
+        Self::from_bytes(&key)
+    }
+
+    fn from_bytes(key: &[u8]) -> Result<Self> {
+        if key.len() != 32 {
+            bail!("store key must be 32 bytes, got {}", key.len());
+        }
+        Ok(Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))))
+    }
+
+    /// Encrypt `data`, binding it to `aad` so it cannot be moved to another
+    /// slot of the store.
+    fn seal(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| anyhow!("Failed to encrypt blob"))?;
+        Ok([ENCRYPTED_MAGIC.as_slice(), nonce.as_slice(), &ciphertext].concat())
+    }
+
+    fn open(&self, blob: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let blob = &blob[ENCRYPTED_MAGIC.len()..];
+        if blob.len() < NONCE_LEN {
+            bail!("encrypted blob is truncated");
+        }
+        let (nonce, ciphertext) = blob.split_at(NONCE_LEN);
+        self.0
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt blob: wrong key or corrupted data"))
+    }
+}
+
+/// Kind of data held by the [Store].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobKind {
+    /// Canonical guest inputs.
+    Input,
+    /// Journals and proofs returned for requests.
+    Receipt,
+    /// [JobRecord]s, listed by [Store::list_jobs].
+    Job,
+}
+
+impl BlobKind {
+    fn dir(self) -> &'static str {
+        match self {
+            BlobKind::Input => "inputs",
+            BlobKind::Receipt => "receipts",
+            BlobKind::Job => "jobs",
+        }
+    }
+}
+
+/// Directory-backed store of request inputs and receipts, laid out as
+/// `<kind>/<tenant>/<key>`. With a [Cipher], blobs are encrypted on write;
+/// reads handle encrypted and plaintext blobs alike, so encryption can be
+/// turned on for an existing store. The same goes for receipt compression.
+pub struct Store {
+    dir: PathBuf,
+    cipher: Option<Cipher>,
+    compress_receipts: bool,
+    /// Serializes appends to, and rebuilds of, job indexes.
+    job_index: Mutex<()>,
+}
+
+impl Store {
+    pub fn open(dir: &Path, cipher: Option<Cipher>) -> Result<Self> {
+        std::fs::create_dir_all(dir).context(format!(
+            "Failed to create store directory {}",
+            dir.display()
+        ))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            cipher,
+            compress_receipts: false,
+            job_index: Mutex::new(()),
+        })
+    }
+
+    /// Compress receipts with zstd on write.
+    pub fn with_receipt_compression(mut self) -> Self {
+        self.compress_receipts = true;
+        self
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    fn path(&self, kind: BlobKind, tenant: &str, key: &str) -> Result<PathBuf> {
+        for part in [tenant, key] {
+            if part.is_empty() || part.starts_with('.') || part.contains(['/', '\\']) {
+                bail!("invalid store path component {part:?}");
+            }
+        }
+        Ok(self.dir.join(kind.dir()).join(tenant).join(key))
+    }
+
+    fn aad(kind: BlobKind, tenant: &str, key: &str) -> Vec<u8> {
+        format!("{}/{tenant}/{key}", kind.dir()).into_bytes()
+    }
+
+    /// Atomically write a blob, replacing any previous one.
+    pub fn put(&self, kind: BlobKind, tenant: &str, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(kind, tenant, key)?;
+        let parent = path.parent().unwrap_or(&self.dir);
+        std::fs::create_dir_all(parent)
+            .context(format!("Failed to create {}", parent.display()))?;
+        let compressed;
+        let data = if self.compress_receipts && kind == BlobKind::Receipt {
+            compressed = compress(data)?;
+            compressed.as_slice()
+        } else {
+            data
+        };
+        let blob = match &self.cipher {
+            Some(cipher) => cipher.seal(data, &Self::aad(kind, tenant, key))?,
+            None => data.to_vec(),
+        };
+        let mut file = NamedTempFile::new_in(parent).context("Failed to create temp file")?;
+        file.write_all(&blob)
+            .context(format!("Failed to write {}", path.display()))?;
+        file.persist(&path)
+            .context(format!("Failed to persist {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn get(&self, kind: BlobKind, tenant: &str, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(kind, tenant, key)?;
+        let blob = match std::fs::read(&path) {
+            Ok(blob) => blob,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context(format!("Failed to read {}", path.display())),
+        };
+        let blob = if blob.starts_with(ENCRYPTED_MAGIC) {
+            let cipher = self.cipher.as_ref().ok_or_else(|| {
+                anyhow!("{} is encrypted but no store key is set", path.display())
+            })?;
+            cipher
+                .open(&blob, &Self::aad(kind, tenant, key))
+                .context(format!("Failed to read {}", path.display()))?
+        } else {
+            blob
+        };
+        match blob.strip_prefix(COMPRESSED_MAGIC.as_slice()) {
+            Some(frame) => zstd::decode_all(frame)
+                .context(format!("Failed to decompress {}", path.display()))
+                .map(Some),
+            None => Ok(Some(blob)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Succeeded,
+    Failed,
+}
+
+/// Summary of a completed request, kept for listing proof history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    /// Unique ID of the job, under which its record is stored, see
+    /// [JobRecord::new_id]. Records written before jobs had IDs are stored
+    /// under their `key`, which they take as their ID.
+    #[serde(default)]
+    pub id: String,
+    /// Key of the request, under which its receipt is stored. Identical
+    /// requests share a key.
+    pub key: String,
+    pub guest: String,
+    pub image_id: String,
+    pub requester: Option<Address>,
+    pub status: JobStatus,
+    /// Trace ID of the request that ran the job.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
+    /// Unix time at which the request completed, in seconds.
+    pub completed_at: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether a failed job was a fault of the guest or of the prover, if
+    /// known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fault: Option<Fault>,
+    /// Bonsai sessions that failed for transient reasons and were replaced
+    /// by new ones, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attempts: Vec<SessionAttempt>,
+}
+
+impl JobRecord {
+    /// A new random job ID.
+    pub fn new_id() -> String {
+        hex::encode(rand::random::<[u8; 16]>())
+    }
+
+    /// Position of the record in listings, which run from the most recent.
+    fn cursor(&self) -> String {
+        format!("{}-{}", self.completed_at, self.id)
+    }
+}
+
+/// Name of a tenant's job index among its job records. Job IDs are hex, so
+/// no record has it.
+const JOB_INDEX: &str = "_index";
+
+/// Which jobs [Store::list_jobs] returns. Unset fields match every job.
+#[derive(Debug, Clone, Default)]
+pub struct JobFilter {
+    /// Guest name or hex image ID.
+    pub guest: Option<String>,
+    pub requester: Option<Address>,
+    pub status: Option<JobStatus>,
+    /// Earliest completion time, in Unix seconds.
+    pub since: Option<u64>,
+    /// Latest completion time, in Unix seconds.
+    pub until: Option<u64>,
+}
+
+impl JobFilter {
+    fn matches(&self, job: &JobRecord) -> bool {
+        if let Some(guest) = &self.guest {
+            let image_id = guest.trim_start_matches("0x");
+            if !job.guest.eq_ignore_ascii_case(guest)
+                && !job.image_id.eq_ignore_ascii_case(image_id)
+            {
+                return false;
+            }
+        }
+        if self.requester.is_some() && job.requester != self.requester {
+            return false;
+        }
+        if self.status.is_some() && Some(job.status) != self.status {
+            return false;
+        }
+        !matches!(self.since, Some(since) if job.completed_at < since)
+            && !matches!(self.until, Some(until) if job.completed_at > until)
+    }
+}
+
+/// A page of jobs, with the cursor of the next page if there is one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobPage {
+    pub jobs: Vec<JobRecord>,
+    pub next_cursor: Option<String>,
+}
+
+impl Store {
+    /// Store a job's record under its ID and add it to the tenant's job
+    /// index.
+    pub fn put_job(&self, tenant: &str, job: &JobRecord) -> Result<()> {
+        let data = redact_json(job).context("Failed to serialize job record")?;
+        self.put(BlobKind::Job, tenant, &job.id, &data)?;
+        let _index = self
+            .job_index
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let path = self.path(BlobKind::Job, tenant, JOB_INDEX)?;
+        if !path.exists() {
+            // Index the tenant's earlier records, this one included.
+            self.rebuild_job_index(tenant)?;
+            return Ok(());
+        }
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{} {}", job.completed_at, job.id))
+            .context(format!("Failed to append to {}", path.display()))
+    }
+
+    /// Read a job record, taking its blob key as the ID of records written
+    /// before jobs had IDs.
+    fn get_job(&self, tenant: &str, id: &str) -> Result<Option<JobRecord>> {
+        let Some(data) = self.get(BlobKind::Job, tenant, id)? else {
+            return Ok(None);
+        };
+        let mut job: JobRecord =
+            serde_json::from_slice(&data).context(format!("Invalid job record {id}"))?;
+        if job.id.is_empty() {
+            job.id = id.to_string();
+        }
+        Ok(Some(job))
+    }
+
+    /// Completion time and ID of each of a tenant's jobs, in the order they
+    /// were recorded.
+    fn job_index(&self, tenant: &str) -> Result<Vec<(u64, String)>> {
+        let path = self.path(BlobKind::Job, tenant, JOB_INDEX)?;
+        let index = match std::fs::read_to_string(&path) {
+            Ok(index) => index,
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let _index = self
+                    .job_index
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                return self.rebuild_job_index(tenant);
+            }
+            Err(err) => return Err(err).context(format!("Failed to read {}", path.display())),
+        };
+        index
+            .lines()
+            .map(|line| {
+                line.split_once(' ')
+                    .and_then(|(at, id)| Some((at.parse().ok()?, id.to_string())))
+                    .ok_or_else(|| anyhow!("invalid line {line:?} in {}", path.display()))
+            })
+            .collect()
+    }
+
+    /// Write the index of a tenant's jobs from their records, for stores
+    /// written before jobs were indexed. Callers hold the index lock.
+    fn rebuild_job_index(&self, tenant: &str) -> Result<Vec<(u64, String)>> {
+        let path = self.path(BlobKind::Job, tenant, JOB_INDEX)?;
+        let dir = path.parent().unwrap_or(&self.dir);
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).context(format!("Failed to read {}", dir.display())),
+        };
+        let mut index = Vec::new();
+        for entry in entries {
+            let entry = entry.context(format!("Failed to read {}", dir.display()))?;
+            let Some(id) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            // Skip temp files of writes in progress.
+            if id.starts_with('.') || id == JOB_INDEX {
+                continue;
+            }
+            if let Some(job) = self.get_job(tenant, &id)? {
+                index.push((job.completed_at, id));
+            }
+        }
+        index.sort();
+        let lines: String = index
+            .iter()
+            .map(|(completed_at, id)| format!("{completed_at} {id}\n"))
+            .collect();
+        let mut file = NamedTempFile::new_in(dir).context("Failed to create temp file")?;
+        file.write_all(lines.as_bytes())
+            .context(format!("Failed to write {}", path.display()))?;
+        file.persist(&path)
+            .context(format!("Failed to persist {}", path.display()))?;
+        Ok(index)
+    }
+
+    /// List a tenant's jobs matching `filter`, most recent first, starting
+    /// after `cursor` as returned with the previous page. Jobs are walked
+    /// through the tenant's job index, and only records up to the end of
+    /// the page are read.
+    pub fn list_jobs(
+        &self,
+        tenant: &str,
+        filter: &JobFilter,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<JobPage> {
+        let limit = limit.max(1);
+        let mut index = self.job_index(tenant)?;
+        index.sort_by(|a, b| b.cmp(a));
+        if let Some(cursor) = cursor {
+            let (completed_at, id) = cursor
+                .split_once('-')
+                .and_then(|(at, id)| Some((at.parse::<u64>().ok()?, id)))
+                .ok_or_else(|| anyhow!("invalid cursor {cursor:?}"))?;
+            index.retain(|(at, job)| (*at, job.as_str()) < (completed_at, id));
+        }
+        let mut jobs = Vec::new();
+        for (_, id) in &index {
+            let Some(job) = self.get_job(tenant, id)? else {
+                continue;
+            };
+            if filter.matches(&job) {
+                jobs.push(job);
+                // One more than the page shows whether there is a next one.
+                if jobs.len() > limit {
+                    break;
+                }
+            }
+        }
+        let next_cursor = (jobs.len() > limit).then(|| jobs[limit - 1].cursor());
+        jobs.truncate(limit);
+        Ok(JobPage { jobs, next_cursor })
+    }
+
+    /// Tenants whose job records predate job indexes, which are migrated by
+    /// indexing them when their jobs are first listed. The latest job of
+    /// every indexed tenant is read, checking its index and that the store's
+    /// key opens it. Nothing is written.
+    pub fn pending_migrations(&self) -> Result<Vec<String>> {
+        let dir = self.dir.join(BlobKind::Job.dir());
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err).context(format!("Failed to read {}", dir.display())),
+        };
+        let mut unindexed = Vec::new();
+        for entry in entries {
+            let entry = entry.context(format!("Failed to read {}", dir.display()))?;
+            let Some(tenant) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if tenant.starts_with('.') {
+                continue;
+            }
+            if !self.path(BlobKind::Job, &tenant, JOB_INDEX)?.exists() {
+                unindexed.push(tenant);
+                continue;
+            }
+            self.list_jobs(&tenant, &JobFilter::default(), None, 1)
+                .context(format!("Failed to read jobs of tenant {tenant}"))?;
+        }
+        unindexed.sort();
+        Ok(unindexed)
+    }
+}
+
+/// zstd compress a blob, prefixed with [COMPRESSED_MAGIC].
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let frame = zstd::encode_all(data, COMPRESSION_LEVEL).context("Failed to compress blob")?;
+    Ok([COMPRESSED_MAGIC.as_slice(), &frame].concat())
+}
@@ -0,0 +1,313 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::types::{Address, TransactionReceipt, U64};
+
+use crate::{
+    bindings::{BonsaiRelay, Callback, CallbackAuthorization, CallbackRequestFilter},
+    chain::ChainKind,
+    clock::{ChainTime, MaxSkew},
+    elog,
+    eth::{blob_fees, EthClient},
+    gas::{estimate_callback, GasStrategy, Posting, PostingCost},
+    postprocess::PostProcessChain,
+    schema::{check_journal_version, journal_validity, journal_version},
+    snark_seal, Output,
+};
+
+/// How often a held callback is priced again: about once a block.
+const REPRICE_INTERVAL: Duration = Duration::from_secs(12);
+
+/// Posts guest results to the relay contract, which verifies them and invokes
+/// the requested callback.
+pub struct Submitter {
+    relay: BonsaiRelay<EthClient>,
+    client: Arc<EthClient>,
+    gas: Arc<RwLock<GasStrategy>>,
+    chain: ChainKind,
+    max_staleness: Option<Duration>,
+    max_skew: MaxSkew,
+}
+
+impl Submitter {
+    pub fn new(relay_address: Address, client: Arc<EthClient>) -> Self {
+        Self {
+            relay: BonsaiRelay::new(relay_address, client.clone()),
+            client,
+            gas: Arc::default(),
+            chain: ChainKind::Ethereum,
+            max_staleness: None,
+            max_skew: MaxSkew::default(),
+        }
+    }
+
+    /// Account for the chain's own fee components, such as rollup L1 data
+    /// fees, when estimating callback costs.
+    pub fn with_chain(mut self, chain: ChainKind) -> Self {
+        self.chain = chain;
+        self
+    }
+
+    /// Submit callbacks as `strategy` currently says: refusing those above
+    /// its gas limit, and pricing posting each result as EIP-4844 blobs
+    /// against calldata if asked to. Results are only ever posted as
+    /// calldata, which is where the relay contract reads them from.
+    pub fn with_gas_strategy(mut self, strategy: Arc<RwLock<GasStrategy>>) -> Self {
+        self.gas = strategy;
+        self
+    }
+
+    /// Refuse to submit results computed from chain state observed more than
+    /// `max_age` ago, so a delayed proof cannot update a price with stale
+    /// data.
+    pub fn with_max_staleness(mut self, max_age: Duration) -> Self {
+        self.max_staleness = Some(max_age);
+        self
+    }
+
+    /// Allow the local clock to differ from the chain's by up to `skew` when
+    /// the latest block cannot be read to check staleness against.
+    pub fn with_max_clock_skew(mut self, skew: Duration) -> Self {
+        self.max_skew = MaxSkew(skew);
+        self
+    }
+
+    /// Check a guest's journal against the staleness bound, measuring its
+    /// age at the timestamp of the latest block. Journals of guests that
+    /// commit no observation range always pass.
+    pub async fn check_fresh(&self, guest_name: &str, journal: &[u8]) -> Result<()> {
+        let Some(max_age) = self.max_staleness else {
+            return Ok(());
+        };
+        let Some(validity) = journal_validity(guest_name, journal)? else {
+            return Ok(());
+        };
+        let now = ChainTime::latest(self.client.as_ref()).await;
+        let age = validity.age(now.time());
+        if age > max_age + now.tolerance(self.max_skew) {
+            let reference = match now {
+                ChainTime::Block { number, .. } => format!("block {number}"),
+                ChainTime::Local(_) => "the local clock".to_string(),
+            };
+            bail!(
+                "result was observed {}s before {reference}, beyond the {}s staleness bound",
+                age.as_secs(),
+                max_age.as_secs()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that a guest's journal is of a version the relay reads, so a
+    /// result of an image committing an unknown layout is never submitted.
+    /// Results of the previous version, from an image still live during an
+    /// upgrade, pass and are submitted unchanged.
+    pub fn check_version(&self, guest_name: &str, journal: &[u8]) -> Result<()> {
+        let version = check_journal_version(guest_name, journal)?;
+        if let Some(current) = journal_version(guest_name) {
+            if version < current {
+                elog!(
+                    "Submitting a version {version} {guest_name} journal, the current version is \
+                     {current}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Build the callback answering a request. The payload is the function
+    /// selector, the journal and the image ID, as `BonsaiCallbackReceiver`
+    /// expects. Execution-only outputs carry an empty seal, which only a dev
+    /// mode relay contract accepts.
+    ///
+    /// With a non-empty `post_process` chain for the named guest, the payload
+    /// carries the processed journal instead. The relay contract verifies
+    /// seals against the journal in the payload, so only unsealed callbacks
+    /// can be post-processed.
+    pub fn callback(
+        request: &CallbackRequestFilter,
+        output: &Output,
+        post_process: Option<(&str, &PostProcessChain)>,
+    ) -> Result<Callback> {
+        let (journal, auth) = match output {
+            Output::Execution { journal } => (
+                journal,
+                CallbackAuthorization {
+                    seal: Default::default(),
+                    post_state_digest: [0u8; 32],
+                },
+            ),
+            Output::Bonsai {
+                journal,
+                receipt_metadata,
+                snark_proof,
+                ..
+            } => (
+                journal,
+                CallbackAuthorization {
+                    seal: snark_seal(snark_proof)?.into(),
+                    post_state_digest: receipt_metadata.post.digest().into(),
+                },
+            ),
+            Output::Stark { .. } => {
+                bail!("receipts proven without Bonsai have no SNARK for the relay contract")
+            }
+        };
+        let journal = match post_process {
+            Some((guest_name, chain)) if !chain.is_empty() => {
+                if !auth.seal.is_empty() {
+                    bail!(
+                        "the relay contract verifies seals against the guest's own journal, so \
+                         sealed callbacks cannot be post-processed"
+                    );
+                }
+                chain
+                    .apply(guest_name, journal)
+                    .context("Failed to post-process the journal")?
+            }
+            _ => journal.clone(),
+        };
+        Ok(Callback {
+            auth,
+            callback_contract: request.callback_contract,
+            payload: [
+                request.function_selector.as_slice(),
+                &journal,
+                request.image_id.as_slice(),
+            ]
+            .concat()
+            .into(),
+            gas_limit: request.gas_limit,
+        })
+    }
+
+    fn strategy(&self) -> Result<GasStrategy> {
+        self.gas
+            .read()
+            .map(|strategy| strategy.clone())
+            .map_err(|_| anyhow!("gas strategy lock poisoned"))
+    }
+
+    /// Price posting a callback's result as EIP-4844 blobs against calldata,
+    /// if the gas strategy asks to, holding the callback while calldata costs
+    /// more than its premium over blobs. It is priced again every block until
+    /// it no longer does, blob fees are unknown, or the strategy's hold time
+    /// passes, and is then posted as calldata, the only posting the relay
+    /// contract reads. Returns whether the callback was held, in which case
+    /// its result may have gone stale.
+    pub async fn hold_for_calldata(&self, callback: &Callback) -> Result<bool> {
+        let strategy = self.strategy()?;
+        if !strategy.blob_pricing {
+            return Ok(false);
+        }
+        let estimate = estimate_callback(callback);
+        let premium = strategy.max_calldata_premium_pct;
+        let deadline = Instant::now() + Duration::from_secs(strategy.max_hold_secs);
+        let mut held = false;
+        loop {
+            let cost = blob_fees(&self.client)
+                .await
+                .map(|(base_fee, blob_base_fee)| {
+                    PostingCost::new(&estimate, base_fee, blob_base_fee)
+                });
+            let cost = match (PostingCost::choose(cost, premium.unwrap_or(0)), cost) {
+                (Posting::Blob, Some(cost)) => cost,
+                _ => {
+                    elog!("Posting result as calldata");
+                    return Ok(held);
+                }
+            };
+            let Some(premium) = premium else {
+                elog!(
+                    "Blobs would cost {} wei against {} wei of calldata, but the relay contract \
+                     only reads calldata",
+                    cost.blob_wei,
+                    cost.calldata_wei
+                );
+                return Ok(false);
+            };
+            if Instant::now() >= deadline {
+                elog!(
+                    "Posting result as {} wei of calldata after holding it {}s, against {} wei \
+                     of blobs",
+                    cost.calldata_wei,
+                    strategy.max_hold_secs,
+                    cost.blob_wei
+                );
+                return Ok(held);
+            }
+            if !held {
+                elog!(
+                    "Holding callback while its {} wei of calldata cost over {premium}% more \
+                     than {} wei of blobs",
+                    cost.calldata_wei,
+                    cost.blob_wei
+                );
+                held = true;
+            }
+            tokio::time::sleep(REPRICE_INTERVAL).await;
+        }
+    }
+
+    /// Submit a callback, first checking that the relay contract would accept
+    /// it.
+    pub async fn submit(&self, callback: Callback) -> Result<TransactionReceipt> {
+        let mut estimate = estimate_callback(&callback);
+        let call = self.relay.invoke_callback(callback);
+        if let Some(calldata) = call.calldata() {
+            // An unreachable fee oracle should not hold back the callback;
+            // the L2 estimate still bounds most of its cost.
+            match self
+                .chain
+                .l1_gas(&self.client, self.relay.address(), calldata)
+                .await
+            {
+                Ok(l1_gas) => estimate.l1_gas = l1_gas,
+                Err(err) => elog!("Warning: L1 gas unknown, estimating L2 gas only: {err:?}"),
+            }
+        }
+        elog!("Estimated callback cost: {estimate}");
+        if let Some(max_gas) = self.strategy()?.max_gas {
+            if estimate.total() > max_gas {
+                bail!(
+                    "callback would cost {} gas, above the {max_gas} gas limit",
+                    estimate.total()
+                );
+            }
+        }
+        if !call.call().await.context("Failed to simulate callback")? {
+            bail!("callback would fail");
+        }
+        let receipt = call
+            .send()
+            .await
+            .context("Failed to send callback")?
+            .await
+            .context("Failed to await callback")?
+            .context("Callback transaction was dropped")?;
+        if receipt.status != Some(U64::one()) {
+            bail!(
+                "callback transaction {:?} reverted",
+                receipt.transaction_hash
+            );
+        }
+        Ok(receipt)
+    }
+}
@@ -0,0 +1,339 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::types::Address;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{redact::register_secret, registry::Guest, webhook::validate_url};
+
+/// Tenant used when the relay runs without a tenants file.
+pub const DEFAULT_TENANT: &str = "default";
+
+const QUOTA_WINDOW: Duration = Duration::from_secs(60);
+
+/// What an API key may do. Roles do not imply one another, except that
+/// [Role::Admin] may do everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Submit prove requests.
+    #[default]
+    Prove,
+    /// Read usage and results.
+    Read,
+    /// Administer the relay's configuration. State shared by every tenant,
+    /// e.g. whether proving is paused, needs the operator key instead.
+    Admin,
+}
+
+impl Role {
+    pub fn permits(self, required: Role) -> bool {
+        self == Role::Admin || self == required
+    }
+}
+
+/// An API key, given either as a plain string with the [Role::Prove] role or
+/// as `{"key": ..., "role": ...}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ApiKey {
+    Plain(String),
+    Scoped {
+        key: String,
+        #[serde(default)]
+        role: Role,
+    },
+}
+
+impl ApiKey {
+    pub fn key(&self) -> &str {
+        match self {
+            ApiKey::Plain(key) | ApiKey::Scoped { key, .. } => key,
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        match self {
+            ApiKey::Plain(_) => Role::default(),
+            ApiKey::Scoped { role, .. } => *role,
+        }
+    }
+}
+
+/// Configuration of a single tenant, as found in the tenants file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TenantConfig {
+    pub id: String,
+    /// API keys identifying this tenant.
+    #[serde(default)]
+    pub api_keys: Vec<ApiKey>,
+    /// Names or hex image IDs of the guests this tenant may use. All guests
+    /// are allowed if absent.
+    pub guests: Option<Vec<String>>,
+    /// Maximum number of requests accepted per minute.
+    pub requests_per_minute: Option<u32>,
+    /// Addresses this tenant makes requests on behalf of, which the
+    /// requester policy is applied to. Requests may only name one of these,
+    /// and are made for the only one if they name none.
+    #[serde(default)]
+    pub requesters: Vec<Address>,
+    /// Where each result proven for this tenant is POSTed, see
+    /// [crate::webhook].
+    pub webhook_url: Option<String>,
+}
+
+/// Per-tenant request counters, used for billing.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct TenantUsage {
+    pub requests: u64,
+    pub executions: u64,
+    pub proofs: u64,
+    /// Unproven executions run through the simulation endpoint.
+    pub simulations: u64,
+    pub deduplicated: u64,
+    pub rejected: u64,
+}
+
+struct QuotaWindow {
+    start: Instant,
+    count: u32,
+}
+
+/// A tenant's configuration and counters. Counters are shared with the
+/// tenant's replacement when the tenants file is reloaded, so requests still
+/// in flight are billed to it.
+pub struct Tenant {
+    pub config: TenantConfig,
+    quota: Arc<Mutex<QuotaWindow>>,
+    usage: Arc<Mutex<TenantUsage>>,
+}
+
+impl Tenant {
+    fn new(config: TenantConfig) -> Self {
+        Self {
+            config,
+            quota: Arc::new(Mutex::new(QuotaWindow {
+                start: Instant::now(),
+                count: 0,
+            })),
+            usage: Arc::new(Mutex::new(TenantUsage::default())),
+        }
+    }
+
+    fn reconfigure(&self, config: TenantConfig) -> Self {
+        Self {
+            config,
+            quota: self.quota.clone(),
+            usage: self.usage.clone(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.config.id
+    }
+
+    /// Whether the guest is on this tenant's allowlist.
+    pub fn allows_guest(&self, guest: &Guest) -> bool {
+        let Some(guests) = &self.config.guests else {
+            return true;
+        };
+        let image_id = hex::encode(guest.image_id);
+        guests.iter().any(|allowed| {
+            allowed.eq_ignore_ascii_case(&guest.name)
+                || allowed
+                    .trim_start_matches("0x")
+                    .eq_ignore_ascii_case(&image_id)
+        })
+    }
+
+    /// The requester a request naming `declared` is made on behalf of. It
+    /// must be one of the tenant's requesters, as clients cannot be taken at
+    /// their word.
+    pub fn requester(&self, declared: Option<Address>) -> Result<Option<Address>> {
+        match (declared, self.config.requesters.as_slice()) {
+            (Some(declared), requesters) if requesters.contains(&declared) => Ok(Some(declared)),
+            (Some(declared), _) => bail!(
+                "tenant {} may not make requests on behalf of {declared:?}",
+                self.id()
+            ),
+            (None, [requester]) => Ok(Some(*requester)),
+            (None, _) => Ok(None),
+        }
+    }
+
+    /// Count a request against the tenant's quota, failing if the quota for
+    /// the current minute is used up.
+    pub fn admit(&self) -> Result<()> {
+        let mut quota = self
+            .quota
+            .lock()
+            .map_err(|_| anyhow!("tenant quota lock poisoned"))?;
+        let now = Instant::now();
+        if now.duration_since(quota.start) >= QUOTA_WINDOW {
+            quota.start = now;
+            quota.count = 0;
+        }
+        if let Some(limit) = self.config.requests_per_minute {
+            if quota.count >= limit {
+                drop(quota);
+                self.record(|usage| usage.rejected += 1)?;
+                bail!("tenant {} exceeded {limit} requests per minute", self.id());
+            }
+        }
+        quota.count += 1;
+        Ok(())
+    }
+
+    pub fn record(&self, update: impl FnOnce(&mut TenantUsage)) -> Result<()> {
+        let mut usage = self
+            .usage
+            .lock()
+            .map_err(|_| anyhow!("tenant usage lock poisoned"))?;
+        update(&mut usage);
+        Ok(())
+    }
+
+    pub fn usage(&self) -> Result<TenantUsage> {
+        Ok(self
+            .usage
+            .lock()
+            .map_err(|_| anyhow!("tenant usage lock poisoned"))?
+            .clone())
+    }
+}
+
+/// SHA-256 digest of an API key. Keys are only compared by digest, so that
+/// the time a comparison takes reveals nothing about the key's bytes.
+pub fn key_digest(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+/// The tenants served by this relay, indexed by ID and API key digest.
+pub struct Tenants {
+    by_id: HashMap<String, Arc<Tenant>>,
+    by_key: HashMap<[u8; 32], (Arc<Tenant>, Role)>,
+}
+
+impl Tenants {
+    /// A single [DEFAULT_TENANT] without API keys or limits, which every
+    /// request is attributed to.
+    pub fn open() -> Self {
+        let tenant = Arc::new(Tenant::new(TenantConfig {
+            id: DEFAULT_TENANT.to_string(),
+            api_keys: Vec::new(),
+            guests: None,
+            requests_per_minute: None,
+            requesters: Vec::new(),
+            webhook_url: None,
+        }));
+        Self {
+            by_id: HashMap::from([(DEFAULT_TENANT.to_string(), tenant)]),
+            by_key: HashMap::new(),
+        }
+    }
+
+    /// Load a JSON array of [TenantConfig]s.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_configs(Self::load_configs(path)?)
+    }
+
+    pub fn load_configs(path: &Path) -> Result<Vec<TenantConfig>> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open tenants file {}", path.display()))?;
+        serde_json::from_reader(file)
+            .context(format!("Failed to parse tenants file {}", path.display()))
+    }
+
+    pub fn from_configs(configs: Vec<TenantConfig>) -> Result<Self> {
+        Self::build(configs, None)
+    }
+
+    /// Tenants with new configurations, keeping the quota windows and usage
+    /// of the current tenants with the same ID.
+    pub fn reload(&self, configs: Vec<TenantConfig>) -> Result<Self> {
+        Self::build(configs, Some(self))
+    }
+
+    fn build(configs: Vec<TenantConfig>, previous: Option<&Tenants>) -> Result<Self> {
+        let mut tenants = Self {
+            by_id: HashMap::new(),
+            by_key: HashMap::new(),
+        };
+        for config in configs {
+            let tenant = Arc::new(
+                match previous.and_then(|previous| previous.by_id.get(&config.id)) {
+                    Some(current) => current.reconfigure(config),
+                    None => Tenant::new(config),
+                },
+            );
+            if let Some(webhook_url) = &tenant.config.webhook_url {
+                register_secret(webhook_url);
+                validate_url(webhook_url)
+                    .context(format!("Invalid webhook of tenant {}", tenant.id()))?;
+            }
+            for api_key in &tenant.config.api_keys {
+                register_secret(api_key.key());
+                match tenants.by_key.entry(key_digest(api_key.key())) {
+                    Entry::Occupied(other) => bail!(
+                        "API key of tenant {} is also used by tenant {}",
+                        tenant.id(),
+                        other.get().0.id()
+                    ),
+                    Entry::Vacant(entry) => {
+                        entry.insert((tenant.clone(), api_key.role()));
+                    }
+                }
+            }
+            match tenants.by_id.entry(tenant.id().to_string()) {
+                Entry::Occupied(_) => bail!("duplicate tenant {}", tenant.id()),
+                Entry::Vacant(entry) => {
+                    entry.insert(tenant);
+                }
+            }
+        }
+        Ok(tenants)
+    }
+
+    /// Whether requests must present an API key.
+    pub fn requires_key(&self) -> bool {
+        !self.by_key.is_empty()
+    }
+
+    /// Identify the tenant making a request and the role of its key. Without
+    /// configured API keys all requests belong to the only tenant, as admin.
+    pub fn authenticate(&self, api_key: Option<&str>) -> Option<(Arc<Tenant>, Role)> {
+        if !self.requires_key() && self.by_id.len() == 1 {
+            let tenant = self.by_id.values().next()?;
+            return Some((tenant.clone(), Role::Admin));
+        }
+        self.by_key.get(&key_digest(api_key?)).cloned()
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<Tenant>> {
+        self.by_id.get(id).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.by_id.values()
+    }
+}
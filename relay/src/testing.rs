@@ -0,0 +1,141 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Support for integration tests that travel through time instead of waiting
+//! for it. Only built with the `testing` feature.
+//!
+//! A [MockClock] follows tokio's clock, so in a runtime with paused time,
+//! such as one from [paused_runtime] or `#[tokio::test(start_paused = true)]`,
+//! poll intervals, schedules and lease renewals fire as soon as every task
+//! is idle, and the wall clock read by staleness checks and audit timestamps
+//! moves along with them. Blocking waits between Bonsai polls and retries
+//! return at once, moving the clock forward by the time they would have
+//! slept.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use tokio::{runtime::Runtime, time::Instant};
+
+pub use crate::clock::{set_clock, Clock, SystemClock};
+
+/// Clock starting at a chosen time and moving forward with tokio's clock and
+/// every blocking sleep.
+pub struct MockClock {
+    start: SystemTime,
+    anchor: Instant,
+    /// Time skipped by blocking sleeps and [MockClock::skip], in nanoseconds.
+    skipped: AtomicU64,
+}
+
+impl MockClock {
+    /// A clock reading `start` at the current instant of tokio's clock.
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            start,
+            anchor: Instant::now(),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// Install a clock reading `start` now for the whole relay, returning it
+    /// to move it along.
+    pub fn install(start: SystemTime) -> Arc<Self> {
+        let clock = Arc::new(Self::new(start));
+        set_clock(clock.clone());
+        clock
+    }
+
+    /// Move this clock forward by `duration` without moving tokio's, so
+    /// timers do not fire. Use [advance] to move both.
+    pub fn skip(&self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.skipped.fetch_add(nanos, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        let skipped = Duration::from_nanos(self.skipped.load(Ordering::SeqCst));
+        self.start + Instant::now().duration_since(self.anchor) + skipped
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.skip(duration);
+    }
+}
+
+/// Move tokio's clock, and with it an installed [MockClock], forward by
+/// `duration`, firing every timer due by then. Time must be paused.
+pub async fn advance(duration: Duration) {
+    tokio::time::advance(duration).await;
+}
+
+/// Runtime with time paused, which jumps to the next timer whenever every
+/// task is idle.
+pub fn paused_runtime() -> std::io::Result<Runtime> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::lease::Leases;
+
+    /// Let a lease renewal woken by the last timer finish on the blocking
+    /// pool, which runs on real time.
+    async fn settle() {
+        for _ in 0..100 {
+            tokio::task::yield_now().await;
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lease_renewals_follow_advanced_time() {
+        let clock = MockClock::install(SystemTime::now());
+        let start = clock.now();
+        let dir = tempfile::tempdir().unwrap();
+        let ttl = Duration::from_secs(30);
+        let ours = Leases::open(dir.path(), ttl).unwrap();
+        let theirs = Leases::open(dir.path(), ttl).unwrap();
+        let guard = ours.claim("job").unwrap().unwrap().keep_alive();
+
+        // Renewed every third of its TTL, the lease outlives the TTL.
+        for _ in 0..9 {
+            advance(ttl / 3).await;
+            settle().await;
+            assert!(theirs.claim("job").unwrap().is_none());
+        }
+        assert!(clock.now() >= start + ttl * 3);
+        assert!(!guard.lost());
+
+        // Moving the wall clock past the TTL without firing the renewal
+        // timer lets the lease lapse.
+        clock.skip(ttl + Duration::from_secs(1));
+        assert!(theirs.claim("job").unwrap().is_some());
+
+        drop(guard);
+        set_clock(Arc::new(SystemClock));
+    }
+}
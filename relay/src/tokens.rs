@@ -0,0 +1,332 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt,
+    path::Path,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use ethers::{
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{transaction::eip2718::TypedTransaction, Address, TransactionRequest, U256, U512},
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+/// `decimals()` selector of ERC-20 tokens.
+const DECIMALS_SELECTOR: [u8; 4] = [0x31, 0x3c, 0xe5, 0x67];
+/// `symbol()` selector of ERC-20 tokens.
+const SYMBOL_SELECTOR: [u8; 4] = [0x95, 0xd8, 0x9b, 0x41];
+
+/// Display metadata of a token.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenMetadata {
+    pub address: Address,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// Metadata of a token known ahead of time, for tokens whose contracts do not
+/// implement the optional ERC-20 metadata functions.
+#[derive(Debug, Clone, Deserialize)]
+pub struct KnownToken {
+    pub chain_id: u64,
+    #[serde(flatten)]
+    pub metadata: TokenMetadata,
+}
+
+/// Resolves token metadata from the chain a client is connected to, caching
+/// what it reads. Tokens are keyed by chain ID, so known tokens of several
+/// chains can share one configuration file.
+pub struct TokenResolver<M> {
+    client: Arc<M>,
+    chain_id: u64,
+    cache: Mutex<HashMap<(u64, Address), TokenMetadata>>,
+}
+
+impl<M: Middleware> TokenResolver<M> {
+    pub async fn new(client: Arc<M>) -> Result<Self> {
+        let chain_id = client
+            .get_chainid()
+            .await
+            .map_err(|err| anyhow!("Failed to query the chain ID: {err}"))?
+            .as_u64();
+        Ok(Self {
+            client,
+            chain_id,
+            cache: Default::default(),
+        })
+    }
+
+    /// Seed the cache with known tokens, which take precedence over what the
+    /// chain reports.
+    pub fn with_known(mut self, tokens: Vec<KnownToken>) -> Self {
+        let cache = self.cache.get_mut();
+        for token in tokens {
+            cache.insert((token.chain_id, token.metadata.address), token.metadata);
+        }
+        self
+    }
+
+    /// Load known tokens from a JSON array of [KnownToken]s.
+    pub fn with_known_file(self, path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .context(format!("Failed to open tokens file {}", path.display()))?;
+        let tokens = serde_json::from_reader(file)
+            .context(format!("Failed to parse tokens file {}", path.display()))?;
+        Ok(self.with_known(tokens))
+    }
+
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    pub async fn resolve(&self, address: Address) -> Result<TokenMetadata> {
+        let key = (self.chain_id, address);
+        if let Some(metadata) = self.cache.lock().await.get(&key) {
+            return Ok(metadata.clone());
+        }
+        let metadata = TokenMetadata {
+            address,
+            symbol: self.symbol(address).await?,
+            decimals: self.decimals(address).await?,
+        };
+        self.cache.lock().await.insert(key, metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Resolve every distinct token in `addresses`.
+    pub async fn resolve_all(
+        &self,
+        addresses: impl IntoIterator<Item = Address>,
+    ) -> Result<TokenTable> {
+        let mut table = TokenTable::default();
+        for address in addresses {
+            if let Entry::Vacant(entry) = table.0.entry(address) {
+                entry.insert(self.resolve(address).await?);
+            }
+        }
+        Ok(table)
+    }
+
+    async fn call(&self, address: Address, selector: [u8; 4]) -> Result<Vec<u8>> {
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(address)
+            .data(selector.to_vec())
+            .into();
+        let output = self
+            .client
+            .call(&tx, None)
+            .await
+            .map_err(|err| anyhow!("Failed to call token {address:?}: {err}"))?;
+        Ok(output.to_vec())
+    }
+
+    async fn decimals(&self, address: Address) -> Result<u8> {
+        let output = self.call(address, DECIMALS_SELECTOR).await?;
+        let decimals = abi::decode(&[ParamType::Uint(8)], &output)
+            .ok()
+            .and_then(|tokens| tokens.into_iter().next())
+            .and_then(Token::into_uint)
+            .ok_or_else(|| anyhow!("Token {address:?} has no decimals()"))?;
+        if decimals > U256::from(77) {
+            bail!("Token {address:?} reports {decimals} decimals");
+        }
+        Ok(decimals.as_u32() as u8)
+    }
+
+    /// Read `symbol()`, which some older tokens return as a `bytes32`.
+    async fn symbol(&self, address: Address) -> Result<String> {
+        let output = self.call(address, SYMBOL_SELECTOR).await?;
+        if let Ok(tokens) = abi::decode(&[ParamType::String], &output) {
+            if let Some(Token::String(symbol)) = tokens.into_iter().next() {
+                return Ok(symbol);
+            }
+        }
+        match abi::decode(&[ParamType::FixedBytes(32)], &output) {
+            Ok(tokens) => match tokens.into_iter().next() {
+                Some(Token::FixedBytes(bytes)) => Ok(String::from_utf8_lossy(&bytes)
+                    .trim_end_matches('\0')
+                    .to_string()),
+                _ => bail!("Token {address:?} has no symbol()"),
+            },
+            Err(_) => bail!("Token {address:?} has no symbol()"),
+        }
+    }
+}
+
+/// Metadata of the tokens a request refers to, resolved up front.
+#[derive(Debug, Clone, Default)]
+pub struct TokenTable(HashMap<Address, TokenMetadata>);
+
+impl TokenTable {
+    pub fn get(&self, address: Address) -> Result<&TokenMetadata> {
+        self.0
+            .get(&address)
+            .ok_or_else(|| anyhow!("token {address:?} was not resolved"))
+    }
+}
+
+/// Which way a pool price is quoted. Uniswap prices, and so the
+/// `sqrtPriceX96` values guests take and commit, are always token1 per
+/// token0; prices in the other direction have to be inverted first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceDirection {
+    /// Units of token1 for one token0, the pool's own direction.
+    #[default]
+    Token1PerToken0,
+    /// Units of token0 for one token1.
+    Token0PerToken1,
+}
+
+/// Price of one whole base token in whole quote tokens, adjusted for the
+/// tokens' decimals.
+#[derive(Debug, Clone)]
+pub struct Price {
+    pub value: f64,
+    pub base: TokenMetadata,
+    pub quote: TokenMetadata,
+}
+
+impl Price {
+    /// Read a Uniswap `sqrtPriceX96` as a price in `direction`.
+    pub fn from_sqrt_price_x96(
+        sqrt_price_x96: U256,
+        token0: &TokenMetadata,
+        token1: &TokenMetadata,
+        direction: PriceDirection,
+    ) -> Self {
+        let sqrt_price = u256_to_f64(sqrt_price_x96) / 2f64.powi(96);
+        let scale = 10f64.powi(token0.decimals as i32 - token1.decimals as i32);
+        let price = Self {
+            value: sqrt_price * sqrt_price * scale,
+            base: token0.clone(),
+            quote: token1.clone(),
+        };
+        match direction {
+            PriceDirection::Token1PerToken0 => price,
+            PriceDirection::Token0PerToken1 => price.invert(),
+        }
+    }
+
+    /// The price of the quote token in units of the base token.
+    pub fn invert(&self) -> Self {
+        Self {
+            value: 1.0 / self.value,
+            base: self.quote.clone(),
+            quote: self.base.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} per {}",
+            format_significant(self.value, 6),
+            self.quote.symbol,
+            self.base.symbol
+        )
+    }
+}
+
+/// Convert a decimal price in `direction`, such as `"1834.25"` USDC per
+/// WETH, to the pool's `sqrtPriceX96`, rounding down.
+pub fn sqrt_price_x96(
+    price: &str,
+    direction: PriceDirection,
+    decimals0: u8,
+    decimals1: u8,
+) -> Result<U256> {
+    let (mantissa, scale) = parse_decimal(price)?;
+    if mantissa.is_zero() {
+        bail!("price must be positive");
+    }
+    let overflow = || anyhow!("price {price} is out of range");
+    let exp10 = |exp: usize| U512::exp10(exp);
+    // In raw units, token1 per token0 = price * 10^decimals1 / 10^decimals0.
+    let (numerator, denominator) = match direction {
+        PriceDirection::Token1PerToken0 => (
+            U512::from(mantissa) * exp10(decimals1 as usize),
+            exp10(scale + decimals0 as usize),
+        ),
+        PriceDirection::Token0PerToken1 => (
+            exp10(scale + decimals1 as usize),
+            U512::from(mantissa) * exp10(decimals0 as usize),
+        ),
+    };
+    let ratio_x192 = numerator
+        .checked_mul(U512::one() << 192)
+        .ok_or_else(overflow)?
+        / denominator;
+    let sqrt_price = U256::try_from(ratio_x192.integer_sqrt()).map_err(|_| overflow())?;
+    if sqrt_price.is_zero() || sqrt_price.bits() > 160 {
+        return Err(overflow());
+    }
+    Ok(sqrt_price)
+}
+
+/// Split a non-negative decimal number into its digits and the number of
+/// them after the decimal point.
+fn parse_decimal(value: &str) -> Result<(U256, usize)> {
+    let (whole, fraction) = value.trim().split_once('.').unwrap_or((value.trim(), ""));
+    let digits = format!("{whole}{fraction}");
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        bail!("invalid decimal number {value:?}");
+    }
+    let mantissa = U256::from_dec_str(&digits).context(format!("{value} is too large"))?;
+    Ok((mantissa, fraction.len()))
+}
+
+/// Format a fixed point token amount in whole units, e.g. `1500000` with 6
+/// decimals as `1.5`.
+pub fn format_amount(amount: U256, decimals: u8) -> String {
+    let unit = U256::exp10(decimals as usize);
+    let whole = amount / unit;
+    let fraction = format!(
+        "{:0>width$}",
+        (amount % unit).to_string(),
+        width = decimals as usize
+    );
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{whole}.{fraction}")
+    }
+}
+
+fn u256_to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0f64, |acc, limb| acc * 2f64.powi(64) + *limb as f64)
+}
+
+fn format_significant(value: f64, digits: usize) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return value.to_string();
+    }
+    let magnitude = value.abs().log10().floor() as i64;
+    let decimals = (digits as i64 - 1 - magnitude).max(0) as usize;
+    format!("{value:.decimals$}")
+}
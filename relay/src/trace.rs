@@ -0,0 +1,114 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Trace IDs correlating a request across the relay's logs and records.
+//! Clients may supply their own, so that requests can be followed from their
+//! systems into the relay.
+
+use std::{cell::RefCell, future::Future};
+
+use anyhow::{bail, Result};
+use tokio::task::JoinHandle;
+
+/// Header carrying a client supplied trace ID, echoed on responses.
+pub const TRACE_ID_HEADER: &str = "x-trace-id";
+
+/// W3C trace context header, whose trace ID is used when no
+/// [TRACE_ID_HEADER] is given.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+const MAX_TRACE_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    static TRACE_ID: String;
+}
+
+thread_local! {
+    /// Trace ID of the blocking task running on this thread, see
+    /// [spawn_blocking].
+    static BLOCKING_TRACE_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// A new random trace ID.
+pub fn generate() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Check a client supplied trace ID. IDs are limited to visible ASCII, so
+/// that they cannot forge log lines.
+pub fn validate(id: &str) -> Result<String> {
+    if id.is_empty() || id.len() > MAX_TRACE_ID_LEN {
+        bail!("trace ID must be 1 to {MAX_TRACE_ID_LEN} characters");
+    }
+    if !id.bytes().all(|byte| byte.is_ascii_graphic()) {
+        bail!("trace ID must be visible ASCII characters");
+    }
+    Ok(id.to_string())
+}
+
+/// Trace ID of a `traceparent` header: `version-traceid-parentid-flags`.
+pub fn from_traceparent(value: &str) -> Option<String> {
+    let mut fields = value.trim().split('-');
+    let (_version, trace_id) = (fields.next()?, fields.next()?);
+    let valid = trace_id.len() == 32
+        && trace_id.bytes().all(|byte| byte.is_ascii_hexdigit())
+        && trace_id.bytes().any(|byte| byte != b'0');
+    valid.then(|| trace_id.to_lowercase())
+}
+
+/// Trace ID of the request being handled, if any.
+pub fn current() -> Option<String> {
+    TRACE_ID
+        .try_with(Clone::clone)
+        .ok()
+        .or_else(|| BLOCKING_TRACE_ID.with(|id| id.borrow().clone()))
+}
+
+/// Run `future` with `id` as the current trace ID.
+pub async fn scope<F: Future>(id: String, future: F) -> F::Output {
+    TRACE_ID.scope(id, future).await
+}
+
+/// Like [tokio::spawn], keeping the current trace ID.
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match current() {
+        Some(id) => tokio::spawn(TRACE_ID.scope(id, future)),
+        None => tokio::spawn(future),
+    }
+}
+
+/// Like [tokio::task::spawn_blocking], keeping the current trace ID.
+pub fn spawn_blocking<F, R>(f: F) -> JoinHandle<R>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let id = current();
+    tokio::task::spawn_blocking(move || {
+        // Cleared on drop, also when `f` panics, as the thread is reused.
+        struct Reset;
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                BLOCKING_TRACE_ID.with(|id| id.borrow_mut().take());
+            }
+        }
+        BLOCKING_TRACE_ID.with(|current| *current.borrow_mut() = id);
+        let _reset = Reset;
+        f()
+    })
+}
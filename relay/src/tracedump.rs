@@ -0,0 +1,275 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dumps of local executions for debugging guest failures that are hard to
+//! reproduce. A dump holds everything needed to execute the guest again, the
+//! exact ELF, input and limits, along with what the execution printed, its
+//! segments and the tail of its instruction trace.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use risc0_zkvm::TraceEvent;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::sha256_hex,
+    clock,
+    pool::{CycleStats, ExecLimits, GuestLogs},
+    registry::Guest,
+    Output,
+};
+
+/// Number of trace events kept from the end of a traced execution. A failure
+/// is almost always explained by the instructions just before it, and
+/// keeping the whole trace of a long execution would take gigabytes.
+pub const DEFAULT_TRACE_TAIL: usize = 100_000;
+
+/// An event of an execution's instruction trace.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceRecord {
+    /// The instruction at `pc` started at `cycle`.
+    Instruction { cycle: u32, pc: u32 },
+    /// A register was written by the preceding instruction.
+    Register { reg: usize, value: u32 },
+    /// A memory word was written by the preceding instruction.
+    Memory { addr: u32, value: u32 },
+}
+
+/// The last events of an execution's trace.
+#[derive(Debug, Clone)]
+pub struct TraceTail {
+    limit: usize,
+    events: VecDeque<TraceRecord>,
+    /// Events dropped from the front to stay within the limit.
+    dropped: u64,
+}
+
+impl TraceTail {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            events: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Record an event reported by the executor.
+    pub fn record(&mut self, event: TraceEvent) {
+        let record = match event {
+            TraceEvent::InstructionStart { cycle, pc, .. } => {
+                TraceRecord::Instruction { cycle, pc }
+            }
+            TraceEvent::RegisterSet { reg, value } => TraceRecord::Register { reg, value },
+            TraceEvent::MemorySet { addr, value } => TraceRecord::Memory { addr, value },
+            _ => return,
+        };
+        if self.limit == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.events.len() == self.limit {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+        self.events.push_back(record);
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &TraceRecord> {
+        self.events.iter()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+/// `manifest.json` of a dump.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DumpManifest {
+    pub guest: String,
+    pub image_id: String,
+    /// File name of the guest ELF within the dump.
+    pub elf: String,
+    pub segment_limit_po2: u32,
+    pub session_limit: Option<u64>,
+    pub input_sha256: String,
+    pub outcome: ExecutionOutcome,
+    pub stats: Option<CycleStats>,
+    pub trace_events: usize,
+    /// Trace events before the dumped tail.
+    pub dropped_events: u64,
+    /// When the dump was written, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl DumpManifest {
+    pub fn limits(&self) -> ExecLimits {
+        ExecLimits {
+            segment_limit_po2: self.segment_limit_po2,
+            session_limit: self.session_limit,
+        }
+    }
+}
+
+/// How an execution ended, as compared between a dump and its replay.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExecutionOutcome {
+    /// The execution failed with this error.
+    Error(String),
+    /// The execution committed this hex encoded journal.
+    Journal(String),
+}
+
+impl ExecutionOutcome {
+    pub fn of(result: &Result<(Output, CycleStats)>) -> Self {
+        match result {
+            Ok((
+                Output::Execution { journal }
+                | Output::Bonsai { journal, .. }
+                | Output::Stark { journal, .. },
+                _,
+            )) => ExecutionOutcome::Journal(hex::encode(journal)),
+            Err(err) => ExecutionOutcome::Error(format!("{err:#}")),
+        }
+    }
+}
+
+impl fmt::Display for ExecutionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecutionOutcome::Error(err) => write!(f, "failure: {err}"),
+            ExecutionOutcome::Journal(journal) => write!(f, "journal 0x{journal}"),
+        }
+    }
+}
+
+/// Write the dump of an execution of `guest` on `input` to `dir`, which must
+/// not hold another dump. The dump includes the input's private section, so
+/// it must be handled like the input itself.
+pub fn write_dump(
+    dir: &Path,
+    guest: &Guest,
+    limits: ExecLimits,
+    input: &[u8],
+    result: &Result<(Output, CycleStats)>,
+    logs: &GuestLogs,
+    trace: &TraceTail,
+) -> Result<()> {
+    if dir.join("manifest.json").exists() {
+        bail!("{} already holds a trace dump", dir.display());
+    }
+    std::fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+    let write = |name: &str, data: &[u8]| {
+        let path = dir.join(name);
+        std::fs::write(&path, data).context(format!("Failed to write {}", path.display()))
+    };
+
+    let elf = format!("{}.elf", guest.name.to_lowercase());
+    write(&elf, &guest.elf()?)?;
+    write("input.bin", input)?;
+    write("stdout.txt", logs.stdout.as_bytes())?;
+    write("stderr.txt", logs.stderr.as_bytes())?;
+    let path = dir.join("trace.jsonl");
+    let file =
+        std::fs::File::create(&path).context(format!("Failed to create {}", path.display()))?;
+    let mut file = BufWriter::new(file);
+    for event in trace.events() {
+        let mut line = serde_json::to_vec(event).context("Failed to serialize trace event")?;
+        line.push(b'\n');
+        file.write_all(&line)
+            .context(format!("Failed to write {}", path.display()))?;
+    }
+    file.flush()
+        .context(format!("Failed to write {}", path.display()))?;
+
+    let manifest = DumpManifest {
+        guest: guest.name.clone(),
+        image_id: hex::encode(guest.image_id),
+        elf,
+        segment_limit_po2: limits.segment_limit_po2,
+        session_limit: limits.session_limit,
+        input_sha256: sha256_hex(input),
+        outcome: ExecutionOutcome::of(result),
+        stats: result.as_ref().ok().map(|(_, stats)| stats.clone()),
+        trace_events: trace.events.len(),
+        dropped_events: trace.dropped,
+        created_at: clock::unix_now(),
+    };
+    write("manifest.json", &serde_json::to_vec_pretty(&manifest)?)
+}
+
+/// A dump written by [write_dump].
+pub struct TraceDump {
+    pub dir: PathBuf,
+    pub manifest: DumpManifest,
+}
+
+impl TraceDump {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join("manifest.json");
+        let manifest =
+            std::fs::read(&path).context(format!("Failed to read {}", path.display()))?;
+        let manifest = serde_json::from_slice(&manifest)
+            .context(format!("Failed to parse {}", path.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest,
+        })
+    }
+
+    /// The dumped guest, checked against the image ID it was dumped with.
+    pub fn guest(&self) -> Result<Guest> {
+        let guest = Guest::from_file(&self.dir.join(&self.manifest.elf))?;
+        if hex::encode(guest.image_id) != self.manifest.image_id {
+            bail!(
+                "dumped ELF has image ID {}, but the dump was taken of {}",
+                hex::encode(guest.image_id),
+                self.manifest.image_id
+            );
+        }
+        Ok(guest)
+    }
+
+    /// The dumped input, checked against its recorded digest.
+    pub fn input(&self) -> Result<Vec<u8>> {
+        let path = self.dir.join("input.bin");
+        let input = std::fs::read(&path).context(format!("Failed to read {}", path.display()))?;
+        if sha256_hex(&input) != self.manifest.input_sha256 {
+            bail!("dumped input does not match its recorded digest");
+        }
+        Ok(input)
+    }
+
+    /// The dumped trace tail, oldest event first.
+    pub fn trace(&self) -> Result<Vec<TraceRecord>> {
+        let path = self.dir.join("trace.jsonl");
+        let file =
+            std::fs::File::open(&path).context(format!("Failed to open {}", path.display()))?;
+        BufReader::new(file)
+            .lines()
+            .map(|line| {
+                let line = line.context(format!("Failed to read {}", path.display()))?;
+                serde_json::from_str(&line).context(format!("Failed to parse {}", path.display()))
+            })
+            .collect()
+    }
+}
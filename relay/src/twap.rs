@@ -0,0 +1,430 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use ethers::{
+    abi::{self, Token},
+    providers::Middleware,
+    types::{Address, Block, BlockId, Bytes, H256, U256},
+};
+
+use crate::{
+    bindings::UniswapV3Pool,
+    finality::FinalityPolicy,
+    input::canonicalize,
+    proofs::{encode_header, verify_account, ProofCache, ProofSource},
+};
+
+/// Storage slot of `slot0` in a Uniswap v3 pool. Must match the TWAP guest.
+const SLOT0_SLOT: u64 = 0;
+
+/// Storage slot of `observations[0]` in a Uniswap v3 pool. Must match the
+/// TWAP guest.
+pub const OBSERVATIONS_SLOT: u64 = 8;
+
+/// A block whose state proves some of a pool's observations.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anchor {
+    /// RLP encoded block header.
+    pub header: Bytes,
+    pub account_proof: Vec<Bytes>,
+    /// Storage proof of `slot0`, bounding the observation indices by the
+    /// ring buffer's cardinality.
+    pub slot0_proof: Vec<Bytes>,
+    /// Storage proof of each proven observation, by ring buffer index.
+    pub observations: Vec<(u16, Vec<Bytes>)>,
+}
+
+/// Input of the TWAP guest: observations of `pool` proven at one or more
+/// blocks. The guest averages the tick between the earliest and the latest
+/// of them, and commits that exact interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TwapInput {
+    pub pool: Address,
+    /// Anchor blocks in ascending order.
+    pub anchors: Vec<Anchor>,
+}
+
+impl TwapInput {
+    /// ABI encode the input in the layout expected by the guest.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let proof = |nodes: &[Bytes]| {
+            Token::Array(
+                nodes
+                    .iter()
+                    .map(|node| Token::Bytes(node.to_vec()))
+                    .collect(),
+            )
+        };
+        let anchors = self
+            .anchors
+            .iter()
+            .map(|anchor| {
+                Token::Tuple(vec![
+                    Token::Bytes(anchor.header.to_vec()),
+                    proof(&anchor.account_proof),
+                    proof(&anchor.slot0_proof),
+                    Token::Array(
+                        anchor
+                            .observations
+                            .iter()
+                            .map(|(index, nodes)| {
+                                Token::Tuple(vec![Token::Uint((*index).into()), proof(nodes)])
+                            })
+                            .collect(),
+                    ),
+                ])
+            })
+            .collect();
+        canonicalize(
+            "TWAP",
+            &abi::encode(&[Token::Address(self.pool), Token::Array(anchors)]),
+        )
+    }
+}
+
+/// Storage slot of the observation at `index`.
+pub(crate) fn observation_slot(index: u16) -> H256 {
+    H256::from_low_u64_be(OBSERVATIONS_SLOT + index as u64)
+}
+
+/// Timestamp of a packed `Oracle.Observation` storage word.
+pub(crate) fn observation_timestamp(word: H256) -> u32 {
+    (U256::from_big_endian(word.as_bytes()) & U256::from(u32::MAX)).as_u32()
+}
+
+/// Whether a packed `Oracle.Observation` storage word is initialized.
+pub(crate) fn observation_initialized(word: H256) -> bool {
+    U256::from_big_endian(word.as_bytes()).bit(248)
+}
+
+/// The pool's oracle does not reach back to the start of a window, so a
+/// proof would silently cover a shorter period.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "pool {pool:?} has {available}s of observation history before the end of the window, \
+     not the {requested}s requested; its observation cardinality is {cardinality}"
+)]
+pub struct InsufficientHistory {
+    pub pool: Address,
+    /// Length of the requested window, in seconds.
+    pub requested: u64,
+    /// Longest window ending at the requested end the pool's observations
+    /// cover, in seconds.
+    pub available: u64,
+    pub cardinality: u16,
+}
+
+/// State of a pool's oracle at a block.
+struct OracleState {
+    block: Block<H256>,
+    /// Index of the newest observation.
+    index: u16,
+    /// Number of observations in the ring buffer, zero if the pool is not
+    /// deployed or initialized.
+    cardinality: u16,
+}
+
+/// Builds TWAP guest inputs from historical state proofs. A window is covered
+/// by the newest observation at its start and the newest at its end. When the
+/// ring buffer has wrapped in between, the start observation is gone from the
+/// state at the end of the window, so it is proven at the block that ends
+/// the window's start instead and the input carries both blocks. Windows
+/// reaching back before the pool's first observation, or past the ring
+/// buffer when stitching blocks is disabled, fail with
+/// [InsufficientHistory].
+pub struct TwapFetcher<M> {
+    client: Arc<M>,
+    source: Arc<dyn ProofSource>,
+    cache: Option<Arc<ProofCache>>,
+    single_block: bool,
+    finality: FinalityPolicy,
+}
+
+impl<M: Middleware + 'static> TwapFetcher<M> {
+    /// Find blocks through `client` and fetch their proofs from `source`.
+    pub fn new(client: Arc<M>, source: Arc<dyn ProofSource>) -> Self {
+        Self {
+            client,
+            source,
+            cache: None,
+            single_block: false,
+            finality: FinalityPolicy::default(),
+        }
+    }
+
+    /// Serve proofs from `cache` where possible.
+    pub fn with_cache(mut self, cache: Arc<ProofCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Prove windows from the ring buffer of the block at their end only,
+    /// for proof sources without historical state.
+    pub fn with_single_block(mut self) -> Self {
+        self.single_block = true;
+        self
+    }
+
+    /// Only anchor inputs to blocks meeting `finality`.
+    pub fn with_finality(mut self, finality: FinalityPolicy) -> Self {
+        self.finality = finality;
+        self
+    }
+
+    async fn block(&self, id: BlockId) -> Result<Block<H256>> {
+        self.client
+            .get_block(id)
+            .await
+            .context(format!("Failed to get block {id:?}"))?
+            .ok_or_else(|| anyhow!("block {id:?} not found"))
+    }
+
+    /// The latest block meeting the finality policy with a timestamp of at
+    /// most `timestamp`, if any.
+    async fn block_at(&self, timestamp: u64) -> Result<Option<Block<H256>>> {
+        let latest = self.finality.block(self.client.as_ref()).await?;
+        if latest.timestamp.as_u64() <= timestamp {
+            return Ok(Some(latest));
+        }
+        let (mut low, mut high) = (0, latest.number.unwrap_or_default().as_u64());
+        // Invariant: block `low` is at or before `timestamp`, `high` after.
+        if self.block(low.into()).await?.timestamp.as_u64() > timestamp {
+            return Ok(None);
+        }
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if self.block(mid.into()).await?.timestamp.as_u64() <= timestamp {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        self.block(low.into()).await.map(Some)
+    }
+
+    async fn observation(&self, pool: Address, block: &Block<H256>, index: u16) -> Result<H256> {
+        let hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        self.client
+            .get_storage_at(pool, observation_slot(index), Some(BlockId::Hash(hash)))
+            .await
+            .context(format!(
+                "Failed to read observation {index} of pool {pool:?} at {hash:?}"
+            ))
+    }
+
+    async fn oracle(&self, pool: Address, block: Block<H256>) -> Result<OracleState> {
+        let hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        let at = BlockId::Hash(hash);
+        let code = self
+            .client
+            .get_code(pool, Some(at))
+            .await
+            .context(format!("Failed to read code of pool {pool:?} at {hash:?}"))?;
+        if code.is_empty() {
+            return Ok(OracleState {
+                block,
+                index: 0,
+                cardinality: 0,
+            });
+        }
+        let (_, _, index, cardinality, _) = UniswapV3Pool::new(pool, self.client.clone())
+            .slot_0()
+            .block(at)
+            .call()
+            .await
+            .context(format!("Failed to read slot0 of pool {pool:?} at {hash:?}"))?;
+        Ok(OracleState {
+            block,
+            index,
+            cardinality,
+        })
+    }
+
+    /// Index of the oldest observation in the ring buffer and the number of
+    /// observations from there to the newest. The oldest is the one after
+    /// the newest, unless the buffer has not filled up yet.
+    async fn ring(&self, pool: Address, oracle: &OracleState) -> Result<(u16, u16)> {
+        let next = ((oracle.index as u32 + 1) % oracle.cardinality as u32) as u16;
+        if observation_initialized(self.observation(pool, &oracle.block, next).await?) {
+            return Ok((next, oracle.cardinality));
+        }
+        Ok((0, oracle.index + 1))
+    }
+
+    /// Index and timestamp of the newest observation at or before
+    /// `timestamp` in the ring buffer of `oracle`, whose oldest observation
+    /// must be at or before `timestamp`.
+    async fn search(
+        &self,
+        pool: Address,
+        oracle: &OracleState,
+        (first, len): (u16, u16),
+        timestamp: u64,
+    ) -> Result<(u16, u32)> {
+        let index = |pos: u16| ((first as u32 + pos as u32) % oracle.cardinality as u32) as u16;
+        let read = |pos: u16| async move {
+            self.observation(pool, &oracle.block, index(pos))
+                .await
+                .map(observation_timestamp)
+        };
+        // Invariant: position `low` is at or before `timestamp`, `high` after.
+        let (mut low, mut high) = (0, len);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if u64::from(read(mid).await?) <= timestamp {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+        Ok((index(low), read(low).await?))
+    }
+
+    /// Prove `slot0` and the observations of `pool` at `indices` in the state
+    /// of `block`.
+    async fn anchor(&self, pool: Address, block: &Block<H256>, indices: &[u16]) -> Result<Anchor> {
+        let hash = block.hash.ok_or_else(|| anyhow!("block has no hash"))?;
+        let mut slots = vec![H256::from_low_u64_be(SLOT0_SLOT)];
+        slots.extend(indices.iter().map(|index| observation_slot(*index)));
+        let response = match &self.cache {
+            Some(cache) => {
+                cache
+                    .get_proof(self.source.as_ref(), block, pool, &slots)
+                    .await?
+            }
+            None => {
+                let response = self.source.get_proof(hash, pool, slots).await?;
+                verify_account(block.state_root, &response)?;
+                response
+            }
+        };
+        let mut storage = response.storage_proof.into_iter();
+        let slot0 = storage
+            .next()
+            .ok_or_else(|| anyhow!("proof of pool {pool:?} has no slot0"))?;
+        Ok(Anchor {
+            header: encode_header(block)?,
+            account_proof: response.account_proof,
+            slot0_proof: slot0.proof,
+            observations: indices
+                .iter()
+                .zip(storage)
+                .map(|(index, storage)| (*index, storage.proof))
+                .collect(),
+        })
+    }
+
+    /// Build the input averaging the tick of `pool` from the newest
+    /// observation at `from` to the newest at `to`, both Unix timestamps.
+    pub async fn fetch(&self, pool: Address, from: u64, to: u64) -> Result<TwapInput> {
+        if from >= to {
+            bail!("window [{from}, {to}] is empty");
+        }
+        let end = self
+            .block_at(to)
+            .await?
+            .ok_or_else(|| anyhow!("timestamp {to} is before the first block"))?;
+        let end = self.oracle(pool, end).await?;
+        if end.cardinality == 0 {
+            bail!("pool {pool:?} is not initialized at {to}");
+        }
+        let end_time = observation_timestamp(self.observation(pool, &end.block, end.index).await?);
+        let ring = self.ring(pool, &end).await?;
+        let oldest = observation_timestamp(self.observation(pool, &end.block, ring.0).await?);
+
+        // The ring buffer at the end of the window still reaches its start.
+        if u64::from(oldest) <= from {
+            let (start_index, start_time) = self.search(pool, &end, ring, from).await?;
+            if start_time >= end_time {
+                bail!("pool {pool:?} has no observation between {from} and {to}");
+            }
+            let anchor = self
+                .anchor(pool, &end.block, &[start_index, end.index])
+                .await?;
+            return Ok(TwapInput {
+                pool,
+                anchors: vec![anchor],
+            });
+        }
+
+        let history = InsufficientHistory {
+            pool,
+            requested: to - from,
+            available: to.saturating_sub(oldest.into()),
+            cardinality: end.cardinality,
+        };
+        if self.single_block {
+            return Err(history.into());
+        }
+        // Otherwise prove the start observation at the block ending the
+        // window's start, which fails if the pool had no observation yet.
+        let Some(start) = self.block_at(from).await? else {
+            return Err(history.into());
+        };
+        let start = self.oracle(pool, start).await?;
+        if start.cardinality == 0 {
+            return Err(history.into());
+        }
+        let start_time =
+            observation_timestamp(self.observation(pool, &start.block, start.index).await?);
+        if start_time >= end_time {
+            bail!("pool {pool:?} has no observation between {from} and {to}");
+        }
+        let anchors = vec![
+            self.anchor(pool, &start.block, &[start.index]).await?,
+            self.anchor(pool, &end.block, &[end.index]).await?,
+        ];
+        Ok(TwapInput { pool, anchors })
+    }
+
+    /// Rebuild the input a TWAP journal was proven from: the observations of
+    /// `pool` from the newest at `from` on, proven at the `blocks` the
+    /// journal commits. Unlike [TwapFetcher::fetch], the blocks are not
+    /// chosen by the finality policy.
+    pub async fn fetch_at(&self, pool: Address, blocks: &[H256], from: u64) -> Result<TwapInput> {
+        let oracle = |hash: H256| async move {
+            let oracle = self.oracle(pool, self.block(hash.into()).await?).await?;
+            if oracle.cardinality == 0 {
+                bail!("pool {pool:?} is not initialized at block {hash:?}");
+            }
+            Ok(oracle)
+        };
+        let anchors = match blocks {
+            [end] => {
+                let end = oracle(*end).await?;
+                let ring = self.ring(pool, &end).await?;
+                let (start_index, _) = self.search(pool, &end, ring, from).await?;
+                vec![
+                    self.anchor(pool, &end.block, &[start_index, end.index])
+                        .await?,
+                ]
+            }
+            [start, end] => {
+                let (start, end) = (oracle(*start).await?, oracle(*end).await?);
+                vec![
+                    self.anchor(pool, &start.block, &[start.index]).await?,
+                    self.anchor(pool, &end.block, &[end.index]).await?,
+                ]
+            }
+            _ => bail!(
+                "TWAP inputs are proven at one or two blocks, not {}",
+                blocks.len()
+            ),
+        };
+        Ok(TwapInput { pool, anchors })
+    }
+}
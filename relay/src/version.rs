@@ -0,0 +1,72 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+
+use crate::{elog, registry::Guest};
+
+/// zkVM release this relay is built against. Keep in sync with the risc0
+/// branch pinned in the workspace Cargo.toml.
+pub const HOST_ZKVM_VERSION: &str = "0.17.0";
+
+/// Identifier of the proving circuit used by [HOST_ZKVM_VERSION].
+pub const HOST_CIRCUIT: &str = "rv32im";
+
+/// What to do when a guest was built for a different zkVM than the host's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum VersionPolicy {
+    /// Log the mismatch and keep serving the guest.
+    Warn,
+    /// Leave the guest out of the registry, serving the others.
+    Refuse,
+}
+
+/// Versions are compatible when they agree on major and minor, which for
+/// 0.x releases is where receipt and circuit changes land.
+fn compatible(a: &str, b: &str) -> bool {
+    a.split('.').take(2).eq(b.split('.').take(2))
+}
+
+/// Compare the zkVM version and circuit a guest was built with against the
+/// host, applying the policy on mismatch.
+pub fn check_guest(guest: &Guest, policy: VersionPolicy) -> Result<()> {
+    let mut problems = Vec::new();
+    match &guest.zkvm_version {
+        Some(version) if !compatible(version, HOST_ZKVM_VERSION) => problems.push(format!(
+            "built with zkVM {version}, host runs {HOST_ZKVM_VERSION}"
+        )),
+        Some(_) => (),
+        None => problems.push("has no recorded zkVM version".to_string()),
+    }
+    if let Some(circuit) = &guest.circuit {
+        if circuit != HOST_CIRCUIT {
+            problems.push(format!(
+                "targets circuit {circuit}, host proves {HOST_CIRCUIT}"
+            ));
+        }
+    }
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let msg = format!("Guest {} {}", guest.name, problems.join(", "));
+    match policy {
+        VersionPolicy::Warn => {
+            elog!("Warning: {msg}");
+            Ok(())
+        }
+        VersionPolicy::Refuse => bail!(msg),
+    }
+}
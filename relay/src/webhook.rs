@@ -0,0 +1,83 @@
+// Copyright 2023 RISC Zero, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Delivery of the results of tenants' requests to their `webhook_url`.
+//!
+//! Each result proven for a tenant is POSTed to it as the JSON
+//! [ResultMessage] queues publish, with the request's trace ID also in the
+//! [TRACE_ID_HEADER]. Delivery is best effort, as for queues: failures are
+//! logged, not retried, and never hold back the response.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+use crate::{elog, queue::ResultMessage, registry::Guest, trace::TRACE_ID_HEADER, Output};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Check that a tenant's webhook URL can be delivered to.
+pub fn validate_url(url: &str) -> Result<()> {
+    let parsed = reqwest::Url::parse(url).context("Invalid webhook URL")?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        bail!("webhook URL must use the http:// or https:// scheme");
+    }
+    Ok(())
+}
+
+/// Posts tenants' results to their webhooks.
+#[derive(Clone)]
+pub struct Webhooks {
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .context("Failed to build webhook client")?;
+        Ok(Self { client })
+    }
+
+    /// Post the result of a request of `tenant` to the tenant's webhook.
+    pub async fn deliver(&self, url: &str, tenant: &str, guest: &Guest, output: &Output) {
+        let result = match ResultMessage::new(guest, output) {
+            Ok(mut message) => {
+                message.tenant = Some(tenant.to_string());
+                self.post(url, &message).await
+            }
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            elog!("Failed to deliver result of tenant {tenant} to its webhook: {err:?}");
+        }
+    }
+
+    async fn post(&self, url: &str, message: &ResultMessage) -> Result<()> {
+        let mut request = self.client.post(url).json(message);
+        if let Some(trace_id) = &message.trace_id {
+            request = request.header(TRACE_ID_HEADER, trace_id);
+        }
+        let response = request
+            .send()
+            .await
+            .context("Failed to reach the webhook")?;
+        let status = response.status();
+        if !status.is_success() {
+            bail!("webhook answered {status}");
+        }
+        Ok(())
+    }
+}
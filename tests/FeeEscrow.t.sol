@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: UNLICENSED
+pragma solidity ^0.8.14;
+
+import "forge-std/Test.sol";
+
+import "../contracts/FeeEscrow.sol";
+
+contract FeeEscrowTest is Test {
+    FeeEscrow escrow;
+    address operator = address(0x0FE);
+    address payer = address(0xBEEF);
+    address callback = address(0xCA11);
+    bytes32 request;
+
+    function setUp() public {
+        escrow = new FeeEscrow(operator, 1 hours);
+        vm.deal(payer, 10 ether);
+        request = escrow.requestId(payer, keccak256("image"), "input", callback, bytes4(0x12345678), 100_000);
+    }
+
+    function open(uint256 amount) internal returns (bytes32) {
+        vm.prank(payer);
+        return escrow.open{value: amount}(payer, keccak256("image"), "input", callback, bytes4(0x12345678), 100_000);
+    }
+
+    function testDepositAccumulates() public {
+        bytes32 id = open(1 ether);
+        vm.prank(payer);
+        escrow.deposit{value: 0.5 ether}(id);
+
+        assertEq(escrow.feeOf(id), 1.5 ether);
+    }
+
+    function testIdenticalRequestsGetOwnDeposits() public {
+        bytes32 first = open(1 ether);
+        bytes32 second = open(1 ether);
+
+        assertEq(first, escrow.escrowId(request, 0));
+        assertEq(second, escrow.escrowId(request, 1));
+        assertEq(escrow.depositCount(request), 2);
+    }
+
+    function testOperatorClaimsFulfilled() public {
+        bytes32 id = open(1 ether);
+
+        vm.expectRevert(FeeEscrow.NotFulfilled.selector);
+        vm.prank(operator);
+        escrow.claim(id);
+
+        vm.prank(callback);
+        escrow.confirm(request);
+        vm.prank(operator);
+        escrow.claim(id);
+
+        assertEq(operator.balance, 1 ether);
+        assertEq(escrow.feeOf(id), 0);
+    }
+
+    function testConfirmFulfilsInNonceOrder() public {
+        bytes32 first = open(1 ether);
+        bytes32 second = open(1 ether);
+
+        vm.prank(callback);
+        escrow.confirm(request);
+
+        vm.startPrank(operator);
+        escrow.claim(first);
+        vm.expectRevert(FeeEscrow.NotFulfilled.selector);
+        escrow.claim(second);
+        vm.stopPrank();
+    }
+
+    function testConfirmOnlyByCallbackContract() public {
+        open(1 ether);
+
+        vm.expectRevert(FeeEscrow.NotCallbackContract.selector);
+        vm.prank(operator);
+        escrow.confirm(request);
+
+        vm.startPrank(callback);
+        escrow.confirm(request);
+        vm.expectRevert(FeeEscrow.NothingToConfirm.selector);
+        escrow.confirm(request);
+        vm.stopPrank();
+    }
+
+    function testClaimOnlyByOperator() public {
+        bytes32 id = open(1 ether);
+        vm.prank(callback);
+        escrow.confirm(request);
+
+        vm.expectRevert(FeeEscrow.NotOperator.selector);
+        vm.prank(payer);
+        escrow.claim(id);
+    }
+
+    function testRefundAfterDelay() public {
+        bytes32 id = open(1 ether);
+
+        vm.expectRevert(abi.encodeWithSelector(FeeEscrow.RefundTooEarly.selector, block.timestamp + 1 hours));
+        vm.prank(payer);
+        escrow.refund(id);
+
+        vm.warp(block.timestamp + 1 hours);
+        vm.prank(payer);
+        escrow.refund(id);
+
+        assertEq(payer.balance, 10 ether);
+        assertEq(escrow.feeOf(id), 0);
+    }
+
+    function testTopUpKeepsRefundDelay() public {
+        uint256 openedAt = block.timestamp;
+        bytes32 id = open(1 ether);
+
+        vm.warp(openedAt + 50 minutes);
+        vm.prank(payer);
+        escrow.deposit{value: 1 ether}(id);
+
+        vm.warp(openedAt + 1 hours);
+        vm.prank(payer);
+        escrow.refund(id);
+        assertEq(payer.balance, 10 ether);
+    }
+
+    function testNoRefundOnceFulfilled() public {
+        bytes32 id = open(1 ether);
+        vm.prank(callback);
+        escrow.confirm(request);
+
+        vm.warp(block.timestamp + 1 hours);
+        vm.expectRevert(FeeEscrow.AlreadyFulfilled.selector);
+        vm.prank(payer);
+        escrow.refund(id);
+    }
+}
@@ -0,0 +1,122 @@
+// SPDX-License-Identifier: UNLICENSED
+pragma solidity ^0.8.14;
+
+import "forge-std/Test.sol";
+
+import {IRiscZeroVerifier} from "bonsai/IRiscZeroVerifier.sol";
+
+import "../contracts/ZkObservationHistory.sol";
+import {AcceptingVerifier} from "./ZkPriceAggregator.t.sol";
+
+contract ZkObservationHistoryTest is Test {
+    AcceptingVerifier verifier;
+    ZkObservationHistory history;
+    address pool = address(0xA);
+
+    function setUp() public {
+        vm.roll(10);
+        verifier = new AcceptingVerifier();
+        history = new ZkObservationHistory(IRiscZeroVerifier(address(verifier)), keccak256("history"), pool);
+    }
+
+    function leaf(uint32 timestamp, int56 tickCumulative) internal pure returns (bytes32) {
+        return keccak256(abi.encode(timestamp, tickCumulative));
+    }
+
+    function node(bytes32 left, bytes32 right) internal pure returns (bytes32) {
+        return keccak256(abi.encodePacked(left, right));
+    }
+
+    function commitment(uint64 count, bytes32[] memory peaks) internal view returns (bytes32) {
+        return keccak256(abi.encode(pool, count, peaks));
+    }
+
+    function journal(bytes32 previousRoot, uint64 count, bytes32[] memory peaks, uint64 observedTo)
+        internal
+        view
+        returns (bytes memory)
+    {
+        return journal(previousRoot, count, peaks, observedTo, 9, blockhash(9));
+    }
+
+    function journal(
+        bytes32 previousRoot,
+        uint64 count,
+        bytes32[] memory peaks,
+        uint64 observedTo,
+        uint64 blockNumber,
+        bytes32 blockHash
+    ) internal view returns (bytes memory) {
+        return abi.encode(
+            pool,
+            previousRoot,
+            commitment(count, peaks),
+            count,
+            peaks,
+            uint64(0),
+            observedTo,
+            blockHash,
+            uint8(15),
+            blockNumber
+        );
+    }
+
+    /// Three observations: a mountain of two leaves and one of a single leaf.
+    function threeLeafPeaks() internal pure returns (bytes32[] memory peaks) {
+        peaks = new bytes32[](2);
+        peaks[0] = node(leaf(100, 10), leaf(112, 22));
+        peaks[1] = leaf(124, 34);
+    }
+
+    function testExtendsFromGenesis() public {
+        bytes32[] memory peaks = threeLeafPeaks();
+        history.extend(journal(history.root(), 3, peaks, 124), "", bytes32(0));
+        assertEq(history.root(), commitment(3, peaks));
+        assertEq(history.count(), 3);
+        assertEq(history.latestTimestamp(), 124);
+    }
+
+    function testRejectsRunNotExtendingRoot() public {
+        bytes32[] memory peaks = threeLeafPeaks();
+        bytes32 genesis = history.root();
+        history.extend(journal(genesis, 3, peaks, 124), "", bytes32(0));
+
+        vm.expectRevert(abi.encodeWithSelector(ZkObservationHistory.UnexpectedRoot.selector, history.root(), genesis));
+        history.extend(journal(genesis, 3, peaks, 124), "", bytes32(0));
+    }
+
+    function testRejectsUnknownBlock() public {
+        bytes32[] memory peaks = threeLeafPeaks();
+        bytes32 forged = keccak256("forged");
+        bytes memory journal_ = journal(history.root(), 3, peaks, 124, 9, forged);
+        vm.expectRevert(abi.encodeWithSelector(ZkObservationHistory.UnknownBlock.selector, 9, forged));
+        history.extend(journal_, "", bytes32(0));
+
+        // Blocks outside of the last 256 have no hash to check against.
+        journal_ = journal(history.root(), 3, peaks, 124, 10, forged);
+        vm.expectRevert(abi.encodeWithSelector(ZkObservationHistory.UnknownBlock.selector, 10, forged));
+        history.extend(journal_, "", bytes32(0));
+    }
+
+    function testRejectsInvalidProof() public {
+        verifier.setAccept(false);
+        bytes memory journal_ = journal(history.root(), 3, threeLeafPeaks(), 124);
+        vm.expectRevert(ZkObservationHistory.InvalidProof.selector);
+        history.extend(journal_, "", bytes32(0));
+    }
+
+    function testVerifiesObservations() public {
+        history.extend(journal(history.root(), 3, threeLeafPeaks(), 124), "", bytes32(0));
+
+        bytes32[] memory proof = new bytes32[](1);
+        proof[0] = leaf(112, 22);
+        assertTrue(history.verifyObservation(0, 100, 10, proof));
+        assertFalse(history.verifyObservation(0, 100, 11, proof));
+        proof[0] = leaf(100, 10);
+        assertTrue(history.verifyObservation(1, 112, 22, proof));
+        assertTrue(history.verifyObservation(2, 124, 34, new bytes32[](0)));
+
+        vm.expectRevert(abi.encodeWithSelector(ZkObservationHistory.LeafOutOfRange.selector, 3, 3));
+        history.verifyObservation(3, 136, 46, new bytes32[](0));
+    }
+}
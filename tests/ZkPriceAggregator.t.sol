@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: UNLICENSED
+pragma solidity ^0.8.14;
+
+import "forge-std/Test.sol";
+
+import {IRiscZeroVerifier} from "bonsai/IRiscZeroVerifier.sol";
+
+import "../contracts/ZkPriceAggregator.sol";
+
+contract AcceptingVerifier {
+    bool public accept = true;
+
+    function setAccept(bool accept_) external {
+        accept = accept_;
+    }
+
+    function verify(bytes calldata, bytes32, bytes32, bytes32) external view returns (bool) {
+        return accept;
+    }
+}
+
+contract ZkPriceAggregatorTest is Test {
+    AcceptingVerifier verifier;
+    ZkPriceAggregator aggregator;
+    address transmitter = address(0x7A);
+    address batcher = address(0xBA);
+    bytes32 imageId = keccak256("swap");
+    address pool = address(0x9001);
+
+    // sqrt(5000) * 2^96: 5000 token1 per token0 at equal decimals.
+    uint160 constant SQRT_PRICE_5000 = 5602277097478613991873193822745;
+    // sqrt(5030) * 2^96 and sqrt(5020) * 2^96, 0.6% and 0.4% above.
+    uint160 constant SQRT_PRICE_5030 = 5619058793872422566973922358759;
+    uint160 constant SQRT_PRICE_5020 = 5613470469472618276210289872774;
+
+    function setUp() public {
+        vm.warp(1000);
+        vm.roll(10);
+        verifier = new AcceptingVerifier();
+        aggregator = new ZkPriceAggregator(
+            IRiscZeroVerifier(address(verifier)),
+            imageId,
+            pool,
+            transmitter,
+            batcher,
+            8,
+            18,
+            18,
+            false,
+            1 hours,
+            50,
+            1e18,
+            "TOKEN1 / TOKEN0"
+        );
+    }
+
+    function journal(uint160 sqrtPriceX96, uint64 observedAt) internal view returns (bytes memory) {
+        return journal(sqrtPriceX96, observedAt, 1e18);
+    }
+
+    function journal(uint160 sqrtPriceX96, uint64 observedAt, uint128 liquidity)
+        internal
+        view
+        returns (bytes memory)
+    {
+        return journal(sqrtPriceX96, observedAt, liquidity, pool, 9, blockhash(9));
+    }
+
+    function journal(
+        uint160 sqrtPriceX96,
+        uint64 observedAt,
+        uint128 liquidity,
+        address pool_,
+        uint64 blockNumber,
+        bytes32 blockHash
+    ) internal pure returns (bytes memory) {
+        return abi.encode(
+            bytes32(0),
+            sqrtPriceX96,
+            uint256(0),
+            uint256(0),
+            uint256(0),
+            observedAt,
+            observedAt,
+            liquidity,
+            uint128(0),
+            pool_,
+            blockHash,
+            blockNumber
+        );
+    }
+
+    function testTransmitUpdatesLatestRound() public {
+        vm.prank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+
+        (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound) =
+            aggregator.latestRoundData();
+        assertEq(roundId, 1);
+        assertApproxEqRel(uint256(answer), 5000e8, 1e15);
+        assertEq(startedAt, 100);
+        assertEq(updatedAt, 100);
+        assertEq(answeredInRound, 1);
+    }
+
+    function testInvertedAnswer() public {
+        ZkPriceAggregator inverted = new ZkPriceAggregator(
+            IRiscZeroVerifier(address(verifier)),
+            imageId,
+            pool,
+            transmitter,
+            batcher,
+            8,
+            18,
+            18,
+            true,
+            1 hours,
+            50,
+            1e18,
+            "TOKEN0 / TOKEN1"
+        );
+        assertApproxEqRel(inverted.price(SQRT_PRICE_5000), 0.0002e8, 1e15);
+    }
+
+    function testDecimalAdjustment() public {
+        // token0 with 6 decimals and token1 with 18: 5000 raw units of token1
+        // per raw unit of token0 is 5e-9 token1 per token0.
+        ZkPriceAggregator adjusted = new ZkPriceAggregator(
+            IRiscZeroVerifier(address(verifier)),
+            imageId,
+            pool,
+            transmitter,
+            batcher,
+            18,
+            6,
+            18,
+            false,
+            1 hours,
+            50,
+            1e18,
+            "TOKEN1 / TOKEN0"
+        );
+        assertApproxEqRel(adjusted.price(SQRT_PRICE_5000), 5e9, 1e15);
+    }
+
+    function testRoundsMustBeSequential() public {
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.UnexpectedRound.selector, 1, 2));
+        vm.prank(transmitter);
+        aggregator.transmit(2, journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+    }
+
+    function testRejectsStaleObservation() public {
+        vm.startPrank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.StaleObservation.selector, 100, 99));
+        aggregator.transmit(2, journal(SQRT_PRICE_5000, 99), "", bytes32(0));
+        vm.stopPrank();
+    }
+
+    function testRejectsDustPool() public {
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.InsufficientLiquidity.selector, 1e18, 1e18 - 1));
+        vm.prank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100, 1e18 - 1), "", bytes32(0));
+    }
+
+    function testRejectsInvalidProof() public {
+        verifier.setAccept(false);
+        vm.expectRevert(ZkPriceAggregator.InvalidProof.selector);
+        vm.prank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+    }
+
+    function testOnlyTransmitter() public {
+        vm.expectRevert(ZkPriceAggregator.NotTransmitter.selector);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+    }
+
+    function testBatcherRecordsNewerRounds() public {
+        vm.startPrank(batcher);
+        assertTrue(aggregator.transmitBatched(SQRT_PRICE_5000, 100, 100, 1e18));
+        assertEq(aggregator.latestRound(), 1);
+        // Stale and dust observations are skipped rather than reverting.
+        assertFalse(aggregator.transmitBatched(SQRT_PRICE_5000, 100, 100, 1e18));
+        assertFalse(aggregator.transmitBatched(SQRT_PRICE_5000, 101, 101, 1e18 - 1));
+        assertEq(aggregator.latestRound(), 1);
+        vm.stopPrank();
+    }
+
+    function testOnlyBatcher() public {
+        vm.expectRevert(ZkPriceAggregator.NotBatcher.selector);
+        vm.prank(transmitter);
+        aggregator.transmitBatched(SQRT_PRICE_5000, 100, 100, 1e18);
+    }
+
+    function testSubmitterSubmitsNewerObservation() public {
+        address submitter = address(0x5B);
+        vm.prank(transmitter);
+        aggregator.setSubmitter(submitter, true);
+
+        vm.startPrank(submitter);
+        aggregator.submit(journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+        assertEq(aggregator.latestRound(), 1);
+
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.StaleObservation.selector, 100, 100));
+        aggregator.submit(journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+
+        aggregator.submit(journal(SQRT_PRICE_5000, 101), "", bytes32(0));
+        assertEq(aggregator.latestRound(), 2);
+        vm.stopPrank();
+    }
+
+    function testOnlySubmitters() public {
+        vm.expectRevert(ZkPriceAggregator.NotSubmitter.selector);
+        aggregator.submit(journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+
+        vm.expectRevert(ZkPriceAggregator.NotTransmitter.selector);
+        aggregator.setSubmitter(address(this), true);
+    }
+
+    function testRejectsFutureObservation() public {
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.FutureObservation.selector, 1001));
+        vm.prank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 1001), "", bytes32(0));
+
+        vm.prank(batcher);
+        assertFalse(aggregator.transmitBatched(SQRT_PRICE_5000, 1001, 1001, 1e18));
+    }
+
+    function testRejectsOtherPool() public {
+        bytes memory other = journal(SQRT_PRICE_5000, 100, 1e18, address(0x9002), 9, blockhash(9));
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.WrongPool.selector, pool, address(0x9002)));
+        vm.prank(transmitter);
+        aggregator.transmit(1, other, "", bytes32(0));
+    }
+
+    function testRejectsUnprovenState() public {
+        bytes memory unproven = journal(SQRT_PRICE_5000, 100, 1e18, address(0), 0, bytes32(0));
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.WrongPool.selector, pool, address(0)));
+        vm.prank(transmitter);
+        aggregator.transmit(1, unproven, "", bytes32(0));
+    }
+
+    function testRejectsUnknownBlock() public {
+        bytes32 forked = keccak256("forked");
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceAggregator.UnknownBlock.selector, 9, forked));
+        vm.prank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100, 1e18, pool, 9, forked), "", bytes32(0));
+    }
+
+    function testCheckUpdate() public {
+        assertEq(uint8(aggregator.checkUpdate(SQRT_PRICE_5000)), uint8(ZkPriceAggregator.UpdateReason.FirstRound));
+
+        vm.warp(100);
+        vm.prank(transmitter);
+        aggregator.transmit(1, journal(SQRT_PRICE_5000, 100), "", bytes32(0));
+        assertEq(uint8(aggregator.checkUpdate(SQRT_PRICE_5020)), uint8(ZkPriceAggregator.UpdateReason.None));
+        assertEq(uint8(aggregator.checkUpdate(SQRT_PRICE_5030)), uint8(ZkPriceAggregator.UpdateReason.Deviation));
+
+        vm.warp(100 + 1 hours);
+        assertEq(uint8(aggregator.checkUpdate(SQRT_PRICE_5000)), uint8(ZkPriceAggregator.UpdateReason.Heartbeat));
+    }
+
+    function testNoDataBeforeFirstRound() public {
+        vm.expectRevert(ZkPriceAggregator.NoDataPresent.selector);
+        aggregator.latestRoundData();
+    }
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: UNLICENSED
+pragma solidity ^0.8.14;
+
+import "forge-std/Test.sol";
+
+import {IRiscZeroVerifier} from "bonsai/IRiscZeroVerifier.sol";
+
+import "../contracts/ZkPriceAggregator.sol";
+import "../contracts/ZkPriceBatcher.sol";
+import {AcceptingVerifier} from "./ZkPriceAggregator.t.sol";
+
+contract ZkPriceBatcherTest is Test {
+    AcceptingVerifier verifier;
+    ZkPriceBatcher batcher;
+    ZkPriceAggregator feedA;
+    ZkPriceAggregator feedB;
+    address transmitter = address(0x7A);
+    address poolA = address(0xA);
+    address poolB = address(0xB);
+
+    event FeedSkipped(address indexed pool, address indexed feed);
+    event PriceFailed(address indexed pool, uint8 status);
+
+    // sqrt(5000) * 2^96: 5000 token1 per token0 at equal decimals.
+    uint160 constant SQRT_PRICE_5000 = 5602277097478613991873193822745;
+
+    function setUp() public {
+        verifier = new AcceptingVerifier();
+        batcher =
+            new ZkPriceBatcher(IRiscZeroVerifier(address(verifier)), keccak256("batch"), transmitter, address(this));
+        feedA = feed(poolA);
+        feedB = feed(poolB);
+        batcher.setFeed(poolA, feedA);
+        batcher.setFeed(poolB, feedB);
+    }
+
+    function feed(address pool) internal returns (ZkPriceAggregator) {
+        return new ZkPriceAggregator(
+            IRiscZeroVerifier(address(verifier)),
+            keccak256("swap"),
+            pool,
+            transmitter,
+            address(batcher),
+            8,
+            18,
+            18,
+            false,
+            1 hours,
+            50,
+            1e18,
+            "TOKEN1 / TOKEN0"
+        );
+    }
+
+    function journal(uint64 observedAt, ZkPriceBatcher.PoolPrice[] memory prices)
+        internal
+        pure
+        returns (bytes memory)
+    {
+        return abi.encode(bytes32(0), observedAt, observedAt, prices);
+    }
+
+    function prices(address first, address second) internal pure returns (ZkPriceBatcher.PoolPrice[] memory) {
+        ZkPriceBatcher.PoolPrice[] memory result = new ZkPriceBatcher.PoolPrice[](2);
+        result[0] = ZkPriceBatcher.PoolPrice(first, 0, SQRT_PRICE_5000, 1e18, 0);
+        result[1] = ZkPriceBatcher.PoolPrice(second, 0, SQRT_PRICE_5000, 1e18, 0);
+        return result;
+    }
+
+    function testTransmitUpdatesEveryFeed() public {
+        vm.prank(transmitter);
+        assertEq(batcher.transmit(journal(100, prices(poolA, poolB)), "", bytes32(0)), 2);
+
+        (, int256 answer,, uint256 updatedAt,) = feedA.latestRoundData();
+        assertApproxEqRel(uint256(answer), 5000e8, 1e15);
+        assertEq(updatedAt, 100);
+        assertEq(feedB.latestRound(), 1);
+    }
+
+    function testSkipsStaleFeeds() public {
+        bytes memory swapJournal = abi.encode(
+            bytes32(0),
+            SQRT_PRICE_5000,
+            uint256(0),
+            uint256(0),
+            uint256(0),
+            uint64(200),
+            uint64(200),
+            uint128(1e18),
+            uint128(0)
+        );
+        vm.prank(transmitter);
+        feedA.transmit(1, swapJournal, "", bytes32(0));
+
+        vm.expectEmit(true, true, false, false);
+        emit FeedSkipped(poolA, address(feedA));
+        vm.prank(transmitter);
+        assertEq(batcher.transmit(journal(100, prices(poolA, poolB)), "", bytes32(0)), 1);
+        assertEq(feedA.latestRound(), 1);
+        assertEq(feedB.latestRound(), 1);
+    }
+
+    function testSkipsFailedPrices() public {
+        ZkPriceBatcher.PoolPrice[] memory batch = prices(poolA, poolB);
+        batch[0] = ZkPriceBatcher.PoolPrice(poolA, 1, 0, 1e15, 1e18);
+
+        vm.expectEmit(true, false, false, true);
+        emit PriceFailed(poolA, 1);
+        vm.prank(transmitter);
+        assertEq(batcher.transmit(journal(100, batch), "", bytes32(0)), 1);
+        assertEq(feedA.latestRound(), 0);
+        assertEq(feedB.latestRound(), 1);
+    }
+
+    function testRejectsUnknownPool() public {
+        vm.expectRevert(abi.encodeWithSelector(ZkPriceBatcher.UnknownPool.selector, address(0xC)));
+        vm.prank(transmitter);
+        batcher.transmit(journal(100, prices(poolA, address(0xC))), "", bytes32(0));
+    }
+
+    function testRejectsInvalidProof() public {
+        verifier.setAccept(false);
+        vm.expectRevert(ZkPriceBatcher.InvalidProof.selector);
+        vm.prank(transmitter);
+        batcher.transmit(journal(100, prices(poolA, poolB)), "", bytes32(0));
+    }
+
+    function testOnlyTransmitter() public {
+        vm.expectRevert(ZkPriceBatcher.NotTransmitter.selector);
+        batcher.transmit(journal(100, prices(poolA, poolB)), "", bytes32(0));
+    }
+
+    function testOnlyOwnerSetsFeeds() public {
+        vm.expectRevert(ZkPriceBatcher.NotOwner.selector);
+        vm.prank(transmitter);
+        batcher.setFeed(poolA, feedB);
+    }
+}
@@ -1,4 +1,4 @@
-use std::ops::{BitOr, Neg, Shl, Shr};
+use std::ops::{BitOr, Shl, Shr};
 
 use ethers_core::types::{I256, U256};
 
@@ -10,79 +10,50 @@ pub const MAX_TICK: i32 = -MIN_TICK;
 pub const MIN_SQRT_RATIO: U256 = U256([4295128739, 0, 0, 0]);
 pub const MAX_SQRT_RATIO: U256 = U256([6743328256752651558, 17280870778742802505, 4294805859, 0]);
 
+/// `2^128 / sqrt(1.0001)^(2^i)` for each bit `i` of an absolute tick, as
+/// rounded by Uniswap's `TickMath`, in limbs so zkVM guests do not parse
+/// them from hex on every call.
+const SQRT_RATIO_FACTORS: [U256; 20] = [
+    U256([0xaa2d162d1a594001, 0xfffcb933bd6fad37, 0x0, 0x0]),
+    U256([0x59a46990580e213a, 0xfff97272373d4132, 0x0, 0x0]),
+    U256([0xef12357cf3c7fdcc, 0xfff2e50f5f656932, 0x0, 0x0]),
+    U256([0x1c3624eaa0941cd0, 0xffe5caca7e10e4e6, 0x0, 0x0]),
+    U256([0xc9db58835c926644, 0xffcb9843d60f6159, 0x0, 0x0]),
+    U256([0x472e6896dfb254c0, 0xff973b41fa98c081, 0x0, 0x0]),
+    U256([0x43ec78b326b52861, 0xff2ea16466c96a38, 0x0, 0x0]),
+    U256([0x11c461f1969c3053, 0xfe5dee046a99a2a8, 0x0, 0x0]),
+    U256([0xdcffc83b479aa3a4, 0xfcbe86c7900a88ae, 0x0, 0x0]),
+    U256([0x6f2b074cf7815e54, 0xf987a7253ac41317, 0x0, 0x0]),
+    U256([0x940c7a398e4b70f3, 0xf3392b0822b70005, 0x0, 0x0]),
+    U256([0x43b29c7fa6e889d9, 0xe7159475a2c29b74, 0x0, 0x0]),
+    U256([0x845ad8f792aa5825, 0xd097f3bdfd2022b8, 0x0, 0x0]),
+    U256([0x8a65dc1f90e061e5, 0xa9f746462d870fdf, 0x0, 0x0]),
+    U256([0x90bb3df62baf32f7, 0x70d869a156d2a1b8, 0x0, 0x0]),
+    U256([0x81231505542fcfa6, 0x31be135f97d08fd9, 0x0, 0x0]),
+    U256([0xc677de54f3e99bc9, 0x9aa508b5b7a84e1, 0x0, 0x0]),
+    U256([0x6699c329225ee604, 0x5d6af8dedb8119, 0x0, 0x0]),
+    U256([0x1ea926041bedfe98, 0x2216e584f5fa, 0x0, 0x0]),
+    U256([0x91f7dc42444e8fa2, 0x48a1703, 0x0, 0x0]),
+];
+
+const Q128: U256 = U256([0, 0, 1, 0]);
+
 pub fn get_sqrt_ratio_at_tick(tick: i32) -> Result<U256, UniswapV3MathError> {
-    let abs_tick = if tick < 0 {
-        U256::from_little_endian(&tick.neg().to_le_bytes())
-    } else {
-        U256::from(tick)
-    };
+    let abs_tick = tick.unsigned_abs();
 
-    if abs_tick > U256::from(MAX_TICK) {
+    if abs_tick > MAX_TICK as u32 {
         return Err(UniswapV3MathError::T);
     }
 
-    let mut ratio = if abs_tick & (U256::from(0x1)) != U256::zero() {
-        U256::from("0xfffcb933bd6fad37aa2d162d1a594001")
+    let mut ratio = if abs_tick & 0x1 != 0 {
+        SQRT_RATIO_FACTORS[0]
     } else {
-        U256::from("0x100000000000000000000000000000000")
+        Q128
     };
-
-    if !(abs_tick & (U256::from(0x2))).is_zero() {
-        ratio = (ratio * U256::from("0xfff97272373d413259a46990580e213a")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x4))).is_zero() {
-        ratio = (ratio * U256::from("0xfff2e50f5f656932ef12357cf3c7fdcc")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x8))).is_zero() {
-        ratio = (ratio * U256::from("0xffe5caca7e10e4e61c3624eaa0941cd0")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x10))).is_zero() {
-        ratio = (ratio * U256::from("0xffcb9843d60f6159c9db58835c926644")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x20))).is_zero() {
-        ratio = (ratio * U256::from("0xff973b41fa98c081472e6896dfb254c0")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x40))).is_zero() {
-        ratio = (ratio * U256::from("0xff2ea16466c96a3843ec78b326b52861")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x80))).is_zero() {
-        ratio = (ratio * U256::from("0xfe5dee046a99a2a811c461f1969c3053")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x100))).is_zero() {
-        ratio = (ratio * U256::from("0xfcbe86c7900a88aedcffc83b479aa3a4")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x200))).is_zero() {
-        ratio = (ratio * U256::from("0xf987a7253ac413176f2b074cf7815e54")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x400))).is_zero() {
-        ratio = (ratio * U256::from("0xf3392b0822b70005940c7a398e4b70f3")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x800))).is_zero() {
-        ratio = (ratio * U256::from("0xe7159475a2c29b7443b29c7fa6e889d9")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x1000))).is_zero() {
-        ratio = (ratio * U256::from("0xd097f3bdfd2022b8845ad8f792aa5825")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x2000))).is_zero() {
-        ratio = (ratio * U256::from("0xa9f746462d870fdf8a65dc1f90e061e5")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x4000))).is_zero() {
-        ratio = (ratio * U256::from("0x70d869a156d2a1b890bb3df62baf32f7")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x8000))).is_zero() {
-        ratio = (ratio * U256::from("0x31be135f97d08fd981231505542fcfa6")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x10000))).is_zero() {
-        ratio = (ratio * U256::from("0x9aa508b5b7a84e1c677de54f3e99bc9")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x20000))).is_zero() {
-        ratio = (ratio * U256::from("0x5d6af8dedb81196699c329225ee604")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x40000))).is_zero() {
-        ratio = (ratio * U256::from("0x2216e584f5fa1ea926041bedfe98")) >> 128
-    }
-    if !(abs_tick & (U256::from(0x80000))).is_zero() {
-        ratio = (ratio * U256::from("0x48a170391f7dc42444e8fa2")) >> 128
+    for (bit, factor) in SQRT_RATIO_FACTORS.iter().enumerate().skip(1) {
+        if abs_tick & (1 << bit) != 0 {
+            ratio = (ratio * *factor) >> 128;
+        }
     }
 
     if tick > 0 {
@@ -217,7 +188,116 @@ pub fn get_tick_at_sqrt_ratio(sqrt_price_x_96: U256) -> Result<i32, UniswapV3Mat
 mod test {
     use super::*;
     use ethers_core::types::U256;
-    use std::ops::Sub;
+    use std::ops::{Neg, Sub};
+
+    /// The runtime computation the factor table replaced, parsing each
+    /// factor from hex.
+    fn reference_sqrt_ratio_at_tick(tick: i32) -> Result<U256, UniswapV3MathError> {
+        let abs_tick = if tick < 0 {
+            U256::from_little_endian(&tick.neg().to_le_bytes())
+        } else {
+            U256::from(tick)
+        };
+
+        if abs_tick > U256::from(MAX_TICK) {
+            return Err(UniswapV3MathError::T);
+        }
+
+        let mut ratio = if abs_tick & (U256::from(0x1)) != U256::zero() {
+            U256::from("0xfffcb933bd6fad37aa2d162d1a594001")
+        } else {
+            U256::from("0x100000000000000000000000000000000")
+        };
+
+        if !(abs_tick & (U256::from(0x2))).is_zero() {
+            ratio = (ratio * U256::from("0xfff97272373d413259a46990580e213a")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x4))).is_zero() {
+            ratio = (ratio * U256::from("0xfff2e50f5f656932ef12357cf3c7fdcc")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x8))).is_zero() {
+            ratio = (ratio * U256::from("0xffe5caca7e10e4e61c3624eaa0941cd0")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x10))).is_zero() {
+            ratio = (ratio * U256::from("0xffcb9843d60f6159c9db58835c926644")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x20))).is_zero() {
+            ratio = (ratio * U256::from("0xff973b41fa98c081472e6896dfb254c0")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x40))).is_zero() {
+            ratio = (ratio * U256::from("0xff2ea16466c96a3843ec78b326b52861")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x80))).is_zero() {
+            ratio = (ratio * U256::from("0xfe5dee046a99a2a811c461f1969c3053")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x100))).is_zero() {
+            ratio = (ratio * U256::from("0xfcbe86c7900a88aedcffc83b479aa3a4")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x200))).is_zero() {
+            ratio = (ratio * U256::from("0xf987a7253ac413176f2b074cf7815e54")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x400))).is_zero() {
+            ratio = (ratio * U256::from("0xf3392b0822b70005940c7a398e4b70f3")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x800))).is_zero() {
+            ratio = (ratio * U256::from("0xe7159475a2c29b7443b29c7fa6e889d9")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x1000))).is_zero() {
+            ratio = (ratio * U256::from("0xd097f3bdfd2022b8845ad8f792aa5825")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x2000))).is_zero() {
+            ratio = (ratio * U256::from("0xa9f746462d870fdf8a65dc1f90e061e5")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x4000))).is_zero() {
+            ratio = (ratio * U256::from("0x70d869a156d2a1b890bb3df62baf32f7")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x8000))).is_zero() {
+            ratio = (ratio * U256::from("0x31be135f97d08fd981231505542fcfa6")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x10000))).is_zero() {
+            ratio = (ratio * U256::from("0x9aa508b5b7a84e1c677de54f3e99bc9")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x20000))).is_zero() {
+            ratio = (ratio * U256::from("0x5d6af8dedb81196699c329225ee604")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x40000))).is_zero() {
+            ratio = (ratio * U256::from("0x2216e584f5fa1ea926041bedfe98")) >> 128
+        }
+        if !(abs_tick & (U256::from(0x80000))).is_zero() {
+            ratio = (ratio * U256::from("0x48a170391f7dc42444e8fa2")) >> 128
+        }
+
+        if tick > 0 {
+            ratio = U256::MAX / ratio;
+        }
+
+        Ok((ratio >> 32)
+            + if (ratio % (U256::one() << 32)).is_zero() {
+                U256::zero()
+            } else {
+                U256::one()
+            })
+    }
+
+    #[test]
+    fn sqrt_ratio_factors_match_reference() {
+        let mut ticks: Vec<i32> = (MIN_TICK..=MAX_TICK).step_by(997).collect();
+        for bit in 0..20 {
+            ticks.extend([1 << bit, (1 << bit) - 1, (1 << bit) + 1]);
+        }
+        ticks.extend([MIN_TICK, MIN_TICK + 1, 0, MAX_TICK - 1, MAX_TICK]);
+        for tick in ticks
+            .iter()
+            .flat_map(|tick| [*tick, -*tick])
+            .filter(|tick| (MIN_TICK..=MAX_TICK).contains(tick))
+        {
+            assert_eq!(
+                get_sqrt_ratio_at_tick(tick).unwrap(),
+                reference_sqrt_ratio_at_tick(tick).unwrap(),
+                "sqrt ratio at {tick} differs from the reference"
+            );
+        }
+    }
 
     #[test]
     fn get_sqrt_ratio_at_tick_bounds() {